@@ -9,8 +9,15 @@ use crate::{
 	state::{LauncherPaths, PersistedState},
 	trace,
 	util::{
-		errors::{wrap, AnyError, RefreshTokenNotAvailableError, StatusError, WrappedError},
-		input::prompt_options,
+		clock::{system_clock, SharedClock},
+		errors::{
+			wrap, AnyError, AuthScopesNotGranted, RefreshTokenNotAvailableError, StatusError,
+			WrappedError,
+		},
+		http::shared_client,
+		input::{prompt_options, prompt_yn},
+		io::restrict_to_owner,
+		plain::is_plain_output,
 	},
 	warning,
 };
@@ -25,15 +32,28 @@ use tunnels::{
 	management::{Authorization, AuthorizationProvider, HttpError},
 };
 
+/// How far ahead of expiry credentials that can't be refreshed are reported.
+const EXPIRY_WARNING_HOURS: i64 = 6;
+
 #[derive(Deserialize)]
 struct DeviceCodeResponse {
 	device_code: String,
 	user_code: String,
 	message: Option<String>,
 	verification_uri: String,
+	verification_uri_complete: Option<String>,
 	expires_in: i64,
 }
 
+/// Body sent to the device code webhook, if one is configured.
+#[derive(Serialize)]
+struct DeviceCodeNotification<'a> {
+	provider: AuthProvider,
+	verification_uri: &'a str,
+	user_code: &'a str,
+	expires_at: DateTime<Utc>,
+}
+
 #[derive(Deserialize)]
 struct AuthenticationResponse {
 	access_token: String,
@@ -41,7 +61,20 @@ struct AuthenticationResponse {
 	expires_in: Option<i64>,
 }
 
-#[derive(clap::ArgEnum, Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Deserialize)]
+struct GithubUser {
+	login: String,
+}
+
+/// Claims of a Microsoft access token that name the account.
+#[derive(Deserialize)]
+struct AccountClaims {
+	preferred_username: Option<String>,
+	upn: Option<String>,
+	email: Option<String>,
+}
+
+#[derive(clap::ArgEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AuthProvider {
 	Microsoft,
 	Github,
@@ -80,13 +113,40 @@ impl AuthProvider {
 		}
 	}
 
-	pub fn get_default_scopes(&self) -> String {
+	/// Minimal scopes needed to host a tunnel.
+	pub fn get_default_scopes(&self) -> Vec<String> {
 		match self {
-			AuthProvider::Microsoft => format!(
-				"{}/.default+offline_access+profile+openid",
-				PROD_FIRST_PARTY_APP_ID
-			),
-			AuthProvider::Github => "read:user+read:org".to_string(),
+			AuthProvider::Microsoft => vec![
+				format!("{}/.default", PROD_FIRST_PARTY_APP_ID),
+				"offline_access".to_string(),
+				"profile".to_string(),
+				"openid".to_string(),
+			],
+			AuthProvider::Github => vec!["read:user".to_string(), "read:org".to_string()],
+		}
+	}
+
+	/// Scopes needed, in addition to the default ones, to use the feature.
+	pub fn get_feature_scopes(&self, feature: AuthFeature) -> &'static [&'static str] {
+		match (self, feature) {
+			// the .default scope already covers everything the app is allowed
+			(AuthProvider::Microsoft, _) => &[],
+			(AuthProvider::Github, AuthFeature::SettingsSync) => &["user:email"],
+		}
+	}
+}
+
+/// Features that need more than the scopes requested for hosting a tunnel.
+/// Their scopes are requested incrementally the first time they're used.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFeature {
+	SettingsSync,
+}
+
+impl Display for AuthFeature {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			AuthFeature::SettingsSync => write!(f, "Settings Sync"),
 		}
 	}
 }
@@ -101,14 +161,54 @@ pub struct StoredCredential {
 	refresh_token: Option<String>,
 	#[serde(rename = "e")]
 	expires_at: Option<DateTime<Utc>>,
+	/// Scopes the credential was granted. None for credentials stored by
+	/// older versions, which always requested the broadest scopes.
+	#[serde(rename = "s", default, skip_serializing_if = "Option::is_none")]
+	scopes: Option<Vec<String>>,
 }
 
 impl StoredCredential {
-	pub async fn is_expired(&self, client: &reqwest::Client) -> bool {
+	pub fn provider(&self) -> AuthProvider {
+		self.provider
+	}
+
+	pub fn access_token(&self) -> &str {
+		&self.access_token
+	}
+
+	pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+		self.expires_at
+	}
+
+	/// Gets whether the credential can be refreshed when it expires.
+	pub fn can_refresh(&self) -> bool {
+		self.refresh_token.is_some()
+	}
+
+	/// Gets the scopes the features need which this credential wasn't granted.
+	pub fn missing_scopes(&self, features: &[AuthFeature]) -> Vec<String> {
+		let granted = match &self.scopes {
+			Some(s) => s,
+			None => return vec![],
+		};
+
+		let mut missing: Vec<String> = vec![];
+		for feature in features {
+			for scope in self.provider.get_feature_scopes(*feature) {
+				if !granted.iter().any(|g| g == scope) && !missing.iter().any(|m| m == scope) {
+					missing.push(scope.to_string());
+				}
+			}
+		}
+
+		missing
+	}
+
+	pub async fn is_expired(&self, client: &reqwest::Client, now: DateTime<Utc>) -> bool {
 		match self.provider {
 			AuthProvider::Microsoft => self
 				.expires_at
-				.map(|e| Utc::now() + chrono::Duration::minutes(5) > e)
+				.map(|e| now + chrono::Duration::minutes(5) > e)
 				.unwrap_or(false),
 
 			// Make an auth request to Github. Mark the credential as expired
@@ -125,19 +225,122 @@ impl StoredCredential {
 		}
 	}
 
-	fn from_response(auth: AuthenticationResponse, provider: AuthProvider) -> Self {
+	fn from_response(
+		auth: AuthenticationResponse,
+		provider: AuthProvider,
+		scopes: Option<Vec<String>>,
+		now: DateTime<Utc>,
+	) -> Self {
 		StoredCredential {
 			provider,
 			access_token: auth.access_token,
 			refresh_token: auth.refresh_token,
-			expires_at: auth.expires_in.map(|e| Utc::now() + Duration::seconds(e)),
+			expires_at: auth.expires_in.map(|e| now + Duration::seconds(e)),
+			scopes,
 		}
 	}
 }
 
+/// Set of credentials stored by the CLI, at most one per provider.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StoredCredentials {
+	/// Provider used by operations that don't ask for a specific one, such as
+	/// hosting a tunnel.
+	#[serde(rename = "d")]
+	default_provider: Option<AuthProvider>,
+	#[serde(rename = "c")]
+	credentials: Vec<StoredCredential>,
+}
+
+impl StoredCredentials {
+	pub fn default_provider(&self) -> Option<AuthProvider> {
+		self.default_provider
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &StoredCredential> {
+		self.credentials.iter()
+	}
+
+	/// Gets the credential for the provider, or the default credential if
+	/// no provider is given.
+	pub fn get(&self, provider: Option<AuthProvider>) -> Option<&StoredCredential> {
+		match provider.or(self.default_provider) {
+			Some(p) => self.credentials.iter().find(|c| c.provider == p),
+			None => self.credentials.first(),
+		}
+	}
+
+	/// Adds or replaces the credential for its provider. The first credential
+	/// stored becomes the default.
+	fn insert(&mut self, creds: StoredCredential) {
+		self.credentials.retain(|c| c.provider != creds.provider);
+		if self.default_provider.is_none() {
+			self.default_provider = Some(creds.provider);
+		}
+		self.credentials.push(creds);
+	}
+
+	/// Removes the credential for the provider, picking a new default if needed.
+	fn remove(&mut self, provider: AuthProvider) {
+		self.credentials.retain(|c| c.provider != provider);
+		if self.default_provider == Some(provider) {
+			self.default_provider = self.credentials.first().map(|c| c.provider);
+		}
+	}
+}
+
+/// Stored value, which may be a single credential from older CLI versions.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredValue {
+	Multiple(StoredCredentials),
+	Single(StoredCredential),
+}
+
+impl From<StoredValue> for StoredCredentials {
+	fn from(v: StoredValue) -> Self {
+		match v {
+			StoredValue::Multiple(c) => c,
+			StoredValue::Single(c) => StoredCredentials {
+				default_provider: Some(c.provider),
+				credentials: vec![c],
+			},
+		}
+	}
+}
+
+fn unseal_credentials(value: &str) -> Option<StoredCredentials> {
+	unseal::<StoredValue>(value).map(StoredCredentials::from)
+}
+
+/// Reads the account name from the claims of a JWT access token.
+fn get_token_account_name(token: &str) -> Option<String> {
+	let payload = token.split('.').nth(1)?;
+	let decoded = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+	let claims: AccountClaims = serde_json::from_slice(&decoded).ok()?;
+	claims.preferred_username.or(claims.upn).or(claims.email)
+}
+
+/// Outcome of the most recent token refresh. It's persisted so that failures
+/// in a long-running host can be seen with `code tunnel status`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CredentialHealth {
+	pub last_refreshed_at: Option<DateTime<Utc>>,
+	pub last_refresh_error: Option<String>,
+	pub last_refresh_error_at: Option<DateTime<Utc>>,
+}
+
+/// Warning that the stored credential will soon stop working.
+pub struct CredentialWarning {
+	pub provider: AuthProvider,
+	pub expires_at: Option<DateTime<Utc>>,
+	pub message: String,
+}
+
 struct StorageWithLastRead {
 	storage: Box<dyn StorageImplementation>,
-	last_read: Cell<Result<Option<StoredCredential>, WrappedError>>,
+	last_read: Cell<Result<Option<StoredCredentials>, WrappedError>>,
+	in_file: bool,
 }
 
 #[derive(Clone)]
@@ -146,11 +349,13 @@ pub struct Auth {
 	log: log::Logger,
 	file_storage_path: PathBuf,
 	storage: Arc<std::sync::Mutex<Option<StorageWithLastRead>>>,
+	health: PersistedState<CredentialHealth>,
+	clock: SharedClock,
 }
 
 trait StorageImplementation: Send + Sync {
-	fn read(&mut self) -> Result<Option<StoredCredential>, WrappedError>;
-	fn store(&mut self, value: StoredCredential) -> Result<(), WrappedError>;
+	fn read(&mut self) -> Result<Option<StoredCredentials>, WrappedError>;
+	fn store(&mut self, value: StoredCredentials) -> Result<(), WrappedError>;
 	fn clear(&mut self) -> Result<(), WrappedError>;
 }
 
@@ -205,7 +410,7 @@ macro_rules! get_next_entry {
 }
 
 impl StorageImplementation for KeyringStorage {
-	fn read(&mut self) -> Result<Option<StoredCredential>, WrappedError> {
+	fn read(&mut self) -> Result<Option<StoredCredentials>, WrappedError> {
 		let mut str = String::new();
 
 		for i in 0.. {
@@ -224,10 +429,10 @@ impl StorageImplementation for KeyringStorage {
 			}
 		}
 
-		Ok(unseal(&str))
+		Ok(unseal_credentials(&str))
 	}
 
-	fn store(&mut self, value: StoredCredential) -> Result<(), WrappedError> {
+	fn store(&mut self, value: StoredCredentials) -> Result<(), WrappedError> {
 		let sealed = seal(&value);
 		let step_size = KEYCHAIN_ENTRY_LIMIT - CONTINUE_MARKER.len();
 
@@ -267,12 +472,14 @@ impl StorageImplementation for KeyringStorage {
 struct FileStorage(PersistedState<Option<String>>);
 
 impl StorageImplementation for FileStorage {
-	fn read(&mut self) -> Result<Option<StoredCredential>, WrappedError> {
-		Ok(self.0.load().and_then(|s| unseal(&s)))
+	fn read(&mut self) -> Result<Option<StoredCredentials>, WrappedError> {
+		Ok(self.0.load().and_then(|s| unseal_credentials(&s)))
 	}
 
-	fn store(&mut self, value: StoredCredential) -> Result<(), WrappedError> {
-		self.0.save(Some(seal(&value)))
+	fn store(&mut self, value: StoredCredentials) -> Result<(), WrappedError> {
+		self.0.save(Some(seal(&value)))?;
+		restrict_to_owner(&self.0.path(), 0o600)
+			.map_err(|e| wrap(e, "error restricting access to the token file"))
 	}
 
 	fn clear(&mut self) -> Result<(), WrappedError> {
@@ -280,16 +487,31 @@ impl StorageImplementation for FileStorage {
 	}
 }
 
+/// Where credentials are stored.
+pub enum TokenStorage {
+	/// The OS keyring, such as the macOS keychain or libsecret.
+	Keyring,
+	/// A file in the data directory, used when no keyring is available.
+	File(PathBuf),
+}
+
 impl Auth {
 	pub fn new(paths: &LauncherPaths, log: log::Logger) -> Auth {
 		Auth {
 			log,
-			client: reqwest::Client::new(),
+			client: shared_client(),
 			file_storage_path: paths.root().join("token.json"),
 			storage: Arc::new(std::sync::Mutex::new(None)),
+			health: PersistedState::new(paths.root().join("token-health.json")),
+			clock: system_clock(),
 		}
 	}
 
+	/// Uses the clock to determine when tokens expire, for tests.
+	pub fn with_clock(self, clock: SharedClock) -> Auth {
+		Auth { clock, ..self }
+	}
+
 	fn with_storage<T, F>(&self, op: F) -> T
 	where
 		F: FnOnce(&mut StorageWithLastRead) -> T,
@@ -311,10 +533,12 @@ impl Auth {
 			Ok(v) => StorageWithLastRead {
 				last_read: Cell::new(Ok(v)),
 				storage: Box::new(keyring_storage),
+				in_file: false,
 			},
 			Err(_) => StorageWithLastRead {
 				last_read: Cell::new(file_storage.read()),
 				storage: Box::new(file_storage),
+				in_file: true,
 			},
 		};
 
@@ -323,6 +547,15 @@ impl Auth {
 		out
 	}
 
+	/// Gets where credentials are stored.
+	pub fn token_storage(&self) -> TokenStorage {
+		if self.with_storage(|s| s.in_file) {
+			TokenStorage::File(self.file_storage_path.clone())
+		} else {
+			TokenStorage::Keyring
+		}
+	}
+
 	/// Gets a tunnel Authentication for use in the tunnel management API.
 	pub async fn get_tunnel_authentication(&self) -> Result<Authorization, AnyError> {
 		let cred = self.get_credential().await?;
@@ -338,16 +571,26 @@ impl Auth {
 		Ok(auth)
 	}
 
-	/// Reads the current details from the keyring.
-	pub fn get_current_credential(&self) -> Result<Option<StoredCredential>, WrappedError> {
+	/// Reads all stored credentials from the keyring.
+	pub fn get_current_credentials(&self) -> Result<StoredCredentials, WrappedError> {
 		self.with_storage(|storage| {
 			let value = storage.last_read.replace(Ok(None));
 			storage.last_read.set(value.clone());
-			value
+			value.map(|v| v.unwrap_or_default())
 		})
 	}
 
-	/// Clears login info from the keyring.
+	/// Reads the current details for the provider, or the default provider if
+	/// none is given, from the keyring.
+	pub fn get_current_credential(
+		&self,
+		provider: Option<AuthProvider>,
+	) -> Result<Option<StoredCredential>, WrappedError> {
+		self.get_current_credentials()
+			.map(|c| c.get(provider).cloned())
+	}
+
+	/// Clears all login info from the keyring.
 	pub fn clear_credentials(&self) -> Result<(), WrappedError> {
 		self.with_storage(|storage| {
 			storage.storage.clear()?;
@@ -356,11 +599,70 @@ impl Auth {
 		})
 	}
 
+	/// Clears login info for a single provider from the keyring.
+	pub fn clear_credential(&self, provider: AuthProvider) -> Result<(), WrappedError> {
+		let mut creds = self.get_current_credentials()?;
+		creds.remove(provider);
+		if creds.credentials.is_empty() {
+			return self.clear_credentials();
+		}
+
+		self.with_storage(|storage| {
+			storage.storage.store(creds.clone())?;
+			storage.last_read.set(Ok(Some(creds)));
+			Ok(())
+		})
+	}
+
+	/// Makes the provider's stored credential the default one.
+	pub fn set_default_provider(&self, provider: AuthProvider) -> Result<(), WrappedError> {
+		let mut creds = self.get_current_credentials()?;
+		creds.default_provider = Some(provider);
+		self.with_storage(|storage| {
+			storage.storage.store(creds.clone())?;
+			storage.last_read.set(Ok(Some(creds)));
+			Ok(())
+		})
+	}
+
+	/// Gets the name of the account the credential belongs to. For GitHub it's
+	/// looked up from the API; Microsoft tokens carry it in their claims.
+	pub async fn get_account_name(&self, creds: &StoredCredential) -> Result<String, AnyError> {
+		match creds.provider {
+			AuthProvider::Microsoft => {
+				get_token_account_name(&creds.access_token).ok_or_else(|| {
+					wrap(
+						"no account claims",
+						"error reading the account from the token",
+					)
+					.into()
+				})
+			}
+			AuthProvider::Github => {
+				let response = self
+					.client
+					.get("https://api.github.com/user")
+					.header("Authorization", format!("token {}", creds.access_token))
+					.header("User-Agent", get_default_user_agent())
+					.send()
+					.await?;
+
+				if !response.status().is_success() {
+					return Err(StatusError::from_res(response).await?.into());
+				}
+
+				Ok(response.json::<GithubUser>().await?.login)
+			}
+		}
+	}
+
 	/// Runs the login flow, optionally pre-filling a provider and/or access token.
+	/// Scopes for the given features are requested up front.
 	pub async fn login(
 		&self,
 		provider: Option<AuthProvider>,
 		access_token: Option<String>,
+		features: &[AuthFeature],
 	) -> Result<StoredCredential, AnyError> {
 		let provider = match provider {
 			Some(p) => p,
@@ -373,17 +675,39 @@ impl Auth {
 				access_token: t,
 				refresh_token: None,
 				expires_at: None,
+				scopes: None,
 			},
-			None => self.do_device_code_flow_with_provider(provider).await?,
+			None => {
+				let mut scopes = provider.get_default_scopes();
+				for feature in features {
+					for scope in provider.get_feature_scopes(*feature) {
+						if !scopes.iter().any(|s| s == scope) {
+							scopes.push(scope.to_string());
+						}
+					}
+				}
+
+				self.do_device_code_flow_with_provider(provider, scopes)
+					.await?
+			}
 		};
 
 		self.store_credentials(credentials.clone());
 		Ok(credentials)
 	}
 
-	/// Gets the currently stored credentials, or asks the user to log in.
+	/// Gets the default stored credentials, or asks the user to log in.
 	pub async fn get_credential(&self) -> Result<StoredCredential, AnyError> {
-		let entry = match self.get_current_credential() {
+		self.get_credential_for(None).await
+	}
+
+	/// Gets the stored credentials for the provider, or the default provider if
+	/// none is given. Asks the user to log in if they're not available.
+	pub async fn get_credential_for(
+		&self,
+		provider: Option<AuthProvider>,
+	) -> Result<StoredCredential, AnyError> {
+		let entry = match self.get_current_credential(provider) {
 			Ok(Some(old_creds)) => {
 				trace!(self.log, "Found token in keyring");
 				match self.get_refreshed_token(&old_creds).await {
@@ -394,8 +718,13 @@ impl Auth {
 					Ok(None) => old_creds,
 					Err(e) => {
 						info!(self.log, "error refreshing token: {}", e);
+						self.record_refresh_error(&e);
+						let scopes = old_creds
+							.scopes
+							.clone()
+							.unwrap_or_else(|| old_creds.provider.get_default_scopes());
 						let new_creds = self
-							.do_device_code_flow_with_provider(old_creds.provider)
+							.do_device_code_flow_with_provider(old_creds.provider, scopes)
 							.await?;
 						self.store_credentials(new_creds.clone());
 						new_creds
@@ -405,7 +734,7 @@ impl Auth {
 
 			Ok(None) => {
 				trace!(self.log, "No token in keyring, getting a new one");
-				let creds = self.do_device_code_flow().await?;
+				let creds = self.do_device_code_flow(provider).await?;
 				self.store_credentials(creds.clone());
 				creds
 			}
@@ -416,7 +745,7 @@ impl Auth {
 					"Error reading token from keyring, getting a new one: {}",
 					e
 				);
-				let creds = self.do_device_code_flow().await?;
+				let creds = self.do_device_code_flow(provider).await?;
 				self.store_credentials(creds.clone());
 				creds
 			}
@@ -425,17 +754,117 @@ impl Auth {
 		Ok(entry)
 	}
 
-	/// Stores credentials, logging a warning if it fails.
+	/// Gets credentials which are also authorized for the given features. If
+	/// the stored credential wasn't granted the scopes they need, the user is
+	/// asked to consent to them and log in again.
+	pub async fn get_credential_for_features(
+		&self,
+		provider: Option<AuthProvider>,
+		features: &[AuthFeature],
+	) -> Result<StoredCredential, AnyError> {
+		let creds = self.get_credential_for(provider).await?;
+		let missing = creds.missing_scopes(features);
+		if missing.is_empty() {
+			return Ok(creds);
+		}
+
+		let names = features
+			.iter()
+			.filter(|f| !creds.provider.get_feature_scopes(**f).is_empty())
+			.map(|f| f.to_string())
+			.collect::<Vec<_>>()
+			.join(", ");
+		self.log.result(&format!(
+			"Using {} requires additional permissions from your {}: {}",
+			names,
+			creds.provider,
+			missing.join(", ")
+		));
+		if !prompt_yn("Log in again to grant them?")? {
+			return Err(AuthScopesNotGranted(missing).into());
+		}
+
+		let mut scopes = creds.scopes.clone().unwrap_or_default();
+		scopes.extend(missing);
+		let new_creds = self
+			.do_device_code_flow_with_provider(creds.provider, scopes)
+			.await?;
+		self.store_credentials(new_creds.clone());
+		Ok(new_creds)
+	}
+
+	/// Gets the outcome of the most recent token refresh.
+	pub fn get_credential_health(&self) -> CredentialHealth {
+		self.health.load()
+	}
+
+	/// Gets a warning if the default credential is expected to stop working
+	/// soon, either because it can't be refreshed or because refreshing it has
+	/// been failing. Makes no network requests.
+	pub fn get_expiry_warning(&self) -> Option<CredentialWarning> {
+		let within = Duration::hours(EXPIRY_WARNING_HOURS);
+		let creds = self.get_current_credential(None).ok()??;
+		let health = self.health.load();
+
+		if let Some(e) = health.last_refresh_error {
+			return Some(CredentialWarning {
+				provider: creds.provider,
+				expires_at: creds.expires_at,
+				message: format!(
+					"Refreshing the {} token failed ({}). Run `code tunnel user login` on the host to keep it online.",
+					creds.provider, e
+				),
+			});
+		}
+
+		match creds.expires_at {
+			Some(e) if creds.refresh_token.is_none() && e - self.clock.now() < within => {
+				Some(CredentialWarning {
+					provider: creds.provider,
+					expires_at: Some(e),
+					message: format!(
+						"The {} token expires at {} and can't be refreshed. Run `code tunnel user login` on the host to keep it online.",
+						creds.provider,
+						e.to_rfc3339()
+					),
+				})
+			}
+			_ => None,
+		}
+	}
+
+	fn record_refresh_error(&self, e: &AnyError) {
+		let mut health = self.health.load();
+		health.last_refresh_error = Some(e.to_string());
+		health.last_refresh_error_at = Some(self.clock.now());
+		if let Err(e) = self.health.save(health) {
+			warning!(self.log, "Failed to record token refresh error: {}", e);
+		}
+	}
+
+	/// Stores credentials alongside those of other providers, logging a
+	/// warning if it fails.
 	fn store_credentials(&self, creds: StoredCredential) {
+		let health = CredentialHealth {
+			last_refreshed_at: Some(self.clock.now()),
+			..Default::default()
+		};
+		if let Err(e) = self.health.save(health) {
+			warning!(self.log, "Failed to record token refresh: {}", e);
+		}
+
+		let mut all = self.get_current_credentials().unwrap_or_default();
+		all.insert(creds);
+
 		self.with_storage(|storage| {
-			if let Err(e) = storage.storage.store(creds.clone()) {
+			if let Err(e) = storage.storage.store(all.clone()) {
 				warning!(
 					self.log,
 					"Failed to update keyring with new credentials: {}",
 					e
 				);
 			}
-			storage.last_read.set(Ok(Some(creds)));
+			storage.last_read.set(Ok(Some(all)));
 		})
 	}
 
@@ -445,7 +874,7 @@ impl Auth {
 		&self,
 		creds: &StoredCredential,
 	) -> Result<Option<StoredCredential>, AnyError> {
-		if !creds.is_expired(&self.client).await {
+		if !creds.is_expired(&self.client, self.clock.now()).await {
 			return Ok(None);
 		}
 
@@ -456,6 +885,7 @@ impl Auth {
 
 		self.do_grant(
 			creds.provider,
+			creds.scopes.clone(),
 			format!(
 				"client_id={}&grant_type=refresh_token&refresh_token={}",
 				creds.provider.client_id(),
@@ -470,6 +900,7 @@ impl Auth {
 	async fn do_grant(
 		&self,
 		provider: AuthProvider,
+		scopes: Option<Vec<String>>,
 		body: String,
 	) -> Result<StoredCredential, AnyError> {
 		let response = self
@@ -485,13 +916,26 @@ impl Auth {
 		}
 
 		let body = response.json::<AuthenticationResponse>().await?;
-		Ok(StoredCredential::from_response(body, provider))
+		Ok(StoredCredential::from_response(
+			body,
+			provider,
+			scopes,
+			self.clock.now(),
+		))
 	}
 
 	/// Implements the device code flow, returning the credentials upon success.
-	async fn do_device_code_flow(&self) -> Result<StoredCredential, AnyError> {
-		let provider = self.prompt_for_provider().await?;
-		self.do_device_code_flow_with_provider(provider).await
+	/// Prompts for a provider if one isn't given.
+	async fn do_device_code_flow(
+		&self,
+		provider: Option<AuthProvider>,
+	) -> Result<StoredCredential, AnyError> {
+		let provider = match provider {
+			Some(p) => p,
+			None => self.prompt_for_provider().await?,
+		};
+		self.do_device_code_flow_with_provider(provider, provider.get_default_scopes())
+			.await
 	}
 
 	async fn prompt_for_provider(&self) -> Result<AuthProvider, AnyError> {
@@ -510,6 +954,7 @@ impl Auth {
 	async fn do_device_code_flow_with_provider(
 		&self,
 		provider: AuthProvider,
+		scopes: Vec<String>,
 	) -> Result<StoredCredential, AnyError> {
 		loop {
 			let init_code = self
@@ -519,7 +964,7 @@ impl Auth {
 				.body(format!(
 					"client_id={}&scope={}",
 					provider.client_id(),
-					provider.get_default_scopes(),
+					scopes.join("+"),
 				))
 				.send()
 				.await?;
@@ -529,7 +974,8 @@ impl Auth {
 			}
 
 			let init_code_json = init_code.json::<DeviceCodeResponse>().await?;
-			let expires_at = Utc::now() + chrono::Duration::seconds(init_code_json.expires_in);
+			let expires_at =
+				self.clock.now() + chrono::Duration::seconds(init_code_json.expires_in);
 
 			match &init_code_json.message {
 				Some(m) => self.log.result(m),
@@ -539,16 +985,23 @@ impl Auth {
 				)),
 			};
 
+			self.show_device_code_qr(&init_code_json);
+			self.notify_device_code_webhook(provider, &init_code_json, expires_at)
+				.await;
+
 			let body = format!(
                 "client_id={}&grant_type=urn:ietf:params:oauth:grant-type:device_code&device_code={}",
                 provider.client_id(),
                 init_code_json.device_code
             );
 
-			while Utc::now() < expires_at {
+			while self.clock.now() < expires_at {
 				sleep(std::time::Duration::from_secs(5)).await;
 
-				match self.do_grant(provider, body.clone()).await {
+				match self
+					.do_grant(provider, Some(scopes.clone()), body.clone())
+					.await
+				{
 					Ok(creds) => return Ok(creds),
 					Err(e) => {
 						trace!(self.log, "refresh poll failed, retrying: {}", e);
@@ -559,6 +1012,78 @@ impl Auth {
 	}
 }
 
+impl Auth {
+	/// Prints a QR code for the verification URL, so it can be opened on a
+	/// phone rather than retyped. Skipped when the output isn't a terminal that
+	/// can render it, in which case the text message alone is shown.
+	fn show_device_code_qr(&self, res: &DeviceCodeResponse) {
+		if !atty::is(atty::Stream::Stdout) || is_plain_output() {
+			return;
+		}
+
+		let uri = res
+			.verification_uri_complete
+			.as_deref()
+			.unwrap_or(&res.verification_uri);
+		match qrcode::QrCode::new(uri.as_bytes()) {
+			Ok(code) => {
+				let rendered = code
+					.render::<qrcode::render::unicode::Dense1x2>()
+					.dark_color(qrcode::render::unicode::Dense1x2::Light)
+					.light_color(qrcode::render::unicode::Dense1x2::Dark)
+					.build();
+				self.log.result(&rendered);
+			}
+			Err(e) => trace!(self.log, "could not render device code QR: {}", e),
+		}
+	}
+
+	/// Sends the verification URL and code to the webhook configured in
+	/// `VSCODE_CLI_DEVICE_CODE_WEBHOOK`, if any. Failures are logged but
+	/// don't interrupt login, since the code is also shown in the terminal.
+	async fn notify_device_code_webhook(
+		&self,
+		provider: AuthProvider,
+		res: &DeviceCodeResponse,
+		expires_at: DateTime<Utc>,
+	) {
+		let url = match std::env::var("VSCODE_CLI_DEVICE_CODE_WEBHOOK") {
+			Ok(u) if !u.is_empty() => u,
+			_ => return,
+		};
+
+		let body = DeviceCodeNotification {
+			provider,
+			verification_uri: res
+				.verification_uri_complete
+				.as_deref()
+				.unwrap_or(&res.verification_uri),
+			user_code: &res.user_code,
+			expires_at,
+		};
+
+		let result = self
+			.client
+			.post(&url)
+			.header("User-Agent", get_default_user_agent())
+			.json(&body)
+			.send()
+			.await;
+
+		match result {
+			Ok(r) if r.status().is_success() => {
+				info!(self.log, "Sent login link to the configured webhook")
+			}
+			Ok(r) => warning!(
+				self.log,
+				"Device code webhook returned status {}",
+				r.status()
+			),
+			Err(e) => warning!(self.log, "Error sending device code webhook: {}", e),
+		}
+	}
+}
+
 #[async_trait]
 impl AuthorizationProvider for Auth {
 	async fn get_authorization(&self) -> Result<Authorization, HttpError> {
@@ -592,3 +1117,58 @@ fn encrypt(value: &str) -> String {
 fn decrypt(value: &str) -> Option<String> {
 	Some(value.to_owned())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_unseal_single_credential() {
+		let legacy = r#"{"p":"Github","a":"token","r":null,"e":null}"#;
+		let creds = unseal_credentials(&encrypt(legacy)).unwrap();
+		assert_eq!(creds.default_provider(), Some(AuthProvider::Github));
+		assert_eq!(creds.iter().count(), 1);
+
+		let cred = creds.get(None).unwrap();
+		assert_eq!(cred.access_token(), "token");
+		assert!(!cred.can_refresh());
+		assert!(cred.missing_scopes(&[AuthFeature::SettingsSync]).is_empty());
+	}
+
+	#[test]
+	fn test_unseal_multiple_credentials() {
+		let mut creds = StoredCredentials::default();
+		for provider in [AuthProvider::Github, AuthProvider::Microsoft] {
+			creds.insert(StoredCredential {
+				provider,
+				access_token: provider.to_string(),
+				refresh_token: None,
+				expires_at: None,
+				scopes: None,
+			});
+		}
+
+		let creds = unseal_credentials(&seal(&creds)).unwrap();
+		assert_eq!(creds.default_provider(), Some(AuthProvider::Github));
+		assert_eq!(
+			creds
+				.get(Some(AuthProvider::Microsoft))
+				.unwrap()
+				.access_token(),
+			"Microsoft Account"
+		);
+	}
+
+	#[test]
+	fn test_get_token_account_name() {
+		let claims = base64::encode_config(
+			r#"{"upn":"upn@example.com","preferred_username":"user@example.com"}"#,
+			base64::URL_SAFE_NO_PAD,
+		);
+		assert_eq!(
+			get_token_account_name(&format!("header.{}.signature", claims)),
+			Some("user@example.com".to_string())
+		);
+		assert_eq!(get_token_account_name("not-a-jwt"), None);
+	}
+}
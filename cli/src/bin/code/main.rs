@@ -4,18 +4,40 @@
  *--------------------------------------------------------------------------------------------*/
 mod legacy_args;
 
-use std::process::Command;
+use std::{path::PathBuf, process::Command};
 
 use clap::Parser;
 use cli::{
-	commands::{args, tunnels, update, version, CommandContext},
-	constants::get_default_user_agent,
+	commands::{
+		apply, args, command_shell, experiments, release, server, state, tunnels, update, version,
+		CommandContext,
+	},
 	desktop, log as own_log,
-	state::LauncherPaths,
+	options::UpdateEndpointLayout,
+	state::{migrations, LauncherPaths},
+	tunnels::{
+		machine_id::get_machine_identity,
+		session_recording::{set_session_recording, RecordingOptions},
+	},
+	update_service::{
+		set_download_connections, set_require_signed, set_update_endpoint,
+		set_update_retry_attempts,
+	},
 	util::{
 		errors::{wrap, AnyError},
+		http::{
+			set_http_timeouts, shared_client, DEFAULT_CONNECT_TIMEOUT_SECS,
+			DEFAULT_READ_TIMEOUT_SECS,
+		},
+		input::set_assume_yes,
 		is_integrated_cli,
-		prereqs::PreReqChecker,
+		patchelf::set_binary_fixup,
+		plain::set_plain_output,
+		prereqs::{set_force_x64, PreReqChecker},
+		priority::set_maintenance_priority,
+		proxy::configure_proxy,
+		tempfile::set_temp_root,
+		tls::configure_tls,
 	},
 };
 use legacy_args::try_parse_legacy;
@@ -38,20 +60,133 @@ async fn main() -> Result<(), std::convert::Infallible> {
 		});
 
 	let core = parsed.core();
-	let context = CommandContext {
-		http: reqwest::ClientBuilder::new()
-			.user_agent(get_default_user_agent())
-			.build()
-			.unwrap(),
-		paths: LauncherPaths::new(&core.global_options.cli_data_dir).unwrap(),
+	set_plain_output(core.global_options.plain);
+	set_assume_yes(core.global_options.yes);
+	set_temp_root(core.global_options.temp_dir.as_ref().map(PathBuf::from));
+	if let Err(e) = configure_proxy(
+		core.global_options.proxy.as_deref(),
+		core.global_options.proxy_auth_helper.as_deref(),
+	)
+	.await
+	{
+		print_and_exit(e);
+	}
+
+	// configured before the shared client is built for the context
+	let paths = LauncherPaths::new(&core.global_options.cli_data_dir).unwrap();
+	let config = paths.config();
+	let (cert, key) = match &core.global_options.tls_client_cert {
+		Some(c) => (Some(c), core.global_options.tls_client_key.as_ref()),
+		None => (
+			config.tls_client_cert.as_ref(),
+			config.tls_client_key.as_ref(),
+		),
+	};
+	set_http_timeouts(
+		core.global_options
+			.http_connect_timeout
+			.or(config.http_connect_timeout)
+			.unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS),
+		core.global_options
+			.http_read_timeout
+			.or(config.http_read_timeout)
+			.unwrap_or(DEFAULT_READ_TIMEOUT_SECS),
+	);
+	if let Err(e) = configure_tls(
+		core.global_options
+			.tls_ca_file
+			.as_ref()
+			.or(config.tls_ca_file.as_ref())
+			.map(|p| p.as_path()),
+		cert.map(|p| p.as_path()),
+		key.map(|p| p.as_path()),
+	) {
+		print_and_exit(e);
+	}
+
+	let mut context = CommandContext {
+		http: shared_client(),
+		paths,
 		log: make_logger(core),
 		args: core.clone(),
+		host_id: None,
 	};
 
 	log::set_logger(Box::leak(Box::new(RustyLogger(context.log.clone()))))
 		.map(|()| log::set_max_level(log::LevelFilter::Debug))
 		.expect("expected to make logger");
 
+	// processes started from here, like servers, log with the same ID
+	let correlation_id = own_log::export_correlation_id();
+	context.log.emit(
+		own_log::Level::Trace,
+		&format!("Correlation ID: {}", correlation_id),
+	);
+
+	// checked before any settings are used, so none are silently dropped
+	if context.args.global_options.strict_config || context.paths.config_wants_strict() {
+		if let Err(e) = context.paths.check_config() {
+			print_and_exit(e);
+		}
+	}
+
+	// `state` commands migrate, or undo migrations, themselves
+	if !matches!(context.args.subcommand, Some(args::Commands::State(_))) {
+		migrate_state(&context);
+	}
+
+	// read before the command changes anything, so results name the host
+	// they were made on even if the command changes its identity
+	if context.args.global_options.json {
+		context.host_id = get_machine_identity(&context.paths).ok().map(|i| i.id);
+	}
+
+	if let Some(priority) = context.args.global_options.maintenance_priority {
+		set_maintenance_priority(priority);
+	}
+	set_force_x64(context.args.global_options.force_x64);
+	if context.args.global_options.record_sessions {
+		let mut options = RecordingOptions::new(
+			context.paths.recordings_dir(),
+			context.args.global_options.session_retention.map(|d| d.0),
+		);
+		if let Some(recipient) = &context.args.global_options.recording_recipient {
+			options = match options.with_recipient(recipient) {
+				Ok(o) => o,
+				Err(e) => print_and_exit(e),
+			};
+		}
+		set_session_recording(Some(options));
+	}
+	if let Some(connections) = context.args.global_options.download_connections {
+		set_download_connections(connections);
+	}
+	configure_update_endpoint(&context);
+	if let Some(attempts) = context
+		.args
+		.global_options
+		.update_retry_attempts
+		.or_else(|| context.paths.config().update_retry_attempts)
+	{
+		set_update_retry_attempts(attempts);
+	}
+	set_require_signed(
+		context.args.global_options.require_signed || context.paths.config().require_signed,
+	);
+	if let Some(fixup) = context
+		.args
+		.global_options
+		.server_binary_fixup
+		.or_else(|| context.paths.config().server_binary_fixup)
+	{
+		set_binary_fixup(fixup);
+	}
+	// the elevated command itself mustn't try to elevate again
+	#[cfg(windows)]
+	let elevate = context.args.global_options.elevate
+		&& context.args.global_options.elevated_output.is_none();
+	let json = context.args.global_options.json;
+
 	let result = match parsed {
 		args::AnyCli::Standalone(args::StandaloneCli {
 			subcommand: Some(cmd),
@@ -78,31 +213,130 @@ async fn main() -> Result<(), std::convert::Infallible> {
 				start_code(context, ca).await
 			}
 
+			Some(args::Commands::CommandShell) => command_shell::command_shell(context).await,
+
 			Some(args::Commands::Version(version_args)) => match version_args.subcommand {
 				args::VersionSubcommand::Use(use_version_args) => {
 					version::switch_to(context, use_version_args).await
 				}
 				args::VersionSubcommand::Show => version::show(context).await,
+				args::VersionSubcommand::Pin(pin_args) => version::pin(context, pin_args).await,
+				args::VersionSubcommand::Unpin => version::unpin(context).await,
+				args::VersionSubcommand::List(list_args) => version::list(context, list_args).await,
+			},
+
+			Some(args::Commands::Server(server_args)) => match server_args.subcommand {
+				args::ServerSubcommand::Prune(prune_args) => {
+					server::prune(context, prune_args).await
+				}
+				args::ServerSubcommand::Rollback(rollback_args) => {
+					server::rollback(context, rollback_args).await
+				}
+				args::ServerSubcommand::Prefetch(prefetch_args) => {
+					server::prefetch(context, prefetch_args).await
+				}
+				args::ServerSubcommand::Verify(verify_args) => {
+					server::verify(context, verify_args).await
+				}
+			},
+
+			Some(args::Commands::State(state_args)) => match state_args.subcommand {
+				args::StateSubcommand::Migrate(migrate_args) => {
+					state::migrate(context, migrate_args).await
+				}
+				args::StateSubcommand::Restore(restore_args) => {
+					state::restore(context, restore_args).await
+				}
+			},
+
+			Some(args::Commands::Release(release_args)) => match release_args.subcommand {
+				args::ReleaseSubcommand::Mirror(mirror_args) => {
+					release::mirror(context, mirror_args).await
+				}
 			},
 
+			Some(args::Commands::Experiments(experiments_args)) => {
+				match experiments_args.subcommand {
+					args::ExperimentsSubcommand::List(list_args) => {
+						experiments::list(context, list_args).await
+					}
+				}
+			}
+
 			Some(args::Commands::Tunnel(tunnel_args)) => match tunnel_args.subcommand {
 				Some(args::TunnelSubcommand::Prune) => tunnels::prune(context).await,
+				Some(args::TunnelSubcommand::Status) => tunnels::status(context).await,
+				Some(args::TunnelSubcommand::RestartServer) => {
+					tunnels::restart_server(context).await
+				}
+				Some(args::TunnelSubcommand::Logs(logs_args)) => {
+					tunnels::logs(context, logs_args).await
+				}
+				Some(args::TunnelSubcommand::Stats(stats_args)) => {
+					tunnels::stats(context, stats_args).await
+				}
+				Some(args::TunnelSubcommand::Gc(gc_args)) => tunnels::gc(context, gc_args).await,
+				Some(args::TunnelSubcommand::List(list_args)) => {
+					tunnels::list(context, list_args).await
+				}
 				Some(args::TunnelSubcommand::Unregister) => tunnels::unregister(context).await,
+				Some(args::TunnelSubcommand::SshConfig(ssh_config_args)) => {
+					tunnels::ssh_config(context, ssh_config_args).await
+				}
+				Some(args::TunnelSubcommand::Sftp(sftp_args)) => {
+					tunnels::sftp(context, sftp_args).await
+				}
+				Some(args::TunnelSubcommand::StdioBridge(bridge_args)) => {
+					tunnels::stdio_bridge(context, bridge_args).await
+				}
+				Some(args::TunnelSubcommand::Forward(forward_args)) => {
+					tunnels::forward(context, forward_args).await
+				}
+				Some(args::TunnelSubcommand::ServerInfo(info_args)) => {
+					tunnels::server_info(context, info_args).await
+				}
+				Some(args::TunnelSubcommand::Ext(ext_args)) => {
+					tunnels::ext(context, ext_args).await
+				}
 				Some(args::TunnelSubcommand::Rename(rename_args)) => {
 					tunnels::rename(context, rename_args).await
 				}
+				Some(args::TunnelSubcommand::Apply(apply_args)) => {
+					apply::apply(context, apply_args).await
+				}
 				Some(args::TunnelSubcommand::User(user_command)) => {
 					tunnels::user(context, user_command).await
 				}
+				Some(args::TunnelSubcommand::Id(id_command)) => {
+					tunnels::id(context, id_command).await
+				}
+				Some(args::TunnelSubcommand::Env(env_command)) => {
+					tunnels::env(context, env_command).await
+				}
 				Some(args::TunnelSubcommand::Service(service_args)) => {
 					tunnels::service(context, service_args).await
 				}
+				Some(args::TunnelSubcommand::Doctor(doctor_args)) => {
+					tunnels::doctor(context, doctor_args).await
+				}
+				#[cfg(feature = "test-relay")]
+				Some(args::TunnelSubcommand::TestRelay(relay_args)) => {
+					tunnels::test_relay(context, relay_args).await
+				}
 				None => tunnels::serve(context, tunnel_args.serve_args).await,
 			},
 		},
 	};
 
 	match result {
+		Err(e) if json => print_json_and_exit(e),
+		#[cfg(windows)]
+		Err(AnyError::WindowsNeedsElevation(_)) if elevate => {
+			match cli::util::elevation::relaunch_elevated() {
+				Ok(code) => std::process::exit(code),
+				Err(e) => print_and_exit(e),
+			}
+		}
 		Err(e) => print_and_exit(e),
 		Ok(code) => std::process::exit(code),
 	}
@@ -117,14 +351,80 @@ fn make_logger(core: &args::CliCore) -> own_log::Logger {
 
 	let tracer = SdkTracerProvider::builder().build().tracer("codecli");
 	let mut log = own_log::Logger::new(tracer, log_level);
+	// stdout is kept for the JSON result, or for JSON progress events
+	if core.global_options.json || core.global_options.progress == args::ProgressFormat::Json {
+		log = log.to_stderr(log_level);
+	}
 	if let Some(f) = &core.global_options.log_to_file {
 		log =
 			log.tee(own_log::FileLogSink::new(log_level, f).expect("expected to make file logger"))
 	}
+	if let Some(f) = &core.global_options.elevated_output {
+		// if this fails, the output's still in the elevated console window
+		if let Ok(sink) = own_log::FileLogSink::new(log_level, f) {
+			log = log.tee(sink.with_results());
+		}
+	}
 
 	log
 }
 
+/// Brings a data directory written by an older CLI up to date. Errors are
+/// logged rather than failing the command, since the migration can be run
+/// again with `state migrate`.
+fn migrate_state(context: &CommandContext) {
+	let current = migrations::current_version(&context.paths);
+	if current > migrations::latest_version() {
+		context.log.emit(
+			own_log::Level::Warn,
+			&format!(
+				"The data directory was migrated by a newer CLI (to version {}). Use that CLI to run `state restore` if this one doesn't work with it.",
+				current
+			),
+		);
+		return;
+	}
+
+	// a restored backup is kept as it is until it's migrated on purpose
+	if migrations::was_restored(&context.paths) {
+		if !migrations::pending(&context.paths).is_empty() {
+			context.log.emit(
+				own_log::Level::Info,
+				"Not migrating the data directory since a backup was restored. Run `state migrate` to migrate it.",
+			);
+		}
+		return;
+	}
+
+	if let Err(e) = migrations::migrate(&context.log, &context.paths) {
+		context.log.emit(
+			own_log::Level::Warn,
+			&format!(
+				"Error migrating the data directory, run `state migrate` to retry: {}",
+				e
+			),
+		);
+	}
+}
+
+/// Applies the update endpoint from flags or the environment, falling back
+/// to the config file, over the one the CLI was built with.
+fn configure_update_endpoint(context: &CommandContext) {
+	let options = &context.args.global_options;
+	let config = context.paths.config();
+	if let Some(url) = options
+		.update_endpoint
+		.as_deref()
+		.or(config.update_endpoint.as_deref())
+	{
+		let layout = options
+			.update_endpoint_layout
+			.or(config.update_endpoint_layout)
+			.unwrap_or(UpdateEndpointLayout::Service);
+		set_update_endpoint(url, layout);
+	}
+}
+
 fn print_and_exit<E>(err: E) -> !
 where
 	E: std::fmt::Display,
@@ -133,6 +433,17 @@ where
 	std::process::exit(1);
 }
 
+/// Prints the error on stderr and, for tools reading the result with
+/// `--json`, as JSON on stdout.
+fn print_json_and_exit(err: AnyError) -> ! {
+	eprint!(
+		"{}",
+		own_log::format(own_log::Level::Error, "", &err.to_string())
+	);
+	println!("{}", serde_json::json!({ "error": err.to_string() }));
+	std::process::exit(1);
+}
+
 async fn start_code(context: CommandContext, args: Vec<String>) -> Result<i32, AnyError> {
 	// todo: once the integrated CLI takes the place of the Node.js CLI, this should
 	// redirect to the current installation without using the CodeVersionManager.
@@ -4,9 +4,17 @@
  *--------------------------------------------------------------------------------------------*/
 
 mod context;
+mod output;
 
+pub mod apply;
 pub mod args;
+pub mod command_shell;
+pub mod experiments;
+pub mod release;
+pub mod server;
+pub mod state;
 pub mod tunnels;
 pub mod update;
 pub mod version;
+pub(crate) use context::update_cache_for;
 pub use context::CommandContext;
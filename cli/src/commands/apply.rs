@@ -0,0 +1,528 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! `tunnel apply`, which brings this machine's tunnel setup in line with a
+//! YAML file describing it, so that configuration management tools can manage
+//! tunnel hosts like any other resource. Only what the file gives is managed,
+//! and applying the same file again changes nothing.
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fmt,
+	fs::read_to_string,
+	path::Path,
+};
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::{
+	auth::Auth,
+	options::Quality,
+	state::check_config_source,
+	tunnels::{
+		create_service_manager,
+		dev_tunnels::{DevTunnels, PortSetting, PortVisibility},
+		forward_targets::ForwardSpec,
+		ip_filter::Cidr,
+		load_service_registration,
+		paths::{get_session_env, set_session_env},
+		save_service_registration, ServiceManager,
+	},
+	util::{
+		errors::{wrap, AnyError, InvalidApplyFile},
+		tempfile::write_file_atomic,
+	},
+};
+
+use super::{
+	args::{ServerSelectionArgs, TunnelApplyArgs, TunnelServiceArgs},
+	tunnels::install_service,
+	CommandContext,
+};
+
+/// The tunnel setup described by the file. Anything left out is left as it is.
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct DesiredSetup {
+	/// Name of the tunnel.
+	name: Option<String>,
+	/// Whether the tunnel service is installed, and what it runs with.
+	service: Option<DesiredService>,
+	/// Ports on this machine forwarded when the tunnel starts, replacing any
+	/// forwarded now.
+	ports: Option<Vec<DesiredPort>>,
+	/// Visibility of ports that don't give their own.
+	visibility: Option<PortVisibility>,
+	/// Commands run as the tunnel starts and stops, checked along with the
+	/// rest of config.json.
+	hooks: Option<Map<String, Value>>,
+	/// Variables set in the environment of servers, replacing any set now.
+	env: Option<BTreeMap<String, String>>,
+	/// Settings in config.json. Settings not given are left as they are.
+	config: Option<Map<String, Value>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct DesiredPort {
+	port: u16,
+	label: Option<String>,
+	visibility: Option<PortVisibility>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct DesiredService {
+	#[serde(default = "default_installed")]
+	installed: bool,
+	quality: Option<String>,
+	commit: Option<String>,
+	/// Ports to forward, like '5432:db.internal:5432'.
+	#[serde(default)]
+	forward: Vec<String>,
+	/// Address ranges clients must connect from, like '10.0.0.0/8'.
+	#[serde(default)]
+	allow_ip: Vec<String>,
+	/// Address ranges clients may not connect from.
+	#[serde(default)]
+	deny_ip: Vec<String>,
+}
+
+fn default_installed() -> bool {
+	true
+}
+
+/// The tunnel setup this machine has now.
+#[derive(Default)]
+struct CurrentSetup {
+	name: Option<String>,
+	/// Arguments the registered service runs with, if it's registered.
+	service: Option<Vec<String>>,
+	env: BTreeMap<String, String>,
+	config: Map<String, Value>,
+}
+
+/// A change needed to bring the machine in line with the file.
+enum Change {
+	Rename {
+		from: Option<String>,
+		to: String,
+	},
+	InstallService {
+		service: TunnelServiceArgs,
+		reinstall: bool,
+	},
+	UninstallService,
+	SetEnv {
+		key: String,
+		from: Option<String>,
+		to: Option<String>,
+	},
+	SetConfig {
+		key: String,
+		from: Option<Value>,
+		to: Value,
+	},
+}
+
+impl fmt::Display for Change {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Change::Rename { from: None, to } => write!(f, "+ name: {}", to),
+			Change::Rename {
+				from: Some(from),
+				to,
+			} => write!(f, "~ name: {} -> {}", from, to),
+			Change::InstallService { service, reinstall } => {
+				let mut args = vec![];
+				service.add_run_args(&mut args);
+				write!(
+					f,
+					"{} service: {}",
+					if *reinstall { "~" } else { "+" },
+					if args.is_empty() {
+						"installed".to_string()
+					} else {
+						format!("installed with {}", args.join(" "))
+					}
+				)
+			}
+			Change::UninstallService => write!(f, "- service"),
+			Change::SetEnv { key, from, to } => match (from, to) {
+				(None, Some(to)) => write!(f, "+ env.{}: {}", key, to),
+				(Some(from), Some(to)) => write!(f, "~ env.{}: {} -> {}", key, from, to),
+				_ => write!(f, "- env.{}", key),
+			},
+			Change::SetConfig { key, from, to } => match from {
+				Some(from) => write!(f, "~ config.{}: {} -> {}", key, from, to),
+				None => write!(f, "+ config.{}: {}", key, to),
+			},
+		}
+	}
+}
+
+/// Brings the machine's tunnel setup in line with the file, after listing the
+/// changes needed.
+pub async fn apply(ctx: CommandContext, args: TunnelApplyArgs) -> Result<i32, AnyError> {
+	let desired = read_desired_setup(&args.file)?;
+	let auth = Auth::new(&ctx.paths, ctx.log.clone());
+	let mut dt = DevTunnels::new(&ctx.log, auth, &ctx.paths);
+	let current = CurrentSetup {
+		name: dt.current_tunnel_name(),
+		service: load_service_registration(&ctx.paths).map(|r| service_run_args(&r.args)),
+		env: get_session_env(&ctx.paths),
+		config: read_config(&ctx.paths.config_file())?,
+	};
+
+	let config_file = ctx.paths.config_file();
+	let changes = plan(&desired, &current, &config_file)?;
+	if changes.is_empty() {
+		ctx.print_change(false, "The tunnel setup already matches, nothing to change")?;
+		return Ok(0);
+	}
+
+	for change in &changes {
+		ctx.log.result(change.to_string());
+	}
+	if args.dry_run {
+		ctx.print_change(
+			false,
+			format!("Dry run, {} change(s) needed", changes.len()),
+		)?;
+		return Ok(0);
+	}
+
+	// settings first, since the service reads them when it starts
+	if let Some(config) = desired_config(&desired)? {
+		let mut merged = current.config.clone();
+		merged.extend(config);
+		write_config(
+			&config_file,
+			&serde_json::to_string_pretty(&Value::Object(merged)).unwrap(),
+		)?;
+	}
+
+	for change in changes.iter() {
+		match change {
+			Change::Rename { to, .. } => dt.rename_tunnel(to).await?,
+			Change::InstallService { service, .. } => {
+				let manager = create_service_manager(ctx.log.clone(), &ctx.paths);
+				install_service(&ctx, &manager, service).await?;
+			}
+			Change::UninstallService => {
+				let manager = create_service_manager(ctx.log.clone(), &ctx.paths);
+				manager.unregister().await?;
+				save_service_registration(&ctx.paths, None)?;
+			}
+			Change::SetEnv { .. } | Change::SetConfig { .. } => {}
+		}
+	}
+
+	if let Some(env) = &desired.env {
+		if changes.iter().any(|c| matches!(c, Change::SetEnv { .. })) {
+			set_session_env(&ctx.paths, env.clone())?;
+		}
+	}
+
+	ctx.print_change(true, format!("Made {} change(s)", changes.len()))?;
+	Ok(0)
+}
+
+fn read_desired_setup(path: &Path) -> Result<DesiredSetup, AnyError> {
+	let s = read_to_string(path)
+		.map_err(|e| InvalidApplyFile(format!("{}: could not be read: {}", path.display(), e)))?;
+	serde_yaml::from_str(&s)
+		.map_err(|e| InvalidApplyFile(format!("{}: {}", path.display(), e)).into())
+}
+
+fn read_config(path: &Path) -> Result<Map<String, Value>, AnyError> {
+	let s = match read_to_string(path) {
+		Ok(s) => s,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Map::new()),
+		Err(e) => return Err(wrap(e, "error reading config.json").into()),
+	};
+
+	match serde_json::from_str(&s) {
+		Ok(Value::Object(m)) => Ok(m),
+		_ => Err(InvalidApplyFile(format!(
+			"{} isn't valid, so its settings can't be changed",
+			path.display()
+		))
+		.into()),
+	}
+}
+
+/// Writes config.json through a temporary file, so the tunnel never reads
+/// it half written.
+fn write_config(path: &Path, contents: &str) -> Result<(), AnyError> {
+	write_file_atomic(path, contents.as_bytes())
+		.map_err(|e| wrap(e, "error writing config.json").into())
+}
+
+/// Gets the settings the file sets in config.json, from its `config` and
+/// the sections stored there.
+fn desired_config(desired: &DesiredSetup) -> Result<Option<Map<String, Value>>, AnyError> {
+	let mut config = desired.config.clone();
+	let mut set = |key: &str, value: Value| {
+		let config = config.get_or_insert_with(Map::new);
+		if config.contains_key(key) {
+			return Err(InvalidApplyFile(format!(
+				"config.{} can't be given along with {}",
+				key, key
+			)));
+		}
+		config.insert(key.to_string(), value);
+		Ok(())
+	};
+
+	match &desired.ports {
+		Some(ports) => {
+			let default_visibility = desired.visibility.unwrap_or_default();
+			let ports: Vec<PortSetting> = ports
+				.iter()
+				.map(|p| PortSetting {
+					port: p.port,
+					label: p.label.clone(),
+					visibility: p.visibility.unwrap_or(default_visibility),
+				})
+				.collect();
+			set("ports", serde_json::to_value(ports).unwrap())?;
+		}
+		None if desired.visibility.is_some() => {
+			return Err(
+				InvalidApplyFile("visibility is given without any ports".to_string()).into(),
+			)
+		}
+		None => {}
+	}
+	if let Some(hooks) = &desired.hooks {
+		set("hooks", Value::Object(hooks.clone()))?;
+	}
+
+	Ok(config)
+}
+
+/// Gets the arguments the service runs `internal-run` with, from those it
+/// was registered with.
+fn service_run_args(args: &[String]) -> Vec<String> {
+	match args.iter().position(|a| a == "internal-run") {
+		Some(i) => args[i + 1..].to_vec(),
+		None => vec![],
+	}
+}
+
+/// Lists the changes needed to go from the current setup to the desired one,
+/// checking the desired one is valid.
+fn plan(
+	desired: &DesiredSetup,
+	current: &CurrentSetup,
+	config_file: &Path,
+) -> Result<Vec<Change>, AnyError> {
+	let mut changes = vec![];
+
+	if let Some(config) = &desired_config(desired)? {
+		let mut merged = current.config.clone();
+		merged.extend(config.clone());
+		check_config_source(
+			config_file,
+			&serde_json::to_string_pretty(&Value::Object(merged)).unwrap(),
+		)
+		.map_err(InvalidApplyFile)?;
+
+		for (key, to) in config {
+			let from = current.config.get(key);
+			if from != Some(to) {
+				changes.push(Change::SetConfig {
+					key: key.clone(),
+					from: from.cloned(),
+					to: to.clone(),
+				});
+			}
+		}
+	}
+
+	if let Some(env) = &desired.env {
+		let keys: BTreeSet<&String> = current.env.keys().chain(env.keys()).collect();
+		for key in keys {
+			let (from, to) = (current.env.get(key), env.get(key));
+			if from != to {
+				changes.push(Change::SetEnv {
+					key: key.clone(),
+					from: from.cloned(),
+					to: to.cloned(),
+				});
+			}
+		}
+	}
+
+	if let Some(name) = &desired.name {
+		if current.name.as_ref() != Some(name) {
+			changes.push(Change::Rename {
+				from: current.name.clone(),
+				to: name.clone(),
+			});
+		}
+	}
+
+	match &desired.service {
+		Some(s) if s.installed => {
+			let service = service_args(s)?;
+			let mut args = vec![];
+			service.add_run_args(&mut args);
+			if current.service.as_ref() != Some(&args) {
+				changes.push(Change::InstallService {
+					service,
+					reinstall: current.service.is_some(),
+				});
+			}
+		}
+		Some(_) if current.service.is_some() => changes.push(Change::UninstallService),
+		_ => {}
+	}
+
+	Ok(changes)
+}
+
+fn service_args(service: &DesiredService) -> Result<TunnelServiceArgs, AnyError> {
+	let quality = match &service.quality {
+		Some(q) => Some(
+			Quality::try_from(q.as_str())
+				.map_err(|e| InvalidApplyFile(format!("service.quality: {}", e)))?,
+		),
+		None => None,
+	};
+	let forward = service
+		.forward
+		.iter()
+		.map(|f| f.parse::<ForwardSpec>())
+		.collect::<Result<Vec<_>, _>>()
+		.map_err(|e| InvalidApplyFile(format!("service.forward: {}", e)))?;
+	let ranges = |key: &str, ranges: &[String]| {
+		ranges
+			.iter()
+			.map(|r| r.parse::<Cidr>())
+			.collect::<Result<Vec<_>, _>>()
+			.map_err(|e| InvalidApplyFile(format!("service.{}: {}", key, e)))
+	};
+
+	let args = TunnelServiceArgs {
+		server: ServerSelectionArgs {
+			quality,
+			commit: service.commit.clone(),
+		},
+		forward,
+		allow_ip: ranges("allowIp", &service.allow_ip)?,
+		deny_ip: ranges("denyIp", &service.deny_ip)?,
+	};
+	args.server.selection().check(None, None)?;
+	Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn desired(yaml: &str) -> DesiredSetup {
+		serde_yaml::from_str(yaml).unwrap()
+	}
+
+	#[test]
+	fn test_plans_changes() {
+		let file = Path::new("config.json");
+		let setup = desired(
+			"name: build-host\nservice:\n  quality: insiders\n  forward: ['5432:db.internal:5432']\nenv:\n  FOO: bar\nconfig:\n  forwardAllow: ['db.internal']\n",
+		);
+
+		let changes = plan(&setup, &CurrentSetup::default(), file).unwrap();
+		let listed: Vec<String> = changes.iter().map(|c| c.to_string()).collect();
+		assert_eq!(
+			listed,
+			vec![
+				"+ config.forwardAllow: [\"db.internal\"]",
+				"+ env.FOO: bar",
+				"+ name: build-host",
+				"+ service: installed with --quality insiders --forward 5432:db.internal:5432",
+			]
+		);
+
+		// once applied, there's nothing left to change
+		let current = CurrentSetup {
+			name: Some("build-host".to_string()),
+			service: Some(service_run_args(
+				&[
+					"--verbose",
+					"tunnel",
+					"service",
+					"internal-run",
+					"--quality",
+					"insiders",
+					"--forward",
+					"5432:db.internal:5432",
+				]
+				.map(String::from),
+			)),
+			env: [("FOO".to_string(), "bar".to_string())]
+				.into_iter()
+				.collect(),
+			config: serde_json::from_str(r#"{ "forwardAllow": ["db.internal"] }"#).unwrap(),
+		};
+		assert!(plan(&setup, &current, file).unwrap().is_empty());
+
+		assert_eq!(
+			plan(&desired("service:\n  installed: false\n"), &current, file)
+				.unwrap()
+				.iter()
+				.map(|c| c.to_string())
+				.collect::<Vec<_>>(),
+			vec!["- service"]
+		);
+	}
+
+	#[test]
+	fn test_plans_ports_and_hooks() {
+		let file = Path::new("config.json");
+		let setup = desired(
+			"visibility: public\nports:\n  - port: 3000\n    label: App\n  - port: 5432\n    visibility: private\nhooks:\n  onStart: ./register.sh\n",
+		);
+
+		let listed: Vec<String> = plan(&setup, &CurrentSetup::default(), file)
+			.unwrap()
+			.iter()
+			.map(|c| c.to_string())
+			.collect();
+		assert_eq!(
+			listed,
+			vec![
+				"+ config.hooks: {\"onStart\":\"./register.sh\"}",
+				"+ config.ports: [{\"label\":\"App\",\"port\":3000,\"visibility\":\"public\"},{\"port\":5432,\"visibility\":\"private\"}]",
+			]
+		);
+
+		let current = CurrentSetup {
+			config: desired_config(&setup).unwrap().unwrap(),
+			..Default::default()
+		};
+		assert!(plan(&setup, &current, file).unwrap().is_empty());
+	}
+
+	#[test]
+	fn test_rejects_invalid_setups() {
+		let file = Path::new("config.json");
+		let current = CurrentSetup::default();
+		assert!(serde_yaml::from_str::<DesiredSetup>("visibility: shared\n").is_err());
+		assert!(plan(&desired("visibility: public\n"), &current, file).is_err());
+		assert!(plan(
+			&desired("ports: [{ port: 3000 }]\nconfig:\n  ports: []\n"),
+			&current,
+			file
+		)
+		.is_err());
+		assert!(plan(&desired("service:\n  quality: nightly\n"), &current, file).is_err());
+		assert!(plan(&desired("service:\n  forward: ['db']\n"), &current, file).is_err());
+		assert!(plan(&desired("config:\n  notASetting: 1\n"), &current, file).is_err());
+		assert!(plan(&desired("hooks:\n  onStrat: ./x.sh\n"), &current, file).is_err());
+	}
+}
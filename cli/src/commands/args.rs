@@ -3,9 +3,22 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::{fmt, path::PathBuf};
-
-use crate::{constants, log, options, tunnels::code_server::CodeServerArgs};
+use std::{fmt, path::PathBuf, str::FromStr};
+
+use crate::{
+	constants, log, options,
+	tunnels::{
+		chaos::ChaosOptions,
+		code_server::CodeServerArgs,
+		dev_tunnels::TunnelTag,
+		forward_targets::{AllowedTarget, ForwardSpec},
+		ip_filter::Cidr,
+		maintenance::MaintenanceWindow,
+		server_routing::AlternateServer,
+		server_selection::ServerSelection,
+	},
+	update_service::Platform,
+};
 use clap::{ArgEnum, Args, Parser, Subcommand};
 use const_format::concatcp;
 
@@ -150,6 +163,24 @@ pub enum Commands {
 
 	/// Changes the version of the editor you're using.
 	Version(VersionArgs),
+
+	/// Manage the servers downloaded for tunnels and the local web UI.
+	Server(ServerArgs),
+
+	/// Maintain mirrors of releases, for networks without internet access.
+	Release(ReleaseArgs),
+
+	/// Show the experiments that turn new parts of the CLI on or off.
+	Experiments(ExperimentsArgs),
+
+	/// Upgrade the CLI's data directory to this version's format, or undo
+	/// upgrades. Other commands upgrade it automatically.
+	State(StateArgs),
+
+	/// Drive the CLI from another program using JSON-RPC messages on stdin
+	/// and stdout, one per line. Run `code command-shell` and send an
+	/// `initialize` request to list the supported methods.
+	CommandShell,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -264,6 +295,37 @@ pub enum VersionSubcommand {
 
 	/// Shows the currently configured editor version.
 	Show,
+
+	/// Pins the server version tunnels and the web UI use, instead of the
+	/// latest, until it's unpinned or pinned to another version.
+	Pin(PinVersionArgs),
+
+	/// Unpins the server version, so the latest one is used again.
+	Unpin,
+
+	/// Lists the versions available from the update service, like to pin or
+	/// pass to `--use-version`.
+	List(ListVersionArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ListVersionArgs {
+	/// Quality to list versions of. Stable and insiders versions are listed
+	/// if not given.
+	#[clap(arg_enum, long, value_name = "quality")]
+	pub quality: Option<options::Quality>,
+
+	/// Number of versions to list per quality, newest first. 0 lists all.
+	#[clap(long, default_value_t = 10)]
+	pub limit: usize,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct PinVersionArgs {
+	/// The server version to use. Can be a version number, or a quality and
+	/// commit like "stable/<commit>".
+	#[clap(value_name = "x.y.z | quality/commit")]
+	pub version: String,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -278,6 +340,175 @@ pub struct UseVersionArgs {
 	pub install_dir: Option<String>,
 }
 
+#[derive(Args, Debug, Clone)]
+pub struct ServerArgs {
+	#[clap(subcommand)]
+	pub subcommand: ServerSubcommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ServerSubcommand {
+	/// Delete old servers that aren't running. Defaults to the retention
+	/// policy in the CLI config.
+	Prune(ServerPruneArgs),
+
+	/// Switch hosts back to the server used before the current one, such as
+	/// when a new Insiders build breaks connections. The earlier server is
+	/// pinned until `version unpin`, and running servers are restarted.
+	Rollback(ServerRollbackArgs),
+
+	/// Download and install a server without starting it, so it's ready
+	/// before the first connection, such as when building machine images or
+	/// CI runners. Uses the pinned version unless one is given.
+	Prefetch(ServerPrefetchArgs),
+
+	/// Check installed servers for missing or changed files, such as from a
+	/// corrupted shared cache, against what was recorded when they were
+	/// installed. Exits with status 1 if any are damaged and not repaired.
+	Verify(ServerVerifyArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ServerPrefetchArgs {
+	/// Quality of the server to install.
+	#[clap(arg_enum, long, value_name = "quality")]
+	pub quality: Option<options::Quality>,
+
+	/// Version of the server to install, like '1.72.0', rather than the latest.
+	#[clap(long, value_name = "version", conflicts_with = "commit")]
+	pub version: Option<String>,
+
+	/// Commit of the server to install.
+	#[clap(long, value_name = "commit")]
+	pub commit: Option<String>,
+
+	/// Install the server build used for the web UI, rather than the headless one.
+	#[clap(long)]
+	pub web: bool,
+
+	/// Download the server for this platform, like 'linux-arm64', rather than
+	/// this machine's, such as when building images of another architecture.
+	/// Builds for other platforms are put in the 'platforms' folder of the
+	/// data directory, rather than installed.
+	#[clap(long, value_name = "platform")]
+	pub platform: Option<Platform>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ServerVerifyArgs {
+	/// Only check servers of this quality.
+	#[clap(arg_enum, long, value_name = "quality")]
+	pub quality: Option<options::Quality>,
+
+	/// Delete damaged servers that aren't running and download them again.
+	#[clap(long)]
+	pub repair: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ServerRollbackArgs {
+	/// Quality of the server to roll back. Defaults to that of the server
+	/// used most recently.
+	#[clap(arg_enum, long, value_name = "quality")]
+	pub quality: Option<options::Quality>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct StateArgs {
+	#[clap(subcommand)]
+	pub subcommand: StateSubcommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum StateSubcommand {
+	/// Run the migrations the data directory hasn't had yet. Files they
+	/// change are backed up first.
+	Migrate(StateMigrateArgs),
+
+	/// Undo migrations by restoring the files backed up before them. Running
+	/// another command afterwards migrates the data directory again, so use
+	/// the CLI version that wrote the files.
+	Restore(StateRestoreArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct StateMigrateArgs {
+	/// List the migrations that would run, and the backups that can be
+	/// restored, without changing anything.
+	#[clap(long)]
+	pub dry_run: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct StateRestoreArgs {
+	/// Backup to restore, as listed by `state migrate --dry-run`. Restoring
+	/// one undoes its migration and those after it. Defaults to the latest.
+	#[clap(value_name = "backup")]
+	pub name: Option<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ReleaseArgs {
+	#[clap(subcommand)]
+	pub subcommand: ReleaseSubcommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ReleaseSubcommand {
+	/// Download the latest CLI, server, and web server releases into a
+	/// directory in the 'static' update endpoint layout, which can be hosted
+	/// by any web server and used with `--update-endpoint`. Run it again to
+	/// bring the mirror up to date.
+	Mirror(ReleaseMirrorArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ReleaseMirrorArgs {
+	/// Quality of the releases to mirror.
+	#[clap(arg_enum, long, value_name = "quality", default_value = "stable")]
+	pub quality: options::Quality,
+
+	/// Comma-separated platforms to mirror releases for, like
+	/// 'linux-x64,linux-arm64'.
+	#[clap(
+		long,
+		value_name = "platforms",
+		value_delimiter = ',',
+		required = true,
+		min_values = 1
+	)]
+	pub platforms: Vec<Platform>,
+
+	/// Directory the mirror is kept in.
+	#[clap(long, value_name = "dir")]
+	pub dest: PathBuf,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ExperimentsArgs {
+	#[clap(subcommand)]
+	pub subcommand: ExperimentsSubcommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ExperimentsSubcommand {
+	/// List experiments, whether they're on, and whether that was decided by
+	/// the update service or 'experiments' in config.json.
+	List(OutputFormatOptions),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ServerPruneArgs {
+	/// Number of most recently used servers to keep.
+	#[clap(long, value_name = "count")]
+	pub keep: Option<usize>,
+
+	/// Only delete servers that haven't been used within this duration,
+	/// such as '30d' or '12h'.
+	#[clap(long, value_name = "duration")]
+	pub older_than: Option<DurationArg>,
+}
+
 #[derive(Args, Debug, Default, Clone)]
 pub struct EditorOptions {
 	/// Compare two files with each other.
@@ -398,6 +629,91 @@ pub struct GlobalOptions {
 	#[clap(long, env = "VSCODE_CLI_DATA_DIR", global = true)]
 	pub cli_data_dir: Option<String>,
 
+	/// Proxy to send HTTP requests and the tunnel connection through, like
+	/// 'http://proxy:8080' or 'socks5://proxy:1080'. Basic credentials can
+	/// be given in the URL. Defaults to the proxy set in HTTPS_PROXY,
+	/// ALL_PROXY, or HTTP_PROXY. Hosts in NO_PROXY are connected to directly.
+	#[clap(
+		long,
+		alias = "proxy-url",
+		value_name = "url",
+		env = "VSCODE_CLI_PROXY",
+		global = true
+	)]
+	pub proxy: Option<String>,
+
+	/// Command that prints the Proxy-Authorization header value to send to
+	/// the proxy, for proxies that need Negotiate (Kerberos) authentication.
+	/// The proxy URL is given in the VSCODE_CLI_PROXY_URL variable.
+	#[clap(
+		long,
+		value_name = "command",
+		env = "VSCODE_CLI_PROXY_AUTH_HELPER",
+		global = true
+	)]
+	pub proxy_auth_helper: Option<String>,
+
+	/// PEM file of CA certificates to trust for HTTPS requests, in addition
+	/// to the built-in ones, such as when TLS is intercepted by a corporate
+	/// proxy. Overrides 'tlsCaFile' in config.json.
+	#[clap(
+		long,
+		value_name = "file",
+		env = "VSCODE_CLI_TLS_CA_FILE",
+		global = true
+	)]
+	pub tls_ca_file: Option<PathBuf>,
+
+	/// PEM file with a client certificate to present for HTTPS requests and
+	/// the tunnel's relay connection, and its PKCS#8 private key unless given
+	/// in `--tls-client-key`. Overrides
+	/// 'tlsClientCert' in config.json.
+	#[clap(
+		long,
+		value_name = "file",
+		env = "VSCODE_CLI_TLS_CLIENT_CERT",
+		global = true
+	)]
+	pub tls_client_cert: Option<PathBuf>,
+
+	/// PEM file with the PKCS#8 private key of `--tls-client-cert`. Overrides
+	/// 'tlsClientKey' in config.json.
+	#[clap(
+		long,
+		value_name = "file",
+		env = "VSCODE_CLI_TLS_CLIENT_KEY",
+		global = true,
+		requires = "tls-client-cert"
+	)]
+	pub tls_client_key: Option<PathBuf>,
+
+	/// Seconds to wait for HTTP connections, like to the update service, to
+	/// be established. Overrides 'httpConnectTimeout' in config.json.
+	/// Defaults to 15; 0 waits indefinitely.
+	#[clap(
+		long,
+		value_name = "seconds",
+		env = "VSCODE_CLI_HTTP_CONNECT_TIMEOUT",
+		global = true
+	)]
+	pub http_connect_timeout: Option<u64>,
+
+	/// Seconds to wait for the response to an HTTP request, or for more of a
+	/// download, once connected. Overrides 'httpReadTimeout' in config.json.
+	/// Defaults to 60; 0 waits indefinitely.
+	#[clap(
+		long,
+		value_name = "seconds",
+		env = "VSCODE_CLI_HTTP_READ_TIMEOUT",
+		global = true
+	)]
+	pub http_read_timeout: Option<u64>,
+
+	/// Directory where temporary files, such as downloads being extracted,
+	/// should be created. Defaults to the system temp directory.
+	#[clap(long, env = "VSCODE_CLI_TEMP_DIR", global = true)]
+	pub temp_dir: Option<String>,
+
 	/// Print verbose output (implies --wait).
 	#[clap(long, global = true)]
 	pub verbose: bool,
@@ -410,6 +726,170 @@ pub struct GlobalOptions {
 	#[clap(long, arg_enum, value_name = "level", global = true)]
 	pub log: Option<log::Level>,
 
+	/// Print plain, sequential output without colors, progress bars, or
+	/// redrawn lines. Used automatically when NO_COLOR is set or TERM=dumb.
+	#[clap(long, alias = "no-color", global = true)]
+	pub plain: bool,
+
+	/// Print results as JSON on stdout, with logs on stderr. Commands that
+	/// change this machine's setup print whether they changed anything and
+	/// the IDs of its tunnel, service, and host.
+	#[clap(long, global = true)]
+	pub json: bool,
+
+	/// Answer yes to confirmation prompts, and use defaults for other
+	/// prompts, so commands can run unattended. Doesn't accept license terms.
+	#[clap(long, short = 'y', global = true)]
+	pub yes: bool,
+
+	/// How to show the progress of downloads and installs. 'json' prints
+	/// events to stdout, one per line, for tools that show their own progress,
+	/// and logs and results to stderr.
+	#[clap(
+		long,
+		arg_enum,
+		value_name = "format",
+		default_value_t = ProgressFormat::Bar,
+		global = true
+	)]
+	pub progress: ProgressFormat,
+
+	/// Always request version information from the update service, rather
+	/// than using recently cached responses.
+	#[clap(long, global = true)]
+	pub no_cache: bool,
+
+	/// Don't ask the update service for the latest versions of the CLI and
+	/// server, using the versions it last reported instead. Useful when it's
+	/// unreachable or rate limited.
+	#[clap(long, global = true, conflicts_with = "no-cache")]
+	pub no_update_check: bool,
+
+	/// Priority at which to run maintenance work, like extracting and pruning
+	/// servers. 'low' reduces its CPU and IO priority.
+	#[clap(
+		long,
+		arg_enum,
+		value_name = "priority",
+		env = "VSCODE_CLI_MAINTENANCE_PRIORITY",
+		global = true
+	)]
+	pub maintenance_priority: Option<options::MaintenancePriority>,
+
+	/// Number of connections to download large archives, like servers, over
+	/// at once, when the server supports it. Defaults to 4; 1 downloads over a
+	/// single connection.
+	#[clap(
+		long,
+		value_name = "count",
+		env = "VSCODE_CLI_DOWNLOAD_CONNECTIONS",
+		global = true
+	)]
+	pub download_connections: Option<usize>,
+
+	/// Number of times requests to the update service are attempted when
+	/// they fail in a way that may be transient, like a 503 response or a
+	/// failed DNS lookup. Overrides 'updateRetryAttempts' in config.json.
+	/// Defaults to 4; 1 doesn't retry.
+	#[clap(
+		long,
+		value_name = "count",
+		env = "VSCODE_CLI_UPDATE_RETRY_ATTEMPTS",
+		global = true
+	)]
+	pub update_retry_attempts: Option<u32>,
+
+	/// URL of the update service to resolve and download the CLI and servers
+	/// from, such as an internal mirror. Overrides 'updateEndpoint' in the
+	/// config.json file in the CLI data directory.
+	#[clap(
+		long,
+		value_name = "url",
+		env = "VSCODE_CLI_UPDATE_ENDPOINT",
+		global = true
+	)]
+	pub update_endpoint: Option<String>,
+
+	/// Layout of URLs on the update endpoint: 'service' for the update
+	/// service API, or 'static' for a mirror of plain files. Overrides
+	/// 'updateEndpointLayout' in config.json. Defaults to 'service'.
+	#[clap(
+		long,
+		arg_enum,
+		value_name = "layout",
+		env = "VSCODE_CLI_UPDATE_ENDPOINT_LAYOUT",
+		global = true
+	)]
+	pub update_endpoint_layout: Option<options::UpdateEndpointLayout>,
+
+	/// Whether to patch downloaded servers with patchelf so they run on
+	/// distributions without the usual filesystem layout, like NixOS. 'auto'
+	/// patches them if their interpreter is missing. Overrides
+	/// 'serverBinaryFixup' in config.json.
+	#[clap(
+		long,
+		arg_enum,
+		value_name = "mode",
+		env = "VSCODE_CLI_SERVER_BINARY_FIXUP",
+		global = true
+	)]
+	pub server_binary_fixup: Option<options::ServerBinaryFixup>,
+
+	/// On Windows ARM64 machines, use x64 builds of VS Code and its server,
+	/// which run under emulation. Useful if an extension doesn't support ARM64.
+	#[clap(long, env = "VSCODE_CLI_FORCE_X64", global = true)]
+	pub force_x64: bool,
+
+	/// Record the sessions this CLI relays, like connections to the VS Code
+	/// server with its terminals, SSH bridge connections and command-shell
+	/// sessions, into the data directory. Sessions are refused if they can't
+	/// be recorded.
+	#[clap(long, env = "VSCODE_CLI_RECORD_SESSIONS", global = true)]
+	pub record_sessions: bool,
+
+	/// How long to keep session recordings for, such as '90d'. Defaults to
+	/// 30 days.
+	#[clap(
+		long,
+		value_name = "duration",
+		env = "VSCODE_CLI_SESSION_RETENTION",
+		global = true
+	)]
+	pub session_retention: Option<DurationArg>,
+
+	/// age public key, like 'age1...', to encrypt session recordings to, so
+	/// they can only be read with the matching identity.
+	#[clap(
+		long,
+		value_name = "key",
+		env = "VSCODE_CLI_RECORDING_RECIPIENT",
+		global = true,
+		requires = "record-sessions"
+	)]
+	pub recording_recipient: Option<String>,
+
+	/// Refuse downloads of the CLI and server that aren't signed with the key
+	/// built into the CLI. Otherwise, only downloads whose signature doesn't
+	/// match are refused. Can also be set with 'requireSigned' in config.json.
+	#[clap(long, env = "VSCODE_CLI_REQUIRE_SIGNED", global = true)]
+	pub require_signed: bool,
+
+	/// Fail to start if config.json can't be read, or has unknown settings,
+	/// including within settings like 'hooks' and 'ports', or invalid values,
+	/// rather than ignoring them. Can also be set with 'strictConfig' in
+	/// config.json.
+	#[clap(long, env = "VSCODE_CLI_STRICT_CONFIG", global = true)]
+	pub strict_config: bool,
+
+	/// On Windows, if the command needs administrator rights, run it again as
+	/// an administrator after a UAC prompt, rather than printing instructions.
+	#[clap(long, global = true)]
+	pub elevate: bool,
+
+	/// File to write the output of a command run by `--elevate` to.
+	#[clap(long, global = true, hide = true)]
+	pub elevated_output: Option<PathBuf>,
+
 	/// Disable telemetry for the current command, even if it was previously
 	/// accepted as part of the license prompt or specified in '--telemetry-level'
 	#[clap(long, global = true, hide = true)]
@@ -530,6 +1010,27 @@ pub enum OutputFormat {
 	Text,
 }
 
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressFormat {
+	Bar,
+	Json,
+}
+
+impl Default for ProgressFormat {
+	fn default() -> Self {
+		ProgressFormat::Bar
+	}
+}
+
+impl fmt::Display for ProgressFormat {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ProgressFormat::Bar => write!(f, "bar"),
+			ProgressFormat::Json => write!(f, "json"),
+		}
+	}
+}
+
 #[derive(Args, Clone, Debug, Default)]
 pub struct ExistingTunnelArgs {
 	/// Name you'd like to assign preexisting tunnel to use to connect the tunnel
@@ -570,6 +1071,230 @@ pub struct TunnelServeArgs {
 	/// If set, the user accepts the server license terms and the server will be started without a user prompt.
 	#[clap(long)]
 	pub accept_server_license_terms: bool,
+
+	/// Also serve the web UI on this port on the local network, from the same
+	/// process and server installation as the tunnel.
+	#[clap(long, value_name = "port")]
+	pub local_web_port: Option<u16>,
+
+	/// Host the local web UI listens on, when `--local-web-port` is given.
+	#[clap(long, value_name = "host", default_value = "127.0.0.1")]
+	pub local_web_host: String,
+
+	/// Quality of the server used for the local web UI.
+	#[clap(arg_enum, long, value_name = "quality")]
+	pub local_web_quality: Option<options::Quality>,
+
+	#[clap(flatten)]
+	pub server: ServerSelectionArgs,
+
+	/// Whether the local web UI requires a connection token. 'none' is only
+	/// allowed on loopback hosts, like behind your own authenticating proxy;
+	/// 'rotate-per-start' uses a new token each time the server starts; and
+	/// 'fixed-from-file' keeps the token in a file. Defaults to
+	/// 'fixed-from-file'.
+	#[clap(arg_enum, long, value_name = "mode")]
+	pub local_web_connection_token: Option<options::ConnectionTokenMode>,
+
+	/// File to read the local web UI's connection token from, in the
+	/// 'fixed-from-file' mode. It's created with a new token if it doesn't
+	/// exist. Defaults to a file in the CLI data directory.
+	#[clap(long, value_name = "file")]
+	pub local_web_connection_token_file: Option<PathBuf>,
+
+	/// Only apply updates and restart, disrupting sessions, during this window
+	/// in local time, like 'sun 03:00-04:00' or '02:00-04:00' for every day.
+	/// May be given multiple times, and adds to 'maintenanceWindows' in
+	/// config.json. Clients can still force an update at other times.
+	#[clap(long, value_name = "window")]
+	pub maintenance_window: Vec<MaintenanceWindow>,
+
+	/// Tell connecting clients this folder is trusted, so they don't prompt
+	/// for workspace trust when opening it or folders inside it. May be given
+	/// multiple times, and adds to 'trustedFolders' in config.json.
+	#[clap(long, value_name = "path")]
+	pub trusted_folder: Vec<PathBuf>,
+
+	/// Deny clients access to files outside this folder, by jailing the
+	/// server to it as `--jail` does. Overrides 'workspaceRoot' in
+	/// config.json.
+	#[clap(long, value_name = "path")]
+	pub workspace_root: Option<PathBuf>,
+
+	/// Folder clients open by default, in the link printed for the tunnel.
+	/// Ports its devcontainer.json asks to forward are forwarded when the
+	/// tunnel starts, with labels and visibility from its 'portsAttributes'
+	/// and .vscode/settings.json. Overrides 'defaultFolder' in config.json.
+	#[clap(long, value_name = "path")]
+	pub default_folder: Option<PathBuf>,
+
+	/// Let the default folder's settings make its ports public. Otherwise
+	/// they're forwarded privately, whatever the folder asks. Also set by
+	/// 'allowPublicFolderPorts' in config.json.
+	#[clap(long)]
+	pub allow_public_folder_ports: bool,
+
+	/// Confine the server, including its terminals and extensions, to this
+	/// folder, such as a single project for contractors. The server starts
+	/// there as its home directory, and clients are limited to files in it.
+	#[clap(long, value_name = "path")]
+	pub jail: Option<PathBuf>,
+
+	/// How the jail, or workspace root, is enforced. 'auto' sandboxes the
+	/// server with Landlock where the kernel supports it.
+	#[clap(long, arg_enum, value_name = "mode", default_value = "auto")]
+	pub jail_sandbox: options::JailSandbox,
+
+	/// Install the server from this archive, such as a server .tar.gz,
+	/// .tar.zst, or .tar.xz copied to a machine without internet access,
+	/// rather than downloading it. Its commit and quality are read from the
+	/// archive.
+	#[clap(long, value_name = "path")]
+	pub install_server_from: Option<PathBuf>,
+
+	/// On startup, delete other tunnels registered under your account whose
+	/// hosts haven't connected within this duration, such as '30d'.
+	#[clap(long, value_name = "duration")]
+	pub gc_older_than: Option<DurationArg>,
+
+	/// Open the link to the tunnel in your default browser once it's ready.
+	#[clap(long)]
+	pub open: bool,
+
+	/// Copy the link to the tunnel to your clipboard once it's ready.
+	#[clap(long)]
+	pub copy_url: bool,
+
+	/// If the server hasn't been used on this machine yet, apply your
+	/// settings, keybindings, and extensions from Settings Sync to it.
+	#[clap(long)]
+	pub bootstrap_settings_sync: bool,
+
+	/// Git repository of dotfiles to clone into ~/dotfiles and install the
+	/// first time the tunnel is hosted on this machine.
+	#[clap(long, value_name = "url")]
+	pub dotfiles_repo: Option<String>,
+
+	/// Command to install the dotfiles with, run in the cloned repository.
+	/// By default an install script like 'install.sh' is run if present, and
+	/// otherwise dotfiles are linked into your home directory.
+	#[clap(long, value_name = "command", requires = "dotfiles-repo")]
+	pub dotfiles_install_command: Option<String>,
+
+	/// Only accept connections from clients with an address in this range,
+	/// like '10.0.0.0/8', on every port of the tunnel. Forwarded ports are
+	/// then served over HTTP, so the address of each request can be checked.
+	/// Addresses are those the relay saw, and since it doesn't report them
+	/// for editor connections, those are turned away while this is set.
+	/// May be given multiple times, and adds to 'allowIp' in config.json.
+	#[clap(long, value_name = "cidr")]
+	pub allow_ip: Vec<Cidr>,
+
+	/// Reject connections from clients with an address in this range, even
+	/// if it's allowed. May be given multiple times, and adds to 'denyIp' in
+	/// config.json.
+	#[clap(long, value_name = "cidr")]
+	pub deny_ip: Vec<Cidr>,
+
+	/// Forward a tunnel port to a host reachable from this machine, like
+	/// '5432:db.internal:5432', so clients can use the tunnel as a jump
+	/// host. The host must be allowed with `--forward-allow`. May be given
+	/// multiple times.
+	#[clap(long, value_name = "port:host:port")]
+	pub forward: Vec<ForwardSpec>,
+
+	/// Allow ports to be forwarded to this host, to hosts under a domain
+	/// like '*.internal', or to addresses in a range like '10.0.0.0/8'.
+	/// Without this, ports can only be forwarded to this machine. May be
+	/// given multiple times.
+	#[clap(long, value_name = "host|cidr")]
+	pub forward_allow: Vec<AllowedTarget>,
+
+	/// Host an alternate server build side by side with the usual one, like
+	/// 'insiders' or 'insiders=<commit>'. Clients get it if they ask for its
+	/// quality, like with '?quality=insiders' on the link, or if they're
+	/// listed in `--alternate-server-for`, unless they ask for a specific
+	/// commit, like desktop VS Code does.
+	#[clap(long, value_name = "quality[=commit]")]
+	pub alternate_server: Option<AlternateServer>,
+
+	/// Always serve the alternate server to clients signed in as this
+	/// identity, like an account name. The identity is reported by clients, so
+	/// this isn't a way to restrict access. May be given multiple times.
+	#[clap(long, value_name = "identity", requires = "alternate-server")]
+	pub alternate_server_for: Vec<String>,
+
+	/// Share only the local port given in `--port` through a temporary tunnel
+	/// that viewers can open without signing in. Viewers can only make
+	/// read-only requests, and bandwidth is limited. The tunnel is deleted
+	/// when it expires.
+	#[clap(long, requires = "port")]
+	pub anonymous: bool,
+
+	/// Port to share with `--anonymous`.
+	#[clap(long, value_name = "port", requires = "anonymous")]
+	pub port: Option<u16>,
+
+	/// How long to share the port with `--anonymous` for, such as '30m'.
+	/// Defaults to 30 minutes, and can be at most a day.
+	#[clap(long, value_name = "duration", requires = "anonymous")]
+	pub expires: Option<DurationArg>,
+
+	/// Tag the tunnel with metadata like 'team=infra', which can be used to
+	/// filter tunnels in `tunnel ls --tag`. May be given multiple times, and
+	/// replaces the tunnel's existing tags when given.
+	#[clap(long, value_name = "key=value")]
+	pub tag: Vec<TunnelTag>,
+
+	/// Expose the SSH server listening on this local port through the tunnel,
+	/// so clients can reach it using `code tunnel ssh-config`.
+	#[clap(long, value_name = "port")]
+	pub ssh_port: Option<u16>,
+
+	/// Let clients have ports on this machine connect back to services on
+	/// their own machines, using `code tunnel forward --reverse`. Ports only
+	/// listen on this machine's loopback address.
+	#[clap(long)]
+	pub allow_reverse_forward: bool,
+
+	/// Let other clients on this machine, like desktop VS Code, use this
+	/// tunnel and connect to the named tunnel with its login, rather than
+	/// logging in themselves. Only your user can connect. If this runs in a
+	/// terminal, you're asked about other tunnels clients want. May be given
+	/// multiple times, and adds to 'shareSession' in config.json.
+	#[clap(long, value_name = "tunnel")]
+	pub share_session: Vec<String>,
+
+	/// Check for new servers this often, such as '6h', installing them ahead
+	/// of time and stopping servers of older builds once no clients have
+	/// connected for 3 hours, so disconnected clients can still get back to
+	/// their sessions, within the maintenance windows if any are given.
+	/// Servers of commits clients asked for are left running. Also set by
+	/// 'serverUpdateInterval' in config.json.
+	#[clap(long, value_name = "duration")]
+	pub server_update_interval: Option<DurationArg>,
+
+	/// Longest to wait between attempts to reconnect to the relay while it's
+	/// unreachable, such as '5m'. Defaults to 2 minutes.
+	#[clap(long, value_name = "duration")]
+	pub relay_retry_max: Option<DurationArg>,
+
+	/// Set when the process embedding the CLI restarts it, rather than the
+	/// CLI respawning itself after an update.
+	#[clap(skip)]
+	pub no_respawn: bool,
+
+	/// For development: inject faults into tunnel connections to test behavior
+	/// on bad networks, e.g. 'latency=200ms,jitter=50ms,drop=1%,reorder=1%'.
+	#[clap(long, hide = true, value_name = "faults")]
+	pub chaos: Option<ChaosOptions>,
+
+	/// For tests: host the tunnel through the fake relay listening at the
+	/// address, started with `tunnel test-relay`, rather than the port
+	/// forwarding service. No credentials or network are needed.
+	#[cfg(feature = "test-relay")]
+	#[clap(long, hide = true, value_name = "addr")]
+	pub test_relay: Option<String>,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -586,24 +1311,180 @@ pub enum TunnelSubcommand {
 	/// Delete all servers which are currently not running.
 	Prune,
 
+	/// Show the health of the credentials and relay connection used to host
+	/// the tunnel, and the host's load, free memory, and free disk space.
+	Status,
+
+	/// Stop the VS Code server started for clients, such as when it's stopped
+	/// responding, without stopping the tunnel. Clients' sessions reconnect
+	/// and start it again.
+	RestartServer,
+
+	/// Show the end of the logs from the tunnel service and the servers it
+	/// started, interleaved by time.
+	Logs(TunnelLogsArgs),
+
+	/// Show connection statistics recorded while hosting the tunnel on this
+	/// machine, such as uptime and relay reconnects.
+	Stats(TunnelStatsArgs),
+
 	/// Rename the name of this machine associated with port forwarding service.
 	Rename(TunnelRenameArgs),
 
+	/// Bring this machine's tunnel setup, like its name, service, and
+	/// settings, in line with a YAML file describing it. Changes are listed
+	/// before they're made, and nothing is changed if the machine matches.
+	Apply(TunnelApplyArgs),
+
+	/// Delete tunnels registered under your account whose hosts haven't
+	/// connected recently, such as those of reimaged machines.
+	Gc(TunnelGcArgs),
+
+	/// List tunnels registered with the port forwarding service.
+	#[clap(alias = "ls")]
+	List(TunnelListArgs),
+
 	/// Remove this machine's association with the port forwarding service.
 	Unregister,
 
+	/// Print OpenSSH config with a Host block for each of your tunnels, so
+	/// ssh, scp, and rsync can reach hosts started with `--ssh-port`.
+	SshConfig(TunnelSshConfigArgs),
+
+	/// Listen on a local port for SFTP connections to a tunnel's host, for
+	/// file managers and deployment tools. The host must be started with
+	/// `--ssh-port`.
+	Sftp(TunnelSftpArgs),
+
+	/// Relays stdin and stdout to a tunnel's SSH server, for use as an SSH
+	/// ProxyCommand.
+	#[clap(hide = true)]
+	StdioBridge(TunnelStdioBridgeArgs),
+
+	/// Forward ports on a tunnel's host to services reachable from this
+	/// machine, like a local license server or database. The host must be
+	/// started with `--allow-reverse-forward`.
+	Forward(TunnelForwardArgs),
+
+	/// Show the server release that would be installed, without downloading it.
+	ServerInfo(TunnelServerInfoArgs),
+
+	/// Manage extensions installed on the server used by the tunnel.
+	Ext(TunnelExtArgs),
+
 	#[clap(subcommand)]
 	User(TunnelUserSubCommands),
 
+	/// Show or rotate the identity this machine registers its tunnel with.
+	#[clap(subcommand)]
+	Id(TunnelIdSubCommands),
+
+	/// Manage environment variables set in every server, and so every
+	/// terminal, started for clients of the tunnel on this machine.
+	#[clap(subcommand)]
+	Env(TunnelEnvSubCommands),
+
 	/// Manages the tunnel when installed as a system service,
 	#[clap(subcommand)]
 	Service(TunnelServiceSubCommands),
+
+	/// Check for problems with this machine's tunnel setup, like a service
+	/// registered to run a CLI that's since moved, and offer to fix them.
+	Doctor(TunnelDoctorArgs),
+
+	/// Runs a fake relay for integration tests, which tunnels started with
+	/// `--test-relay` are hosted through.
+	#[cfg(feature = "test-relay")]
+	#[clap(hide = true)]
+	TestRelay(TunnelTestRelayArgs),
+}
+
+#[cfg(feature = "test-relay")]
+#[derive(Args, Debug, Clone)]
+pub struct TunnelTestRelayArgs {
+	/// Address to listen on for hosts.
+	#[clap(long, default_value = "127.0.0.1:0")]
+	pub listen: String,
+}
+
+/// Options choosing the server hosts start, used by every way of hosting.
+#[derive(Args, Debug, Default, Clone)]
+pub struct ServerSelectionArgs {
+	/// Quality of the server to host, rather than the CLI's own. Conflicts
+	/// with a pinned version of another quality.
+	#[clap(arg_enum, long, value_name = "quality")]
+	pub quality: Option<options::Quality>,
+
+	/// Commit of the server to host, used over the pinned version and the
+	/// commits clients ask for.
+	#[clap(long, value_name = "sha")]
+	pub commit: Option<String>,
+}
+
+impl ServerSelectionArgs {
+	pub fn selection(&self) -> ServerSelection {
+		ServerSelection {
+			quality: self.quality,
+			commit: self.commit.as_ref().map(|c| c.to_lowercase()),
+		}
+	}
+}
+
+/// Options the tunnel service runs with, given when it's installed.
+#[derive(Args, Debug, Default, Clone)]
+pub struct TunnelServiceArgs {
+	#[clap(flatten)]
+	pub server: ServerSelectionArgs,
+
+	/// Forward a tunnel port to a host reachable from this machine, like
+	/// '5432:db.internal:5432'. The host must be allowed by 'forwardAllow' in
+	/// config.json. May be given multiple times.
+	#[clap(long, value_name = "port:host:port")]
+	pub forward: Vec<ForwardSpec>,
+
+	/// Only accept connections from clients with an address in this range,
+	/// like '10.0.0.0/8'. May be given multiple times, and adds to 'allowIp'
+	/// in config.json.
+	#[clap(long, value_name = "cidr")]
+	pub allow_ip: Vec<Cidr>,
+
+	/// Reject connections from clients with an address in this range, even
+	/// if it's allowed. May be given multiple times, and adds to 'denyIp' in
+	/// config.json.
+	#[clap(long, value_name = "cidr")]
+	pub deny_ip: Vec<Cidr>,
+}
+
+impl TunnelServiceArgs {
+	/// Adds the arguments the service's `internal-run` is given.
+	pub fn add_run_args(&self, target: &mut Vec<String>) {
+		if let Some(q) = &self.server.quality {
+			target.push("--quality".to_string());
+			target.push(q.get_machine_name().to_string());
+		}
+		if let Some(c) = &self.server.commit {
+			target.push("--commit".to_string());
+			target.push(c.clone());
+		}
+		for f in &self.forward {
+			target.push("--forward".to_string());
+			target.push(f.to_string());
+		}
+		for c in &self.allow_ip {
+			target.push("--allow-ip".to_string());
+			target.push(c.to_string());
+		}
+		for c in &self.deny_ip {
+			target.push("--deny-ip".to_string());
+			target.push(c.to_string());
+		}
+	}
 }
 
 #[derive(Subcommand, Debug, Clone)]
 pub enum TunnelServiceSubCommands {
 	/// Installs or re-installs the tunnel service on the machine.
-	Install,
+	Install(TunnelServiceArgs),
 
 	/// Uninstalls and stops the tunnel service.
 	Uninstall,
@@ -613,7 +1494,47 @@ pub enum TunnelServiceSubCommands {
 
 	/// Internal command for running the service
 	#[clap(hide = true)]
-	InternalRun,
+	InternalRun(TunnelServiceArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelSshConfigArgs {
+	/// Prefix for the Host aliases, which are followed by the tunnel name.
+	#[clap(long, default_value = "tunnel-")]
+	pub prefix: String,
+
+	/// User to log in as on the tunnel hosts.
+	#[clap(long)]
+	pub user: Option<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelSftpArgs {
+	/// Name of the tunnel to connect to.
+	pub name: String,
+
+	/// Local port to listen on.
+	#[clap(long, default_value = "2222")]
+	pub port: u16,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelStdioBridgeArgs {
+	/// Name of the tunnel to connect to.
+	pub name: String,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelForwardArgs {
+	/// Name of the tunnel to connect to.
+	pub name: String,
+
+	/// Have a port on the host connect to a host and port reachable from this
+	/// machine, like '5432:localhost:5432'. You're asked to allow each one
+	/// before it's forwarded, even with --yes, so this must be run in a
+	/// terminal. May be given multiple times.
+	#[clap(long, value_name = "port:host:port", required = true)]
+	pub reverse: Vec<ForwardSpec>,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -622,16 +1543,380 @@ pub struct TunnelRenameArgs {
 	pub name: String,
 }
 
+#[derive(Args, Debug, Clone)]
+pub struct TunnelGcArgs {
+	/// Delete tunnels whose hosts haven't connected within this duration,
+	/// such as '30d' or '12h'.
+	#[clap(long, value_name = "duration", default_value = "30d")]
+	pub older_than: DurationArg,
+
+	/// List the tunnels that would be deleted, without deleting them.
+	#[clap(long)]
+	pub dry_run: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelExtArgs {
+	#[clap(subcommand)]
+	pub subcommand: TunnelExtSubcommand,
+
+	/// Quality of the server whose extensions to manage. Defaults to the
+	/// server used most recently.
+	#[clap(arg_enum, long, value_name = "quality", global = true)]
+	pub quality: Option<options::Quality>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum TunnelExtSubcommand {
+	/// List installed extensions.
+	List(ListExtensionArgs),
+	/// Install an extension.
+	Install(InstallExtensionArgs),
+	/// Uninstall an extension.
+	Uninstall(UninstallExtensionArgs),
+	/// Update installed extensions to their latest versions.
+	Update(UpdateExtensionArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct UpdateExtensionArgs {
+	/// Identifiers of the extensions to update. If none are given, all
+	/// installed extensions are updated.
+	#[clap(name = "ext-id")]
+	pub id: Vec<String>,
+
+	/// Update to pre-release versions of the extensions.
+	#[clap(long)]
+	pub pre_release: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelServerInfoArgs {
+	/// Quality of the server to look up.
+	#[clap(arg_enum, long, value_name = "quality")]
+	pub quality: Option<options::Quality>,
+
+	/// Look up the server build used for the web UI, rather than the headless one.
+	#[clap(long)]
+	pub web: bool,
+
+	/// Look up the server for this platform, like 'linux-arm64', rather than
+	/// this machine's, such as to provision containers of another
+	/// architecture.
+	#[clap(long, value_name = "platform")]
+	pub platform: Option<Platform>,
+}
+
+/// Environment variable given on the command line as 'KEY=VALUE'.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvVarArg {
+	pub name: String,
+	pub value: String,
+}
+
+impl FromStr for EnvVarArg {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (name, value) = s
+			.split_once('=')
+			.ok_or_else(|| format!("expected a variable like 'KEY=VALUE', got '{}'", s))?;
+		if name.is_empty() || name.contains('\0') || value.contains('\0') {
+			return Err(format!("invalid variable '{}'", s));
+		}
+
+		Ok(EnvVarArg {
+			name: name.to_string(),
+			value: value.to_string(),
+		})
+	}
+}
+
+/// Longest duration accepted on the command line, 10 years.
+const MAX_DURATION_ARG_SECS: i64 = 10 * 365 * 24 * 60 * 60;
+
+/// Duration given on the command line as a number and a unit, like '30d'.
+#[derive(Debug, Clone, Copy)]
+pub struct DurationArg(pub chrono::Duration);
+
+impl FromStr for DurationArg {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.trim();
+		let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+		let (num, unit) = s.split_at(split);
+		let num = num
+			.parse::<i64>()
+			.map_err(|_| format!("expected a duration like '30d', got '{}'", s))?;
+
+		let unit_secs: i64 = match unit {
+			"s" => 1,
+			"m" => 60,
+			"h" => 60 * 60,
+			"d" | "" => 24 * 60 * 60,
+			"w" => 7 * 24 * 60 * 60,
+			_ => {
+				return Err(format!(
+					"unknown duration unit '{}', use s, m, h, d, or w",
+					unit
+				))
+			}
+		};
+
+		let secs = num
+			.checked_mul(unit_secs)
+			.filter(|secs| *secs <= MAX_DURATION_ARG_SECS)
+			.ok_or_else(|| format!("duration '{}' is too long, use at most 10 years", s))?;
+		let duration = chrono::Duration::seconds(secs);
+
+		Ok(DurationArg(duration))
+	}
+}
+
+impl fmt::Display for DurationArg {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let d = self.0;
+		if d.num_days() > 0 && d == chrono::Duration::days(d.num_days()) {
+			write!(f, "{}d", d.num_days())
+		} else if d.num_hours() > 0 && d == chrono::Duration::hours(d.num_hours()) {
+			write!(f, "{}h", d.num_hours())
+		} else if d.num_minutes() > 0 && d == chrono::Duration::minutes(d.num_minutes()) {
+			write!(f, "{}m", d.num_minutes())
+		} else {
+			write!(f, "{}s", d.num_seconds())
+		}
+	}
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelApplyArgs {
+	/// YAML file describing the tunnel setup, with any of 'name', 'service',
+	/// 'ports', 'visibility', 'hooks', 'env', and 'config'. Anything it
+	/// leaves out is left as it is.
+	#[clap(long, short = 'f', value_name = "file")]
+	pub file: PathBuf,
+
+	/// List the changes that would be made, without making them.
+	#[clap(long)]
+	pub dry_run: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelLogsArgs {
+	/// Which logs to show.
+	#[clap(long, arg_enum, default_value = "all")]
+	pub source: LogSource,
+
+	/// Number of lines to show, from the end of the logs.
+	#[clap(long, short = 'n', default_value = "100")]
+	pub lines: usize,
+
+	/// Number of matching lines to skip from the end of the logs, to page
+	/// back through them with `--lines`.
+	#[clap(long, default_value = "0")]
+	pub offset: usize,
+
+	/// Only show lines logged at or after this time, given as a time like
+	/// '2022-10-01 12:00:00' or a date, or as a duration before now like '2h'.
+	#[clap(long, value_name = "time")]
+	pub since: Option<LogTimeArg>,
+
+	/// Only show lines logged before this time, given like `--since`.
+	#[clap(long, value_name = "time")]
+	pub until: Option<LogTimeArg>,
+
+	/// Only show lines matching this regular expression.
+	#[clap(long, value_name = "pattern")]
+	pub grep: Option<regex::Regex>,
+
+	/// Only show lines logged at this level or above.
+	#[clap(long, arg_enum, value_name = "level")]
+	pub level: Option<log::Level>,
+}
+
+/// Time given on the command line to filter logs by, in local time like the
+/// logs are written in.
+#[derive(Debug, Clone, Copy)]
+pub struct LogTimeArg(pub chrono::NaiveDateTime);
+
+impl FromStr for LogTimeArg {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.trim();
+		for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"] {
+			if let Ok(t) = chrono::NaiveDateTime::parse_from_str(s, format) {
+				return Ok(LogTimeArg(t));
+			}
+		}
+
+		if let Ok(d) = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+			return Ok(LogTimeArg(d.and_hms(0, 0, 0)));
+		}
+
+		match DurationArg::from_str(s) {
+			Ok(d) => Ok(LogTimeArg(chrono::Local::now().naive_local() - d.0)),
+			Err(_) => Err(format!(
+				"expected a time like '2022-10-01 12:00:00' or a duration like '2h', got '{}'",
+				s
+			)),
+		}
+	}
+}
+
+impl fmt::Display for LogTimeArg {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.0.format("%Y-%m-%d %H:%M:%S"))
+	}
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogSource {
+	/// Both the service and server logs.
+	All,
+	/// Logs of the tunnel service.
+	Service,
+	/// Output of servers started by the tunnel.
+	Server,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelStatsArgs {
+	/// Show stats recorded within this duration, such as '7d' or '12h'.
+	#[clap(long, value_name = "duration", default_value = "7d")]
+	pub since: DurationArg,
+
+	/// Show a row for each hour, rather than each day.
+	#[clap(long)]
+	pub hourly: bool,
+
+	/// Name of the tunnel to show stats for. Defaults to the tunnel this
+	/// machine hosts.
+	#[clap(long)]
+	pub name: Option<String>,
+
+	#[clap(flatten)]
+	pub format: OutputFormatOptions,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelListArgs {
+	/// List all tunnels registered under your account, not just this machine's.
+	#[clap(long)]
+	pub all: bool,
+
+	/// Only list tunnels tagged with this, like 'team=infra'. May be given
+	/// multiple times to require several tags.
+	#[clap(long, value_name = "key=value")]
+	pub tag: Vec<TunnelTag>,
+
+	/// Delete tunnels whose host has been offline for longer than `--stale-days`.
+	/// The tunnel hosted by this machine is never deleted.
+	#[clap(long)]
+	pub delete_stale: bool,
+
+	/// Number of days a tunnel's host must be offline for it to be considered stale.
+	#[clap(long, value_name = "days", default_value_t = 30)]
+	pub stale_days: u32,
+
+	#[clap(flatten)]
+	pub format: OutputFormatOptions,
+}
+
 #[derive(Subcommand, Debug, Clone)]
 pub enum TunnelUserSubCommands {
 	/// Log in to port forwarding service
 	Login(LoginArgs),
 
 	/// Log out of port forwarding service
-	Logout,
+	Logout(LogoutArgs),
 
 	/// Show the account that's logged into port forwarding service
 	Show,
+
+	/// List all accounts the CLI is logged in with
+	List(OutputFormatOptions),
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum TunnelIdSubCommands {
+	/// Show this machine's identity and the tunnel registered with it.
+	Show,
+
+	/// Give this machine a new identity and register a new tunnel for it,
+	/// leaving the current tunnel to any other machine using it. Use this
+	/// after cloning a VM image that had already hosted a tunnel.
+	Rotate(TunnelIdRotateArgs),
+
+	/// Keep this machine's identity and tunnel, accepting that the machine's
+	/// OS ID changed. Use this if the tunnel data was moved rather than
+	/// copied, or the OS was reinstalled.
+	Accept,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum TunnelEnvSubCommands {
+	/// Set variables, like 'GOPATH=/opt/go'. Servers already running keep
+	/// their environment until they're restarted.
+	Set(TunnelEnvSetArgs),
+
+	/// Remove variables set with `env set`.
+	Unset(TunnelEnvUnsetArgs),
+
+	/// List the variables that are set.
+	#[clap(alias = "ls")]
+	List(TunnelEnvListArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelEnvSetArgs {
+	/// Variables to set, as KEY=VALUE.
+	#[clap(required = true, value_name = "KEY=VALUE")]
+	pub vars: Vec<EnvVarArg>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelEnvUnsetArgs {
+	/// Names of the variables to remove.
+	#[clap(required = true, value_name = "KEY")]
+	pub names: Vec<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelEnvListArgs {
+	/// Print the values of the variables. They're hidden by default since
+	/// they may be secrets.
+	#[clap(long)]
+	pub show_values: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelDoctorArgs {
+	/// Audit permissions on the data directory, tokens, and server sockets,
+	/// and how the service is confined, and score the result.
+	#[clap(long)]
+	pub security: bool,
+
+	/// Restrict permissions and update the service to fix what the security
+	/// audit finds.
+	#[clap(long, requires = "security")]
+	pub fix: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelIdRotateArgs {
+	/// Name for the new tunnel. A random name is used if not given, or if
+	/// the name's taken.
+	#[clap(long)]
+	pub name: Option<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct LogoutArgs {
+	/// The auth provider to log out of. If not provided, all accounts are logged out.
+	#[clap(arg_enum, long)]
+	pub provider: Option<AuthProvider>,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -644,6 +1929,16 @@ pub struct LoginArgs {
 	/// The auth provider to use. If not provided, a prompt will be shown.
 	#[clap(arg_enum, long)]
 	pub provider: Option<AuthProvider>,
+
+	/// Additional features to grant permissions for now, rather than being
+	/// asked when they're first used.
+	#[clap(arg_enum, long = "feature", value_name = "feature")]
+	pub features: Vec<AuthFeature>,
+
+	/// Use this account for hosting tunnels. The first account you log in
+	/// with is used by default.
+	#[clap(long)]
+	pub set_default: bool,
 }
 
 #[derive(clap::ArgEnum, Debug, Clone, Copy)]
@@ -651,3 +1946,39 @@ pub enum AuthProvider {
 	Microsoft,
 	Github,
 }
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy)]
+pub enum AuthFeature {
+	SettingsSync,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_duration_arg() {
+		let parse = |s: &str| DurationArg::from_str(s).map(|d| d.0);
+		assert_eq!(parse("90"), Ok(chrono::Duration::days(90)));
+		assert_eq!(parse("30s"), Ok(chrono::Duration::seconds(30)));
+		assert_eq!(parse("15m"), Ok(chrono::Duration::minutes(15)));
+		assert_eq!(parse(" 2h "), Ok(chrono::Duration::hours(2)));
+		assert_eq!(parse("2w"), Ok(chrono::Duration::weeks(2)));
+		assert_eq!(parse("3650d"), Ok(chrono::Duration::days(3650)));
+
+		assert!(parse("").is_err());
+		assert!(parse("d").is_err());
+		assert!(parse("-1d").is_err());
+		assert!(parse("5y").is_err());
+		assert!(parse("3651d").is_err());
+		assert!(parse("9223372036854775807w").is_err());
+		assert!(parse("99999999999999999999s").is_err());
+	}
+
+	#[test]
+	fn test_display_duration_arg() {
+		for s in ["90d", "2h", "15m", "30s"] {
+			assert_eq!(DurationArg::from_str(s).unwrap().to_string(), s);
+		}
+	}
+}
@@ -0,0 +1,462 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! `code command-shell` lets another program, like the desktop app or an
+//! installer, drive the CLI with JSON-RPC 2.0 messages on stdin and stdout,
+//! one per line, rather than parsing its console output. stdout carries only
+//! messages: logs and other output are sent as `log` and `output`
+//! notifications.
+//!
+//! Methods, as of protocol version 1:
+//! - `initialize` `{ protocolVersion?, minProtocolVersion? }`: agrees on the
+//!   protocol version with the caller, which should send the newest and oldest
+//!   versions it speaks, and returns it with the supported methods. Callers
+//!   that don't send versions are assumed to speak version 1. It fails if the
+//!   caller and the CLI have no version in common.
+//! - `tunnel/start` `{ name?, acceptServerLicenseTerms? }`: starts hosting
+//!   the tunnel in the background. The CLI must already be logged in.
+//! - `tunnel/stop`: stops the tunnel, replying once it's stopped.
+//! - `tunnel/status`: returns whether the tunnel's running, and its health.
+//! - `shutdown`: stops the tunnel if it's running, replies, then exits.
+//!
+//! `tunnel/stopped` is sent when a tunnel stops, whether it was asked to or
+//! not. Closing stdin is the same as sending `shutdown`.
+//!
+//! With `--record-sessions`, every message in and out is recorded.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+	sync::mpsc,
+	task::JoinHandle,
+};
+
+use crate::{
+	auth::Auth,
+	constants::VSCODE_CLI_VERSION,
+	log,
+	tunnels::{
+		dev_tunnels::DevTunnels,
+		legal,
+		relay_breaker::load_relay_health,
+		session_recording::{Direction, RecordedData, SessionRecorder},
+	},
+	util::{
+		errors::AnyError,
+		protocol_version::{ProtocolVersions, VersionMismatch},
+	},
+};
+
+use super::{
+	args::TunnelServeArgs,
+	tunnels::{serve_with_csa, ShutdownSignal},
+	update_cache_for, CommandContext,
+};
+
+/// The version is incremented when methods are changed incompatibly, and
+/// the minimum when support for older versions is dropped. Adding methods or
+/// optional fields doesn't change either.
+const SHELL_PROTOCOL: ProtocolVersions = ProtocolVersions::new(1, 1);
+const METHODS: [&str; 5] = [
+	"initialize",
+	"tunnel/start",
+	"tunnel/stop",
+	"tunnel/status",
+	"shutdown",
+];
+
+/// How long to wait for remaining messages to be written when exiting.
+const WRITER_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+const PARSE_ERROR: i32 = -32700;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const SERVER_ERROR: i32 = -32000;
+
+#[derive(Deserialize)]
+struct RpcRequest {
+	id: Option<Value>,
+	method: String,
+	#[serde(default)]
+	params: Value,
+}
+
+struct RpcError {
+	code: i32,
+	message: String,
+}
+
+impl From<AnyError> for RpcError {
+	fn from(e: AnyError) -> Self {
+		RpcError {
+			code: SERVER_ERROR,
+			message: e.to_string(),
+		}
+	}
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct InitializeParams {
+	protocol_version: Option<u32>,
+	min_protocol_version: Option<u32>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct StartTunnelParams {
+	name: Option<String>,
+	#[serde(default)]
+	accept_server_license_terms: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TunnelStatus {
+	running: bool,
+	name: Option<String>,
+	logged_in: bool,
+	relay_unreachable_since: Option<String>,
+	relay_last_error: Option<String>,
+}
+
+/// Writes messages to stdout, one per line, in the order they're sent.
+#[derive(Clone)]
+struct MessageWriter {
+	tx: mpsc::UnboundedSender<String>,
+}
+
+impl MessageWriter {
+	fn new(recorder: Option<SessionRecorder>) -> (Self, JoinHandle<()>) {
+		let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+		let task = tokio::spawn(async move {
+			let mut stdout = tokio::io::stdout();
+			while let Some(mut line) = rx.recv().await {
+				if let Some(r) = &recorder {
+					r.record(Direction::Output, line.as_bytes());
+				}
+				line.push('\n');
+				if stdout.write_all(line.as_bytes()).await.is_err() {
+					return;
+				}
+				stdout.flush().await.ok();
+			}
+		});
+
+		(MessageWriter { tx }, task)
+	}
+
+	fn notify(&self, method: &str, params: Value) {
+		self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+	}
+
+	fn respond(&self, id: Value, result: Result<Value, RpcError>) {
+		self.send(match result {
+			Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+			Err(e) => json!({
+				"jsonrpc": "2.0",
+				"id": id,
+				"error": { "code": e.code, "message": e.message },
+			}),
+		});
+	}
+
+	fn send(&self, message: Value) {
+		self.tx.send(message.to_string()).ok();
+	}
+}
+
+/// Sends logs and results as notifications.
+#[derive(Clone)]
+struct NotificationLogSink {
+	level: log::Level,
+	writer: MessageWriter,
+}
+
+impl log::LogSink for NotificationLogSink {
+	fn write_log(&self, level: log::Level, prefix: &str, message: &str) {
+		if level < self.level {
+			return;
+		}
+
+		self.writer.notify(
+			"log",
+			json!({
+				"level": level.to_string(),
+				"prefix": prefix.trim(),
+				"message": message,
+			}),
+		);
+	}
+
+	fn write_result(&self, message: &str) {
+		self.writer.notify("output", json!({ "message": message }));
+	}
+}
+
+struct RunningTunnel {
+	shutdown_tx: mpsc::UnboundedSender<ShutdownSignal>,
+	task: JoinHandle<Result<i32, AnyError>>,
+}
+
+struct Shell {
+	ctx: CommandContext,
+	log: log::Logger,
+	writer: MessageWriter,
+	tunnel: Option<RunningTunnel>,
+}
+
+pub async fn command_shell(ctx: CommandContext) -> Result<i32, AnyError> {
+	let recorder = SessionRecorder::start("command-shell", RecordedData::Contents, json!({}))?;
+	let (writer, writer_task) = MessageWriter::new(recorder.clone());
+	let level = if ctx.args.global_options.verbose {
+		log::Level::Trace
+	} else {
+		ctx.args.global_options.log.unwrap_or(log::Level::Info)
+	};
+	let log = ctx.log.with_only_sink(NotificationLogSink {
+		level,
+		writer: writer.clone(),
+	});
+
+	let mut shell = Shell {
+		ctx,
+		log,
+		writer,
+		tunnel: None,
+	};
+
+	let mut lines = BufReader::new(tokio::io::stdin()).lines();
+	loop {
+		let tunnel_exit = async {
+			match &mut shell.tunnel {
+				Some(t) => (&mut t.task).await,
+				None => futures::future::pending().await,
+			}
+		};
+
+		tokio::select! {
+			r = tunnel_exit => {
+				shell.tunnel = None;
+				shell.notify_stopped(r);
+			},
+			line = lines.next_line() => {
+				let line = match line {
+					Ok(Some(l)) => l,
+					// stdin closed, so the parent's gone or done with us
+					_ => {
+						shell.stop_tunnel().await;
+						break;
+					}
+				};
+
+				if let Some(r) = &recorder {
+					r.record(Direction::Input, line.as_bytes());
+				}
+				if shell.handle_line(&line).await {
+					break;
+				}
+			},
+		}
+	}
+
+	// let any remaining messages be written. Background tasks may still hold
+	// the logger, so don't wait for every sender to be dropped.
+	drop(shell);
+	tokio::time::timeout(WRITER_DRAIN_TIMEOUT, writer_task)
+		.await
+		.ok();
+	Ok(0)
+}
+
+impl Shell {
+	/// Handles a line from stdin, returning true if the shell should exit.
+	async fn handle_line(&mut self, line: &str) -> bool {
+		if line.trim().is_empty() {
+			return false;
+		}
+
+		let req: RpcRequest = match serde_json::from_str(line) {
+			Ok(r) => r,
+			Err(e) => {
+				self.writer.respond(
+					Value::Null,
+					Err(RpcError {
+						code: PARSE_ERROR,
+						message: e.to_string(),
+					}),
+				);
+				return false;
+			}
+		};
+
+		let is_shutdown = req.method == "shutdown";
+		let result = match req.method.as_str() {
+			"initialize" => match parse_params::<InitializeParams>(req.params) {
+				Ok(p) => initialize(p),
+				Err(e) => Err(e),
+			},
+			"tunnel/start" => match parse_params::<StartTunnelParams>(req.params) {
+				Ok(p) => self.start_tunnel(p).map(|_| json!({})),
+				Err(e) => Err(e),
+			},
+			"tunnel/stop" => {
+				self.stop_tunnel().await;
+				Ok(json!({}))
+			}
+			"tunnel/status" => Ok(json!(self.status())),
+			"shutdown" => {
+				self.stop_tunnel().await;
+				Ok(json!({}))
+			}
+			m => Err(RpcError {
+				code: METHOD_NOT_FOUND,
+				message: format!("unknown method '{}'", m),
+			}),
+		};
+
+		// requests without an id are notifications, which get no response
+		if let Some(id) = req.id {
+			self.writer.respond(id, result);
+		}
+
+		is_shutdown
+	}
+
+	fn start_tunnel(&mut self, params: StartTunnelParams) -> Result<(), RpcError> {
+		if self.tunnel.is_some() {
+			return Err(RpcError {
+				code: SERVER_ERROR,
+				message: "the tunnel is already running".to_string(),
+			});
+		}
+
+		legal::check_consent(&self.ctx.paths, params.accept_server_license_terms)?;
+
+		// logging in may need to prompt, which can't be done over stdin
+		let auth = Auth::new(&self.ctx.paths, self.log.clone());
+		if !matches!(auth.get_current_credential(None), Ok(Some(_))) {
+			return Err(RpcError {
+				code: SERVER_ERROR,
+				message: "not logged in, run `tunnel user login` first".to_string(),
+			});
+		}
+
+		let (shutdown_tx, shutdown_rx) = mpsc::unbounded_channel();
+		let gateway_args = TunnelServeArgs {
+			random_name: params.name.is_none(),
+			name: params.name,
+			accept_server_license_terms: true,
+			no_respawn: true,
+			..Default::default()
+		};
+
+		let task = tokio::spawn(serve_with_csa(
+			self.ctx.paths.clone(),
+			self.log.clone(),
+			gateway_args,
+			(&self.ctx.args).into(),
+			update_cache_for(&self.ctx.args, &self.ctx.paths),
+			Some(shutdown_rx),
+		));
+
+		self.tunnel = Some(RunningTunnel { shutdown_tx, task });
+		Ok(())
+	}
+
+	async fn stop_tunnel(&mut self) {
+		if let Some(t) = self.tunnel.take() {
+			t.shutdown_tx.send(ShutdownSignal::StopRequested).ok();
+			let r = t.task.await;
+			self.notify_stopped(r);
+		}
+	}
+
+	fn notify_stopped(&self, r: Result<Result<i32, AnyError>, tokio::task::JoinError>) {
+		let params = match r {
+			Ok(Ok(code)) => json!({ "exitCode": code }),
+			Ok(Err(e)) => json!({ "error": e.to_string() }),
+			Err(e) => json!({ "error": e.to_string() }),
+		};
+		self.writer.notify("tunnel/stopped", params);
+	}
+
+	fn status(&self) -> TunnelStatus {
+		let auth = Auth::new(&self.ctx.paths, self.log.clone());
+		let logged_in = matches!(auth.get_current_credential(None), Ok(Some(_)));
+		let relay = load_relay_health(&self.ctx.paths);
+
+		TunnelStatus {
+			running: self.tunnel.is_some(),
+			name: DevTunnels::new(&self.log, auth, &self.ctx.paths).current_tunnel_name(),
+			logged_in,
+			relay_unreachable_since: relay.unreachable_since.map(|d| d.to_rfc3339()),
+			relay_last_error: relay.last_error,
+		}
+	}
+}
+
+fn initialize(params: InitializeParams) -> Result<Value, RpcError> {
+	let caller = match params.protocol_version {
+		Some(version) => ProtocolVersions::new(version, params.min_protocol_version.unwrap_or(1)),
+		None => ProtocolVersions::unversioned(),
+	};
+
+	let version = SHELL_PROTOCOL.negotiate(&caller).map_err(|e| RpcError {
+		code: SERVER_ERROR,
+		message: match e {
+			VersionMismatch::PeerIsOlder { .. } => {
+				format!("{}, update the program that started command-shell", e)
+			}
+			VersionMismatch::PeerIsNewer { .. } => format!("{}, update the CLI", e),
+		},
+	})?;
+
+	Ok(json!({
+		"protocolVersion": version,
+		"minProtocolVersion": SHELL_PROTOCOL.min_version,
+		"version": VSCODE_CLI_VERSION,
+		"methods": METHODS,
+	}))
+}
+
+fn parse_params<T: DeserializeOwned + Default>(params: Value) -> Result<T, RpcError> {
+	if params.is_null() {
+		return Ok(T::default());
+	}
+
+	serde_json::from_value(params).map_err(|e| RpcError {
+		code: INVALID_PARAMS,
+		message: e.to_string(),
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_initialize() {
+		let r = initialize(InitializeParams::default()).ok().unwrap();
+		assert_eq!(r["protocolVersion"], 1);
+
+		let r = initialize(InitializeParams {
+			protocol_version: Some(4),
+			min_protocol_version: Some(1),
+		})
+		.ok()
+		.unwrap();
+		assert_eq!(r["protocolVersion"], 1);
+
+		let e = initialize(InitializeParams {
+			protocol_version: Some(4),
+			min_protocol_version: Some(2),
+		})
+		.err()
+		.unwrap();
+		assert!(e.message.contains("update the CLI"));
+	}
+}
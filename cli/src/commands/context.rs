@@ -3,13 +3,93 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use crate::{log, state::LauncherPaths};
+use indicatif::ProgressBar;
 
-use super::args::CliCore;
+use crate::{
+	log,
+	state::LauncherPaths,
+	tunnels::{
+		create_service_manager, dev_tunnels::load_launcher_tunnel, load_service_registration,
+		ServiceManager,
+	},
+	update_service::UpdateServiceCache,
+	util::{
+		errors::{wrap, AnyError},
+		input::ProgressBarReporter,
+		progress::{JsonProgressReporter, ReportProgress},
+	},
+};
+
+use super::{
+	args::{CliCore, ProgressFormat},
+	output::{ChangeOutput, TunnelOutput},
+};
 
 pub struct CommandContext {
 	pub log: log::Logger,
 	pub paths: LauncherPaths,
 	pub args: CliCore,
 	pub http: reqwest::Client,
+	/// This machine's identity as it was before the command ran, reported
+	/// with the result of commands that change the machine's setup.
+	pub host_id: Option<String>,
+}
+
+impl CommandContext {
+	/// Gets the cache to use for update service lookups, unless disabled.
+	pub fn update_cache(&self) -> Option<UpdateServiceCache> {
+		update_cache_for(&self.args, &self.paths)
+	}
+
+	/// Gets the reporter for downloads and installs the command shows, in the
+	/// format chosen with `--progress`.
+	pub fn progress_reporter(&self) -> Box<dyn ReportProgress + Send> {
+		match self.args.global_options.progress {
+			ProgressFormat::Bar => Box::new(ProgressBarReporter::from(ProgressBar::new(1))),
+			ProgressFormat::Json => Box::new(JsonProgressReporter::new(std::io::stdout())),
+		}
+	}
+
+	/// Prints the result of a command that changes this machine's setup. With
+	/// `--json`, it's printed on stdout with the IDs of the machine's tunnel,
+	/// service, and host; otherwise only the message is shown.
+	pub fn print_change(&self, changed: bool, message: impl Into<String>) -> Result<(), AnyError> {
+		let message = message.into();
+		if !self.args.global_options.json {
+			self.log.result(message);
+			return Ok(());
+		}
+
+		let output = ChangeOutput {
+			changed,
+			message,
+			host_id: self.host_id.clone(),
+			tunnel: load_launcher_tunnel(&self.paths).map(|t| TunnelOutput {
+				id: t.id,
+				name: t.name,
+				cluster: t.cluster,
+			}),
+			service_name: load_service_registration(&self.paths)
+				.map(|_| create_service_manager(self.log.clone(), &self.paths).service_name()),
+		};
+		println!(
+			"{}",
+			serde_json::to_string(&output).map_err(|e| wrap(e, "error serializing"))?
+		);
+		Ok(())
+	}
+}
+
+/// Gets the cache to use for update service lookups given the CLI args.
+pub(crate) fn update_cache_for(
+	args: &CliCore,
+	paths: &LauncherPaths,
+) -> Option<UpdateServiceCache> {
+	if args.global_options.no_cache {
+		None
+	} else if args.global_options.no_update_check {
+		Some(UpdateServiceCache::new(paths).without_update_check())
+	} else {
+		Some(UpdateServiceCache::new(paths))
+	}
 }
@@ -0,0 +1,55 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use crate::{
+	debug,
+	experiments::{refresh_remote_flags, Experiments, EXPERIMENTS},
+	update_service::UpdateService,
+	util::{
+		errors::{wrap, AnyError},
+		http::ReqwestSimpleHttp,
+	},
+};
+
+use super::{
+	args::OutputFormatOptions,
+	output::{Column, OutputTable},
+	CommandContext,
+};
+
+/// Lists the experiments the CLI knows about and whether they're on.
+pub async fn list(ctx: CommandContext, format: OutputFormatOptions) -> Result<i32, AnyError> {
+	let update_service = UpdateService::new(
+		ctx.log.clone(),
+		ReqwestSimpleHttp::with_client(ctx.http.clone()),
+	)
+	.with_cache(ctx.update_cache());
+	if let Err(e) = refresh_remote_flags(&ctx.log, &update_service, &ctx.paths).await {
+		debug!(
+			ctx.log,
+			"Could not refresh experiments, using cached ones: {}", e
+		);
+	}
+
+	let experiments = Experiments::load(&ctx.paths);
+	let mut name = Column::new("name");
+	let mut enabled = Column::new("enabled");
+	let mut source = Column::new("source");
+	let mut description = Column::new("description");
+	for e in EXPERIMENTS {
+		let (on, from) = experiments.get(e);
+		name.add_row(e.name.to_string());
+		enabled.add_row(on.to_string());
+		source.add_row(from.to_string());
+		description.add_row(e.description.to_string());
+	}
+
+	format
+		.format
+		.print_table(OutputTable::new(vec![name, enabled, source, description]))
+		.map_err(|e| wrap(e, "error printing experiments"))?;
+
+	Ok(0)
+}
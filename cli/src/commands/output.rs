@@ -7,6 +7,10 @@ use std::fmt::Display;
 
 use std::io::{BufWriter, Write};
 
+use serde::Serialize;
+
+use crate::util::plain::is_plain_output;
+
 use super::args::OutputFormat;
 
 pub struct Column {
@@ -34,11 +38,34 @@ impl OutputFormat {
 	pub fn print_table(&self, table: OutputTable) -> Result<(), std::io::Error> {
 		match *self {
 			OutputFormat::Json => JsonTablePrinter().print(table, &mut std::io::stdout()),
+			OutputFormat::Text if is_plain_output() => {
+				PlainTablePrinter().print(table, &mut std::io::stdout())
+			}
 			OutputFormat::Text => TextTablePrinter().print(table, &mut std::io::stdout()),
 		}
 	}
 }
 
+/// What a command that changes this machine's setup did, printed as JSON on
+/// stdout with `--json`. The IDs are included whether or not anything changed,
+/// so tools wrapping the CLI can track what it manages from any run.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeOutput {
+	pub changed: bool,
+	pub message: String,
+	pub host_id: Option<String>,
+	pub tunnel: Option<TunnelOutput>,
+	pub service_name: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TunnelOutput {
+	pub id: String,
+	pub name: String,
+	pub cluster: String,
+}
+
 pub struct OutputTable {
 	cols: Vec<Column>,
 }
@@ -73,11 +100,15 @@ impl TablePrinter for JsonTablePrinter {
 				} else {
 					bw.write_all(b"{")?;
 				}
-				for col in &table.cols {
+				for (j, col) in table.cols.iter().enumerate() {
+					if j > 0 {
+						bw.write_all(b",")?;
+					}
 					serde_json::to_writer(&mut bw, col.heading)?;
 					bw.write_all(b":")?;
 					serde_json::to_writer(&mut bw, &col.data[i])?;
 				}
+				bw.write_all(b"}")?;
 			}
 		}
 
@@ -119,6 +150,34 @@ impl TablePrinter for TextTablePrinter {
 	}
 }
 
+/// Type that prints each row as a list of "heading: value" lines, which is
+/// easier to follow with a screen reader than a table.
+pub struct PlainTablePrinter();
+
+impl TablePrinter for PlainTablePrinter {
+	fn print(
+		&self,
+		table: OutputTable,
+		out: &mut dyn std::io::Write,
+	) -> Result<(), std::io::Error> {
+		let mut bw = BufWriter::new(out);
+
+		if !table.cols.is_empty() {
+			let data_len = table.cols[0].data.len();
+			for i in 0..data_len {
+				if i > 0 {
+					writeln!(bw)?;
+				}
+				for col in table.cols.iter() {
+					writeln!(bw, "{}: {}", col.heading, col.data[i])?;
+				}
+			}
+		}
+
+		bw.flush()
+	}
+}
+
 fn write_columns<T>(
 	mut w: impl Write,
 	cols: impl Iterator<Item = T>,
@@ -0,0 +1,96 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::path::PathBuf;
+
+use crate::{
+	log,
+	update_service::{StaticMirror, TargetKind, UpdateService},
+	util::{
+		errors::{wrap, AnyError},
+		http::ReqwestSimpleHttp,
+	},
+	warning,
+};
+
+use super::{args::ReleaseMirrorArgs, CommandContext};
+
+/// Kinds of release a mirror holds for each platform.
+const MIRRORED_TARGETS: [(TargetKind, &str); 3] = [
+	(TargetKind::Cli, "cli"),
+	(TargetKind::Server, "server"),
+	(TargetKind::Web, "web server"),
+];
+
+/// Downloads the latest releases for the platforms into the mirror, along
+/// with the metadata clients resolve them with.
+pub async fn mirror(ctx: CommandContext, args: ReleaseMirrorArgs) -> Result<i32, AnyError> {
+	// not cached, so the mirror matches what the update service has now
+	let update_service = UpdateService::new(
+		ctx.log.clone(),
+		ReqwestSimpleHttp::with_client(ctx.http.clone()),
+	);
+	let mirror = StaticMirror::new(args.dest.clone());
+
+	let mut mirrored = 0;
+	for platform in &args.platforms {
+		for (target, label) in MIRRORED_TARGETS {
+			let release = match update_service
+				.get_latest_commit(*platform, target, args.quality)
+				.await
+			{
+				Err(AnyError::NoBuildsForPlatformError(_)) => {
+					warning!(
+						ctx.log,
+						"Skipping the {} for {}, no builds are published for it",
+						label,
+						platform
+					);
+					continue;
+				}
+				r => r?,
+			};
+			if mirror.has_latest(&release)? {
+				ctx.log
+					.result(format!("{} {}: {} is up to date", platform, label, release));
+				continue;
+			}
+
+			let download = mirror.download_path(&release)?;
+			if let Some(dir) = download.parent() {
+				std::fs::create_dir_all(dir)
+					.map_err(|e| wrap(e, format!("error creating {}", dir.display())))?;
+			}
+
+			// downloaded beside its final path, so interrupted downloads are
+			// resumed on the next run and never served
+			let mut partial = download.clone().into_os_string();
+			partial.push(".partial");
+			let partial = PathBuf::from(partial);
+			update_service
+				.download_release(&release, &partial, ctx.progress_reporter())
+				.await?;
+			std::fs::rename(&partial, &download)
+				.map_err(|e| wrap(e, format!("error moving {}", download.display())))?;
+
+			let sha256 = update_service.get_expected_sha256(&release).await;
+			let signature = update_service.get_download_signature(&release).await;
+			mirror.publish(&release, sha256.as_deref(), signature.as_deref())?;
+			ctx.log
+				.result(format!("{} {}: mirrored {}", platform, label, release));
+			mirrored += 1;
+		}
+	}
+
+	ctx.print_change(
+		mirrored > 0,
+		format!(
+			"Mirrored {} release(s) to {}",
+			mirrored,
+			args.dest.display()
+		),
+	)?;
+	Ok(0)
+}
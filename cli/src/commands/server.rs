@@ -0,0 +1,293 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use crate::{
+	desktop::RequestedVersion,
+	log,
+	state::LauncherPaths,
+	tunnels::{
+		code_server::{CodeServerArgs, ResolvedServerParams, ServerBuilder, ServerParamsRaw},
+		paths::{
+			get_all_installs, get_install_state, get_pinned_version, set_pinned_version,
+			stop_running_servers, InstallState, LastUsedServers, RetentionPolicy, ServerHistory,
+		},
+		server_selection::ServerSelection,
+	},
+	util::{
+		errors::{wrap, AnyError, NoServerToRollBackTo},
+		http::ReqwestSimpleHttp,
+		prereqs::PreReqChecker,
+	},
+};
+
+use super::{
+	args::{ServerPrefetchArgs, ServerPruneArgs, ServerRollbackArgs, ServerVerifyArgs},
+	tunnels::default_quality,
+	CommandContext,
+};
+
+/// Deletes servers that the retention policy doesn't keep.
+pub async fn prune(ctx: CommandContext, args: ServerPruneArgs) -> Result<i32, AnyError> {
+	let mut policy = RetentionPolicy::configured(&ctx.paths);
+	if let Some(keep) = args.keep {
+		policy.keep = keep;
+	}
+	if let Some(older_than) = args.older_than {
+		policy.older_than = Some(older_than.0);
+	}
+
+	let removed = LastUsedServers::new(&ctx.paths).prune(&ctx.log, policy)?;
+	for server in &removed {
+		ctx.log.result(format!(
+			"Deleted {}",
+			server.server_paths(&ctx.paths).server_dir.display()
+		));
+	}
+
+	ctx.print_change(
+		!removed.is_empty(),
+		format!("Removed {} unused server(s)", removed.len()),
+	)?;
+
+	Ok(0)
+}
+
+/// Pins the server used before the current one and restarts running servers,
+/// so clients get the earlier server when they reconnect.
+pub async fn rollback(ctx: CommandContext, args: ServerRollbackArgs) -> Result<i32, AnyError> {
+	let history = ServerHistory::new(&ctx.paths);
+	let entries = history.get_all();
+	let quality = match args.quality.or_else(|| entries.first().map(|e| e.quality)) {
+		Some(q) => q,
+		None => {
+			return Err(NoServerToRollBackTo("no servers have been used yet".to_string()).into())
+		}
+	};
+
+	// a pinned commit is what hosts use, even if it hasn't been used yet
+	let current = match get_pinned_version(&ctx.paths) {
+		Some(RequestedVersion::Commit { commit, quality: q }) if q == quality => Some(commit),
+		_ => entries
+			.iter()
+			.find(|e| e.quality == quality)
+			.map(|e| e.commit.clone()),
+	};
+	let current = current.ok_or_else(|| {
+		NoServerToRollBackTo(format!("no {} servers have been used yet", quality))
+	})?;
+
+	let previous = history.previous(quality, &current).ok_or_else(|| {
+		NoServerToRollBackTo(format!("no {} server was used before {}", quality, current))
+	})?;
+
+	set_pinned_version(
+		&ctx.paths,
+		Some(RequestedVersion::Commit {
+			commit: previous.clone(),
+			quality,
+		}),
+	)?;
+	history.mark_rolled_back(quality, &current)?;
+
+	for s in stop_running_servers(&ctx.paths).await {
+		ctx.log.result(format!("Stopped server {}", s.commit));
+	}
+
+	ctx.print_change(
+		true,
+		format!(
+			"Rolled back the {} server from {} to {}. It's pinned until you run `version unpin`.",
+			quality, current, previous
+		),
+	)?;
+
+	Ok(0)
+}
+
+/// Installs a server the way hosts do when a client first connects, but
+/// without starting it.
+pub async fn prefetch(ctx: CommandContext, args: ServerPrefetchArgs) -> Result<i32, AnyError> {
+	let native = PreReqChecker::new().verify().await;
+	let (platform, foreign) = match (args.platform, native) {
+		(Some(p), Ok(n)) => (p, p.to_string() != n.to_string()),
+		(Some(p), Err(_)) => (p, true),
+		(None, n) => (n?, false),
+	};
+	// builds for other platforms can't run here, so they're kept apart from
+	// the installed servers, where hosts and server commands don't see them
+	let install_paths = match foreign {
+		true => LauncherPaths::new_without_replacements(
+			ctx.paths
+				.root()
+				.join("platforms")
+				.join(platform.to_string()),
+		),
+		false => ctx.paths.clone(),
+	};
+	let quality = args.quality.unwrap_or_else(default_quality);
+	let selection = ServerSelection {
+		quality: args.quality,
+		commit: args.commit,
+	};
+	let mut params = selection.params(
+		&ctx.paths,
+		None,
+		quality,
+		CodeServerArgs::default(),
+		!args.web,
+		platform,
+	)?;
+	if let Some(version) = args.version {
+		params.pinned_version = Some(RequestedVersion::Version { version, quality });
+	}
+
+	let (resolved, existed) = install(&ctx, &install_paths, params).await?;
+	let message = match foreign {
+		true => format!(
+			"Downloaded the {} server {} for {} to {}",
+			resolved.release.quality,
+			resolved.release.commit,
+			resolved.release.platform,
+			resolved
+				.as_installed_server()
+				.server_paths(&install_paths)
+				.server_dir
+				.display()
+		),
+		false => format!(
+			"Installed the {} server {} for {}",
+			resolved.release.quality, resolved.release.commit, resolved.release.platform
+		),
+	};
+	ctx.print_change(!existed, message)?;
+
+	Ok(0)
+}
+
+/// Checks installed servers against what was recorded when they were
+/// installed, and reinstalls damaged ones with `--repair`.
+pub async fn verify(ctx: CommandContext, args: ServerVerifyArgs) -> Result<i32, AnyError> {
+	// servers are repaired with this machine's builds
+	let repair_platform = match args.repair {
+		true => Some(PreReqChecker::new().verify().await?),
+		false => None,
+	};
+
+	let (mut checked, mut damaged, mut repaired) = (0, 0, 0);
+	for server in get_all_installs(&ctx.paths) {
+		if args.quality.map(|q| q != server.quality).unwrap_or(false) {
+			continue;
+		}
+
+		let paths = server.server_paths(&ctx.paths);
+		let problems = match get_install_state(&server, &paths) {
+			// still being installed by another process
+			InstallState::Incomplete if paths.is_install_locked() => continue,
+			InstallState::Incomplete => vec!["its install didn't finish".to_string()],
+			InstallState::Intact | InstallState::Legacy => {
+				let paths = paths.clone();
+				tokio::task::spawn_blocking(move || paths.check_integrity())
+					.await
+					.map_err(|e| wrap(e, "error checking server files"))?
+			}
+		};
+
+		checked += 1;
+		let name = format!(
+			"{} server {}{}",
+			server.quality,
+			server.commit,
+			if server.headless { "" } else { " (web)" }
+		);
+		if problems.is_empty() {
+			ctx.log.result(format!("{}: ok", name));
+			continue;
+		}
+
+		ctx.log.result(format!("{}: damaged", name));
+		for problem in &problems {
+			ctx.log.result(format!("  {}", problem));
+		}
+
+		let platform = match repair_platform {
+			Some(p) => p,
+			None => {
+				damaged += 1;
+				continue;
+			}
+		};
+
+		// the lock keeps hosts from installing or starting the server while
+		// it's removed. It's released for the reinstall, which takes it again.
+		let lock = paths
+			.lock_install(&ctx.log, &mut ctx.progress_reporter())
+			.await?;
+		if paths.get_running_pid().is_some() {
+			warning!(
+				ctx.log,
+				"Not repairing the {}, since it's running. Stop it and run this again.",
+				name
+			);
+			damaged += 1;
+			continue;
+		}
+
+		paths.delete()?;
+		drop(lock);
+		let params = ServerParamsRaw {
+			commit_id: Some(server.commit.clone()),
+			quality: server.quality,
+			code_server_args: CodeServerArgs::default(),
+			headless: server.headless,
+			platform,
+			pinned_version: None,
+		};
+		match install(&ctx, &ctx.paths, params).await {
+			Ok(_) => {
+				ctx.log.result(format!("{}: repaired", name));
+				repaired += 1;
+			}
+			Err(e) => {
+				warning!(ctx.log, "Error repairing the {}: {}", name, e);
+				damaged += 1;
+			}
+		}
+	}
+
+	ctx.print_change(
+		repaired > 0,
+		format!(
+			"Checked {} server(s): {} damaged, {} repaired",
+			checked,
+			damaged + repaired,
+			repaired
+		),
+	)?;
+
+	Ok(if damaged > 0 { 1 } else { 0 })
+}
+
+/// Resolves the server and installs it in `paths` if it isn't already,
+/// returning it and whether it was installed before.
+async fn install(
+	ctx: &CommandContext,
+	paths: &LauncherPaths,
+	params: ServerParamsRaw,
+) -> Result<(ResolvedServerParams, bool), AnyError> {
+	let http = ReqwestSimpleHttp::with_client(ctx.http.clone());
+	let resolved = params
+		.resolve(&ctx.log, http.clone(), ctx.update_cache())
+		.await?;
+	let existed = resolved
+		.as_installed_server()
+		.server_paths(paths)
+		.executable
+		.exists();
+	ServerBuilder::new(&ctx.log, &resolved, paths, http)
+		.setup_with_progress(&mut ctx.progress_reporter())
+		.await?;
+
+	Ok((resolved, existed))
+}
@@ -0,0 +1,67 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use crate::{state::migrations, util::errors::AnyError};
+
+use super::{
+	args::{StateMigrateArgs, StateRestoreArgs},
+	CommandContext,
+};
+
+/// Runs pending migrations of the data directory, or lists them.
+pub async fn migrate(ctx: CommandContext, args: StateMigrateArgs) -> Result<i32, AnyError> {
+	if args.dry_run {
+		ctx.log.result(format!(
+			"Data directory version: {} (this CLI migrates to {})",
+			migrations::current_version(&ctx.paths),
+			migrations::latest_version()
+		));
+		for m in migrations::pending(&ctx.paths) {
+			if m.backup.is_empty() {
+				ctx.log
+					.result(format!("  {}: {}", m.version, m.description));
+			} else {
+				ctx.log.result(format!(
+					"  {}: {} (backs up {})",
+					m.version,
+					m.description,
+					m.backup.join(", ")
+				));
+			}
+		}
+		for b in migrations::list_backups(&ctx.paths) {
+			ctx.log.result(format!(
+				"Backup {}: restores version {}, from before migration {}",
+				b.name, b.version, b.migration
+			));
+		}
+		ctx.print_change(false, "Dry run, nothing was migrated")?;
+		return Ok(0);
+	}
+
+	let ran = migrations::migrate(&ctx.log, &ctx.paths)?;
+	ctx.print_change(
+		!ran.is_empty(),
+		format!(
+			"Ran {} migration(s), the data directory is at version {}",
+			ran.len(),
+			migrations::current_version(&ctx.paths)
+		),
+	)?;
+	Ok(0)
+}
+
+/// Restores files backed up before a migration.
+pub async fn restore(ctx: CommandContext, args: StateRestoreArgs) -> Result<i32, AnyError> {
+	let backup = migrations::restore(&ctx.paths, args.name.as_deref())?;
+	ctx.print_change(
+		true,
+		format!(
+			"Restored backup {}, the data directory is at version {}. It's only migrated again with `state migrate`.",
+			backup.name, backup.version
+		),
+	)?;
+	Ok(0)
+}
@@ -4,7 +4,9 @@
  *--------------------------------------------------------------------------------------------*/
 
 use async_trait::async_trait;
+use serde::Serialize;
 use std::fmt;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use sysinfo::{Pid, SystemExt};
 use tokio::sync::mpsc;
@@ -12,26 +14,90 @@ use tokio::time::{sleep, Duration};
 
 use super::{
 	args::{
-		AuthProvider, CliCore, ExistingTunnelArgs, TunnelRenameArgs, TunnelServeArgs,
-		TunnelServiceSubCommands, TunnelUserSubCommands,
+		AuthFeature, AuthProvider, CliCore, DurationArg, ExistingTunnelArgs, ExtensionSubcommand,
+		InstallExtensionArgs, LogSource, TunnelDoctorArgs, TunnelEnvSubCommands, TunnelExtArgs,
+		TunnelExtSubcommand, TunnelForwardArgs, TunnelGcArgs, TunnelIdSubCommands, TunnelListArgs,
+		TunnelLogsArgs, TunnelRenameArgs, TunnelServeArgs, TunnelServerInfoArgs, TunnelServiceArgs,
+		TunnelServiceSubCommands, TunnelSftpArgs, TunnelSshConfigArgs, TunnelStatsArgs,
+		TunnelStdioBridgeArgs, TunnelUserSubCommands,
 	},
-	CommandContext,
+	output::{Column, OutputTable},
+	update_cache_for, CommandContext,
 };
 
 use crate::{
 	auth::Auth,
+	constants::{APPLICATION_NAME, REVERSE_FORWARD_PORT, SSH_BRIDGE_PORT, VSCODE_CLI_QUALITY},
+	experiments::refresh_remote_flags,
 	log::{self, Logger},
+	options::{ConnectionTokenMode, Quality},
 	state::LauncherPaths,
 	tunnels::{
-		code_server::CodeServerArgs, create_service_manager, dev_tunnels, legal,
-		paths::get_all_servers, ServiceContainer, ServiceManager,
+		anonymous::{
+			delete_leftover_tunnels, forget_temporary_tunnel, record_temporary_tunnel,
+			share_anonymous, AnonymousShareOptions,
+		},
+		ca_certs, check_service_executable,
+		code_server::{install_server_from_archive, CodeServerArgs},
+		create_service_manager,
+		dev_tunnels::{self, ActiveTunnel, TunnelPortAccess},
+		dotfiles::{bootstrap_dotfiles, DotfilesOptions},
+		folder_ports::{read_folder_ports, FolderPort},
+		forward_targets::ForwardTargetPolicy,
+		fs_jail::FsJail,
+		hooks::run_hook,
+		ip_filter::{Cidr, IpFilter},
+		legal, load_service_registration,
+		local_web::{start_local_web, LocalWebOptions},
+		machine_id::{
+			accept_machine_fingerprint, check_machine_identity, get_machine_identity,
+			rotate_machine_identity,
+		},
+		maintenance::MaintenanceWindows,
+		notifications::Notifier,
+		paths::{
+			clean_abandoned_installs, find_installed_server, get_all_servers, get_pinned_version,
+			get_session_env, set_session_env, stop_running_servers,
+		},
+		relay_breaker::{load_relay_health, RelayRetryOptions},
+		reverse_forward, save_service_registration,
+		security_audit::{self, CheckStatus, SecurityFix},
+		server_routing::ServerRouting,
+		server_selection::ServerSelection,
+		session_share::SessionShare,
+		settings_sync::bootstrap_settings_sync,
+		ssh_bridge,
+		stats_history::{load_stats_history, HourlyStats},
+		workspace_trust::WorkspacePolicy,
+		ServeOptions, ServiceContainer, ServiceExecutable, ServiceManager, ServiceRegistration,
 	},
+	update_service::{TargetKind, UpdateService, UpdateServiceCache},
 	util::{
-		errors::{wrap, AnyError},
+		command::capture_command_and_check_status,
+		errors::{
+			wrap, AnyError, InvalidTunnelExpiry, InvalidWorkspacePolicy, NoInstalledServerError,
+			NoReverseForwards,
+		},
+		http::ReqwestSimpleHttp,
+		input::{prompt_consent, prompt_yn},
+		machine::get_host_resources,
 		prereqs::PreReqChecker,
 	},
 };
 
+#[cfg(feature = "test-relay")]
+use super::args::TunnelTestRelayArgs;
+#[cfg(feature = "test-relay")]
+use crate::tunnels::test_relay::TestRelay;
+
+impl From<AuthFeature> for crate::auth::AuthFeature {
+	fn from(feature: AuthFeature) -> Self {
+		match feature {
+			AuthFeature::SettingsSync => crate::auth::AuthFeature::SettingsSync,
+		}
+	}
+}
+
 impl From<AuthProvider> for crate::auth::AuthProvider {
 	fn from(auth_provider: AuthProvider) -> Self {
 		match auth_provider {
@@ -60,11 +126,12 @@ impl From<ExistingTunnelArgs> for Option<dev_tunnels::ExistingTunnel> {
 
 struct TunnelServiceContainer {
 	args: CliCore,
+	service: TunnelServiceArgs,
 }
 
 impl TunnelServiceContainer {
-	fn new(args: CliCore) -> Self {
-		Self { args }
+	fn new(args: CliCore, service: TunnelServiceArgs) -> Self {
+		Self { args, service }
 	}
 }
 
@@ -77,14 +144,20 @@ impl ServiceContainer for TunnelServiceContainer {
 		shutdown_rx: mpsc::UnboundedReceiver<ShutdownSignal>,
 	) -> Result<(), AnyError> {
 		let csa = (&self.args).into();
+		let update_cache = update_cache_for(&self.args, &launcher_paths);
 		serve_with_csa(
 			launcher_paths,
 			log,
 			TunnelServeArgs {
 				random_name: true, // avoid prompting
+				server: self.service.server.clone(),
+				forward: self.service.forward.clone(),
+				allow_ip: self.service.allow_ip.clone(),
+				deny_ip: self.service.deny_ip.clone(),
 				..Default::default()
 			},
 			csa,
+			update_cache,
 			Some(shutdown_rx),
 		)
 		.await?;
@@ -94,62 +167,96 @@ impl ServiceContainer for TunnelServiceContainer {
 /// Describes the signal to manully stop the server
 pub enum ShutdownSignal {
 	CtrlC,
+	/// Ctrl-Break was pressed in the console, on Windows.
+	CtrlBreak,
+	/// The console window was closed, on Windows.
+	ConsoleClosed,
+	/// The user is logging off, on Windows.
+	LoggedOff,
+	/// The system is shutting down, on Windows.
+	SystemShutdown,
 	ParentProcessKilled,
 	ServiceStopped,
+	/// The process embedding the CLI through `command-shell` asked to stop.
+	StopRequested,
 }
 
 impl fmt::Display for ShutdownSignal {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match self {
 			ShutdownSignal::CtrlC => write!(f, "Ctrl-C received"),
+			ShutdownSignal::CtrlBreak => write!(f, "Ctrl-Break received"),
+			ShutdownSignal::ConsoleClosed => write!(f, "Console closed"),
+			ShutdownSignal::LoggedOff => write!(f, "User logging off"),
+			ShutdownSignal::SystemShutdown => write!(f, "System shutting down"),
 			ShutdownSignal::ParentProcessKilled => write!(f, "Parent process no longer exists"),
 			ShutdownSignal::ServiceStopped => write!(f, "Service stopped"),
+			ShutdownSignal::StopRequested => write!(f, "Stop requested"),
 		}
 	}
 }
 
+/// Waits for the console to ask the process to stop. On Windows, that's
+/// Ctrl-Break, the console closing, logoff, or shutdown as well as Ctrl-C;
+/// for the last three, Windows waits a few seconds for the process to exit.
+#[cfg(windows)]
+pub async fn console_shutdown_signal() -> ShutdownSignal {
+	use tokio::signal::windows::{ctrl_break, ctrl_close, ctrl_logoff, ctrl_shutdown};
+
+	let (mut brk, mut close, mut logoff, mut shutdown) =
+		match (ctrl_break(), ctrl_close(), ctrl_logoff(), ctrl_shutdown()) {
+			(Ok(b), Ok(c), Ok(l), Ok(s)) => (b, c, l, s),
+			_ => {
+				tokio::signal::ctrl_c().await.ok();
+				return ShutdownSignal::CtrlC;
+			}
+		};
+
+	tokio::select! {
+		_ = tokio::signal::ctrl_c() => ShutdownSignal::CtrlC,
+		_ = brk.recv() => ShutdownSignal::CtrlBreak,
+		_ = close.recv() => ShutdownSignal::ConsoleClosed,
+		_ = logoff.recv() => ShutdownSignal::LoggedOff,
+		_ = shutdown.recv() => ShutdownSignal::SystemShutdown,
+	}
+}
+
+/// Waits for the console to ask the process to stop with Ctrl-C.
+#[cfg(not(windows))]
+pub async fn console_shutdown_signal() -> ShutdownSignal {
+	tokio::signal::ctrl_c().await.ok();
+	ShutdownSignal::CtrlC
+}
+
 pub async fn service(
 	ctx: CommandContext,
 	service_args: TunnelServiceSubCommands,
 ) -> Result<i32, AnyError> {
 	let manager = create_service_manager(ctx.log.clone(), &ctx.paths);
 	match service_args {
-		TunnelServiceSubCommands::Install => {
-			// ensure logged in, otherwise subsequent serving will fail
-			Auth::new(&ctx.paths, ctx.log.clone())
-				.get_credential()
-				.await?;
-
-			// likewise for license consent
-			legal::require_consent(&ctx.paths, false)?;
-
-			let current_exe =
-				std::env::current_exe().map_err(|e| wrap(e, "could not get current exe"))?;
-
-			manager
-				.register(
-					current_exe,
-					&[
-						"--verbose",
-						"--cli-data-dir",
-						ctx.paths.root().as_os_str().to_string_lossy().as_ref(),
-						"tunnel",
-						"service",
-						"internal-run",
-					],
-				)
-				.await?;
-			ctx.log.result("Service successfully installed! You can use `code tunnel service log` to monitor it, and `code tunnel service uninstall` to remove it.");
+		TunnelServiceSubCommands::Install(service) => {
+			let previous = load_service_registration(&ctx.paths);
+			install_service(&ctx, &manager, &service).await?;
+			ctx.print_change(
+				load_service_registration(&ctx.paths) != previous,
+				"Service successfully installed! You can use `code tunnel service log` to monitor it, and `code tunnel service uninstall` to remove it.",
+			)?;
 		}
 		TunnelServiceSubCommands::Uninstall => {
+			let previous = load_service_registration(&ctx.paths);
 			manager.unregister().await?;
+			save_service_registration(&ctx.paths, None)?;
+			ctx.print_change(previous.is_some(), "Service uninstalled")?;
 		}
 		TunnelServiceSubCommands::Log => {
 			manager.show_logs().await?;
 		}
-		TunnelServiceSubCommands::InternalRun => {
+		TunnelServiceSubCommands::InternalRun(service) => {
 			manager
-				.run(ctx.paths.clone(), TunnelServiceContainer::new(ctx.args))
+				.run(
+					ctx.paths.clone(),
+					TunnelServiceContainer::new(ctx.args, service),
+				)
 				.await?;
 		}
 	}
@@ -157,42 +264,721 @@ pub async fn service(
 	Ok(0)
 }
 
+/// Installs the service, or installs it again with new options, after
+/// checking it'll be able to start.
+pub(crate) async fn install_service(
+	ctx: &CommandContext,
+	manager: &impl ServiceManager,
+	service: &TunnelServiceArgs,
+) -> Result<(), AnyError> {
+	// checked now, rather than leaving the service failing to start
+	service
+		.server
+		.selection()
+		.check(get_pinned_version(&ctx.paths).as_ref(), None)?;
+
+	// ensure logged in, otherwise subsequent serving will fail
+	Auth::new(&ctx.paths, ctx.log.clone())
+		.get_credential()
+		.await?;
+
+	// likewise for license consent
+	legal::require_consent(&ctx.paths, false)?;
+
+	let data_dir = ctx.paths.root().as_os_str().to_string_lossy().to_string();
+	let mut args = vec!["--verbose", "--cli-data-dir", data_dir.as_str()];
+	let priority = ctx
+		.args
+		.global_options
+		.maintenance_priority
+		.map(|p| p.to_string());
+	if let Some(p) = &priority {
+		args.extend(["--maintenance-priority", p.as_str()]);
+	}
+	if ctx.args.global_options.force_x64 {
+		args.push("--force-x64");
+	}
+	if ctx.args.global_options.record_sessions {
+		args.push("--record-sessions");
+	}
+	let retention = ctx
+		.args
+		.global_options
+		.session_retention
+		.map(|d| d.to_string());
+	if let Some(r) = &retention {
+		args.extend(["--session-retention", r.as_str()]);
+	}
+	if let Some(r) = &ctx.args.global_options.recording_recipient {
+		args.extend(["--recording-recipient", r.as_str()]);
+	}
+	if ctx.args.global_options.require_signed {
+		args.push("--require-signed");
+	}
+	if let Some(p) = &ctx.args.global_options.proxy {
+		args.extend(["--proxy", p.as_str()]);
+	}
+	let tls_files = [
+		("--tls-ca-file", &ctx.args.global_options.tls_ca_file),
+		(
+			"--tls-client-cert",
+			&ctx.args.global_options.tls_client_cert,
+		),
+		("--tls-client-key", &ctx.args.global_options.tls_client_key),
+	]
+	.into_iter()
+	.filter_map(|(flag, file)| {
+		file.as_ref()
+			.map(|f| (flag, f.to_string_lossy().to_string()))
+	})
+	.collect::<Vec<_>>();
+	for (flag, file) in &tls_files {
+		args.extend([*flag, file.as_str()]);
+	}
+	let connections = ctx
+		.args
+		.global_options
+		.download_connections
+		.map(|c| c.to_string());
+	if let Some(c) = &connections {
+		args.extend(["--download-connections", c.as_str()]);
+	}
+	let retry_attempts = ctx
+		.args
+		.global_options
+		.update_retry_attempts
+		.map(|a| a.to_string());
+	if let Some(a) = &retry_attempts {
+		args.extend(["--update-retry-attempts", a.as_str()]);
+	}
+	let timeouts = [
+		(
+			"--http-connect-timeout",
+			ctx.args.global_options.http_connect_timeout,
+		),
+		(
+			"--http-read-timeout",
+			ctx.args.global_options.http_read_timeout,
+		),
+	]
+	.into_iter()
+	.filter_map(|(flag, secs)| secs.map(|s| (flag, s.to_string())))
+	.collect::<Vec<_>>();
+	for (flag, secs) in &timeouts {
+		args.extend([*flag, secs.as_str()]);
+	}
+	if let Some(e) = &ctx.args.global_options.update_endpoint {
+		args.extend(["--update-endpoint", e.as_str()]);
+	}
+	let layout = ctx
+		.args
+		.global_options
+		.update_endpoint_layout
+		.map(|l| l.to_string());
+	if let Some(l) = &layout {
+		args.extend(["--update-endpoint-layout", l.as_str()]);
+	}
+	let fixup = ctx
+		.args
+		.global_options
+		.server_binary_fixup
+		.map(|f| f.to_string());
+	if let Some(f) = &fixup {
+		args.extend(["--server-binary-fixup", f.as_str()]);
+	}
+	args.extend(["tunnel", "service", "internal-run"]);
+	let mut args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+	service.add_run_args(&mut args);
+
+	register_service(&ctx.paths, manager, args).await
+}
+
+/// Registers the service to run this executable with the arguments.
+async fn register_service(
+	paths: &LauncherPaths,
+	manager: &impl ServiceManager,
+	args: Vec<String>,
+) -> Result<(), AnyError> {
+	let current_exe = std::env::current_exe().map_err(|e| wrap(e, "could not get current exe"))?;
+	let arg_refs: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+	manager.register(current_exe.clone(), &arg_refs).await?;
+	save_service_registration(
+		paths,
+		Some(ServiceRegistration {
+			exe: current_exe,
+			args,
+		}),
+	)?;
+	Ok(())
+}
+
+/// Registers the service again if it runs a CLI that's since been moved or
+/// removed, which would otherwise leave it failing to start. Asks first when
+/// there's someone to ask. Returns whether the service was left broken.
+async fn repair_service_registration(log: &Logger, paths: &LauncherPaths) -> bool {
+	let registration = match load_service_registration(paths) {
+		Some(r) => r,
+		None => return false,
+	};
+
+	let missing = match check_service_executable(&registration) {
+		ServiceExecutable::Missing(p) => p,
+		_ => return false,
+	};
+
+	warning!(
+		log,
+		"The tunnel service is registered to run {}, which no longer exists",
+		missing.display()
+	);
+
+	if atty::is(atty::Stream::Stdin)
+		&& !prompt_yn("Register the service again to run this CLI?").unwrap_or(false)
+	{
+		return true;
+	}
+
+	let manager = create_service_manager(log.clone(), paths);
+	match register_service(paths, &manager, registration.args).await {
+		Ok(()) => {
+			info!(log, "Registered the tunnel service again to run this CLI");
+			false
+		}
+		Err(e) => {
+			warning!(log, "Error registering the tunnel service again: {}", e);
+			true
+		}
+	}
+}
+
+/// Checks for problems with this machine's tunnel setup.
+pub async fn doctor(ctx: CommandContext, args: TunnelDoctorArgs) -> Result<i32, AnyError> {
+	let mut problems = 0;
+
+	match load_service_registration(&ctx.paths) {
+		None => ctx.log.result("Service: not registered by this CLI"),
+		Some(r) => match check_service_executable(&r) {
+			ServiceExecutable::Current => ctx.log.result("Service: runs this CLI"),
+			ServiceExecutable::Other(p) => ctx.log.result(&format!(
+				"Service: runs another CLI at {}. Run `{} tunnel service install` to run this one instead.",
+				p.display(),
+				APPLICATION_NAME
+			)),
+			ServiceExecutable::Missing(p) => {
+				ctx.log.result(&format!(
+					"Service: runs {}, which no longer exists",
+					p.display()
+				));
+				if repair_service_registration(&ctx.log, &ctx.paths).await {
+					problems += 1;
+				} else {
+					ctx.log.result("Service: now runs this CLI");
+				}
+			}
+		},
+	}
+
+	if let Some(file) = ctx.paths.config().server_ca_certs {
+		match ca_certs::check(&file) {
+			Ok(n) => ctx.log.result(&format!(
+				"Server CA certificates: {} trusted from {}",
+				n,
+				file.display()
+			)),
+			Err(e) => {
+				ctx.log.result(&format!("Server CA certificates: {}", e));
+				problems += 1;
+			}
+		}
+	}
+
+	if args.security {
+		problems += security_doctor(&ctx, args.fix).await?;
+	}
+
+	Ok(if problems > 0 { 1 } else { 0 })
+}
+
+/// Runs the security audit, fixing what it finds if asked to. Returns the
+/// number of checks that still fail.
+async fn security_doctor(ctx: &CommandContext, fix: bool) -> Result<usize, AnyError> {
+	let auth = Auth::new(&ctx.paths, ctx.log.clone());
+	let manager = create_service_manager(ctx.log.clone(), &ctx.paths);
+	let mut checks = security_audit::audit(&ctx.paths, &auth, &manager);
+
+	if fix {
+		let mut fixed = 0;
+		for check in checks.iter_mut() {
+			let result = match check.fix.take() {
+				Some(SecurityFix::Restrict(paths, mode)) => {
+					security_audit::restrict_paths(&paths, mode)
+						.map_err(|e| AnyError::from(wrap(e, "error restricting permissions")))
+				}
+				Some(SecurityFix::ReregisterService) => match load_service_registration(&ctx.paths)
+				{
+					Some(r) => register_service(&ctx.paths, &manager, r.args).await,
+					None => Ok(()),
+				},
+				None => continue,
+			};
+
+			match result {
+				Ok(()) => fixed += 1,
+				Err(e) => warning!(ctx.log, "Could not fix {}: {}", check.name, e),
+			}
+		}
+
+		ctx.print_change(fixed > 0, format!("Fixed {} problem(s)", fixed))?;
+		if fixed > 0 {
+			checks = security_audit::audit(&ctx.paths, &auth, &manager);
+		}
+	}
+
+	for check in &checks {
+		let status = match check.status {
+			CheckStatus::Pass => "PASS",
+			CheckStatus::Warn => "WARN",
+			CheckStatus::Fail => "FAIL",
+			CheckStatus::Skipped => "SKIP",
+		};
+		ctx.log
+			.result(&format!("[{}] {}: {}", status, check.name, check.detail));
+		if let (Some(r), CheckStatus::Warn | CheckStatus::Fail) = (&check.remediation, check.status)
+		{
+			ctx.log.result(&format!("       To fix: {}", r));
+		}
+	}
+
+	ctx.log.result(&format!(
+		"Security score: {}/100",
+		security_audit::score(&checks)
+	));
+
+	Ok(checks
+		.iter()
+		.filter(|c| c.status == CheckStatus::Fail)
+		.count())
+}
+
 pub async fn user(ctx: CommandContext, user_args: TunnelUserSubCommands) -> Result<i32, AnyError> {
 	let auth = Auth::new(&ctx.paths, ctx.log.clone());
 	match user_args {
 		TunnelUserSubCommands::Login(login_args) => {
-			auth.login(
-				login_args.provider.map(|p| p.into()),
-				login_args.access_token.to_owned(),
-			)
-			.await?;
+			let creds = auth
+				.login(
+					login_args.provider.map(|p| p.into()),
+					login_args.access_token.to_owned(),
+					&login_args
+						.features
+						.iter()
+						.map(|f| (*f).into())
+						.collect::<Vec<_>>(),
+				)
+				.await?;
+			if login_args.set_default {
+				auth.set_default_provider(creds.provider())?;
+			}
+			ctx.print_change(true, format!("Logged in with {}", creds.provider()))?;
 		}
-		TunnelUserSubCommands::Logout => {
-			auth.clear_credentials()?;
+		TunnelUserSubCommands::Logout(logout_args) => {
+			let provider = logout_args.provider.map(|p| p.into());
+			let logged_in = auth.get_current_credentials()?.get(provider).is_some();
+			match provider {
+				Some(p) => auth.clear_credential(p)?,
+				None => auth.clear_credentials()?,
+			}
+			ctx.print_change(logged_in, "Logged out")?;
 		}
 		TunnelUserSubCommands::Show => {
-			if let Ok(Some(_)) = auth.get_current_credential() {
+			if let Ok(Some(_)) = auth.get_current_credential(None) {
 				ctx.log.result("logged in");
 			} else {
 				ctx.log.result("not logged in");
 				return Ok(1);
 			}
 		}
+		TunnelUserSubCommands::List(format) => {
+			let creds = auth.get_current_credentials()?;
+			let mut provider = Column::new("provider");
+			let mut account = Column::new("account");
+			let mut default = Column::new("default");
+			let mut expires_at = Column::new("expires_at");
+			for cred in creds.iter() {
+				provider.add_row(cred.provider().to_string());
+				account.add_row(match auth.get_account_name(cred).await {
+					Ok(name) => name,
+					Err(e) => {
+						warning!(ctx.log, "Could not get the {} name: {}", cred.provider(), e);
+						"unknown".to_string()
+					}
+				});
+				default.add_row((creds.default_provider() == Some(cred.provider())).to_string());
+				expires_at.add_row(
+					cred.expires_at()
+						.map(|e| e.to_rfc3339())
+						.unwrap_or_else(|| "unknown".to_string()),
+				);
+			}
+
+			format
+				.format
+				.print_table(OutputTable::new(vec![
+					provider, account, default, expires_at,
+				]))
+				.map_err(|e| wrap(e, "error printing accounts"))?;
+		}
 	}
 
 	Ok(0)
 }
 
 /// Remove the tunnel used by this gateway, if any.
+pub async fn id(ctx: CommandContext, id_args: TunnelIdSubCommands) -> Result<i32, AnyError> {
+	let auth = Auth::new(&ctx.paths, ctx.log.clone());
+	let mut dt = dev_tunnels::DevTunnels::new(&ctx.log, auth, &ctx.paths);
+
+	match id_args {
+		TunnelIdSubCommands::Show => {
+			let identity = get_machine_identity(&ctx.paths)?;
+			ctx.log.result(&format!("Machine ID: {}", identity.id));
+			ctx.log
+				.result(&format!("Created: {}", identity.created_at.to_rfc3339()));
+			match dt.current_tunnel() {
+				Some(t) => {
+					ctx.log
+						.result(&format!("Tunnel: {} ({}.{})", t.name, t.id, t.cluster));
+					if let Err(e) = check_machine_identity(&ctx.paths, &t) {
+						ctx.log.result(&format!("Warning: {}", e));
+					}
+				}
+				None => ctx.log.result("Tunnel: not registered"),
+			}
+		}
+		TunnelIdSubCommands::Rotate(rotate_args) => {
+			let previous = dt.current_tunnel();
+			let identity = rotate_machine_identity(&ctx.paths)?;
+			ctx.log.result(&format!("New machine ID: {}", identity.id));
+
+			if previous.is_some() || rotate_args.name.is_some() {
+				let tunnel = dt.reregister_tunnel(rotate_args.name).await?;
+				ctx.log
+					.result(&format!("Registered new tunnel {}", tunnel.name));
+			}
+			if let Some(p) = previous {
+				ctx.log.result(&format!(
+					"Tunnel {} was left for other machines using it. Restart the tunnel or service on this machine to host the new one.",
+					p.name
+				));
+			}
+			ctx.print_change(true, "Rotated the machine identity")?;
+		}
+		TunnelIdSubCommands::Accept => {
+			accept_machine_fingerprint(&ctx.paths)?;
+			dt.claim_current_tunnel()?;
+			ctx.print_change(
+				true,
+				"This machine's current fingerprint and tunnel were accepted",
+			)?;
+		}
+	}
+
+	Ok(0)
+}
+
+pub async fn env(ctx: CommandContext, env_args: TunnelEnvSubCommands) -> Result<i32, AnyError> {
+	let mut env = get_session_env(&ctx.paths);
+
+	match env_args {
+		TunnelEnvSubCommands::Set(set_args) => {
+			let mut changed = false;
+			for var in set_args.vars {
+				ctx.log.result(&format!("Set {}", var.name));
+				changed |= env.get(&var.name) != Some(&var.value);
+				env.insert(var.name, var.value);
+			}
+			set_session_env(&ctx.paths, env)?;
+			ctx.print_change(
+				changed,
+				"Restart running servers, or the tunnel, for them to use the new values.",
+			)?;
+		}
+		TunnelEnvSubCommands::Unset(unset_args) => {
+			let mut code = 0;
+			let mut changed = false;
+			for name in unset_args.names {
+				if env.remove(&name).is_some() {
+					ctx.log.result(&format!("Removed {}", name));
+					changed = true;
+				} else {
+					ctx.log.result(&format!("{} is not set", name));
+					code = 1;
+				}
+			}
+			set_session_env(&ctx.paths, env)?;
+			// for tools, the variables ending up unset is what matters
+			if ctx.args.global_options.json {
+				ctx.print_change(changed, "Unset the variables")?;
+				return Ok(0);
+			}
+			return Ok(code);
+		}
+		TunnelEnvSubCommands::List(list_args) => {
+			for (name, value) in env {
+				if list_args.show_values {
+					ctx.log.result(&format!("{}={}", name, value));
+				} else {
+					ctx.log.result(&name);
+				}
+			}
+		}
+	}
+
+	Ok(0)
+}
+
 pub async fn rename(ctx: CommandContext, rename_args: TunnelRenameArgs) -> Result<i32, AnyError> {
 	let auth = Auth::new(&ctx.paths, ctx.log.clone());
 	let mut dt = dev_tunnels::DevTunnels::new(&ctx.log, auth, &ctx.paths);
-	dt.rename_tunnel(&rename_args.name).await?;
+	// the name's taken by this machine's own tunnel, so renaming again would fail
+	let changed = dt.current_tunnel_name().as_deref() != Some(rename_args.name.as_str());
+	if changed {
+		dt.rename_tunnel(&rename_args.name).await?;
+	}
+	ctx.print_change(
+		changed,
+		format!("Successfully renamed this gateway to {}", &rename_args.name),
+	)?;
+
+	Ok(0)
+}
+
+/// Deletes tunnels whose hosts haven't connected within the given duration,
+/// after confirming with the user.
+pub async fn gc(ctx: CommandContext, gc_args: TunnelGcArgs) -> Result<i32, AnyError> {
+	let auth = Auth::new(&ctx.paths, ctx.log.clone());
+	let mut dt = dev_tunnels::DevTunnels::new(&ctx.log, auth, &ctx.paths);
+	let stale = dt
+		.list_tunnels(&[])
+		.await?
+		.into_iter()
+		.filter(|t| t.is_stale(gc_args.older_than.0))
+		.collect::<Vec<_>>();
+
+	if stale.is_empty() {
+		ctx.print_change(
+			false,
+			format!(
+				"No tunnels found whose hosts have been offline for more than {}",
+				gc_args.older_than
+			),
+		)?;
+		return Ok(0);
+	}
+
 	ctx.log.result(&format!(
-		"Successfully renamed this gateway to {}",
-		&rename_args.name
+		"These tunnels' hosts have been offline for more than {}:",
+		gc_args.older_than
 	));
+	for t in &stale {
+		ctx.log.result(&format!(
+			"  {} (last connected: {})",
+			t.name,
+			t.last_seen
+				.map(|l| l.to_rfc3339())
+				.unwrap_or_else(|| "never".to_string())
+		));
+	}
+
+	if gc_args.dry_run {
+		ctx.print_change(false, "Dry run, no tunnels were deleted")?;
+		return Ok(0);
+	}
+
+	if !prompt_yn("Delete these tunnels?")? {
+		return Ok(1);
+	}
+
+	for t in &stale {
+		dt.delete_tunnel(t).await?;
+	}
+
+	ctx.print_change(true, format!("Deleted {} stale tunnels", stale.len()))?;
+	Ok(0)
+}
+
+/// Lists tunnels registered with the service, optionally deleting stale ones.
+pub async fn list(ctx: CommandContext, list_args: TunnelListArgs) -> Result<i32, AnyError> {
+	let auth = Auth::new(&ctx.paths, ctx.log.clone());
+	let mut dt = dev_tunnels::DevTunnels::new(&ctx.log, auth, &ctx.paths);
+	// asking for tags implies looking beyond this machine
+	let all = list_args.all || !list_args.tag.is_empty();
+	let tunnels = dt
+		.list_tunnels(&list_args.tag)
+		.await?
+		.into_iter()
+		.filter(|t| all || t.is_current)
+		.collect::<Vec<_>>();
+
+	let stale_after = chrono::Duration::days(list_args.stale_days.into());
+	let mut name = Column::new("name");
+	let mut id = Column::new("id");
+	let mut host = Column::new("host");
+	let mut last_seen = Column::new("last_seen");
+	let mut status = Column::new("status");
+	let mut tags = Column::new("tags");
+	for tunnel in &tunnels {
+		let deleted = if list_args.delete_stale && tunnel.is_stale(stale_after) {
+			match dt.delete_tunnel(tunnel).await {
+				Ok(()) => true,
+				Err(e) => {
+					warning!(ctx.log, "Failed to delete tunnel {}: {}", tunnel.name, e);
+					false
+				}
+			}
+		} else {
+			false
+		};
+
+		name.add_row(tunnel.name.clone());
+		id.add_row(format!("{}.{}", tunnel.id, tunnel.cluster));
+		host.add_row(match (&tunnel.host_id, tunnel.is_current) {
+			(_, true) => "this machine".to_string(),
+			(Some(h), false) => h.clone(),
+			(None, false) => "unknown".to_string(),
+		});
+		last_seen.add_row(
+			tunnel
+				.last_seen
+				.map(|l| l.to_rfc3339())
+				.unwrap_or_else(|| "never".to_string()),
+		);
+		status.add_row(
+			if deleted {
+				"deleted"
+			} else if tunnel.online {
+				"online"
+			} else if tunnel.is_stale(stale_after) {
+				"stale"
+			} else {
+				"offline"
+			}
+			.to_string(),
+		);
+		tags.add_row(tunnel.tags.join(","));
+	}
+
+	list_args
+		.format
+		.format
+		.print_table(OutputTable::new(vec![
+			name, id, host, last_seen, status, tags,
+		]))
+		.map_err(|e| wrap(e, "error printing tunnels"))?;
+
+	Ok(0)
+}
+
+/// Prints OpenSSH config with a Host block for each tunnel registered under
+/// the current account, which connect through `tunnel stdio-bridge`.
+pub async fn ssh_config(ctx: CommandContext, args: TunnelSshConfigArgs) -> Result<i32, AnyError> {
+	let auth = Auth::new(&ctx.paths, ctx.log.clone());
+	let mut dt = dev_tunnels::DevTunnels::new(&ctx.log, auth, &ctx.paths);
+	let tunnels = dt.list_tunnels(&[]).await?;
+	let exe = std::env::current_exe().map_err(|e| wrap(e, "could not get current exe"))?;
 
+	let mut config = format!(
+		"# Generated by `{} tunnel ssh-config`. Hosts must be started with `--ssh-port`.\n",
+		APPLICATION_NAME
+	);
+	for tunnel in &tunnels {
+		config.push_str(&format!("\nHost {}{}\n", args.prefix, tunnel.name));
+		config.push_str(&format!("\tHostName {}\n", tunnel.name));
+		if let Some(user) = &args.user {
+			config.push_str(&format!("\tUser {}\n", user));
+		}
+		config.push_str(&format!(
+			"\tProxyCommand \"{}\" tunnel stdio-bridge %h\n",
+			exe.display()
+		));
+	}
+
+	ctx.log.result(config);
+	Ok(0)
+}
+
+/// Gets access to a port of one of the account's tunnels.
+async fn get_port_access(
+	log: &Logger,
+	paths: &LauncherPaths,
+	name: &str,
+	port: u16,
+) -> Result<TunnelPortAccess, AnyError> {
+	let auth = Auth::new(paths, log.clone());
+	let mut dt = dev_tunnels::DevTunnels::new(log, auth, paths);
+	dt.get_port_access(name, port).await
+}
+
+/// Listens on a local port for SFTP connections to a tunnel's host.
+pub async fn sftp(ctx: CommandContext, args: TunnelSftpArgs) -> Result<i32, AnyError> {
+	let access = get_port_access(&ctx.log, &ctx.paths, &args.name, SSH_BRIDGE_PORT).await?;
+	ssh_bridge::bridge_local_port(&ctx.log, args.port, &access.uri, &access.token).await?;
+	Ok(0)
+}
+
+/// Relays stdin and stdout to the SSH server of a tunnel's host.
+pub async fn stdio_bridge(
+	ctx: CommandContext,
+	args: TunnelStdioBridgeArgs,
+) -> Result<i32, AnyError> {
+	// stdout carries the SSH connection, so keep logs out of it
+	let level = if ctx.args.global_options.verbose {
+		log::Level::Trace
+	} else {
+		ctx.args.global_options.log.unwrap_or(log::Level::Warn)
+	};
+	let log = ctx.log.to_stderr(level);
+
+	let result = match get_port_access(&log, &ctx.paths, &args.name, SSH_BRIDGE_PORT).await {
+		Ok(access) => ssh_bridge::bridge_stdio(&access.uri, &access.token).await,
+		Err(e) => Err(e),
+	};
+
+	// errors returned from commands are printed to stdout, so print it here
+	match result {
+		Ok(()) => Ok(0),
+		Err(e) => {
+			error!(log, "{}", e);
+			Ok(1)
+		}
+	}
+}
+
+/// Forwards ports on a tunnel's host to targets reachable from this machine,
+/// once the user allows each of them. `--yes` doesn't allow them, since
+/// they open this machine to the host.
+pub async fn forward(ctx: CommandContext, args: TunnelForwardArgs) -> Result<i32, AnyError> {
+	reverse_forward::check_rules(&args.reverse)?;
+	let mut rules = vec![];
+	for rule in args.reverse {
+		let allowed = prompt_consent(&format!(
+			"Allow anyone on the host of {} to connect to {} on this machine through its port {}?",
+			args.name, rule.target, rule.port
+		))?;
+		if allowed {
+			rules.push(rule);
+		} else {
+			ctx.log.result(format!(
+				"Not forwarding port {} to {}",
+				rule.port, rule.target
+			));
+		}
+	}
+	if rules.is_empty() {
+		return Err(NoReverseForwards().into());
+	}
+
+	let access = get_port_access(&ctx.log, &ctx.paths, &args.name, REVERSE_FORWARD_PORT).await?;
+	reverse_forward::forward_reverse(&ctx.log, &access.uri, &access.token, &rules).await?;
 	Ok(0)
 }
 
@@ -200,24 +986,521 @@ pub async fn rename(ctx: CommandContext, rename_args: TunnelRenameArgs) -> Resul
 pub async fn unregister(ctx: CommandContext) -> Result<i32, AnyError> {
 	let auth = Auth::new(&ctx.paths, ctx.log.clone());
 	let mut dt = dev_tunnels::DevTunnels::new(&ctx.log, auth, &ctx.paths);
+	let previous = dt.current_tunnel();
 	dt.remove_tunnel().await?;
+	ctx.print_change(
+		previous.is_some(),
+		match previous {
+			Some(t) => format!("Unregistered tunnel {}", t.name),
+			None => "No tunnel is registered".to_string(),
+		},
+	)?;
+	Ok(0)
+}
+
+#[derive(Serialize)]
+struct ServerInfo {
+	name: String,
+	commit: String,
+	quality: String,
+	platform: String,
+	download_url: String,
+	size: Option<u64>,
+}
+
+/// Resolves the server release that serving a tunnel would install, and
+/// prints where it comes from, without downloading it.
+pub async fn server_info(ctx: CommandContext, args: TunnelServerInfoArgs) -> Result<i32, AnyError> {
+	let platform = match args.platform {
+		Some(p) => p,
+		None => PreReqChecker::new().verify().await?,
+	};
+	let quality = args.quality.unwrap_or_else(default_quality);
+	let target = if args.web {
+		TargetKind::Web
+	} else {
+		TargetKind::Server
+	};
+
+	let update_service = UpdateService::new(
+		ctx.log.clone(),
+		ReqwestSimpleHttp::with_client(ctx.http.clone()),
+	)
+	.with_cache(ctx.update_cache());
+	let release = update_service
+		.get_latest_commit(platform, target, quality)
+		.await?;
+
+	let info = ServerInfo {
+		download_url: update_service.get_download_url(&release)?,
+		size: update_service.get_download_size(&release).await?,
+		// the release may be for another platform, if there are no builds for this one
+		platform: if args.web {
+			release.platform.web()
+		} else {
+			release.platform.headless()
+		},
+		quality: quality.to_string(),
+		name: release.name,
+		commit: release.commit,
+	};
+
+	if ctx.args.global_options.json {
+		println!(
+			"{}",
+			serde_json::to_string_pretty(&info).map_err(|e| wrap(e, "error serializing"))?
+		);
+	} else {
+		ctx.log.result(&format!("Name: {}", info.name));
+		ctx.log.result(&format!("Commit: {}", info.commit));
+		ctx.log.result(&format!("Quality: {}", info.quality));
+		ctx.log.result(&format!("Platform: {}", info.platform));
+		ctx.log
+			.result(&format!("Download URL: {}", info.download_url));
+		ctx.log.result(&format!(
+			"Size: {}",
+			info.size
+				.map(|s| format!("{} bytes", s))
+				.unwrap_or_else(|| "unknown".to_string())
+		));
+	}
+
+	Ok(0)
+}
+
+/// Manages extensions on an installed server by running the server's own
+/// CLI, so that extensions can be managed without opening an editor.
+pub async fn ext(ctx: CommandContext, args: TunnelExtArgs) -> Result<i32, AnyError> {
+	let server = find_installed_server(&ctx.paths, args.quality).ok_or(NoInstalledServerError())?;
+	let executable = server.server_paths(&ctx.paths).executable;
+	debug!(
+		ctx.log,
+		"Managing extensions with server {}",
+		executable.display()
+	);
+
+	let mut code_args = vec![];
+	// what's reported when the server changed the extensions, if anything
+	let change = match args.subcommand {
+		TunnelExtSubcommand::List(a) => {
+			ExtensionSubcommand::List(a).add_code_args(&mut code_args);
+			None
+		}
+		TunnelExtSubcommand::Install(a) => {
+			ExtensionSubcommand::Install(a).add_code_args(&mut code_args);
+			Some("Installed the extension(s)")
+		}
+		TunnelExtSubcommand::Uninstall(a) => {
+			ExtensionSubcommand::Uninstall(a).add_code_args(&mut code_args);
+			Some("Uninstalled the extension(s)")
+		}
+		TunnelExtSubcommand::Update(a) => {
+			let ids = if a.id.is_empty() {
+				list_server_extensions(&executable).await?
+			} else {
+				a.id
+			};
+
+			if ids.is_empty() {
+				ctx.print_change(false, "No extensions are installed")?;
+				return Ok(0);
+			}
+
+			// installing with --force updates to the latest version
+			ExtensionSubcommand::Install(InstallExtensionArgs {
+				id_or_path: ids,
+				pre_release: a.pre_release,
+				force: true,
+			})
+			.add_code_args(&mut code_args);
+			Some("Updated the extension(s)")
+		}
+	};
+
+	let mut command = tokio::process::Command::new(&executable);
+	command.args(&code_args);
+	let status = match (change, ctx.args.global_options.json) {
+		// stdout is kept for the JSON result, so the server's output is logged
+		(Some(_), true) => {
+			let output = command
+				.stderr(std::process::Stdio::inherit())
+				.output()
+				.await
+				.map_err(|e| wrap(e, "error running the server"))?;
+			for line in String::from_utf8_lossy(&output.stdout).lines() {
+				ctx.log.result(line);
+			}
+			output.status
+		}
+		_ => command
+			.status()
+			.await
+			.map_err(|e| wrap(e, "error running the server"))?,
+	};
+
+	if let (Some(message), true) = (change, status.success()) {
+		ctx.print_change(true, message)?;
+	}
+
+	Ok(status.code().unwrap_or(1))
+}
+
+/// Gets the IDs of the extensions installed on the server.
+async fn list_server_extensions(executable: &Path) -> Result<Vec<String>, AnyError> {
+	let output = capture_command_and_check_status(executable, &["--list-extensions"]).await?;
+	Ok(String::from_utf8_lossy(&output.stdout)
+		.lines()
+		.map(|l| l.trim())
+		.filter(|l| !l.is_empty())
+		.map(|l| l.to_string())
+		.collect())
+}
+
+/// Gets the server quality to use when none is given on the command line.
+pub(crate) fn default_quality() -> Quality {
+	VSCODE_CLI_QUALITY
+		.and_then(|q| Quality::try_from(q).ok())
+		.unwrap_or(Quality::Stable)
+}
+
+/// Prints stats recorded while hosting the tunnel, grouped by day or hour.
+pub async fn stats(ctx: CommandContext, stats_args: TunnelStatsArgs) -> Result<i32, AnyError> {
+	let name = match stats_args.name {
+		Some(n) => n,
+		None => {
+			let auth = Auth::new(&ctx.paths, ctx.log.clone());
+			let dt = dev_tunnels::DevTunnels::new(&ctx.log, auth, &ctx.paths);
+			match dt.current_tunnel_name() {
+				Some(n) => n,
+				None => {
+					ctx.log.result("No tunnel has been hosted on this machine");
+					return Ok(1);
+				}
+			}
+		}
+	};
+
+	let history = load_stats_history(&ctx.paths, &name, chrono::Utc::now() - stats_args.since.0);
+	let period_format = if stats_args.hourly {
+		"%Y-%m-%d %H:00"
+	} else {
+		"%Y-%m-%d"
+	};
+
+	let mut periods: Vec<(String, HourlyStats)> = vec![];
+	for hour in history {
+		let period = hour
+			.hour
+			.with_timezone(&chrono::Local)
+			.format(period_format)
+			.to_string();
+		match periods.last_mut() {
+			Some((p, stats)) if *p == period => stats.merge(&hour),
+			_ => periods.push((period, hour)),
+		}
+	}
+
+	let mut period = Column::new("period");
+	let mut uptime = Column::new("uptime_hours");
+	let mut connections = Column::new("connections");
+	let mut reconnects = Column::new("reconnects");
+	let mut rx = Column::new("rx_bytes");
+	let mut tx = Column::new("tx_bytes");
+	for (p, stats) in periods {
+		period.add_row(p);
+		uptime.add_row(format!("{:.1}", stats.uptime_secs as f64 / 3600.0));
+		connections.add_row(stats.connections.to_string());
+		reconnects.add_row(stats.reconnects.to_string());
+		rx.add_row(stats.rx.to_string());
+		tx.add_row(stats.tx.to_string());
+	}
+
+	stats_args
+		.format
+		.format
+		.print_table(OutputTable::new(vec![
+			period,
+			uptime,
+			connections,
+			reconnects,
+			rx,
+			tx,
+		]))
+		.map_err(|e| wrap(e, "error printing stats"))?;
+
 	Ok(0)
 }
 
+/// Shows the state of the credentials used to host the tunnel, including
+/// any recent refresh failures. Exits with a non-zero code if there's a problem.
+pub async fn status(ctx: CommandContext) -> Result<i32, AnyError> {
+	let auth = Auth::new(&ctx.paths, ctx.log.clone());
+	let creds = match auth.get_current_credential(None) {
+		Ok(Some(c)) => c,
+		_ => {
+			ctx.log.result("not logged in");
+			return Ok(1);
+		}
+	};
+
+	let health = auth.get_credential_health();
+	ctx.log
+		.result(&format!("Logged in with: {}", creds.provider()));
+	ctx.log.result(&format!(
+		"Token expires: {}",
+		creds
+			.expires_at()
+			.map(|e| e.to_rfc3339())
+			.unwrap_or_else(|| "unknown".to_string())
+	));
+	ctx.log.result(&format!(
+		"Token can be refreshed: {}",
+		if creds.can_refresh() { "yes" } else { "no" }
+	));
+	if let Some(at) = health.last_refreshed_at {
+		ctx.log
+			.result(&format!("Last refreshed: {}", at.to_rfc3339()));
+	}
+	if let (Some(e), Some(at)) = (health.last_refresh_error, health.last_refresh_error_at) {
+		ctx.log.result(&format!(
+			"Last refresh failed at {}: {}",
+			at.to_rfc3339(),
+			e
+		));
+	}
+
+	let relay = load_relay_health(&ctx.paths);
+	match &relay.unreachable_since {
+		Some(since) => ctx.log.result(&format!(
+			"Relay: degraded: relay unreachable since {}",
+			since.to_rfc3339()
+		)),
+		None => ctx.log.result("Relay: ok"),
+	}
+	if let (Some(_), Some(e)) = (&relay.unreachable_since, &relay.last_error) {
+		ctx.log.result(&format!("Last relay error: {}", e));
+	}
+
+	let session_env = get_session_env(&ctx.paths);
+	if !session_env.is_empty() {
+		let names: Vec<&str> = session_env.keys().map(|k| k.as_str()).collect();
+		ctx.log
+			.result(&format!("Session environment: {}", names.join(", ")));
+	}
+
+	let workspace = dirs::home_dir().unwrap_or_default();
+	let resources = get_host_resources(&workspace);
+	ctx.log.result(&format!(
+		"Load average: {:.2}, {:.2}, {:.2} ({} CPUs)",
+		resources.load_average[0],
+		resources.load_average[1],
+		resources.load_average[2],
+		resources.cpus
+	));
+	ctx.log.result(&format!(
+		"Free memory: {} of {}",
+		format_bytes(resources.available_memory),
+		format_bytes(resources.total_memory)
+	));
+	let low_disk = match (resources.disk_available, resources.disk_total) {
+		(Some(available), Some(total)) => {
+			ctx.log.result(&format!(
+				"Free disk: {} of {} ({})",
+				format_bytes(available),
+				format_bytes(total),
+				workspace.display()
+			));
+			available < LOW_DISK_SPACE
+		}
+		_ => false,
+	};
+
+	let mut code = 0;
+	if low_disk {
+		ctx.log.result(&format!(
+			"Warning: less than {} of disk space is free",
+			format_bytes(LOW_DISK_SPACE)
+		));
+		code = 1;
+	}
+	if let Some(w) = auth.get_expiry_warning() {
+		ctx.log.result(&format!("Warning: {}", w.message));
+		code = 1;
+	}
+	if relay.unreachable_since.is_some() {
+		code = 1;
+	}
+
+	Ok(code)
+}
+
+/// Free disk space below which `status` warns.
+const LOW_DISK_SPACE: u64 = 1024 * 1024 * 1024;
+
+fn format_bytes(bytes: u64) -> String {
+	const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+	let mut value = bytes as f64;
+	let mut unit = 0;
+	while value >= 1024.0 && unit < UNITS.len() - 1 {
+		value /= 1024.0;
+		unit += 1;
+	}
+
+	if unit == 0 {
+		format!("{} {}", bytes, UNITS[0])
+	} else {
+		format!("{:.1} {}", value, UNITS[unit])
+	}
+}
+
+/// Prints the end of the service and server logs, interleaved by time.
+pub async fn logs(ctx: CommandContext, args: TunnelLogsArgs) -> Result<i32, AnyError> {
+	let mut lines = vec![];
+	if args.source != LogSource::Server {
+		let service_log = ctx.paths.service_log_file();
+		if args.source == LogSource::Service && !service_log.exists() {
+			ctx.log.result(
+				"The tunnel service hasn't written a log here; try `tunnel service log` instead.",
+			);
+			return Ok(1);
+		}
+		lines.extend(parse_log_lines(log::read_rotated_log(&service_log)));
+	}
+	if args.source != LogSource::Service {
+		lines.extend(parse_log_lines(log::read_rotated_log(
+			&ctx.paths.server_log_file(),
+		)));
+	}
+
+	let since = args.since.map(|t| t.to_string());
+	let until = args.until.map(|t| t.to_string());
+	lines.retain(|l| {
+		since.as_ref().map(|s| &l.timestamp >= s).unwrap_or(true)
+			&& until.as_ref().map(|u| &l.timestamp < u).unwrap_or(true)
+			&& args
+				.level
+				.map(|min| l.level.map(|lvl| lvl >= min).unwrap_or(false))
+				.unwrap_or(true)
+			&& args
+				.grep
+				.as_ref()
+				.map(|re| re.is_match(&l.plain))
+				.unwrap_or(true)
+	});
+
+	// stable, so lines with the same timestamp stay in order
+	lines.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+	let end = lines.len().saturating_sub(args.offset);
+	for line in &lines[end.saturating_sub(args.lines)..end] {
+		ctx.log.result(&line.line);
+	}
+
+	Ok(0)
+}
+
+/// A line read from a log file.
+struct LogLine {
+	timestamp: String,
+	level: Option<log::Level>,
+	/// The line without colors, to match against.
+	plain: String,
+	line: String,
+}
+
+/// Parses the timestamp and level of log lines. Lines without them, like
+/// continuations of multi-line messages, use the previous line's.
+fn parse_log_lines(lines: Vec<String>) -> Vec<LogLine> {
+	let mut last = (String::new(), None);
+	lines
+		.into_iter()
+		.map(|line| {
+			let plain = strip_ansi(&line);
+			if let Some(ts) = plain
+				.strip_prefix('[')
+				.and_then(|p| p.get(..LOG_TIMESTAMP_LEN))
+				.filter(|_| plain.as_bytes().get(LOG_TIMESTAMP_LEN + 1) == Some(&b']'))
+			{
+				let level = plain
+					.get(LOG_TIMESTAMP_LEN + 2..)
+					.and_then(|rest| rest.split_whitespace().next())
+					.and_then(parse_log_level);
+				last = (ts.to_string(), level);
+			}
+			LogLine {
+				timestamp: last.0.clone(),
+				level: last.1,
+				plain,
+				line,
+			}
+		})
+		.collect()
+}
+
+fn parse_log_level(name: &str) -> Option<log::Level> {
+	[
+		log::Level::Trace,
+		log::Level::Debug,
+		log::Level::Info,
+		log::Level::Warn,
+		log::Level::Error,
+		log::Level::Critical,
+	]
+	.into_iter()
+	.find(|l| l.name() == Some(name))
+}
+
+/// Length of timestamps written by the logger, like `2022-10-01 12:34:56`.
+const LOG_TIMESTAMP_LEN: usize = 19;
+
+fn strip_ansi(s: &str) -> String {
+	let mut out = String::with_capacity(s.len());
+	let mut chars = s.chars();
+	while let Some(c) = chars.next() {
+		if c == '\x1b' {
+			// skip the escape sequence through its final letter
+			for c in chars.by_ref() {
+				if c.is_ascii_alphabetic() {
+					break;
+				}
+			}
+		} else {
+			out.push(c);
+		}
+	}
+	out
+}
+
 /// Removes unused servers.
 pub async fn prune(ctx: CommandContext) -> Result<i32, AnyError> {
-	get_all_servers(&ctx.paths)
+	let mut removed = 0;
+	for s in get_all_servers(&ctx.paths)
 		.into_iter()
 		.map(|s| s.server_paths(&ctx.paths))
 		.filter(|s| s.get_running_pid().is_none())
-		.try_for_each(|s| {
-			ctx.log
-				.result(&format!("Deleted {}", s.server_dir.display()));
-			s.delete()
-		})
-		.map_err(AnyError::from)?;
+	{
+		s.delete()?;
+		ctx.log
+			.result(&format!("Deleted {}", s.server_dir.display()));
+		removed += 1;
+	}
+
+	ctx.print_change(removed > 0, format!("Removed {} unused server(s)", removed))?;
 
-	ctx.log.result("Successfully removed all unused servers");
+	Ok(0)
+}
+
+/// Stops running servers, leaving the tunnel up to start them again.
+pub async fn restart_server(ctx: CommandContext) -> Result<i32, AnyError> {
+	let stopped = stop_running_servers(&ctx.paths).await;
+	if stopped.is_empty() {
+		ctx.print_change(false, "No servers are running")?;
+		return Ok(0);
+	}
+
+	for s in &stopped {
+		ctx.log.result(&format!("Stopped server {}", s.commit));
+	}
+	ctx.print_change(true, "Servers will be started again when clients reconnect")?;
 
 	Ok(0)
 }
@@ -228,17 +1511,370 @@ pub async fn serve(ctx: CommandContext, gateway_args: TunnelServeArgs) -> Result
 		log, paths, args, ..
 	} = ctx;
 
+	if gateway_args.anonymous {
+		return serve_anonymous(&log, &paths, &gateway_args).await;
+	}
+
 	legal::require_consent(&paths, gateway_args.accept_server_license_terms)?;
+	repair_service_registration(&log, &paths).await;
 
 	let csa = (&args).into();
-	serve_with_csa(paths, log, gateway_args, csa, None).await
+	let update_cache = update_cache_for(&args, &paths);
+	serve_with_csa(paths, log, gateway_args, csa, update_cache, None).await
 }
 
-async fn serve_with_csa(
+/// Default lifetime of tunnels from `--anonymous`.
+const DEFAULT_ANONYMOUS_EXPIRY_MINS: i64 = 30;
+
+/// Shares a single port through a temporary, anonymous tunnel, deleting the
+/// tunnel once it expires, or when the next one starts if this is killed.
+async fn serve_anonymous(
+	log: &Logger,
+	paths: &LauncherPaths,
+	gateway_args: &TunnelServeArgs,
+) -> Result<i32, AnyError> {
+	let expires = gateway_args
+		.expires
+		.as_ref()
+		.map(|d| d.0)
+		.unwrap_or_else(|| chrono::Duration::minutes(DEFAULT_ANONYMOUS_EXPIRY_MINS));
+	if expires <= chrono::Duration::zero() || expires > chrono::Duration::days(1) {
+		return Err(InvalidTunnelExpiry(format!(
+			"'{}' must be between 1 second and 1 day",
+			DurationArg(expires)
+		))
+		.into());
+	}
+
+	let auth = Auth::new(paths, log.clone());
+	let mut dt = dev_tunnels::DevTunnels::new(log, auth, paths);
+	delete_leftover_tunnels(log, paths, &mut dt).await;
+	let (tunnel, persisted) = dt.start_anonymous_tunnel().await?;
+	if let Err(e) = record_temporary_tunnel(paths, &persisted) {
+		warning!(log, "Error recording temporary tunnel: {}", e);
+	}
+
+	let result = share_anonymous(
+		log,
+		tunnel,
+		AnonymousShareOptions {
+			port: gateway_args.port.unwrap(), // required by clap with --anonymous
+			expires: expires.to_std().unwrap(),
+		},
+	)
+	.await;
+
+	match dt.delete_temporary_tunnel(&persisted).await {
+		Ok(()) => {
+			info!(log, "Deleted temporary tunnel {}", persisted.name);
+			forget_temporary_tunnel(paths, &persisted);
+		}
+		Err(e) => warning!(
+			log,
+			"Error deleting temporary tunnel {}: {}",
+			persisted.name,
+			e
+		),
+	}
+
+	result.map(|_| 0)
+}
+
+/// Deletes stale tunnels without prompting, for the opt-in `--gc-older-than`
+/// policy. Failures are logged, since they shouldn't prevent hosting.
+async fn delete_stale_tunnels(
+	log: &Logger,
+	dt: &mut dev_tunnels::DevTunnels,
+	older_than: chrono::Duration,
+) {
+	let tunnels = match dt.list_tunnels(&[]).await {
+		Ok(t) => t,
+		Err(e) => {
+			warning!(log, "Error listing tunnels to clean up: {}", e);
+			return;
+		}
+	};
+
+	for t in tunnels.iter().filter(|t| t.is_stale(older_than)) {
+		match dt.delete_tunnel(t).await {
+			Ok(()) => info!(log, "Deleted stale tunnel {}", t.name),
+			Err(e) => warning!(log, "Error deleting stale tunnel {}: {}", t.name, e),
+		}
+	}
+}
+
+/// Gets the maintenance windows from the arguments and the config file.
+fn maintenance_windows(
+	log: &Logger,
+	paths: &LauncherPaths,
+	gateway_args: &TunnelServeArgs,
+) -> MaintenanceWindows {
+	let mut windows = gateway_args.maintenance_window.clone();
+	for w in paths.config().maintenance_windows {
+		match w.parse() {
+			Ok(w) => windows.push(w),
+			Err(e) => warning!(log, "Ignoring maintenance window in config.json: {}", e),
+		}
+	}
+
+	let windows = MaintenanceWindows(windows);
+	if !windows.is_empty() {
+		info!(log, "Updates and restarts are limited to: {}", windows);
+	}
+	windows
+}
+
+/// Gets how often to update servers in the background from the arguments or
+/// the config file, if at all.
+fn server_update_interval(
+	log: &Logger,
+	paths: &LauncherPaths,
+	gateway_args: &TunnelServeArgs,
+) -> Option<std::time::Duration> {
+	let interval = match &gateway_args.server_update_interval {
+		Some(i) => *i,
+		None => match paths
+			.config()
+			.server_update_interval?
+			.parse::<DurationArg>()
+		{
+			Ok(i) => i,
+			Err(e) => {
+				warning!(log, "Ignoring server update interval in config.json: {}", e);
+				return None;
+			}
+		},
+	};
+
+	match interval.0.to_std() {
+		Ok(i) if !i.is_zero() => Some(i),
+		_ => {
+			warning!(
+				log,
+				"Ignoring server update interval {}, it must be positive",
+				interval
+			);
+			None
+		}
+	}
+}
+
+/// Gets the addresses clients may connect from, from the flags and
+/// config.json. Unlike other settings, invalid ranges in config.json are an
+/// error, since ignoring them could let in clients they were meant to keep
+/// out.
+fn ip_filter(paths: &LauncherPaths, gateway_args: &TunnelServeArgs) -> Result<IpFilter, AnyError> {
+	let config = paths.config();
+	let parse = |key: &str, ranges: Vec<String>| {
+		ranges
+			.into_iter()
+			.map(|r| {
+				r.parse::<Cidr>()
+					.map_err(|e| wrap(e, format!("invalid '{}' in config.json", key)))
+			})
+			.collect::<Result<Vec<_>, _>>()
+	};
+
+	let mut allow = gateway_args.allow_ip.clone();
+	allow.extend(parse("allowIp", config.allow_ip)?);
+	let mut deny = gateway_args.deny_ip.clone();
+	deny.extend(parse("denyIp", config.deny_ip)?);
+	Ok(IpFilter { allow, deny })
+}
+
+/// Gets the hosts ports may be forwarded to from the flags and config.json.
+fn forward_target_policy(
+	log: &Logger,
+	paths: &LauncherPaths,
+	gateway_args: &TunnelServeArgs,
+) -> ForwardTargetPolicy {
+	let mut allowed = gateway_args.forward_allow.clone();
+	for a in paths.config().forward_allow {
+		match a.parse() {
+			Ok(a) => allowed.push(a),
+			Err(e) => warning!(log, "Ignoring forward target in config.json: {}", e),
+		}
+	}
+
+	if !allowed.is_empty() {
+		let list: Vec<String> = allowed.iter().map(|a| a.to_string()).collect();
+		info!(log, "Ports may be forwarded to: {}", list.join(", "));
+	}
+	ForwardTargetPolicy { allowed }
+}
+
+/// Gets the server selected with `--quality` and `--commit`, checking up
+/// front that it doesn't conflict with the pinned version or local web UI.
+fn server_selection(
+	log: &Logger,
+	paths: &LauncherPaths,
+	gateway_args: &TunnelServeArgs,
+) -> Result<ServerSelection, AnyError> {
+	let selection = gateway_args.server.selection();
+	let pinned = get_pinned_version(paths);
+	selection.check(
+		pinned.as_ref(),
+		gateway_args
+			.local_web_quality
+			.map(|q| ("--local-web-quality", q)),
+	)?;
+
+	match (&selection.commit, &pinned) {
+		(Some(c), Some(p)) => info!(
+			log,
+			"Hosting server commit {}, rather than the pinned version {}", c, p
+		),
+		(Some(c), None) => info!(log, "Hosting server commit {}", c),
+		_ => {}
+	}
+	Ok(selection)
+}
+
+/// Gets which clients are served the alternate server, if one was given.
+fn server_routing(log: &Logger, gateway_args: &TunnelServeArgs) -> ServerRouting {
+	if let Some(a) = &gateway_args.alternate_server {
+		info!(
+			log,
+			"Hosting the alternate {} server for clients that ask for it", a
+		);
+	}
+
+	ServerRouting {
+		alternate: gateway_args.alternate_server.clone(),
+		identities: gateway_args.alternate_server_for.clone(),
+		selection: gateway_args.server.selection(),
+	}
+}
+
+/// Gets the trusted folders and workspace root from the flags and config.json.
+fn workspace_policy(
+	log: &Logger,
+	paths: &LauncherPaths,
+	gateway_args: &TunnelServeArgs,
+) -> Result<WorkspacePolicy, AnyError> {
+	let config = paths.config();
+	let mut trusted_folders = gateway_args.trusted_folder.clone();
+	trusted_folders.extend(config.trusted_folders);
+	let root = gateway_args
+		.workspace_root
+		.clone()
+		.or(config.workspace_root)
+		.or_else(|| gateway_args.jail.clone());
+
+	let policy = WorkspacePolicy::new(trusted_folders, root).map_err(InvalidWorkspacePolicy)?;
+	// the server is jailed to the root, so a jail can't be given elsewhere
+	if let (Some(jail), Some(root)) = (&gateway_args.jail, &policy.root) {
+		if std::fs::canonicalize(jail).ok().as_ref() != Some(root) {
+			return Err(InvalidWorkspacePolicy(format!(
+				"the workspace root {} must be the jail {} when both are given",
+				root.display(),
+				jail.display()
+			))
+			.into());
+		}
+	}
+	if let Some(root) = &policy.root {
+		info!(log, "Clients are limited to files in {}", root.display());
+	}
+	for f in &policy.trusted_folders {
+		debug!(log, "Clients will trust {}", f.display());
+	}
+	Ok(policy)
+}
+
+/// Gets the folder clients open by default from the flags or config.json,
+/// as an absolute path.
+fn default_folder(
+	log: &Logger,
+	paths: &LauncherPaths,
+	gateway_args: &TunnelServeArgs,
+	workspace: &WorkspacePolicy,
+) -> Option<PathBuf> {
+	let folder = gateway_args
+		.default_folder
+		.clone()
+		.or(paths.config().default_folder)?;
+	let folder = std::env::current_dir().unwrap_or_default().join(folder);
+	if !workspace.allows(&folder) {
+		warning!(
+			log,
+			"Ignoring default folder {}, it's outside the workspace root",
+			folder.display()
+		);
+		return None;
+	}
+
+	Some(folder)
+}
+
+/// Reads the ports the default folder asks to be forwarded, keeping them
+/// private unless the host allows the folder to make them public.
+fn folder_ports(
+	log: &Logger,
+	paths: &LauncherPaths,
+	gateway_args: &TunnelServeArgs,
+	folder: &Path,
+) -> Vec<FolderPort> {
+	let allow_public =
+		gateway_args.allow_public_folder_ports || paths.config().allow_public_folder_ports;
+	let ports = read_folder_ports(log, folder, allow_public);
+	if !ports.is_empty() {
+		info!(
+			log,
+			"Forwarding {} port(s) from the dev container in {}",
+			ports.len(),
+			folder.display()
+		);
+	}
+	ports
+}
+
+/// Hosts the tunnel through the fake relay, if `--test-relay` was given.
+#[cfg(feature = "test-relay")]
+async fn start_test_tunnel(
+	log: &Logger,
+	gateway_args: &TunnelServeArgs,
+) -> Result<Option<ActiveTunnel>, AnyError> {
+	let addr = match &gateway_args.test_relay {
+		Some(a) => a,
+		None => return Ok(None),
+	};
+
+	warning!(log, "Hosting the tunnel through the test relay at {}", addr);
+	let name = gateway_args
+		.name
+		.clone()
+		.unwrap_or_else(|| "test".to_string());
+	ActiveTunnel::start_test_tunnel(log, name, addr)
+		.await
+		.map(Some)
+}
+
+#[cfg(not(feature = "test-relay"))]
+async fn start_test_tunnel(
+	_log: &Logger,
+	_gateway_args: &TunnelServeArgs,
+) -> Result<Option<ActiveTunnel>, AnyError> {
+	Ok(None)
+}
+
+/// Runs the fake relay that tunnels started with `--test-relay` are hosted
+/// through, until Ctrl+C is pressed.
+#[cfg(feature = "test-relay")]
+pub async fn test_relay(ctx: CommandContext, args: TunnelTestRelayArgs) -> Result<i32, AnyError> {
+	let relay = TestRelay::start(ctx.log.clone(), &args.listen).await?;
+	ctx.log
+		.result(format!("Test relay listening on {}", relay.local_addr()));
+	tokio::signal::ctrl_c().await.ok();
+	Ok(0)
+}
+
+pub(crate) async fn serve_with_csa(
 	paths: LauncherPaths,
 	log: Logger,
 	gateway_args: TunnelServeArgs,
-	csa: CodeServerArgs,
+	mut csa: CodeServerArgs,
+	update_cache: Option<UpdateServiceCache>,
 	shutdown_rx: Option<mpsc::UnboundedReceiver<ShutdownSignal>>,
 ) -> Result<i32, AnyError> {
 	// Intentionally read before starting the server. If the server updated and
@@ -246,15 +1882,89 @@ async fn serve_with_csa(
 	// current_exe will point to the wrong path.
 	let current_exe = std::env::current_exe().unwrap();
 	let platform = spanf!(log, log.span("prereq"), PreReqChecker::new().verify())?;
+	let workspace = workspace_policy(&log, &paths, &gateway_args)?;
+	let default_folder = default_folder(&log, &paths, &gateway_args, &workspace);
+	let selection = server_selection(&log, &paths, &gateway_args)?;
+	let ip_filter = ip_filter(&paths, &gateway_args)?;
+	// the workspace root is enforced by jailing the server to it
+	if let Some(root) = gateway_args.jail.as_ref().or(workspace.root.as_ref()) {
+		let root = std::fs::canonicalize(root)
+			.map_err(|e| wrap(e, format!("error resolving jail {}", root.display())))?;
+		csa.jail = Some(FsJail {
+			root,
+			sandbox: gateway_args.jail_sandbox,
+		});
+	}
+
+	if let Some(archive) = &gateway_args.install_server_from {
+		let server = install_server_from_archive(&log, &paths, archive, platform).await?;
+		log.result(format!(
+			"Installed server {} ({}) for offline use",
+			server.commit, server.quality
+		));
+	}
+
+	// Remove downloads left behind by earlier runs in the background, so
+	// that long-lived hosts don't slowly fill their disk.
+	let cleanup_log = log.clone();
+	let cleanup_paths = paths.clone();
+	tokio::task::spawn_blocking(move || clean_abandoned_installs(&cleanup_log, &cleanup_paths));
+
+	// experiments only change how later work is done, so the tunnel doesn't
+	// wait for them
+	let experiments_log = log.clone();
+	let experiments_paths = paths.clone();
+	let experiments_service =
+		UpdateService::new(log.clone(), ReqwestSimpleHttp::new()).with_cache(update_cache.clone());
+	tokio::spawn(async move {
+		if let Err(e) =
+			refresh_remote_flags(&experiments_log, &experiments_service, &experiments_paths).await
+		{
+			debug!(experiments_log, "Could not refresh experiments: {}", e);
+		}
+	});
 
 	let auth = Auth::new(&paths, log.clone());
-	let mut dt = dev_tunnels::DevTunnels::new(&log, auth, &paths);
-	let tunnel = if let Some(d) = gateway_args.tunnel.clone().into() {
-		dt.start_existing_tunnel(d).await
-	} else {
-		dt.start_new_launcher_tunnel(gateway_args.name, gateway_args.random_name)
-			.await
-	}?;
+	let mut relay_retry = RelayRetryOptions::default();
+	if let Some(max) = &gateway_args.relay_retry_max {
+		relay_retry.max_delay = max.0.to_std().unwrap_or(relay_retry.max_delay);
+	}
+	let tunnel = match start_test_tunnel(&log, &gateway_args).await? {
+		Some(tunnel) => tunnel,
+		None => {
+			let mut dt = dev_tunnels::DevTunnels::new(&log, auth.clone(), &paths)
+				.with_relay_retry(relay_retry)
+				.with_tags(gateway_args.tag.clone());
+			let tunnel = if let Some(d) = gateway_args.tunnel.clone().into() {
+				dt.start_existing_tunnel(d).await
+			} else {
+				dt.start_new_launcher_tunnel(gateway_args.name.clone(), gateway_args.random_name)
+					.await
+			}?;
+
+			if let Some(older_than) = gateway_args.gc_older_than {
+				delete_stale_tunnels(&log, &mut dt, older_than.0).await;
+			}
+			tunnel
+		}
+	};
+
+	if gateway_args.bootstrap_settings_sync {
+		match bootstrap_settings_sync(&log, &auth).await {
+			Ok(extensions) => csa.install_extensions.extend(extensions),
+			Err(e) => warning!(log, "{}", e),
+		}
+	}
+
+	if let Some(repo) = gateway_args.dotfiles_repo.clone() {
+		let options = DotfilesOptions {
+			repo,
+			install_command: gateway_args.dotfiles_install_command.clone(),
+		};
+		if let Err(e) = bootstrap_dotfiles(&log, &paths, &options).await {
+			warning!(log, "Error installing dotfiles: {}", e);
+		}
+	}
 
 	let shutdown_tx = if let Some(tx) = shutdown_rx {
 		tx
@@ -279,20 +1989,113 @@ async fn serve_with_csa(
 			}
 		}
 		tokio::spawn(async move {
-			tokio::signal::ctrl_c().await.ok();
-			tx.send(ShutdownSignal::CtrlC).ok();
+			tx.send(console_shutdown_signal().await).ok();
 		});
 		rx
 	};
 
-	let mut r = crate::tunnels::serve(&log, tunnel, &paths, &csa, platform, shutdown_tx).await?;
+	let local_web = match gateway_args.local_web_port {
+		Some(port) => {
+			let options = LocalWebOptions {
+				host: gateway_args.local_web_host.clone(),
+				port,
+				quality: gateway_args
+					.local_web_quality
+					.unwrap_or_else(default_quality),
+				selection: selection.clone(),
+				token_mode: gateway_args
+					.local_web_connection_token
+					.unwrap_or(ConnectionTokenMode::FixedFromFile),
+				token_file: gateway_args.local_web_connection_token_file.clone(),
+			};
+			let web = start_local_web(&log, &paths, &csa, platform, update_cache.clone(), options)
+				.await?;
+			log.result(&format!("Local web UI available at {}", web.url));
+			Some(web)
+		}
+		None => None,
+	};
+
+	let mut shared_tunnels = paths.config().share_session;
+	shared_tunnels.extend(gateway_args.share_session.iter().cloned());
+	let session_share = if shared_tunnels.is_empty() {
+		None
+	} else {
+		Some(SessionShare::new(
+			log.clone(),
+			paths.clone(),
+			auth.clone(),
+			shared_tunnels,
+		))
+	};
+
+	let hooks = paths.config().hooks;
+	let tunnel_name = tunnel.name.clone();
+	if let Some(command) = hooks.on_start.clone() {
+		let (log, tunnel_name) = (log.clone(), tunnel_name.clone());
+		tokio::spawn(async move { run_hook(&log, "start", &command, &tunnel_name).await });
+	}
+
+	let r = crate::tunnels::serve(
+		&log,
+		tunnel,
+		&paths,
+		&csa,
+		platform,
+		auth,
+		ServeOptions {
+			update_cache,
+			open_browser: gateway_args.open,
+			copy_url: gateway_args.copy_url,
+			chaos: gateway_args.chaos.clone(),
+			ip_filter,
+			ssh_port: gateway_args.ssh_port,
+			allow_reverse_forward: gateway_args.allow_reverse_forward,
+			maintenance: maintenance_windows(&log, &paths, &gateway_args),
+			notifier: Notifier::new(paths.config().notifications),
+			workspace,
+			forwards: gateway_args.forward.clone(),
+			ports: paths.config().ports,
+			folder_ports: default_folder
+				.as_deref()
+				.map(|f| folder_ports(&log, &paths, &gateway_args, f))
+				.unwrap_or_default(),
+			default_folder,
+			forward_targets: forward_target_policy(&log, &paths, &gateway_args),
+			routing: server_routing(&log, &gateway_args),
+			server_update_interval: server_update_interval(&log, &paths, &gateway_args),
+			session_share,
+		},
+		shutdown_tx,
+	)
+	.await;
+
+	// stop the local web server before exiting or respawning, since the new
+	// process will want to listen on the same port.
+	if let Some(web) = local_web {
+		web.kill().await;
+	}
+	if let Some(command) = &hooks.on_stop {
+		run_hook(&log, "stop", command, &tunnel_name).await;
+	}
+
+	let mut r = r?;
 	r.tunnel.close().await.ok();
 
-	if r.respawn {
+	if r.respawn && gateway_args.no_respawn {
+		warning!(
+			log,
+			"The CLI was updated, restart it to use the new version"
+		);
+	} else if r.respawn {
 		warning!(log, "respawn requested, starting new server");
 		// reuse current args, but specify no-forward since tunnels will
-		// already be running in this process, and we cannot do a login
-		let args = std::env::args().skip(1).collect::<Vec<String>>();
+		// already be running in this process, and we cannot do a login. Don't
+		// open the browser again, the user's already been shown the link.
+		let args = std::env::args()
+			.skip(1)
+			.filter(|a| a != "--open")
+			.collect::<Vec<String>>();
 		let exit = std::process::Command::new(current_exe)
 			.args(args)
 			.spawn()
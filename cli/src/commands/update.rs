@@ -3,13 +3,11 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use indicatif::ProgressBar;
-
 use crate::{
 	constants::PRODUCT_NAME_LONG,
 	self_update::SelfUpdate,
 	update_service::UpdateService,
-	util::{errors::AnyError, http::ReqwestSimpleHttp, input::ProgressBarReporter},
+	util::{errors::AnyError, http::ReqwestSimpleHttp},
 };
 
 use super::{args::StandaloneUpdateArgs, CommandContext};
@@ -18,8 +16,9 @@ pub async fn update(ctx: CommandContext, args: StandaloneUpdateArgs) -> Result<i
 	let update_service = UpdateService::new(
 		ctx.log.clone(),
 		ReqwestSimpleHttp::with_client(ctx.http.clone()),
-	);
-	let update_service = SelfUpdate::new(&update_service)?;
+	)
+	.with_cache(ctx.update_cache());
+	let update_service = SelfUpdate::new(&update_service, &ctx.paths)?;
 
 	let current_version = update_service.get_current_release().await?;
 	if update_service.is_up_to_date_with(&current_version) {
@@ -36,10 +35,8 @@ pub async fn update(ctx: CommandContext, args: StandaloneUpdateArgs) -> Result<i
 		return Ok(0);
 	}
 
-	let pb = ProgressBar::new(1);
-	pb.set_message("Downloading...");
 	update_service
-		.do_update(&current_version, ProgressBarReporter::from(pb))
+		.do_update(&current_version, ctx.progress_reporter())
 		.await?;
 	ctx.log
 		.result(format!("Successfully updated to {}", current_version));
@@ -8,13 +8,21 @@ use std::path::{Path, PathBuf};
 use crate::{
 	desktop::{prompt_to_install, CodeVersionManager, RequestedVersion},
 	log,
+	options::Quality,
+	tunnels::paths::{get_pinned_version, set_pinned_version},
+	update_service::UpdateService,
 	util::{
-		errors::{AnyError, NoInstallInUserProvidedPath},
+		errors::{wrap, AnyError, InvalidRequestedVersion, NoInstallInUserProvidedPath},
+		http::ReqwestSimpleHttp,
 		prereqs::PreReqChecker,
 	},
 };
 
-use super::{args::UseVersionArgs, CommandContext};
+use super::{
+	args::{ListVersionArgs, OutputFormat, PinVersionArgs, UseVersionArgs},
+	output::{Column, OutputTable},
+	CommandContext,
+};
 
 pub async fn switch_to(ctx: CommandContext, args: UseVersionArgs) -> Result<i32, AnyError> {
 	let platform = PreReqChecker::new().verify().await?;
@@ -53,6 +61,74 @@ pub async fn show(ctx: CommandContext) -> Result<i32, AnyError> {
 		Some(p) => println!("Installation path: {}", p.display()),
 		None => println!("No existing installation found"),
 	}
+	if let Some(pinned) = get_pinned_version(&ctx.paths) {
+		println!("Pinned server version: {}", pinned);
+	}
+
+	Ok(0)
+}
+
+pub async fn pin(ctx: CommandContext, args: PinVersionArgs) -> Result<i32, AnyError> {
+	let version = match RequestedVersion::try_from(args.version.as_str())? {
+		v @ (RequestedVersion::Version { .. } | RequestedVersion::Commit { .. }) => v,
+		_ => return Err(InvalidRequestedVersion().into()),
+	};
+
+	let changed = get_pinned_version(&ctx.paths).as_ref() != Some(&version);
+	set_pinned_version(&ctx.paths, Some(version.clone()))?;
+	ctx.print_change(
+		changed,
+		format!(
+			"Pinned the server to {}. Restart running tunnels to use it.",
+			version
+		),
+	)?;
+	Ok(0)
+}
+
+pub async fn unpin(ctx: CommandContext) -> Result<i32, AnyError> {
+	let changed = get_pinned_version(&ctx.paths).is_some();
+	set_pinned_version(&ctx.paths, None)?;
+	ctx.print_change(
+		changed,
+		"Unpinned the server version, the latest will be used",
+	)?;
+	Ok(0)
+}
+
+pub async fn list(ctx: CommandContext, args: ListVersionArgs) -> Result<i32, AnyError> {
+	let update_service = UpdateService::new(
+		ctx.log.clone(),
+		ReqwestSimpleHttp::with_client(ctx.http.clone()),
+	);
+	let qualities = match args.quality {
+		Some(q) => vec![q],
+		None => vec![Quality::Stable, Quality::Insiders],
+	};
+
+	let mut quality_col = Column::new("quality");
+	let mut version_col = Column::new("version");
+	for quality in qualities {
+		let versions = update_service.get_versions(quality).await?;
+		let limit = if args.limit == 0 {
+			versions.len()
+		} else {
+			args.limit
+		};
+		for version in versions.into_iter().take(limit) {
+			quality_col.add_row(quality.get_machine_name().to_string());
+			version_col.add_row(version);
+		}
+	}
+
+	let format = if ctx.args.global_options.json {
+		OutputFormat::Json
+	} else {
+		OutputFormat::Text
+	};
+	format
+		.print_table(OutputTable::new(vec![quality_col, version_col]))
+		.map_err(|e| wrap(e, "error printing versions"))?;
 
 	Ok(0)
 }
@@ -11,13 +11,48 @@ use lazy_static::lazy_static;
 use crate::options::Quality;
 
 pub const CONTROL_PORT: u16 = 31545;
+/// Port on which the host serves the SSH bridge, when enabled with `--ssh-port`.
+pub const SSH_BRIDGE_PORT: u16 = 31546;
+/// Port on which the host routes HTTP requests to forwarded services by their
+/// host name or path, for ports forwarded with a `host` or `path`.
+pub const HOST_ROUTER_PORT: u16 = 31547;
+/// Port on which the host serves reverse forwarding, where ports on the host
+/// connect back to services on a client's machine.
+pub const REVERSE_FORWARD_PORT: u16 = 31548;
 
 /// Protocol version sent to clients. This can be used to indiciate new or
 /// changed capabilities that clients may wish to leverage.
 ///  1 - Initial protocol version
 ///  2 - Addition of `serve.compressed` property to control whether servermsg's
 ///      are compressed bidirectionally.
-pub const PROTOCOL_VERSION: u32 = 2;
+///  3 - Addition of `hostping`/`hostpong` and `connectionquality` messages so
+///      clients can show the quality of their connection.
+///  4 - Addition of `clientinfo` so clients can report their address, which
+///      is required before other calls if the host restricts addresses.
+///  5 - Addition of `forwardmany` and `unforwardmany` to change many ports in
+///      one call.
+///  6 - Addition of `resources` to the `version` message, with the host's CPU,
+///      memory, and disk usage.
+///  7 - Addition of `host` and `path` to `forward`, to share one endpoint
+///      between several HTTP services.
+///  8 - Addition of `force` to `update`, to update outside the host's
+///      maintenance windows.
+///  9 - Addition of `trusted_folders` and `workspace_root` to the `version`
+///      message, so clients needn't prompt for workspace trust.
+/// 10 - Addition of `reverse_forward` to the `version` message, set when the
+///      host serves reverse forwarding on `REVERSE_FORWARD_PORT`.
+/// 11 - Addition of `target` to `forward`, to forward a port to another host
+///      reachable from the host, if the host allows it.
+/// 12 - Addition of `correlationId` to requests, an ID of the user action a
+///      request was made for, which the host logs its handling with.
+/// 13 - `restartserver` only restarts the requesting client's server, and
+///      `serverrestarted` is sent before it stops rather than after.
+/// 14 - Addition of `identity` to `clientinfo` and `quality_hint` to `serve`,
+///      which route clients that don't give a `commit_id` to the host's
+///      alternate server.
+/// 15 - Addition of `sharesession`, answered on the host's local control
+///      socket, to connect to the account's tunnels with the host's login.
+pub const PROTOCOL_VERSION: u32 = 15;
 
 pub const VSCODE_CLI_VERSION: Option<&'static str> = option_env!("VSCODE_CLI_VERSION");
 pub const VSCODE_CLI_AI_KEY: Option<&'static str> = option_env!("VSCODE_CLI_AI_KEY");
@@ -27,6 +62,7 @@ pub const DOCUMENTATION_URL: Option<&'static str> = option_env!("VSCODE_CLI_DOCU
 pub const VSCODE_CLI_COMMIT: Option<&'static str> = option_env!("VSCODE_CLI_COMMIT");
 pub const VSCODE_CLI_UPDATE_ENDPOINT: Option<&'static str> =
 	option_env!("VSCODE_CLI_UPDATE_ENDPOINT");
+pub const VSCODE_CLI_SIGNING_KEY: Option<&'static str> = option_env!("VSCODE_CLI_SIGNING_KEY");
 
 pub const TUNNEL_SERVICE_USER_AGENT_ENV_VAR: &str = "TUNNEL_SERVICE_USER_AGENT";
 
@@ -52,6 +88,16 @@ pub const QUALITYLESS_PRODUCT_NAME: &str = match option_env!("VSCODE_CLI_QUALITY
 /// Name of the application without quality information.
 pub const QUALITYLESS_SERVER_NAME: &str = concatcp!(QUALITYLESS_PRODUCT_NAME, " Server");
 
+/// Name of the folder in the user's home directory where the server keeps its data.
+pub const SERVER_DATA_FOLDER_NAME: &str = match option_env!("VSCODE_CLI_SERVER_DATA_FOLDER_NAME") {
+	Some(n) => n,
+	None => ".vscode-server-oss",
+};
+
+/// URL of the Settings Sync service, if configured for this build.
+pub const VSCODE_CLI_SETTINGS_SYNC_URL: Option<&'static str> =
+	option_env!("VSCODE_CLI_SETTINGS_SYNC_URL");
+
 /// Web URL the editor is hosted at. For VS Code, this is vscode.dev.
 pub const EDITOR_WEB_URL: Option<&'static str> = option_env!("VSCODE_CLI_EDITOR_WEB_URL");
 
@@ -0,0 +1,174 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Flags that turn new or risky parts of the CLI on or off, so they can ship
+//! dark and be enabled gradually. The update service can set them remotely,
+//! and its answer is cached locally; 'experiments' in config.json overrides
+//! both, for testing on a single machine.
+
+use std::{collections::HashMap, fmt};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	constants::VSCODE_CLI_QUALITY,
+	debug, log,
+	options::Quality,
+	state::{LauncherPaths, PersistedState},
+	update_service::UpdateService,
+	util::errors::AnyError,
+};
+
+/// Installs servers from deltas against an earlier install, rather than
+/// downloading them in full.
+pub const DELTA_UPDATES: &str = "deltaUpdates";
+
+/// How long flags from the update service are used before asking again.
+const REMOTE_FLAGS_TTL_HOURS: i64 = 24;
+
+/// An experiment the CLI knows about.
+pub struct Experiment {
+	pub name: &'static str,
+	pub description: &'static str,
+	/// Whether it's on when neither the update service nor the config set it.
+	pub default: bool,
+}
+
+/// Every experiment the CLI knows about. Flags for other names are ignored.
+pub const EXPERIMENTS: &[Experiment] = &[Experiment {
+	name: DELTA_UPDATES,
+	description: "Install servers from deltas against an earlier install",
+	default: false,
+}];
+
+/// Where an experiment's state came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExperimentSource {
+	Default,
+	Remote,
+	Local,
+}
+
+impl fmt::Display for ExperimentSource {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ExperimentSource::Default => write!(f, "default"),
+			ExperimentSource::Remote => write!(f, "remote"),
+			ExperimentSource::Local => write!(f, "local"),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct RemoteFlags {
+	fetched_at: Option<DateTime<Utc>>,
+	flags: HashMap<String, bool>,
+}
+
+/// The state of experiments on this machine.
+pub struct Experiments {
+	remote: HashMap<String, bool>,
+	local: HashMap<String, bool>,
+}
+
+impl Experiments {
+	/// Reads the flags last fetched from the update service and the local
+	/// overrides in config.json.
+	pub fn load(paths: &LauncherPaths) -> Self {
+		Experiments {
+			remote: remote_flags_state(paths).load().flags,
+			local: paths.config().experiments.into_iter().collect(),
+		}
+	}
+
+	/// Gets whether the experiment is on, and what decided it.
+	pub fn get(&self, experiment: &Experiment) -> (bool, ExperimentSource) {
+		if let Some(v) = self.local.get(experiment.name) {
+			(*v, ExperimentSource::Local)
+		} else if let Some(v) = self.remote.get(experiment.name) {
+			(*v, ExperimentSource::Remote)
+		} else {
+			(experiment.default, ExperimentSource::Default)
+		}
+	}
+
+	/// Gets whether the named experiment is on. Unknown names are off.
+	pub fn is_enabled(&self, name: &str) -> bool {
+		EXPERIMENTS
+			.iter()
+			.find(|e| e.name == name)
+			.map(|e| self.get(e).0)
+			.unwrap_or(false)
+	}
+}
+
+fn remote_flags_state(paths: &LauncherPaths) -> PersistedState<RemoteFlags> {
+	PersistedState::new(paths.root().join("experiments.json"))
+}
+
+/// Asks the update service for its flags if the cached ones are out of date.
+/// The cached flags are kept if it can't be reached.
+pub async fn refresh_remote_flags(
+	log: &log::Logger,
+	update_service: &UpdateService,
+	paths: &LauncherPaths,
+) -> Result<(), AnyError> {
+	let state = remote_flags_state(paths);
+	let current = state.load();
+	if let Some(fetched_at) = current.fetched_at {
+		if Utc::now() - fetched_at < Duration::hours(REMOTE_FLAGS_TTL_HOURS) {
+			return Ok(());
+		}
+	}
+
+	let quality = VSCODE_CLI_QUALITY
+		.and_then(|q| Quality::try_from(q).ok())
+		.unwrap_or(Quality::Stable);
+	let flags = update_service.get_experiments(quality).await?;
+	debug!(log, "Fetched {} experiment flag(s)", flags.len());
+	state.save(RemoteFlags {
+		fetched_at: Some(Utc::now()),
+		flags,
+	})?;
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_resolves_experiments() {
+		let experiment = &EXPERIMENTS[0];
+		let mut experiments = Experiments {
+			remote: HashMap::new(),
+			local: HashMap::new(),
+		};
+		assert_eq!(
+			experiments.get(experiment),
+			(experiment.default, ExperimentSource::Default)
+		);
+
+		experiments
+			.remote
+			.insert(experiment.name.to_string(), !experiment.default);
+		assert_eq!(
+			experiments.get(experiment),
+			(!experiment.default, ExperimentSource::Remote)
+		);
+
+		experiments
+			.local
+			.insert(experiment.name.to_string(), experiment.default);
+		assert_eq!(
+			experiments.get(experiment),
+			(experiment.default, ExperimentSource::Local)
+		);
+
+		assert!(!experiments.is_enabled("notAnExperiment"));
+	}
+}
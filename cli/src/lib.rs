@@ -11,6 +11,7 @@ pub mod constants;
 pub mod log;
 pub mod commands;
 pub mod desktop;
+pub mod experiments;
 pub mod options;
 pub mod self_update;
 pub mod state;
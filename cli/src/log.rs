@@ -3,22 +3,45 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
+use crate::util::plain::is_plain_output;
 use chrono::Local;
+use lazy_static::lazy_static;
 use opentelemetry::{
 	sdk::trace::{Tracer, TracerProvider},
-	trace::{SpanBuilder, Tracer as TraitTracer, TracerProvider as TracerProviderTrait},
+	trace::{
+		FutureExt, SpanBuilder, TraceContextExt, Tracer as TraitTracer,
+		TracerProvider as TracerProviderTrait,
+	},
+	Context, KeyValue,
 };
 use std::fmt;
-use std::{env, path::Path, sync::Arc};
 use std::{
+	collections::HashMap,
+	future::Future,
 	io::Write,
-	sync::atomic::{AtomicU32, Ordering},
+	sync::atomic::{AtomicU32, AtomicU64, Ordering},
+	time::{Duration, Instant},
+};
+use std::{
+	path::{Path, PathBuf},
+	sync::Arc,
 };
-
-const NO_COLOR_ENV: &str = "NO_COLOR";
 
 static INSTANCE_COUNTER: AtomicU32 = AtomicU32::new(0);
 
+/// Environment variable the correlation ID is passed to child processes in,
+/// so their logs and spans can be traced back to the command that started them.
+pub const CORRELATION_ID_ENV_VAR: &str = "VSCODE_CLI_CORRELATION_ID";
+/// Longest correlation ID that's accepted from another process or a client.
+const MAX_CORRELATION_ID_LEN: usize = 64;
+
+lazy_static! {
+	static ref PROCESS_CORRELATION_ID: String = std::env::var(CORRELATION_ID_ENV_VAR)
+		.ok()
+		.and_then(|id| sanitize_correlation_id(&id))
+		.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+}
+
 // Gets a next incrementing number that can be used in logs
 pub fn next_counter() -> u32 {
 	INSTANCE_COUNTER.fetch_add(1, Ordering::SeqCst)
@@ -70,7 +93,7 @@ impl Level {
 	}
 
 	pub fn color_code(&self) -> Option<&str> {
-		if env::var(NO_COLOR_ENV).is_ok() || !atty::is(atty::Stream::Stdout) {
+		if is_plain_output() || !atty::is(atty::Stream::Stdout) {
 			return None;
 		}
 
@@ -95,19 +118,52 @@ pub fn new_tunnel_prefix() -> String {
 }
 
 pub fn new_code_server_prefix() -> String {
-	format!("[codeserver.{}]", next_counter())
+	"[server]".to_string()
 }
 
 pub fn new_rpc_prefix() -> String {
 	format!("[rpc.{}]", next_counter())
 }
 
+/// Gets the correlation ID of this process. It's inherited from the process
+/// that started this one, if any, and otherwise generated on first use.
+pub fn process_correlation_id() -> &'static str {
+	&PROCESS_CORRELATION_ID
+}
+
+/// Sets the correlation ID of this process in its environment, so processes
+/// it starts, like servers, log and trace with the same ID. Returns the ID.
+pub fn export_correlation_id() -> &'static str {
+	let id = process_correlation_id();
+	std::env::set_var(CORRELATION_ID_ENV_VAR, id);
+	id
+}
+
+/// Checks a correlation ID given by another process or a client, returning
+/// `None` if it's not one that should be written to logs. IDs are limited in
+/// length and characters so they can't be used to bloat or forge log lines.
+pub fn sanitize_correlation_id(id: &str) -> Option<String> {
+	let id = id.trim();
+	if id.is_empty()
+		|| id.len() > MAX_CORRELATION_ID_LEN
+		|| !id
+			.chars()
+			.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+	{
+		return None;
+	}
+
+	Some(id.to_string())
+}
+
 // Base logger implementation
 #[derive(Clone)]
 pub struct Logger {
 	tracer: Tracer,
 	sink: Vec<Box<dyn LogSink>>,
 	prefix: Option<String>,
+	correlation_id: Option<String>,
+	repeats: Option<Arc<std::sync::Mutex<RepeatTracker>>>,
 }
 
 // Copy trick from https://stackoverflow.com/a/30353928
@@ -154,10 +210,31 @@ impl LogSink for StdioLogSink {
 	}
 }
 
+/// Writes logs to stderr, for commands whose stdout is used for data.
+#[derive(Clone)]
+pub struct StderrLogSink {
+	level: Level,
+}
+
+impl LogSink for StderrLogSink {
+	fn write_log(&self, level: Level, prefix: &str, message: &str) {
+		if level < self.level {
+			return;
+		}
+
+		eprint!("{}", format(level, prefix, message));
+	}
+
+	fn write_result(&self, message: &str) {
+		eprintln!("{}", message);
+	}
+}
+
 #[derive(Clone)]
 pub struct FileLogSink {
 	level: Level,
 	file: Arc<std::sync::Mutex<std::fs::File>>,
+	results: bool,
 }
 
 impl FileLogSink {
@@ -166,8 +243,15 @@ impl FileLogSink {
 		Ok(Self {
 			level,
 			file: Arc::new(std::sync::Mutex::new(file)),
+			results: false,
 		})
 	}
+
+	/// Also writes results to the file, for when it stands in for stdout.
+	pub fn with_results(mut self) -> Self {
+		self.results = true;
+		self
+	}
 }
 
 impl LogSink for FileLogSink {
@@ -182,15 +266,114 @@ impl LogSink for FileLogSink {
 		self.file.lock().unwrap().write_all(line.as_bytes()).ok();
 	}
 
+	fn write_result(&self, message: &str) {
+		if self.results {
+			writeln!(self.file.lock().unwrap(), "{}", message).ok();
+		}
+	}
+}
+
+/// Log sink that writes to a file, moving it aside once it reaches a size
+/// limit. Up to `keep` earlier files are kept, named by `rotated_log_path`.
+#[derive(Clone)]
+pub struct RotatingFileLogSink {
+	level: Level,
+	path: PathBuf,
+	max_size: u64,
+	keep: usize,
+	/// Open file, and how many bytes it contains.
+	file: Arc<std::sync::Mutex<(std::fs::File, u64)>>,
+}
+
+impl RotatingFileLogSink {
+	pub fn new(level: Level, path: &Path, max_size: u64, keep: usize) -> std::io::Result<Self> {
+		let file = std::fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(path)?;
+		let size = file.metadata()?.len();
+		Ok(Self {
+			level,
+			path: path.to_owned(),
+			max_size,
+			keep,
+			file: Arc::new(std::sync::Mutex::new((file, size))),
+		})
+	}
+
+	fn rotate(&self, file: &mut (std::fs::File, u64)) -> std::io::Result<()> {
+		for i in (1..self.keep).rev() {
+			std::fs::rename(
+				rotated_log_path(&self.path, i),
+				rotated_log_path(&self.path, i + 1),
+			)
+			.ok();
+		}
+
+		if self.keep > 0 {
+			std::fs::rename(&self.path, rotated_log_path(&self.path, 1))?;
+		}
+
+		*file = (std::fs::File::create(&self.path)?, 0);
+		Ok(())
+	}
+}
+
+impl LogSink for RotatingFileLogSink {
+	fn write_log(&self, level: Level, prefix: &str, message: &str) {
+		if level < self.level {
+			return;
+		}
+
+		let line = format(level, prefix, message);
+		let mut file = self.file.lock().unwrap();
+		if file.1 > 0 && file.1 + line.len() as u64 > self.max_size {
+			self.rotate(&mut file).ok();
+		}
+
+		// ignore any errors, not much we can do if logging fails...
+		if file.0.write_all(line.as_bytes()).is_ok() {
+			file.1 += line.len() as u64;
+		}
+	}
+
 	fn write_result(&self, _message: &str) {}
 }
 
+/// Reads the lines of a log and any files rotated from it, oldest first.
+pub fn read_rotated_log(path: &Path) -> Vec<String> {
+	let mut files = vec![path.to_owned()];
+	for n in 1.. {
+		let rotated = rotated_log_path(path, n);
+		if !rotated.exists() {
+			break;
+		}
+		files.push(rotated);
+	}
+
+	files
+		.iter()
+		.rev()
+		.filter_map(|f| std::fs::read_to_string(f).ok())
+		.flat_map(|contents| contents.lines().map(|l| l.to_string()).collect::<Vec<_>>())
+		.collect()
+}
+
+/// Gets the path of the `n`th most recent rotated file for the log at `path`.
+pub fn rotated_log_path(path: &Path, n: usize) -> PathBuf {
+	let mut name = path.file_name().unwrap_or_default().to_owned();
+	name.push(format!(".{}", n));
+	path.with_file_name(name)
+}
+
 impl Logger {
 	pub fn test() -> Self {
 		Self {
 			tracer: TracerProvider::builder().build().tracer("codeclitest"),
 			sink: vec![],
 			prefix: None,
+			correlation_id: None,
+			repeats: None,
 		}
 	}
 
@@ -199,11 +382,21 @@ impl Logger {
 			tracer,
 			sink: vec![Box::new(StdioLogSink { level })],
 			prefix: None,
+			correlation_id: None,
+			repeats: None,
 		}
 	}
 
+	/// Starts building a span. Spans are tagged with the logger's correlation
+	/// ID, or this process's if the logger has none.
 	pub fn span(&self, name: &str) -> SpanBuilder {
-		self.tracer.span_builder(format!("serverlauncher/{}", name))
+		let correlation_id = self
+			.correlation_id
+			.clone()
+			.unwrap_or_else(|| process_correlation_id().to_string());
+		self.tracer
+			.span_builder(format!("serverlauncher/{}", name))
+			.with_attributes(vec![KeyValue::new("correlation_id", correlation_id)])
 	}
 
 	pub fn tracer(&self) -> &Tracer {
@@ -211,9 +404,57 @@ impl Logger {
 	}
 
 	pub fn emit(&self, level: Level, message: &str) {
+		if let Some(repeats) = &self.repeats {
+			match repeats
+				.lock()
+				.unwrap()
+				.record(level, message, Instant::now())
+			{
+				Repeat::Suppress => return,
+				Repeat::Emit => {}
+				Repeat::EmitAfter(n) => {
+					self.write(level, &format!("previous message repeated {} times", n))
+				}
+			}
+		}
+
+		self.write(level, message);
+	}
+
+	fn write(&self, level: Level, message: &str) {
 		let prefix = self.prefix.as_deref().unwrap_or("");
+		let prefix = match &self.correlation_id {
+			Some(id) => format!("{}[{}] ", prefix, id),
+			None => prefix.to_string(),
+		};
 		for sink in &self.sink {
-			sink.write_log(level, prefix, message);
+			sink.write_log(level, &prefix, message);
+		}
+	}
+
+	/// Creates a copy of the logger that collapses messages repeated within
+	/// a short time, like errors from a client that's flapping, into a count
+	/// of how many times they repeated. Copies made from it share the counts.
+	pub fn deduplicated(&self) -> Logger {
+		Logger {
+			repeats: Some(Arc::new(std::sync::Mutex::new(RepeatTracker::default()))),
+			..self.clone()
+		}
+	}
+
+	/// Writes the counts of messages that were suppressed as repeats, such as
+	/// before a connection they were logged for closes.
+	pub fn flush_repeated(&self) {
+		let flushed = match &self.repeats {
+			Some(r) => r.lock().unwrap().flush(),
+			None => return,
+		};
+
+		for (level, message, n) in flushed {
+			self.write(
+				level,
+				&format!("message repeated {} more times: {}", n, message),
+			);
 		}
 	}
 
@@ -223,6 +464,37 @@ impl Logger {
 		}
 	}
 
+	/// Creates a copy of the logger that only writes to stderr, at the given
+	/// level, so that nothing is written to stdout.
+	pub fn to_stderr(&self, level: Level) -> Logger {
+		Logger {
+			sink: vec![Box::new(StderrLogSink { level })],
+			..self.clone()
+		}
+	}
+
+	/// Creates a copy of the logger that only writes to the given sink.
+	pub fn with_only_sink<T>(&self, sink: T) -> Logger
+	where
+		T: LogSink + 'static,
+	{
+		Logger {
+			sink: vec![Box::new(sink)],
+			..self.clone()
+		}
+	}
+
+	/// Creates a copy of the logger that writes the correlation ID with each
+	/// message and tags its spans with it, such as for work done on behalf of
+	/// a client's command. The ID replaces any the logger already had, rather
+	/// than being added to it, so loggers derived per-call don't grow.
+	pub fn with_correlation_id(&self, id: &str) -> Logger {
+		Logger {
+			correlation_id: Some(id.to_string()),
+			..self.clone()
+		}
+	}
+
 	pub fn prefixed(&self, prefix: &str) -> Logger {
 		Logger {
 			prefix: Some(match &self.prefix {
@@ -260,7 +532,7 @@ pub struct DownloadLogger<'a> {
 	logger: &'a Logger,
 }
 
-impl<'a> crate::util::io::ReportCopyProgress for DownloadLogger<'a> {
+impl<'a> crate::util::progress::ReportProgress for DownloadLogger<'a> {
 	fn report_progress(&mut self, bytes_so_far: u64, total_bytes: u64) {
 		if total_bytes > 0 {
 			self.logger.emit(
@@ -282,6 +554,196 @@ impl<'a> crate::util::io::ReportCopyProgress for DownloadLogger<'a> {
 	}
 }
 
+/// Time after a message is written during which identical messages are
+/// counted rather than written, on deduplicated loggers.
+const REPEAT_WINDOW: Duration = Duration::from_secs(30);
+/// Number of distinct messages tracked for repeats at once.
+const MAX_TRACKED_REPEATS: usize = 64;
+
+enum Repeat {
+	/// The message should be written.
+	Emit,
+	/// The message is a repeat, and should not be written.
+	Suppress,
+	/// The message should be written, after a note that the previous copy
+	/// was repeated this many times.
+	EmitAfter(u32),
+}
+
+struct RepeatedMessage {
+	level: Level,
+	written_at: Instant,
+	suppressed: u32,
+}
+
+/// Tracks recently-written messages for `Logger::deduplicated`.
+#[derive(Default)]
+struct RepeatTracker {
+	messages: HashMap<String, RepeatedMessage>,
+}
+
+impl RepeatTracker {
+	fn record(&mut self, level: Level, message: &str, now: Instant) -> Repeat {
+		if let Some(m) = self.messages.get_mut(message) {
+			if m.level == level && now.duration_since(m.written_at) < REPEAT_WINDOW {
+				m.suppressed += 1;
+				return Repeat::Suppress;
+			}
+
+			let suppressed = std::mem::replace(&mut m.suppressed, 0);
+			m.level = level;
+			m.written_at = now;
+			return match suppressed {
+				0 => Repeat::Emit,
+				n => Repeat::EmitAfter(n),
+			};
+		}
+
+		// forget messages that are no longer repeating, so that the map stays small
+		if self.messages.len() >= MAX_TRACKED_REPEATS {
+			self.messages.retain(|_, m| {
+				m.suppressed > 0 || now.duration_since(m.written_at) < REPEAT_WINDOW
+			});
+			if self.messages.len() >= MAX_TRACKED_REPEATS {
+				return Repeat::Emit;
+			}
+		}
+
+		self.messages.insert(
+			message.to_string(),
+			RepeatedMessage {
+				level,
+				written_at: now,
+				suppressed: 0,
+			},
+		);
+		Repeat::Emit
+	}
+
+	/// Returns and resets the messages that have suppressed repeats.
+	fn flush(&mut self) -> Vec<(Level, String, u32)> {
+		self.messages
+			.iter_mut()
+			.filter(|(_, m)| m.suppressed > 0)
+			.map(|(message, m)| {
+				let n = std::mem::replace(&mut m.suppressed, 0);
+				(m.level, message.clone(), n)
+			})
+			.collect()
+	}
+}
+
+/// Interval at which long-lived spans, like connections, report heartbeats.
+pub const SPAN_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Byte counters for a long-lived span, reported in its heartbeats. Clones
+/// share the same counts.
+#[derive(Clone, Default)]
+pub struct SpanCounters {
+	tx: Arc<AtomicU64>,
+	rx: Arc<AtomicU64>,
+}
+
+impl SpanCounters {
+	pub fn add_tx(&self, n: usize) {
+		self.tx.fetch_add(n as u64, Ordering::Relaxed);
+	}
+
+	pub fn add_rx(&self, n: usize) {
+		self.rx.fetch_add(n as u64, Ordering::Relaxed);
+	}
+
+	pub fn tx(&self) -> u64 {
+		self.tx.load(Ordering::Relaxed)
+	}
+
+	pub fn rx(&self) -> u64 {
+		self.rx.load(Ordering::Relaxed)
+	}
+}
+
+/// Runs the future in the span's context. Every `SPAN_HEARTBEAT_INTERVAL`
+/// until it finishes, a `heartbeat` event is added to the span and logged,
+/// with how long it's been running and the bytes counted since the last one,
+/// so that a long session can be followed through the logs. A `summary`
+/// event with the totals is added once it finishes; the span isn't ended.
+pub async fn with_heartbeat<F: Future>(
+	log: &Logger,
+	name: &str,
+	cx: &Context,
+	counters: Option<&SpanCounters>,
+	f: F,
+) -> F::Output {
+	let started = Instant::now();
+	let mut interval = tokio::time::interval_at(
+		tokio::time::Instant::now() + SPAN_HEARTBEAT_INTERVAL,
+		SPAN_HEARTBEAT_INTERVAL,
+	);
+	let (mut last_tx, mut last_rx) = (0, 0);
+
+	let f = f.with_context(cx.clone());
+	tokio::pin!(f);
+
+	let output = loop {
+		tokio::select! {
+			o = &mut f => break o,
+			_ = interval.tick() => {
+				let mut attributes = vec![KeyValue::new(
+					"duration_ms",
+					started.elapsed().as_millis() as i64,
+				)];
+				let mut message = format!("{} running for {}s", name, started.elapsed().as_secs());
+				if let Some(c) = counters {
+					let (tx, rx) = (c.tx(), c.rx());
+					attributes.push(KeyValue::new("tx", (tx - last_tx) as i64));
+					attributes.push(KeyValue::new("rx", (rx - last_rx) as i64));
+					message.push_str(&format!(
+						", sent {} and received {} bytes since the last heartbeat",
+						tx - last_tx,
+						rx - last_rx
+					));
+					last_tx = tx;
+					last_rx = rx;
+				}
+
+				cx.span().add_event("heartbeat", attributes);
+				log.emit(Level::Debug, &message);
+			}
+		}
+	};
+
+	let mut attributes = vec![KeyValue::new(
+		"duration_ms",
+		started.elapsed().as_millis() as i64,
+	)];
+	if let Some(c) = counters {
+		attributes.push(KeyValue::new("tx", c.tx() as i64));
+		attributes.push(KeyValue::new("rx", c.rx() as i64));
+	}
+	cx.span().add_event("summary", attributes);
+
+	output
+}
+
+/// Spawns the future in a new span of its own, which reports heartbeats until
+/// the future finishes, like `with_heartbeat`.
+pub fn spawn_with_heartbeat<F>(
+	log: &Logger,
+	name: &'static str,
+	counters: Option<SpanCounters>,
+	f: F,
+) where
+	F: Future<Output = ()> + Send + 'static,
+{
+	let log = log.clone();
+	tokio::spawn(async move {
+		let span = log.span(name).start(log.tracer());
+		let cx = Context::current_with_span(span);
+		with_heartbeat(&log, name, &cx, counters.as_ref(), f).await;
+		cx.span().end();
+	});
+}
+
 pub fn format(level: Level, prefix: &str, message: &str) -> String {
 	let current = Local::now();
 	let timestamp = current.format("%Y-%m-%d %H:%M:%S").to_string();
@@ -300,7 +762,7 @@ pub fn format(level: Level, prefix: &str, message: &str) -> String {
 
 pub fn emit(level: Level, prefix: &str, message: &str) {
 	let line = format(level, prefix, message);
-	if level == Level::Trace {
+	if level == Level::Trace && !is_plain_output() && atty::is(atty::Stream::Stdout) {
 		print!("\x1b[2m{}\x1b[0m", line);
 	} else {
 		print!("{}", line);
@@ -395,3 +857,71 @@ macro_rules! spanf {
 		t
 	}};
 }
+
+/// Like `spanf!`, for long-lived operations: the span reports heartbeats
+/// while the operation runs, with the given `SpanCounters`, if any.
+#[macro_export]
+macro_rules! spanh {
+	($logger:expr, $name:expr, $counters:expr, $func:expr) => {{
+		use opentelemetry::trace::TraceContextExt;
+
+		let span = $logger.span($name).start($logger.tracer());
+		let cx = opentelemetry::Context::current_with_span(span);
+		let t = $crate::log::with_heartbeat(&$logger, $name, &cx, $counters, $func).await;
+
+		if let Err(e) = &t {
+			cx.span().record_error(e);
+		}
+
+		cx.span().end();
+
+		t
+	}};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_repeat_tracker() {
+		let mut t = RepeatTracker::default();
+		let start = Instant::now();
+
+		assert!(matches!(t.record(Level::Warn, "a", start), Repeat::Emit));
+		assert!(matches!(
+			t.record(Level::Warn, "a", start),
+			Repeat::Suppress
+		));
+		assert!(matches!(t.record(Level::Warn, "b", start), Repeat::Emit));
+		assert!(matches!(
+			t.record(Level::Warn, "a", start),
+			Repeat::Suppress
+		));
+		assert!(matches!(
+			t.record(Level::Warn, "a", start + REPEAT_WINDOW),
+			Repeat::EmitAfter(2)
+		));
+		assert!(matches!(
+			t.record(Level::Warn, "a", start + REPEAT_WINDOW),
+			Repeat::Suppress
+		));
+
+		let flushed = t.flush();
+		assert_eq!(flushed.len(), 1);
+		assert_eq!(flushed[0].1, "a");
+		assert_eq!(flushed[0].2, 1);
+		assert!(t.flush().is_empty());
+	}
+
+	#[test]
+	fn test_sanitize_correlation_id() {
+		assert_eq!(
+			sanitize_correlation_id(" 3f2a-bc_1.2 "),
+			Some("3f2a-bc_1.2".to_string())
+		);
+		assert_eq!(sanitize_correlation_id(""), None);
+		assert_eq!(sanitize_correlation_id("a\n[error] forged"), None);
+		assert_eq!(sanitize_correlation_id(&"a".repeat(65)), None);
+	}
+}
@@ -113,3 +113,118 @@ impl fmt::Display for TelemetryLevel {
 		}
 	}
 }
+
+/// Whether a locally spawned server requires clients to send a connection
+/// token, and where the token comes from.
+#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConnectionTokenMode {
+	/// No token is required. Only for servers reachable from loopback, such
+	/// as those behind an authenticating proxy on the same machine.
+	None,
+	/// A new token is generated each time the server starts.
+	RotatePerStart,
+	/// The token is read from a file, which is created if it doesn't exist,
+	/// so it stays the same across restarts.
+	FixedFromFile,
+}
+
+impl fmt::Display for ConnectionTokenMode {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ConnectionTokenMode::None => write!(f, "none"),
+			ConnectionTokenMode::RotatePerStart => write!(f, "rotate-per-start"),
+			ConnectionTokenMode::FixedFromFile => write!(f, "fixed-from-file"),
+		}
+	}
+}
+
+/// How URLs are laid out on the update endpoint.
+#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpdateEndpointLayout {
+	/// The update service API, which resolves versions dynamically.
+	Service,
+	/// A mirror of plain files, which can be hosted by any static web server.
+	/// Version metadata is read from `{quality}/latest/{platform}.json` and
+	/// `{quality}/versions/{version}/{platform}.json`, released versions are
+	/// listed in `{quality}/releases.json`, and releases are downloaded from
+	/// `{quality}/{commit}/{platform}`.
+	Static,
+}
+
+impl fmt::Display for UpdateEndpointLayout {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			UpdateEndpointLayout::Service => write!(f, "service"),
+			UpdateEndpointLayout::Static => write!(f, "static"),
+		}
+	}
+}
+
+/// Whether server binaries are patched to run on distributions without the
+/// usual filesystem layout, like NixOS.
+#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServerBinaryFixup {
+	/// Patch binaries with patchelf if their interpreter is missing, warning
+	/// if that fails.
+	Auto,
+	/// Always patch binaries with patchelf, failing the install if that fails.
+	Patchelf,
+	/// Never patch binaries, such as when nix-ld or an FHS environment
+	/// already lets them run.
+	None,
+}
+
+impl fmt::Display for ServerBinaryFixup {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ServerBinaryFixup::Auto => write!(f, "auto"),
+			ServerBinaryFixup::Patchelf => write!(f, "patchelf"),
+			ServerBinaryFixup::None => write!(f, "none"),
+		}
+	}
+}
+
+/// How a filesystem jail for the server is enforced, beyond starting it in
+/// the jail with its home directory set there.
+#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JailSandbox {
+	/// Sandbox the server with Landlock where the kernel supports it,
+	/// warning otherwise.
+	Auto,
+	/// Sandbox the server with Landlock, failing to start it if the kernel
+	/// doesn't support it.
+	Landlock,
+	/// Only set up the server's environment, without a sandbox.
+	Off,
+}
+
+impl fmt::Display for JailSandbox {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			JailSandbox::Auto => write!(f, "auto"),
+			JailSandbox::Landlock => write!(f, "landlock"),
+			JailSandbox::Off => write!(f, "off"),
+		}
+	}
+}
+
+/// Priority at which maintenance work, like extracting and pruning servers,
+/// is run relative to other processes on the machine.
+#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MaintenancePriority {
+	Normal,
+	Low,
+}
+
+impl fmt::Display for MaintenancePriority {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			MaintenancePriority::Normal => write!(f, "normal"),
+			MaintenancePriority::Low => write!(f, "low"),
+		}
+	}
+}
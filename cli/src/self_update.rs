@@ -3,17 +3,21 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::{fs, path::Path, process::Command};
-use tempfile::tempdir;
+use std::{
+	fs,
+	path::{Path, PathBuf},
+	process::Command,
+};
 
 use crate::{
 	constants::{VSCODE_CLI_COMMIT, VSCODE_CLI_QUALITY},
 	options::Quality,
+	state::LauncherPaths,
 	update_service::{unzip_downloaded_release, Platform, Release, TargetKind, UpdateService},
 	util::{
 		errors::{wrap, AnyError, CorruptDownload, UpdatesNotConfigured},
-		http,
-		io::{ReportCopyProgress, SilentCopyProgress},
+		progress::{ProgressStage, ReportProgress},
+		tempfile::{new_temp_dir, new_temp_file_in},
 	},
 };
 
@@ -22,10 +26,11 @@ pub struct SelfUpdate<'a> {
 	quality: Quality,
 	platform: Platform,
 	update_service: &'a UpdateService,
+	data_dir: PathBuf,
 }
 
 impl<'a> SelfUpdate<'a> {
-	pub fn new(update_service: &'a UpdateService) -> Result<Self, AnyError> {
+	pub fn new(update_service: &'a UpdateService, paths: &LauncherPaths) -> Result<Self, AnyError> {
 		let commit = VSCODE_CLI_COMMIT
 			.ok_or_else(|| UpdatesNotConfigured("unknown build commit".to_string()))?;
 
@@ -42,6 +47,7 @@ impl<'a> SelfUpdate<'a> {
 			quality,
 			platform,
 			update_service,
+			data_dir: paths.root().to_owned(),
 		})
 	}
 
@@ -61,28 +67,52 @@ impl<'a> SelfUpdate<'a> {
 	pub async fn do_update(
 		&self,
 		release: &Release,
-		progress: impl ReportCopyProgress,
+		mut progress: impl ReportProgress + Send,
 	) -> Result<(), AnyError> {
 		// 1. Download the archive into a temporary directory
-		let tempdir = tempdir().map_err(|e| wrap(e, "Failed to create temp dir"))?;
-		let archive_path = tempdir.path().join("archive");
-		let stream = self.update_service.get_download_stream(release).await?;
-		http::download_into_file(&archive_path, progress, stream).await?;
+		// The archive is named by commit in the data directory, which only this
+		// user can write to, so a failed download can be resumed by the next
+		// update.
+		let tempdir = new_temp_dir()?;
+		let archive_path = self
+			.data_dir
+			.join(format!("code-cli-{}.partial", release.commit));
+		discard_foreign_file(&archive_path);
+		progress.begin_stage(ProgressStage::Download);
+		self.update_service
+			.download_release(release, &archive_path, &mut progress)
+			.await?;
+		progress.end_stage();
 
 		// 2. Unzip the archive and get the binary
 		let target_path =
 			std::env::current_exe().map_err(|e| wrap(e, "could not get current exe"))?;
-		let staging_path = target_path.with_extension(".update");
+		// stage next to the CLI so it can be renamed into place; it's removed
+		// if anything goes wrong before then.
+		let staging_path = new_temp_file_in(
+			target_path
+				.parent()
+				.ok_or_else(|| wrap("", "could not get CLI directory"))?,
+		)?;
 		let archive_contents_path = tempdir.path().join("content");
-		// unzipping the single binary is pretty small and fast--don't bother with passing progress
-		unzip_downloaded_release(&archive_path, &archive_contents_path, SilentCopyProgress())?;
+		progress.begin_stage(ProgressStage::Extract);
+		let extracted =
+			unzip_downloaded_release(&archive_path, &archive_contents_path, &mut progress);
+		fs::remove_file(&archive_path).ok();
+		extracted?;
 		copy_updated_cli_to_path(&archive_contents_path, &staging_path)?;
+		progress.end_stage();
 
 		// 3. Copy file metadata, make sure the new binary is executable\
 		copy_file_metadata(&target_path, &staging_path)
 			.map_err(|e| wrap(e, "failed to set file permissions"))?;
+		progress.begin_stage(ProgressStage::Verify);
+		progress.report_indeterminate();
 		validate_cli_is_good(&staging_path)?;
+		progress.end_stage();
 
+		progress.begin_stage(ProgressStage::Install);
+		progress.report_indeterminate();
 		// Try to rename the old CLI to the tempdir, where it can get cleaned up by the
 		// OS later. However, this can fail if the tempdir is on a different drive
 		// than the installation dir. In this case just rename it to ".old".
@@ -91,13 +121,42 @@ impl<'a> SelfUpdate<'a> {
 				.map_err(|e| wrap(e, "failed to rename old CLI"))?;
 		}
 
-		fs::rename(&staging_path, &target_path)
-			.map_err(|e| wrap(e, "failed to rename newly installed CLI"))?;
+		staging_path
+			.persist(&target_path)
+			.map_err(|e| wrap(e.error, "failed to rename newly installed CLI"))?;
+		progress.end_stage();
 
 		Ok(())
 	}
 }
 
+/// Removes anything at the path that isn't a file this user created, like a
+/// link or a file left by someone else, so a download isn't resumed into it.
+fn discard_foreign_file(path: &Path) {
+	let metadata = match fs::symlink_metadata(path) {
+		Ok(m) => m,
+		Err(_) => return,
+	};
+
+	if metadata.is_dir() {
+		fs::remove_dir_all(path).ok();
+	} else if !metadata.is_file() || !is_owned_by_current_user(&metadata) {
+		fs::remove_file(path).ok();
+	}
+}
+
+#[cfg(unix)]
+fn is_owned_by_current_user(metadata: &fs::Metadata) -> bool {
+	use std::os::unix::fs::MetadataExt;
+	metadata.uid() == unsafe { libc::geteuid() }
+}
+
+#[cfg(not(unix))]
+fn is_owned_by_current_user(_metadata: &fs::Metadata) -> bool {
+	// files in the data directory inherit its ACL, which only allows this user
+	true
+}
+
 fn validate_cli_is_good(exe_path: &Path) -> Result<(), AnyError> {
 	let o = Command::new(exe_path)
 		.args(["--version"])
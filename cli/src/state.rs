@@ -5,18 +5,139 @@
 
 extern crate dirs;
 
+pub mod migrations;
+
 use std::{
+	collections::BTreeMap,
 	fs::{create_dir, read_to_string, remove_dir_all, write},
 	path::{Path, PathBuf},
 	sync::{Arc, Mutex},
 };
 
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::util::errors::{wrap, AnyError, NoHomeForLauncherError, WrappedError};
+use crate::{
+	commands::args::DurationArg,
+	options::{ServerBinaryFixup, UpdateEndpointLayout},
+	tunnels::{
+		dev_tunnels::PortSetting, forward_targets::AllowedTarget, hooks::TunnelHooks,
+		ip_filter::Cidr, maintenance::MaintenanceWindow, notifications::NotificationChannel,
+	},
+	util::{
+		errors::{wrap, AnyError, InvalidConfigError, NoHomeForLauncherError, WrappedError},
+		io::restrict_to_owner,
+	},
+};
 
 const HOME_DIR_ALTS: [&str; 2] = ["$HOME", "~"];
 
+/// Configuration read from `config.json` in the CLI data directory. Command
+/// line flags and environment variables take precedence over it.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CliConfig {
+	/// URL of the update service or mirror to download releases from.
+	#[serde(default)]
+	pub update_endpoint: Option<String>,
+	/// Layout of URLs on the update endpoint.
+	#[serde(default)]
+	pub update_endpoint_layout: Option<UpdateEndpointLayout>,
+	/// Number of times requests to the update endpoint are attempted.
+	#[serde(default)]
+	pub update_retry_attempts: Option<u32>,
+	/// Whether server binaries are patched to run on this system.
+	#[serde(default)]
+	pub server_binary_fixup: Option<ServerBinaryFixup>,
+	/// Whether downloads must be signed with the key built into the CLI.
+	#[serde(default)]
+	pub require_signed: bool,
+	/// Windows during which the tunnel may update and restart, like
+	/// 'sun 03:00-04:00'.
+	#[serde(default)]
+	pub maintenance_windows: Vec<String>,
+	/// Number of most recently used servers to keep when removing old ones.
+	#[serde(default)]
+	pub server_retention_keep: Option<usize>,
+	/// If set, only servers unused for this many days are removed.
+	#[serde(default)]
+	pub server_retention_days: Option<i64>,
+	/// Where to send notifications about conditions that need attention on
+	/// an unattended host.
+	#[serde(default)]
+	pub notifications: Vec<NotificationChannel>,
+	/// Folders connecting clients are told to trust.
+	#[serde(default)]
+	pub trusted_folders: Vec<PathBuf>,
+	/// If set, clients are denied access to files outside this folder, which
+	/// the server is jailed to.
+	#[serde(default)]
+	pub workspace_root: Option<PathBuf>,
+	/// Folder clients open by default, whose dev container's ports are
+	/// forwarded when the tunnel starts.
+	#[serde(default)]
+	pub default_folder: Option<PathBuf>,
+	/// Whether the default folder's settings may make its ports public,
+	/// rather than them always being forwarded privately.
+	#[serde(default)]
+	pub allow_public_folder_ports: bool,
+	/// PEM file of CA certificates the server trusts for its own requests,
+	/// like to the extension gallery, in addition to the built-in ones.
+	#[serde(default)]
+	pub server_ca_certs: Option<PathBuf>,
+	/// PEM file of CA certificates the CLI trusts for HTTPS requests, in
+	/// addition to the built-in ones.
+	#[serde(default)]
+	pub tls_ca_file: Option<PathBuf>,
+	/// PEM file with a client certificate the CLI presents for HTTPS requests.
+	#[serde(default)]
+	pub tls_client_cert: Option<PathBuf>,
+	/// PEM file with the private key of the client certificate, if it's not
+	/// in the same file.
+	#[serde(default)]
+	pub tls_client_key: Option<PathBuf>,
+	/// Seconds HTTP requests wait to connect. 0 waits indefinitely.
+	#[serde(default)]
+	pub http_connect_timeout: Option<u64>,
+	/// Seconds HTTP requests wait for a response, or for more of a download.
+	/// 0 waits indefinitely.
+	#[serde(default)]
+	pub http_read_timeout: Option<u64>,
+	/// Hosts, domains like '*.internal', or address ranges that ports may be
+	/// forwarded to, in addition to this machine.
+	#[serde(default)]
+	pub forward_allow: Vec<String>,
+	/// Ports on this machine forwarded when the tunnel starts, with their
+	/// labels and visibility.
+	#[serde(default)]
+	pub ports: Vec<PortSetting>,
+	/// Commands run as the tunnel starts and stops.
+	#[serde(default)]
+	pub hooks: TunnelHooks,
+	/// Address ranges, like '10.0.0.0/8', that clients must connect from.
+	#[serde(default)]
+	pub allow_ip: Vec<String>,
+	/// Address ranges clients may not connect from, even if allowed.
+	#[serde(default)]
+	pub deny_ip: Vec<String>,
+	/// Tunnels other clients on this machine may connect to with the tunnel
+	/// daemon's login. If any are set, the daemon serves them locally.
+	#[serde(default)]
+	pub share_session: Vec<String>,
+	/// If set, how often the tunnel checks for new servers, like '6h',
+	/// installing them and swapping them in once clients have been gone for
+	/// the reconnection grace period.
+	#[serde(default)]
+	pub server_update_interval: Option<String>,
+	/// Whether errors in this file stop the CLI from starting, rather than
+	/// the settings they're in being ignored.
+	#[serde(default)]
+	pub strict_config: bool,
+	/// Experiments to turn on or off on this machine, overriding the update
+	/// service, like { "deltaUpdates": true }.
+	#[serde(default)]
+	pub experiments: BTreeMap<String, bool>,
+}
+
 #[derive(Clone)]
 pub struct LauncherPaths {
 	root: PathBuf,
@@ -81,6 +202,11 @@ where
 		}
 	}
 
+	/// Gets the file the state is persisted to.
+	pub fn path(&self) -> PathBuf {
+		self.container.lock().unwrap().path.clone()
+	}
+
 	/// Loads persisted state.
 	pub fn load(&self) -> T {
 		self.container.lock().unwrap().load_or_get()
@@ -119,7 +245,9 @@ impl LauncherPaths {
 		}
 
 		if !Path::new(&replaced).exists() {
+			// it holds credentials and logs, so only the user should read it
 			create_dir(&replaced)
+				.and_then(|_| restrict_to_owner(Path::new(&replaced), 0o700))
 				.map_err(|e| wrap(e, format!("error creating directory {}", &replaced)))?;
 		}
 
@@ -137,11 +265,74 @@ impl LauncherPaths {
 		&self.root
 	}
 
+	/// Reads the CLI's configuration file. It's optional, so this returns the
+	/// default configuration if it doesn't exist or can't be read.
+	pub fn config(&self) -> CliConfig {
+		PersistedState::new(self.config_file()).load()
+	}
+
+	/// Path of the CLI's configuration file.
+	pub fn config_file(&self) -> PathBuf {
+		self.root.join("config.json")
+	}
+
+	/// Whether the configuration file asks to be checked strictly. A file
+	/// that isn't valid JSON is taken to ask for it if it mentions the
+	/// setting at all, so an error elsewhere in it can't turn it off.
+	pub fn config_wants_strict(&self) -> bool {
+		let s = match read_to_string(self.config_file()) {
+			Ok(s) => s,
+			Err(_) => return false,
+		};
+
+		match serde_json::from_str::<serde_json::Value>(&s) {
+			Ok(v) => v.get("strictConfig").and_then(|v| v.as_bool()) == Some(true),
+			Err(_) => s.contains("\"strictConfig\""),
+		}
+	}
+
+	/// Reads the CLI's configuration file, failing on anything `config`
+	/// would ignore: a file that exists but can't be read, invalid JSON,
+	/// unknown settings at any depth, and values of the wrong type or format.
+	/// Errors give the line and column they're at.
+	pub fn check_config(&self) -> Result<CliConfig, AnyError> {
+		let path = self.config_file();
+		let s = match read_to_string(&path) {
+			Ok(s) => s,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(CliConfig::default()),
+			Err(e) => {
+				return Err(InvalidConfigError(format!(
+					"{}: could not be read: {}",
+					path.display(),
+					e
+				))
+				.into())
+			}
+		};
+		check_config_source(&path, &s).map_err(|e| InvalidConfigError(e).into())
+	}
+
 	/// Suggested path for tunnel service logs, when using file logs
 	pub fn service_log_file(&self) -> PathBuf {
 		self.root.join("tunnel-service.log")
 	}
 
+	/// Path of the log capturing output from spawned servers.
+	pub fn server_log_file(&self) -> PathBuf {
+		self.root.join("server.log")
+	}
+
+	/// Path of the audit log, which records security-relevant events such as
+	/// rejected connections.
+	pub fn audit_log_file(&self) -> PathBuf {
+		self.root.join("audit.log")
+	}
+
+	/// Directory session recordings are kept in, when enabled.
+	pub fn recordings_dir(&self) -> PathBuf {
+		self.root.join("recordings")
+	}
+
 	/// Removes the launcher data directory.
 	pub fn remove(&self) -> Result<(), WrappedError> {
 		remove_dir_all(&self.root).map_err(|e| {
@@ -155,3 +346,164 @@ impl LauncherPaths {
 		})
 	}
 }
+
+/// Checks config.json contents read from `path`, like `check_config`, giving
+/// a message describing the first error.
+pub fn check_config_source(path: &Path, s: &str) -> Result<CliConfig, String> {
+	let fail = |message: String| format!("{}: {}", path.display(), message);
+
+	let value: serde_json::Value = serde_json::from_str(s).map_err(|e| fail(e.to_string()))?;
+	let config: CliConfig = serde_json::from_str(s).map_err(|e| fail(e.to_string()))?;
+
+	// settings serde ignored are missing when the config is written back out
+	let known = serde_json::to_value(&config).unwrap();
+	if let Some((path, key)) = find_unknown_key(&value, &known, "") {
+		return Err(fail(format!(
+			"unknown setting '{}'{}",
+			path,
+			locate(s, &key)
+		)));
+	}
+
+	for w in &config.maintenance_windows {
+		if let Err(e) = w.parse::<MaintenanceWindow>() {
+			return Err(fail(format!(
+				"invalid maintenance window{}: {}",
+				locate(s, w),
+				e
+			)));
+		}
+	}
+	if let Some(i) = &config.server_update_interval {
+		if let Err(e) = i.parse::<DurationArg>() {
+			return Err(fail(format!(
+				"invalid server update interval{}: {}",
+				locate(s, i),
+				e
+			)));
+		}
+	}
+	for a in &config.forward_allow {
+		if let Err(e) = a.parse::<AllowedTarget>() {
+			return Err(fail(format!(
+				"invalid forward target{}: {}",
+				locate(s, a),
+				e
+			)));
+		}
+	}
+	for r in config.allow_ip.iter().chain(config.deny_ip.iter()) {
+		if let Err(e) = r.parse::<Cidr>() {
+			return Err(fail(format!(
+				"invalid address range{}: {}",
+				locate(s, r),
+				e
+			)));
+		}
+	}
+
+	Ok(config)
+}
+
+/// Finds the first key in `source`, looking into nested objects and arrays,
+/// that isn't in `known`, giving its dotted path and the key itself. Keys set
+/// to null are skipped, since settings left unset may not be written out.
+fn find_unknown_key(
+	source: &serde_json::Value,
+	known: &serde_json::Value,
+	path: &str,
+) -> Option<(String, String)> {
+	let join = |key: &str| match path {
+		"" => key.to_string(),
+		p => format!("{}.{}", p, key),
+	};
+
+	match (source, known) {
+		(serde_json::Value::Object(source), serde_json::Value::Object(known)) => {
+			for (key, value) in source {
+				match known.get(key) {
+					Some(k) => {
+						if let Some(found) = find_unknown_key(value, k, &join(key)) {
+							return Some(found);
+						}
+					}
+					None if value.is_null() => {}
+					None => return Some((join(key), key.clone())),
+				}
+			}
+			None
+		}
+		(serde_json::Value::Array(source), serde_json::Value::Array(known)) => source
+			.iter()
+			.zip(known.iter())
+			.enumerate()
+			.find_map(|(i, (s, k))| find_unknown_key(s, k, &join(&i.to_string()))),
+		_ => None,
+	}
+}
+
+/// Describes where a string first appears in the JSON source, for errors
+/// about values that parsed but aren't valid.
+fn locate(source: &str, value: &str) -> String {
+	let needle = serde_json::to_string(value).unwrap();
+	match source.find(&needle) {
+		Some(i) => {
+			let before = &source[..i];
+			let line = before.matches('\n').count() + 1;
+			let column = i - before.rfind('\n').map(|n| n + 1).unwrap_or(0) + 1;
+			format!(" at line {} column {}", line, column)
+		}
+		None => String::new(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn check(s: &str) -> Result<CliConfig, String> {
+		check_config_source(Path::new("config.json"), s)
+	}
+
+	#[test]
+	fn test_check_config_finds_unknown_settings() {
+		assert!(check(r#"{ "requireSigned": true }"#).is_ok());
+		assert!(check(r#"{ "hooks": { "onStart": "echo hi", "onStop": null } }"#).is_ok());
+		assert!(check(r#"{ "experiments": { "anything": true } }"#).is_ok());
+		assert!(
+			check(r#"{ "notifications": [{ "type": "ntfy", "url": "https://ntfy.sh/t" }] }"#)
+				.is_ok()
+		);
+
+		let e = check(r#"{ "requireSigend": true }"#).unwrap_err();
+		assert!(
+			e.contains("unknown setting 'requireSigend' at line 1"),
+			"{}",
+			e
+		);
+
+		let e = check(r#"{ "hooks": { "onStrat": "echo hi" } }"#).unwrap_err();
+		assert!(e.contains("unknown setting 'hooks.onStrat'"), "{}", e);
+
+		let e =
+			check(r#"{ "ports": [{ "port": 80 }, { "port": 81, "lable": "web" }] }"#).unwrap_err();
+		assert!(e.contains("unknown setting 'ports.1.lable'"), "{}", e);
+
+		let e = check(
+			r#"{ "notifications": [{ "type": "ntfy", "url": "https://ntfy.sh/t", "tokn": "x" }] }"#,
+		)
+		.unwrap_err();
+		assert!(
+			e.contains("unknown setting 'notifications.0.tokn'"),
+			"{}",
+			e
+		);
+	}
+
+	#[test]
+	fn test_check_config_rejects_invalid_values() {
+		assert!(check(r#"{ "requireSigned": "yes" }"#).is_err());
+		assert!(check(r#"{ "allowIp": ["10.0.0.0/99"] }"#).is_err());
+		assert!(check("{").is_err());
+	}
+}
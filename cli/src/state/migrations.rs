@@ -0,0 +1,391 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	fs::{copy, create_dir_all, read_dir, remove_file},
+	path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	info, log,
+	util::{
+		errors::{wrap, AnyError, NoStateBackup},
+		io::restrict_to_owner,
+	},
+};
+
+use super::{LauncherPaths, PersistedState};
+
+const STATE_VERSION_FILE: &str = "state-version.json";
+const BACKUPS_DIR: &str = "state-backups";
+const BACKUP_INFO_FILE: &str = "backup.json";
+
+/// A change to the files in the CLI data directory, made once, in order of
+/// version, for data directories written by older CLIs. Migrations may run
+/// again with `state migrate` after a backup is restored, so they must be
+/// safe to repeat.
+pub struct Migration {
+	pub version: u32,
+	pub description: &'static str,
+	/// Files, relative to the data directory, that the migration changes or
+	/// removes. They're backed up before it runs, so it can be undone.
+	pub backup: &'static [&'static str],
+	run: fn(&LauncherPaths) -> Result<(), AnyError>,
+}
+
+/// Migrations of the data directory. New ones are added at the end with the
+/// next version; existing ones must not be changed or removed.
+pub const MIGRATIONS: &[Migration] = &[Migration {
+	version: 1,
+	description: "Restrict the data directory to its owner",
+	backup: &[],
+	run: restrict_data_dir,
+}];
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct StateVersion {
+	version: u32,
+	/// Set when a backup is restored, so that migrations don't run again
+	/// until asked to with `state migrate`.
+	#[serde(default)]
+	restored: bool,
+}
+
+fn state_version(paths: &LauncherPaths) -> PersistedState<StateVersion> {
+	PersistedState::new(paths.root().join(STATE_VERSION_FILE))
+}
+
+/// Gets the version the data directory has been migrated to.
+pub fn current_version(paths: &LauncherPaths) -> u32 {
+	state_version(paths).load().version
+}
+
+/// Gets whether a backup was restored since migrations last ran, in which
+/// case they're only run when asked to.
+pub fn was_restored(paths: &LauncherPaths) -> bool {
+	state_version(paths).load().restored
+}
+
+/// Gets the version migrations bring data directories to.
+pub fn latest_version() -> u32 {
+	MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// Gets the migrations the data directory hasn't had yet.
+pub fn pending(paths: &LauncherPaths) -> Vec<&'static Migration> {
+	pending_in(paths, MIGRATIONS)
+}
+
+fn pending_in(paths: &LauncherPaths, migrations: &'static [Migration]) -> Vec<&'static Migration> {
+	let current = current_version(paths);
+	migrations.iter().filter(|m| m.version > current).collect()
+}
+
+/// Runs pending migrations in order, backing up the files each changes, and
+/// returns the ones that ran. The version is recorded after each, so a
+/// failure leaves later migrations pending. Any restored backup is taken to
+/// be done with.
+pub fn migrate(
+	log: &log::Logger,
+	paths: &LauncherPaths,
+) -> Result<Vec<&'static Migration>, AnyError> {
+	migrate_in(log, paths, MIGRATIONS)
+}
+
+fn migrate_in(
+	log: &log::Logger,
+	paths: &LauncherPaths,
+	migrations: &'static [Migration],
+) -> Result<Vec<&'static Migration>, AnyError> {
+	let state = state_version(paths);
+	let current = state.load();
+	if current.restored {
+		state.save(StateVersion {
+			restored: false,
+			..current
+		})?;
+	}
+
+	let mut ran = vec![];
+	for migration in pending_in(paths, migrations) {
+		if !migration.backup.is_empty() {
+			let backup = back_up(paths, migration)?;
+			info!(
+				log,
+				"Backed up files changed by migration {} to {}",
+				migration.version,
+				backup.display()
+			);
+		}
+
+		info!(
+			log,
+			"Migrating the data directory: {}", migration.description
+		);
+		(migration.run)(paths)?;
+		state.save(StateVersion {
+			version: migration.version,
+			restored: false,
+		})?;
+		ran.push(migration);
+	}
+
+	Ok(ran)
+}
+
+/// Files saved before a migration changed them.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StateBackup {
+	/// Name of the backup's directory.
+	#[serde(skip)]
+	pub name: String,
+	/// Version the data directory was at before the migration.
+	pub version: u32,
+	/// Migration the files were backed up for.
+	pub migration: u32,
+	/// Backed up files that existed, relative to the data directory.
+	pub files: Vec<String>,
+}
+
+fn backups_dir(paths: &LauncherPaths) -> PathBuf {
+	paths.root().join(BACKUPS_DIR)
+}
+
+fn back_up(paths: &LauncherPaths, migration: &Migration) -> Result<PathBuf, AnyError> {
+	let name = format!(
+		"{}-v{}",
+		Utc::now().format("%Y%m%dT%H%M%S%.3f"),
+		migration.version
+	);
+	let dir = backups_dir(paths).join(&name);
+	create_dir_all(&dir).map_err(|e| wrap(e, format!("error creating {}", dir.display())))?;
+
+	let mut files = vec![];
+	for file in migration.backup {
+		let from = paths.root().join(file);
+		if !from.exists() {
+			continue;
+		}
+		copy_creating_dirs(&from, &dir.join(file))
+			.map_err(|e| wrap(e, format!("error backing up {}", from.display())))?;
+		files.push(file.to_string());
+	}
+
+	let backup = StateBackup {
+		name,
+		version: current_version(paths),
+		migration: migration.version,
+		files,
+	};
+	PersistedState::new(dir.join(BACKUP_INFO_FILE)).save(backup)?;
+	Ok(dir)
+}
+
+/// Lists backups made before migrations, oldest first.
+pub fn list_backups(paths: &LauncherPaths) -> Vec<StateBackup> {
+	let mut backups = match read_dir(backups_dir(paths)) {
+		Ok(entries) => entries
+			.flatten()
+			.filter_map(|e| {
+				let info = e.path().join(BACKUP_INFO_FILE);
+				if !info.exists() {
+					return None;
+				}
+				let backup: StateBackup = PersistedState::new(info).load();
+				Some(StateBackup {
+					name: e.file_name().to_string_lossy().to_string(),
+					..backup
+				})
+			})
+			.collect::<Vec<_>>(),
+		Err(_) => vec![],
+	};
+
+	backups.sort_by(|a, b| a.name.cmp(&b.name));
+	backups
+}
+
+/// Undoes the migration the backup was made for, and those after it, by
+/// putting the backed up files back and returning the data directory to the
+/// version it was at. Files a migration created that didn't exist before it
+/// are removed.
+pub fn restore(paths: &LauncherPaths, name: Option<&str>) -> Result<StateBackup, AnyError> {
+	restore_in(paths, name, MIGRATIONS)
+}
+
+fn restore_in(
+	paths: &LauncherPaths,
+	name: Option<&str>,
+	migrations: &[Migration],
+) -> Result<StateBackup, AnyError> {
+	let backups = list_backups(paths);
+	let index = match name {
+		Some(n) => backups.iter().position(|b| b.name == n),
+		None => backups.len().checked_sub(1),
+	}
+	.ok_or_else(|| {
+		NoStateBackup(match name {
+			Some(n) => format!("there's no backup named {}", n),
+			None => "no migrations have been backed up".to_string(),
+		})
+	})?;
+
+	// later migrations are undone first, so each backup is put back over the
+	// files as they were when it was made
+	for backup in backups[index..].iter().rev() {
+		restore_files(paths, backup, migrations)?;
+	}
+
+	let backup = backups.into_iter().nth(index).unwrap();
+	state_version(paths).save(StateVersion {
+		version: backup.version,
+		restored: true,
+	})?;
+	Ok(backup)
+}
+
+fn restore_files(
+	paths: &LauncherPaths,
+	backup: &StateBackup,
+	migrations: &[Migration],
+) -> Result<(), AnyError> {
+	let dir = backups_dir(paths).join(&backup.name);
+	let files = migrations
+		.iter()
+		.find(|m| m.version == backup.migration)
+		.map(|m| m.backup)
+		.unwrap_or_default();
+	for file in files {
+		let to = paths.root().join(file);
+		if backup.files.iter().any(|f| f == file) {
+			copy_creating_dirs(&dir.join(file), &to)
+				.map_err(|e| wrap(e, format!("error restoring {}", to.display())))?;
+		} else {
+			remove_file(&to).ok();
+		}
+	}
+
+	Ok(())
+}
+
+/// Copies the file, creating the directories it's copied into, since files
+/// may be nested in the data directory.
+fn copy_creating_dirs(from: &Path, to: &Path) -> std::io::Result<()> {
+	if let Some(parent) = to.parent() {
+		create_dir_all(parent)?;
+	}
+	copy(from, to).map(|_| ())
+}
+
+fn restrict_data_dir(paths: &LauncherPaths) -> Result<(), AnyError> {
+	restrict_to_owner(paths.root(), 0o700)
+		.map_err(|e| wrap(e, "error restricting the data directory").into())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TEST_FILE: &str = "test-state";
+
+	fn rewrite_test_file(paths: &LauncherPaths) -> Result<(), AnyError> {
+		let file = paths.root().join(TEST_FILE);
+		std::fs::write(&file, "new").map_err(|e| wrap(e, "error writing test file").into())
+	}
+
+	const TEST_MIGRATIONS: &[Migration] = &[
+		Migration {
+			version: 1,
+			description: "Restrict the data directory to its owner",
+			backup: &[],
+			run: restrict_data_dir,
+		},
+		Migration {
+			version: 2,
+			description: "Rewrite the test file",
+			backup: &[TEST_FILE],
+			run: rewrite_test_file,
+		},
+	];
+
+	#[test]
+	fn test_migrate() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let log = log::Logger::test();
+
+		assert_eq!(pending(&paths).len(), MIGRATIONS.len());
+		assert_eq!(migrate(&log, &paths).unwrap().len(), MIGRATIONS.len());
+		assert_eq!(current_version(&paths), latest_version());
+		assert!(pending(&paths).is_empty());
+	}
+
+	#[test]
+	fn test_migrate_and_restore() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let log = log::Logger::test();
+		let file = dir.path().join(TEST_FILE);
+		std::fs::write(&file, "old").unwrap();
+
+		assert_eq!(migrate_in(&log, &paths, TEST_MIGRATIONS).unwrap().len(), 2);
+		assert_eq!(current_version(&paths), 2);
+		assert_eq!(std::fs::read_to_string(&file).unwrap(), "new");
+
+		let backup = restore_in(&paths, None, TEST_MIGRATIONS).unwrap();
+		assert_eq!(backup.version, 1);
+		assert_eq!(current_version(&paths), 1);
+		assert_eq!(std::fs::read_to_string(&file).unwrap(), "old");
+		assert!(was_restored(&paths));
+
+		// migrating again gives the same result
+		assert_eq!(migrate_in(&log, &paths, TEST_MIGRATIONS).unwrap().len(), 1);
+		assert_eq!(std::fs::read_to_string(&file).unwrap(), "new");
+		assert!(!was_restored(&paths));
+	}
+
+	const NESTED_FILE: &str = "nested/test-state";
+
+	fn rewrite_nested_file(paths: &LauncherPaths) -> Result<(), AnyError> {
+		let file = paths.root().join(NESTED_FILE);
+		std::fs::write(&file, "new").map_err(|e| wrap(e, "error writing test file").into())
+	}
+
+	const NESTED_MIGRATIONS: &[Migration] = &[Migration {
+		version: 1,
+		description: "Rewrite the nested test file",
+		backup: &[NESTED_FILE],
+		run: rewrite_nested_file,
+	}];
+
+	#[test]
+	fn test_backs_up_nested_files() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let file = dir.path().join(NESTED_FILE);
+		std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+		std::fs::write(&file, "old").unwrap();
+
+		migrate_in(&log::Logger::test(), &paths, NESTED_MIGRATIONS).unwrap();
+		assert_eq!(std::fs::read_to_string(&file).unwrap(), "new");
+		restore_in(&paths, None, NESTED_MIGRATIONS).unwrap();
+		assert_eq!(std::fs::read_to_string(&file).unwrap(), "old");
+	}
+
+	#[test]
+	fn test_restore_removes_created_files() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let file = dir.path().join(TEST_FILE);
+
+		migrate_in(&log::Logger::test(), &paths, TEST_MIGRATIONS).unwrap();
+		assert!(file.exists());
+		restore_in(&paths, None, TEST_MIGRATIONS).unwrap();
+		assert!(!file.exists());
+	}
+}
@@ -3,18 +3,50 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
+pub mod anonymous;
+pub mod ca_certs;
+pub mod chaos;
 pub mod code_server;
 pub mod dev_tunnels;
+pub mod dotfiles;
+pub mod folder_ports;
+pub mod forward_targets;
+pub mod fs_jail;
+pub mod hooks;
+pub mod install_watcher;
+pub mod ip_filter;
 pub mod legal;
+pub mod local_web;
+pub mod machine_id;
+pub mod maintenance;
+pub mod notifications;
 pub mod paths;
+pub mod relay_breaker;
+pub mod relay_transport;
+pub mod reverse_forward;
+pub mod security_audit;
+pub mod server_routing;
+pub mod server_selection;
+pub mod session_recording;
+pub mod session_share;
+pub mod settings_sync;
+pub mod ssh_bridge;
+pub mod stats_history;
+#[cfg(feature = "test-relay")]
+pub mod test_relay;
+pub mod workspace_trust;
 
+mod connection_quality;
 mod control_server;
+mod host_router;
 mod name_generator;
 mod port_forwarder;
 mod protocol;
 #[cfg_attr(unix, path = "tunnels/server_bridge_unix.rs")]
 #[cfg_attr(windows, path = "tunnels/server_bridge_windows.rs")]
 mod server_bridge;
+mod server_delta;
+mod server_updates;
 mod service;
 #[cfg(target_os = "linux")]
 mod service_linux;
@@ -24,7 +56,9 @@ mod service_macos;
 mod service_windows;
 mod socket_signal;
 
-pub use control_server::serve;
+pub use control_server::{serve, ServeOptions};
 pub use service::{
-	create_service_manager, ServiceContainer, ServiceManager, SERVICE_LOG_FILE_NAME,
+	check_service_executable, create_service_manager, load_service_registration,
+	save_service_registration, ServiceContainer, ServiceExecutable, ServiceManager,
+	ServiceRegistration, SERVICE_LOG_FILE_NAME,
 };
@@ -0,0 +1,308 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	convert::Infallible,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use hyper::{
+	client::conn::Builder, server::conn::Http, service::service_fn, Body, Method, Request,
+	Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	net::TcpStream,
+	time::{sleep_until, Instant},
+};
+
+use crate::{
+	commands::tunnels::console_shutdown_signal,
+	debug, info, log,
+	state::{LauncherPaths, PersistedState},
+	util::{
+		clock::{sleep_until_wall, SystemClock},
+		errors::{wrap, AnyError},
+		machine::process_exists,
+	},
+	warning,
+};
+
+use super::dev_tunnels::{ActiveTunnel, DevTunnels, PersistedTunnel};
+
+/// Lists the temporary tunnels hosted on this machine, so ones left behind
+/// by a process that was killed before deleting its tunnel are deleted when
+/// the next one starts.
+const TEMPORARY_TUNNELS_FILE: &str = "temporary-tunnels.json";
+
+/// Combined rate at which data is sent to all viewers of an anonymous tunnel.
+const MAX_BYTES_PER_SECOND: u64 = 1024 * 1024;
+/// Size of chunks relayed to viewers, which bounds how bursty they can be.
+const RELAY_CHUNK_SIZE: usize = 16 * 1024;
+
+/// A temporary tunnel, and the process hosting it.
+#[derive(Serialize, Deserialize, Clone)]
+struct TemporaryTunnel {
+	tunnel: PersistedTunnel,
+	pid: u32,
+}
+
+fn temporary_tunnels(paths: &LauncherPaths) -> PersistedState<Vec<TemporaryTunnel>> {
+	PersistedState::new(paths.root().join(TEMPORARY_TUNNELS_FILE))
+}
+
+/// Records the tunnel as hosted by this process, until `forget_temporary_tunnel`.
+pub fn record_temporary_tunnel(
+	paths: &LauncherPaths,
+	tunnel: &PersistedTunnel,
+) -> Result<(), AnyError> {
+	let entry = TemporaryTunnel {
+		tunnel: tunnel.clone(),
+		pid: std::process::id(),
+	};
+	temporary_tunnels(paths).update_with(entry, |entry, tunnels| tunnels.push(entry))?;
+	Ok(())
+}
+
+/// Removes the tunnel from the list once it's deleted.
+pub fn forget_temporary_tunnel(paths: &LauncherPaths, tunnel: &PersistedTunnel) {
+	temporary_tunnels(paths)
+		.update_with(tunnel.id.clone(), |id, tunnels| {
+			tunnels.retain(|t| t.tunnel.id != id)
+		})
+		.ok();
+}
+
+/// Deletes temporary tunnels whose process is gone, such as ones that were
+/// killed before they could delete their tunnel. Failures are logged and the
+/// tunnel kept in the list, to be tried again next time.
+pub async fn delete_leftover_tunnels(
+	log: &log::Logger,
+	paths: &LauncherPaths,
+	dt: &mut DevTunnels,
+) {
+	let leftover: Vec<TemporaryTunnel> = temporary_tunnels(paths)
+		.load()
+		.into_iter()
+		.filter(|t| !process_exists(t.pid))
+		.collect();
+
+	for t in leftover {
+		match dt.delete_temporary_tunnel(&t.tunnel).await {
+			Ok(()) => {
+				info!(log, "Deleted leftover temporary tunnel {}", t.tunnel.name);
+				forget_temporary_tunnel(paths, &t.tunnel);
+			}
+			Err(e) => warning!(
+				log,
+				"Error deleting leftover temporary tunnel {}: {}",
+				t.tunnel.name,
+				e
+			),
+		}
+	}
+}
+
+/// Options for `share_anonymous`.
+pub struct AnonymousShareOptions {
+	/// Local port to share.
+	pub port: u16,
+	/// How long to share the port for.
+	pub expires: Duration,
+}
+
+/// Shares a single local port through a tunnel that allows anonymous access,
+/// until the expiry passes or the user presses Ctrl+C. Since viewers don't
+/// sign in, only read-only HTTP requests are relayed, and their combined
+/// bandwidth is capped. The tunnel is closed before returning.
+pub async fn share_anonymous(
+	log: &log::Logger,
+	mut tunnel: ActiveTunnel,
+	options: AnonymousShareOptions,
+) -> Result<(), AnyError> {
+	let mut connections = tunnel.add_port_direct(options.port).await?;
+	let uri = tunnel.get_port_uri(options.port).await?;
+	let expires_at = chrono::Local::now()
+		+ chrono::Duration::from_std(options.expires).unwrap_or_else(|_| chrono::Duration::zero());
+	log.result(format!(
+		"Sharing port {} at {} until {}. Anyone with the link can view it without signing in.",
+		options.port,
+		uri,
+		expires_at.format("%H:%M")
+	));
+
+	let limiter = Arc::new(BandwidthLimiter::new(MAX_BYTES_PER_SECOND));
+	// measured on the wall clock, so time spent suspended counts
+	let expiry = sleep_until_wall(&SystemClock, expires_at.with_timezone(&chrono::Utc));
+	tokio::pin!(expiry);
+
+	loop {
+		tokio::select! {
+			_ = &mut expiry => {
+				info!(log, "Anonymous access has expired");
+				break;
+			}
+			signal = console_shutdown_signal() => {
+				info!(log, "{}, stopping sharing", signal);
+				break;
+			}
+			conn = connections.recv() => match conn {
+				Some(conn) => {
+					let log = log.clone();
+					let limiter = limiter.clone();
+					let port = options.port;
+					tokio::spawn(async move {
+						let (writehalf, readhalf) = conn.into_split();
+						if let Err(e) = serve_viewer(readhalf, writehalf, port, limiter).await {
+							debug!(log, "Viewer connection closed: {}", e);
+						}
+					});
+				}
+				None => {
+					warning!(log, "Tunnel disposed, stopping sharing");
+					break;
+				}
+			}
+		}
+	}
+
+	tunnel.close().await
+}
+
+/// Serves HTTP requests from a viewer, relaying allowed ones to the port.
+async fn serve_viewer(
+	readhalf: impl AsyncRead + Send + Unpin + 'static,
+	writehalf: impl AsyncWrite + Send + Unpin + 'static,
+	port: u16,
+	limiter: Arc<BandwidthLimiter>,
+) -> Result<(), AnyError> {
+	// Hyper needs a single duplex stream, and a pipe through it is also a
+	// convenient place to throttle data sent to the viewer.
+	let (local, remote) = tokio::io::duplex(RELAY_CHUNK_SIZE * 4);
+	let (local_read, local_write) = tokio::io::split(local);
+	tokio::spawn(copy_from_viewer(readhalf, local_write));
+	tokio::spawn(copy_to_viewer(local_read, writehalf, limiter));
+
+	Http::new()
+		.http1_only(true)
+		.serve_connection(
+			remote,
+			service_fn(
+				move |req| async move { Ok::<_, Infallible>(relay_read_only(req, port).await) },
+			),
+		)
+		.await
+		.map_err(|e| wrap(e, "error serving viewer").into())
+}
+
+async fn copy_from_viewer(
+	mut readhalf: impl AsyncRead + Unpin,
+	mut local: impl AsyncWrite + Unpin,
+) {
+	tokio::io::copy(&mut readhalf, &mut local).await.ok();
+	local.shutdown().await.ok();
+}
+
+async fn copy_to_viewer(
+	mut local: impl AsyncRead + Unpin,
+	mut writehalf: impl AsyncWrite + Unpin,
+	limiter: Arc<BandwidthLimiter>,
+) {
+	let mut buf = vec![0; RELAY_CHUNK_SIZE];
+	loop {
+		let n = match local.read(&mut buf).await {
+			Ok(0) | Err(_) => break,
+			Ok(n) => n,
+		};
+
+		limiter.acquire(n).await;
+		if writehalf.write_all(&buf[..n]).await.is_err() {
+			break;
+		}
+	}
+
+	writehalf.shutdown().await.ok();
+}
+
+/// Relays a request to the local port if it can't change anything there,
+/// otherwise responds with an error.
+async fn relay_read_only(req: Request<Body>, port: u16) -> Response<Body> {
+	if !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+		return error_response(
+			StatusCode::METHOD_NOT_ALLOWED,
+			"This link is read-only; only GET, HEAD, and OPTIONS requests are allowed.",
+		);
+	}
+
+	match relay(req, port).await {
+		Ok(res) => res,
+		Err(e) => error_response(
+			StatusCode::BAD_GATEWAY,
+			&format!("Error connecting to the shared port: {}", e),
+		),
+	}
+}
+
+async fn relay(mut req: Request<Body>, port: u16) -> Result<Response<Body>, AnyError> {
+	let stream = TcpStream::connect(("127.0.0.1", port))
+		.await
+		.map_err(|e| wrap(e, "error connecting to port"))?;
+	let (mut request_sender, connection) = Builder::new()
+		.handshake(stream)
+		.await
+		.map_err(|e| wrap(e, "error establishing connection"))?;
+
+	// start the connection processing; it's shut down when the sender is dropped
+	tokio::spawn(connection);
+
+	// upgrades (such as websockets) would allow arbitrary two-way traffic
+	let headers = req.headers_mut();
+	headers.remove(hyper::header::CONNECTION);
+	headers.remove(hyper::header::UPGRADE);
+
+	request_sender
+		.send_request(req)
+		.await
+		.map_err(|e| wrap(e, "error sending request").into())
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+	Response::builder()
+		.status(status)
+		.header(hyper::header::CONTENT_TYPE, "text/plain")
+		.body(Body::from(message.to_string()))
+		.unwrap()
+}
+
+/// Limits the rate at which bytes are sent, shared across connections.
+struct BandwidthLimiter {
+	bytes_per_second: u64,
+	/// Time at which everything sent so far will have "drained" at the
+	/// limited rate.
+	next_free: Mutex<Instant>,
+}
+
+impl BandwidthLimiter {
+	fn new(bytes_per_second: u64) -> Self {
+		BandwidthLimiter {
+			bytes_per_second,
+			next_free: Mutex::new(Instant::now()),
+		}
+	}
+
+	/// Waits until `n` more bytes may be sent.
+	async fn acquire(&self, n: usize) {
+		let start = {
+			let mut next_free = self.next_free.lock().unwrap();
+			let start = (*next_free).max(Instant::now());
+			*next_free = start + Duration::from_secs_f64(n as f64 / self.bytes_per_second as f64);
+			start
+		};
+
+		sleep_until(start).await;
+	}
+}
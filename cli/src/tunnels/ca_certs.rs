@@ -0,0 +1,51 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::path::Path;
+
+use tokio::process::Command;
+
+use crate::{log, util::tls::read_certificates, warning};
+
+/// Variable Node.js reads additional trusted CA certificates from, used by
+/// the server for its own requests like to the extension gallery.
+const NODE_EXTRA_CA_CERTS: &str = "NODE_EXTRA_CA_CERTS";
+
+/// Makes the server trust the CA certificates in the PEM file, in addition
+/// to the built-in ones. Problems with the file are logged, since the server
+/// is still usable without them, but `tunnel doctor` reports them too.
+pub fn apply(log: &log::Logger, cmd: &mut Command, file: &Path) {
+	if let Err(e) = check(file) {
+		warning!(log, "{}. The server may not be able to reach services.", e);
+	}
+
+	cmd.env(NODE_EXTRA_CA_CERTS, file);
+}
+
+/// Checks that the file holds PEM certificates that can be parsed, returning
+/// how many it has.
+pub fn check(file: &Path) -> Result<usize, String> {
+	read_certificates(file).map(|certs| certs.len())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::util::tls::PEM_BEGIN;
+
+	#[test]
+	fn test_check_rejects_files_without_certificates() {
+		let dir = tempfile::tempdir().unwrap();
+		assert!(check(&dir.path().join("missing.pem")).is_err());
+
+		let empty = dir.path().join("empty.pem");
+		std::fs::write(&empty, "not a certificate").unwrap();
+		assert!(check(&empty).unwrap_err().contains("no PEM certificates"));
+
+		let truncated = dir.path().join("truncated.pem");
+		std::fs::write(&truncated, format!("{}\nMIIB", PEM_BEGIN)).unwrap();
+		assert!(check(&truncated).unwrap_err().contains("isn't terminated"));
+	}
+}
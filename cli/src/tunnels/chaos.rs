@@ -0,0 +1,375 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{collections::VecDeque, fmt, str::FromStr, time::Duration};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+	sync::mpsc,
+	time::{sleep_until, Instant},
+};
+
+use super::socket_signal::SocketSignal;
+
+/// Extra delay for a "dropped" message that can't actually be lost. The
+/// tunnel is a reliable stream, so on a real network a lost packet shows up
+/// as a stall while it's retransmitted.
+const MIN_RETRANSMIT_DELAY: Duration = Duration::from_millis(200);
+/// Minimum time a reordered message is held back, so that it's overtaken.
+const MIN_REORDER_HOLD: Duration = Duration::from_millis(50);
+/// Size of chunks read from the client when delaying incoming data.
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Faults to inject into the tunnel data path, for testing how the editor
+/// behaves on bad networks. Parsed from a list like `latency=200ms,drop=1%`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChaosOptions {
+	/// Delay added to data in each direction.
+	pub latency: Duration,
+	/// Up to this much random delay is added on top of the latency.
+	pub jitter: Duration,
+	/// Fraction of messages that are lost. Low-priority messages are dropped
+	/// outright; others are delayed as if they were retransmitted.
+	pub drop: f64,
+	/// Fraction of low-priority messages delivered out of order.
+	pub reorder: f64,
+}
+
+impl ChaosOptions {
+	/// Delays, drops, and reorders signals sent to the client, returning a
+	/// receiver that yields them once they're "delivered".
+	pub fn delay_signals(
+		&self,
+		mut rx: mpsc::Receiver<SocketSignal>,
+	) -> mpsc::Receiver<SocketSignal> {
+		let (tx, delayed_rx) = mpsc::channel(4);
+		let mut scheduler = ChaosScheduler::new(self.clone());
+
+		tokio::spawn(async move {
+			let mut line = DelayLine::default();
+			loop {
+				let next_due = line.next_due();
+				tokio::select! {
+					signal = rx.recv() => match signal {
+						Some(signal) => {
+							let low_priority = matches!(signal, SocketSignal::SendLowPriority(_));
+							if let Some(due) = scheduler.schedule(Instant::now(), low_priority) {
+								line.push(due, signal);
+							}
+						}
+						None => break,
+					},
+					_ = sleep_until(next_due.unwrap_or_else(Instant::now)), if next_due.is_some() => {
+						for signal in line.pop_due(Instant::now()) {
+							if tx.send(signal).await.is_err() {
+								return;
+							}
+						}
+					}
+				}
+			}
+
+			// deliver anything still in flight once the sender is gone
+			while let Some((due, signal)) = line.pop_front() {
+				sleep_until(due).await;
+				if tx.send(signal).await.is_err() {
+					return;
+				}
+			}
+		});
+
+		delayed_rx
+	}
+
+	/// Delays data read from the client, returning a reader that yields it
+	/// once it's "delivered". Incoming data is never dropped or reordered,
+	/// since it's a byte stream.
+	pub fn delay_reader(
+		&self,
+		mut reader: impl AsyncRead + Send + Unpin + 'static,
+	) -> impl AsyncRead + Send + Unpin + 'static {
+		let (mut delayed_writer, delayed_reader) = tokio::io::duplex(READ_CHUNK_SIZE * 4);
+		let mut scheduler = ChaosScheduler::new(ChaosOptions {
+			reorder: 0.0,
+			..self.clone()
+		});
+
+		tokio::spawn(async move {
+			let mut line = DelayLine::default();
+			let mut buf = vec![0; READ_CHUNK_SIZE];
+			let mut reading = true;
+			while reading || !line.is_empty() {
+				let next_due = line.next_due();
+				tokio::select! {
+					n = reader.read(&mut buf), if reading => match n {
+						Ok(0) | Err(_) => reading = false,
+						Ok(n) => {
+							// a chunk is never "low priority", so it's always scheduled
+							if let Some(due) = scheduler.schedule(Instant::now(), false) {
+								line.push(due, buf[..n].to_vec());
+							}
+						}
+					},
+					_ = sleep_until(next_due.unwrap_or_else(Instant::now)), if next_due.is_some() => {
+						for chunk in line.pop_due(Instant::now()) {
+							if delayed_writer.write_all(&chunk).await.is_err() {
+								return;
+							}
+						}
+					}
+				}
+			}
+
+			delayed_writer.shutdown().await.ok();
+		});
+
+		delayed_reader
+	}
+
+	fn retransmit_delay(&self) -> Duration {
+		(self.latency * 2).max(MIN_RETRANSMIT_DELAY)
+	}
+}
+
+impl FromStr for ChaosOptions {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut options = ChaosOptions::default();
+		for part in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+			let (key, value) = part
+				.split_once('=')
+				.ok_or_else(|| format!("expected a fault like 'latency=200ms', got '{}'", part))?;
+
+			match key.trim() {
+				"latency" => options.latency = parse_millis(value)?,
+				"jitter" => options.jitter = parse_millis(value)?,
+				"drop" => options.drop = parse_fraction(value)?,
+				"reorder" => options.reorder = parse_fraction(value)?,
+				k => {
+					return Err(format!(
+						"unknown fault '{}', use latency, jitter, drop, or reorder",
+						k
+					))
+				}
+			}
+		}
+
+		Ok(options)
+	}
+}
+
+impl fmt::Display for ChaosOptions {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"latency={}ms,jitter={}ms,drop={}%,reorder={}%",
+			self.latency.as_millis(),
+			self.jitter.as_millis(),
+			self.drop * 100.0,
+			self.reorder * 100.0
+		)
+	}
+}
+
+fn parse_millis(s: &str) -> Result<Duration, String> {
+	let s = s.trim();
+	let (num, scale) = if let Some(ms) = s.strip_suffix("ms") {
+		(ms, 1)
+	} else if let Some(secs) = s.strip_suffix('s') {
+		(secs, 1000)
+	} else {
+		(s, 1)
+	};
+
+	num.parse::<u64>()
+		.map(|n| Duration::from_millis(n * scale))
+		.map_err(|_| format!("expected a duration like '200ms', got '{}'", s))
+}
+
+fn parse_fraction(s: &str) -> Result<f64, String> {
+	let s = s.trim();
+	let value = match s.strip_suffix('%') {
+		Some(pct) => pct.parse::<f64>().map(|p| p / 100.0),
+		None => s.parse::<f64>(),
+	}
+	.map_err(|_| format!("expected a percentage like '1%', got '{}'", s))?;
+
+	if !(0.0..=1.0).contains(&value) {
+		return Err(format!(
+			"expected a percentage between 0% and 100%, got '{}'",
+			s
+		));
+	}
+
+	Ok(value)
+}
+
+/// Decides when, or whether, each message is delivered.
+struct ChaosScheduler {
+	options: ChaosOptions,
+	rng: StdRng,
+	last_due: Option<Instant>,
+}
+
+impl ChaosScheduler {
+	fn new(options: ChaosOptions) -> Self {
+		Self::with_rng(options, StdRng::from_entropy())
+	}
+
+	fn with_rng(options: ChaosOptions, rng: StdRng) -> Self {
+		ChaosScheduler {
+			options,
+			rng,
+			last_due: None,
+		}
+	}
+
+	/// Gets the time a message sent at `now` should be delivered, or None if
+	/// it should be dropped.
+	fn schedule(&mut self, now: Instant, low_priority: bool) -> Option<Instant> {
+		let mut delay = self.options.latency;
+		if !self.options.jitter.is_zero() {
+			delay += self.options.jitter.mul_f64(self.rng.gen::<f64>());
+		}
+
+		if self.rng.gen_bool(self.options.drop) {
+			if low_priority {
+				return None;
+			}
+			delay += self.options.retransmit_delay();
+		}
+
+		if low_priority && self.rng.gen_bool(self.options.reorder) {
+			// held back without holding up the messages after it
+			return Some(now + delay + self.options.latency.max(MIN_REORDER_HOLD));
+		}
+
+		// jitter alone shouldn't reorder messages, so never deliver earlier
+		// than the message before
+		let due = match self.last_due {
+			Some(last) => (now + delay).max(last),
+			None => now + delay,
+		};
+		self.last_due = Some(due);
+		Some(due)
+	}
+}
+
+/// Messages in flight, ordered by when they're due.
+struct DelayLine<T> {
+	queue: VecDeque<(Instant, T)>,
+}
+
+impl<T> Default for DelayLine<T> {
+	fn default() -> Self {
+		DelayLine {
+			queue: VecDeque::new(),
+		}
+	}
+}
+
+impl<T> DelayLine<T> {
+	fn push(&mut self, due: Instant, item: T) {
+		let index = self.queue.partition_point(|(d, _)| *d <= due);
+		self.queue.insert(index, (due, item));
+	}
+
+	fn next_due(&self) -> Option<Instant> {
+		self.queue.front().map(|(d, _)| *d)
+	}
+
+	fn is_empty(&self) -> bool {
+		self.queue.is_empty()
+	}
+
+	fn pop_front(&mut self) -> Option<(Instant, T)> {
+		self.queue.pop_front()
+	}
+
+	fn pop_due(&mut self, now: Instant) -> Vec<T> {
+		let count = self.queue.partition_point(|(d, _)| *d <= now);
+		self.queue.drain(..count).map(|(_, item)| item).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parses_options() {
+		let options: ChaosOptions = "latency=200ms, jitter=1s,drop=1%,reorder=0.5"
+			.parse()
+			.unwrap();
+		assert_eq!(
+			options,
+			ChaosOptions {
+				latency: Duration::from_millis(200),
+				jitter: Duration::from_secs(1),
+				drop: 0.01,
+				reorder: 0.5,
+			}
+		);
+
+		assert!("latency".parse::<ChaosOptions>().is_err());
+		assert!("drop=200%".parse::<ChaosOptions>().is_err());
+		assert!("bandwidth=1mb".parse::<ChaosOptions>().is_err());
+	}
+
+	#[test]
+	fn test_jitter_keeps_order() {
+		let options = ChaosOptions {
+			latency: Duration::from_millis(100),
+			jitter: Duration::from_millis(500),
+			..Default::default()
+		};
+		let mut scheduler = ChaosScheduler::with_rng(options, StdRng::seed_from_u64(0));
+
+		let now = Instant::now();
+		let mut last = now;
+		for i in 0..100 {
+			let due = scheduler
+				.schedule(now + Duration::from_millis(i), false)
+				.unwrap();
+			assert!(due >= last);
+			assert!(due >= now + Duration::from_millis(100));
+			last = due;
+		}
+	}
+
+	#[test]
+	fn test_drops_only_low_priority() {
+		let options = ChaosOptions {
+			latency: Duration::from_millis(10),
+			drop: 1.0,
+			..Default::default()
+		};
+		let mut scheduler = ChaosScheduler::with_rng(options, StdRng::seed_from_u64(0));
+
+		let now = Instant::now();
+		assert_eq!(scheduler.schedule(now, true), None);
+		assert_eq!(
+			scheduler.schedule(now, false),
+			Some(now + Duration::from_millis(10) + MIN_RETRANSMIT_DELAY)
+		);
+	}
+
+	#[test]
+	fn test_delay_line_orders_by_due() {
+		let now = Instant::now();
+		let mut line = DelayLine::default();
+		line.push(now + Duration::from_millis(20), "b");
+		line.push(now + Duration::from_millis(30), "c");
+		line.push(now + Duration::from_millis(10), "a");
+
+		assert_eq!(
+			line.pop_due(now + Duration::from_millis(20)),
+			vec!["a", "b"]
+		);
+		assert_eq!(line.next_due(), Some(now + Duration::from_millis(30)));
+	}
+}
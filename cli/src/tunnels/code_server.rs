@@ -2,20 +2,35 @@
  *  Copyright (c) Microsoft Corporation. All rights reserved.
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
-use super::paths::{InstalledServer, LastUsedServers, ServerPaths};
-use crate::constants::{APPLICATION_NAME, QUALITYLESS_PRODUCT_NAME, QUALITYLESS_SERVER_NAME};
-use crate::options::{Quality, TelemetryLevel};
+use super::ca_certs;
+use super::fs_jail::{FsJail, JailPaths};
+use super::install_watcher::watch_install;
+use super::paths::{
+	get_all_servers, get_session_env, InstalledServer, LastUsedServers, RetentionPolicy,
+	ServerHistory, ServerPaths,
+};
+use super::server_delta::apply_delta;
+use crate::constants::{
+	APPLICATION_NAME, QUALITYLESS_PRODUCT_NAME, QUALITYLESS_SERVER_NAME, SERVER_DATA_FOLDER_NAME,
+};
+use crate::desktop::RequestedVersion;
+use crate::experiments::{Experiments, DELTA_UPDATES};
+use crate::log::RotatingFileLogSink;
+use crate::options::{ConnectionTokenMode, Quality, TelemetryLevel};
 use crate::state::LauncherPaths;
 use crate::update_service::{
-	unzip_downloaded_release, Platform, Release, TargetKind, UpdateService,
+	unzip_downloaded_release, Platform, Release, TargetKind, UpdateService, UpdateServiceCache,
 };
 use crate::util::command::{capture_command, kill_tree};
 use crate::util::errors::{
-	wrap, AnyError, ExtensionInstallFailed, MissingEntrypointError, WrappedError,
+	wrap, AnyError, ExtensionInstallFailed, InvalidServerArchive, MismatchConnectionToken,
+	MismatchedJail, MissingEntrypointError, WrappedError,
 };
-use crate::util::http::{self, SimpleHttp};
-use crate::util::io::SilentCopyProgress;
+use crate::util::http::SimpleHttp;
 use crate::util::machine::process_exists;
+use crate::util::patchelf::fixup_server_binaries;
+use crate::util::progress::{ProgressStage, ReportProgress, SilentProgress, TeeProgress};
+use crate::util::tempfile::{new_temp_dir, new_temp_dir_in, temp_root};
 use crate::{debug, info, log, span, spanf, trace, warning};
 use lazy_static::lazy_static;
 use opentelemetry::KeyValue;
@@ -23,7 +38,7 @@ use regex::Regex;
 use serde::Deserialize;
 use std::fs;
 use std::fs::File;
-use std::io::{ErrorKind, Write};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
@@ -40,7 +55,10 @@ lazy_static! {
 	static ref WEB_UI_RE: Regex = Regex::new(r"Web UI available at (.+)").unwrap();
 }
 
-const MAX_RETAINED_SERVERS: usize = 5;
+/// Size at which the server log is rotated.
+const SERVER_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// Number of rotated server logs kept.
+const SERVER_LOG_KEEP: usize = 3;
 
 #[derive(Clone, Debug, Default)]
 pub struct CodeServerArgs {
@@ -63,9 +81,11 @@ pub struct CodeServerArgs {
 	pub force: bool,
 	pub start_server: bool,
 	// connection tokens
+	pub connection_token_mode: Option<ConnectionTokenMode>,
 	pub connection_token: Option<String>,
 	pub connection_token_file: Option<String>,
-	pub without_connection_token: bool,
+	// confines the server to a directory
+	pub jail: Option<FsJail>,
 }
 
 impl CodeServerArgs {
@@ -77,6 +97,13 @@ impl CodeServerArgs {
 		}
 	}
 
+	/// Gets the connection token mode a server listening on a port is started
+	/// with. Servers listening on sockets never require a token.
+	pub fn port_token_mode(&self) -> ConnectionTokenMode {
+		self.connection_token_mode
+			.unwrap_or(ConnectionTokenMode::None)
+	}
+
 	pub fn telemetry_disabled(&self) -> bool {
 		self.telemetry_level == Some(TelemetryLevel::Off)
 	}
@@ -94,14 +121,21 @@ impl CodeServerArgs {
 			}
 		}
 
-		if let Some(i) = &self.connection_token {
-			args.push(format!("--connection-token={}", i));
-		}
-		if let Some(i) = &self.connection_token_file {
-			args.push(format!("--connection-token-file={}", i));
-		}
-		if self.without_connection_token {
-			args.push(String::from("--without-connection-token"));
+		match self.connection_token_mode {
+			Some(ConnectionTokenMode::None) => {
+				args.push(String::from("--without-connection-token"));
+			}
+			Some(ConnectionTokenMode::RotatePerStart) => {
+				if let Some(i) = &self.connection_token {
+					args.push(format!("--connection-token={}", i));
+				}
+			}
+			Some(ConnectionTokenMode::FixedFromFile) => {
+				if let Some(i) = &self.connection_token_file {
+					args.push(format!("--connection-token-file={}", i));
+				}
+			}
+			None => {}
 		}
 		if self.accept_server_license_terms {
 			args.push(String::from("--accept-server-license-terms"));
@@ -152,6 +186,8 @@ pub struct ServerParamsRaw {
 	pub code_server_args: CodeServerArgs,
 	pub headless: bool,
 	pub platform: Platform,
+	/// Version used instead of the latest when no commit is requested.
+	pub pinned_version: Option<RequestedVersion>,
 }
 
 /// Server params that can be used to start a VS Code server.
@@ -161,7 +197,7 @@ pub struct ResolvedServerParams {
 }
 
 impl ResolvedServerParams {
-	fn as_installed_server(&self) -> InstalledServer {
+	pub fn as_installed_server(&self) -> InstalledServer {
 		InstalledServer {
 			commit: self.release.commit.clone(),
 			quality: self.release.quality,
@@ -175,9 +211,10 @@ impl ServerParamsRaw {
 		self,
 		log: &log::Logger,
 		http: impl SimpleHttp + Send + Sync + 'static,
+		cache: Option<UpdateServiceCache>,
 	) -> Result<ResolvedServerParams, AnyError> {
 		Ok(ResolvedServerParams {
-			release: self.get_or_fetch_commit_id(log, http).await?,
+			release: self.get_or_fetch_commit_id(log, http, cache).await?,
 			code_server_args: self.code_server_args,
 		})
 	}
@@ -186,6 +223,7 @@ impl ServerParamsRaw {
 		&self,
 		log: &log::Logger,
 		http: impl SimpleHttp + Send + Sync + 'static,
+		cache: Option<UpdateServiceCache>,
 	) -> Result<Release, AnyError> {
 		let target = match self.headless {
 			true => TargetKind::Server,
@@ -199,12 +237,35 @@ impl ServerParamsRaw {
 				target,
 				name: String::new(),
 				platform: self.platform,
+				sha256: None,
 			});
 		}
 
-		UpdateService::new(log.clone(), http)
-			.get_latest_commit(self.platform, target, self.quality)
-			.await
+		let update_service = UpdateService::new(log.clone(), http).with_cache(cache);
+		match &self.pinned_version {
+			Some(RequestedVersion::Commit { commit, quality }) => {
+				info!(log, "Using pinned server commit {}", commit);
+				Ok(Release {
+					commit: commit.clone(),
+					quality: *quality,
+					target,
+					name: String::new(),
+					platform: self.platform,
+					sha256: None,
+				})
+			}
+			Some(RequestedVersion::Version { version, quality }) => {
+				info!(log, "Using pinned server version {}", version);
+				update_service
+					.get_release_by_semver_version(self.platform, target, *quality, version)
+					.await
+			}
+			_ => {
+				update_service
+					.get_latest_commit(self.platform, target, self.quality)
+					.await
+			}
+		}
 	}
 }
 
@@ -271,6 +332,22 @@ impl CodeServerOrigin {
 		}
 	}
 
+	/// Gets whether the server process is still running.
+	pub fn is_running(&self) -> bool {
+		match self {
+			CodeServerOrigin::New(child) => child.id().map(process_exists).unwrap_or(false),
+			CodeServerOrigin::Existing(pid) => process_exists(*pid),
+		}
+	}
+
+	/// Gets the server's process ID, if it's still known.
+	pub fn pid(&self) -> Option<u32> {
+		match self {
+			CodeServerOrigin::New(child) => child.id(),
+			CodeServerOrigin::Existing(pid) => Some(*pid),
+		}
+	}
+
 	pub async fn kill(&mut self) {
 		match self {
 			CodeServerOrigin::New(child) => {
@@ -290,12 +367,44 @@ async fn check_and_create_dir(path: &Path) -> Result<(), WrappedError> {
 	Ok(())
 }
 
+/// Writes the install manifest, hashing the server's files on a blocking
+/// thread since there are thousands of them.
+async fn write_manifest(paths: &ServerPaths, commit: &str) -> Result<(), AnyError> {
+	let (paths, commit) = (paths.clone(), commit.to_owned());
+	tokio::task::spawn_blocking(move || paths.write_manifest(&commit))
+		.await
+		.map_err(|e| wrap(e, "error writing server manifest"))??;
+	Ok(())
+}
+
 async fn install_server_if_needed(
 	log: &log::Logger,
 	paths: &ServerPaths,
 	release: &Release,
-	http: impl SimpleHttp + Send + Sync + 'static,
+	base: Option<(String, ServerPaths)>,
+	http: impl SimpleHttp + Send + Sync + Clone + 'static,
+	progress: &mut (impl ReportProgress + Send),
 ) -> Result<(), AnyError> {
+	if let Some(changed) = paths.needs_verification() {
+		match paths.verify() {
+			Ok(()) => info!(
+				log,
+				"Verified installation at {}, after {} was changed",
+				paths.server_dir.display(),
+				changed.display()
+			),
+			Err(e) => {
+				warning!(
+					log,
+					"Reinstalling server, since {} after {} was changed",
+					e,
+					changed.display()
+				);
+				paths.delete()?;
+			}
+		}
+	}
+
 	if paths.executable.exists() {
 		info!(
 			log,
@@ -305,34 +414,162 @@ async fn install_server_if_needed(
 		return Ok(());
 	}
 
-	let tar_file_path = spanf!(
-		log,
-		log.span("server.download"),
-		download_server(&paths.server_dir, release, log, http)
-	)?;
+	check_and_create_dir(&paths.server_dir).await?;
+
+	// an earlier install is updated with a delta where one's published, since
+	// it's much smaller than the whole server
+	let from_delta = match base {
+		Some((from, base_paths)) if !paths.archive.exists() => {
+			progress.begin_stage(ProgressStage::Download);
+			let installed = spanf!(
+				log,
+				log.span("server.delta"),
+				install_from_delta(
+					log,
+					paths,
+					&base_paths,
+					&from,
+					release,
+					http.clone(),
+					&mut *progress
+				)
+			);
+			progress.end_stage();
+
+			match installed {
+				Ok(installed) => installed,
+				Err(e) => {
+					warning!(
+						log,
+						"Could not update server from {}, downloading it in full instead: {}",
+						from,
+						e
+					);
+					paths.delete().ok();
+					check_and_create_dir(&paths.server_dir).await?;
+					false
+				}
+			}
+		}
+		_ => false,
+	};
+
+	// the server is extracted as it's downloaded where possible, unless
+	// there's a partial download from an earlier attempt to resume
+	let streamed = if from_delta {
+		true
+	} else if paths.archive.exists() {
+		false
+	} else {
+		progress.begin_stage(ProgressStage::Download);
+		let streamed = spanf!(
+			log,
+			log.span("server.download"),
+			UpdateService::new(log.clone(), http.clone()).download_and_extract_release(
+				release,
+				&paths.server_dir,
+				TeeProgress(
+					log.get_download_logger("server download progress:"),
+					&mut *progress,
+				),
+			)
+		)?;
+		progress.end_stage();
+		streamed
+	};
+
+	if !streamed {
+		// kept if the download fails, so a later attempt can resume it, and
+		// removed once it's been extracted
+		progress.begin_stage(ProgressStage::Download);
+		spanf!(
+			log,
+			log.span("server.download"),
+			download_server(&paths.archive, release, log, http, &mut *progress)
+		)?;
+		progress.end_stage();
+	}
 
-	span!(
-		log,
-		log.span("server.extract"),
-		install_server(&tar_file_path, paths, log)
-	)?;
+	progress.begin_stage(ProgressStage::Extract);
+	if !streamed {
+		let installed = span!(
+			log,
+			log.span("server.extract"),
+			install_server(&paths.archive, paths, log, &mut *progress)
+		);
+		std::fs::remove_file(&paths.archive).ok();
+		installed?;
+	} else if !paths.executable.exists() {
+		paths.delete().ok();
+		return Err(MissingEntrypointError().into());
+	}
+	progress.end_stage();
+
+	progress.begin_stage(ProgressStage::Install);
+	progress.report_indeterminate();
+	// the install isn't complete without its manifest, so remove it if the
+	// server can't be made to run
+	if let Err(e) = fixup_server_binaries(log, &paths.server_dir).await {
+		paths.delete().ok();
+		return Err(e);
+	}
+	write_manifest(paths, &release.commit).await?;
+	progress.end_stage();
 
 	Ok(())
 }
 
-async fn download_server(
-	path: &Path,
-	release: &Release,
+/// Installs the release by applying a delta from the install of an earlier
+/// commit in `base`. Returns false, leaving the install empty, if no delta is
+/// published between the two commits.
+async fn install_from_delta(
 	log: &log::Logger,
+	paths: &ServerPaths,
+	base: &ServerPaths,
+	from: &str,
+	release: &Release,
 	http: impl SimpleHttp + Send + Sync + 'static,
-) -> Result<PathBuf, AnyError> {
-	let response = UpdateService::new(log.clone(), http)
-		.get_download_stream(release)
-		.await?;
+	progress: impl ReportProgress,
+) -> Result<bool, AnyError> {
+	let delta = paths.server_dir.join(format!("{}.delta", release.commit));
+	let found = UpdateService::new(log.clone(), http)
+		.download_delta(
+			release,
+			from,
+			&delta,
+			TeeProgress(log.get_download_logger("server delta progress:"), progress),
+		)
+		.await;
+	if !matches!(found, Ok(true)) {
+		tokio::fs::remove_file(&delta).await.ok();
+		return found;
+	}
 
-	let mut save_path = path.to_owned();
-	save_path.push("archive");
+	info!(log, "Updating server from {}...", from);
+	let (base, target, from, to) = (
+		base.clone(),
+		paths.clone(),
+		from.to_string(),
+		release.commit.clone(),
+	);
+	let delta_path = delta.clone();
+	let applied =
+		tokio::task::spawn_blocking(move || apply_delta(&delta_path, &base, &target, &from, &to))
+			.await
+			.map_err(|e| wrap(e, "error applying delta"));
+	tokio::fs::remove_file(&delta).await.ok();
+	applied??;
+
+	Ok(true)
+}
 
+async fn download_server(
+	save_path: &Path,
+	release: &Release,
+	log: &log::Logger,
+	http: impl SimpleHttp + Send + Sync + 'static,
+	progress: impl ReportProgress,
+) -> Result<(), AnyError> {
 	info!(
 		log,
 		"Downloading {} server -> {}",
@@ -340,39 +577,239 @@ async fn download_server(
 		save_path.display()
 	);
 
-	http::download_into_file(
-		&save_path,
-		log.get_download_logger("server download progress:"),
-		response,
-	)
-	.await?;
+	UpdateService::new(log.clone(), http)
+		.download_release(
+			release,
+			save_path,
+			TeeProgress(
+				log.get_download_logger("server download progress:"),
+				progress,
+			),
+		)
+		.await?;
 
-	Ok(save_path)
+	Ok(())
 }
 
 fn install_server(
 	compressed_file: &Path,
 	paths: &ServerPaths,
 	log: &log::Logger,
+	progress: impl ReportProgress + Send,
 ) -> Result<(), AnyError> {
 	info!(log, "Setting up server...");
 
-	unzip_downloaded_release(compressed_file, &paths.server_dir, SilentCopyProgress())?;
+	unzip_downloaded_release(compressed_file, &paths.server_dir, progress)?;
 
-	match fs::remove_file(compressed_file) {
-		Ok(()) => {}
-		Err(e) => {
-			if e.kind() != ErrorKind::NotFound {
-				return Err(AnyError::from(wrap(e, "error removing downloaded file")));
+	if !paths.executable.exists() {
+		return Err(AnyError::from(MissingEntrypointError()));
+	}
+
+	Ok(())
+}
+
+/// Gets the most recently installed complete server of the same quality and
+/// kind as `server`, which it can be installed from with a delta.
+fn find_delta_base(
+	launcher_paths: &LauncherPaths,
+	server: &InstalledServer,
+) -> Option<(String, ServerPaths)> {
+	get_all_servers(launcher_paths)
+		.into_iter()
+		.filter(|s| {
+			s.quality == server.quality
+				&& s.headless == server.headless
+				&& s.commit != server.commit
+		})
+		.filter_map(|s| {
+			let paths = s.server_paths(launcher_paths);
+			if paths.needs_verification().is_some() {
+				return None;
 			}
+			let manifest = paths.read_manifest()?;
+			Some((manifest.installed_at, s.commit, paths))
+		})
+		.max_by_key(|(installed_at, _, _)| *installed_at)
+		.map(|(_, commit, paths)| (commit, paths))
+}
+
+/// Metadata embedded in server archives, from their `product.json`.
+#[derive(Deserialize)]
+struct ArchiveProduct {
+	commit: Option<String>,
+	quality: Option<Quality>,
+}
+
+/// Installs a server from an archive on disk, without contacting the update
+/// service, for machines without internet access. The commit and quality are
+/// read from the archive, which must be built for the given platform.
+pub async fn install_server_from_archive(
+	log: &log::Logger,
+	launcher_paths: &LauncherPaths,
+	archive: &Path,
+	platform: Platform,
+) -> Result<InstalledServer, AnyError> {
+	if !archive.is_file() {
+		return Err(InvalidServerArchive(format!("{} is not a file", archive.display())).into());
+	}
+
+	// staged in the data directory, so it can be moved into place once the
+	// commit and quality are known
+	let staging = new_temp_dir_in(launcher_paths.root())?;
+	info!(log, "Extracting {}...", archive.display());
+	unzip_downloaded_release(archive, staging.path(), SilentProgress())?;
+
+	let product: ArchiveProduct = fs::read_to_string(staging.path().join("product.json"))
+		.ok()
+		.and_then(|s| serde_json::from_str(&s).ok())
+		.ok_or_else(|| InvalidServerArchive("it has no valid product.json".to_string()))?;
+	let (commit, quality) = match (product.commit, product.quality) {
+		(Some(c), Some(q)) => (c, q),
+		_ => {
+			return Err(InvalidServerArchive(
+				"its product.json doesn't give a commit and quality".to_string(),
+			)
+			.into())
+		}
+	};
+
+	let expected_arch = platform_arch(platform);
+	let node = staging
+		.path()
+		.join(if cfg!(windows) { "node.exe" } else { "node" });
+	match binary_arch(&node) {
+		Some(arch) if arch == expected_arch => {}
+		Some(arch) => {
+			return Err(InvalidServerArchive(format!(
+				"it's built for {}, but this machine needs {}",
+				arch, expected_arch
+			))
+			.into())
+		}
+		None => {
+			return Err(InvalidServerArchive(format!(
+				"it doesn't contain a server for {}",
+				expected_arch
+			))
+			.into())
 		}
 	}
 
-	if !paths.executable.exists() {
-		return Err(AnyError::from(MissingEntrypointError()));
+	let server = InstalledServer {
+		commit,
+		quality,
+		headless: true,
+	};
+	let paths = server.server_paths(launcher_paths);
+	if !staging
+		.path()
+		.join("bin")
+		.join(quality.server_entrypoint())
+		.exists()
+	{
+		return Err(MissingEntrypointError().into());
 	}
 
-	Ok(())
+	if paths.read_manifest().is_some() && paths.executable.exists() {
+		info!(
+			log,
+			"Server {} is already installed at {}",
+			server.commit,
+			paths.server_dir.display()
+		);
+		return Ok(server);
+	}
+
+	// replace any incomplete install of the same commit
+	if paths.server_dir.exists() {
+		paths.delete()?;
+	}
+	if let Some(parent) = paths.server_dir.parent() {
+		check_and_create_dir(parent).await?;
+	}
+	// the staging directory is left empty once moved, so dropping it is a no-op
+	fs::rename(staging.path(), &paths.server_dir).map_err(|e| {
+		wrap(
+			e,
+			format!("error moving server into {}", paths.server_dir.display()),
+		)
+	})?;
+
+	if let Err(e) = fixup_server_binaries(log, &paths.server_dir).await {
+		paths.delete().ok();
+		return Err(e);
+	}
+	write_manifest(&paths, &server.commit).await?;
+
+	info!(
+		log,
+		"Installed {} server {} from {}",
+		quality.get_capitalized_name(),
+		server.commit,
+		archive.display()
+	);
+	Ok(server)
+}
+
+/// Gets the architecture name binaries for the platform are built for.
+fn platform_arch(platform: Platform) -> &'static str {
+	match platform {
+		Platform::LinuxX64
+		| Platform::LinuxAlpineX64
+		| Platform::DarwinX64
+		| Platform::WindowsX64
+		| Platform::FreeBsdX64 => "x64",
+		Platform::LinuxARM64
+		| Platform::LinuxAlpineARM64
+		| Platform::DarwinARM64
+		| Platform::WindowsARM64
+		| Platform::FreeBsdARM64 => "arm64",
+		Platform::LinuxARM32 => "armhf",
+		Platform::LinuxRiscv64 => "riscv64",
+		Platform::WindowsX86 => "x86",
+	}
+}
+
+/// Reads the architecture of an ELF, Mach-O, or PE executable from its header.
+fn binary_arch(path: &Path) -> Option<&'static str> {
+	let mut header = Vec::with_capacity(4096);
+	File::open(path)
+		.ok()?
+		.take(4096)
+		.read_to_end(&mut header)
+		.ok()?;
+	let u16_at = |i: usize| Some(u16::from_le_bytes(header.get(i..i + 2)?.try_into().ok()?));
+	let u32_at = |i: usize| Some(u32::from_le_bytes(header.get(i..i + 4)?.try_into().ok()?));
+
+	if header.starts_with(b"\x7fELF") {
+		return match u16_at(18)? {
+			62 => Some("x64"),
+			183 => Some("arm64"),
+			40 => Some("armhf"),
+			243 => Some("riscv64"),
+			_ => None,
+		};
+	}
+
+	if u32_at(0)? == 0xfeedfacf {
+		return match u32_at(4)? {
+			0x0100_0007 => Some("x64"),
+			0x0100_000c => Some("arm64"),
+			_ => None,
+		};
+	}
+
+	if header.starts_with(b"MZ") {
+		let pe = u32_at(0x3c)? as usize;
+		return match u16_at(pe + 4)? {
+			0x8664 => Some("x64"),
+			0xaa64 => Some("arm64"),
+			0x014c => Some("x86"),
+			_ => None,
+		};
+	}
+
+	None
 }
 
 /// Ensures the given list of extensions are installed on the running server.
@@ -410,7 +847,9 @@ pub struct ServerBuilder<'a, Http: SimpleHttp + Send + Sync + Clone> {
 	logger: &'a log::Logger,
 	server_params: &'a ResolvedServerParams,
 	last_used: LastUsedServers<'a>,
+	launcher_paths: &'a LauncherPaths,
 	server_paths: ServerPaths,
+	server_log_file: PathBuf,
 	http: Http,
 }
 
@@ -425,9 +864,11 @@ impl<'a, Http: SimpleHttp + Send + Sync + Clone + 'static> ServerBuilder<'a, Htt
 			logger,
 			server_params,
 			last_used: LastUsedServers::new(launcher_paths),
+			launcher_paths,
 			server_paths: server_params
 				.as_installed_server()
 				.server_paths(launcher_paths),
+			server_log_file: launcher_paths.server_log_file(),
 			http,
 		}
 	}
@@ -451,6 +892,35 @@ impl<'a, Http: SimpleHttp + Send + Sync + Clone + 'static> ServerBuilder<'a, Htt
 			return Ok(None);
 		}
 
+		// A server's token can't be changed once it's started, and a rotated
+		// token isn't known to anyone but the process that started it.
+		let requested_mode = self.server_params.code_server_args.port_token_mode();
+		let running_mode = self.server_paths.read_token_mode();
+		if running_mode != requested_mode || requested_mode == ConnectionTokenMode::RotatePerStart {
+			return Err(MismatchConnectionToken(format!(
+				"A server is already running with connection token mode '{}', but '{}' was requested. Stop the running server to change its mode.",
+				running_mode, requested_mode
+			))
+			.into());
+		}
+
+		// the jail is set up when the server starts, so it can't be changed after
+		let requested_jail = self
+			.server_params
+			.code_server_args
+			.jail
+			.as_ref()
+			.map(|j| j.root.clone());
+		let running_jail = self.server_paths.read_jail();
+		if running_jail != requested_jail {
+			return Err(MismatchedJail(format!(
+				"A server is already running {}, but {} was requested. Stop the running server to change its jail.",
+				running_jail.map_or("without a jail".to_string(), |j| format!("jailed to {}", j.display())),
+				requested_jail.map_or("no jail".to_string(), |j| format!("a jail in {}", j.display())),
+			))
+			.into());
+		}
+
 		do_extension_install_on_running_server(
 			&self.server_paths.executable,
 			&self.server_params.code_server_args.install_extensions,
@@ -481,38 +951,82 @@ impl<'a, Http: SimpleHttp + Send + Sync + Clone + 'static> ServerBuilder<'a, Htt
 
 	/// Ensures the server is set up in the configured directory.
 	pub async fn setup(&self) -> Result<(), AnyError> {
+		self.setup_with_progress(&mut SilentProgress()).await
+	}
+
+	/// Like `setup`, reporting the download and extraction stages.
+	pub async fn setup_with_progress(
+		&self,
+		progress: &mut (impl ReportProgress + Send),
+	) -> Result<(), AnyError> {
+		self.install(progress).await?;
+
+		let server = self.server_params.as_installed_server();
+		let policy = RetentionPolicy::configured(self.launcher_paths);
+		match self.last_used.add(server) {
+			Err(e) => warning!(self.logger, "Error adding server to last used: {}", e),
+			Ok(count) if count > policy.keep => {
+				if let Err(e) = self.last_used.prune(self.logger, policy) {
+					warning!(self.logger, "Error removing old servers: {}", e);
+				}
+			}
+			Ok(_) => {}
+		}
+
+		Ok(())
+	}
+
+	/// Installs the server ahead of clients asking for it. Unlike `setup`, it
+	/// isn't counted as used, so it doesn't push servers clients still use
+	/// out of the last used servers kept.
+	pub async fn install_ahead(&self) -> Result<(), AnyError> {
+		self.install(&mut SilentProgress()).await
+	}
+
+	async fn install(&self, progress: &mut (impl ReportProgress + Send)) -> Result<(), AnyError> {
 		debug!(
 			self.logger,
 			"Installing and setting up {}...", QUALITYLESS_SERVER_NAME
 		);
 		check_and_create_dir(&self.server_paths.server_dir).await?;
+		let base = if Experiments::load(self.launcher_paths).is_enabled(DELTA_UPDATES) {
+			find_delta_base(
+				self.launcher_paths,
+				&self.server_params.as_installed_server(),
+			)
+		} else {
+			None
+		};
+		// held until the install is done, so connections racing to install the
+		// same server don't download it over each other
+		let _lock = self
+			.server_paths
+			.lock_install(self.logger, progress)
+			.await?;
 		install_server_if_needed(
 			self.logger,
 			&self.server_paths,
 			&self.server_params.release,
+			base,
 			self.http.clone(),
+			progress,
 		)
 		.await?;
 		debug!(self.logger, "Server setup complete");
-
-		match self.last_used.add(self.server_params.as_installed_server()) {
-			Err(e) => warning!(self.logger, "Error adding server to last used: {}", e),
-			Ok(count) if count > MAX_RETAINED_SERVERS => {
-				if let Err(e) = self.last_used.trim(self.logger, MAX_RETAINED_SERVERS) {
-					warning!(self.logger, "Error trimming old servers: {}", e);
-				}
-			}
-			Ok(_) => {}
-		}
-
 		Ok(())
 	}
 
 	pub async fn listen_on_default_socket(&self) -> Result<SocketCodeServer, AnyError> {
 		let requested_file = if cfg!(target_os = "windows") {
 			PathBuf::from(format!(r"\\.\pipe\vscode-server-{}", Uuid::new_v4()))
+		} else if self.server_params.code_server_args.jail.is_some() {
+			// jailed servers can write where their socket is, so it's put in
+			// a directory of its own rather than the shared temp root
+			new_temp_dir()?
+				.into_path()
+				.join(format!("vscode-server-{}", Uuid::new_v4()))
 		} else {
-			std::env::temp_dir().join(format!("vscode-server-{}", Uuid::new_v4()))
+			temp_root().join(format!("vscode-server-{}", Uuid::new_v4()))
 		};
 
 		self.listen_on_socket(&requested_file).await
@@ -532,15 +1046,15 @@ impl<'a, Http: SimpleHttp + Send + Sync + Clone + 'static> ServerBuilder<'a, Htt
 	async fn _listen_on_socket(&self, socket: &Path) -> Result<SocketCodeServer, AnyError> {
 		remove_file(&socket).await.ok(); // ignore any error if it doesn't exist
 
-		let mut cmd = self.get_base_command();
+		let mut cmd = self.get_base_command(socket.parent())?;
 		cmd.arg("--start-server")
 			.arg("--without-connection-token")
 			.arg("--enable-remote-auto-shutdown")
 			.arg(format!("--socket-path={}", socket.display()));
 
-		let child = self.spawn_server_process(cmd)?;
+		let child = self.spawn_server_process(cmd, Some(ConnectionTokenMode::None))?;
 		let log_file = self.get_logfile()?;
-		let plog = self.logger.prefixed(&log::new_code_server_prefix());
+		let plog = self.get_server_logger();
 
 		let (mut origin, listen_rx) =
 			monitor_server::<SocketMatcher, PathBuf>(child, Some(log_file), plog, false);
@@ -566,6 +1080,52 @@ impl<'a, Http: SimpleHttp + Send + Sync + Clone + 'static> ServerBuilder<'a, Htt
 		})
 	}
 
+	/// Starts the server listening on the host and port given in its
+	/// arguments, for example to serve the web UI on the local network.
+	pub async fn listen_on_port(&self) -> Result<PortCodeServer, AnyError> {
+		Ok(spanf!(
+			self.logger,
+			self.logger.span("server.start").with_attributes(vec! {
+				KeyValue::new("commit_id", self.server_params.release.commit.to_string()),
+				KeyValue::new("quality", format!("{}", self.server_params.release.quality)),
+			}),
+			self._listen_on_port()
+		)?)
+	}
+
+	async fn _listen_on_port(&self) -> Result<PortCodeServer, AnyError> {
+		let mut cmd = self.get_base_command(None)?;
+		cmd.arg("--start-server");
+
+		let token_mode = self.server_params.code_server_args.port_token_mode();
+		let child = self.spawn_server_process(cmd, Some(token_mode))?;
+		let log_file = self.get_logfile()?;
+		let plog = self.get_server_logger();
+
+		let (mut origin, listen_rx) =
+			monitor_server::<PortMatcher, u16>(child, Some(log_file), plog, false);
+
+		let port = match timeout(Duration::from_secs(8), listen_rx).await {
+			Err(e) => {
+				origin.kill().await;
+				Err(wrap(e, "timed out looking for port"))
+			}
+			Ok(Err(e)) => {
+				origin.kill().await;
+				Err(wrap(e, "server exited without writing port"))
+			}
+			Ok(Ok(port)) => Ok(port),
+		}?;
+
+		info!(self.logger, "Server started");
+
+		Ok(PortCodeServer {
+			commit_id: self.server_params.release.commit.to_owned(),
+			port,
+			origin: Arc::new(origin),
+		})
+	}
+
 	/// Starts with a given opaque set of args. Does not set up any port or
 	/// socket, but does return one if present, in the form of a channel.
 	pub async fn start_opaque_with_args<M, R>(
@@ -576,16 +1136,20 @@ impl<'a, Http: SimpleHttp + Send + Sync + Clone + 'static> ServerBuilder<'a, Htt
 		M: ServerOutputMatcher<R>,
 		R: 'static + Send + std::fmt::Debug,
 	{
-		let mut cmd = self.get_base_command();
+		let mut cmd = self.get_base_command(None)?;
 		cmd.args(args);
 
-		let child = self.spawn_server_process(cmd)?;
-		let plog = self.logger.prefixed(&log::new_code_server_prefix());
+		let child = self.spawn_server_process(cmd, None)?;
+		let plog = self.get_server_logger();
 
 		Ok(monitor_server::<M, R>(child, None, plog, true))
 	}
 
-	fn spawn_server_process(&self, mut cmd: Command) -> Result<Child, AnyError> {
+	fn spawn_server_process(
+		&self,
+		mut cmd: Command,
+		token_mode: Option<ConnectionTokenMode>,
+	) -> Result<Child, AnyError> {
 		info!(self.logger, "Starting server...");
 
 		debug!(self.logger, "Starting server with command... {:?}", cmd);
@@ -596,12 +1160,47 @@ impl<'a, Http: SimpleHttp + Send + Sync + Clone + 'static> ServerBuilder<'a, Htt
 			.spawn()
 			.map_err(|e| wrap(e, "error spawning server"))?;
 
+		let pid = child.id().expect("expected server to have pid");
+		self.server_paths.write_pid(pid)?;
+		tokio::spawn(watch_install(
+			self.logger.clone(),
+			self.server_paths.clone(),
+			pid,
+		));
+		if let Some(mode) = token_mode {
+			self.server_paths.write_token_mode(mode)?;
+		}
+		let jail = self.server_params.code_server_args.jail.as_ref();
 		self.server_paths
-			.write_pid(child.id().expect("expected server to have pid"))?;
+			.write_jail(jail.map(|j| j.root.as_path()))?;
+
+		// only servers that are started count as used, not ones just installed
+		let server = self.server_params.as_installed_server();
+		if let Err(e) = ServerHistory::new(self.launcher_paths).record(&server) {
+			warning!(self.logger, "Error recording server history: {}", e);
+		}
 
 		Ok(child)
 	}
 
+	/// Gets a logger for the server's output, which also writes everything to
+	/// the rotated server log, regardless of the CLI's log level.
+	fn get_server_logger(&self) -> log::Logger {
+		let plog = self.logger.prefixed(&log::new_code_server_prefix());
+		match RotatingFileLogSink::new(
+			log::Level::Trace,
+			&self.server_log_file,
+			SERVER_LOG_MAX_SIZE,
+			SERVER_LOG_KEEP,
+		) {
+			Ok(sink) => plog.tee(sink),
+			Err(e) => {
+				warning!(self.logger, "Error opening server log: {}", e);
+				plog
+			}
+		}
+	}
+
 	fn get_logfile(&self) -> Result<File, WrappedError> {
 		File::create(&self.server_paths.logfile).map_err(|e| {
 			wrap(
@@ -614,11 +1213,38 @@ impl<'a, Http: SimpleHttp + Send + Sync + Clone + 'static> ServerBuilder<'a, Htt
 		})
 	}
 
-	fn get_base_command(&self) -> Command {
+	/// Gets the command to run the server, in its jail if it has one. The
+	/// socket directory is where it'll create a socket, if any.
+	fn get_base_command(&self, socket_dir: Option<&Path>) -> Result<Command, AnyError> {
 		let mut cmd = Command::new(&self.server_paths.executable);
 		cmd.stdin(std::process::Stdio::null())
-			.args(self.server_params.code_server_args.command_arguments());
-		cmd
+			.args(self.server_params.code_server_args.command_arguments())
+			.envs(get_session_env(self.launcher_paths));
+
+		let ca_certs = self.launcher_paths.config().server_ca_certs;
+		if let Some(file) = &ca_certs {
+			ca_certs::apply(self.logger, &mut cmd, file);
+		}
+
+		if let Some(jail) = &self.server_params.code_server_args.jail {
+			// jailed servers get data and temp directories of their own, so
+			// they can't change what other servers use
+			let data_dir = jail.root.join(SERVER_DATA_FOLDER_NAME);
+			let temp_dir = new_temp_dir()?.into_path();
+			jail.apply(
+				self.logger,
+				&mut cmd,
+				&JailPaths {
+					install_dir: &self.server_paths.server_dir,
+					data_dir: &data_dir,
+					temp_dir: &temp_dir,
+					socket_dir,
+					ca_certs: ca_certs.as_deref(),
+				},
+			)?;
+		}
+
+		Ok(cmd)
 	}
 }
 
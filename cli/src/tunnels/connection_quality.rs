@@ -0,0 +1,194 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	collections::{HashMap, VecDeque},
+	fmt,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+/// Number of recent pings that quality is calculated over.
+const SAMPLE_WINDOW: usize = 12;
+
+/// Pings not answered within this time are counted as dropped.
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QualityLevel {
+	Good,
+	Fair,
+	Poor,
+}
+
+impl fmt::Display for QualityLevel {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			QualityLevel::Good => write!(f, "good"),
+			QualityLevel::Fair => write!(f, "fair"),
+			QualityLevel::Poor => write!(f, "poor"),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QualitySummary {
+	/// Average round-trip time of answered pings.
+	pub rtt: Duration,
+	/// Fraction of pings, from 0 to 1, that went unanswered.
+	pub drop_rate: f32,
+	pub level: QualityLevel,
+}
+
+impl QualitySummary {
+	fn new(rtt: Duration, drop_rate: f32) -> Self {
+		let level = if rtt > Duration::from_millis(400) || drop_rate >= 0.1 {
+			QualityLevel::Poor
+		} else if rtt > Duration::from_millis(150) || drop_rate > 0.0 {
+			QualityLevel::Fair
+		} else {
+			QualityLevel::Good
+		};
+
+		QualitySummary {
+			rtt,
+			drop_rate,
+			level,
+		}
+	}
+}
+
+#[derive(Default)]
+struct QualityState {
+	next_seq: u32,
+	pending: HashMap<u32, Instant>,
+	/// Outcome of recent pings; None for dropped ones.
+	samples: VecDeque<Option<Duration>>,
+	/// Set once the client answers a ping. Older clients don't, and we
+	/// shouldn't report their connections as dropping everything.
+	has_response: bool,
+}
+
+impl QualityState {
+	fn push_sample(&mut self, sample: Option<Duration>) {
+		if self.samples.len() == SAMPLE_WINDOW {
+			self.samples.pop_front();
+		}
+		self.samples.push_back(sample);
+	}
+}
+
+/// Tracks round-trip times and drops of pings sent to a client.
+#[derive(Clone, Default)]
+pub struct QualityTracker {
+	state: Arc<Mutex<QualityState>>,
+}
+
+impl QualityTracker {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Records that a ping is being sent, returning its sequence number. Pings
+	/// that have been outstanding for too long are counted as dropped.
+	pub fn start_ping(&self) -> u32 {
+		self.start_ping_at(Instant::now())
+	}
+
+	fn start_ping_at(&self, now: Instant) -> u32 {
+		let mut state = self.state.lock().unwrap();
+		let timed_out: Vec<u32> = state
+			.pending
+			.iter()
+			.filter(|(_, sent)| now.duration_since(**sent) > PING_TIMEOUT)
+			.map(|(seq, _)| *seq)
+			.collect();
+		for seq in timed_out {
+			state.pending.remove(&seq);
+			state.push_sample(None);
+		}
+
+		let seq = state.next_seq;
+		state.next_seq = state.next_seq.wrapping_add(1);
+		state.pending.insert(seq, now);
+		seq
+	}
+
+	/// Records the client's answer to a ping.
+	pub fn record_pong(&self, seq: u32) {
+		self.record_pong_at(seq, Instant::now())
+	}
+
+	fn record_pong_at(&self, seq: u32, now: Instant) {
+		let mut state = self.state.lock().unwrap();
+		if let Some(sent) = state.pending.remove(&seq) {
+			state.has_response = true;
+			state.push_sample(Some(now.duration_since(sent)));
+		}
+	}
+
+	/// Gets the quality of the connection, if the client answers pings.
+	pub fn summary(&self) -> Option<QualitySummary> {
+		let state = self.state.lock().unwrap();
+		if !state.has_response || state.samples.is_empty() {
+			return None;
+		}
+
+		let answered: Vec<Duration> = state.samples.iter().flatten().copied().collect();
+		let drop_rate = 1.0 - answered.len() as f32 / state.samples.len() as f32;
+		let rtt = if answered.is_empty() {
+			PING_TIMEOUT
+		} else {
+			answered.iter().sum::<Duration>() / answered.len() as u32
+		};
+
+		Some(QualitySummary::new(rtt, drop_rate))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_no_summary_without_responses() {
+		let tracker = QualityTracker::new();
+		let start = Instant::now();
+		tracker.start_ping_at(start);
+		tracker.start_ping_at(start + PING_TIMEOUT * 2);
+		assert!(tracker.summary().is_none());
+	}
+
+	#[test]
+	fn test_summary_counts_drops() {
+		let tracker = QualityTracker::new();
+		let start = Instant::now();
+		let a = tracker.start_ping_at(start);
+		tracker.record_pong_at(a, start + Duration::from_millis(50));
+		tracker.start_ping_at(start + Duration::from_secs(1)); // never answered
+		let c = tracker.start_ping_at(start + PING_TIMEOUT * 2);
+		tracker.record_pong_at(c, start + PING_TIMEOUT * 2 + Duration::from_millis(50));
+
+		let summary = tracker.summary().unwrap();
+		assert_eq!(summary.rtt, Duration::from_millis(50));
+		assert!((summary.drop_rate - 1.0 / 3.0).abs() < 0.001);
+		assert_eq!(summary.level, QualityLevel::Poor);
+	}
+
+	#[test]
+	fn test_summary_good_connection() {
+		let tracker = QualityTracker::new();
+		let start = Instant::now();
+		for i in 0..5 {
+			let at = start + Duration::from_secs(i);
+			let seq = tracker.start_ping_at(at);
+			tracker.record_pong_at(seq, at + Duration::from_millis(20));
+		}
+
+		let summary = tracker.summary().unwrap();
+		assert_eq!(summary.level, QualityLevel::Good);
+		assert_eq!(summary.drop_rate, 0.0);
+	}
+}
@@ -2,58 +2,101 @@
  *  Copyright (c) Microsoft Corporation. All rights reserved.
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
+use crate::auth::Auth;
 use crate::commands::tunnels::ShutdownSignal;
 use crate::constants::{
-	CONTROL_PORT, EDITOR_WEB_URL, PROTOCOL_VERSION, QUALITYLESS_SERVER_NAME, VSCODE_CLI_VERSION,
+	CONTROL_PORT, EDITOR_WEB_URL, PROTOCOL_VERSION, QUALITYLESS_SERVER_NAME, REVERSE_FORWARD_PORT,
+	SSH_BRIDGE_PORT, VSCODE_CLI_VERSION,
 };
-use crate::log;
+use crate::log::{self, SpanCounters};
 use crate::self_update::SelfUpdate;
 use crate::state::LauncherPaths;
 use crate::tunnels::protocol::HttpRequestParams;
 use crate::tunnels::socket_signal::CloseReason;
-use crate::update_service::{Platform, UpdateService};
+use crate::update_service::{Platform, UpdateService, UpdateServiceCache};
+use crate::util::clipboard::copy_to_clipboard;
+use crate::util::clock::{system_clock, SuspendDetector, WallInterval};
 use crate::util::errors::{
 	wrap, AnyError, MismatchedLaunchModeError, NoAttachedServerError, ServerWriteError,
+	SessionShareNotAllowed,
 };
 use crate::util::http::{
-	DelegatedHttpRequest, DelegatedSimpleHttp, FallbackSimpleHttp, ReqwestSimpleHttp,
+	DelegatedHttpRequest, DelegatedSimpleHttp, FallbackSimpleHttp, ReqwestSimpleHttp, SimpleHttp,
 };
-use crate::util::io::SilentCopyProgress;
 use crate::util::is_integrated_cli;
+use crate::util::machine::get_host_resources;
+use crate::util::progress::{ProgressStage, ReportProgress, SilentProgress, StageStack};
 use crate::util::sync::{new_barrier, Barrier};
 use opentelemetry::trace::SpanKind;
 use opentelemetry::KeyValue;
+use serde_json::json;
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::env;
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::pin;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{broadcast, mpsc, Mutex};
 
+use super::chaos::ChaosOptions;
 use super::code_server::{
 	AnyCodeServer, CodeServerArgs, ServerBuilder, ServerParamsRaw, SocketCodeServer,
 };
-use super::dev_tunnels::ActiveTunnel;
-use super::paths::prune_stopped_servers;
+use super::connection_quality::{QualityLevel, QualityTracker};
+use super::dev_tunnels::{ActiveTunnel, PortOptions, PortSetting};
+use super::folder_ports::FolderPort;
+use super::forward_targets::{ForwardSpec, ForwardTarget, ForwardTargetPolicy};
+use super::host_router::HostRoute;
+use super::ip_filter::{audit_rejected_connection, IpFilter, PortGate};
+use super::maintenance::MaintenanceWindows;
+use super::notifications::{watch_host_health, Notifier};
+use super::paths::{prune_stopped_servers, stop_running_servers_where};
 use super::port_forwarder::{PortForwarding, PortForwardingProcessor};
 use super::protocol::{
-	CallServerHttpParams, CallServerHttpResult, ClientRequestMethod, EmptyResult, ErrorResponse,
-	ForwardParams, ForwardResult, GetHostnameResponse, ResponseError, ServeParams, ServerLog,
-	ServerMessageParams, ServerRequestMethod, SuccessResponse, ToClientRequest, ToServerRequest,
-	UnforwardParams, UpdateParams, UpdateResult, VersionParams,
+	AuthWarningParams, CallServerHttpParams, CallServerHttpResult, ClientRequestMethod,
+	ConnectionQualityParams, EmptyResult, ErrorResponse, ForwardManyParams, ForwardManyResult,
+	ForwardParams, ForwardResult, GetHostnameResponse, HostPingParams, PortForwardResult,
+	ProgressParams, ResponseError, ServeParams, ServerLog, ServerMessageParams,
+	ServerRequestMethod, ServerRestartedParams, ShareSessionParams, ShareSessionResult,
+	SuccessResponse, ToClientRequest, ToServerRequest, UnforwardParams, UpdateParams, UpdateResult,
+	VersionParams,
 };
+use super::reverse_forward::serve_reverse_forwarding;
 use super::server_bridge::{get_socket_rw_stream, ServerBridge};
-use super::socket_signal::{ClientMessageDecoder, ServerMessageSink, SocketSignal};
+use super::server_routing::{AlternateServer, ServerRouting};
+use super::server_selection::ServerSelection;
+use super::server_updates::{update_servers_when_idle, ActiveClients, ServerUpdateOptions};
+use super::session_recording::{RecordedData, SessionRecorder};
+use super::session_share::{listen_local, SessionShare};
+use super::socket_signal::{
+	BacklogEvent, ClientMessageDecoder, OutgoingQueue, ServerMessageSink, SocketSignal,
+};
+use super::ssh_bridge::serve_ssh_bridge;
+use super::stats_history::{StatsRecorder, UptimeRecorder};
+use super::workspace_trust::WorkspacePolicy;
 
 type ServerBridgeList = Option<Vec<(u16, ServerBridge)>>;
 type ServerBridgeListLock = Arc<Mutex<ServerBridgeList>>;
 type HttpRequestsMap = Arc<std::sync::Mutex<HashMap<u32, DelegatedHttpRequest>>>;
 type CodeServerCell = Arc<Mutex<Option<SocketCodeServer>>>;
 
+/// How often connections check whether the host's credentials will soon expire.
+const AUTH_WARNING_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15 * 60);
+
+/// How often clients are pinged to measure connection quality.
+const QUALITY_PING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Quality is sent to the client whenever it changes, and also every this
+/// many pings so that newly-attached UI can show it.
+const QUALITY_REPORT_EVERY_TICKS: u32 = 6;
+
+/// How often uptime is recorded and tunnel stats are saved to disk.
+const STATS_FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
 struct HandlerContext {
 	/// Exit barrier for the socket.
 	closer: Barrier<()>,
@@ -71,16 +114,38 @@ struct HandlerContext {
 	server_bridges: ServerBridgeListLock,
 	// the cli arguments used to start the code server
 	code_server_args: CodeServerArgs,
-	/// counter for the number of bytes received from the socket
-	rx_counter: Arc<AtomicUsize>,
+	/// counters for the bytes sent and received on the socket
+	counters: SpanCounters,
 	/// port forwarding functionality
 	port_forwarding: PortForwarding,
 	/// install platform for the VS Code server
 	platform: Platform,
 	/// http client to make download/update requests
 	http: FallbackSimpleHttp,
+	/// cache for update service metadata, if enabled
+	update_cache: Option<UpdateServiceCache>,
 	/// requests being served by the client
 	http_requests: HttpRequestsMap,
+	/// round-trip and drop measurements for the connection
+	quality: QualityTracker,
+	/// addresses clients are allowed to connect from
+	ip_filter: IpFilter,
+	/// windows during which updates and restarts are allowed
+	maintenance: MaintenanceWindows,
+	/// sends notifications about problems on the host
+	notifier: Notifier,
+	/// trusted folders and the root clients are kept inside
+	workspace: WorkspacePolicy,
+	/// notifies every connection when the server is restarted
+	server_restarts: broadcast::Sender<ServerRestartedParams>,
+	/// which clients are served the alternate server, if any
+	routing: ServerRouting,
+	/// identity the client reported, if any
+	identity: Option<String>,
+	/// shares the host's login, if the client is on the local control socket
+	session_share: Option<SessionShare>,
+	/// clients connected to the host, and the commits they asked for
+	clients: ActiveClients,
 }
 
 static MESSAGE_ID_COUNTER: AtomicU32 = AtomicU32::new(0);
@@ -132,14 +197,92 @@ pub struct ServerTermination {
 	pub tunnel: ActiveTunnel,
 }
 
-fn print_listening(log: &log::Logger, tunnel_name: &str) {
+/// Options for hosting the control server, set from the command line.
+#[derive(Clone, Default)]
+pub struct ServeOptions {
+	/// Cache for update service metadata, if enabled.
+	pub update_cache: Option<UpdateServiceCache>,
+	/// Open the editor link in the default browser once the tunnel is ready.
+	pub open_browser: bool,
+	/// Copy the editor link to the clipboard once the tunnel is ready.
+	pub copy_url: bool,
+	/// Faults to inject into each connection, for testing on bad networks.
+	pub chaos: Option<ChaosOptions>,
+	/// Addresses clients are allowed to connect from.
+	pub ip_filter: IpFilter,
+	/// Local port of an SSH server to expose through the SSH bridge.
+	pub ssh_port: Option<u16>,
+	/// Whether clients may have ports on this host connect back to their
+	/// machines.
+	pub allow_reverse_forward: bool,
+	/// Windows during which updates and restarts are allowed.
+	pub maintenance: MaintenanceWindows,
+	/// Sends notifications about problems on the host.
+	pub notifier: Notifier,
+	/// Folders clients are told to trust, and the root they're kept inside.
+	pub workspace: WorkspacePolicy,
+	/// Ports to forward to other hosts once the tunnel starts.
+	pub forwards: Vec<ForwardSpec>,
+	/// Ports on this machine to forward once the tunnel starts.
+	pub ports: Vec<PortSetting>,
+	/// Folder clients open by default, if not the current directory.
+	pub default_folder: Option<PathBuf>,
+	/// Ports the default folder asks to be forwarded once the tunnel starts.
+	pub folder_ports: Vec<FolderPort>,
+	/// Hosts other than this one that ports may be forwarded to.
+	pub forward_targets: ForwardTargetPolicy,
+	/// Alternate server some clients are served, and which ones.
+	pub routing: ServerRouting,
+	/// If set, how often to check for new servers, installing them ahead of
+	/// time and swapping them in once clients have been gone for the
+	/// reconnection grace period.
+	pub server_update_interval: Option<std::time::Duration>,
+	/// If set, the control protocol is also served to clients on this machine,
+	/// which may ask for the host's login.
+	pub session_share: Option<SessionShare>,
+}
+
+/// What each connection on the control port is served with, taken from the
+/// `ServeOptions` and the server's own state and cloned for every connection.
+#[derive(Clone)]
+struct ConnectionOptions {
+	/// A loopback channel to talk to the TCP server task.
+	server_tx: mpsc::Sender<ServerSignal>,
+	launcher_paths: LauncherPaths,
+	code_server_args: CodeServerArgs,
+	port_forwarding: PortForwarding,
+	platform: Platform,
+	auth: Auth,
+	update_cache: Option<UpdateServiceCache>,
+	chaos: Option<ChaosOptions>,
+	ip_filter: IpFilter,
+	maintenance: MaintenanceWindows,
+	notifier: Notifier,
+	workspace: WorkspacePolicy,
+	server_restarts: broadcast::Sender<ServerRestartedParams>,
+	routing: ServerRouting,
+	allow_reverse_forward: bool,
+	/// Only set for connections on the local control socket.
+	session_share: Option<SessionShare>,
+	clients: ActiveClients,
+}
+
+/// Prints the link to connect to the tunnel, returning it if one is available.
+fn print_listening(
+	log: &log::Logger,
+	tunnel_name: &str,
+	default_folder: Option<&Path>,
+) -> Option<url::Url> {
 	debug!(
 		log,
 		"{} is listening for incoming connections", QUALITYLESS_SERVER_NAME
 	);
 
 	let home_dir = dirs::home_dir().unwrap_or_else(|| PathBuf::from(""));
-	let current_dir = env::current_dir().unwrap_or_else(|_| PathBuf::from(""));
+	let current_dir = match default_folder {
+		Some(f) => f.to_path_buf(),
+		None => env::current_dir().unwrap_or_else(|_| PathBuf::from("")),
+	};
 
 	let dir = if home_dir == current_dir {
 		PathBuf::from("")
@@ -147,10 +290,7 @@ fn print_listening(log: &log::Logger, tunnel_name: &str) {
 		current_dir
 	};
 
-	let base_web_url = match EDITOR_WEB_URL {
-		Some(u) => u,
-		None => return,
-	};
+	let base_web_url = EDITOR_WEB_URL?;
 
 	let mut addr = url::Url::parse(base_web_url).unwrap();
 	{
@@ -167,6 +307,25 @@ fn print_listening(log: &log::Logger, tunnel_name: &str) {
 
 	let message = &format!("\nOpen this link in your browser {}\n", addr);
 	log.result(message);
+	Some(addr)
+}
+
+/// Opens and/or copies the editor link, as requested in the options. These
+/// are conveniences, so failures are only logged.
+async fn share_editor_url(log: &log::Logger, url: &url::Url, options: &ServeOptions) {
+	if options.open_browser {
+		match open::that(url.as_str()) {
+			Ok(_) => log.result("Opened the link in your default browser"),
+			Err(e) => warning!(log, "Could not open the browser: {}", e),
+		}
+	}
+
+	if options.copy_url {
+		match copy_to_clipboard(url.as_str()).await {
+			Ok(_) => log.result("Copied the link to your clipboard"),
+			Err(e) => warning!(log, "Could not copy the link to the clipboard: {}", e),
+		}
+	}
 }
 
 // Runs the launcher server. Exits on a ctrl+c or when requested by a user.
@@ -178,14 +337,195 @@ pub async fn serve(
 	launcher_paths: &LauncherPaths,
 	code_server_args: &CodeServerArgs,
 	platform: Platform,
+	auth: Auth,
+	options: ServeOptions,
 	shutdown_rx: mpsc::UnboundedReceiver<ShutdownSignal>,
 ) -> Result<ServerTermination, AnyError> {
 	let mut port = tunnel.add_port_direct(CONTROL_PORT).await?;
-	print_listening(log, &tunnel.name);
+	let gate = PortGate::new(
+		log.clone(),
+		launcher_paths.clone(),
+		options.ip_filter.clone(),
+	);
+	if let Some(ssh_port) = options.ssh_port {
+		let connections = tunnel.add_port_direct(SSH_BRIDGE_PORT).await?;
+		tokio::spawn(serve_ssh_bridge(
+			log.clone(),
+			connections,
+			gate.clone(),
+			ssh_port,
+		));
+		info!(
+			log,
+			"Exposing the SSH server on port {} for `code tunnel ssh-config`", ssh_port
+		);
+	}
+	if options.allow_reverse_forward {
+		let connections = tunnel.add_port_direct(REVERSE_FORWARD_PORT).await?;
+		tokio::spawn(serve_reverse_forwarding(
+			log.clone(),
+			connections,
+			gate.clone(),
+		));
+		info!(
+			log,
+			"Clients may forward ports on this host to their machines with `code tunnel forward --reverse`"
+		);
+	}
+	if !gate.is_open() {
+		info!(
+			log,
+			"Only accepting connections from allowed addresses. Forwarded ports are served over HTTP so each request's address can be checked."
+		);
+		warning!(
+			log,
+			"The relay doesn't report the address of clients connecting to the editor, so they'll be turned away while addresses are restricted"
+		);
+	}
+	if let Some(chaos) = &options.chaos {
+		warning!(log, "Injecting faults into tunnel connections: {}", chaos);
+	}
+
+	if let Some(url) = print_listening(log, &tunnel.name, options.default_folder.as_deref()) {
+		share_editor_url(log, &url, &options).await;
+	}
 
-	let mut forwarding = PortForwardingProcessor::new();
+	let mut forwarding =
+		PortForwardingProcessor::new(log.clone(), options.forward_targets.clone(), gate);
+	for spec in options.forwards.clone() {
+		let handle = forwarding.handle();
+		let log = log.clone();
+		tokio::spawn(async move {
+			match handle
+				.forward_target(spec.port, spec.target.clone(), PortOptions::default())
+				.await
+			{
+				Ok(uri) => log.result(&format!(
+					"Port {} forwarded to {} is available at {}",
+					spec.port, spec.target, uri
+				)),
+				Err(e) => warning!(
+					log,
+					"Could not forward port {} to {}: {}",
+					spec.port,
+					spec.target,
+					e
+				),
+			}
+		});
+	}
+	for setting in options.ports.clone() {
+		let handle = forwarding.handle();
+		let log = log.clone();
+		tokio::spawn(async move {
+			match handle
+				.forward_with_options(setting.port, setting.options())
+				.await
+			{
+				Ok(uri) => log.result(&format!(
+					"Port {} is available {} at {}",
+					setting.port,
+					if setting.options().public {
+						"publicly"
+					} else {
+						"privately"
+					},
+					uri
+				)),
+				Err(e) => warning!(log, "Could not forward port {}: {}", setting.port, e),
+			}
+		});
+	}
+	for folder_port in options.folder_ports.clone() {
+		let handle = forwarding.handle();
+		let log = log.clone();
+		tokio::spawn(async move {
+			let FolderPort {
+				port,
+				target,
+				options,
+			} = folder_port;
+			let name = match &options.label {
+				Some(label) => format!("{} ({})", port, label),
+				None => port.to_string(),
+			};
+			let visibility = if options.public {
+				"publicly"
+			} else {
+				"privately"
+			};
+			let result = match target {
+				Some(target) => handle.forward_target(port, target, options).await,
+				None => handle.forward_with_options(port, options).await,
+			};
+			match result {
+				Ok(uri) => log.result(&format!(
+					"Port {} for the folder is available {} at {}",
+					name, visibility, uri
+				)),
+				Err(e) => warning!(log, "Could not forward port {} for the folder: {}", name, e),
+			}
+		});
+	}
 	let (tx, mut rx) = mpsc::channel::<ServerSignal>(4);
 	let (exit_barrier, signal_exit) = new_barrier();
+	let (server_restarts, _) = broadcast::channel(4);
+
+	let stats = StatsRecorder::new(launcher_paths, &tunnel.name);
+	let mut uptime = UptimeRecorder::new(stats.clone(), log.clone());
+	let mut stats_interval = tokio::time::interval(STATS_FLUSH_INTERVAL);
+	let mut suspend_detector = SuspendDetector::new(system_clock());
+	let mut in_maintenance_window = false;
+
+	tokio::spawn(watch_host_health(
+		log.clone(),
+		options.notifier.clone(),
+		auth.clone(),
+		launcher_paths.clone(),
+		exit_barrier.clone(),
+	));
+
+	let active_clients = ActiveClients::default();
+	let connection_options = ConnectionOptions {
+		server_tx: tx.clone(),
+		launcher_paths: launcher_paths.clone(),
+		code_server_args: code_server_args.clone(),
+		port_forwarding: forwarding.handle(),
+		platform,
+		auth: auth.clone(),
+		update_cache: options.update_cache.clone(),
+		chaos: options.chaos.clone(),
+		ip_filter: options.ip_filter.clone(),
+		maintenance: options.maintenance.clone(),
+		notifier: options.notifier.clone(),
+		workspace: options.workspace.clone(),
+		server_restarts,
+		routing: options.routing.clone(),
+		allow_reverse_forward: options.allow_reverse_forward,
+		session_share: None,
+		clients: active_clients.clone(),
+	};
+	let mut local_connections = match &options.session_share {
+		Some(_) => listen_local(log.clone(), launcher_paths, exit_barrier.clone()),
+		None => mpsc::channel(1).1,
+	};
+
+	if let Some(interval) = options.server_update_interval {
+		tokio::spawn(update_servers_when_idle(
+			log.clone(),
+			launcher_paths.clone(),
+			ServerUpdateOptions {
+				interval,
+				code_server_args: code_server_args.clone(),
+				platform,
+				selection: options.routing.selection.clone(),
+				update_cache: options.update_cache.clone(),
+				maintenance: options.maintenance.clone(),
+			},
+			active_clients,
+			exit_barrier.clone(),
+		));
+	}
 
 	pin!(shutdown_rx);
 
@@ -211,6 +551,19 @@ pub async fn serve(
 			Some(w) = forwarding.recv() => {
 				forwarding.process(w, &mut tunnel).await;
 			},
+			_ = stats_interval.tick() => {
+				if let Some(d) = suspend_detector.check() {
+					info!(log, "System resumed after being suspended for about {}s", d.as_secs());
+				}
+				uptime.tick(tunnel.reconnect_count());
+
+				let was_in_window = in_maintenance_window;
+				in_maintenance_window = !options.maintenance.is_empty() && options.maintenance.allows_now();
+				if in_maintenance_window && !was_in_window {
+					info!(log, "Maintenance window started, checking for updates");
+					tokio::spawn(apply_scheduled_update(log.clone(), launcher_paths.clone(), options.update_cache.clone(), tx.clone()));
+				}
+			},
 			l = port.recv() => {
 				let socket = match l {
 					Some(p) => p,
@@ -223,66 +576,144 @@ pub async fn serve(
 					}
 				};
 
-				let own_log = log.prefixed(&log::new_rpc_prefix());
-				let own_tx = tx.clone();
-				let own_paths = launcher_paths.clone();
-				let own_exit = exit_barrier.clone();
-				let own_code_server_args = code_server_args.clone();
-				let own_forwarding = forwarding.handle();
-
-				tokio::spawn(async move {
-					use opentelemetry::trace::{FutureExt, TraceContextExt};
-
-					let span = own_log.span("server.socket").with_kind(SpanKind::Consumer).start(own_log.tracer());
-					let cx = opentelemetry::Context::current_with_span(span);
-					let serve_at = Instant::now();
-
-					debug!(own_log, "Serving new connection");
-
-					let (writehalf, readhalf) = socket.into_split();
-					let stats = process_socket(own_exit, readhalf, writehalf, own_log, own_tx, own_paths, own_code_server_args, own_forwarding, platform).with_context(cx.clone()).await;
-
-					cx.span().add_event(
-						"socket.bandwidth",
-						vec![
-							KeyValue::new("tx", stats.tx as f64),
-							KeyValue::new("rx", stats.rx as f64),
-							KeyValue::new("duration_ms", serve_at.elapsed().as_millis() as f64),
-						],
-					);
-					cx.span().end();
-				 });
+				let address = socket.observed_address();
+				let (writehalf, readhalf) = socket.into_split();
+				spawn_connection(
+					log,
+					exit_barrier.clone(),
+					connection_options.clone(),
+					stats.clone(),
+					address,
+					readhalf,
+					writehalf,
+				);
+			},
+			Some(stream) = local_connections.recv() => {
+				let (readhalf, writehalf) = tokio::io::split(stream);
+				spawn_connection(
+					log,
+					exit_barrier.clone(),
+					ConnectionOptions {
+						session_share: options.session_share.clone(),
+						..connection_options.clone()
+					},
+					stats.clone(),
+					None,
+					Box::new(readhalf),
+					Box::new(writehalf),
+				);
 			}
 		}
 	}
 }
 
+/// Serves a connection to the control port, from the relay or a local client,
+/// until it closes.
+#[allow(clippy::too_many_arguments)]
+fn spawn_connection(
+	log: &log::Logger,
+	exit_barrier: Barrier<()>,
+	options: ConnectionOptions,
+	stats: StatsRecorder,
+	address: Option<IpAddr>,
+	readhalf: Box<dyn AsyncRead + Send + Unpin>,
+	writehalf: Box<dyn AsyncWrite + Send + Unpin>,
+) {
+	let own_log = log.prefixed(&log::new_rpc_prefix()).deduplicated();
+	tokio::spawn(async move {
+		use opentelemetry::trace::TraceContextExt;
+		let _client = options.clients.connect().await;
+
+		let span = own_log
+			.span("server.socket")
+			.with_kind(SpanKind::Consumer)
+			.start(own_log.tracer());
+		let cx = opentelemetry::Context::current_with_span(span);
+		let serve_at = Instant::now();
+		let counters = SpanCounters::default();
+
+		debug!(own_log, "Serving new connection");
+
+		let heartbeat_log = own_log.clone();
+		let socket_stats = log::with_heartbeat(
+			&heartbeat_log,
+			"server.socket",
+			&cx,
+			Some(&counters),
+			process_socket(
+				exit_barrier,
+				readhalf,
+				writehalf,
+				own_log,
+				counters.clone(),
+				address,
+				options,
+			),
+		)
+		.await;
+
+		heartbeat_log.flush_repeated();
+		stats.record_connection(socket_stats.tx, socket_stats.rx);
+		cx.span().add_event(
+			"socket.bandwidth",
+			vec![
+				KeyValue::new("tx", socket_stats.tx as f64),
+				KeyValue::new("rx", socket_stats.rx as f64),
+				KeyValue::new("duration_ms", serve_at.elapsed().as_millis() as f64),
+			],
+		);
+		cx.span().end();
+	});
+}
+
 struct SocketStats {
 	rx: usize,
 	tx: usize,
 }
 
-#[allow(clippy::too_many_arguments)] // necessary here
 async fn process_socket(
 	mut exit_barrier: Barrier<()>,
 	readhalf: impl AsyncRead + Send + Unpin + 'static,
 	mut writehalf: impl AsyncWrite + Unpin,
 	log: log::Logger,
-	server_tx: mpsc::Sender<ServerSignal>,
-	launcher_paths: LauncherPaths,
-	code_server_args: CodeServerArgs,
-	port_forwarding: PortForwarding,
-	platform: Platform,
+	counters: SpanCounters,
+	address: Option<IpAddr>,
+	options: ConnectionOptions,
 ) -> SocketStats {
-	let (socket_tx, mut socket_rx) = mpsc::channel(4);
+	let ConnectionOptions {
+		server_tx,
+		launcher_paths,
+		code_server_args,
+		port_forwarding,
+		platform,
+		auth,
+		update_cache,
+		chaos,
+		ip_filter,
+		maintenance,
+		notifier,
+		workspace,
+		server_restarts,
+		routing,
+		allow_reverse_forward,
+		session_share,
+		clients,
+	} = options;
+	let (socket_tx, socket_rx) = mpsc::channel(4);
+	let (readhalf, mut socket_rx): (Box<dyn AsyncRead + Send + Unpin>, _) = match &chaos {
+		Some(c) => (
+			Box::new(c.delay_reader(readhalf)),
+			c.delay_signals(socket_rx),
+		),
+		None => (Box::new(readhalf), socket_rx),
+	};
 	let http_requests = Arc::new(std::sync::Mutex::new(HashMap::new()));
-	let rx_counter = Arc::new(AtomicUsize::new(0));
 
 	let server_bridges: ServerBridgeListLock = Arc::new(Mutex::new(Some(vec![])));
 	let server_bridges_lock = Arc::clone(&server_bridges);
 	let barrier_ctx = exit_barrier.clone();
 	let log_ctx = log.clone();
-	let rx_counter_ctx = rx_counter.clone();
+	let counters_ctx = counters.clone();
 	let http_requests_ctx = http_requests.clone();
 	let (http_delegated, mut http_rx) = DelegatedSimpleHttp::new(log_ctx.clone());
 
@@ -294,16 +725,58 @@ async fn process_socket(
 			log: log_ctx,
 			launcher_paths,
 			code_server_args,
-			rx_counter: rx_counter_ctx,
+			counters: counters_ctx,
 			code_server: Arc::new(Mutex::new(None)),
 			server_bridges: server_bridges_lock,
 			port_forwarding,
 			platform,
 			http: FallbackSimpleHttp::new(ReqwestSimpleHttp::new(), http_delegated),
+			update_cache,
 			http_requests: http_requests_ctx,
+			quality: QualityTracker::new(),
+			ip_filter,
+			maintenance,
+			notifier,
+			workspace,
+			server_restarts,
+			routing,
+			identity: None,
+			session_share,
+			clients,
 		};
 
-		send_version(&ctx.socket_tx).await;
+		// checked against the address the relay saw, never one the client
+		// reports, which it could make up
+		if !ctx.ip_filter.is_empty() {
+			let address = address.map(|a| a.to_string());
+			match ctx.ip_filter.check(address.as_deref()) {
+				Ok(addr) => info!(ctx.log, "Accepted connection from client at {}", addr),
+				Err(reason) => {
+					reject_client(&ctx, address.as_deref(), &reason).await;
+					ctx.dispose().await;
+					return;
+				}
+			}
+		}
+
+		send_version(&ctx.socket_tx, &ctx.workspace, allow_reverse_forward).await;
+		tokio::spawn(watch_auth_expiry(
+			auth,
+			ctx.socket_tx.clone(),
+			ctx.closer.clone(),
+		));
+		tokio::spawn(watch_server_restarts(
+			ctx.server_restarts.subscribe(),
+			ctx.code_server.clone(),
+			ctx.socket_tx.clone(),
+			ctx.closer.clone(),
+		));
+		tokio::spawn(watch_connection_quality(
+			ctx.log.clone(),
+			ctx.quality.clone(),
+			ctx.socket_tx.clone(),
+			ctx.closer.clone(),
+		));
 
 		if let Err(e) = handle_socket_read(readhalf, &mut ctx).await {
 			debug!(ctx.log, "closing socket reader: {}", e);
@@ -316,15 +789,19 @@ async fn process_socket(
 		ctx.dispose().await;
 	});
 
-	let mut tx_counter = 0;
+	let mut queue = OutgoingQueue::default();
 
 	loop {
+		// Biased so that everything ready to send is queued before writing,
+		// letting interactive messages go ahead of low-priority ones.
 		tokio::select! {
+			biased;
+
 			_ = exit_barrier.wait() => {
 				writehalf.shutdown().await.ok();
 				break;
 			},
-			Some(r) = http_rx.recv() => {
+			Some(r) = http_rx.recv(), if queue.can_accept() => {
 				let id = next_message_id();
 				let serialized = rmp_serde::to_vec_named(&ToClientRequest {
 					id: None,
@@ -336,23 +813,13 @@ async fn process_socket(
 				})
 				.unwrap();
 				http_requests.lock().unwrap().insert(id, r);
-
-				tx_counter += serialized.len();
-				if let Err(e) = writehalf.write_all(&serialized).await {
-					debug!(log, "Closing connection: {}", e);
-					break;
-				}
+				queue.push(serialized);
 			}
-			recv = socket_rx.recv() => match recv {
+			recv = socket_rx.recv(), if queue.can_accept() => match recv {
 				None => break,
 				Some(message) => match message {
-					SocketSignal::Send(bytes) => {
-						tx_counter += bytes.len();
-						if let Err(e) = writehalf.write_all(&bytes).await {
-							debug!(log, "Closing connection: {}", e);
-							break;
-						}
-					}
+					SocketSignal::Send(bytes) => queue.push(bytes),
+					SocketSignal::SendLowPriority(bytes) => queue.push_low_priority(bytes),
 					SocketSignal::CloseWith(reason) => {
 						debug!(log, "Closing connection: {}", reason.0);
 						break;
@@ -369,27 +836,248 @@ async fn process_socket(
 						}
 					}
 				}
+			},
+			_ = std::future::ready(()), if !queue.is_empty() => {
+				let bytes = queue.pop().unwrap();
+				counters.add_tx(bytes.len());
+				if let Err(e) = writehalf.write_all(&bytes).await {
+					debug!(log, "Closing connection: {}", e);
+					break;
+				}
+
+				if let Some(event) = queue.check_backlog(Instant::now()) {
+					report_backlog(&log, event);
+				}
 			}
 		}
 	}
 
 	SocketStats {
-		tx: tx_counter,
-		rx: rx_counter.load(Ordering::Acquire),
+		tx: counters.tx() as usize,
+		rx: counters.rx() as usize,
 	}
 }
 
-async fn send_version(tx: &mpsc::Sender<SocketSignal>) {
+/// Logs and records a diagnostic event when a client stops or resumes keeping
+/// up with the data sent to it.
+fn report_backlog(log: &log::Logger, event: BacklogEvent) {
+	use opentelemetry::trace::TraceContextExt;
+
+	let cx = opentelemetry::Context::current();
+	match event {
+		BacklogEvent::Shedding {
+			backlogged_for,
+			queued,
+			dropped,
+		} => {
+			warning!(
+				log,
+				"Client has not kept up with sent data for {}ms ({} messages queued), dropping logs and diagnostics until it does",
+				backlogged_for.as_millis(),
+				queued
+			);
+			cx.span().add_event(
+				"socket.slow_consumer",
+				vec![
+					KeyValue::new("backlog_ms", backlogged_for.as_millis() as f64),
+					KeyValue::new("queued", queued as i64),
+					KeyValue::new("dropped", dropped as i64),
+				],
+			);
+		}
+		BacklogEvent::Recovered { dropped } => {
+			info!(
+				log,
+				"Client caught up with sent data, {} low-priority messages were dropped", dropped
+			);
+			cx.span().add_event(
+				"socket.slow_consumer_recovered",
+				vec![KeyValue::new("dropped", dropped as i64)],
+			);
+		}
+	}
+}
+
+async fn send_version(
+	tx: &mpsc::Sender<SocketSignal>,
+	policy: &WorkspacePolicy,
+	reverse_forward: bool,
+) {
+	let workspace = policy
+		.root
+		.clone()
+		.unwrap_or_else(|| dirs::home_dir().unwrap_or_default());
+	let resources = tokio::task::spawn_blocking(move || get_host_resources(&workspace))
+		.await
+		.unwrap();
+
 	tx.send(SocketSignal::from_message(&ToClientRequest {
 		id: None,
 		params: ClientRequestMethod::version(VersionParams {
 			version: VSCODE_CLI_VERSION.unwrap_or("dev"),
 			protocol_version: PROTOCOL_VERSION,
+			resources,
+			trusted_folders: policy
+				.trusted_folders
+				.iter()
+				.map(|f| f.to_string_lossy().to_string())
+				.collect(),
+			workspace_root: policy
+				.root
+				.as_ref()
+				.map(|r| r.to_string_lossy().to_string()),
+			reverse_forward,
 		}),
 	}))
 	.await
 	.ok();
 }
+
+/// Periodically checks whether the host's credentials will soon stop working,
+/// and warns the client when they will. Each distinct warning is sent once.
+async fn watch_auth_expiry(auth: Auth, tx: mpsc::Sender<SocketSignal>, mut closer: Barrier<()>) {
+	// measured on the wall clock, so expiry is re-checked soon after a resume
+	let mut interval = WallInterval::new(system_clock(), AUTH_WARNING_CHECK_INTERVAL);
+	let mut last_message: Option<String> = None;
+
+	loop {
+		tokio::select! {
+			_ = closer.wait() => return,
+			_ = interval.tick() => {},
+		}
+
+		match auth.get_expiry_warning() {
+			Some(w) if last_message.as_ref() != Some(&w.message) => {
+				let sent = tx
+					.send(SocketSignal::from_message(&ToClientRequest {
+						id: None,
+						params: ClientRequestMethod::authwarning(AuthWarningParams {
+							provider: w.provider.to_string(),
+							expires_at: w.expires_at.map(|e| e.to_rfc3339()),
+							message: w.message.clone(),
+						}),
+					}))
+					.await;
+				if sent.is_err() {
+					return;
+				}
+				last_message = Some(w.message);
+			}
+			Some(_) => {}
+			None => last_message = None,
+		}
+	}
+}
+
+/// Forgets the connection's server when it's restarted, so the next `serve`
+/// starts it again, and tells the client to reconnect its sessions. Restarts
+/// of other servers are ignored.
+async fn watch_server_restarts(
+	mut restarts: broadcast::Receiver<ServerRestartedParams>,
+	code_server: CodeServerCell,
+	tx: mpsc::Sender<SocketSignal>,
+	mut closer: Barrier<()>,
+) {
+	loop {
+		let params = tokio::select! {
+			_ = closer.wait() => return,
+			r = restarts.recv() => match r {
+				Ok(p) => p,
+				Err(broadcast::error::RecvError::Lagged(_)) => continue,
+				Err(broadcast::error::RecvError::Closed) => return,
+			},
+		};
+
+		{
+			let mut server = code_server.lock().await;
+			match &*server {
+				Some(s) if params.commits.contains(&s.commit_id) => server.take(),
+				_ => continue,
+			};
+		}
+
+		let sent = tx
+			.send(SocketSignal::from_message(&ToClientRequest {
+				id: None,
+				params: ClientRequestMethod::serverrestarted(params),
+			}))
+			.await;
+		if sent.is_err() {
+			return;
+		}
+	}
+}
+
+/// Pings the client periodically to measure the quality of its connection,
+/// sending it summaries so it can show a latency indicator. Changes in quality
+/// are logged, to help correlate reports of slowness with network conditions.
+async fn watch_connection_quality(
+	log: log::Logger,
+	tracker: QualityTracker,
+	tx: mpsc::Sender<SocketSignal>,
+	mut closer: Barrier<()>,
+) {
+	let mut interval = tokio::time::interval(QUALITY_PING_INTERVAL);
+	let mut last_level: Option<QualityLevel> = None;
+	let mut ticks: u32 = 0;
+
+	loop {
+		tokio::select! {
+			_ = closer.wait() => return,
+			_ = interval.tick() => {},
+		}
+
+		let seq = tracker.start_ping();
+		let sent = tx
+			.send(SocketSignal::from_low_priority_message(&ToClientRequest {
+				id: None,
+				params: ClientRequestMethod::hostping(HostPingParams { seq }),
+			}))
+			.await;
+		if sent.is_err() {
+			return;
+		}
+
+		ticks = ticks.wrapping_add(1);
+		let summary = match tracker.summary() {
+			Some(s) => s,
+			None => continue,
+		};
+
+		let changed = last_level != Some(summary.level);
+		if changed {
+			let message = format!(
+				"Connection quality is {} (round trip {}ms, {:.0}% dropped)",
+				summary.level,
+				summary.rtt.as_millis(),
+				summary.drop_rate * 100.0
+			);
+			if summary.level == QualityLevel::Good || last_level.is_none() {
+				info!(log, "{}", message);
+			} else {
+				warning!(log, "{}", message);
+			}
+			last_level = Some(summary.level);
+		}
+
+		if changed || ticks % QUALITY_REPORT_EVERY_TICKS == 0 {
+			let sent = tx
+				.send(SocketSignal::from_low_priority_message(&ToClientRequest {
+					id: None,
+					params: ClientRequestMethod::connectionquality(ConnectionQualityParams {
+						quality: summary.level.to_string(),
+						rtt_ms: summary.rtt.as_millis() as u64,
+						drop_rate: summary.drop_rate,
+					}),
+				}))
+				.await;
+			if sent.is_err() {
+				return;
+			}
+		}
+	}
+}
+
 async fn handle_socket_read(
 	readhalf: impl AsyncRead + Unpin,
 	ctx: &mut HandlerContext,
@@ -429,8 +1117,7 @@ async fn read_next(
 		_ = ctx.closer.wait() => return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "eof")),
 	};
 	decode_buf.resize(msg_length, 0);
-	ctx.rx_counter
-		.fetch_add(msg_length + 4 /* u32 */, Ordering::Relaxed);
+	ctx.counters.add_rx(msg_length + 4 /* u32 */);
 
 	tokio::select! {
 		r = socket_reader.read_exact(decode_buf) => r?,
@@ -449,7 +1136,17 @@ async fn read_next(
 // Dispatches a server request. Returns `true` if the socket reading should
 // continue,
 async fn dispatch_next(req: ToServerRequest, ctx: &mut HandlerContext, did_update: &mut bool) {
-	let log = ctx.log.prefixed(
+	// Work done for the call is logged with the client's correlation ID, if it
+	// sent one, so it can be traced back to the command that made it.
+	let call_log = match req
+		.correlation_id
+		.as_deref()
+		.and_then(log::sanitize_correlation_id)
+	{
+		Some(id) => ctx.log.with_correlation_id(&id),
+		None => ctx.log.clone(),
+	};
+	let log = call_log.prefixed(
 		req.id
 			.map(|id| format!("[call.{}]", id))
 			.as_deref()
@@ -509,7 +1206,7 @@ async fn dispatch_next(req: ToServerRequest, ctx: &mut HandlerContext, did_updat
 	// dispatch_async.
 	macro_rules! dispatch_blocking {
 		($name:expr, $e:expr) => {
-			dispatch_raw!(ctx.log, ctx.socket_tx, $name, $e);
+			dispatch_raw!(call_log, ctx.socket_tx, $name, $e);
 		};
 	}
 
@@ -517,7 +1214,7 @@ async fn dispatch_next(req: ToServerRequest, ctx: &mut HandlerContext, did_updat
 	macro_rules! dispatch_async {
 		($name:expr, $e:expr) => {
 			let socket_tx = ctx.socket_tx.clone();
-			let span_logger = ctx.log.clone();
+			let span_logger = call_log.clone();
 			tokio::spawn(async move { dispatch_raw!(span_logger, socket_tx, $name, $e) })
 		};
 	}
@@ -527,27 +1224,63 @@ async fn dispatch_next(req: ToServerRequest, ctx: &mut HandlerContext, did_updat
 			success!(ctx.socket_tx, EmptyResult {});
 		}
 		ServerRequestMethod::serve(params) => {
-			let log = ctx.log.clone();
+			let log = call_log.clone();
 			let http = ctx.http.clone();
+			let update_cache = ctx.update_cache.clone();
 			let server_bridges = ctx.server_bridges.clone();
 			let code_server_args = ctx.code_server_args.clone();
 			let code_server = ctx.code_server.clone();
 			let platform = ctx.platform;
 			let socket_tx = ctx.socket_tx.clone();
 			let paths = ctx.launcher_paths.clone();
-			dispatch_async!(
-				"serve",
-				handle_serve(
-					log,
+			let notifier = ctx.notifier.clone();
+			let alternate = ctx
+				.routing
+				.route(
+					ctx.identity.as_deref(),
+					params.quality_hint,
+					params.commit_id.as_deref(),
+				)
+				.cloned();
+			// servers of commits asked for by name aren't swapped for newer ones
+			let pinned = params
+				.commit_id
+				.as_deref()
+				.or_else(|| alternate.as_ref().and_then(|a| a.commit.as_deref()));
+			if let Some(commit) = pinned {
+				ctx.clients.request_commit(commit);
+			}
+			let selection = ctx.routing.selection.clone();
+			dispatch_async!("serve", async move {
+				let r = handle_serve(
+					log.clone(),
 					http,
+					update_cache,
 					server_bridges,
 					code_server_args,
 					platform,
 					code_server,
 					socket_tx,
 					paths,
-					params
+					params,
+					alternate,
+					selection,
 				)
+				.await;
+				if let Err(e) = &r {
+					notifier.record_server_failure(&log, e).await;
+				}
+				r
+			});
+		}
+		ServerRequestMethod::restartserver(_) => {
+			let log = call_log.clone();
+			let paths = ctx.launcher_paths.clone();
+			let server_restarts = ctx.server_restarts.clone();
+			let code_server = ctx.code_server.clone();
+			dispatch_async!(
+				"restartserver",
+				handle_restart_server(log, paths, code_server, server_restarts)
 			);
 		}
 		ServerRequestMethod::prune => {
@@ -559,7 +1292,15 @@ async fn dispatch_next(req: ToServerRequest, ctx: &mut HandlerContext, did_updat
 		}
 		ServerRequestMethod::update(p) => {
 			dispatch_blocking!("update", async {
-				let r = handle_update(&ctx.http, &ctx.log, &p).await;
+				let r = handle_update(
+					&ctx.http,
+					&ctx.launcher_paths,
+					&ctx.update_cache,
+					&call_log,
+					&ctx.maintenance,
+					&p,
+				)
+				.await;
 				if matches!(&r, Ok(u) if u.did_update) {
 					*did_update = true;
 				}
@@ -575,19 +1316,49 @@ async fn dispatch_next(req: ToServerRequest, ctx: &mut HandlerContext, did_updat
 			}
 		}
 		ServerRequestMethod::callserverhttp(p) => {
+			if !ctx.workspace.allows_request(&p.path) {
+				warning!(
+					call_log,
+					"Denied request for a file outside the workspace root: {}",
+					p.path
+				);
+				success!(
+					ctx.socket_tx,
+					CallServerHttpResult {
+						status: 403,
+						headers: HashMap::new(),
+						body: vec![],
+					}
+				);
+				return;
+			}
+
 			let code_server = ctx.code_server.lock().await.clone();
 			dispatch_async!("callserverhttp", handle_call_server_http(code_server, p));
 		}
 		ServerRequestMethod::forward(p) => {
-			let log = ctx.log.clone();
+			let log = call_log.clone();
 			let port_forwarding = ctx.port_forwarding.clone();
 			dispatch_async!("forward", handle_forward(log, port_forwarding, p));
 		}
 		ServerRequestMethod::unforward(p) => {
-			let log = ctx.log.clone();
+			let log = call_log.clone();
 			let port_forwarding = ctx.port_forwarding.clone();
 			dispatch_async!("unforward", handle_unforward(log, port_forwarding, p));
 		}
+		ServerRequestMethod::forwardmany(p) => {
+			let log = call_log.clone();
+			let port_forwarding = ctx.port_forwarding.clone();
+			dispatch_async!("forwardmany", handle_forward_many(log, port_forwarding, p));
+		}
+		ServerRequestMethod::unforwardmany(p) => {
+			let log = call_log.clone();
+			let port_forwarding = ctx.port_forwarding.clone();
+			dispatch_async!(
+				"unforwardmany",
+				handle_unforward_many(log, port_forwarding, p)
+			);
+		}
 		ServerRequestMethod::httpheaders(p) => {
 			if let Some(req) = ctx.http_requests.lock().unwrap().get(&p.req_id) {
 				req.initial_response(p.status_code, p.headers);
@@ -608,9 +1379,45 @@ async fn dispatch_next(req: ToServerRequest, ctx: &mut HandlerContext, did_updat
 			}
 			success!(ctx.socket_tx, EmptyResult {});
 		}
+		ServerRequestMethod::hostpong(p) => {
+			ctx.quality.record_pong(p.seq);
+		}
+		ServerRequestMethod::sharesession(p) => {
+			let session_share = ctx.session_share.clone();
+			dispatch_async!("sharesession", handle_share_session(session_share, p));
+		}
+		ServerRequestMethod::clientinfo(p) => {
+			if p.identity.is_some() {
+				ctx.identity = p.identity.clone();
+			}
+			success!(ctx.socket_tx, EmptyResult {});
+		}
 	};
 }
 
+/// Refuses a client that failed the address checks, recording it in the audit
+/// log and closing the connection.
+async fn reject_client(ctx: &HandlerContext, address: Option<&str>, reason: &str) {
+	warning!(
+		ctx.log,
+		"Rejected connection from {}: {}",
+		address.unwrap_or("unknown address"),
+		reason
+	);
+
+	if let Err(e) = audit_rejected_connection(&ctx.launcher_paths, address, reason) {
+		warning!(ctx.log, "Failed to write to the audit log: {}", e);
+	}
+
+	ctx.socket_tx
+		.send(SocketSignal::CloseWith(CloseReason(format!(
+			"connection not allowed: {}",
+			reason
+		))))
+		.await
+		.ok();
+}
+
 #[derive(Clone)]
 struct ServerOutputSink {
 	tx: mpsc::Sender<SocketSignal>,
@@ -618,7 +1425,7 @@ struct ServerOutputSink {
 
 impl log::LogSink for ServerOutputSink {
 	fn write_log(&self, level: log::Level, _prefix: &str, message: &str) {
-		let s = SocketSignal::from_message(&ToClientRequest {
+		let s = SocketSignal::from_low_priority_message(&ToClientRequest {
 			id: None,
 			params: ClientRequestMethod::serverlog(ServerLog {
 				line: message,
@@ -632,10 +1439,53 @@ impl log::LogSink for ServerOutputSink {
 	fn write_result(&self, _message: &str) {}
 }
 
+/// Sends progress setting up the server to the client as notifications.
+struct ClientProgressReporter {
+	tx: mpsc::Sender<SocketSignal>,
+	stages: StageStack,
+}
+
+impl ClientProgressReporter {
+	fn send(&self, done: Option<u64>, total: Option<u64>, ended: bool) {
+		let s = SocketSignal::from_low_priority_message(&ToClientRequest {
+			id: None,
+			params: ClientRequestMethod::progress(ProgressParams {
+				stages: self.stages.path(),
+				done,
+				total,
+				ended,
+			}),
+		});
+
+		self.tx.try_send(s).ok();
+	}
+}
+
+impl ReportProgress for ClientProgressReporter {
+	fn report_progress(&mut self, done: u64, total: u64) {
+		self.send(Some(done), Some(total), false);
+	}
+
+	fn report_indeterminate(&mut self) {
+		self.send(None, None, false);
+	}
+
+	fn begin_stage(&mut self, stage: ProgressStage) {
+		self.stages.push(stage);
+		self.send(None, None, false);
+	}
+
+	fn end_stage(&mut self) {
+		self.send(None, None, true);
+		self.stages.pop();
+	}
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn handle_serve(
 	log: log::Logger,
 	http: FallbackSimpleHttp,
+	update_cache: Option<UpdateServiceCache>,
 	server_bridges: ServerBridgeListLock,
 	mut code_server_args: CodeServerArgs,
 	platform: Platform,
@@ -643,30 +1493,60 @@ async fn handle_serve(
 	socket_tx: mpsc::Sender<SocketSignal>,
 	launcher_paths: LauncherPaths,
 	params: ServeParams,
+	alternate: Option<AlternateServer>,
+	selection: ServerSelection,
 ) -> Result<EmptyResult, AnyError> {
 	// fill params.extensions into code_server_args.install_extensions
 	code_server_args
 		.install_extensions
 		.extend(params.extensions.into_iter());
 
-	let params_raw = ServerParamsRaw {
-		commit_id: params.commit_id,
-		quality: params.quality,
-		code_server_args,
-		headless: true,
-		platform,
+	// the alternate server is hosted side by side with the usual one, so
+	// it's used in place of the quality the client asked for. It's only
+	// routed to clients that didn't pin a commit.
+	let params_raw = match alternate {
+		Some(a) => {
+			info!(log, "Serving the alternate {} server to this client", a);
+			ServerParamsRaw {
+				commit_id: a.commit,
+				quality: a.quality,
+				code_server_args,
+				headless: true,
+				platform,
+				pinned_version: None,
+			}
+		}
+		None => selection.params(
+			&launcher_paths,
+			params.commit_id,
+			params.quality,
+			code_server_args,
+			true,
+			platform,
+		)?,
 	};
 
+	let mut progress = ClientProgressReporter {
+		tx: socket_tx.clone(),
+		stages: StageStack::default(),
+	};
+
+	progress.begin_stage(ProgressStage::Resolve);
+	progress.report_indeterminate();
 	let resolved = if params.use_local_download {
-		params_raw.resolve(&log, http.delegated()).await
+		params_raw
+			.resolve(&log, http.delegated(), update_cache)
+			.await
 	} else {
-		params_raw.resolve(&log, http.clone()).await
+		params_raw.resolve(&log, http.clone(), update_cache).await
 	}?;
+	progress.end_stage();
 
 	let mut server_ref = code_server.lock().await;
 	let server = match &*server_ref {
-		Some(o) => o.clone(),
-		None => {
+		// the server may have been stopped, such as by `tunnel restart-server`
+		Some(o) if o.origin.is_running() => o.clone(),
+		_ => {
 			let install_log = log.tee(ServerOutputSink {
 				tx: socket_tx.clone(),
 			});
@@ -677,8 +1557,12 @@ async fn handle_serve(
 						Some(AnyCodeServer::Socket(s)) => s,
 						Some(_) => return Err(AnyError::from(MismatchedLaunchModeError())),
 						None => {
-							$sb.setup().await?;
-							$sb.listen_on_default_socket().await?
+							$sb.setup_with_progress(&mut progress).await?;
+							progress.begin_stage(ProgressStage::Spawn);
+							progress.report_indeterminate();
+							let server = $sb.listen_on_default_socket().await?;
+							progress.end_stage();
+							server
 						}
 					}
 				};
@@ -730,8 +1614,20 @@ async fn attach_server_bridge(
 		)
 	};
 
-	let attached_fut =
-		ServerBridge::new(&code_server.socket, socket_id, server_messages, decoder).await;
+	let recorder = SessionRecorder::start(
+		"server",
+		RecordedData::Binary,
+		json!({ "socketId": socket_id, "commit": code_server.commit_id }),
+	)?;
+	let attached_fut = ServerBridge::new(
+		&code_server.socket,
+		socket_id,
+		server_messages,
+		decoder,
+		recorder,
+		log,
+	)
+	.await;
 
 	match attached_fut {
 		Ok(a) => {
@@ -771,6 +1667,47 @@ async fn handle_server_message(
 	Ok(EmptyResult {})
 }
 
+async fn handle_restart_server(
+	log: log::Logger,
+	paths: LauncherPaths,
+	code_server: CodeServerCell,
+	server_restarts: broadcast::Sender<ServerRestartedParams>,
+) -> Result<EmptyResult, AnyError> {
+	let commit = match &*code_server.lock().await {
+		Some(s) => s.commit_id.clone(),
+		None => return Err(AnyError::from(NoAttachedServerError())),
+	};
+
+	// announced first, so connections to the server forget it before it goes
+	// away rather than seeing it fail under them
+	server_restarts
+		.send(ServerRestartedParams {
+			commits: vec![commit.clone()],
+		})
+		.ok();
+
+	let stopped = stop_running_servers_where(&paths, |s| s.headless && s.commit == commit).await;
+	info!(
+		log,
+		"Stopped server {} for restart ({} process(es)), it'll start again when clients reconnect",
+		commit,
+		stopped.len()
+	);
+
+	Ok(EmptyResult {})
+}
+
+async fn handle_share_session(
+	session_share: Option<SessionShare>,
+	params: ShareSessionParams,
+) -> Result<ShareSessionResult, AnyError> {
+	match session_share {
+		Some(s) => s.share(&params.tunnel).await,
+		// clients through the relay must not get the host's login
+		None => Err(SessionShareNotAllowed(params.tunnel).into()),
+	}
+}
+
 async fn handle_prune(paths: &LauncherPaths) -> Result<Vec<String>, AnyError> {
 	prune_stopped_servers(paths).map(|v| {
 		v.iter()
@@ -779,9 +1716,43 @@ async fn handle_prune(paths: &LauncherPaths) -> Result<Vec<String>, AnyError> {
 	})
 }
 
+/// Applies any CLI update once a maintenance window starts, restarting the
+/// host if it updated.
+async fn apply_scheduled_update(
+	log: log::Logger,
+	launcher_paths: LauncherPaths,
+	update_cache: Option<UpdateServiceCache>,
+	server_tx: mpsc::Sender<ServerSignal>,
+) {
+	let params = UpdateParams {
+		do_update: true,
+		force: true,
+	};
+	let maintenance = MaintenanceWindows::default();
+	match handle_update(
+		&ReqwestSimpleHttp::new(),
+		&launcher_paths,
+		&update_cache,
+		&log,
+		&maintenance,
+		&params,
+	)
+	.await
+	{
+		Ok(r) if r.did_update => {
+			server_tx.send(ServerSignal::Respawn).await.ok();
+		}
+		Ok(_) => debug!(log, "No update to apply in the maintenance window"),
+		Err(e) => warning!(log, "Error updating in the maintenance window: {}", e),
+	}
+}
+
 async fn handle_update(
-	http: &FallbackSimpleHttp,
+	http: &(impl SimpleHttp + Clone + Send + Sync + 'static),
+	launcher_paths: &LauncherPaths,
+	update_cache: &Option<UpdateServiceCache>,
 	log: &log::Logger,
+	maintenance: &MaintenanceWindows,
 	params: &UpdateParams,
 ) -> Result<UpdateResult, AnyError> {
 	if let Ok(true) = is_integrated_cli() {
@@ -791,8 +1762,9 @@ async fn handle_update(
 		});
 	}
 
-	let update_service = UpdateService::new(log.clone(), http.clone());
-	let updater = SelfUpdate::new(&update_service)?;
+	let update_service =
+		UpdateService::new(log.clone(), http.clone()).with_cache(update_cache.clone());
+	let updater = SelfUpdate::new(&update_service, launcher_paths)?;
 	let latest_release = updater.get_current_release().await?;
 	let up_to_date = updater.is_up_to_date_with(&latest_release);
 
@@ -803,11 +1775,21 @@ async fn handle_update(
 		});
 	}
 
+	// updating restarts the host, so it waits for the next maintenance window
+	if !params.force && !maintenance.allows_now() {
+		info!(
+			log,
+			"Deferring update to {} until a maintenance window: {}", latest_release, maintenance
+		);
+		return Ok(UpdateResult {
+			up_to_date: false,
+			did_update: false,
+		});
+	}
+
 	info!(log, "Updating CLI to {}", latest_release);
 
-	updater
-		.do_update(&latest_release, SilentCopyProgress())
-		.await?;
+	updater.do_update(&latest_release, SilentProgress()).await?;
 
 	Ok(UpdateResult {
 		up_to_date: true,
@@ -826,8 +1808,30 @@ async fn handle_forward(
 	port_forwarding: PortForwarding,
 	params: ForwardParams,
 ) -> Result<ForwardResult, AnyError> {
-	info!(log, "Forwarding port {}", params.port);
-	let uri = port_forwarding.forward(params.port).await?;
+	let uri = if let Some(target) = params.target {
+		let target: ForwardTarget = target
+			.parse()
+			.map_err(|e: String| wrap(e, "invalid forward target"))?;
+		info!(log, "Forwarding port {} to {}", params.port, target);
+		port_forwarding
+			.forward_target(params.port, target, PortOptions::default())
+			.await?
+	} else if params.host.is_some() || params.path.is_some() {
+		info!(
+			log,
+			"Routing host {} and path {} to port {}",
+			params.host.as_deref().unwrap_or("*"),
+			params.path.as_deref().unwrap_or("/"),
+			params.port
+		);
+		port_forwarding
+			.forward_route(HostRoute::new(params.host, params.path, params.port))
+			.await?
+	} else {
+		info!(log, "Forwarding port {}", params.port);
+		port_forwarding.forward(params.port).await?
+	};
+	log.result(&format!("Port {} is available at {}", params.port, uri));
 	Ok(ForwardResult { uri })
 }
 
@@ -841,6 +1845,67 @@ async fn handle_unforward(
 	Ok(EmptyResult {})
 }
 
+async fn handle_forward_many(
+	log: log::Logger,
+	port_forwarding: PortForwarding,
+	params: ForwardManyParams,
+) -> Result<ForwardManyResult, AnyError> {
+	let mut ports = params.ports;
+	ports.sort_unstable();
+	ports.dedup();
+
+	info!(log, "Forwarding ports {:?}", ports);
+	let results = port_forwarding.forward_many(ports.clone()).await?;
+	let ports = ports
+		.into_iter()
+		.zip(results)
+		.map(|(port, r)| match r {
+			Ok(uri) => {
+				log.result(&format!("Port {} is available at {}", port, uri));
+				PortForwardResult {
+					port,
+					uri: Some(uri),
+					error: None,
+				}
+			}
+			Err(e) => {
+				warning!(log, "Error forwarding port {}: {}", port, e);
+				PortForwardResult {
+					port,
+					uri: None,
+					error: Some(e.to_string()),
+				}
+			}
+		})
+		.collect();
+
+	Ok(ForwardManyResult { ports })
+}
+
+async fn handle_unforward_many(
+	log: log::Logger,
+	port_forwarding: PortForwarding,
+	params: ForwardManyParams,
+) -> Result<ForwardManyResult, AnyError> {
+	let mut ports = params.ports;
+	ports.sort_unstable();
+	ports.dedup();
+
+	info!(log, "Unforwarding ports {:?}", ports);
+	let results = port_forwarding.unforward_many(ports.clone()).await?;
+	let ports = ports
+		.into_iter()
+		.zip(results)
+		.map(|(port, r)| PortForwardResult {
+			port,
+			uri: None,
+			error: r.err().map(|e| e.to_string()),
+		})
+		.collect();
+
+	Ok(ForwardManyResult { ports })
+}
+
 async fn handle_call_server_http(
 	code_server: Option<SocketCodeServer>,
 	params: CallServerHttpParams,
@@ -8,34 +8,50 @@ use crate::state::{LauncherPaths, PersistedState};
 use crate::util::errors::{
 	wrap, AnyError, DevTunnelError, InvalidTunnelName, TunnelCreationFailed, WrappedError,
 };
+use crate::util::http::shared_client;
 use crate::util::input::prompt_placeholder;
+use crate::util::power::watch_for_resume;
 use crate::{debug, info, log, spanf, trace, warning};
 use async_trait::async_trait;
-use futures::TryFutureExt;
+use chrono::{DateTime, Utc};
+use futures::{Future, StreamExt, TryFutureExt};
 use lazy_static::lazy_static;
 use rand::prelude::IteratorRandom;
 use regex::Regex;
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::{mpsc, watch};
 use tunnels::connections::{ForwardedPortConnection, RelayTunnelHost};
 use tunnels::contracts::{
-	Tunnel, TunnelPort, TunnelRelayTunnelEndpoint, PORT_TOKEN, TUNNEL_PROTOCOL_AUTO,
+	Tunnel, TunnelAccessControl, TunnelAccessControlEntry, TunnelAccessControlEntryType,
+	TunnelPort, TunnelRelayTunnelEndpoint, PORT_TOKEN, TUNNEL_PROTOCOL_AUTO,
 };
 use tunnels::management::{
 	new_tunnel_management, HttpError, TunnelLocator, TunnelManagementClient, TunnelRequestOptions,
 	NO_REQUEST_OPTIONS,
 };
 
+use super::machine_id::{check_machine_identity, get_machine_identity};
 use super::name_generator;
+use super::relay_breaker::{RelayCircuitBreaker, RelayRetryOptions};
+use super::relay_transport::connect_relay;
+#[cfg(feature = "test-relay")]
+use super::test_relay::TestRelayHost;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PersistedTunnel {
 	pub name: String,
 	pub id: String,
 	pub cluster: String,
+	/// Identity of the machine that registered the tunnel, if it was
+	/// registered by this CLI's launcher.
+	#[serde(default)]
+	pub machine_id: Option<String>,
 }
 
 impl PersistedTunnel {
@@ -130,21 +146,237 @@ impl AccessTokenProvider for LookupAccessTokenProvider {
 #[derive(Clone)]
 pub struct DevTunnels {
 	log: log::Logger,
+	paths: LauncherPaths,
 	launcher_tunnel: PersistedState<Option<PersistedTunnel>>,
 	client: TunnelManagementClient,
+	relay_retry: RelayRetryOptions,
+	tags: Option<Vec<TunnelTag>>,
+}
+
+/// Summary of a tunnel registered under the current account.
+pub struct TunnelSummary {
+	pub name: String,
+	pub id: String,
+	pub cluster: String,
+	/// ID of the host that last connected to the tunnel, if any.
+	pub host_id: Option<String>,
+	/// Last time a host connected to the tunnel.
+	pub last_seen: Option<DateTime<Utc>>,
+	/// Whether a host is currently connected to the tunnel.
+	pub online: bool,
+	/// Whether this is the tunnel hosted by the current machine.
+	pub is_current: bool,
+	/// Metadata tags on the tunnel, like `team=infra`.
+	pub tags: Vec<String>,
+}
+
+/// Public URI and access token for connecting to a port on a tunnel.
+pub struct TunnelPortAccess {
+	pub uri: String,
+	pub token: String,
+}
+
+/// What a client needs to connect to any port of a tunnel through the relay.
+pub struct TunnelClientAccess {
+	pub id: String,
+	pub cluster: String,
+	/// URI of the tunnel's ports, with `PORT_TOKEN` in place of the port.
+	pub port_uri_format: String,
+	/// Token the relay accepts from clients of the tunnel.
+	pub token: String,
+}
+
+impl TunnelSummary {
+	/// Gets whether the tunnel has been offline for longer than the given
+	/// duration. Tunnels a host has never connected to, like ones that were
+	/// just created, aren't stale.
+	pub fn is_stale(&self, offline_for: chrono::Duration) -> bool {
+		!self.online
+			&& !self.is_current
+			&& self
+				.last_seen
+				.map(|l| Utc::now() - l > offline_for)
+				.unwrap_or(false)
+	}
+}
+
+/// How a forwarded port is described to clients, and who can connect to it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PortOptions {
+	/// Name clients show for the port.
+	pub label: Option<String>,
+	/// Whether anyone with the port's link can connect to it, without
+	/// signing in.
+	pub public: bool,
+}
+
+/// Who can connect to a forwarded port.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PortVisibility {
+	/// Only the tunnel's owner, and those it's shared with.
+	Private,
+	/// Anyone with the port's link.
+	Public,
+}
+
+impl Default for PortVisibility {
+	fn default() -> Self {
+		PortVisibility::Private
+	}
+}
+
+/// A port on this machine forwarded when the tunnel starts, from the
+/// `ports` setting.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PortSetting {
+	pub port: u16,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub label: Option<String>,
+	#[serde(default)]
+	pub visibility: PortVisibility,
+}
+
+impl PortSetting {
+	pub fn options(&self) -> PortOptions {
+		PortOptions {
+			label: self.label.clone(),
+			public: self.visibility == PortVisibility::Public,
+		}
+	}
+}
+
+/// Describes the port to the relay.
+fn tunnel_port(port_number: u16, options: &PortOptions) -> TunnelPort {
+	let access_control = if options.public {
+		Some(TunnelAccessControl {
+			entries: vec![TunnelAccessControlEntry {
+				kind: TunnelAccessControlEntryType::Anonymous,
+				scopes: vec!["connect".to_string()],
+				..Default::default()
+			}],
+		})
+	} else {
+		None
+	};
+
+	TunnelPort {
+		port_number,
+		protocol: Some(TUNNEL_PROTOCOL_AUTO.to_owned()),
+		name: options.label.clone(),
+		access_control,
+		..Default::default()
+	}
 }
 
 /// Representation of a tunnel returned from the `start` methods.
 pub struct ActiveTunnel {
 	/// Name of the tunnel
 	pub name: String,
-	manager: ActiveTunnelManager,
+	manager: TunnelManager,
+}
+
+/// What the tunnel is hosted through.
+enum TunnelManager {
+	Relay(ActiveTunnelManager),
+	/// A local fake relay, for integration tests.
+	#[cfg(feature = "test-relay")]
+	Test(TestRelayHost),
+}
+
+impl TunnelManager {
+	async fn add_port_tcp(&self, port_number: u16) -> Result<(), AnyError> {
+		self.add_port_tcp_with_options(port_number, &PortOptions::default())
+			.await
+	}
+
+	async fn add_port_tcp_with_options(
+		&self,
+		port_number: u16,
+		options: &PortOptions,
+	) -> Result<(), AnyError> {
+		match self {
+			TunnelManager::Relay(m) => Ok(m.add_port_tcp(port_number, options).await?),
+			// the fake relay has no labels or access control
+			#[cfg(feature = "test-relay")]
+			TunnelManager::Test(h) => h.add_port_tcp(port_number).await,
+		}
+	}
+
+	async fn remove_port(&self, port_number: u16) -> Result<(), AnyError> {
+		match self {
+			TunnelManager::Relay(m) => Ok(m.remove_port(port_number).await?),
+			#[cfg(feature = "test-relay")]
+			TunnelManager::Test(h) => h.remove_port(port_number).await,
+		}
+	}
+}
+
+/// Connection made to a port forwarded through the tunnel.
+pub enum PortConnection {
+	Relay(ForwardedPortConnection),
+	#[cfg(feature = "test-relay")]
+	Test(tokio::net::TcpStream),
+}
+
+impl PortConnection {
+	/// Gets the address of the client as the relay observed it, if it's
+	/// known. The relay doesn't report it for connections to ports, only
+	/// on HTTP requests, so it's only known for the fake relay, where the
+	/// host sees the connection itself.
+	pub fn observed_address(&self) -> Option<IpAddr> {
+		match self {
+			PortConnection::Relay(_) => None,
+			#[cfg(feature = "test-relay")]
+			PortConnection::Test(s) => s.peer_addr().ok().map(|a| a.ip()),
+		}
+	}
+
+	/// Splits the connection into its write and read halves.
+	pub fn into_split(
+		self,
+	) -> (
+		Box<dyn AsyncWrite + Send + Unpin>,
+		Box<dyn AsyncRead + Send + Unpin>,
+	) {
+		match self {
+			PortConnection::Relay(c) => {
+				let (w, r) = c.into_split();
+				(Box::new(w), Box::new(r))
+			}
+			#[cfg(feature = "test-relay")]
+			PortConnection::Test(s) => {
+				let (r, w) = s.into_split();
+				(Box::new(w), Box::new(r))
+			}
+		}
+	}
 }
 
 impl ActiveTunnel {
+	/// Hosts a tunnel through the fake relay listening at the address, which
+	/// needs no credentials or network. Used for integration tests.
+	#[cfg(feature = "test-relay")]
+	pub async fn start_test_tunnel(
+		log: &log::Logger,
+		name: String,
+		relay_addr: &str,
+	) -> Result<ActiveTunnel, AnyError> {
+		let host = TestRelayHost::connect(log.clone(), relay_addr.to_string()).await?;
+		Ok(ActiveTunnel {
+			name,
+			manager: TunnelManager::Test(host),
+		})
+	}
+
 	/// Closes and unregisters the tunnel.
 	pub async fn close(&mut self) -> Result<(), AnyError> {
-		self.manager.kill().await?;
+		match &mut self.manager {
+			TunnelManager::Relay(m) => m.kill().await?,
+			#[cfg(feature = "test-relay")]
+			TunnelManager::Test(h) => h.close().await,
+		}
 		Ok(())
 	}
 
@@ -152,26 +384,106 @@ impl ActiveTunnel {
 	pub async fn add_port_direct(
 		&mut self,
 		port_number: u16,
-	) -> Result<mpsc::UnboundedReceiver<ForwardedPortConnection>, AnyError> {
-		let port = self.manager.add_port_direct(port_number).await?;
-		Ok(port)
+	) -> Result<mpsc::UnboundedReceiver<PortConnection>, AnyError> {
+		self.add_port_direct_with_options(port_number, &PortOptions::default())
+			.await
+	}
+
+	/// Like `add_port_direct`, with a label and access other than the
+	/// tunnel's.
+	pub async fn add_port_direct_with_options(
+		&mut self,
+		port_number: u16,
+		options: &PortOptions,
+	) -> Result<mpsc::UnboundedReceiver<PortConnection>, AnyError> {
+		match &self.manager {
+			TunnelManager::Relay(m) => {
+				let mut port = m.add_port_direct(port_number, options).await?;
+				let (tx, rx) = mpsc::unbounded_channel();
+				tokio::spawn(async move {
+					while let Some(c) = port.recv().await {
+						if tx.send(PortConnection::Relay(c)).is_err() {
+							break;
+						}
+					}
+				});
+				Ok(rx)
+			}
+			// the fake relay has no labels or access control
+			#[cfg(feature = "test-relay")]
+			TunnelManager::Test(h) => h.add_port_direct(port_number).await,
+		}
 	}
 
 	/// Forwards a port over TCP.
 	pub async fn add_port_tcp(&mut self, port_number: u16) -> Result<(), AnyError> {
-		self.manager.add_port_tcp(port_number).await?;
-		Ok(())
+		self.manager.add_port_tcp(port_number).await
+	}
+
+	/// Forwards a port over TCP with a label and access other than the
+	/// tunnel's.
+	pub async fn add_port_tcp_with_options(
+		&mut self,
+		port_number: u16,
+		options: &PortOptions,
+	) -> Result<(), AnyError> {
+		self.manager
+			.add_port_tcp_with_options(port_number, options)
+			.await
 	}
 
 	/// Removes a forwarded port TCP.
 	pub async fn remove_port(&mut self, port_number: u16) -> Result<(), AnyError> {
-		self.manager.remove_port(port_number).await?;
-		Ok(())
+		self.manager.remove_port(port_number).await
+	}
+
+	/// Forwards many ports over TCP, a few at a time. Returns the result for
+	/// each port, in the order they were given.
+	pub async fn add_ports_tcp(&mut self, port_numbers: &[u16]) -> Vec<Result<(), AnyError>> {
+		let manager = &self.manager;
+		futures::stream::iter(
+			port_numbers
+				.iter()
+				.map(|p| async move { manager.add_port_tcp(*p).await }),
+		)
+		.buffered(MAX_CONCURRENT_TUNNEL_CALLS)
+		.collect()
+		.await
+	}
+
+	/// Removes many forwarded TCP ports, a few at a time. Returns the result
+	/// for each port, in the order they were given.
+	pub async fn remove_ports(&mut self, port_numbers: &[u16]) -> Vec<Result<(), AnyError>> {
+		let manager = &self.manager;
+		futures::stream::iter(
+			port_numbers
+				.iter()
+				.map(|p| async move { manager.remove_port(*p).await }),
+		)
+		.buffered(MAX_CONCURRENT_TUNNEL_CALLS)
+		.collect()
+		.await
+	}
+
+	/// Gets the number of times the tunnel has had to reconnect to the relay
+	/// since it was started.
+	pub fn reconnect_count(&self) -> u32 {
+		let connect_count = match &self.manager {
+			TunnelManager::Relay(m) => m.connect_count.as_ref(),
+			#[cfg(feature = "test-relay")]
+			TunnelManager::Test(h) => h.connect_count(),
+		};
+
+		connect_count.load(Ordering::Relaxed).saturating_sub(1)
 	}
 
 	/// Gets the public URI on which a forwarded port can be access in browser.
 	pub async fn get_port_uri(&mut self, port: u16) -> Result<String, AnyError> {
-		let endpoint = self.manager.get_endpoint().await?;
+		let endpoint = match &mut self.manager {
+			TunnelManager::Relay(m) => m.get_endpoint().await?,
+			#[cfg(feature = "test-relay")]
+			TunnelManager::Test(h) => return h.get_port_uri(port).await,
+		};
 		let format = endpoint
 			.base
 			.port_uri_format
@@ -182,8 +494,29 @@ impl ActiveTunnel {
 }
 
 const VSCODE_CLI_TUNNEL_TAG: &str = "vscode-server-launcher";
+/// Maximum number of tunnel management or port calls made at once, for bulk
+/// operations such as forwarding many ports.
+const MAX_CONCURRENT_TUNNEL_CALLS: usize = 6;
+/// Tag for temporary tunnels from `--anonymous`. They're tagged separately so
+/// they aren't counted or listed as machines.
+const VSCODE_CLI_ANONYMOUS_TUNNEL_TAG: &str = "vscode-cli-anonymous";
 const MAX_TUNNEL_NAME_LENGTH: usize = 20;
 
+/// Runs the futures, at most `MAX_CONCURRENT_TUNNEL_CALLS` at a time, and
+/// returns the first error if any failed.
+async fn run_concurrently<T, E>(
+	calls: impl Iterator<Item = impl Future<Output = Result<T, E>>>,
+) -> Result<(), E> {
+	let results: Vec<_> = futures::stream::iter(calls)
+		.buffer_unordered(MAX_CONCURRENT_TUNNEL_CALLS)
+		.collect()
+		.await;
+	results
+		.into_iter()
+		.find_map(|r| r.err())
+		.map_or(Ok(()), Err)
+}
+
 fn get_host_token_from_tunnel(tunnel: &Tunnel) -> String {
 	tunnel
 		.access_tokens
@@ -213,6 +546,59 @@ fn is_valid_name(name: &str) -> Result<(), InvalidTunnelName> {
 	Ok(())
 }
 
+/// Longest tag the tunnel service accepts.
+const MAX_TAG_LENGTH: usize = 50;
+
+/// Metadata tag on a tunnel, like `team=infra`, for organizing many tunnels.
+/// It's stored as a `key=value` tag on the tunnel registration, alongside the
+/// tag holding the tunnel's name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TunnelTag {
+	pub key: String,
+	pub value: String,
+}
+
+impl std::str::FromStr for TunnelTag {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (key, value) = s
+			.split_once('=')
+			.ok_or_else(|| format!("expected a tag like 'team=infra', got '{}'", s))?;
+
+		let re = Regex::new(r"^[\w-]+$").unwrap();
+		if !re.is_match(key) || !re.is_match(value) {
+			return Err(format!(
+				"tag keys and values can only contain letters, numbers, '_', and '-', got '{}'",
+				s
+			));
+		}
+		if s.len() > MAX_TAG_LENGTH {
+			return Err(format!(
+				"tags cannot be longer than {} characters",
+				MAX_TAG_LENGTH
+			));
+		}
+
+		Ok(TunnelTag {
+			key: key.to_string(),
+			value: value.to_string(),
+		})
+	}
+}
+
+impl std::fmt::Display for TunnelTag {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}={}", self.key, self.value)
+	}
+}
+
+/// Gets whether the tag on a tunnel is a metadata tag, rather than its name
+/// or a tag the CLI uses to find tunnels. Names can't contain '='.
+fn is_metadata_tag(tag: &str) -> bool {
+	tag.contains('=')
+}
+
 lazy_static! {
 	static ref HOST_TUNNEL_REQUEST_OPTIONS: TunnelRequestOptions = TunnelRequestOptions {
 		include_ports: true,
@@ -237,18 +623,96 @@ pub struct ExistingTunnel {
 	pub cluster: String,
 }
 
+fn launcher_tunnel_state(paths: &LauncherPaths) -> PersistedState<Option<PersistedTunnel>> {
+	PersistedState::new(paths.root().join("code_tunnel.json"))
+}
+
+/// Gets the tunnel this machine hosts, if it's registered one, without
+/// needing a DevTunnels client.
+pub fn load_launcher_tunnel(paths: &LauncherPaths) -> Option<PersistedTunnel> {
+	launcher_tunnel_state(paths).load()
+}
+
 impl DevTunnels {
 	pub fn new(log: &log::Logger, auth: auth::Auth, paths: &LauncherPaths) -> DevTunnels {
+		// use the CLI's client so management requests go through its proxy
 		let mut client = new_tunnel_management(&TUNNEL_SERVICE_USER_AGENT);
 		client.authorization_provider(auth);
+		client.client(shared_client());
 
 		DevTunnels {
 			log: log.clone(),
+			paths: paths.clone(),
 			client: client.into(),
-			launcher_tunnel: PersistedState::new(paths.root().join("code_tunnel.json")),
+			launcher_tunnel: launcher_tunnel_state(paths),
+			relay_retry: RelayRetryOptions::default(),
+			tags: None,
+		}
+	}
+
+	/// Sets the metadata tags the launcher tunnel is registered with,
+	/// replacing any it has. Without this, existing tags are kept.
+	pub fn with_tags(self, tags: Vec<TunnelTag>) -> DevTunnels {
+		DevTunnels {
+			tags: if tags.is_empty() { None } else { Some(tags) },
+			..self
 		}
 	}
 
+	/// Gets the tags to register the launcher tunnel with: its name, the
+	/// launcher tag, and metadata tags.
+	fn launcher_tags(&self, name: &str, existing: &[String]) -> Vec<String> {
+		let mut tags = vec![name.to_string(), VSCODE_CLI_TUNNEL_TAG.to_string()];
+		match &self.tags {
+			Some(t) => tags.extend(t.iter().map(|t| t.to_string())),
+			None => tags.extend(existing.iter().filter(|t| is_metadata_tag(t)).cloned()),
+		}
+		tags
+	}
+
+	/// Sets how reconnects to the relay are retried for tunnels started later.
+	pub fn with_relay_retry(self, relay_retry: RelayRetryOptions) -> DevTunnels {
+		DevTunnels {
+			relay_retry,
+			..self
+		}
+	}
+
+	/// Gets the name of the tunnel this machine hosts, if it's hosted one.
+	pub fn current_tunnel_name(&self) -> Option<String> {
+		self.launcher_tunnel.load().map(|t| t.name)
+	}
+
+	/// Gets the tunnel this machine hosts, if it's registered one.
+	pub fn current_tunnel(&self) -> Option<PersistedTunnel> {
+		self.launcher_tunnel.load()
+	}
+
+	/// Records that the current tunnel belongs to this machine's identity.
+	pub fn claim_current_tunnel(&mut self) -> Result<(), AnyError> {
+		if let Some(mut persisted) = self.launcher_tunnel.load() {
+			persisted.machine_id = Some(get_machine_identity(&self.paths)?.id);
+			self.launcher_tunnel.save(Some(persisted))?;
+		}
+		Ok(())
+	}
+
+	/// Registers a new tunnel for this machine in place of its current one,
+	/// which is left as-is for any other machine using it. Without a name, or
+	/// if the name's taken, a random one is used.
+	pub async fn reregister_tunnel(
+		&mut self,
+		name: Option<String>,
+	) -> Result<PersistedTunnel, AnyError> {
+		self.launcher_tunnel.save(None)?;
+
+		let use_random_name = name.is_none();
+		let name = self.get_name_for_tunnel(name, use_random_name).await?;
+		let (persisted, _) = self.create_tunnel(&name, NO_REQUEST_OPTIONS).await?;
+		self.launcher_tunnel.save(Some(persisted.clone()))?;
+		Ok(persisted)
+	}
+
 	pub async fn remove_tunnel(&mut self) -> Result<(), AnyError> {
 		let tunnel = match self.launcher_tunnel.load() {
 			Some(t) => t,
@@ -269,6 +733,137 @@ impl DevTunnels {
 		Ok(())
 	}
 
+	/// Lists tunnels created by the CLI under the current account, including
+	/// ones hosted by other machines.
+	/// Only tunnels with all of the given tags are listed.
+	pub async fn list_tunnels(
+		&mut self,
+		with_tags: &[TunnelTag],
+	) -> Result<Vec<TunnelSummary>, AnyError> {
+		let current = self.launcher_tunnel.load();
+		let with_tags: Vec<String> = with_tags.iter().map(|t| t.to_string()).collect();
+		let tunnels = self.list_all_server_tunnels(&with_tags).await?;
+
+		Ok(tunnels
+			.into_iter()
+			.filter_map(|t| {
+				let id = t.tunnel_id.clone()?;
+				let cluster = t.cluster_id.clone()?;
+				let status = t.status.as_ref();
+				Some(TunnelSummary {
+					name: t
+						.tags
+						.iter()
+						.find(|tag| *tag != VSCODE_CLI_TUNNEL_TAG && !is_metadata_tag(tag))
+						.cloned()
+						.unwrap_or_else(|| id.clone()),
+					tags: t
+						.tags
+						.iter()
+						.filter(|t| is_metadata_tag(t))
+						.cloned()
+						.collect(),
+					host_id: t.endpoints.first().map(|e| e.host_id.clone()),
+					last_seen: status.and_then(|s| s.last_host_connection_time),
+					online: status
+						.and_then(|s| s.host_connection_count.as_ref())
+						.map(|c| c.get_count())
+						.unwrap_or(0) > 0,
+					is_current: current
+						.as_ref()
+						.map(|c| c.id == id && c.cluster == cluster)
+						.unwrap_or(false),
+					id,
+					cluster,
+				})
+			})
+			.collect())
+	}
+
+	/// Deletes a tunnel registered under the current account.
+	pub async fn delete_tunnel(&mut self, tunnel: &TunnelSummary) -> Result<(), AnyError> {
+		spanf!(
+			self.log,
+			self.log.span("dev-tunnel.delete"),
+			self.client.delete_tunnel(
+				&TunnelLocator::ID {
+					cluster: tunnel.cluster.clone(),
+					id: tunnel.id.clone(),
+				},
+				NO_REQUEST_OPTIONS
+			)
+		)
+		.map_err(|e| wrap(e, "failed to execute `tunnel delete`"))?;
+
+		if tunnel.is_current {
+			self.launcher_tunnel.save(None)?;
+		}
+
+		Ok(())
+	}
+
+	/// Gets the public URI of a port on one of the current account's tunnels,
+	/// and a token that allows connecting to it.
+	pub async fn get_port_access(
+		&mut self,
+		name: &str,
+		port: u16,
+	) -> Result<TunnelPortAccess, AnyError> {
+		let access = self.get_client_access(name).await?;
+		Ok(TunnelPortAccess {
+			uri: access
+				.port_uri_format
+				.replace(PORT_TOKEN, &port.to_string()),
+			token: access.token,
+		})
+	}
+
+	/// Gets what a client needs to connect to one of the current account's
+	/// tunnels, which must be online.
+	pub async fn get_client_access(&mut self, name: &str) -> Result<TunnelClientAccess, AnyError> {
+		let summary = self
+			.list_tunnels(&[])
+			.await?
+			.into_iter()
+			.find(|t| t.name == name)
+			.ok_or_else(|| wrap("", format!("no tunnel named '{}' was found", name)))?;
+
+		let tunnel = spanf!(
+			self.log,
+			self.log.span("dev-tunnel.client-access"),
+			self.client.get_tunnel(
+				&TunnelLocator::ID {
+					cluster: summary.cluster.clone(),
+					id: summary.id.clone(),
+				},
+				&TunnelRequestOptions {
+					token_scopes: vec!["connect".to_string()],
+					..Default::default()
+				}
+			)
+		)
+		.map_err(|e| wrap(e, "failed to lookup tunnel"))?;
+
+		let port_uri_format = tunnel
+			.endpoints
+			.iter()
+			.find_map(|e| e.port_uri_format.clone())
+			.ok_or_else(|| wrap("", format!("tunnel '{}' is not online", name)))?;
+		let token = tunnel
+			.access_tokens
+			.as_ref()
+			.and_then(|t| t.get("connect"))
+			.cloned()
+			.ok_or_else(|| wrap("", "no connect token was issued for the tunnel"))?;
+
+		Ok(TunnelClientAccess {
+			id: summary.id,
+			cluster: summary.cluster,
+			port_uri_format,
+			token,
+		})
+	}
+
 	/// Renames the current tunnel to the new name.
 	pub async fn rename_tunnel(&mut self, name: &str) -> Result<(), AnyError> {
 		is_valid_name(name)?;
@@ -294,7 +889,7 @@ impl DevTunnels {
 		)
 		.map_err(|e| wrap(e, "failed to lookup original tunnel"))?;
 
-		full_tunnel.tags = vec![name.to_string(), VSCODE_CLI_TUNNEL_TAG.to_string()];
+		full_tunnel.tags = self.launcher_tags(name, &full_tunnel.tags);
 		spanf!(
 			self.log,
 			self.log.span("dev-tunnel.tag.update"),
@@ -325,7 +920,7 @@ impl DevTunnels {
 			return Ok((full_tunnel, persisted));
 		}
 
-		full_tunnel.tags = vec![name.to_string(), VSCODE_CLI_TUNNEL_TAG.to_string()];
+		full_tunnel.tags = self.launcher_tags(name, &full_tunnel.tags);
 
 		let new_tunnel = spanf!(
 			self.log,
@@ -380,6 +975,10 @@ impl DevTunnels {
 	) -> Result<ActiveTunnel, AnyError> {
 		let (tunnel, persisted) = match self.launcher_tunnel.load() {
 			Some(mut persisted) => {
+				// hosting a tunnel cloned from another machine would take it
+				// over from that machine, so have the user split them first
+				check_machine_identity(&self.paths, &persisted)?;
+
 				if let Some(name) = preferred_name {
 					if persisted.name.ne(&name) {
 						(_, persisted) = self.update_tunnel_name(persisted, &name).await?;
@@ -389,6 +988,7 @@ impl DevTunnels {
 				let (tunnel, persisted, _) = self
 					.get_or_create_tunnel(persisted, None, &HOST_TUNNEL_REQUEST_OPTIONS)
 					.await?;
+				self.apply_tags(&tunnel, &persisted.name).await;
 				(tunnel, persisted)
 			}
 			None => {
@@ -407,36 +1007,39 @@ impl DevTunnels {
 		let locator = TunnelLocator::try_from(&tunnel).unwrap();
 		let host_token = get_host_token_from_tunnel(&tunnel);
 
-		for port_to_delete in tunnel
+		let port_deletions = tunnel
 			.ports
 			.iter()
 			.filter(|p| p.port_number != CONTROL_PORT)
-		{
-			let output_fut = self.client.delete_tunnel_port(
-				&locator,
-				port_to_delete.port_number,
-				NO_REQUEST_OPTIONS,
-			);
-			spanf!(
-				self.log,
-				self.log.span("dev-tunnel.port.delete"),
-				output_fut
-			)
-			.map_err(|e| wrap(e, "failed to delete port"))?;
-		}
+			.map(|port_to_delete| {
+				let log = self.log.clone();
+				let output_fut = self.client.delete_tunnel_port(
+					&locator,
+					port_to_delete.port_number,
+					NO_REQUEST_OPTIONS,
+				);
+				async move {
+					spanf!(log, log.span("dev-tunnel.port.delete"), output_fut)
+						.map_err(|e| wrap(e, "failed to delete port"))
+				}
+			});
+		run_concurrently(port_deletions).await?;
 
 		// cleanup any old trailing tunnel endpoints
-		for endpoint in tunnel.endpoints {
+		let endpoint_prunes = tunnel.endpoints.iter().map(|endpoint| {
+			let log = self.log.clone();
 			let fut = self.client.delete_tunnel_endpoints(
 				&locator,
 				&endpoint.host_id,
 				None,
 				NO_REQUEST_OPTIONS,
 			);
-
-			spanf!(self.log, self.log.span("dev-tunnel.endpoint.prune"), fut)
-				.map_err(|e| wrap(e, "failed to prune tunnel endpoint"))?;
-		}
+			async move {
+				spanf!(log, log.span("dev-tunnel.endpoint.prune"), fut)
+					.map_err(|e| wrap(e, "failed to prune tunnel endpoint"))
+			}
+		});
+		run_concurrently(endpoint_prunes).await?;
 
 		self.start_tunnel(
 			locator.clone(),
@@ -462,7 +1065,7 @@ impl DevTunnels {
 		let mut tried_recycle = false;
 
 		let new_tunnel = Tunnel {
-			tags: vec![name.to_string(), VSCODE_CLI_TUNNEL_TAG.to_string()],
+			tags: self.launcher_tags(name, &[]),
 			..Default::default()
 		};
 
@@ -499,6 +1102,7 @@ impl DevTunnels {
 							cluster: t.cluster_id.clone().unwrap(),
 							id: t.tunnel_id.clone().unwrap(),
 							name: name.to_string(),
+							machine_id: Some(get_machine_identity(&self.paths)?.id),
 						},
 						t,
 					))
@@ -515,7 +1119,7 @@ impl DevTunnels {
 			"Tunnel limit hit, trying to recycle an old tunnel"
 		);
 
-		let existing_tunnels = self.list_all_server_tunnels().await?;
+		let existing_tunnels = self.list_all_server_tunnels(&[]).await?;
 
 		let recyclable = existing_tunnels
 			.iter()
@@ -547,12 +1151,37 @@ impl DevTunnels {
 		}
 	}
 
-	async fn list_all_server_tunnels(&mut self) -> Result<Vec<Tunnel>, AnyError> {
+	/// Updates the tunnel's tags if they differ from the ones it should have.
+	/// Failing to is only a warning, since the tunnel can still be hosted.
+	async fn apply_tags(&mut self, tunnel: &Tunnel, name: &str) {
+		let tags = self.launcher_tags(name, &tunnel.tags);
+		if tunnel.tags == tags {
+			return;
+		}
+
+		let mut updated = tunnel.clone();
+		updated.tags = tags;
+		let result = spanf!(
+			self.log,
+			self.log.span("dev-tunnel.tag.update"),
+			self.client.update_tunnel(&updated, NO_REQUEST_OPTIONS)
+		);
+		if let Err(e) = result {
+			warning!(self.log, "Failed to update tunnel tags: {}", e);
+		}
+	}
+
+	async fn list_all_server_tunnels(
+		&mut self,
+		with_tags: &[String],
+	) -> Result<Vec<Tunnel>, AnyError> {
+		let mut tags = vec![VSCODE_CLI_TUNNEL_TAG.to_string()];
+		tags.extend(with_tags.iter().cloned());
 		let tunnels = spanf!(
 			self.log,
 			self.log.span("dev-tunnel.listall"),
 			self.client.list_all_tunnels(&TunnelRequestOptions {
-				tags: vec![VSCODE_CLI_TUNNEL_TAG.to_string()],
+				tags,
 				require_all_tags: true,
 				..Default::default()
 			})
@@ -587,7 +1216,7 @@ impl DevTunnels {
 		preferred_name: Option<String>,
 		mut use_random_name: bool,
 	) -> Result<String, AnyError> {
-		let existing_tunnels = self.list_all_server_tunnels().await?;
+		let existing_tunnels = self.list_all_server_tunnels(&[]).await?;
 		let is_name_free = |n: &str| {
 			!existing_tunnels
 				.iter()
@@ -637,6 +1266,84 @@ impl DevTunnels {
 		}
 	}
 
+	/// Creates and starts a temporary tunnel that anyone with its link can
+	/// connect to, without signing in. It isn't persisted as the machine's
+	/// tunnel, and should be deleted with `delete_temporary_tunnel` once it's
+	/// no longer needed.
+	pub async fn start_anonymous_tunnel(
+		&mut self,
+	) -> Result<(ActiveTunnel, PersistedTunnel), AnyError> {
+		let name = name_generator::generate_name(MAX_TUNNEL_NAME_LENGTH);
+		info!(self.log, "Creating temporary tunnel {}", name);
+
+		let new_tunnel = Tunnel {
+			tags: vec![name.clone(), VSCODE_CLI_ANONYMOUS_TUNNEL_TAG.to_string()],
+			access_control: Some(TunnelAccessControl {
+				entries: vec![TunnelAccessControlEntry {
+					kind: TunnelAccessControlEntryType::Anonymous,
+					scopes: vec!["connect".to_string()],
+					..Default::default()
+				}],
+			}),
+			..Default::default()
+		};
+
+		let tunnel = spanf!(
+			self.log,
+			self.log.span("dev-tunnel.create"),
+			self.client
+				.create_tunnel(&new_tunnel, &HOST_TUNNEL_REQUEST_OPTIONS)
+		)
+		.map_err(|e| TunnelCreationFailed(name.clone(), format!("{:?}", e)))?;
+
+		let persisted = PersistedTunnel {
+			cluster: tunnel.cluster_id.clone().unwrap(),
+			id: tunnel.tunnel_id.clone().unwrap(),
+			name,
+			machine_id: None,
+		};
+		let locator = TunnelLocator::try_from(&tunnel).unwrap();
+		let host_token = get_host_token_from_tunnel(&tunnel);
+
+		let active = self
+			.start_tunnel(
+				locator.clone(),
+				&persisted,
+				self.client.clone(),
+				LookupAccessTokenProvider::new(
+					self.client.clone(),
+					locator,
+					self.log.clone(),
+					Some(host_token),
+				),
+			)
+			.await;
+
+		match active {
+			Ok(active) => Ok((active, persisted)),
+			Err(e) => {
+				self.delete_temporary_tunnel(&persisted).await.ok();
+				Err(e)
+			}
+		}
+	}
+
+	/// Deletes a tunnel created by `start_anonymous_tunnel`.
+	pub async fn delete_temporary_tunnel(
+		&mut self,
+		tunnel: &PersistedTunnel,
+	) -> Result<(), AnyError> {
+		spanf!(
+			self.log,
+			self.log.span("dev-tunnel.delete"),
+			self.client
+				.delete_tunnel(&tunnel.locator(), NO_REQUEST_OPTIONS)
+		)
+		.map_err(|e| wrap(e, "failed to execute `tunnel delete`"))?;
+
+		Ok(())
+	}
+
 	/// Hosts an existing tunnel, where the tunnel ID and host token are given.
 	pub async fn start_existing_tunnel(
 		&mut self,
@@ -646,6 +1353,7 @@ impl DevTunnels {
 			name: tunnel.tunnel_name,
 			id: tunnel.tunnel_id,
 			cluster: tunnel.cluster,
+			machine_id: None,
 		};
 
 		let mut mgmt = self.client.build();
@@ -669,7 +1377,9 @@ impl DevTunnels {
 		client: TunnelManagementClient,
 		access_token: impl AccessTokenProvider + 'static,
 	) -> Result<ActiveTunnel, AnyError> {
-		let mut manager = ActiveTunnelManager::new(self.log.clone(), client, locator, access_token);
+		let breaker = RelayCircuitBreaker::new(self.log.clone(), &self.paths, self.relay_retry);
+		let mut manager =
+			ActiveTunnelManager::new(self.log.clone(), client, locator, access_token, breaker);
 
 		let endpoint_result = spanf!(
 			self.log,
@@ -690,15 +1400,22 @@ impl DevTunnels {
 
 		Ok(ActiveTunnel {
 			name: tunnel_details.name.clone(),
-			manager,
+			manager: TunnelManager::Relay(manager),
 		})
 	}
 }
 
+/// After the system resumes, reconnection is retried at this interval rather
+/// than with backoff, since the network usually takes a few seconds to return.
+const FAST_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+const FAST_RECONNECT_ATTEMPTS: u32 = 15;
+
 struct ActiveTunnelManager {
 	close_tx: Option<mpsc::Sender<()>>,
 	endpoint_rx: watch::Receiver<Option<Result<TunnelRelayTunnelEndpoint, WrappedError>>>,
 	relay: Arc<tokio::sync::Mutex<RelayTunnelHost>>,
+	/// Number of times the relay connection has been established.
+	connect_count: Arc<AtomicU32>,
 }
 
 impl ActiveTunnelManager {
@@ -707,12 +1424,15 @@ impl ActiveTunnelManager {
 		mgmt: TunnelManagementClient,
 		locator: TunnelLocator,
 		access_token: impl AccessTokenProvider + 'static,
+		breaker: RelayCircuitBreaker,
 	) -> ActiveTunnelManager {
 		let (endpoint_tx, endpoint_rx) = watch::channel(None);
 		let (close_tx, close_rx) = mpsc::channel(1);
 
 		let relay = Arc::new(tokio::sync::Mutex::new(RelayTunnelHost::new(locator, mgmt)));
 		let relay_spawned = relay.clone();
+		let connect_count = Arc::new(AtomicU32::new(0));
+		let connect_count_spawned = connect_count.clone();
 
 		tokio::spawn(async move {
 			ActiveTunnelManager::spawn_tunnel(
@@ -720,7 +1440,9 @@ impl ActiveTunnelManager {
 				relay_spawned,
 				close_rx,
 				endpoint_tx,
+				connect_count_spawned,
 				access_token,
+				breaker,
 			)
 			.await;
 		});
@@ -729,20 +1451,20 @@ impl ActiveTunnelManager {
 			endpoint_rx,
 			relay,
 			close_tx: Some(close_tx),
+			connect_count,
 		}
 	}
 
 	/// Adds a port for TCP/IP forwarding.
-	#[allow(dead_code)] // todo: port forwarding
-	pub async fn add_port_tcp(&self, port_number: u16) -> Result<(), WrappedError> {
+	pub async fn add_port_tcp(
+		&self,
+		port_number: u16,
+		options: &PortOptions,
+	) -> Result<(), WrappedError> {
 		self.relay
 			.lock()
 			.await
-			.add_port(&TunnelPort {
-				port_number,
-				protocol: Some(TUNNEL_PROTOCOL_AUTO.to_owned()),
-				..Default::default()
-			})
+			.add_port(&tunnel_port(port_number, options))
 			.await
 			.map_err(|e| wrap(e, "error adding port to relay"))?;
 		Ok(())
@@ -752,15 +1474,12 @@ impl ActiveTunnelManager {
 	pub async fn add_port_direct(
 		&self,
 		port_number: u16,
+		options: &PortOptions,
 	) -> Result<mpsc::UnboundedReceiver<ForwardedPortConnection>, WrappedError> {
 		self.relay
 			.lock()
 			.await
-			.add_port_raw(&TunnelPort {
-				port_number,
-				protocol: Some(TUNNEL_PROTOCOL_AUTO.to_owned()),
-				..Default::default()
-			})
+			.add_port_raw(&tunnel_port(port_number, options))
 			.await
 			.map_err(|e| wrap(e, "error adding port to relay"))
 	}
@@ -813,15 +1532,38 @@ impl ActiveTunnelManager {
 		relay: Arc<tokio::sync::Mutex<RelayTunnelHost>>,
 		mut close_rx: mpsc::Receiver<()>,
 		endpoint_tx: watch::Sender<Option<Result<TunnelRelayTunnelEndpoint, WrappedError>>>,
+		connect_count: Arc<AtomicU32>,
 		access_token_provider: impl AccessTokenProvider + 'static,
+		mut breaker: RelayCircuitBreaker,
 	) {
-		let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(120));
+		let mut resume_rx = watch_for_resume(log.clone());
+		// attempts left to reconnect quickly, set after the system resumes
+		let mut fast_attempts = 0;
+
+		// waits before the next attempt, cut short if the system resumes
+		macro_rules! wait_to_retry {
+			() => {
+				if fast_attempts > 0 {
+					fast_attempts -= 1;
+					tokio::time::sleep(FAST_RECONNECT_INTERVAL).await;
+				} else {
+					tokio::select! {
+						_ = breaker.delay() => {},
+						Some(_) = resume_rx.recv() => {
+							info!(log, "System resumed, retrying tunnel connection");
+							breaker.reset_delay();
+							fast_attempts = FAST_RECONNECT_ATTEMPTS;
+						},
+					}
+				}
+			};
+		}
 
 		macro_rules! fail {
 			($e: expr, $msg: expr) => {
-				warning!(log, "{}: {}", $msg, $e);
+				breaker.record_failure(&format!("{}: {}", $msg, $e));
 				endpoint_tx.send(Some(Err($e))).ok();
-				backoff.delay().await;
+				wait_to_retry!();
 			};
 		}
 
@@ -841,7 +1583,7 @@ impl ActiveTunnelManager {
 			let handle_res = {
 				let mut relay = relay.lock().await;
 				relay
-					.connect(&access_token)
+					.connect_with_transport(&access_token, connect_relay)
 					.await
 					.map_err(|e| wrap(e, "error connecting to tunnel"))
 			};
@@ -854,20 +1596,35 @@ impl ActiveTunnelManager {
 				}
 			};
 
-			backoff.reset();
+			breaker.record_success();
+			fast_attempts = 0;
+			connect_count.fetch_add(1, Ordering::Relaxed);
 			endpoint_tx.send(Some(Ok(handle.endpoint().clone()))).ok();
 
+			let span = log.span("dev-tunnel.relay").start(log.tracer());
+			let cx = opentelemetry::Context::current_with_span(span);
+
 			tokio::select! {
 				// error is mapped like this prevent it being used across an await,
 				// which Rust dislikes since there's a non-sendable dyn Error in there
-				res = (&mut handle).map_err(|e| wrap(e, "error from tunnel connection")) => {
+				res = log::with_heartbeat(&log, "dev-tunnel.relay", &cx, None, (&mut handle).map_err(|e| wrap(e, "error from tunnel connection"))) => {
 					if let Err(e) = res {
 						fail!(e, "Tunnel exited unexpectedly, reconnecting");
 					} else {
-						warning!(log, "Tunnel exited unexpectedly but gracefully, reconnecting");
-						backoff.delay().await;
+						breaker.record_failure("Tunnel exited unexpectedly but gracefully, reconnecting");
+						wait_to_retry!();
 					}
 				},
+				Some(_) = resume_rx.recv() => {
+					// the relay drops connections that miss keepalives, so one
+					// that slept through them is almost certainly dead, but
+					// can take minutes to time out. Reconnect now instead;
+					// the first attempts double as a probe of whether the
+					// network is back yet.
+					info!(log, "System resumed, reconnecting tunnel");
+					trace!(log, "Previous connection closed with result: {:?}", handle.close().await);
+					fast_attempts = FAST_RECONNECT_ATTEMPTS;
+				},
 				_ = close_rx.recv() => {
 					trace!(log, "Tunnel closing gracefully");
 					trace!(log, "Tunnel closed with result: {:?}", handle.close().await);
@@ -877,36 +1634,3 @@ impl ActiveTunnelManager {
 		}
 	}
 }
-
-struct Backoff {
-	failures: u32,
-	base_duration: Duration,
-	max_duration: Duration,
-}
-
-impl Backoff {
-	pub fn new(base_duration: Duration, max_duration: Duration) -> Self {
-		Self {
-			failures: 0,
-			base_duration,
-			max_duration,
-		}
-	}
-
-	pub async fn delay(&mut self) {
-		tokio::time::sleep(self.next()).await
-	}
-
-	pub fn next(&mut self) -> Duration {
-		self.failures += 1;
-		let duration = self
-			.base_duration
-			.checked_mul(self.failures)
-			.unwrap_or(self.max_duration);
-		std::cmp::min(duration, self.max_duration)
-	}
-
-	pub fn reset(&mut self) {
-		self.failures = 0;
-	}
-}
@@ -0,0 +1,245 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	fs::File,
+	io::Write,
+	path::{Path, PathBuf},
+	process::Stdio,
+	time::Duration,
+};
+
+use tokio::process::Command;
+
+use crate::{
+	info, log,
+	state::LauncherPaths,
+	util::errors::{wrap, AnyError, CommandFailed, CommandTimedOut, MissingHomeDirectory},
+	warning,
+};
+
+/// Maximum time given to cloning the repository and to running its install
+/// script, each, so a script waiting on input can't hold up the host forever.
+const DOTFILES_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Scripts looked for in the root of the repository, in order. These are the
+/// same ones dev containers look for.
+#[cfg(not(windows))]
+const INSTALL_SCRIPTS: &[&str] = &[
+	"install.sh",
+	"install",
+	"bootstrap.sh",
+	"bootstrap",
+	"script/bootstrap",
+	"setup.sh",
+	"setup",
+	"script/setup",
+];
+
+#[cfg(windows)]
+const INSTALL_SCRIPTS: &[&str] = &["install.cmd", "install.bat", "install.ps1"];
+
+/// Created in the CLI data directory when installing dotfiles starts, so an
+/// attempt that fails partway is retried the next time.
+const PENDING_MARKER: &str = "dotfiles-pending";
+/// The pending marker is renamed to this once dotfiles are installed.
+const INSTALLED_MARKER: &str = "dotfiles-installed";
+
+pub struct DotfilesOptions {
+	/// URL of the git repository to clone.
+	pub repo: String,
+	/// Command to run in the repository instead of looking for an install script.
+	pub install_command: Option<String>,
+}
+
+/// Clones the dotfiles repository into the home directory and runs its install
+/// script, until that succeeds once on a machine. Output is written to
+/// `dotfiles.log` in the CLI data directory.
+pub async fn bootstrap_dotfiles(
+	log: &log::Logger,
+	paths: &LauncherPaths,
+	options: &DotfilesOptions,
+) -> Result<(), AnyError> {
+	let home = dirs::home_dir().ok_or(MissingHomeDirectory())?;
+	let target = home.join("dotfiles");
+	let pending = paths.root().join(PENDING_MARKER);
+	let installed = paths.root().join(INSTALLED_MARKER);
+	if installed.exists() {
+		info!(log, "Dotfiles already installed, not installing them again");
+		return Ok(());
+	}
+
+	// without the marker, an existing directory isn't one an earlier attempt
+	// cloned, so it's left alone
+	if target.exists() && !pending.exists() {
+		info!(
+			log,
+			"Dotfiles already exist in {}, not cloning them again",
+			target.display()
+		);
+		return Ok(());
+	}
+
+	let log_path = paths.root().join("dotfiles.log");
+	info!(
+		log,
+		"Installing dotfiles from {}, logging to {}",
+		options.repo,
+		log_path.display()
+	);
+	let log_file = File::create(&log_path)
+		.map_err(|e| wrap(e, format!("error creating {}", log_path.display())))?;
+	File::create(&pending).map_err(|e| wrap(e, format!("error creating {}", pending.display())))?;
+
+	if !target.exists() {
+		let mut clone = Command::new("git");
+		clone
+			.args(["clone", "--depth", "1", "--"])
+			.arg(&options.repo)
+			.arg(&target);
+		run_logged(clone, &log_file).await?;
+	}
+
+	let install = match &options.install_command {
+		Some(c) => Some(shell_command(c)),
+		None => find_install_script(&target)
+			.map(|s| script_command(&s))
+			.transpose()?,
+	};
+
+	match install {
+		Some(mut install) => {
+			install.current_dir(&target);
+			run_logged(install, &log_file).await?;
+		}
+		None => link_dotfiles(log, &target, &home),
+	}
+
+	std::fs::rename(&pending, &installed)
+		.map_err(|e| wrap(e, format!("error creating {}", installed.display())))?;
+	info!(log, "Dotfiles installed");
+	Ok(())
+}
+
+fn find_install_script(dir: &Path) -> Option<PathBuf> {
+	INSTALL_SCRIPTS
+		.iter()
+		.map(|s| dir.join(s))
+		.find(|p| p.is_file())
+}
+
+#[cfg(not(windows))]
+fn shell_command(command: &str) -> Command {
+	let mut cmd = Command::new("sh");
+	cmd.args(["-c", command]);
+	cmd
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+	let mut cmd = Command::new("cmd");
+	cmd.args(["/C", command]);
+	cmd
+}
+
+#[cfg(not(windows))]
+fn script_command(script: &Path) -> Result<Command, AnyError> {
+	use std::os::unix::fs::PermissionsExt;
+
+	// scripts aren't always committed as executable
+	let mut perms = std::fs::metadata(script)
+		.map_err(|e| wrap(e, "error reading install script"))?
+		.permissions();
+	perms.set_mode(perms.mode() | 0o111);
+	std::fs::set_permissions(script, perms)
+		.map_err(|e| wrap(e, "error making install script executable"))?;
+
+	Ok(Command::new(script))
+}
+
+#[cfg(windows)]
+fn script_command(script: &Path) -> Result<Command, AnyError> {
+	if script.extension().map(|e| e == "ps1").unwrap_or(false) {
+		let mut cmd = Command::new("powershell");
+		cmd.args(["-NoProfile", "-ExecutionPolicy", "Bypass", "-File"])
+			.arg(script);
+		Ok(cmd)
+	} else {
+		let mut cmd = Command::new("cmd");
+		cmd.arg("/C").arg(script);
+		Ok(cmd)
+	}
+}
+
+/// Links dotfiles in the root of the repository into the home directory, for
+/// repositories without an install script. Existing files are left alone.
+#[cfg(not(windows))]
+fn link_dotfiles(log: &log::Logger, repo: &Path, home: &Path) {
+	let entries = match std::fs::read_dir(repo) {
+		Ok(e) => e,
+		Err(e) => {
+			warning!(log, "Error reading dotfiles repository: {}", e);
+			return;
+		}
+	};
+
+	for entry in entries.flatten() {
+		let name = entry.file_name();
+		let name_str = name.to_string_lossy();
+		if !name_str.starts_with('.') || name_str == ".git" {
+			continue;
+		}
+
+		let link = home.join(&name);
+		if link.exists() {
+			continue;
+		}
+
+		if let Err(e) = std::os::unix::fs::symlink(entry.path(), &link) {
+			warning!(log, "Error linking {}: {}", link.display(), e);
+		}
+	}
+}
+
+#[cfg(windows)]
+fn link_dotfiles(log: &log::Logger, _repo: &Path, _home: &Path) {
+	warning!(log, "No install script found in the dotfiles repository");
+}
+
+/// Runs the command with its output appended to the log file, failing if it
+/// doesn't exit successfully within the timeout.
+async fn run_logged(mut cmd: Command, mut log_file: &File) -> Result<(), AnyError> {
+	let child = cmd
+		.stdin(Stdio::null())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.kill_on_drop(true)
+		.spawn()
+		.map_err(|e| wrap(e, format!("error running {:?}", cmd)))?;
+
+	// the child is killed if it's dropped on timeout
+	let output = tokio::time::timeout(DOTFILES_TIMEOUT, child.wait_with_output())
+		.await
+		.map_err(|_| CommandTimedOut {
+			command: format!("{:?}", cmd),
+			timeout: DOTFILES_TIMEOUT,
+		})?
+		.map_err(|e| wrap(e, "error waiting for command"))?;
+
+	log_file
+		.write_all(&output.stdout)
+		.and_then(|_| log_file.write_all(&output.stderr))
+		.map_err(|e| wrap(e, "error writing dotfiles log"))?;
+
+	if !output.status.success() {
+		return Err(CommandFailed {
+			command: format!("{:?}", cmd),
+			output,
+		}
+		.into());
+	}
+
+	Ok(())
+}
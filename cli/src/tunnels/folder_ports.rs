@@ -0,0 +1,330 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Reads the ports a folder's dev container and workspace settings ask to be
+//! forwarded, so the tunnel can forward them when it starts rather than when
+//! the first client opens the folder. Ports come from `forwardPorts` in its
+//! `devcontainer.json`, and are labelled and made public by `portsAttributes`
+//! there or `remote.portsAttributes` in `.vscode/settings.json`, which takes
+//! precedence. Since anyone who can edit the folder can write these, ports
+//! are only made public if the host's owner allows it.
+
+use std::{collections::HashMap, path::Path};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{info, log, warning};
+
+use super::{dev_tunnels::PortOptions, forward_targets::ForwardTarget};
+
+/// Where dev container configuration is looked for in the folder, in order.
+const DEVCONTAINER_FILES: [&str; 2] = [".devcontainer/devcontainer.json", ".devcontainer.json"];
+const SETTINGS_FILE: &str = ".vscode/settings.json";
+
+/// A port the folder asks to be forwarded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FolderPort {
+	/// Port on the tunnel.
+	pub port: u16,
+	/// Host other than this machine the port connects to, for entries like
+	/// 'db:5432'.
+	pub target: Option<ForwardTarget>,
+	pub options: PortOptions,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct DevContainer {
+	#[serde(default)]
+	forward_ports: Vec<Value>,
+	#[serde(default)]
+	ports_attributes: HashMap<String, PortAttributes>,
+}
+
+#[derive(Deserialize, Default)]
+struct WorkspaceSettings {
+	#[serde(default, rename = "remote.portsAttributes")]
+	ports_attributes: HashMap<String, PortAttributes>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+struct PortAttributes {
+	#[serde(default)]
+	label: Option<String>,
+	/// 'public' or 'private'.
+	#[serde(default, alias = "visibility")]
+	privacy: Option<String>,
+}
+
+/// Gets the ports the folder asks to be forwarded, logging and skipping
+/// files or entries that can't be read. Ports the folder asks to make public
+/// are kept private unless `allow_public` is set.
+pub fn read_folder_ports(log: &log::Logger, folder: &Path, allow_public: bool) -> Vec<FolderPort> {
+	let devcontainer: DevContainer = DEVCONTAINER_FILES
+		.iter()
+		.map(|f| folder.join(f))
+		.find(|f| f.exists())
+		.and_then(|f| read_jsonc(log, &f))
+		.unwrap_or_default();
+	let settings: WorkspaceSettings =
+		read_jsonc(log, &folder.join(SETTINGS_FILE)).unwrap_or_default();
+
+	let mut ports: Vec<FolderPort> = vec![];
+	for entry in &devcontainer.forward_ports {
+		let (port, target) = match parse_forward_port(entry) {
+			Some(p) => p,
+			None => {
+				warning!(log, "Ignoring port {} in devcontainer.json", entry);
+				continue;
+			}
+		};
+		if ports.iter().any(|p| p.port == port) {
+			continue;
+		}
+
+		// workspace settings apply to the port on top of the dev container's
+		let mut attributes =
+			find_attributes(&devcontainer.ports_attributes, port).unwrap_or_default();
+		if let Some(a) = find_attributes(&settings.ports_attributes, port) {
+			attributes.label = a.label.or(attributes.label);
+			attributes.privacy = a.privacy.or(attributes.privacy);
+		}
+
+		let asks_public = attributes
+			.privacy
+			.map(|p| p.eq_ignore_ascii_case("public"))
+			.unwrap_or(false);
+		if asks_public && allow_public {
+			info!(
+				log,
+				"Making port {} public, as the folder's settings ask", port
+			);
+		} else if asks_public {
+			warning!(
+				log,
+				"Forwarding port {} privately, though the folder's settings ask for it to be public. Pass --allow-public-folder-ports to allow it",
+				port
+			);
+		}
+
+		ports.push(FolderPort {
+			port,
+			target,
+			options: PortOptions {
+				label: attributes.label,
+				public: asks_public && allow_public,
+			},
+		});
+	}
+
+	ports
+}
+
+fn read_jsonc<T: serde::de::DeserializeOwned>(log: &log::Logger, path: &Path) -> Option<T> {
+	let contents = std::fs::read_to_string(path).ok()?;
+	match serde_json::from_str(&strip_jsonc(&contents)) {
+		Ok(v) => Some(v),
+		Err(e) => {
+			warning!(log, "Ignoring ports in {}: {}", path.display(), e);
+			None
+		}
+	}
+}
+
+/// Parses an entry of `forwardPorts`, either a port number, or a host and
+/// port like 'db:5432'. Returns the tunnel port, and the target if it's not
+/// on this machine.
+fn parse_forward_port(entry: &Value) -> Option<(u16, Option<ForwardTarget>)> {
+	match entry {
+		Value::Number(n) => Some((u16::try_from(n.as_u64()?).ok()?, None)),
+		Value::String(s) => {
+			if let Ok(port) = s.parse::<u16>() {
+				return Some((port, None));
+			}
+
+			let target = s.parse::<ForwardTarget>().ok()?;
+			match target.host.as_str() {
+				"localhost" | "127.0.0.1" | "::1" => Some((target.port, None)),
+				_ => Some((target.port, Some(target))),
+			}
+		}
+		_ => None,
+	}
+}
+
+/// Finds the attributes for the port, keyed by it like '3000', or by a range
+/// that includes it like '3000-3005'. Keys that match process names aren't
+/// supported, since no process is running yet.
+fn find_attributes(
+	attributes: &HashMap<String, PortAttributes>,
+	port: u16,
+) -> Option<PortAttributes> {
+	if let Some(a) = attributes.get(&port.to_string()) {
+		return Some(a.clone());
+	}
+
+	attributes
+		.iter()
+		.find(|(key, _)| match key.split_once('-') {
+			Some((start, end)) => match (start.trim().parse::<u16>(), end.trim().parse::<u16>()) {
+				(Ok(start), Ok(end)) => (start..=end).contains(&port),
+				_ => false,
+			},
+			None => false,
+		})
+		.map(|(_, a)| a.clone())
+}
+
+/// Removes comments and trailing commas from JSON with comments, as VS Code
+/// and dev containers write it, so it can be parsed as JSON.
+fn strip_jsonc(s: &str) -> String {
+	let mut out = String::with_capacity(s.len());
+	let mut chars = s.chars().peekable();
+	let mut in_string = false;
+	while let Some(c) = chars.next() {
+		if in_string {
+			out.push(c);
+			match c {
+				'\\' => out.extend(chars.next()),
+				'"' => in_string = false,
+				_ => {}
+			}
+			continue;
+		}
+
+		match (c, chars.peek()) {
+			('"', _) => {
+				in_string = true;
+				out.push(c);
+			}
+			('/', Some('/')) => {
+				for c in chars.by_ref() {
+					if c == '\n' {
+						out.push(c);
+						break;
+					}
+				}
+			}
+			('/', Some('*')) => {
+				chars.next();
+				let mut prev = ' ';
+				for c in chars.by_ref() {
+					if prev == '*' && c == '/' {
+						break;
+					}
+					prev = c;
+				}
+			}
+			(']' | '}', _) => {
+				let trimmed = out.trim_end().len();
+				if out[..trimmed].ends_with(',') {
+					out.truncate(trimmed - 1);
+				}
+				out.push(c);
+			}
+			_ => out.push(c),
+		}
+	}
+
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_strip_jsonc() {
+		let s = r#"{
+			// the app
+			"a": "http://x/*y*/", /* trailing */
+			"b": [1, 2,],
+			"c": "quote \" // not a comment",
+		}"#;
+		let v: Value = serde_json::from_str(&strip_jsonc(s)).unwrap();
+		assert_eq!(v["a"], "http://x/*y*/");
+		assert_eq!(v["b"], serde_json::json!([1, 2]));
+		assert_eq!(v["c"], "quote \" // not a comment");
+	}
+
+	#[test]
+	fn test_parse_forward_port() {
+		assert_eq!(
+			parse_forward_port(&serde_json::json!(3000)),
+			Some((3000, None))
+		);
+		assert_eq!(
+			parse_forward_port(&serde_json::json!("8080")),
+			Some((8080, None))
+		);
+		assert_eq!(
+			parse_forward_port(&serde_json::json!("localhost:5000")),
+			Some((5000, None))
+		);
+		assert_eq!(
+			parse_forward_port(&serde_json::json!("db:5432")),
+			Some((
+				5432,
+				Some(ForwardTarget {
+					host: "db".to_string(),
+					port: 5432
+				})
+			))
+		);
+		assert_eq!(parse_forward_port(&serde_json::json!(70000)), None);
+		assert_eq!(parse_forward_port(&serde_json::json!(true)), None);
+	}
+
+	#[test]
+	fn test_read_folder_ports() {
+		let dir = tempfile::tempdir().unwrap();
+		std::fs::create_dir_all(dir.path().join(".devcontainer")).unwrap();
+		std::fs::create_dir_all(dir.path().join(".vscode")).unwrap();
+		std::fs::write(
+			dir.path().join(".devcontainer/devcontainer.json"),
+			r#"{
+				// ports for the app
+				"forwardPorts": [3000, "db:5432", 3001],
+				"portsAttributes": {
+					"3000": { "label": "App", "visibility": "public" },
+					"5432": { "label": "Database" },
+				},
+			}"#,
+		)
+		.unwrap();
+		std::fs::write(
+			dir.path().join(".vscode/settings.json"),
+			r#"{ "remote.portsAttributes": { "3000-3001": { "label": "Web" } } }"#,
+		)
+		.unwrap();
+
+		let log = log::Logger::test();
+		let ports = read_folder_ports(&log, dir.path(), true);
+		assert_eq!(ports.len(), 3);
+
+		assert_eq!(ports[0].port, 3000);
+		assert_eq!(ports[0].options.label.as_deref(), Some("Web"));
+		assert!(ports[0].options.public);
+
+		assert_eq!(ports[1].port, 5432);
+		assert_eq!(ports[1].target.as_ref().unwrap().host, "db");
+		assert_eq!(ports[1].options.label.as_deref(), Some("Database"));
+		assert!(!ports[1].options.public);
+
+		assert_eq!(ports[2].port, 3001);
+		assert_eq!(ports[2].options.label.as_deref(), Some("Web"));
+
+		let ports = read_folder_ports(&log, dir.path(), false);
+		assert_eq!(ports[0].options.label.as_deref(), Some("Web"));
+		assert!(!ports[0].options.public);
+	}
+
+	#[test]
+	fn test_read_folder_ports_without_config() {
+		let dir = tempfile::tempdir().unwrap();
+		assert!(read_folder_ports(&log::Logger::test(), dir.path(), false).is_empty());
+	}
+}
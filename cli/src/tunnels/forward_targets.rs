@@ -0,0 +1,266 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Forwards tunnel ports to hosts reachable from this machine, not just to
+//! its own ports, so the tunnel can act as a jump host to services like
+//! databases on an internal network. Only hosts an admin has allowed can be
+//! targeted, since otherwise any client could reach anything the host can.
+
+use std::{fmt, net::SocketAddr, str::FromStr};
+
+use tokio::{net::TcpStream, sync::mpsc};
+
+use crate::{
+	debug, log,
+	util::errors::{wrap, AnyError, ForwardTargetNotAllowed},
+	warning,
+};
+
+use super::{dev_tunnels::PortConnection, ip_filter::Cidr};
+
+/// A host and port to forward connections to, like `db.internal:5432`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ForwardTarget {
+	pub host: String,
+	pub port: u16,
+}
+
+impl FromStr for ForwardTarget {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (host, port) = s
+			.rsplit_once(':')
+			.ok_or_else(|| format!("expected a target like 'db.internal:5432', got '{}'", s))?;
+		let host = host.trim_start_matches('[').trim_end_matches(']');
+		if host.is_empty() {
+			return Err(format!("missing host in '{}'", s));
+		}
+		let port = port
+			.parse::<u16>()
+			.map_err(|_| format!("invalid port in '{}'", s))?;
+
+		Ok(ForwardTarget {
+			host: host.to_lowercase(),
+			port,
+		})
+	}
+}
+
+impl fmt::Display for ForwardTarget {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if self.host.contains(':') {
+			write!(f, "[{}]:{}", self.host, self.port)
+		} else {
+			write!(f, "{}:{}", self.host, self.port)
+		}
+	}
+}
+
+/// A tunnel port and the target it's forwarded to, given on the command line
+/// like `5432:db.internal:5432`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ForwardSpec {
+	pub port: u16,
+	pub target: ForwardTarget,
+}
+
+impl FromStr for ForwardSpec {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (port, target) = s.split_once(':').ok_or_else(|| {
+			format!(
+				"expected a forward like '5432:db.internal:5432', got '{}'",
+				s
+			)
+		})?;
+		let port = port
+			.parse::<u16>()
+			.map_err(|_| format!("invalid tunnel port in '{}'", s))?;
+
+		Ok(ForwardSpec {
+			port,
+			target: target.parse()?,
+		})
+	}
+}
+
+impl fmt::Display for ForwardSpec {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}:{}", self.port, self.target)
+	}
+}
+
+/// A host or range of addresses that ports may be forwarded to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AllowedTarget {
+	/// A host name, like `db.internal`, or every host under a domain, like
+	/// `*.internal`. Matched against the name the target is given by.
+	Host(String),
+	/// A range of addresses, like `10.0.0.0/8`. Matched against the
+	/// addresses the target resolves to.
+	Range(Cidr),
+}
+
+impl FromStr for AllowedTarget {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.trim();
+		if s.is_empty() {
+			return Err("expected a host name or address range".to_string());
+		}
+
+		match s.parse::<Cidr>() {
+			Ok(c) => Ok(AllowedTarget::Range(c)),
+			Err(_) if s.contains('/') => Err(format!("invalid address range '{}'", s)),
+			Err(_) => Ok(AllowedTarget::Host(s.to_lowercase())),
+		}
+	}
+}
+
+impl fmt::Display for AllowedTarget {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			AllowedTarget::Host(h) => write!(f, "{}", h),
+			AllowedTarget::Range(c) => write!(f, "{}", c),
+		}
+	}
+}
+
+/// Hosts ports may be forwarded to. This machine's own loopback addresses
+/// are always allowed, since ports on them can be forwarded anyway.
+#[derive(Clone, Debug, Default)]
+pub struct ForwardTargetPolicy {
+	pub allowed: Vec<AllowedTarget>,
+}
+
+impl ForwardTargetPolicy {
+	fn allows_host(&self, host: &str) -> bool {
+		self.allowed.iter().any(|a| match a {
+			AllowedTarget::Host(h) => match h.strip_prefix("*.") {
+				Some(domain) => host
+					.strip_suffix(domain)
+					.map(|sub| sub.ends_with('.'))
+					.unwrap_or(false),
+				None => host == h,
+			},
+			AllowedTarget::Range(_) => false,
+		})
+	}
+
+	fn allows_addr(&self, addr: &SocketAddr) -> bool {
+		addr.ip().is_loopback()
+			|| self.allowed.iter().any(|a| match a {
+				AllowedTarget::Range(c) => c.contains(&addr.ip()),
+				AllowedTarget::Host(_) => false,
+			})
+	}
+
+	/// Resolves the target to an address connections may be made to, or
+	/// fails if it's not allowed. Connections should be made to the returned
+	/// address, rather than resolving the host again, so a DNS change can't
+	/// send them somewhere that wasn't allowed.
+	pub async fn resolve(&self, target: &ForwardTarget) -> Result<SocketAddr, AnyError> {
+		let addrs = tokio::net::lookup_host((target.host.as_str(), target.port))
+			.await
+			.map_err(|e| wrap(e, format!("error resolving {}", target.host)))?
+			.collect::<Vec<_>>();
+
+		let by_name = self.allows_host(&target.host);
+		addrs
+			.into_iter()
+			.find(|a| by_name || self.allows_addr(a))
+			.ok_or_else(|| ForwardTargetNotAllowed(target.to_string()).into())
+	}
+}
+
+/// Relays connections made to a tunnel port to the target, until the port
+/// is removed from the tunnel. The target is checked against the policy for
+/// each connection, since the addresses it resolves to can change.
+pub async fn serve_forward_target(
+	log: log::Logger,
+	mut connections: mpsc::UnboundedReceiver<PortConnection>,
+	target: ForwardTarget,
+	policy: ForwardTargetPolicy,
+) {
+	while let Some(conn) = connections.recv().await {
+		let log = log.clone();
+		let target = target.clone();
+		let policy = policy.clone();
+		tokio::spawn(async move {
+			let result = async {
+				let addr = policy.resolve(&target).await?;
+				let stream = TcpStream::connect(addr)
+					.await
+					.map_err(|e| wrap(e, format!("error connecting to {}", target)))?;
+				Ok::<_, AnyError>((addr, stream))
+			}
+			.await;
+
+			let (addr, stream) = match result {
+				Ok(r) => r,
+				Err(e) => {
+					warning!(log, "Could not forward connection to {}: {}", target, e);
+					return;
+				}
+			};
+
+			debug!(log, "Forwarding connection to {} ({})", target, addr);
+			let (mut writehalf, mut readhalf) = conn.into_split();
+			let (mut target_read, mut target_write) = stream.into_split();
+			tokio::select! {
+				_ = tokio::io::copy(&mut readhalf, &mut target_write) => {},
+				_ = tokio::io::copy(&mut target_read, &mut writehalf) => {},
+			}
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn policy(allowed: &[&str]) -> ForwardTargetPolicy {
+		ForwardTargetPolicy {
+			allowed: allowed.iter().map(|s| s.parse().unwrap()).collect(),
+		}
+	}
+
+	#[test]
+	fn test_parses_forward_spec() {
+		let spec: ForwardSpec = "5432:db.internal:5433".parse().unwrap();
+		assert_eq!(spec.port, 5432);
+		assert_eq!(spec.target.host, "db.internal");
+		assert_eq!(spec.target.port, 5433);
+		assert_eq!(spec.target.to_string(), "db.internal:5433");
+
+		let spec: ForwardSpec = "8080:[fd00::1]:80".parse().unwrap();
+		assert_eq!(spec.target.host, "fd00::1");
+		assert_eq!(spec.target.to_string(), "[fd00::1]:80");
+
+		assert!("5432".parse::<ForwardSpec>().is_err());
+		assert!("5432:db.internal".parse::<ForwardSpec>().is_err());
+		assert!("x:db.internal:5432".parse::<ForwardSpec>().is_err());
+	}
+
+	#[test]
+	fn test_allows_targets() {
+		let p = policy(&["db.internal", "*.corp.example", "10.0.0.0/8"]);
+		assert!(p.allows_host("db.internal"));
+		assert!(p.allows_host("build.corp.example"));
+		assert!(!p.allows_host("corp.example"));
+		assert!(!p.allows_host("notcorp.example"));
+		assert!(!p.allows_host("cache.internal"));
+
+		assert!(p.allows_addr(&"10.1.2.3:5432".parse().unwrap()));
+		assert!(p.allows_addr(&"127.0.0.1:5432".parse().unwrap()));
+		assert!(!p.allows_addr(&"192.168.0.1:5432".parse().unwrap()));
+
+		assert!("10.0.0.0/33".parse::<AllowedTarget>().is_err());
+		assert!(!policy(&[]).allows_addr(&"10.1.2.3:5432".parse().unwrap()));
+	}
+}
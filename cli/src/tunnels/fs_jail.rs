@@ -0,0 +1,255 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+
+use crate::{
+	info, log,
+	options::JailSandbox,
+	util::errors::{wrap, AnyError},
+	warning,
+};
+
+/// Confines the server, and everything it runs such as terminals and
+/// extensions, to a directory tree. The server starts there with its home
+/// directory set there, and on Linux it's sandboxed with Landlock so it can't
+/// reach files outside it, other than those it needs to run.
+#[derive(Clone, Debug)]
+pub struct FsJail {
+	pub root: PathBuf,
+	pub sandbox: JailSandbox,
+}
+
+/// Paths the server needs outside of the jail.
+pub struct JailPaths<'a> {
+	/// The server's installation, which is read and executed.
+	pub install_dir: &'a Path,
+	/// Where the server keeps its data, like settings and extensions. It's
+	/// in the jail, so it isn't shared with servers outside it.
+	pub data_dir: &'a Path,
+	/// Temporary directory for the server, used only by it.
+	pub temp_dir: &'a Path,
+	/// Directory the server creates its socket in, if any, which should be
+	/// used only by it.
+	pub socket_dir: Option<&'a Path>,
+	/// Extra CA certificates the server reads, if any.
+	pub ca_certs: Option<&'a Path>,
+}
+
+impl FsJail {
+	/// Sets up the command to run in the jail.
+	pub fn apply(
+		&self,
+		log: &log::Logger,
+		cmd: &mut Command,
+		paths: &JailPaths,
+	) -> Result<(), AnyError> {
+		std::fs::create_dir_all(paths.temp_dir)
+			.map_err(|e| wrap(e, "error creating server temp dir"))?;
+
+		cmd.current_dir(&self.root)
+			.env("HOME", &self.root)
+			.env("TMPDIR", paths.temp_dir)
+			.arg(format!("--server-data-dir={}", paths.data_dir.display()));
+
+		match self.sandbox {
+			JailSandbox::Off => Ok(()),
+			JailSandbox::Landlock => sandbox::apply(cmd, &self.root, paths),
+			JailSandbox::Auto => match sandbox::apply(cmd, &self.root, paths) {
+				Ok(()) => {
+					info!(log, "Server is sandboxed to {}", self.root.display());
+					Ok(())
+				}
+				Err(e) => {
+					warning!(
+						log,
+						"{}. The server starts in {}, but isn't prevented from reaching other files.",
+						e,
+						self.root.display()
+					);
+					Ok(())
+				}
+			},
+		}
+	}
+}
+
+#[cfg(target_os = "linux")]
+mod sandbox {
+	use std::{ffi::CString, os::unix::ffi::OsStrExt, path::Path, sync::Arc};
+
+	use tokio::process::Command;
+
+	use super::JailPaths;
+	use crate::util::errors::{AnyError, SandboxUnavailable};
+
+	// from linux/landlock.h, ABI version 1
+	const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+	const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+	const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+	const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+	const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+	const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+	const ACCESS_FS_ALL: u64 = (1 << 13) - 1;
+
+	const READ: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+	const READ_EXECUTE: u64 = READ | ACCESS_FS_EXECUTE;
+
+	/// System directories the server and the tools it runs are read from.
+	const SYSTEM_DIRS: [&str; 12] = [
+		"/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/etc", "/opt", "/nix", "/run",
+		"/proc", "/sys",
+	];
+
+	#[repr(C)]
+	struct RulesetAttr {
+		handled_access_fs: u64,
+	}
+
+	#[repr(C, packed)]
+	struct PathBeneathAttr {
+		allowed_access: u64,
+		parent_fd: i32,
+	}
+
+	struct Fd(i32);
+
+	impl Drop for Fd {
+		fn drop(&mut self) {
+			unsafe { libc::close(self.0) };
+		}
+	}
+
+	fn unavailable(message: &str) -> AnyError {
+		SandboxUnavailable(format!("{} ({})", message, std::io::Error::last_os_error())).into()
+	}
+
+	pub fn apply(cmd: &mut Command, root: &Path, paths: &JailPaths) -> Result<(), AnyError> {
+		let version = unsafe {
+			libc::syscall(
+				libc::SYS_landlock_create_ruleset,
+				std::ptr::null::<RulesetAttr>(),
+				0,
+				LANDLOCK_CREATE_RULESET_VERSION,
+			)
+		};
+		if version < 1 {
+			return Err(unavailable("Landlock isn't supported by this kernel"));
+		}
+		// rules only add access, so everything in the jail would be writable
+		if paths.install_dir.starts_with(root) {
+			return Err(SandboxUnavailable(format!(
+				"the server's installation in {} is inside the jail, so it couldn't be kept read-only",
+				paths.install_dir.display()
+			))
+			.into());
+		}
+
+		let attr = RulesetAttr {
+			handled_access_fs: ACCESS_FS_ALL,
+		};
+		let ruleset = unsafe {
+			libc::syscall(
+				libc::SYS_landlock_create_ruleset,
+				&attr as *const RulesetAttr,
+				std::mem::size_of::<RulesetAttr>(),
+				0,
+			)
+		};
+		if ruleset < 0 {
+			return Err(unavailable("error creating Landlock ruleset"));
+		}
+		let ruleset = Fd(ruleset as i32);
+
+		for dir in SYSTEM_DIRS {
+			add_rule(&ruleset, Path::new(dir), READ_EXECUTE)?;
+		}
+		add_rule(&ruleset, Path::new("/dev"), READ | ACCESS_FS_WRITE_FILE)?;
+		add_rule(&ruleset, paths.install_dir, READ_EXECUTE)?;
+		add_rule(&ruleset, paths.data_dir, ACCESS_FS_ALL)?;
+		add_rule(&ruleset, paths.temp_dir, ACCESS_FS_ALL)?;
+		add_rule(&ruleset, root, ACCESS_FS_ALL)?;
+		if let Some(dir) = paths.socket_dir {
+			add_rule(&ruleset, dir, ACCESS_FS_ALL)?;
+		}
+		if let Some(file) = paths.ca_certs {
+			add_rule(&ruleset, file, READ)?;
+		}
+
+		// the ruleset is closed once the command, and so the closure, is dropped
+		let ruleset = Arc::new(ruleset);
+		unsafe {
+			cmd.pre_exec(move || {
+				if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+					|| libc::syscall(libc::SYS_landlock_restrict_self, ruleset.0, 0) != 0
+				{
+					return Err(std::io::Error::last_os_error());
+				}
+				Ok(())
+			});
+		}
+
+		Ok(())
+	}
+
+	/// Allows the access beneath the path. Paths that don't exist are skipped.
+	fn add_rule(ruleset: &Fd, path: &Path, access: u64) -> Result<(), AnyError> {
+		let c_path = match CString::new(path.as_os_str().as_bytes()) {
+			Ok(p) => p,
+			Err(_) => return Ok(()),
+		};
+
+		let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+		if fd < 0 {
+			return Ok(());
+		}
+		let fd = Fd(fd);
+
+		// files can't be given directory rights, like for a socket's directory
+		// that turned out to be a file
+		let is_dir = path.is_dir();
+		let attr = PathBeneathAttr {
+			allowed_access: if is_dir {
+				access
+			} else {
+				access & (ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_WRITE_FILE)
+			},
+			parent_fd: fd.0,
+		};
+		let r = unsafe {
+			libc::syscall(
+				libc::SYS_landlock_add_rule,
+				ruleset.0,
+				LANDLOCK_RULE_PATH_BENEATH,
+				&attr as *const PathBeneathAttr,
+				0,
+			)
+		};
+		if r != 0 {
+			return Err(unavailable(&format!(
+				"error allowing access to {}",
+				path.display()
+			)));
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sandbox {
+	use std::path::Path;
+
+	use tokio::process::Command;
+
+	use super::JailPaths;
+	use crate::util::errors::{AnyError, SandboxUnavailable};
+
+	pub fn apply(_cmd: &mut Command, _root: &Path, _paths: &JailPaths) -> Result<(), AnyError> {
+		Err(SandboxUnavailable("sandboxing is only supported on Linux".to_string()).into())
+	}
+}
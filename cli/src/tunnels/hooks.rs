@@ -0,0 +1,69 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Commands the host's owner sets in the `hooks` setting to run as the
+//! tunnel starts and stops, like registering the machine with a monitoring
+//! system. They're run by the shell, with the tunnel's name in the
+//! `VSCODE_TUNNEL_NAME` environment variable.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{info, log, util::command::ExternalCommand, warning};
+
+/// How long a hook may run before it's stopped.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct TunnelHooks {
+	/// Run once the tunnel is hosted.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub on_start: Option<String>,
+	/// Run once the tunnel stops being hosted, before the CLI exits or
+	/// restarts.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub on_stop: Option<String>,
+}
+
+/// Runs the hook, logging rather than returning errors, since a failing hook
+/// shouldn't keep the tunnel from running.
+pub async fn run_hook(log: &log::Logger, name: &str, command: &str, tunnel_name: &str) {
+	info!(log, "Running {} hook", name);
+	if let Err(e) = shell(command)
+		.env("VSCODE_TUNNEL_NAME", tunnel_name)
+		.timeout(Some(HOOK_TIMEOUT))
+		.log(log)
+		.run()
+		.await
+	{
+		warning!(log, "Error running {} hook: {}", name, e);
+	}
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> ExternalCommand {
+	ExternalCommand::new("cmd").args(["/C", command])
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> ExternalCommand {
+	ExternalCommand::new("sh").args(["-c", command])
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_run_hook() {
+		let dir = tempfile::tempdir().unwrap();
+		let out = dir.path().join("out");
+		let command = format!("echo \"$VSCODE_TUNNEL_NAME\" > '{}'", out.display());
+		run_hook(&log::Logger::test(), "start", &command, "build-host").await;
+		assert_eq!(std::fs::read_to_string(&out).unwrap(), "build-host\n");
+	}
+}
@@ -0,0 +1,309 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Routes HTTP requests on a single forwarded port to several local services,
+//! chosen by the request's host name or path. This lets many HTTP services
+//! share one tunnel endpoint, and so one origin, instead of each taking a
+//! port of their own.
+
+use std::{
+	convert::Infallible,
+	sync::{Arc, RwLock},
+};
+
+use hyper::{
+	client::conn::Builder,
+	header::{HeaderValue, HOST},
+	server::conn::Http,
+	service::service_fn,
+	Body, Request, Response, StatusCode, Uri,
+};
+use tokio::{
+	io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+	net::TcpStream,
+	sync::mpsc,
+};
+
+use crate::{
+	debug, log,
+	util::errors::{wrap, AnyError},
+};
+
+use super::{dev_tunnels::PortConnection, ip_filter::PortGate};
+
+/// Size of the buffer between a tunnel connection and the HTTP server.
+const PIPE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A local service reachable through the router.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostRoute {
+	/// Host name requests must be for, like `api.localhost`. This is matched
+	/// against `X-Forwarded-Host`, or `Host` if that's not set.
+	pub host: Option<String>,
+	/// Path prefix requests must be under, like `/api`. It's removed from
+	/// requests before they're passed on.
+	pub path: Option<String>,
+	/// Local port the service listens on.
+	pub port: u16,
+}
+
+impl HostRoute {
+	pub fn new(host: Option<String>, path: Option<String>, port: u16) -> Self {
+		HostRoute {
+			host: host.map(|h| h.to_lowercase()),
+			path: path.map(|p| format!("/{}", p.trim_matches('/'))),
+			port,
+		}
+	}
+
+	/// Gets whether the routes would match the same requests.
+	fn same_match(&self, other: &HostRoute) -> bool {
+		self.host == other.host && self.path == other.path
+	}
+
+	/// Gets the length of the path prefix the request matches with, or None
+	/// if it doesn't match.
+	fn match_len(&self, host: Option<&str>, path: &str) -> Option<usize> {
+		if let Some(h) = &self.host {
+			if host != Some(h.as_str()) {
+				return None;
+			}
+		}
+
+		match self.path.as_deref() {
+			None | Some("/") => Some(0),
+			Some(p) if path == p => Some(p.len()),
+			Some(p) if path.starts_with(p) && path[p.len()..].starts_with('/') => Some(p.len()),
+			Some(_) => None,
+		}
+	}
+}
+
+/// Routes shared between the forwarder, which changes them, and the router.
+#[derive(Clone, Default)]
+pub struct HostRoutes(Arc<RwLock<Vec<HostRoute>>>);
+
+impl HostRoutes {
+	/// Adds the route, replacing any that matches the same requests.
+	pub fn add(&self, route: HostRoute) {
+		let mut routes = self.0.write().unwrap();
+		routes.retain(|r| !r.same_match(&route));
+		routes.push(route);
+	}
+
+	/// Removes routes to the port, returning whether there were any.
+	pub fn remove_port(&self, port: u16) -> bool {
+		let mut routes = self.0.write().unwrap();
+		let len = routes.len();
+		routes.retain(|r| r.port != port);
+		routes.len() != len
+	}
+
+	/// Finds the route for a request, preferring routes with a host, then
+	/// those with the longest path. Returns the port and the length of the
+	/// path prefix to remove.
+	fn resolve(&self, host: Option<&str>, path: &str) -> Option<(u16, usize)> {
+		self.0
+			.read()
+			.unwrap()
+			.iter()
+			.filter_map(|r| r.match_len(host, path).map(|l| (r, l)))
+			.max_by_key(|(r, l)| (r.host.is_some(), *l))
+			.map(|(r, l)| (r.port, l))
+	}
+}
+
+/// Serves router connections from the tunnel until it's closed, rejecting
+/// requests from clients the gate doesn't allow.
+pub async fn serve_host_router(
+	log: log::Logger,
+	mut connections: mpsc::UnboundedReceiver<PortConnection>,
+	routes: HostRoutes,
+	gate: PortGate,
+) {
+	while let Some(conn) = connections.recv().await {
+		let log = log.clone();
+		let routes = routes.clone();
+		let gate = gate.clone();
+		tokio::spawn(async move {
+			let (writehalf, readhalf) = conn.into_split();
+			if let Err(e) = serve_connection(readhalf, writehalf, routes, gate).await {
+				debug!(log, "Routed connection closed: {}", e);
+			}
+		});
+	}
+}
+
+async fn serve_connection(
+	mut readhalf: impl AsyncRead + Send + Unpin + 'static,
+	mut writehalf: impl AsyncWrite + Send + Unpin + 'static,
+	routes: HostRoutes,
+	gate: PortGate,
+) -> Result<(), AnyError> {
+	// Hyper needs a single duplex stream
+	let (local, remote) = tokio::io::duplex(PIPE_BUFFER_SIZE);
+	let (mut local_read, mut local_write) = tokio::io::split(local);
+	tokio::spawn(async move {
+		tokio::io::copy(&mut readhalf, &mut local_write).await.ok();
+		local_write.shutdown().await.ok();
+	});
+	tokio::spawn(async move {
+		tokio::io::copy(&mut local_read, &mut writehalf).await.ok();
+		writehalf.shutdown().await.ok();
+	});
+
+	Http::new()
+		.http1_only(true)
+		.serve_connection(
+			remote,
+			service_fn(move |req| {
+				let routes = routes.clone();
+				let rejected = gate.check(&req).err();
+				async move {
+					Ok::<_, Infallible>(match rejected {
+						Some(r) => r,
+						None => route_request(req, &routes).await,
+					})
+				}
+			}),
+		)
+		.with_upgrades()
+		.await
+		.map_err(|e| wrap(e, "error serving routed connection").into())
+}
+
+/// Gets the host the request was made for, without any port.
+fn request_host(req: &Request<Body>) -> Option<String> {
+	let header = req
+		.headers()
+		.get("x-forwarded-host")
+		.or_else(|| req.headers().get(HOST))?
+		.to_str()
+		.ok()?;
+
+	// a proxy may have appended to the forwarded host; the first is the client's
+	let host = header.split(',').next()?.trim();
+	let host = match host.rsplit_once(':') {
+		Some((h, p)) if p.chars().all(|c| c.is_ascii_digit()) => h,
+		_ => host,
+	};
+
+	Some(host.to_lowercase())
+}
+
+async fn route_request(mut req: Request<Body>, routes: &HostRoutes) -> Response<Body> {
+	let host = request_host(&req);
+	let (port, prefix_len) = match routes.resolve(host.as_deref(), req.uri().path()) {
+		Some(r) => r,
+		None => {
+			return error_response(
+				StatusCode::NOT_FOUND,
+				"No forwarded service matches this host or path.",
+			)
+		}
+	};
+
+	if prefix_len > 0 {
+		let path = &req.uri().path()[prefix_len..];
+		let path_and_query = match req.uri().query() {
+			Some(q) => format!("{}?{}", if path.is_empty() { "/" } else { path }, q),
+			None => (if path.is_empty() { "/" } else { path }).to_string(),
+		};
+		if let Ok(uri) = path_and_query.parse::<Uri>() {
+			*req.uri_mut() = uri;
+		}
+	}
+
+	// services often check the host, so present the request as a local one
+	if let Some(h) = host.and_then(|h| HeaderValue::from_str(&h).ok()) {
+		req.headers_mut().insert("x-forwarded-host", h);
+	}
+	if let Ok(h) = HeaderValue::from_str(&format!("localhost:{}", port)) {
+		req.headers_mut().insert(HOST, h);
+	}
+
+	match relay(req, port).await {
+		Ok(res) => res,
+		Err(e) => error_response(
+			StatusCode::BAD_GATEWAY,
+			&format!("Error connecting to the forwarded service: {}", e),
+		),
+	}
+}
+
+async fn relay(mut req: Request<Body>, port: u16) -> Result<Response<Body>, AnyError> {
+	let stream = TcpStream::connect(("127.0.0.1", port))
+		.await
+		.map_err(|e| wrap(e, "error connecting to port"))?;
+	let (mut request_sender, connection) = Builder::new()
+		.handshake(stream)
+		.await
+		.map_err(|e| wrap(e, "error establishing connection"))?;
+
+	// start the connection processing; it's shut down when the sender is dropped
+	tokio::spawn(connection);
+
+	// websockets and other upgrades are joined once both sides have upgraded
+	let client_upgrade = hyper::upgrade::on(&mut req);
+	let mut res = request_sender
+		.send_request(req)
+		.await
+		.map_err(|e| wrap(e, "error sending request"))?;
+
+	if res.status() == StatusCode::SWITCHING_PROTOCOLS {
+		let service_upgrade = hyper::upgrade::on(&mut res);
+		tokio::spawn(async move {
+			if let (Ok(mut client), Ok(mut service)) = (client_upgrade.await, service_upgrade.await)
+			{
+				tokio::io::copy_bidirectional(&mut client, &mut service)
+					.await
+					.ok();
+			}
+		});
+	}
+
+	Ok(res)
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+	Response::builder()
+		.status(status)
+		.header(hyper::header::CONTENT_TYPE, "text/plain")
+		.body(Body::from(message.to_string()))
+		.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_resolve() {
+		let routes = HostRoutes::default();
+		routes.add(HostRoute::new(None, None, 3000));
+		routes.add(HostRoute::new(None, Some("api/".to_string()), 4000));
+		routes.add(HostRoute::new(
+			Some("Docs.localhost".to_string()),
+			None,
+			5000,
+		));
+
+		assert_eq!(routes.resolve(None, "/"), Some((3000, 0)));
+		assert_eq!(routes.resolve(None, "/api"), Some((4000, 4)));
+		assert_eq!(routes.resolve(None, "/api/users"), Some((4000, 4)));
+		assert_eq!(routes.resolve(None, "/apis"), Some((3000, 0)));
+		assert_eq!(
+			routes.resolve(Some("docs.localhost"), "/api"),
+			Some((5000, 0))
+		);
+
+		routes.add(HostRoute::new(None, Some("/api".to_string()), 4001));
+		assert_eq!(routes.resolve(None, "/api"), Some((4001, 4)));
+
+		assert!(routes.remove_port(3000));
+		assert!(!routes.remove_port(3000));
+		assert_eq!(routes.resolve(None, "/"), None);
+	}
+}
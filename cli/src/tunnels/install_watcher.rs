@@ -0,0 +1,132 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Watches the install of a running server for files being changed or
+//! removed, as security agents that quarantine files sometimes do. The server
+//! then fails in ways that are hard to trace back, so the file is logged as
+//! soon as it changes, and the install is verified before it's next used.
+
+use std::{collections::HashSet, path::PathBuf, time::Duration};
+
+use notify::{
+	event::{MetadataKind, ModifyKind},
+	Event, EventKind, RecursiveMode, Watcher,
+};
+use tokio::sync::mpsc;
+
+use crate::{debug, log, util::machine::process_exists, warning};
+
+use super::paths::ServerPaths;
+
+/// How often to check whether the server has exited, to stop watching.
+const EXIT_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Watches the server's install until the process exits.
+pub async fn watch_install(log: log::Logger, paths: ServerPaths, pid: u32) {
+	let (tx, mut rx) = mpsc::unbounded_channel();
+	let mut watcher = match notify::recommended_watcher(move |res: notify::Result<Event>| {
+		if let Ok(event) = res {
+			tx.send(event).ok();
+		}
+	}) {
+		Ok(w) => w,
+		Err(e) => {
+			debug!(log, "Could not watch the server install: {}", e);
+			return;
+		}
+	};
+
+	if let Err(e) = watcher.watch(&paths.server_dir, RecursiveMode::Recursive) {
+		debug!(log, "Could not watch {}: {}", paths.server_dir.display(), e);
+		return;
+	}
+
+	let mut reported = HashSet::new();
+	let mut exit_check = tokio::time::interval(EXIT_CHECK_INTERVAL);
+	loop {
+		tokio::select! {
+			Some(event) = rx.recv() => {
+				if let Some(action) = describe(&event.kind) {
+					for path in event.paths {
+						report_change(&log, &paths, &mut reported, path, action);
+					}
+				}
+			},
+			_ = exit_check.tick() => {
+				if !process_exists(pid) {
+					break;
+				}
+			},
+		}
+	}
+
+	// dropping the watcher stops it
+	drop(watcher);
+}
+
+/// Describes changes that can break the install. Reads and changes to
+/// access times, which happen in normal use, are ignored.
+fn describe(kind: &EventKind) -> Option<&'static str> {
+	match kind {
+		EventKind::Remove(_) => Some("removed"),
+		EventKind::Modify(ModifyKind::Name(_)) => Some("moved"),
+		EventKind::Modify(ModifyKind::Data(_)) => Some("modified"),
+		EventKind::Modify(ModifyKind::Metadata(
+			MetadataKind::Permissions | MetadataKind::Ownership,
+		)) => Some("had its permissions changed"),
+		_ => None,
+	}
+}
+
+fn report_change(
+	log: &log::Logger,
+	paths: &ServerPaths,
+	reported: &mut HashSet<PathBuf>,
+	path: PathBuf,
+	action: &str,
+) {
+	if paths.is_cli_file(&path) || !reported.insert(path.clone()) {
+		return;
+	}
+
+	warning!(
+		log,
+		"Server install file {} was {} while the server was running. The install will be verified before it's next used.",
+		path.display(),
+		action
+	);
+	if let Err(e) = paths.mark_for_verification(&path) {
+		warning!(log, "Could not mark the install for verification: {}", e);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use notify::event::{CreateKind, DataChange, RemoveKind, RenameMode};
+
+	#[test]
+	fn test_describe() {
+		assert_eq!(
+			describe(&EventKind::Remove(RemoveKind::File)),
+			Some("removed")
+		);
+		assert_eq!(
+			describe(&EventKind::Modify(ModifyKind::Name(RenameMode::From))),
+			Some("moved")
+		);
+		assert_eq!(
+			describe(&EventKind::Modify(ModifyKind::Data(DataChange::Content))),
+			Some("modified")
+		);
+		assert_eq!(
+			describe(&EventKind::Modify(ModifyKind::Metadata(
+				MetadataKind::AccessTime
+			))),
+			None
+		);
+		assert_eq!(describe(&EventKind::Create(CreateKind::File)), None);
+	}
+}
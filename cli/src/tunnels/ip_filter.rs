@@ -0,0 +1,292 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	fmt,
+	fs::OpenOptions,
+	io::Write,
+	net::{IpAddr, Ipv6Addr},
+	str::FromStr,
+};
+
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Serialize;
+
+use crate::{log, state::LauncherPaths, warning};
+
+/// Header the relay adds the client's address to on HTTP requests made
+/// through the tunnel.
+const FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// A block of addresses, like `10.0.0.0/8` or `2001:db8::/32`. A bare
+/// address matches only itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cidr {
+	addr: IpAddr,
+	prefix_len: u8,
+}
+
+impl Cidr {
+	pub fn contains(&self, addr: &IpAddr) -> bool {
+		let (net, addr, bits) = match (self.addr, to_canonical(addr)) {
+			(IpAddr::V4(net), IpAddr::V4(addr)) => {
+				(u32::from(net) as u128, u32::from(addr) as u128, 32)
+			}
+			(IpAddr::V6(net), IpAddr::V6(addr)) => (u128::from(net), u128::from(addr), 128),
+			_ => return false,
+		};
+
+		let host_bits = bits - self.prefix_len as u32;
+		if host_bits >= bits {
+			return true;
+		}
+
+		(net >> host_bits) == (addr >> host_bits)
+	}
+}
+
+/// Treats IPv4-mapped IPv6 addresses as the IPv4 address they contain, so
+/// rules written for IPv4 apply to them.
+fn to_canonical(addr: &IpAddr) -> IpAddr {
+	match addr {
+		IpAddr::V6(v6) => match v6.to_ipv4() {
+			Some(v4) if is_v4_mapped(v6) => IpAddr::V4(v4),
+			_ => *addr,
+		},
+		a => *a,
+	}
+}
+
+fn is_v4_mapped(addr: &Ipv6Addr) -> bool {
+	matches!(addr.segments(), [0, 0, 0, 0, 0, 0xffff, _, _])
+}
+
+impl FromStr for Cidr {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (addr, prefix_len) = match s.trim().split_once('/') {
+			Some((a, p)) => (a, Some(p)),
+			None => (s.trim(), None),
+		};
+
+		let addr =
+			to_canonical(&addr.parse::<IpAddr>().map_err(|_| {
+				format!("expected an address range like '10.0.0.0/8', got '{}'", s)
+			})?);
+		let max_len = if addr.is_ipv4() { 32 } else { 128 };
+		let prefix_len = match prefix_len {
+			Some(p) => p
+				.parse::<u8>()
+				.ok()
+				.filter(|p| *p <= max_len)
+				.ok_or_else(|| format!("invalid prefix length in '{}'", s))?,
+			None => max_len,
+		};
+
+		Ok(Cidr { addr, prefix_len })
+	}
+}
+
+impl fmt::Display for Cidr {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}/{}", self.addr, self.prefix_len)
+	}
+}
+
+/// Allow and deny lists checked against the address the relay observed a
+/// client connecting from. Addresses clients report themselves are never
+/// trusted. Denied ranges take precedence over allowed ones.
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+	pub allow: Vec<Cidr>,
+	pub deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+	pub fn is_empty(&self) -> bool {
+		self.allow.is_empty() && self.deny.is_empty()
+	}
+
+	/// Checks whether a client at the address may connect, returning the
+	/// reason if it may not. Clients whose address the relay didn't report
+	/// are rejected when any rules are configured.
+	pub fn check(&self, addr: Option<&str>) -> Result<IpAddr, String> {
+		let addr = match addr.map(|a| a.parse::<IpAddr>()) {
+			Some(Ok(a)) => a,
+			Some(Err(_)) => return Err("the relay reported an invalid address".to_string()),
+			None => return Err("the relay did not report the client's address".to_string()),
+		};
+
+		if let Some(rule) = self.deny.iter().find(|r| r.contains(&addr)) {
+			return Err(format!("address is in denied range {}", rule));
+		}
+
+		if !self.allow.is_empty() && !self.allow.iter().any(|r| r.contains(&addr)) {
+			return Err("address is not in an allowed range".to_string());
+		}
+
+		Ok(addr)
+	}
+}
+
+/// Checks requests to tunnel ports other than the control port against an
+/// `IpFilter`. Clients don't report their address on these ports, so the one
+/// the relay adds to each HTTP request is checked instead. Connections that
+/// aren't HTTP carry no address and can't be checked.
+#[derive(Clone)]
+pub struct PortGate {
+	log: log::Logger,
+	paths: LauncherPaths,
+	filter: IpFilter,
+}
+
+impl PortGate {
+	pub fn new(log: log::Logger, paths: LauncherPaths, filter: IpFilter) -> Self {
+		PortGate { log, paths, filter }
+	}
+
+	/// Gets whether any address may connect.
+	pub fn is_open(&self) -> bool {
+		self.filter.is_empty()
+	}
+
+	/// Checks the request, returning the response to reject it with if the
+	/// client's address isn't allowed.
+	pub fn check<B>(&self, req: &Request<B>) -> Result<(), Response<Body>> {
+		if self.is_open() {
+			return Ok(());
+		}
+
+		let address = forwarded_address(req);
+		let reason = match self.filter.check(address.as_deref()) {
+			Ok(_) => return Ok(()),
+			Err(reason) => reason,
+		};
+
+		warning!(
+			self.log,
+			"Rejected request from {}: {}",
+			address.as_deref().unwrap_or("unknown address"),
+			reason
+		);
+		if let Err(e) = audit_rejected_connection(&self.paths, address.as_deref(), &reason) {
+			warning!(self.log, "Failed to write to the audit log: {}", e);
+		}
+
+		Err(Response::builder()
+			.status(StatusCode::FORBIDDEN)
+			.body(Body::from(format!("connection not allowed: {}", reason)))
+			.unwrap())
+	}
+}
+
+/// Gets the address of the client that made a request through the relay.
+/// The relay appends it to any `X-Forwarded-For` the client sent, so only
+/// the last entry can be trusted.
+fn forwarded_address<B>(req: &Request<B>) -> Option<String> {
+	let header = req.headers().get_all(FORWARDED_FOR).iter().last()?;
+	let address = header.to_str().ok()?.rsplit(',').next()?.trim();
+	Some(address.to_string())
+}
+
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+	time: chrono::DateTime<chrono::Utc>,
+	event: &'a str,
+	address: Option<&'a str>,
+	reason: &'a str,
+}
+
+/// Appends a line recording a rejected connection to the audit log. Failures
+/// are returned so they can be logged, but shouldn't stop the rejection.
+pub fn audit_rejected_connection(
+	paths: &LauncherPaths,
+	address: Option<&str>,
+	reason: &str,
+) -> std::io::Result<()> {
+	let entry = AuditEntry {
+		time: chrono::Utc::now(),
+		event: "connection_rejected",
+		address,
+		reason,
+	};
+
+	let mut file = OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(paths.audit_log_file())?;
+	writeln!(file, "{}", serde_json::to_string(&entry).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn filter(allow: &[&str], deny: &[&str]) -> IpFilter {
+		IpFilter {
+			allow: allow.iter().map(|s| s.parse().unwrap()).collect(),
+			deny: deny.iter().map(|s| s.parse().unwrap()).collect(),
+		}
+	}
+
+	#[test]
+	fn test_parses_cidr() {
+		assert_eq!(
+			"10.0.0.0/8".parse::<Cidr>().unwrap().to_string(),
+			"10.0.0.0/8"
+		);
+		assert_eq!("::1".parse::<Cidr>().unwrap().to_string(), "::1/128");
+		assert_eq!(
+			"0.0.0.0/0".parse::<Cidr>().unwrap().to_string(),
+			"0.0.0.0/0"
+		);
+		assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+		assert!("example.com".parse::<Cidr>().is_err());
+	}
+
+	#[test]
+	fn test_checks_rules() {
+		let f = filter(&["10.0.0.0/8", "2001:db8::/32"], &["10.1.0.0/16"]);
+		assert!(f.check(Some("10.2.3.4")).is_ok());
+		assert!(f.check(Some("::ffff:10.2.3.4")).is_ok());
+		assert!(f.check(Some("2001:db8::1")).is_ok());
+		assert!(f.check(Some("10.1.2.3")).is_err());
+		assert!(f.check(Some("192.168.0.1")).is_err());
+		assert!(f.check(Some("nope")).is_err());
+		assert!(f.check(None).is_err());
+
+		let deny_only = filter(&[], &["192.168.0.0/16"]);
+		assert!(deny_only.check(Some("8.8.8.8")).is_ok());
+		assert!(deny_only.check(Some("192.168.1.1")).is_err());
+
+		let everything = filter(&["0.0.0.0/0"], &[]);
+		assert!(everything.check(Some("1.2.3.4")).is_ok());
+	}
+
+	#[test]
+	fn test_gates_requests() {
+		let dir = tempfile::tempdir().unwrap();
+		let gate = PortGate::new(
+			log::Logger::test(),
+			LauncherPaths::new_without_replacements(dir.path().to_owned()),
+			filter(&["10.0.0.0/8"], &[]),
+		);
+		let request = |forwarded_for: Option<&str>| {
+			let mut req = Request::builder();
+			if let Some(f) = forwarded_for {
+				req = req.header(FORWARDED_FOR, f);
+			}
+			req.body(()).unwrap()
+		};
+
+		assert!(gate.check(&request(Some("10.1.2.3"))).is_ok());
+		// the client can't pick the address by sending its own header
+		assert!(gate.check(&request(Some("10.1.2.3, 192.168.0.1"))).is_err());
+		assert!(gate.check(&request(Some("192.168.0.1, 10.1.2.3"))).is_ok());
+		assert!(gate.check(&request(None)).is_err());
+		assert!(dir.path().join("audit.log").exists());
+	}
+}
@@ -5,7 +5,7 @@
 use crate::constants::PRODUCT_NAME_LONG;
 use crate::state::{LauncherPaths, PersistedState};
 use crate::util::errors::{AnyError, MissingLegalConsent};
-use crate::util::input::prompt_yn;
+use crate::util::input::{is_assume_yes, prompt_yn};
 use serde::{Deserialize, Serialize};
 
 const LICENSE_TEXT: Option<&'static str> = option_env!("VSCODE_CLI_REMOTE_LICENSE_TEXT");
@@ -41,6 +41,13 @@ pub fn require_consent(
 	let mut load = license.load();
 
 	if !load.consented.unwrap_or(false) {
+		// `--yes` answers prompts, but terms have to be accepted explicitly
+		if is_assume_yes() {
+			return Err(AnyError::from(MissingLegalConsent(
+				"Pass --accept-server-license-terms to accept the license terms when running unattended.".to_string(),
+			)));
+		}
+
 		match prompt_yn(prompt) {
 			Ok(true) => {
 				save = true;
@@ -62,3 +69,25 @@ pub fn require_consent(
 
 	Ok(())
 }
+
+/// Like `require_consent`, but for callers that can't show the terms or
+/// prompt, such as `command-shell`. Consent given through
+/// `accept_server_license_terms` is not remembered.
+pub fn check_consent(
+	paths: &LauncherPaths,
+	accept_server_license_terms: bool,
+) -> Result<(), AnyError> {
+	if LICENSE_TEXT.is_none() || LICENSE_PROMPT.is_none() || accept_server_license_terms {
+		return Ok(());
+	}
+
+	let license: PersistedState<PersistedConsent> =
+		PersistedState::new(paths.root().join("license_consent.json"));
+	if license.load().consented.unwrap_or(false) {
+		return Ok(());
+	}
+
+	Err(AnyError::from(MissingLegalConsent(
+		"the server license terms must be accepted before hosting a tunnel".to_string(),
+	)))
+}
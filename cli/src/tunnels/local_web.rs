@@ -0,0 +1,289 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	fs,
+	net::{IpAddr, Ipv4Addr, Ipv6Addr},
+	path::{Path, PathBuf},
+};
+
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::{
+	info, log,
+	options::{ConnectionTokenMode, Quality},
+	state::LauncherPaths,
+	update_service::{Platform, UpdateServiceCache},
+	util::{
+		command::kill_tree,
+		errors::{wrap, AnyError, ConnectionTokenRequired, MismatchConnectionToken},
+		http::ReqwestSimpleHttp,
+		tempfile::write_file_atomic,
+	},
+};
+
+use super::code_server::{AnyCodeServer, CodeServerArgs, PortCodeServer, ServerBuilder};
+use super::server_selection::ServerSelection;
+
+/// File the token is read from, unless another is given.
+const LOCAL_WEB_TOKEN_FILE: &str = "local-web-token";
+
+/// Options for serving the web UI locally alongside the tunnel.
+pub struct LocalWebOptions {
+	pub host: String,
+	pub port: u16,
+	pub quality: Quality,
+	/// Server selected on the command line, used over `quality`.
+	pub selection: ServerSelection,
+	pub token_mode: ConnectionTokenMode,
+	/// File to read the token from in `ConnectionTokenMode::FixedFromFile`.
+	pub token_file: Option<PathBuf>,
+}
+
+/// Web server started by `start_local_web`.
+pub struct LocalWebServer {
+	/// Asks the task watching the server to stop it, and is told once it has.
+	stop_tx: oneshot::Sender<oneshot::Sender<()>>,
+	/// URL at which the web UI can be opened, including its connection token.
+	pub url: String,
+}
+
+impl LocalWebServer {
+	/// Starts a task that stops the server when asked, so it can be stopped
+	/// however many handles to it are held.
+	fn new(server: PortCodeServer, url: String) -> Self {
+		let (stop_tx, stop_rx) = oneshot::channel::<oneshot::Sender<()>>();
+		tokio::spawn(async move {
+			let done = stop_rx.await.ok();
+			stop_server(&server).await;
+			if let Some(done) = done {
+				done.send(()).ok();
+			}
+		});
+
+		Self { stop_tx, url }
+	}
+
+	/// Stops the server, if it's still running.
+	pub async fn kill(self) {
+		let (done_tx, done_rx) = oneshot::channel();
+		if self.stop_tx.send(done_tx).is_ok() {
+			done_rx.await.ok();
+		}
+	}
+}
+
+async fn stop_server(server: &PortCodeServer) {
+	if let Some(pid) = server.origin.pid() {
+		kill_tree(pid).await.ok();
+	}
+}
+
+/// Starts a web server for the latest release of the quality, listening on a
+/// local address. It's run by the same daemon that hosts the tunnel, so it
+/// shares its launcher directories, downloads, and update cache rather than
+/// competing with a second process.
+pub async fn start_local_web(
+	log: &log::Logger,
+	launcher_paths: &LauncherPaths,
+	code_server_args: &CodeServerArgs,
+	platform: Platform,
+	update_cache: Option<UpdateServiceCache>,
+	options: LocalWebOptions,
+) -> Result<LocalWebServer, AnyError> {
+	let mut args = code_server_args.clone();
+	args.host = Some(options.host.clone());
+	args.port = Some(options.port);
+	args.socket_path = None;
+	args.connection_token_mode = Some(options.token_mode);
+	args.connection_token = None;
+	args.connection_token_file = None;
+
+	// The web UI may be exposed beyond this machine, so a token is required
+	// unless it's only reachable over loopback, like behind an auth proxy.
+	let token = match options.token_mode {
+		ConnectionTokenMode::None => {
+			if !is_loopback_host(&options.host) {
+				return Err(ConnectionTokenRequired(options.host.clone()).into());
+			}
+			None
+		}
+		ConnectionTokenMode::RotatePerStart => {
+			let token = Uuid::new_v4().to_string();
+			args.connection_token = Some(token.clone());
+			Some(token)
+		}
+		ConnectionTokenMode::FixedFromFile => {
+			let file = options
+				.token_file
+				.clone()
+				.unwrap_or_else(|| launcher_paths.root().join(LOCAL_WEB_TOKEN_FILE));
+			let token = read_or_create_token(&file)?;
+			args.connection_token_file = Some(file.to_string_lossy().to_string());
+			Some(token)
+		}
+	};
+
+	let http = ReqwestSimpleHttp::new();
+	let resolved = options
+		.selection
+		.params(launcher_paths, None, options.quality, args, false, platform)?
+		.resolve(log, http.clone(), update_cache)
+		.await?;
+
+	// A server left running by an earlier process is reused only if it was
+	// started with the same token mode, otherwise this reports the mismatch,
+	// and only if it accepts this token, since it may have been started with
+	// another one.
+	let sb = ServerBuilder::new(log, &resolved, launcher_paths, http);
+	let server = match sb.get_running().await? {
+		Some(AnyCodeServer::Port(s))
+			if accepts_token(&options.host, s.port, token.as_deref()).await =>
+		{
+			s
+		}
+		Some(AnyCodeServer::Port(s)) => {
+			info!(
+				log,
+				"Restarting the web server left running by an earlier process, since it doesn't accept this connection token"
+			);
+			stop_server(&s).await;
+			sb.listen_on_port().await?
+		}
+		_ => {
+			sb.setup().await?;
+			sb.listen_on_port().await?
+		}
+	};
+
+	if !accepts_token(&options.host, server.port, token.as_deref()).await {
+		stop_server(&server).await;
+		return Err(MismatchConnectionToken(
+			"The web server didn't accept its connection token".to_string(),
+		)
+		.into());
+	}
+
+	let url = local_url(&options.host, server.port, token.as_deref());
+	Ok(LocalWebServer::new(server, url))
+}
+
+/// Gets whether the server on the port answers a request with the token,
+/// rather than turning it away. Redirects aren't followed, since the server
+/// redirects to drop the token from the URL once it's accepted.
+async fn accepts_token(host: &str, port: u16, token: Option<&str>) -> bool {
+	let client = match reqwest::Client::builder()
+		.redirect(reqwest::redirect::Policy::none())
+		.no_proxy()
+		.build()
+	{
+		Ok(c) => c,
+		Err(_) => return false,
+	};
+
+	match client
+		.get(local_url(&probe_host(host), port, token))
+		.send()
+		.await
+	{
+		Ok(res) => {
+			let status = res.status();
+			status.is_success() || status.is_redirection()
+		}
+		Err(_) => false,
+	}
+}
+
+/// Gets the host to reach the server on from this machine, which for a
+/// server listening on all addresses is the loopback address.
+fn probe_host(host: &str) -> String {
+	match host
+		.trim_start_matches('[')
+		.trim_end_matches(']')
+		.parse::<IpAddr>()
+	{
+		Ok(IpAddr::V4(ip)) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.to_string(),
+		Ok(IpAddr::V6(ip)) if ip.is_unspecified() => Ipv6Addr::LOCALHOST.to_string(),
+		_ => host.to_string(),
+	}
+}
+
+/// Gets the URL of the web UI, bracketing IPv6 addresses as URLs need.
+fn local_url(host: &str, port: u16, token: Option<&str>) -> String {
+	let host = match host.parse::<Ipv6Addr>() {
+		Ok(ip) => format!("[{}]", ip),
+		Err(_) => host.to_string(),
+	};
+
+	match token {
+		Some(t) => format!("http://{}:{}/?tkn={}", host, port, t),
+		None => format!("http://{}:{}/", host, port),
+	}
+}
+
+/// Gets whether the host can only be reached from this machine.
+fn is_loopback_host(host: &str) -> bool {
+	if host.eq_ignore_ascii_case("localhost") {
+		return true;
+	}
+
+	host.trim_start_matches('[')
+		.trim_end_matches(']')
+		.parse::<IpAddr>()
+		.map(|ip| ip.is_loopback())
+		.unwrap_or(false)
+}
+
+/// Reads the token from the file, creating it with a new token if it doesn't
+/// exist.
+fn read_or_create_token(file: &Path) -> Result<String, AnyError> {
+	if let Ok(s) = fs::read_to_string(file) {
+		let token = s.trim();
+		if !token.is_empty() {
+			return Ok(token.to_string());
+		}
+	}
+
+	let token = Uuid::new_v4().to_string();
+
+	write_file_atomic(file, token.as_bytes())
+		.map_err(|e| wrap(e, format!("error writing token to {}", file.display())))?;
+	Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_local_url() {
+		assert_eq!(
+			local_url("127.0.0.1", 8000, Some("abc")),
+			"http://127.0.0.1:8000/?tkn=abc"
+		);
+		assert_eq!(local_url("::1", 8000, None), "http://[::1]:8000/");
+		assert_eq!(local_url("[::1]", 8000, None), "http://[::1]:8000/");
+		assert_eq!(local_url("localhost", 8000, None), "http://localhost:8000/");
+	}
+
+	#[test]
+	fn test_probe_host() {
+		assert_eq!(probe_host("0.0.0.0"), "127.0.0.1");
+		assert_eq!(probe_host("::"), "::1");
+		assert_eq!(probe_host("[::]"), "::1");
+		assert_eq!(probe_host("example.com"), "example.com");
+	}
+
+	#[test]
+	fn test_is_loopback_host() {
+		assert!(is_loopback_host("localhost"));
+		assert!(is_loopback_host("127.0.0.1"));
+		assert!(is_loopback_host("::1"));
+		assert!(is_loopback_host("[::1]"));
+		assert!(!is_loopback_host("0.0.0.0"));
+		assert!(!is_loopback_host("example.com"));
+	}
+}
@@ -0,0 +1,162 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Identity of this machine, stored in the CLI's data directory and recorded
+//! with the tunnel it registers. VMs cloned from an image that already hosted
+//! a tunnel share an identity, and the tunnel, until one of them rotates it.
+//!
+//! To notice clones, the identity is bound to a fingerprint of the OS's own
+//! machine ID, which cloning tools and cloud images regenerate. If it stops
+//! matching, the machine refuses to host until the user either rotates the
+//! identity or accepts the new fingerprint.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	state::{LauncherPaths, PersistedState},
+	util::errors::{MachineIdentityMismatch, WrappedError},
+};
+
+use super::dev_tunnels::PersistedTunnel;
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct PersistedMachineId {
+	id: Option<String>,
+	created_at: Option<DateTime<Utc>>,
+	/// OS machine ID when the identity was created or last accepted.
+	#[serde(default)]
+	fingerprint: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MachineIdentity {
+	pub id: String,
+	pub created_at: DateTime<Utc>,
+}
+
+fn machine_id_state(paths: &LauncherPaths) -> PersistedState<PersistedMachineId> {
+	PersistedState::new(paths.root().join("machine-id.json"))
+}
+
+/// Gets this machine's identity, creating one if it doesn't have one yet.
+pub fn get_machine_identity(paths: &LauncherPaths) -> Result<MachineIdentity, WrappedError> {
+	match machine_id_state(paths).load() {
+		PersistedMachineId {
+			id: Some(id),
+			created_at: Some(created_at),
+			..
+		} => Ok(MachineIdentity { id, created_at }),
+		_ => rotate_machine_identity(paths),
+	}
+}
+
+/// Replaces this machine's identity with a new one.
+pub fn rotate_machine_identity(paths: &LauncherPaths) -> Result<MachineIdentity, WrappedError> {
+	let identity = MachineIdentity {
+		id: uuid::Uuid::new_v4().to_string(),
+		created_at: Utc::now(),
+	};
+
+	machine_id_state(paths).save(PersistedMachineId {
+		id: Some(identity.id.clone()),
+		created_at: Some(identity.created_at),
+		fingerprint: get_os_machine_id(),
+	})?;
+
+	Ok(identity)
+}
+
+/// Binds this machine's identity to its current fingerprint, for when it's
+/// known not to be a clone, such as after its OS was reinstalled.
+pub fn accept_machine_fingerprint(paths: &LauncherPaths) -> Result<(), WrappedError> {
+	let state = machine_id_state(paths);
+	let mut persisted = state.load();
+	persisted.fingerprint = get_os_machine_id();
+	state.save(persisted)
+}
+
+/// Checks that the tunnel was registered by this machine, rather than one
+/// whose data was copied here. Identities made before fingerprints were
+/// recorded are bound to this machine's on first check.
+pub fn check_machine_identity(
+	paths: &LauncherPaths,
+	tunnel: &PersistedTunnel,
+) -> Result<(), MachineIdentityMismatch> {
+	let state = machine_id_state(paths);
+	let mut persisted = state.load();
+
+	if let (Some(registered), Some(current)) = (&tunnel.machine_id, &persisted.id) {
+		if registered != current {
+			return Err(MachineIdentityMismatch(format!(
+				"The tunnel {} was registered by another machine",
+				tunnel.name
+			)));
+		}
+	}
+
+	let current = match get_os_machine_id() {
+		Some(f) => f,
+		None => return Ok(()),
+	};
+
+	match &persisted.fingerprint {
+		Some(f) if *f != current => Err(MachineIdentityMismatch(format!(
+			"The tunnel {} was registered on a machine with a different OS machine ID",
+			tunnel.name
+		))),
+		Some(_) => Ok(()),
+		None => {
+			persisted.fingerprint = Some(current);
+			state.save(persisted).ok();
+			Ok(())
+		}
+	}
+}
+
+/// Gets the ID the OS assigns the machine, if there is one.
+#[cfg(target_os = "linux")]
+fn get_os_machine_id() -> Option<String> {
+	["/etc/machine-id", "/var/lib/dbus/machine-id"]
+		.iter()
+		.filter_map(|p| std::fs::read_to_string(p).ok())
+		.map(|s| s.trim().to_string())
+		.find(|s| !s.is_empty())
+}
+
+/// Gets the ID the OS assigns the machine, if there is one.
+#[cfg(target_os = "macos")]
+fn get_os_machine_id() -> Option<String> {
+	let output = std::process::Command::new("ioreg")
+		.args(["-rd1", "-c", "IOPlatformExpertDevice"])
+		.output()
+		.ok()?;
+
+	String::from_utf8_lossy(&output.stdout)
+		.lines()
+		.find(|l| l.contains("\"IOPlatformUUID\""))
+		.and_then(|l| l.split('"').nth(3))
+		.map(|s| s.to_string())
+}
+
+/// Gets the ID the OS assigns the machine, if there is one.
+#[cfg(windows)]
+fn get_os_machine_id() -> Option<String> {
+	use winreg::enums::{HKEY_LOCAL_MACHINE, KEY_READ, KEY_WOW64_64KEY};
+	use winreg::RegKey;
+
+	RegKey::predef(HKEY_LOCAL_MACHINE)
+		.open_subkey_with_flags(
+			"SOFTWARE\\Microsoft\\Cryptography",
+			KEY_READ | KEY_WOW64_64KEY,
+		)
+		.and_then(|k| k.get_value::<String, _>("MachineGuid"))
+		.ok()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn get_os_machine_id() -> Option<String> {
+	None
+}
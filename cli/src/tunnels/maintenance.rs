@@ -0,0 +1,161 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{fmt, str::FromStr};
+
+use chrono::{Datelike, Duration, NaiveDateTime, NaiveTime, Weekday};
+
+/// A recurring period, in local time, during which the daemon may apply
+/// updates and restart, disrupting sessions. Written like `sun 03:00-04:00`,
+/// `sat,sun 22:00-02:00`, or `03:00-04:00` for every day. Windows that end
+/// before they start run past midnight.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MaintenanceWindow {
+	/// Days the window starts on, or empty for every day.
+	days: Vec<Weekday>,
+	start: NaiveTime,
+	end: NaiveTime,
+}
+
+impl MaintenanceWindow {
+	fn starts_on(&self, day: Weekday) -> bool {
+		self.days.is_empty() || self.days.contains(&day)
+	}
+
+	/// Gets whether the local time is within the window.
+	pub fn contains(&self, now: NaiveDateTime) -> bool {
+		let time = now.time();
+		if self.start <= self.end {
+			return self.starts_on(now.weekday()) && time >= self.start && time < self.end;
+		}
+
+		(self.starts_on(now.weekday()) && time >= self.start)
+			|| (self.starts_on((now - Duration::days(1)).weekday()) && time < self.end)
+	}
+}
+
+impl FromStr for MaintenanceWindow {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (days, times) = match s.trim().rsplit_once(' ') {
+			Some((d, t)) => (d.trim(), t),
+			None => ("", s.trim()),
+		};
+
+		let (start, end) = times
+			.split_once('-')
+			.ok_or_else(|| format!("expected a time range like 03:00-04:00, got '{}'", times))?;
+		let parse_time = |t: &str| {
+			NaiveTime::parse_from_str(t, "%H:%M").map_err(|_| format!("invalid time '{}'", t))
+		};
+
+		let days = match days {
+			"" => vec![],
+			d => d
+				.split(',')
+				.map(|d| {
+					d.trim()
+						.parse::<Weekday>()
+						.map_err(|_| format!("invalid day '{}'", d))
+				})
+				.collect::<Result<Vec<_>, _>>()?,
+		};
+
+		Ok(MaintenanceWindow {
+			days,
+			start: parse_time(start)?,
+			end: parse_time(end)?,
+		})
+	}
+}
+
+impl fmt::Display for MaintenanceWindow {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if !self.days.is_empty() {
+			let days: Vec<String> = self.days.iter().map(|d| d.to_string()).collect();
+			write!(f, "{} ", days.join(",").to_lowercase())?;
+		}
+
+		write!(
+			f,
+			"{}-{}",
+			self.start.format("%H:%M"),
+			self.end.format("%H:%M")
+		)
+	}
+}
+
+/// The maintenance windows the daemon is configured with. Without any,
+/// maintenance may happen at any time.
+#[derive(Clone, Debug, Default)]
+pub struct MaintenanceWindows(pub Vec<MaintenanceWindow>);
+
+impl MaintenanceWindows {
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	/// Gets whether maintenance may happen at the local time.
+	pub fn allows(&self, now: NaiveDateTime) -> bool {
+		self.is_empty() || self.0.iter().any(|w| w.contains(now))
+	}
+
+	/// Gets whether maintenance may happen now.
+	pub fn allows_now(&self) -> bool {
+		self.allows(chrono::Local::now().naive_local())
+	}
+}
+
+impl fmt::Display for MaintenanceWindows {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let windows: Vec<String> = self.0.iter().map(|w| w.to_string()).collect();
+		write!(f, "{}", windows.join("; "))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::NaiveDate;
+
+	fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+		// 2023-01-01 is a Sunday
+		NaiveDate::from_ymd(2023, 1, day).and_hms(hour, minute, 0)
+	}
+
+	#[test]
+	fn test_parse() {
+		let w: MaintenanceWindow = "sat,sun 22:00-02:00".parse().unwrap();
+		assert_eq!(w.days, vec![Weekday::Sat, Weekday::Sun]);
+		assert_eq!(w.to_string(), "sat,sun 22:00-02:00");
+
+		let w: MaintenanceWindow = "03:00-04:00".parse().unwrap();
+		assert!(w.days.is_empty());
+
+		assert!("sun".parse::<MaintenanceWindow>().is_err());
+		assert!("funday 03:00-04:00".parse::<MaintenanceWindow>().is_err());
+		assert!("sun 3am-4am".parse::<MaintenanceWindow>().is_err());
+	}
+
+	#[test]
+	fn test_contains() {
+		let w: MaintenanceWindow = "sun 03:00-04:00".parse().unwrap();
+		assert!(w.contains(at(1, 3, 0)));
+		assert!(w.contains(at(1, 3, 59)));
+		assert!(!w.contains(at(1, 4, 0)));
+		assert!(!w.contains(at(2, 3, 30)));
+
+		let w: MaintenanceWindow = "sun 23:00-01:00".parse().unwrap();
+		assert!(w.contains(at(1, 23, 30)));
+		assert!(w.contains(at(2, 0, 30)));
+		assert!(!w.contains(at(1, 0, 30)));
+
+		let windows = MaintenanceWindows(vec![]);
+		assert!(windows.allows(at(3, 12, 0)));
+		let windows = MaintenanceWindows(vec![w]);
+		assert!(!windows.allows(at(3, 12, 0)));
+	}
+}
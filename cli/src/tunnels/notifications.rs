@@ -0,0 +1,424 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Sends notifications about conditions that need someone's attention, like
+//! credentials that are about to expire, to the channels set up in the CLI
+//! config. Headless hosts often have no one watching their logs, so this is
+//! how their owners find out before the tunnel goes offline.
+
+use std::{
+	collections::{HashMap, VecDeque},
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+	net::TcpStream,
+};
+
+use crate::{
+	auth::Auth,
+	debug, info, log,
+	state::LauncherPaths,
+	util::{
+		errors::{wrap, AnyError, SmtpError, StatusError},
+		http::shared_client,
+		machine::get_host_resources,
+		sync::Barrier,
+	},
+	warning,
+};
+
+/// How long to wait before notifying about the same condition again.
+const NOTIFY_COOLDOWN: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// How often the host is checked for conditions that need attention.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Number of server failures within `CRASH_WINDOW` that are reported.
+const CRASH_THRESHOLD: usize = 3;
+const CRASH_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// The disk is nearly full when less than this fraction, or less than
+/// `DISK_LOW_BYTES`, is available.
+const DISK_LOW_FRACTION: f64 = 0.05;
+const DISK_LOW_BYTES: u64 = 1024 * 1024 * 1024;
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A place notifications are sent to.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum NotificationChannel {
+	/// Publishes to a topic on ntfy.sh or a self-hosted ntfy server, like
+	/// 'https://ntfy.sh/my-topic'.
+	Ntfy {
+		url: String,
+		/// Access token, for protected topics.
+		#[serde(default)]
+		token: Option<String>,
+	},
+	/// Posts to a Matrix webhook, such as one provided by a hookshot bridge.
+	#[serde(rename_all = "camelCase")]
+	Matrix { webhook_url: String },
+	/// Sends email through an SMTP relay that accepts mail without
+	/// authentication, such as a mail server on the host or local network.
+	/// `server` is a 'host:port' address.
+	Smtp {
+		server: String,
+		from: String,
+		to: Vec<String>,
+	},
+}
+
+/// A condition on the host that needs attention.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Condition {
+	AuthExpiring,
+	RepeatedCrashes,
+	DiskNearlyFull,
+}
+
+impl Condition {
+	fn title(&self) -> &'static str {
+		match self {
+			Condition::AuthExpiring => "Tunnel credentials expiring",
+			Condition::RepeatedCrashes => "Server repeatedly failing",
+			Condition::DiskNearlyFull => "Disk nearly full",
+		}
+	}
+}
+
+/// Sends notifications to the configured channels. Each condition is sent at
+/// most once per `NOTIFY_COOLDOWN`, so a persistent problem doesn't flood them.
+#[derive(Clone)]
+pub struct Notifier {
+	channels: Arc<Vec<NotificationChannel>>,
+	hostname: String,
+	last_sent: Arc<Mutex<HashMap<Condition, Instant>>>,
+	failures: Arc<Mutex<FailureCounter>>,
+}
+
+impl Notifier {
+	pub fn new(channels: Vec<NotificationChannel>) -> Self {
+		Notifier {
+			channels: Arc::new(channels),
+			hostname: gethostname::gethostname().to_string_lossy().to_string(),
+			last_sent: Default::default(),
+			failures: Arc::new(Mutex::new(FailureCounter::new(
+				CRASH_THRESHOLD,
+				CRASH_WINDOW,
+			))),
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.channels.is_empty()
+	}
+
+	/// Notifies every channel of the condition. Failures are logged, since
+	/// there's nowhere else to report them.
+	pub async fn notify(&self, log: &log::Logger, condition: Condition, message: &str) {
+		if self.is_empty() {
+			return;
+		}
+
+		{
+			let mut last_sent = self.last_sent.lock().unwrap();
+			let now = Instant::now();
+			match last_sent.get(&condition) {
+				Some(t) if now.duration_since(*t) < NOTIFY_COOLDOWN => return,
+				_ => last_sent.insert(condition, now),
+			};
+		}
+
+		info!(log, "Sending notification: {}", condition.title());
+		for channel in self.channels.iter() {
+			if let Err(e) = self.send(channel, condition, message).await {
+				warning!(log, "Error sending notification: {}", e);
+			}
+		}
+	}
+
+	/// Records that a server failed to install or start, notifying when it
+	/// has happened repeatedly.
+	pub async fn record_server_failure(&self, log: &log::Logger, error: &AnyError) {
+		let repeated = self.failures.lock().unwrap().record(Instant::now());
+		if repeated {
+			let message = format!(
+				"The server failed to start {} times in the last {} minutes. The last error was: {}",
+				CRASH_THRESHOLD,
+				CRASH_WINDOW.as_secs() / 60,
+				error
+			);
+			self.notify(log, Condition::RepeatedCrashes, &message).await;
+		}
+	}
+
+	async fn send(
+		&self,
+		channel: &NotificationChannel,
+		condition: Condition,
+		message: &str,
+	) -> Result<(), AnyError> {
+		let title = format!("{} on {}", condition.title(), self.hostname);
+		let req = match channel {
+			NotificationChannel::Ntfy { url, token } => {
+				let req = shared_client()
+					.post(url)
+					.header("Title", &title)
+					.header("Priority", "high")
+					.body(message.to_string());
+				match token {
+					Some(t) => req.bearer_auth(t),
+					None => req,
+				}
+			}
+			NotificationChannel::Matrix { webhook_url } => shared_client()
+				.post(webhook_url)
+				.json(&serde_json::json!({ "text": format!("{}: {}", title, message) })),
+			NotificationChannel::Smtp { server, from, to } => {
+				return tokio::time::timeout(
+					SMTP_TIMEOUT,
+					send_mail(server, &self.hostname, from, to, &title, message),
+				)
+				.await
+				.map_err(|_| SmtpError("timed out".to_string()))?;
+			}
+		};
+
+		let res = req.send().await?;
+		if !res.status().is_success() {
+			return Err(StatusError::from_res(res).await?.into());
+		}
+
+		Ok(())
+	}
+}
+
+/// Counts events, reporting when `threshold` of them happen within `window`.
+/// Once reported, the count starts over.
+struct FailureCounter {
+	threshold: usize,
+	window: Duration,
+	times: VecDeque<Instant>,
+}
+
+impl FailureCounter {
+	fn new(threshold: usize, window: Duration) -> Self {
+		FailureCounter {
+			threshold,
+			window,
+			times: VecDeque::new(),
+		}
+	}
+
+	fn record(&mut self, now: Instant) -> bool {
+		while let Some(t) = self.times.front() {
+			if now.duration_since(*t) > self.window {
+				self.times.pop_front();
+			} else {
+				break;
+			}
+		}
+
+		self.times.push_back(now);
+		if self.times.len() >= self.threshold {
+			self.times.clear();
+			return true;
+		}
+
+		false
+	}
+}
+
+/// Sends an email with a minimal SMTP conversation.
+async fn send_mail(
+	server: &str,
+	hostname: &str,
+	from: &str,
+	to: &[String],
+	subject: &str,
+	body: &str,
+) -> Result<(), AnyError> {
+	let stream = TcpStream::connect(server)
+		.await
+		.map_err(|e| wrap(e, format!("error connecting to {}", server)))?;
+	let (read, mut write) = stream.into_split();
+	let mut read = BufReader::new(read);
+
+	expect_reply(&mut read, 220).await?;
+	smtp_command(&mut write, &mut read, &format!("EHLO {}", hostname), 250).await?;
+	smtp_command(&mut write, &mut read, &format!("MAIL FROM:<{}>", from), 250).await?;
+	for recipient in to {
+		smtp_command(
+			&mut write,
+			&mut read,
+			&format!("RCPT TO:<{}>", recipient),
+			250,
+		)
+		.await?;
+	}
+	smtp_command(&mut write, &mut read, "DATA", 354).await?;
+
+	let mut data = format!(
+		"From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+		from,
+		to.join(", "),
+		subject,
+		chrono::Utc::now().to_rfc2822()
+	);
+	for line in body.lines() {
+		// lines starting with a dot are escaped so they don't end the message
+		if line.starts_with('.') {
+			data.push('.');
+		}
+		data.push_str(line);
+		data.push_str("\r\n");
+	}
+	data.push('.');
+	smtp_command(&mut write, &mut read, &data, 250).await?;
+
+	write.write_all(b"QUIT\r\n").await.ok();
+	Ok(())
+}
+
+async fn smtp_command(
+	write: &mut (impl AsyncWrite + Unpin),
+	read: &mut BufReader<tokio::net::tcp::OwnedReadHalf>,
+	command: &str,
+	expected: u16,
+) -> Result<(), AnyError> {
+	write
+		.write_all(format!("{}\r\n", command).as_bytes())
+		.await
+		.map_err(|e| wrap(e, "error writing to mail server"))?;
+	expect_reply(read, expected).await
+}
+
+/// Reads a possibly multi-line reply, failing unless it has the expected
+/// code. Recipients the server will forward to (251) are accepted as well.
+async fn expect_reply(
+	read: &mut BufReader<tokio::net::tcp::OwnedReadHalf>,
+	expected: u16,
+) -> Result<(), AnyError> {
+	loop {
+		let mut line = String::new();
+		let n = read
+			.read_line(&mut line)
+			.await
+			.map_err(|e| wrap(e, "error reading from mail server"))?;
+		if n == 0 {
+			return Err(SmtpError("connection closed".to_string()).into());
+		}
+
+		// continuation lines have a dash after the code, like "250-SIZE"
+		if line.as_bytes().get(3) == Some(&b'-') {
+			continue;
+		}
+
+		let code: u16 = line.get(..3).and_then(|c| c.parse().ok()).unwrap_or(0);
+		if code == expected || (expected == 250 && code == 251) {
+			return Ok(());
+		}
+
+		return Err(SmtpError(line.trim().to_string()).into());
+	}
+}
+
+/// Periodically checks the host for conditions that need attention, like
+/// expiring credentials or a full disk, until the closer is triggered.
+pub async fn watch_host_health(
+	log: log::Logger,
+	notifier: Notifier,
+	auth: Auth,
+	paths: LauncherPaths,
+	mut closer: Barrier<()>,
+) {
+	if notifier.is_empty() {
+		return;
+	}
+
+	let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+	loop {
+		tokio::select! {
+			_ = closer.wait() => return,
+			_ = interval.tick() => {},
+		}
+
+		if let Some(w) = auth.get_expiry_warning() {
+			notifier
+				.notify(&log, Condition::AuthExpiring, &w.message)
+				.await;
+		}
+
+		let root = paths.root().to_owned();
+		let resources = tokio::task::spawn_blocking(move || get_host_resources(&root)).await;
+		if let Ok(r) = resources {
+			if let (Some(total), Some(available)) = (r.disk_total, r.disk_available) {
+				debug!(log, "{} of {} bytes available on disk", available, total);
+				if is_disk_nearly_full(total, available) {
+					let message = format!(
+						"Only {} MB of {} MB is available on the disk holding {}. Servers may fail to install or update.",
+						available / 1024 / 1024,
+						total / 1024 / 1024,
+						paths.root().display()
+					);
+					notifier
+						.notify(&log, Condition::DiskNearlyFull, &message)
+						.await;
+				}
+			}
+		}
+	}
+}
+
+fn is_disk_nearly_full(total: u64, available: u64) -> bool {
+	available < DISK_LOW_BYTES || (available as f64) < (total as f64) * DISK_LOW_FRACTION
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_failure_counter() {
+		let mut counter = FailureCounter::new(3, Duration::from_secs(60));
+		let start = Instant::now();
+		assert!(!counter.record(start));
+		assert!(!counter.record(start + Duration::from_secs(10)));
+		// the first failure has left the window
+		assert!(!counter.record(start + Duration::from_secs(65)));
+		assert!(counter.record(start + Duration::from_secs(66)));
+		// the count starts over once reported
+		assert!(!counter.record(start + Duration::from_secs(67)));
+	}
+
+	#[test]
+	fn test_is_disk_nearly_full() {
+		let gb = 1024 * 1024 * 1024;
+		assert!(is_disk_nearly_full(100 * gb, gb / 2));
+		assert!(is_disk_nearly_full(100 * gb, 4 * gb));
+		assert!(!is_disk_nearly_full(100 * gb, 10 * gb));
+	}
+
+	#[test]
+	fn test_parse_channels() {
+		let channels: Vec<NotificationChannel> = serde_json::from_str(
+			r#"[
+				{ "type": "ntfy", "url": "https://ntfy.sh/topic" },
+				{ "type": "matrix", "webhookUrl": "https://example.com/hook" },
+				{ "type": "smtp", "server": "localhost:25", "from": "a@example.com", "to": ["b@example.com"] }
+			]"#,
+		)
+		.unwrap();
+		assert_eq!(channels.len(), 3);
+		assert!(
+			matches!(&channels[1], NotificationChannel::Matrix { webhook_url } if webhook_url == "https://example.com/hook")
+		);
+	}
+}
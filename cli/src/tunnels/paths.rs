@@ -4,18 +4,33 @@
  *--------------------------------------------------------------------------------------------*/
 
 use std::{
-	fs::{read_dir, read_to_string, remove_dir_all, write},
-	path::PathBuf,
+	collections::BTreeMap,
+	fs::{
+		metadata, read_dir, read_to_string, remove_dir_all, remove_file, symlink_metadata, write,
+		File, OpenOptions,
+	},
+	io::{ErrorKind, Seek, SeekFrom, Write},
+	path::{Path, PathBuf},
+	time::Duration,
 };
 
+use chrono::{DateTime, Utc};
+use clap::ArgEnum;
+use fs2::FileExt;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use crate::{
+	desktop::RequestedVersion,
 	log, options,
 	state::{LauncherPaths, PersistedState},
 	util::{
-		errors::{wrap, AnyError, WrappedError},
+		command::kill_tree,
+		errors::{wrap, AnyError, MissingEntrypointError, WrappedError},
+		io::restrict_to_owner,
 		machine,
+		priority::run_maintenance,
+		progress::{ProgressStage, ReportProgress},
 	},
 };
 
@@ -23,8 +38,25 @@ const INSIDERS_INSTALL_FOLDER: &str = "server-insiders";
 const STABLE_INSTALL_FOLDER: &str = "server-stable";
 const EXPLORATION_INSTALL_FOLDER: &str = "server-exploration";
 const PIDFILE_SUFFIX: &str = ".pid";
+const TOKEN_MODE_FILE_SUFFIX: &str = ".token-mode";
+const JAIL_FILE_SUFFIX: &str = ".jail";
+const INSTALL_LOCK_SUFFIX: &str = ".install-lock";
 const LOGFILE_SUFFIX: &str = ".log";
+const MANIFEST_FILE_NAME: &str = ".cli-manifest.json";
+/// File in the server directory written when files in the install were
+/// changed or removed while it was in use, so it's checked before next use.
+const VERIFY_MARKER_FILE_NAME: &str = ".cli-needs-verification";
+/// Suffix of the file a server archive is downloaded into, named by commit.
+const ARCHIVE_FILE_SUFFIX: &str = ".partial";
+
+/// Incomplete installations untouched for longer than this are assumed to
+/// have been left behind by a process that crashed or was killed mid-install.
+const ABANDONED_INSTALL_AGE: Duration = Duration::from_secs(60 * 60);
 
+/// How often to check whether another process finished installing a server.
+const INSTALL_LOCK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
 pub struct ServerPaths {
 	// Directory into which the server is downloaded
 	pub server_dir: PathBuf,
@@ -34,6 +66,47 @@ pub struct ServerPaths {
 	pub logfile: PathBuf,
 	// File where the process ID for the server should be written.
 	pub pidfile: PathBuf,
+	// File where the connection token mode the server was started with is
+	// written, alongside its process ID.
+	pub token_mode_file: PathBuf,
+	// File where the jail the server was started in is written, if any.
+	pub jail_file: PathBuf,
+	// File written once the server is fully downloaded and extracted.
+	pub manifest: PathBuf,
+	// File the server archive is downloaded into before extraction. It's kept
+	// if the download fails, so that it can be resumed.
+	pub archive: PathBuf,
+	// File written if the install was tampered with, naming the first file
+	// that was changed.
+	pub verify_marker: PathBuf,
+	// File locked by the process installing the server, so that other
+	// processes wait for it rather than install it too. It holds the ID of
+	// the process, for logging.
+	pub install_lock: PathBuf,
+}
+
+/// Held while installing a server; the lock is released when dropped, or by
+/// the OS if the process exits. The file is left in place, since removing it
+/// would let another process lock a new file while one waits on the old one.
+pub struct InstallLock(File);
+
+impl Drop for InstallLock {
+	fn drop(&mut self) {
+		self.0.set_len(0).ok();
+		self.0.unlock().ok();
+	}
+}
+
+/// Written into the server directory after a successful installation, so that
+/// complete installs can be told apart from ones interrupted part-way through.
+#[derive(Serialize, Deserialize)]
+pub struct ServerManifest {
+	pub commit: String,
+	pub installed_at: DateTime<Utc>,
+	/// SHA-256 digests of the server's files when it was installed, by path
+	/// relative to the server directory. Empty in manifests of older CLIs.
+	#[serde(default)]
+	pub files: BTreeMap<String, String>,
 }
 
 impl ServerPaths {
@@ -56,6 +129,88 @@ impl ServerPaths {
 		None
 	}
 
+	/// Takes the lock on installing the server, waiting while another
+	/// process holds it.
+	pub async fn lock_install(
+		&self,
+		log: &log::Logger,
+		progress: &mut (impl ReportProgress + Send),
+	) -> Result<InstallLock, WrappedError> {
+		let mut file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.open(&self.install_lock)
+			.map_err(|e| wrap(e, format!("error opening {}", self.install_lock.display())))?;
+
+		let mut waiting = false;
+		loop {
+			match file.try_lock_exclusive() {
+				Ok(()) => break,
+				Err(e) if e.kind() == fs2::lock_contended_error().kind() => {}
+				Err(e) => {
+					return Err(wrap(
+						e,
+						format!("error locking {}", self.install_lock.display()),
+					))
+				}
+			}
+
+			if !waiting {
+				match self.install_lock_pid() {
+					Some(pid) => info!(
+						log,
+						"Another process ({}) is installing the server, waiting for it to finish...",
+						pid
+					),
+					None => info!(
+						log,
+						"Another process is installing the server, waiting for it to finish..."
+					),
+				}
+				progress.begin_stage(ProgressStage::Other("wait-for-install"));
+				waiting = true;
+			}
+			progress.report_indeterminate();
+			tokio::time::sleep(INSTALL_LOCK_POLL_INTERVAL).await;
+		}
+
+		if waiting {
+			progress.end_stage();
+		}
+
+		file.set_len(0)
+			.and_then(|_| file.seek(SeekFrom::Start(0)))
+			.and_then(|_| write!(file, "{}", std::process::id()))
+			.map_err(|e| wrap(e, "error writing install lock"))?;
+		Ok(InstallLock(file))
+	}
+
+	/// Gets whether a process, this one or another, holds the install lock.
+	pub fn is_install_locked(&self) -> bool {
+		let file = match File::open(&self.install_lock) {
+			Ok(f) => f,
+			Err(_) => return false,
+		};
+
+		match file.try_lock_shared() {
+			Ok(()) => {
+				file.unlock().ok();
+				false
+			}
+			Err(_) => true,
+		}
+	}
+
+	/// Gets the ID of the process that last took the install lock, if it's
+	/// still running. It can't be read while locked on Windows.
+	fn install_lock_pid(&self) -> Option<u32> {
+		read_to_string(&self.install_lock)
+			.ok()
+			.and_then(|s| s.trim().parse::<u32>().ok())
+			.filter(|pid| machine::process_exists(*pid))
+	}
+
 	/// Delete the server directory
 	pub fn delete(&self) -> Result<(), WrappedError> {
 		remove_dir_all(&self.server_dir).map_err(|e| {
@@ -76,11 +231,182 @@ impl ServerPaths {
 		})
 	}
 
+	/// Records the connection token mode the running server was started with.
+	pub fn write_token_mode(&self, mode: options::ConnectionTokenMode) -> Result<(), WrappedError> {
+		write(&self.token_mode_file, mode.to_string()).map_err(|e| {
+			wrap(
+				e,
+				format!(
+					"error writing token mode into {}",
+					self.token_mode_file.display()
+				),
+			)
+		})
+	}
+
+	/// Reads the connection token mode the running server was started with.
+	/// Servers started before modes were recorded never required a token.
+	pub fn read_token_mode(&self) -> options::ConnectionTokenMode {
+		read_to_string(&self.token_mode_file)
+			.ok()
+			.and_then(|s| options::ConnectionTokenMode::from_str(s.trim(), true).ok())
+			.unwrap_or(options::ConnectionTokenMode::None)
+	}
+
+	/// Records the directory the running server is jailed to, if any.
+	pub fn write_jail(&self, root: Option<&Path>) -> Result<(), WrappedError> {
+		let root = match root {
+			Some(r) => r,
+			None => {
+				remove_file(&self.jail_file).ok();
+				return Ok(());
+			}
+		};
+
+		write(&self.jail_file, root.to_string_lossy().as_bytes()).map_err(|e| {
+			wrap(
+				e,
+				format!("error writing jail into {}", self.jail_file.display()),
+			)
+		})
+	}
+
+	/// Reads the directory the running server is jailed to, if any.
+	pub fn read_jail(&self) -> Option<PathBuf> {
+		read_to_string(&self.jail_file).ok().map(PathBuf::from)
+	}
+
 	fn read_pid(&self) -> Option<u32> {
 		read_to_string(&self.pidfile)
 			.ok()
 			.and_then(|s| s.parse::<u32>().ok())
 	}
+
+	/// Records that the server was installed completely, along with digests
+	/// of its files so that they can be checked later.
+	pub fn write_manifest(&self, commit: &str) -> Result<(), WrappedError> {
+		let manifest = ServerManifest {
+			commit: commit.to_owned(),
+			installed_at: Utc::now(),
+			files: self
+				.hash_files()
+				.map_err(|e| wrap(e, "error hashing server files"))?,
+		};
+
+		write(&self.manifest, serde_json::to_string(&manifest).unwrap()).map_err(|e| {
+			wrap(
+				e,
+				format!("error writing server manifest {}", self.manifest.display()),
+			)
+		})
+	}
+
+	/// Reads the install manifest, if it exists and is valid.
+	pub fn read_manifest(&self) -> Option<ServerManifest> {
+		read_to_string(&self.manifest)
+			.ok()
+			.and_then(|s| serde_json::from_str(&s).ok())
+	}
+
+	/// Gets whether the file is one the CLI keeps in the server directory,
+	/// rather than part of the server itself.
+	pub fn is_cli_file(&self, path: &Path) -> bool {
+		path == self.manifest || path == self.archive || path == self.verify_marker
+	}
+
+	/// Gets the digests of the server's files, by path relative to the server
+	/// directory with '/' separators. Links aren't followed.
+	fn hash_files(&self) -> std::io::Result<BTreeMap<String, String>> {
+		let mut hashes = BTreeMap::new();
+		let mut dirs = vec![self.server_dir.clone()];
+		while let Some(dir) = dirs.pop() {
+			for entry in read_dir(&dir)? {
+				let path = entry?.path();
+				let file_type = symlink_metadata(&path)?.file_type();
+				if file_type.is_dir() {
+					dirs.push(path);
+				} else if file_type.is_file() && !self.is_cli_file(&path) {
+					hashes.insert(self.relative_name(&path), hash_file(&path)?);
+				}
+			}
+		}
+
+		Ok(hashes)
+	}
+
+	fn relative_name(&self, path: &Path) -> String {
+		path.strip_prefix(&self.server_dir)
+			.unwrap_or(path)
+			.components()
+			.map(|c| c.as_os_str().to_string_lossy())
+			.collect::<Vec<_>>()
+			.join("/")
+	}
+
+	/// Checks the install against its manifest: that it finished, that its
+	/// entrypoint exists, and that files recorded when it was installed are
+	/// still there and unchanged. Returns the problems found, if any.
+	pub fn check_integrity(&self) -> Vec<String> {
+		let mut problems = vec![];
+		if !self.executable.exists() {
+			problems.push(MissingEntrypointError().to_string());
+		}
+
+		let manifest = match self.read_manifest() {
+			Some(m) => m,
+			None if self.manifest.exists() => {
+				problems.push("its manifest can't be read".to_string());
+				return problems;
+			}
+			// installed before manifests were written, so there's nothing to check against
+			None => return problems,
+		};
+
+		for (name, expected) in &manifest.files {
+			let path = self.server_dir.join(name);
+			match hash_file(&path) {
+				Ok(actual) if &actual == expected => {}
+				Ok(_) => problems.push(format!("{} was changed", name)),
+				Err(e) if e.kind() == ErrorKind::NotFound => {
+					problems.push(format!("{} is missing", name))
+				}
+				Err(e) => problems.push(format!("{} can't be read: {}", name, e)),
+			}
+		}
+
+		problems
+	}
+
+	/// Marks the install to be verified before it's next used, recording the
+	/// file that was changed. The first file recorded is kept.
+	pub fn mark_for_verification(&self, changed: &Path) -> Result<(), WrappedError> {
+		if self.verify_marker.exists() {
+			return Ok(());
+		}
+
+		write(&self.verify_marker, changed.to_string_lossy().as_bytes())
+			.map_err(|e| wrap(e, format!("error writing {}", self.verify_marker.display())))
+	}
+
+	/// Gets the file recorded when the install was marked for verification,
+	/// if it was.
+	pub fn needs_verification(&self) -> Option<PathBuf> {
+		read_to_string(&self.verify_marker).ok().map(PathBuf::from)
+	}
+
+	/// Checks that the install is complete and its entrypoint is still in
+	/// place, clearing the mark if so.
+	pub fn verify(&self) -> Result<(), String> {
+		if self.read_manifest().is_none() {
+			return Err("its manifest is missing".to_string());
+		}
+		if !self.executable.exists() {
+			return Err(format!("{} is missing", self.executable.display()));
+		}
+
+		remove_file(&self.verify_marker).ok();
+		Ok(())
+	}
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -99,9 +425,16 @@ impl InstalledServer {
 			executable: server_dir
 				.join("bin")
 				.join(self.quality.server_entrypoint()),
+			manifest: server_dir.join(MANIFEST_FILE_NAME),
+			verify_marker: server_dir.join(VERIFY_MARKER_FILE_NAME),
+			archive: server_dir.join(format!("{}{}", self.commit, ARCHIVE_FILE_SUFFIX)),
 			server_dir,
 			logfile: base_folder.join(format!(".{}{}", self.commit, LOGFILE_SUFFIX)),
 			pidfile: base_folder.join(format!(".{}{}", self.commit, PIDFILE_SUFFIX)),
+			token_mode_file: base_folder
+				.join(format!(".{}{}", self.commit, TOKEN_MODE_FILE_SUFFIX)),
+			jail_file: base_folder.join(format!(".{}{}", self.commit, JAIL_FILE_SUFFIX)),
+			install_lock: base_folder.join(format!(".{}{}", self.commit, INSTALL_LOCK_SUFFIX)),
 		}
 	}
 
@@ -120,8 +453,53 @@ impl InstalledServer {
 	}
 }
 
+/// Number of most recently used servers kept by default when old ones are
+/// removed.
+pub const DEFAULT_RETAINED_SERVERS: usize = 5;
+
+/// Which servers to keep when removing ones that are no longer used.
+#[derive(Clone, Copy, Debug)]
+pub struct RetentionPolicy {
+	/// Number of most recently used servers to keep, however old.
+	pub keep: usize,
+	/// If set, only servers that haven't been used for this long are removed.
+	pub older_than: Option<chrono::Duration>,
+}
+
+impl Default for RetentionPolicy {
+	fn default() -> Self {
+		RetentionPolicy {
+			keep: DEFAULT_RETAINED_SERVERS,
+			older_than: None,
+		}
+	}
+}
+
+impl RetentionPolicy {
+	/// Gets the policy set in the config file, or the default one.
+	pub fn configured(paths: &LauncherPaths) -> Self {
+		let config = paths.config();
+		RetentionPolicy {
+			keep: config
+				.server_retention_keep
+				.unwrap_or(DEFAULT_RETAINED_SERVERS),
+			older_than: config.server_retention_days.map(chrono::Duration::days),
+		}
+	}
+}
+
+/// A server and when it was last used.
+#[derive(Serialize, Deserialize, Clone)]
+struct LastUsedServer {
+	#[serde(flatten)]
+	server: InstalledServer,
+	/// Not recorded by older versions of the CLI.
+	#[serde(default)]
+	used_at: Option<DateTime<Utc>>,
+}
+
 pub struct LastUsedServers<'a> {
-	state: PersistedState<Vec<InstalledServer>>,
+	state: PersistedState<Vec<LastUsedServer>>,
 	paths: &'a LauncherPaths,
 }
 
@@ -136,42 +514,293 @@ impl<'a> LastUsedServers<'a> {
 	/// Adds a server as having been used most recently. Returns the number of retained server.
 	pub fn add(&self, server: InstalledServer) -> Result<usize, WrappedError> {
 		self.state.update_with(server, |server, l| {
-			if let Some(index) = l.iter().position(|s| s == &server) {
+			if let Some(index) = l.iter().position(|s| s.server == server) {
 				l.remove(index);
 			}
-			l.insert(0, server);
+			l.insert(
+				0,
+				LastUsedServer {
+					server,
+					used_at: Some(Utc::now()),
+				},
+			);
 			l.len()
 		})
 	}
 
-	/// Trims so that at most `max_servers` are saved on disk.
-	pub fn trim(&self, log: &log::Logger, max_servers: usize) -> Result<(), WrappedError> {
-		let mut servers = self.state.load();
-		while servers.len() > max_servers {
-			let server = servers.pop().unwrap();
+	/// Gets the servers that have been used, most recent first.
+	pub fn get_all(&self) -> Vec<InstalledServer> {
+		self.state.load().into_iter().map(|s| s.server).collect()
+	}
+
+	/// Removes servers on disk that the policy doesn't retain, other than
+	/// ones that are running, being installed, or kept for rolling back, see
+	/// `rollback_servers`. Incomplete installs are left for
+	/// `clean_abandoned_installs`. Servers the CLI hasn't recorded using, like
+	/// those from older versions, are considered used when their directory
+	/// last changed. Returns the servers that were removed.
+	pub fn prune(
+		&self,
+		log: &log::Logger,
+		policy: RetentionPolicy,
+	) -> Result<Vec<InstalledServer>, WrappedError> {
+		let recorded = self.state.load();
+		let mut unrecorded: Vec<LastUsedServer> = get_all_installs(self.paths)
+			.into_iter()
+			.filter(|s| !recorded.iter().any(|r| &r.server == s))
+			.map(|server| LastUsedServer {
+				used_at: metadata(server.server_paths(self.paths).server_dir)
+					.and_then(|m| m.modified())
+					.ok()
+					.map(DateTime::<Utc>::from),
+				server,
+			})
+			.collect();
+		unrecorded.sort_by(|a, b| b.used_at.cmp(&a.used_at));
+
+		let recorded_len = recorded.len();
+		let rollback = rollback_servers(self.paths);
+		let cutoff = policy.older_than.map(|d| Utc::now() - d);
+		let mut retained = vec![];
+		let mut removed = vec![];
+		for (i, entry) in recorded.into_iter().chain(unrecorded).enumerate() {
+			let recent = match (cutoff, entry.used_at) {
+				(Some(cutoff), Some(used_at)) => used_at > cutoff,
+				_ => false,
+			};
+			let server_paths = entry.server.server_paths(self.paths);
+			let in_use = server_paths.get_running_pid().is_some()
+				|| server_paths.is_install_locked()
+				|| (server_paths.server_dir.exists()
+					&& get_install_state(&entry.server, &server_paths) == InstallState::Incomplete)
+				|| rollback.contains(&(entry.server.quality, entry.server.commit.clone()));
+			if i < policy.keep || recent || in_use {
+				if i < recorded_len {
+					retained.push(entry);
+				}
+				continue;
+			}
+
 			debug!(
 				log,
 				"Removing old server {}/{}",
-				server.quality.get_machine_name(),
-				server.commit
+				entry.server.quality.get_machine_name(),
+				entry.server.commit
 			);
-			let server_paths = server.server_paths(self.paths);
-			server_paths.delete()?;
+			if server_paths.server_dir.exists() {
+				run_maintenance(|| server_paths.delete())?;
+			}
+			remove_file(&server_paths.pidfile).ok();
+			remove_file(&server_paths.token_mode_file).ok();
+			remove_file(&server_paths.jail_file).ok();
+			removed.push(entry.server);
 		}
-		self.state.save(servers)?;
-		Ok(())
+
+		self.state.save(retained)?;
+		Ok(removed)
+	}
+}
+
+/// Gets the servers hosts use or roll back to: the pinned commit, if any, and
+/// the commit each quality would be rolled back to from the one in use.
+fn rollback_servers(paths: &LauncherPaths) -> Vec<(options::Quality, String)> {
+	let pinned = match get_pinned_version(paths) {
+		Some(RequestedVersion::Commit { commit, quality }) => Some((quality, commit)),
+		_ => None,
+	};
+
+	let history = ServerHistory::new(paths);
+	let entries = history.get_all();
+	let mut servers: Vec<(options::Quality, String)> = pinned.iter().cloned().collect();
+	for quality in options::Quality::value_variants() {
+		let current = match &pinned {
+			Some((q, commit)) if q == quality => Some(commit.clone()),
+			_ => entries
+				.iter()
+				.find(|e| e.quality == *quality)
+				.map(|e| e.commit.clone()),
+		};
+		if let Some(previous) = current.and_then(|c| history.previous(*quality, &c)) {
+			servers.push((*quality, previous));
+		}
+	}
+
+	servers
+}
+
+/// Number of servers remembered in the history, for rolling back.
+const SERVER_HISTORY_LEN: usize = 10;
+
+/// A server commit that hosts switched to, and when.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ServerHistoryEntry {
+	pub quality: options::Quality,
+	pub commit: String,
+	pub used_from: DateTime<Utc>,
+	/// Set when hosts were rolled back from this commit, so later rollbacks
+	/// don't return to it.
+	#[serde(default)]
+	pub rolled_back: bool,
+}
+
+/// The server commits hosts have used in turn, newest first, so they can be
+/// rolled back to an earlier one if a new server breaks.
+pub struct ServerHistory {
+	state: PersistedState<Vec<ServerHistoryEntry>>,
+}
+
+impl ServerHistory {
+	pub fn new(paths: &LauncherPaths) -> Self {
+		ServerHistory {
+			state: PersistedState::new(paths.root().join("server-history.json")),
+		}
+	}
+
+	/// Records the server as the one in use for its quality.
+	pub fn record(&self, server: &InstalledServer) -> Result<(), WrappedError> {
+		let is_current = self
+			.get_all()
+			.iter()
+			.find(|e| e.quality == server.quality)
+			.map(|e| e.commit == server.commit)
+			.unwrap_or(false);
+		if is_current {
+			return Ok(());
+		}
+
+		self.state.update_with(server.clone(), |server, l| {
+			l.retain(|e| !(e.quality == server.quality && e.commit == server.commit));
+			l.insert(
+				0,
+				ServerHistoryEntry {
+					quality: server.quality,
+					commit: server.commit,
+					used_from: Utc::now(),
+					rolled_back: false,
+				},
+			);
+			l.truncate(SERVER_HISTORY_LEN);
+		})
+	}
+
+	/// Gets the servers that have been used, most recent first.
+	pub fn get_all(&self) -> Vec<ServerHistoryEntry> {
+		self.state.load()
+	}
+
+	/// Gets the commit to roll back to from `current`: the newest one used
+	/// before it that hasn't itself been rolled back from. There's none if
+	/// `current` hasn't been used.
+	pub fn previous(&self, quality: options::Quality, current: &str) -> Option<String> {
+		let entries: Vec<ServerHistoryEntry> = self
+			.get_all()
+			.into_iter()
+			.filter(|e| e.quality == quality)
+			.collect();
+		let start = entries.iter().position(|e| e.commit == current)?;
+
+		entries
+			.into_iter()
+			.skip(start)
+			.find(|e| e.commit != current && !e.rolled_back)
+			.map(|e| e.commit)
 	}
+
+	/// Marks the commit as rolled back from.
+	pub fn mark_rolled_back(
+		&self,
+		quality: options::Quality,
+		commit: &str,
+	) -> Result<(), WrappedError> {
+		self.state
+			.update_with((quality, commit.to_owned()), |(quality, commit), l| {
+				for e in l.iter_mut() {
+					if e.quality == quality && e.commit == commit {
+						e.rolled_back = true;
+					}
+				}
+			})
+	}
+}
+
+/// Gets the server version hosts use instead of the latest one, if pinned.
+pub fn get_pinned_version(paths: &LauncherPaths) -> Option<RequestedVersion> {
+	pinned_version_state(paths).load()
+}
+
+/// Pins the server version hosts use, or unpins it so they use the latest.
+pub fn set_pinned_version(
+	paths: &LauncherPaths,
+	version: Option<RequestedVersion>,
+) -> Result<(), WrappedError> {
+	pinned_version_state(paths).save(version)
+}
+
+fn pinned_version_state(paths: &LauncherPaths) -> PersistedState<Option<RequestedVersion>> {
+	PersistedState::new(paths.root().join("pinned-version.json"))
+}
+
+/// Gets the variables set in the environment of servers hosts start.
+pub fn get_session_env(paths: &LauncherPaths) -> BTreeMap<String, String> {
+	session_env_state(paths).load()
+}
+
+/// Sets the variables set in the environment of servers hosts start. They
+/// may hold secrets, so only the current user can read them.
+pub fn set_session_env(
+	paths: &LauncherPaths,
+	env: BTreeMap<String, String>,
+) -> Result<(), WrappedError> {
+	let state = session_env_state(paths);
+	state.save(env)?;
+	restrict_to_owner(&state.path(), 0o600)
+		.map_err(|e| wrap(e, format!("error securing {}", state.path().display())))
+}
+
+fn session_env_state(paths: &LauncherPaths) -> PersistedState<BTreeMap<String, String>> {
+	PersistedState::new(paths.root().join("session-env.json"))
 }
 
 /// Prunes servers not currently running, and returns the deleted servers.
 pub fn prune_stopped_servers(launcher_paths: &LauncherPaths) -> Result<Vec<ServerPaths>, AnyError> {
-	get_all_servers(launcher_paths)
-		.into_iter()
-		.map(|s| s.server_paths(launcher_paths))
-		.filter(|s| s.get_running_pid().is_none())
-		.map(|s| s.delete().map(|_| s))
-		.collect::<Result<_, _>>()
-		.map_err(AnyError::from)
+	run_maintenance(|| {
+		get_all_servers(launcher_paths)
+			.into_iter()
+			.map(|s| s.server_paths(launcher_paths))
+			.filter(|s| s.get_running_pid().is_none())
+			.map(|s| s.delete().map(|_| s))
+			.collect::<Result<_, _>>()
+			.map_err(AnyError::from)
+	})
+}
+
+/// Stops servers that are running, so they're started again when clients
+/// next connect, and returns the stopped servers.
+pub async fn stop_running_servers(launcher_paths: &LauncherPaths) -> Vec<InstalledServer> {
+	stop_running_servers_where(launcher_paths, |_| true).await
+}
+
+/// Like `stop_running_servers`, only stopping servers the filter accepts.
+pub async fn stop_running_servers_where(
+	launcher_paths: &LauncherPaths,
+	filter: impl Fn(&InstalledServer) -> bool,
+) -> Vec<InstalledServer> {
+	let mut stopped = vec![];
+	for server in get_all_servers(launcher_paths) {
+		if !filter(&server) {
+			continue;
+		}
+
+		let paths = server.server_paths(launcher_paths);
+		if let Some(pid) = paths.get_running_pid() {
+			if kill_tree(pid).await.is_ok() {
+				remove_file(&paths.pidfile).ok();
+				stopped.push(server);
+			}
+		}
+	}
+
+	stopped
 }
 
 // Gets a list of all servers which look like they might be running.
@@ -214,3 +843,368 @@ fn add_server_paths_in_folder(
 		}
 	}
 }
+
+/// Finds a complete headless server installation, preferring the one used
+/// most recently, optionally restricted to the given quality.
+pub fn find_installed_server(
+	lp: &LauncherPaths,
+	quality: Option<options::Quality>,
+) -> Option<InstalledServer> {
+	let mut candidates = LastUsedServers::new(lp).get_all();
+	candidates.extend(get_all_servers(lp));
+
+	candidates.into_iter().find(|s| {
+		s.headless
+			&& quality.map(|q| q == s.quality).unwrap_or(true)
+			&& get_install_state(s, &s.server_paths(lp)) != InstallState::Incomplete
+	})
+}
+
+/// State of a server installation found on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstallState {
+	/// The server has a valid manifest and its entrypoint exists.
+	Intact,
+	/// The server was installed by an older CLI that did not write manifests,
+	/// but looks complete.
+	Legacy,
+	/// The download or extraction never finished.
+	Incomplete,
+}
+
+/// Inspects the server directory to determine whether its installation is complete.
+pub fn get_install_state(server: &InstalledServer, paths: &ServerPaths) -> InstallState {
+	if paths.archive.exists() || !paths.executable.exists() {
+		return InstallState::Incomplete;
+	}
+
+	match paths.read_manifest() {
+		Some(m) if m.commit == server.commit => InstallState::Intact,
+		Some(_) => InstallState::Incomplete,
+		None if paths.manifest.exists() => InstallState::Incomplete,
+		None => InstallState::Legacy,
+	}
+}
+
+/// Scans all server install folders, removing downloads and extractions that
+/// were abandoned by earlier runs, and backfilling manifests for complete
+/// installs made before manifests existed. Returns the removed directories.
+pub fn clean_abandoned_installs(log: &log::Logger, lp: &LauncherPaths) -> Vec<PathBuf> {
+	run_maintenance(|| clean_abandoned_installs_older_than(log, lp, ABANDONED_INSTALL_AGE))
+}
+
+fn clean_abandoned_installs_older_than(
+	log: &log::Logger,
+	lp: &LauncherPaths,
+	max_age: Duration,
+) -> Vec<PathBuf> {
+	let mut removed = vec![];
+	for server in get_all_installs(lp) {
+		let paths = server.server_paths(lp);
+		match get_install_state(&server, &paths) {
+			InstallState::Intact => continue,
+			InstallState::Legacy => {
+				debug!(log, "Backfilling manifest for server {}", server.commit);
+				if let Err(e) = paths.write_manifest(&server.commit) {
+					warning!(log, "Error backfilling server manifest: {}", e);
+				}
+			}
+			InstallState::Incomplete => {
+				// an install may still be in progress in another process; only
+				// remove it once nothing has touched it for a while.
+				if !is_older_than(&paths.server_dir, max_age)
+					|| !is_older_than(&paths.archive, max_age)
+					|| paths.get_running_pid().is_some()
+					|| paths.is_install_locked()
+				{
+					continue;
+				}
+
+				info!(
+					log,
+					"Removing incomplete server install at {}",
+					paths.server_dir.display()
+				);
+				match paths.delete() {
+					Ok(()) => {
+						remove_file(&paths.pidfile).ok();
+						remove_file(&paths.token_mode_file).ok();
+						remove_file(&paths.jail_file).ok();
+						removed.push(paths.server_dir);
+					}
+					Err(e) => warning!(log, "Error removing incomplete server: {}", e),
+				}
+			}
+		}
+	}
+
+	removed
+}
+
+/// Gets whether the path was last modified longer than `age` ago. Paths that
+/// don't exist are considered old.
+fn is_older_than(path: &Path, age: Duration) -> bool {
+	match metadata(path).and_then(|m| m.modified()) {
+		Ok(modified) => modified.elapsed().map(|e| e >= age).unwrap_or(false),
+		Err(_) => true,
+	}
+}
+
+/// Gets the lowercase hex SHA-256 digest of the file's contents.
+fn hash_file(path: &Path) -> std::io::Result<String> {
+	let mut hasher = Sha256::new();
+	std::io::copy(&mut File::open(path)?, &mut hasher)?;
+	Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Gets all server directories on disk, of every quality.
+pub fn get_all_installs(lp: &LauncherPaths) -> Vec<InstalledServer> {
+	let mut servers: Vec<InstalledServer> = vec![];
+	for quality in [
+		options::Quality::Stable,
+		options::Quality::Insiders,
+		options::Quality::Exploration,
+	] {
+		for headless in [false, true] {
+			let server = InstalledServer {
+				commit: "".to_owned(),
+				headless,
+				quality,
+			};
+			add_server_paths_in_folder(lp, &server, &mut servers);
+		}
+	}
+
+	servers
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::util::progress::SilentProgress;
+
+	fn make_server(lp: &LauncherPaths, commit: &str) -> (InstalledServer, ServerPaths) {
+		let server = InstalledServer {
+			quality: options::Quality::Stable,
+			commit: commit.to_owned(),
+			headless: true,
+		};
+		let paths = server.server_paths(lp);
+		std::fs::create_dir_all(paths.executable.parent().unwrap()).unwrap();
+		(server, paths)
+	}
+
+	#[test]
+	fn test_server_history_rollback() {
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let history = ServerHistory::new(&lp);
+		let (a, _) = make_server(&lp, "a");
+		let (b, _) = make_server(&lp, "b");
+
+		history.record(&a).unwrap();
+		history.record(&b).unwrap();
+		history.record(&b).unwrap();
+		assert_eq!(history.get_all().len(), 2);
+		assert_eq!(
+			history.previous(options::Quality::Stable, "b"),
+			Some("a".to_string())
+		);
+		assert_eq!(history.previous(options::Quality::Insiders, "b"), None);
+		assert_eq!(history.previous(options::Quality::Stable, "c"), None);
+
+		// after rolling back, using the earlier server again doesn't make the
+		// broken one a rollback target
+		history
+			.mark_rolled_back(options::Quality::Stable, "b")
+			.unwrap();
+		history.record(&a).unwrap();
+		assert_eq!(history.previous(options::Quality::Stable, "a"), None);
+	}
+
+	#[tokio::test]
+	async fn test_install_lock() {
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let (_, paths) = make_server(&lp, "a");
+		let log = log::Logger::test();
+
+		let lock = paths
+			.lock_install(&log, &mut SilentProgress())
+			.await
+			.unwrap();
+		assert!(paths.is_install_locked());
+
+		// others wait until the lock is released
+		let waiter = {
+			let (paths, log) = (paths.clone(), log.clone());
+			tokio::spawn(async move {
+				paths
+					.lock_install(&log, &mut SilentProgress())
+					.await
+					.map(|_| ())
+			})
+		};
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert!(!waiter.is_finished());
+		drop(lock);
+		waiter.await.unwrap().unwrap();
+		assert!(!paths.is_install_locked());
+
+		// a lock file left behind, like by a process that was killed, isn't held
+		write(&paths.install_lock, std::process::id().to_string()).unwrap();
+		assert!(!paths.is_install_locked());
+		assert!(paths
+			.lock_install(&log, &mut SilentProgress())
+			.await
+			.is_ok());
+	}
+
+	#[test]
+	fn test_get_install_state() {
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::new_without_replacements(dir.path().to_owned());
+
+		let (server, paths) = make_server(&lp, "a");
+		assert_eq!(get_install_state(&server, &paths), InstallState::Incomplete);
+
+		write(&paths.executable, "").unwrap();
+		assert_eq!(get_install_state(&server, &paths), InstallState::Legacy);
+
+		paths.write_manifest("a").unwrap();
+		assert_eq!(get_install_state(&server, &paths), InstallState::Intact);
+
+		write(&paths.archive, "").unwrap();
+		assert_eq!(get_install_state(&server, &paths), InstallState::Incomplete);
+	}
+
+	#[test]
+	fn test_check_integrity() {
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let (_, paths) = make_server(&lp, "a");
+		write(&paths.executable, "entrypoint").unwrap();
+		write(paths.server_dir.join("package.json"), "{}").unwrap();
+		paths.write_manifest("a").unwrap();
+
+		let manifest = paths.read_manifest().unwrap();
+		assert_eq!(manifest.files.len(), 2);
+		assert!(manifest.files.contains_key("package.json"));
+		assert!(paths.check_integrity().is_empty());
+
+		write(paths.server_dir.join("package.json"), "{\"changed\":1}").unwrap();
+		remove_file(&paths.executable).unwrap();
+		let problems = paths.check_integrity();
+		assert_eq!(problems.len(), 3);
+		assert!(problems.contains(&"package.json was changed".to_string()));
+	}
+
+	#[test]
+	fn test_clean_abandoned_installs() {
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let log = log::Logger::test();
+
+		let (_, partial) = make_server(&lp, "partial");
+		write(&partial.archive, "").unwrap();
+
+		let (_, legacy) = make_server(&lp, "legacy");
+		write(&legacy.executable, "").unwrap();
+
+		let (_, intact) = make_server(&lp, "intact");
+		write(&intact.executable, "").unwrap();
+		intact.write_manifest("intact").unwrap();
+
+		assert!(clean_abandoned_installs(&log, &lp).is_empty());
+
+		let removed = clean_abandoned_installs_older_than(&log, &lp, Duration::ZERO);
+		assert_eq!(removed, vec![partial.server_dir.clone()]);
+		assert!(!partial.server_dir.exists());
+		assert!(legacy.read_manifest().is_some());
+		assert!(intact.executable.exists());
+	}
+
+	#[test]
+	fn test_prune_by_retention_policy() {
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let log = log::Logger::test();
+		let last_used = LastUsedServers::new(&lp);
+
+		let (unrecorded, unrecorded_paths) = make_server(&lp, "unrecorded");
+		let (old, old_paths) = make_server(&lp, "old");
+		let (new, new_paths) = make_server(&lp, "new");
+		last_used.add(old).unwrap();
+		last_used.add(new.clone()).unwrap();
+
+		let policy = RetentionPolicy {
+			keep: 1,
+			older_than: Some(chrono::Duration::days(1)),
+		};
+		assert!(last_used.prune(&log, policy).unwrap().is_empty());
+
+		let policy = RetentionPolicy {
+			keep: 1,
+			older_than: None,
+		};
+		let removed = last_used.prune(&log, policy).unwrap();
+		assert_eq!(removed.len(), 2);
+		assert!(removed.contains(&unrecorded));
+		assert!(!unrecorded_paths.server_dir.exists());
+		assert!(!old_paths.server_dir.exists());
+		assert!(new_paths.server_dir.exists());
+		assert_eq!(last_used.get_all().len(), 1);
+		assert!(last_used.get_all().contains(&new));
+	}
+
+	#[tokio::test]
+	async fn test_prune_keeps_servers_in_use() {
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let log = log::Logger::test();
+		let last_used = LastUsedServers::new(&lp);
+		let history = ServerHistory::new(&lp);
+		let policy = RetentionPolicy {
+			keep: 0,
+			older_than: None,
+		};
+
+		let (previous, previous_paths) = make_server(&lp, "previous");
+		let (pinned, pinned_paths) = make_server(&lp, "pinned");
+		let (current, _) = make_server(&lp, "current");
+		let (installing, installing_paths) = make_server(&lp, "installing");
+		let (incomplete, incomplete_paths) = make_server(&lp, "incomplete");
+		for s in [&previous, &pinned, &current] {
+			write(&s.server_paths(&lp).executable, "").unwrap();
+			s.server_paths(&lp).write_manifest(&s.commit).unwrap();
+		}
+		write(&installing_paths.executable, "").unwrap();
+		let _lock = installing_paths
+			.lock_install(&log, &mut SilentProgress())
+			.await
+			.unwrap();
+		write(&incomplete_paths.archive, "").unwrap();
+		history.record(&previous).unwrap();
+		history.record(&pinned).unwrap();
+		set_pinned_version(
+			&lp,
+			Some(RequestedVersion::Commit {
+				commit: pinned.commit.clone(),
+				quality: pinned.quality,
+			}),
+		)
+		.unwrap();
+		for s in [&previous, &pinned, &current, &installing, &incomplete] {
+			last_used.add(s.clone()).unwrap();
+		}
+
+		// the pinned commit rolls back to the one used before it
+		let removed = last_used.prune(&log, policy).unwrap();
+		assert_eq!(removed.len(), 1);
+		assert!(removed.contains(&current));
+		assert!(previous_paths.server_dir.exists());
+		assert!(pinned_paths.server_dir.exists());
+		assert!(installing_paths.server_dir.exists());
+		assert!(incomplete_paths.server_dir.exists());
+	}
+}
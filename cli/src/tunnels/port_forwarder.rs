@@ -3,20 +3,38 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use tokio::sync::{mpsc, oneshot};
 
 use crate::{
-	constants::CONTROL_PORT,
-	util::errors::{AnyError, CannotForwardControlPort, ServerHasClosed},
+	constants::{CONTROL_PORT, HOST_ROUTER_PORT},
+	log,
+	util::errors::{
+		AnyError, CannotForwardControlPort, ForwardTargetNotFilterable, ServerHasClosed,
+	},
 };
 
-use super::dev_tunnels::ActiveTunnel;
+use super::{
+	dev_tunnels::{ActiveTunnel, PortOptions},
+	forward_targets::{serve_forward_target, ForwardTarget, ForwardTargetPolicy},
+	host_router::{serve_host_router, HostRoute, HostRoutes},
+	ip_filter::PortGate,
+};
 
 pub enum PortForwardingRec {
 	Forward(u16, oneshot::Sender<Result<String, AnyError>>),
+	ForwardWithOptions(u16, PortOptions, oneshot::Sender<Result<String, AnyError>>),
 	Unforward(u16, oneshot::Sender<Result<(), AnyError>>),
+	ForwardMany(Vec<u16>, oneshot::Sender<Vec<Result<String, AnyError>>>),
+	UnforwardMany(Vec<u16>, oneshot::Sender<Vec<Result<(), AnyError>>>),
+	ForwardRoute(HostRoute, oneshot::Sender<Result<String, AnyError>>),
+	ForwardTarget(
+		u16,
+		ForwardTarget,
+		PortOptions,
+		oneshot::Sender<Result<String, AnyError>>,
+	),
 }
 
 /// Provides a port forwarding service for connected clients. Clients can make
@@ -26,15 +44,28 @@ pub struct PortForwardingProcessor {
 	tx: mpsc::Sender<PortForwardingRec>,
 	rx: mpsc::Receiver<PortForwardingRec>,
 	forwarded: HashSet<u16>,
+	routes: HostRoutes,
+	/// Whether the host router has been added to the tunnel.
+	routing: bool,
+	/// Hosts other than this one that ports may be forwarded to.
+	target_policy: ForwardTargetPolicy,
+	/// Addresses clients may connect to forwarded ports from.
+	gate: PortGate,
+	log: log::Logger,
 }
 
 impl PortForwardingProcessor {
-	pub fn new() -> Self {
+	pub fn new(log: log::Logger, target_policy: ForwardTargetPolicy, gate: PortGate) -> Self {
 		let (tx, rx) = mpsc::channel(8);
 		Self {
 			tx,
 			rx,
 			forwarded: HashSet::new(),
+			routes: HostRoutes::default(),
+			routing: false,
+			target_policy,
+			gate,
+			log,
 		}
 	}
 
@@ -57,10 +88,178 @@ impl PortForwardingProcessor {
 			PortForwardingRec::Forward(port, tx) => {
 				tx.send(self.process_forward(port, tunnel).await).ok();
 			}
+			PortForwardingRec::ForwardWithOptions(port, options, tx) => {
+				tx.send(
+					self.process_forward_with_options(port, options, tunnel)
+						.await,
+				)
+				.ok();
+			}
 			PortForwardingRec::Unforward(port, tx) => {
 				tx.send(self.process_unforward(port, tunnel).await).ok();
 			}
+			PortForwardingRec::ForwardMany(ports, tx) => {
+				tx.send(self.process_forward_many(ports, tunnel).await).ok();
+			}
+			PortForwardingRec::UnforwardMany(ports, tx) => {
+				tx.send(self.process_unforward_many(ports, tunnel).await)
+					.ok();
+			}
+			PortForwardingRec::ForwardRoute(route, tx) => {
+				tx.send(self.process_forward_route(route, tunnel).await)
+					.ok();
+			}
+			PortForwardingRec::ForwardTarget(port, target, options, tx) => {
+				tx.send(
+					self.process_forward_target(port, target, options, tunnel)
+						.await,
+				)
+				.ok();
+			}
+		}
+	}
+
+	/// Forwards the port to a host reachable from this machine, if the
+	/// policy allows it.
+	async fn process_forward_target(
+		&mut self,
+		port: u16,
+		target: ForwardTarget,
+		options: PortOptions,
+		tunnel: &mut ActiveTunnel,
+	) -> Result<String, AnyError> {
+		if port == CONTROL_PORT {
+			return Err(CannotForwardControlPort().into());
+		}
+		if !self.gate.is_open() {
+			return Err(ForwardTargetNotFilterable(target.to_string()).into());
+		}
+
+		// checked up front so clients get an error, though it's checked again
+		// for each connection in case the target's addresses change
+		self.target_policy.resolve(&target).await?;
+
+		if self.forwarded.contains(&port) {
+			tunnel.remove_port(port).await?;
+			self.forwarded.remove(&port);
 		}
+
+		let connections = tunnel.add_port_direct_with_options(port, &options).await?;
+		tokio::spawn(serve_forward_target(
+			self.log.clone(),
+			connections,
+			target,
+			self.target_policy.clone(),
+		));
+		self.forwarded.insert(port);
+
+		tunnel.get_port_uri(port).await
+	}
+
+	/// Routes requests for a host or path on the shared HTTP endpoint to the
+	/// port, exposing the endpoint on the tunnel the first time.
+	async fn process_forward_route(
+		&mut self,
+		route: HostRoute,
+		tunnel: &mut ActiveTunnel,
+	) -> Result<String, AnyError> {
+		if route.port == CONTROL_PORT {
+			return Err(CannotForwardControlPort().into());
+		}
+
+		if !self.routing {
+			let connections = tunnel.add_port_direct(HOST_ROUTER_PORT).await?;
+			tokio::spawn(serve_host_router(
+				self.log.clone(),
+				connections,
+				self.routes.clone(),
+				self.gate.clone(),
+			));
+			self.routing = true;
+		}
+
+		let uri = tunnel.get_port_uri(HOST_ROUTER_PORT).await?;
+		let uri = match &route.path {
+			Some(p) => format!("{}{}", uri.trim_end_matches('/'), p),
+			None => uri,
+		};
+
+		self.routes.add(route);
+		Ok(uri)
+	}
+
+	async fn process_unforward_many(
+		&mut self,
+		ports: Vec<u16>,
+		tunnel: &mut ActiveTunnel,
+	) -> Vec<Result<(), AnyError>> {
+		let to_remove: Vec<u16> = ports
+			.iter()
+			.copied()
+			.filter(|p| *p != CONTROL_PORT)
+			.collect();
+		let mut removed = to_remove
+			.iter()
+			.copied()
+			.zip(tunnel.remove_ports(&to_remove).await)
+			.collect::<HashMap<_, _>>();
+
+		ports
+			.into_iter()
+			.map(|port| match removed.remove(&port) {
+				Some(Ok(())) => {
+					self.forwarded.remove(&port);
+					Ok(())
+				}
+				Some(Err(e)) => Err(e),
+				None => Err(CannotForwardControlPort().into()),
+			})
+			.collect()
+	}
+
+	/// Forwards the ports, adding any that are new to the tunnel at once.
+	/// `ports` must not contain duplicates.
+	async fn process_forward_many(
+		&mut self,
+		ports: Vec<u16>,
+		tunnel: &mut ActiveTunnel,
+	) -> Vec<Result<String, AnyError>> {
+		let to_add: Vec<u16> = ports
+			.iter()
+			.copied()
+			.filter(|p| *p != CONTROL_PORT && !self.forwarded.contains(p))
+			.collect();
+		let added = if self.gate.is_open() {
+			tunnel.add_ports_tcp(&to_add).await
+		} else {
+			let mut added = Vec::with_capacity(to_add.len());
+			for port in &to_add {
+				added.push(
+					self.add_gated_port(*port, &PortOptions::default(), tunnel)
+						.await,
+				);
+			}
+			added
+		};
+		let mut added = to_add.iter().copied().zip(added).collect::<HashMap<_, _>>();
+
+		let mut results = Vec::with_capacity(ports.len());
+		for port in ports {
+			if port == CONTROL_PORT {
+				results.push(Err(CannotForwardControlPort().into()));
+				continue;
+			}
+
+			if let Some(Err(e)) = added.remove(&port) {
+				results.push(Err(e));
+				continue;
+			}
+
+			self.forwarded.insert(port);
+			results.push(tunnel.get_port_uri(port).await);
+		}
+
+		results
 	}
 
 	async fn process_unforward(
@@ -72,11 +271,38 @@ impl PortForwardingProcessor {
 			return Err(CannotForwardControlPort().into());
 		}
 
+		// a port that's only routed isn't on the tunnel by itself
+		if self.routes.remove_port(port) && !self.forwarded.contains(&port) {
+			return Ok(());
+		}
+
 		tunnel.remove_port(port).await?;
 		self.forwarded.remove(&port);
 		Ok(())
 	}
 
+	/// Forwards the port with the label and access, replacing it if it was
+	/// already forwarded without them.
+	async fn process_forward_with_options(
+		&mut self,
+		port: u16,
+		options: PortOptions,
+		tunnel: &mut ActiveTunnel,
+	) -> Result<String, AnyError> {
+		if port == CONTROL_PORT {
+			return Err(CannotForwardControlPort().into());
+		}
+
+		if self.forwarded.contains(&port) {
+			tunnel.remove_port(port).await?;
+			self.forwarded.remove(&port);
+		}
+
+		self.add_port(port, &options, tunnel).await?;
+		self.forwarded.insert(port);
+		tunnel.get_port_uri(port).await
+	}
+
 	async fn process_forward(
 		&mut self,
 		port: u16,
@@ -87,12 +313,48 @@ impl PortForwardingProcessor {
 		}
 
 		if !self.forwarded.contains(&port) {
-			tunnel.add_port_tcp(port).await?;
+			self.add_port(port, &PortOptions::default(), tunnel).await?;
 			self.forwarded.insert(port);
 		}
 
 		tunnel.get_port_uri(port).await
 	}
+
+	/// Adds the port to the tunnel, through the gate if client addresses
+	/// are restricted.
+	async fn add_port(
+		&self,
+		port: u16,
+		options: &PortOptions,
+		tunnel: &mut ActiveTunnel,
+	) -> Result<(), AnyError> {
+		if self.gate.is_open() {
+			tunnel.add_port_tcp_with_options(port, options).await
+		} else {
+			self.add_gated_port(port, options, tunnel).await
+		}
+	}
+
+	/// Serves the port through a router that checks each request against the
+	/// gate. The relay would otherwise connect clients to the port without
+	/// the CLI seeing them, so only HTTP can be forwarded this way.
+	async fn add_gated_port(
+		&self,
+		port: u16,
+		options: &PortOptions,
+		tunnel: &mut ActiveTunnel,
+	) -> Result<(), AnyError> {
+		let connections = tunnel.add_port_direct_with_options(port, options).await?;
+		let routes = HostRoutes::default();
+		routes.add(HostRoute::new(None, None, port));
+		tokio::spawn(serve_host_router(
+			self.log.clone(),
+			connections,
+			routes,
+			self.gate.clone(),
+		));
+		Ok(())
+	}
 }
 
 #[derive(Clone)]
@@ -115,6 +377,95 @@ impl PortForwarding {
 		}
 	}
 
+	/// Forwards the port with a label and access other than the tunnel's,
+	/// returning the URI it's available at.
+	pub async fn forward_with_options(
+		&self,
+		port: u16,
+		options: PortOptions,
+	) -> Result<String, AnyError> {
+		let (tx, rx) = oneshot::channel();
+		let req = PortForwardingRec::ForwardWithOptions(port, options, tx);
+
+		if self.tx.send(req).await.is_err() {
+			return Err(ServerHasClosed().into());
+		}
+
+		match rx.await {
+			Ok(r) => r,
+			Err(_) => Err(ServerHasClosed().into()),
+		}
+	}
+
+	/// Routes requests for the host or path on the shared HTTP endpoint to the
+	/// route's port, returning the URI it's available at.
+	pub async fn forward_route(&self, route: HostRoute) -> Result<String, AnyError> {
+		let (tx, rx) = oneshot::channel();
+		let req = PortForwardingRec::ForwardRoute(route, tx);
+
+		if self.tx.send(req).await.is_err() {
+			return Err(ServerHasClosed().into());
+		}
+
+		match rx.await {
+			Ok(r) => r,
+			Err(_) => Err(ServerHasClosed().into()),
+		}
+	}
+
+	/// Forwards the port to the target host and port, with the given label
+	/// and access, returning the URI it's available at.
+	pub async fn forward_target(
+		&self,
+		port: u16,
+		target: ForwardTarget,
+		options: PortOptions,
+	) -> Result<String, AnyError> {
+		let (tx, rx) = oneshot::channel();
+		let req = PortForwardingRec::ForwardTarget(port, target, options, tx);
+
+		if self.tx.send(req).await.is_err() {
+			return Err(ServerHasClosed().into());
+		}
+
+		match rx.await {
+			Ok(r) => r,
+			Err(_) => Err(ServerHasClosed().into()),
+		}
+	}
+
+	/// Forwards many ports in one request, returning the result for each
+	/// port in order. `ports` must not contain duplicates.
+	pub async fn forward_many(
+		&self,
+		ports: Vec<u16>,
+	) -> Result<Vec<Result<String, AnyError>>, AnyError> {
+		let (tx, rx) = oneshot::channel();
+		let req = PortForwardingRec::ForwardMany(ports, tx);
+
+		if self.tx.send(req).await.is_err() {
+			return Err(ServerHasClosed().into());
+		}
+
+		rx.await.map_err(|_| ServerHasClosed().into())
+	}
+
+	/// Stops forwarding many ports in one request, returning the result for
+	/// each port in order. `ports` must not contain duplicates.
+	pub async fn unforward_many(
+		&self,
+		ports: Vec<u16>,
+	) -> Result<Vec<Result<(), AnyError>>, AnyError> {
+		let (tx, rx) = oneshot::channel();
+		let req = PortForwardingRec::UnforwardMany(ports, tx);
+
+		if self.tx.send(req).await.is_err() {
+			return Err(ServerHasClosed().into());
+		}
+
+		rx.await.map_err(|_| ServerHasClosed().into())
+	}
+
 	pub async fn unforward(&self, port: u16) -> Result<(), AnyError> {
 		let (tx, rx) = oneshot::channel();
 		let req = PortForwardingRec::Unforward(port, tx);
@@ -4,7 +4,10 @@
  *--------------------------------------------------------------------------------------------*/
 use std::collections::HashMap;
 
-use crate::options::Quality;
+use crate::{
+	options::Quality,
+	util::{machine::HostResources, progress::ProgressStage},
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Debug)]
@@ -22,6 +25,10 @@ pub enum ServerRequestMethod {
 	forward(ForwardParams),
 	/// Stops forwarding a port from the machine the CLI is running on.
 	unforward(UnforwardParams),
+	/// Forwards many ports at once.
+	forwardmany(ForwardManyParams),
+	/// Stops forwarding many ports at once.
+	unforwardmany(ForwardManyParams),
 	/// Gets the hostname of the machine the CLI is running on.
 	gethostname(EmptyResult),
 	/// Checks for or applies an update to the CLI.
@@ -34,6 +41,19 @@ pub enum ServerRequestMethod {
 	httpheaders(HttpHeadersParams),
 	/// Sent (repeatedly) with data in response to an `makehttpreq` from the server.
 	httpbody(HttpBodyParams),
+	/// Sent in reply to a `hostping` from the server, to measure round-trip time.
+	hostpong(HostPingParams),
+	/// Sent by the client to describe itself.
+	clientinfo(ClientInfoParams),
+	/// Stops the client's VS Code server, keeping the tunnel up. Connections to
+	/// it are sent `serverrestarted` before it stops, and it's started again
+	/// when clients next call `serve`.
+	restartserver(EmptyResult),
+	/// Asks for the host's access to one of its account's tunnels, so a client
+	/// on the same machine, like desktop VS Code, can connect to it without
+	/// logging in. Only answered on the host's local control socket, and for
+	/// tunnels the host's owner allowed, see `session_share`.
+	sharesession(ShareSessionParams),
 }
 
 #[derive(Serialize, Debug)]
@@ -44,6 +64,73 @@ pub enum ClientRequestMethod<'a> {
 	serverlog(ServerLog<'a>),
 	makehttpreq(HttpRequestParams<'a>),
 	version(VersionParams),
+	/// Sent when the host's credentials are about to stop working, after
+	/// which it will go offline until someone signs in again.
+	authwarning(AuthWarningParams),
+	/// Sent periodically; clients should reply with a `hostpong`.
+	hostping(HostPingParams),
+	/// Sent periodically once the client answers pings, describing the
+	/// quality of its connection to the host.
+	connectionquality(ConnectionQualityParams),
+	/// Sent while a `serve` request is setting up the server, as it moves
+	/// through stages like downloading and extracting it.
+	progress(ProgressParams<'a>),
+	/// Sent to every client when the VS Code server was restarted. Their
+	/// sessions were closed, and should be reconnected with `serve`.
+	serverrestarted(ServerRestartedParams),
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ClientInfoParams {
+	/// Address of the client, as seen by the client. It's only informational,
+	/// since clients could report any address: the host's address
+	/// restrictions are checked against the one the relay observed.
+	#[allow(dead_code)]
+	pub address: Option<String>,
+	/// Identity of the user connecting, like their account name, used to
+	/// route them to an alternate server if the host has one. It's reported by
+	/// the client and not verified, and never overrides a `commit_id` given
+	/// to `serve`.
+	#[serde(default)]
+	pub identity: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HostPingParams {
+	pub seq: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ConnectionQualityParams {
+	/// One of "good", "fair", or "poor".
+	pub quality: String,
+	pub rtt_ms: u64,
+	/// Fraction of recent pings, from 0 to 1, that went unanswered.
+	pub drop_rate: f32,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ServerRestartedParams {
+	/// Commits of the servers that were stopped.
+	pub commits: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ProgressParams<'a> {
+	/// Stages from outermost to innermost, like `["download"]`.
+	pub stages: &'a [ProgressStage],
+	/// Progress through the innermost stage, if it's known.
+	pub done: Option<u64>,
+	pub total: Option<u64>,
+	/// Whether the innermost stage just ended.
+	pub ended: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuthWarningParams {
+	pub provider: String,
+	pub expires_at: Option<String>,
+	pub message: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -71,6 +158,33 @@ pub struct HttpHeadersParams {
 #[derive(Deserialize, Debug)]
 pub struct ForwardParams {
 	pub port: u16,
+	/// Host name to route to the port on the shared HTTP endpoint, rather
+	/// than forwarding the port by itself.
+	#[serde(default)]
+	pub host: Option<String>,
+	/// Path prefix to route to the port on the shared HTTP endpoint.
+	#[serde(default)]
+	pub path: Option<String>,
+	/// Host and port reachable from this machine, like `db.internal:5432`,
+	/// to forward the port to instead of the same port on this machine.
+	#[serde(default)]
+	pub target: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ShareSessionParams {
+	/// Name of the tunnel to connect to.
+	pub tunnel: String,
+}
+
+#[derive(Serialize)]
+pub struct ShareSessionResult {
+	pub tunnel_id: String,
+	pub cluster_id: String,
+	/// URI of the tunnel's ports, with `{port}` in place of the port number.
+	pub port_uri_format: String,
+	/// Token the relay accepts from clients connecting to the tunnel.
+	pub access_token: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -83,6 +197,28 @@ pub struct ForwardResult {
 	pub uri: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct ForwardManyParams {
+	pub ports: Vec<u16>,
+}
+
+#[derive(Serialize)]
+pub struct ForwardManyResult {
+	pub ports: Vec<PortForwardResult>,
+}
+
+/// Result of forwarding or unforwarding one port in a batch.
+#[derive(Serialize)]
+pub struct PortForwardResult {
+	pub port: u16,
+	/// URI the port is available at, when forwarding succeeded.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub uri: Option<String>,
+	/// Error message, if the operation failed for this port.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct ServeParams {
 	pub socket_id: u16,
@@ -94,6 +230,11 @@ pub struct ServeParams {
 	/// If true, the client and server should gzip servermsg's sent in either direction.
 	#[serde(default)]
 	pub compress: bool,
+	/// Quality the user asked for, like with `?quality=` on the link, which
+	/// selects the host's alternate server if it's of that quality. Ignored
+	/// if `commit_id` is given.
+	#[serde(default)]
+	pub quality_hint: Option<Quality>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -102,6 +243,10 @@ pub struct EmptyResult {}
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UpdateParams {
 	pub do_update: bool,
+	/// Update even if it's outside the host's maintenance windows, which
+	/// disrupts its sessions.
+	#[serde(default)]
+	pub force: bool,
 }
 
 #[derive(Deserialize, Debug)]
@@ -127,6 +272,11 @@ pub struct UpdateResult {
 #[derive(Deserialize, Debug)]
 pub struct ToServerRequest {
 	pub id: Option<u32>,
+	/// ID of the user command the request was made for, which is written with
+	/// logs and attached to spans for handling it so it can be traced across
+	/// processes.
+	#[serde(default, rename = "correlationId")]
+	pub correlation_id: Option<String>,
 	#[serde(flatten)]
 	pub params: ServerRequestMethod,
 }
@@ -190,4 +340,19 @@ pub struct CallServerHttpResult {
 pub struct VersionParams {
 	pub version: &'static str,
 	pub protocol_version: u32,
+	/// Resource usage on the host, so clients can warn before it runs out.
+	pub resources: HostResources,
+	/// Folders the host's owner trusts, which clients can open without
+	/// prompting for workspace trust.
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub trusted_folders: Vec<String>,
+	/// If set, the server is jailed to this folder, see `FsJail`, and clients
+	/// are denied access to files outside it. Where the jail can't be
+	/// sandboxed, like outside Linux, the server starts there but can reach
+	/// other files, so clients should treat it as a hint.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub workspace_root: Option<String>,
+	/// Whether clients can have ports on the host connect back to their
+	/// machine through `REVERSE_FORWARD_PORT`.
+	pub reverse_forward: bool,
 }
@@ -0,0 +1,210 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Circuit breaker for connections to the tunnel relay. After a few
+//! consecutive failures the circuit opens: the relay is considered
+//! unreachable, that's recorded for `tunnel status`, and reconnects back off
+//! exponentially with jitter and are logged quietly until one succeeds.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	debug, info, log,
+	state::{LauncherPaths, PersistedState},
+	warning,
+};
+
+/// Consecutive failures after which the relay is considered unreachable.
+const FAILURE_THRESHOLD: u32 = 3;
+/// Fraction by which each delay is randomly shortened or lengthened, so
+/// hosts disconnected by the same outage don't all retry in lockstep.
+const JITTER: f64 = 0.2;
+
+/// How reconnects to the relay are retried.
+#[derive(Clone, Copy, Debug)]
+pub struct RelayRetryOptions {
+	/// Delay after the first failure, doubled after each one after that.
+	pub base_delay: Duration,
+	/// Longest delay between attempts.
+	pub max_delay: Duration,
+}
+
+impl Default for RelayRetryOptions {
+	fn default() -> Self {
+		RelayRetryOptions {
+			base_delay: Duration::from_secs(5),
+			max_delay: Duration::from_secs(120),
+		}
+	}
+}
+
+/// Health of the relay connection, as last recorded by the host.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct RelayHealth {
+	/// Set while the circuit is open, to when the relay became unreachable.
+	pub unreachable_since: Option<DateTime<Utc>>,
+	/// Most recent error connecting to the relay.
+	pub last_error: Option<String>,
+}
+
+fn relay_health_path(paths: &LauncherPaths) -> PathBuf {
+	paths.root().join("relay-health.json")
+}
+
+/// Loads the relay health last recorded by the host.
+pub fn load_relay_health(paths: &LauncherPaths) -> RelayHealth {
+	PersistedState::<RelayHealth>::new(relay_health_path(paths)).load()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BreakerState {
+	/// Connections are succeeding, or have only failed a few times.
+	Closed,
+	/// The relay is unreachable.
+	Open,
+	/// The relay was unreachable, and a connection is being attempted.
+	HalfOpen,
+}
+
+pub struct RelayCircuitBreaker {
+	log: log::Logger,
+	options: RelayRetryOptions,
+	health: PersistedState<RelayHealth>,
+	state: BreakerState,
+	failures: u32,
+	opened_at: Option<DateTime<Utc>>,
+}
+
+impl RelayCircuitBreaker {
+	pub fn new(log: log::Logger, paths: &LauncherPaths, options: RelayRetryOptions) -> Self {
+		let health = PersistedState::new(relay_health_path(paths));
+		// clear anything left by a previous host, which is no longer current
+		health.save(RelayHealth::default()).ok();
+
+		RelayCircuitBreaker {
+			log,
+			options,
+			health,
+			state: BreakerState::Closed,
+			failures: 0,
+			opened_at: None,
+		}
+	}
+
+	/// Records a failure to connect to the relay, or a lost connection.
+	pub fn record_failure(&mut self, error: &str) {
+		self.failures += 1;
+
+		match self.state {
+			BreakerState::Closed if self.failures >= FAILURE_THRESHOLD => {
+				let now = Utc::now();
+				warning!(
+					self.log,
+					"Relay unreachable after {} attempts, retrying at most every {}s: {}",
+					self.failures,
+					self.options.max_delay.as_secs(),
+					error
+				);
+				self.transition(BreakerState::Open);
+				self.opened_at = Some(now);
+			}
+			BreakerState::Closed => {
+				warning!(self.log, "{}", error);
+			}
+			BreakerState::Open | BreakerState::HalfOpen => {
+				debug!(self.log, "Relay still unreachable: {}", error);
+				self.transition(BreakerState::Open);
+			}
+		}
+
+		self.health
+			.save(RelayHealth {
+				unreachable_since: self.opened_at,
+				last_error: Some(error.to_string()),
+			})
+			.ok();
+	}
+
+	/// Records a successful connection to the relay.
+	pub fn record_success(&mut self) {
+		if let Some(opened_at) = self.opened_at.take() {
+			info!(
+				self.log,
+				"Relay reachable again after {}s",
+				(Utc::now() - opened_at).num_seconds()
+			);
+			self.health.save(RelayHealth::default()).ok();
+		}
+
+		self.transition(BreakerState::Closed);
+		self.failures = 0;
+	}
+
+	/// Waits before the next attempt.
+	pub async fn delay(&mut self) {
+		tokio::time::sleep(self.next_delay()).await;
+		if self.state == BreakerState::Open {
+			self.transition(BreakerState::HalfOpen);
+		}
+	}
+
+	/// Retries from the shortest delay again, such as when the network may
+	/// have changed, without closing the circuit.
+	pub fn reset_delay(&mut self) {
+		self.failures = self.failures.min(1);
+	}
+
+	fn next_delay(&self) -> Duration {
+		let delay = exponential_delay(&self.options, self.failures);
+		let jitter = rand::thread_rng().gen_range(1.0 - JITTER..=1.0 + JITTER);
+		delay.mul_f64(jitter).min(self.options.max_delay)
+	}
+
+	fn transition(&mut self, to: BreakerState) {
+		if self.state != to {
+			debug!(self.log, "Relay circuit {:?} -> {:?}", self.state, to);
+			self.state = to;
+		}
+	}
+}
+
+impl Drop for RelayCircuitBreaker {
+	fn drop(&mut self) {
+		// the host's stopped, so nothing's unreachable anymore
+		self.health.save(RelayHealth::default()).ok();
+	}
+}
+
+fn exponential_delay(options: &RelayRetryOptions, failures: u32) -> Duration {
+	let exponent = failures.saturating_sub(1).min(16);
+	options
+		.base_delay
+		.checked_mul(1 << exponent)
+		.unwrap_or(options.max_delay)
+		.min(options.max_delay)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_exponential_delay() {
+		let options = RelayRetryOptions {
+			base_delay: Duration::from_secs(5),
+			max_delay: Duration::from_secs(60),
+		};
+		let delays: Vec<u64> = (1..=6)
+			.map(|f| exponential_delay(&options, f).as_secs())
+			.collect();
+		assert_eq!(delays, vec![5, 10, 20, 40, 60, 60]);
+		assert_eq!(exponential_delay(&options, 1000).as_secs(), 60);
+	}
+}
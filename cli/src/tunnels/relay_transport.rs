@@ -0,0 +1,45 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Opens the connection the tunnel's relay websocket runs over. It isn't made
+//! by reqwest, so the proxy its requests go through is applied here instead,
+//! along with the TLS settings, and the stream handed to the relay host to run
+//! the websocket over.
+
+use std::io;
+
+use futures::future::BoxFuture;
+
+use crate::util::{
+	errors::{wrap, AnyError},
+	io::AsyncStream,
+	proxy::connect_through_proxy,
+	tls::tls_connector,
+};
+
+/// Connects to the relay at the websocket URL, through the configured proxy,
+/// wrapping the connection in TLS with the configured settings for `wss` URLs.
+pub fn connect_relay(url: url::Url) -> BoxFuture<'static, io::Result<Box<dyn AsyncStream>>> {
+	Box::pin(async move { connect(&url).await.map_err(to_io_error) })
+}
+
+async fn connect(url: &url::Url) -> Result<Box<dyn AsyncStream>, AnyError> {
+	let host = url.host_str().unwrap_or_default().to_string();
+	let port = url.port_or_known_default().unwrap_or(443);
+	let stream = connect_through_proxy(&host, port).await?;
+	if url.scheme() != "wss" {
+		return Ok(Box::new(stream));
+	}
+
+	let stream = tokio_native_tls::TlsConnector::from(tls_connector()?)
+		.connect(&host, stream)
+		.await
+		.map_err(|e| wrap(e, format!("TLS error connecting to {}", host)))?;
+	Ok(Box::new(stream))
+}
+
+fn to_io_error(e: AnyError) -> io::Error {
+	io::Error::new(io::ErrorKind::Other, e.to_string())
+}
@@ -0,0 +1,583 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Reverse forwarding, where a port on the tunnel's host connects back to a
+//! service on a client's machine, like a license server or database that
+//! only the client can reach. Hosts started with `--allow-reverse-forward`
+//! serve it on `REVERSE_FORWARD_PORT`, upgrading connections like the SSH
+//! bridge does.
+//!
+//! A client first opens a rules connection and sends a JSON line with the
+//! host ports it wants, `{"ports":[5432]}`. The host listens on each on its
+//! loopback address and replies with `{"ports":[{"port":5432}]}`, with an
+//! `error` for ports it couldn't listen on. While the rules connection stays
+//! open, each connection made to those ports is announced with a line like
+//! `{"id":"...","port":5432}`, and the client opens a data connection with
+//! the id in the `X-Reverse-Connection` header, which the host relays the
+//! connection over. The targets the ports connect to are never sent to the
+//! host, and the client asks its user before forwarding each of them. Port 0
+//! and ports asked for more than once are refused before any are listened on.
+
+use std::{
+	collections::HashMap,
+	future::Future,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use hyper::{header::UPGRADE, Body, Request, Response};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+	io::{
+		AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+		BufReader,
+	},
+	net::{TcpListener, TcpStream},
+	pin,
+	sync::{mpsc, oneshot},
+};
+
+use crate::{
+	debug, info, log,
+	util::{
+		errors::{wrap, AnyError, InvalidReversePort, NoReverseForwards},
+		io::AsyncStream,
+	},
+	warning,
+};
+
+use super::{
+	dev_tunnels::PortConnection,
+	forward_targets::ForwardSpec,
+	ip_filter::PortGate,
+	ssh_bridge::{bad_request, connect_bridge, serve_upgrades, switching_protocols},
+};
+
+/// Header naming the announced connection a data connection is for.
+const CONNECTION_HEADER: &str = "X-Reverse-Connection";
+/// How long the host waits for the client to open an announced connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest line read from the other side, so it can't exhaust memory.
+const MAX_LINE_LENGTH: u64 = 4096;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RulesRequest {
+	ports: Vec<u16>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RulesResponse {
+	ports: Vec<RuleResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RuleResult {
+	port: u16,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ConnectMessage {
+	id: String,
+	port: u16,
+}
+
+/// Connections announced to clients, waiting for them to be opened.
+type PendingConnections = Arc<Mutex<HashMap<String, oneshot::Sender<Box<dyn AsyncStream>>>>>;
+
+/// Serves reverse forwarding on connections from the tunnel until the tunnel
+/// is closed.
+pub async fn serve_reverse_forwarding(
+	log: log::Logger,
+	connections: mpsc::UnboundedReceiver<PortConnection>,
+	gate: PortGate,
+) {
+	let pending = PendingConnections::default();
+	serve_upgrades(log, connections, gate, move |log, req| {
+		accept(log, req, pending.clone())
+	})
+	.await
+}
+
+fn accept(log: log::Logger, req: Request<Body>, pending: PendingConnections) -> Response<Body> {
+	if !req.headers().contains_key(UPGRADE) {
+		return bad_request("expected an upgrade request");
+	}
+
+	let opened = match req.headers().get(CONNECTION_HEADER) {
+		Some(id) => {
+			let id = id.to_str().unwrap_or_default();
+			match pending.lock().unwrap().remove(id) {
+				Some(tx) => Some(tx),
+				None => return bad_request("unknown or expired connection"),
+			}
+		}
+		None => None,
+	};
+
+	tokio::spawn(async move {
+		let upgraded = match hyper::upgrade::on(req).await {
+			Ok(u) => u,
+			Err(e) => {
+				debug!(log, "Error upgrading reverse forwarding connection: {}", e);
+				return;
+			}
+		};
+
+		match opened {
+			Some(tx) => {
+				tx.send(Box::new(upgraded)).ok();
+			}
+			None => {
+				if let Err(e) = serve_rules(&log, upgraded, pending).await {
+					debug!(log, "Reverse forwarding closed: {}", e);
+				}
+			}
+		}
+	});
+
+	switching_protocols()
+}
+
+/// Gets why the port at the index can't be forwarded, if it can't: port 0
+/// would listen on a port no one is told about, and a port given twice can
+/// only connect to one target.
+fn port_error(ports: &[u16], index: usize) -> Option<String> {
+	let port = ports[index];
+	if port == 0 {
+		Some("port 0 can't be forwarded".to_string())
+	} else if ports[..index].contains(&port) {
+		Some(format!("port {} was given more than once", port))
+	} else {
+		None
+	}
+}
+
+/// Checks the rules can all be forwarded, before asking the host for them.
+pub fn check_rules(rules: &[ForwardSpec]) -> Result<(), AnyError> {
+	let ports: Vec<u16> = rules.iter().map(|r| r.port).collect();
+	match (0..ports.len()).find_map(|i| port_error(&ports, i)) {
+		Some(e) => Err(InvalidReversePort(e).into()),
+		None => Ok(()),
+	}
+}
+
+/// Listens on the ports the client asks for, announcing connections made to
+/// them, until the client closes the rules connection.
+async fn serve_rules(
+	log: &log::Logger,
+	rules_connection: impl AsyncRead + AsyncWrite,
+	pending: PendingConnections,
+) -> Result<(), AnyError> {
+	let (read, mut write) = tokio::io::split(rules_connection);
+	let mut read = BufReader::new(read);
+	let request: RulesRequest = match read_message(&mut read).await? {
+		Some(r) => r,
+		None => return Ok(()),
+	};
+
+	// every port's checked before any are listened on
+	let mut results = vec![];
+	let mut valid = vec![];
+	for (i, port) in request.ports.iter().enumerate() {
+		match port_error(&request.ports, i) {
+			Some(error) => results.push(RuleResult {
+				port: *port,
+				error: Some(error),
+			}),
+			None => valid.push(*port),
+		}
+	}
+
+	let (accepted_tx, mut accepted_rx) = mpsc::channel(8);
+	let mut listeners = vec![];
+	for port in valid {
+		match TcpListener::bind(("127.0.0.1", port)).await {
+			Ok(listener) => {
+				info!(log, "Reverse forwarding port {} to a client", port);
+				listeners.push(tokio::spawn(accept_connections(
+					listener,
+					port,
+					accepted_tx.clone(),
+				)));
+				results.push(RuleResult { port, error: None });
+			}
+			Err(e) => results.push(RuleResult {
+				port,
+				error: Some(e.to_string()),
+			}),
+		}
+	}
+	drop(accepted_tx);
+
+	let result = async {
+		write_message(&mut write, &RulesResponse { ports: results }).await?;
+
+		// nothing more is sent on the rules connection, so it's read only to
+		// notice when the client goes away
+		let closed = tokio::io::copy(&mut read, &mut tokio::io::sink());
+		pin!(closed);
+		loop {
+			tokio::select! {
+				_ = &mut closed => return Ok::<(), AnyError>(()),
+				Some((port, stream)) = accepted_rx.recv() => {
+					let id = uuid::Uuid::new_v4().to_simple().to_string();
+					let (tx, rx) = oneshot::channel();
+					pending.lock().unwrap().insert(id.clone(), tx);
+					write_message(&mut write, &ConnectMessage { id: id.clone(), port }).await?;
+					tokio::spawn(relay_announced(log.clone(), pending.clone(), id, stream, rx));
+				}
+			}
+		}
+	}
+	.await;
+
+	for l in listeners {
+		l.abort();
+	}
+	info!(log, "Stopped reverse forwarding to a client");
+	result
+}
+
+async fn accept_connections(
+	listener: TcpListener,
+	port: u16,
+	accepted: mpsc::Sender<(u16, TcpStream)>,
+) {
+	while let Ok((stream, _)) = listener.accept().await {
+		if accepted.send((port, stream)).await.is_err() {
+			return;
+		}
+	}
+}
+
+/// Relays the connection once the client opens the data connection for it.
+async fn relay_announced(
+	log: log::Logger,
+	pending: PendingConnections,
+	id: String,
+	mut stream: TcpStream,
+	opened: oneshot::Receiver<Box<dyn AsyncStream>>,
+) {
+	let mut upgraded = match tokio::time::timeout(CONNECT_TIMEOUT, opened).await {
+		Ok(Ok(u)) => u,
+		_ => {
+			pending.lock().unwrap().remove(&id);
+			debug!(log, "Client did not open reverse connection {}", id);
+			return;
+		}
+	};
+
+	match tokio::io::copy_bidirectional(&mut stream, &mut upgraded).await {
+		Ok((tx, rx)) => debug!(
+			log,
+			"Reverse connection {} closed after {}B out, {}B in", id, tx, rx
+		),
+		Err(e) => debug!(log, "Reverse connection {} failed: {}", id, e),
+	}
+}
+
+/// Connects to reverse forwarding at the port URI, asking the host to listen
+/// on the port of each rule and relaying connections made to it to the rule's
+/// target from this machine. Runs until Ctrl+C is pressed or the host closes
+/// the connection.
+pub async fn forward_reverse(
+	log: &log::Logger,
+	port_uri: &str,
+	access_token: &str,
+	rules: &[ForwardSpec],
+) -> Result<(), AnyError> {
+	check_rules(rules)?;
+	let upgraded = connect_bridge(port_uri, access_token, None).await?;
+	let (port_uri, access_token) = (port_uri.to_string(), access_token.to_string());
+	let open = move |id: String| {
+		let (port_uri, access_token) = (port_uri.clone(), access_token.clone());
+		async move { connect_bridge(&port_uri, &access_token, Some((CONNECTION_HEADER, &id))).await }
+	};
+
+	tokio::select! {
+		r = forward_over(log, upgraded, rules, open) => r,
+		_ = tokio::signal::ctrl_c() => Ok(()),
+	}
+}
+
+/// Asks the host for the rules' ports on the rules connection, relaying each
+/// connection it announces over the data connection `open` makes for its id.
+async fn forward_over<F, Fut, D>(
+	log: &log::Logger,
+	rules_connection: impl AsyncRead + AsyncWrite,
+	rules: &[ForwardSpec],
+	open: F,
+) -> Result<(), AnyError>
+where
+	F: Fn(String) -> Fut,
+	Fut: Future<Output = Result<D, AnyError>> + Send + 'static,
+	D: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	let (read, mut write) = tokio::io::split(rules_connection);
+	let mut read = BufReader::new(read);
+
+	write_message(
+		&mut write,
+		&RulesRequest {
+			ports: rules.iter().map(|r| r.port).collect(),
+		},
+	)
+	.await?;
+	let response: RulesResponse = read_message(&mut read)
+		.await?
+		.ok_or_else(|| wrap("connection closed", "the host did not answer"))?;
+
+	let mut forwarded = 0;
+	for r in &response.ports {
+		let target = match rules.iter().find(|rule| rule.port == r.port) {
+			Some(rule) => &rule.target,
+			None => continue,
+		};
+		match &r.error {
+			Some(e) => warning!(
+				log,
+				"Could not forward port {} on the host to {}: {}",
+				r.port,
+				target,
+				e
+			),
+			None => {
+				log.result(format!(
+					"Port {} on the host connects to {} on this machine",
+					r.port, target
+				));
+				forwarded += 1;
+			}
+		}
+	}
+	if forwarded == 0 {
+		return Err(NoReverseForwards().into());
+	}
+	log.result("Press Ctrl+C to stop.");
+
+	loop {
+		let message: ConnectMessage = match read_message(&mut read).await? {
+			Some(m) => m,
+			None => {
+				info!(log, "The host closed the connection");
+				return Ok(());
+			}
+		};
+
+		let target = match rules.iter().find(|rule| rule.port == message.port) {
+			Some(rule) => rule.target.clone(),
+			None => continue,
+		};
+		let log = log.clone();
+		let port = message.port;
+		let opened = open(message.id);
+		tokio::spawn(async move {
+			let result = async {
+				let mut local = TcpStream::connect((target.host.as_str(), target.port))
+					.await
+					.map_err(|e| wrap(e, format!("error connecting to {}", target)))?;
+				let mut remote = opened.await?;
+				tokio::io::copy_bidirectional(&mut local, &mut remote)
+					.await
+					.map_err(|e| wrap(e, "error relaying data"))?;
+				Ok::<_, AnyError>(())
+			}
+			.await;
+
+			if let Err(e) = result {
+				warning!(
+					log,
+					"Connection from port {} on the host failed: {}",
+					port,
+					e
+				);
+			}
+		});
+	}
+}
+
+async fn read_message<T: DeserializeOwned>(
+	read: &mut (impl AsyncBufRead + Unpin),
+) -> Result<Option<T>, AnyError> {
+	let mut line = String::new();
+	let n = read
+		.take(MAX_LINE_LENGTH)
+		.read_line(&mut line)
+		.await
+		.map_err(|e| wrap(e, "error reading from reverse forwarding connection"))?;
+	if n == 0 {
+		return Ok(None);
+	}
+
+	serde_json::from_str(&line)
+		.map(Some)
+		.map_err(|e| wrap(e, "invalid reverse forwarding message").into())
+}
+
+async fn write_message(
+	write: &mut (impl AsyncWrite + Unpin),
+	message: &impl Serialize,
+) -> Result<(), AnyError> {
+	let mut line = serde_json::to_vec(message).unwrap();
+	line.push(b'\n');
+	write
+		.write_all(&line)
+		.await
+		.map_err(|e| wrap(e, "error writing to reverse forwarding connection").into())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_messages_round_trip() {
+		let (client, server) = tokio::io::duplex(1024);
+		let (_, mut write) = tokio::io::split(client);
+		let (read, _) = tokio::io::split(server);
+		let mut read = BufReader::new(read);
+
+		write_message(
+			&mut write,
+			&RulesResponse {
+				ports: vec![
+					RuleResult {
+						port: 5432,
+						error: None,
+					},
+					RuleResult {
+						port: 80,
+						error: Some("in use".to_string()),
+					},
+				],
+			},
+		)
+		.await
+		.unwrap();
+		drop(write);
+
+		let response: RulesResponse = read_message(&mut read).await.unwrap().unwrap();
+		assert_eq!(response.ports.len(), 2);
+		assert_eq!(response.ports[0].port, 5432);
+		assert!(response.ports[0].error.is_none());
+		assert_eq!(response.ports[1].error.as_deref(), Some("in use"));
+
+		let eof: Option<ConnectMessage> = read_message(&mut read).await.unwrap();
+		assert!(eof.is_none());
+	}
+
+	#[test]
+	fn test_port_errors() {
+		let ports = [5432, 0, 8080, 5432];
+		assert_eq!(port_error(&ports, 0), None);
+		assert!(port_error(&ports, 1).is_some());
+		assert_eq!(port_error(&ports, 2), None);
+		assert!(port_error(&ports, 3).unwrap().contains("more than once"));
+
+		let rules: Vec<ForwardSpec> = vec![
+			"5432:localhost:5432".parse().unwrap(),
+			"5432:localhost:5433".parse().unwrap(),
+		];
+		assert!(matches!(
+			check_rules(&rules),
+			Err(AnyError::InvalidReversePort(_))
+		));
+		assert!(check_rules(&rules[..1]).is_ok());
+	}
+
+	#[tokio::test]
+	async fn test_refuses_invalid_ports_before_listening() {
+		let (client, host) = tokio::io::duplex(1024);
+		let serving = tokio::spawn(async move {
+			serve_rules(&log::Logger::test(), host, PendingConnections::default()).await
+		});
+
+		let (read, mut write) = tokio::io::split(client);
+		let mut read = BufReader::new(read);
+		write_message(&mut write, &RulesRequest { ports: vec![0, 0] })
+			.await
+			.unwrap();
+		let response: RulesResponse = read_message(&mut read).await.unwrap().unwrap();
+		assert_eq!(response.ports.len(), 2);
+		assert!(response.ports.iter().all(|r| r.error.is_some()));
+
+		drop((read, write));
+		serving.await.unwrap().unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_forwards_end_to_end() {
+		// a service only the client's machine can reach, which echoes back
+		let target = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+		let target_port = target.local_addr().unwrap().port();
+		tokio::spawn(async move {
+			while let Ok((mut stream, _)) = target.accept().await {
+				tokio::spawn(async move {
+					let (mut read, mut write) = stream.split();
+					tokio::io::copy(&mut read, &mut write).await.ok();
+				});
+			}
+		});
+
+		let host_port = {
+			let free = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+			free.local_addr().unwrap().port()
+		};
+		let rules: Vec<ForwardSpec> = vec![format!("{}:127.0.0.1:{}", host_port, target_port)
+			.parse()
+			.unwrap()];
+
+		let log = log::Logger::test();
+		let pending = PendingConnections::default();
+		let (client, host) = tokio::io::duplex(1024);
+		let serving = tokio::spawn({
+			let (log, pending) = (log.clone(), pending.clone());
+			async move { serve_rules(&log, host, pending).await }
+		});
+
+		// data connections are handed to the host like `accept` does
+		let open = move |id: String| {
+			let pending = pending.clone();
+			async move {
+				let (client, host) = tokio::io::duplex(1024);
+				let tx = pending
+					.lock()
+					.unwrap()
+					.remove(&id)
+					.ok_or_else(|| wrap("", "unknown connection"))?;
+				tx.send(Box::new(host)).ok();
+				Ok::<_, AnyError>(client)
+			}
+		};
+		let forwarding =
+			tokio::spawn(async move { forward_over(&log, client, &rules, open).await });
+
+		let mut stream = loop {
+			match TcpStream::connect(("127.0.0.1", host_port)).await {
+				Ok(s) => break s,
+				Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+			}
+		};
+		stream.write_all(b"hello").await.unwrap();
+		let mut buf = [0; 5];
+		stream.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"hello");
+
+		forwarding.abort();
+		serving.await.unwrap().unwrap();
+	}
+
+	#[test]
+	fn test_omits_missing_errors() {
+		let json = serde_json::to_string(&RuleResult {
+			port: 5432,
+			error: None,
+		})
+		.unwrap();
+		assert_eq!(json, r#"{"port":5432}"#);
+	}
+}
@@ -0,0 +1,273 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Checks for `tunnel doctor --security`, covering who else on the machine
+//! could read the CLI's data, tokens, and server sockets, and how the service
+//! is confined. Each check can suggest a command to fix what it finds, and
+//! most can be fixed with `--fix`.
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+	auth::{Auth, TokenStorage},
+	state::LauncherPaths,
+	util::io::restrict_to_owner,
+};
+
+use super::{load_service_registration, ServiceManager};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckStatus {
+	Pass,
+	Warn,
+	Fail,
+	/// The check doesn't apply, or can't be done on this platform.
+	Skipped,
+}
+
+/// Fix that `--fix` can apply.
+pub enum SecurityFix {
+	/// Restricts the paths to their owner, with the given Unix mode.
+	Restrict(Vec<PathBuf>, u32),
+	/// Registers the service again, so its definition has current hardening.
+	ReregisterService,
+}
+
+pub struct SecurityCheck {
+	pub name: &'static str,
+	pub status: CheckStatus,
+	pub detail: String,
+	/// Command the user can run to fix the problem themselves.
+	pub remediation: Option<String>,
+	pub fix: Option<SecurityFix>,
+}
+
+impl SecurityCheck {
+	fn new(name: &'static str, status: CheckStatus, detail: impl Into<String>) -> Self {
+		SecurityCheck {
+			name,
+			status,
+			detail: detail.into(),
+			remediation: None,
+			fix: None,
+		}
+	}
+
+	fn with_remediation(mut self, remediation: impl Into<String>) -> Self {
+		self.remediation = Some(remediation.into());
+		self
+	}
+
+	fn with_fix(mut self, fix: SecurityFix) -> Self {
+		self.fix = Some(fix);
+		self
+	}
+}
+
+/// Runs all security checks.
+pub fn audit(
+	paths: &LauncherPaths,
+	auth: &Auth,
+	service: &impl ServiceManager,
+) -> Vec<SecurityCheck> {
+	vec![
+		check_data_dir(paths),
+		check_token_storage(auth),
+		check_server_sockets(),
+		check_service_hardening(paths, service),
+	]
+}
+
+/// Scores the checks out of 100, counting warnings as half a pass. Skipped
+/// checks aren't counted.
+pub fn score(checks: &[SecurityCheck]) -> u32 {
+	let (points, total) = checks.iter().fold((0, 0), |(p, t), c| match c.status {
+		CheckStatus::Pass => (p + 2, t + 2),
+		CheckStatus::Warn => (p + 1, t + 2),
+		CheckStatus::Fail => (p, t + 2),
+		CheckStatus::Skipped => (p, t),
+	});
+
+	if total == 0 {
+		100
+	} else {
+		points * 100 / total
+	}
+}
+
+/// Restricts the paths to their owner, for `SecurityFix::Restrict`.
+pub fn restrict_paths(paths: &[PathBuf], mode: u32) -> std::io::Result<()> {
+	paths.iter().try_for_each(|p| restrict_to_owner(p, mode))
+}
+
+fn check_data_dir(paths: &LauncherPaths) -> SecurityCheck {
+	const NAME: &str = "Data directory";
+	let dir = paths.root();
+	match accessible_by_others(dir) {
+		None => SecurityCheck::new(NAME, CheckStatus::Skipped, "ACLs aren't checked"),
+		Some(false) => SecurityCheck::new(
+			NAME,
+			CheckStatus::Pass,
+			format!("{} is only accessible by its owner", dir.display()),
+		),
+		Some(true) => SecurityCheck::new(
+			NAME,
+			CheckStatus::Fail,
+			format!(
+				"{} is accessible by other users, and holds logs and state",
+				dir.display()
+			),
+		)
+		.with_remediation(format!("chmod 700 '{}'", dir.display()))
+		.with_fix(SecurityFix::Restrict(vec![dir.to_path_buf()], 0o700)),
+	}
+}
+
+fn check_token_storage(auth: &Auth) -> SecurityCheck {
+	const NAME: &str = "Token storage";
+	let file = match auth.token_storage() {
+		TokenStorage::Keyring => {
+			return SecurityCheck::new(
+				NAME,
+				CheckStatus::Pass,
+				"Tokens are stored in the OS keyring",
+			)
+		}
+		TokenStorage::File(f) => f,
+	};
+
+	let remediation = if std::env::var("VSCODE_CLI_USE_FILE_KEYCHAIN").is_ok() {
+		"unset VSCODE_CLI_USE_FILE_KEYCHAIN, then log in again".to_string()
+	} else {
+		"install and unlock a keyring, such as gnome-keyring, then log in again".to_string()
+	};
+
+	if file.exists() && accessible_by_others(&file) == Some(true) {
+		return SecurityCheck::new(
+			NAME,
+			CheckStatus::Fail,
+			format!(
+				"Tokens are stored in {}, which other users can read",
+				file.display()
+			),
+		)
+		.with_remediation(format!("chmod 600 '{}'", file.display()))
+		.with_fix(SecurityFix::Restrict(vec![file], 0o600));
+	}
+
+	SecurityCheck::new(
+		NAME,
+		CheckStatus::Warn,
+		format!(
+			"Tokens are stored in {} because no keyring is available",
+			file.display()
+		),
+	)
+	.with_remediation(remediation)
+}
+
+#[cfg(unix)]
+fn check_server_sockets() -> SecurityCheck {
+	use crate::util::tempfile::temp_root;
+	use std::os::unix::fs::FileTypeExt;
+
+	const NAME: &str = "Server sockets";
+	let exposed: Vec<PathBuf> = std::fs::read_dir(temp_root())
+		.map(|entries| {
+			entries
+				.filter_map(|e| e.ok())
+				.filter(|e| {
+					e.file_name()
+						.to_string_lossy()
+						.starts_with("vscode-server-")
+				})
+				.filter(|e| matches!(e.file_type(), Ok(t) if t.is_socket()))
+				.map(|e| e.path())
+				.filter(|p| accessible_by_others(p) == Some(true))
+				.collect()
+		})
+		.unwrap_or_default();
+
+	if exposed.is_empty() {
+		return SecurityCheck::new(
+			NAME,
+			CheckStatus::Pass,
+			"Server sockets are only accessible by their owner",
+		);
+	}
+
+	SecurityCheck::new(
+		NAME,
+		CheckStatus::Fail,
+		format!(
+			"{} server socket(s) in {} can be connected to by other users",
+			exposed.len(),
+			temp_root().display()
+		),
+	)
+	.with_remediation(format!(
+		"chmod 600 {}",
+		exposed
+			.iter()
+			.map(|p| format!("'{}'", p.display()))
+			.collect::<Vec<_>>()
+			.join(" ")
+	))
+	.with_fix(SecurityFix::Restrict(exposed, 0o600))
+}
+
+#[cfg(windows)]
+fn check_server_sockets() -> SecurityCheck {
+	SecurityCheck::new(
+		"Server sockets",
+		CheckStatus::Skipped,
+		"Named pipe ACLs aren't checked",
+	)
+}
+
+fn check_service_hardening(paths: &LauncherPaths, service: &impl ServiceManager) -> SecurityCheck {
+	const NAME: &str = "Service hardening";
+	if load_service_registration(paths).is_none() {
+		return SecurityCheck::new(NAME, CheckStatus::Skipped, "The service isn't registered");
+	}
+
+	match service.missing_hardening() {
+		None => SecurityCheck::new(
+			NAME,
+			CheckStatus::Skipped,
+			"Service definitions aren't checked",
+		),
+		Some(missing) if missing.is_empty() => SecurityCheck::new(
+			NAME,
+			CheckStatus::Pass,
+			"The service definition has all hardening settings",
+		),
+		Some(missing) => SecurityCheck::new(
+			NAME,
+			CheckStatus::Warn,
+			format!("The service definition is missing {}", missing.join(", ")),
+		)
+		.with_remediation(format!(
+			"{} tunnel service install",
+			crate::constants::APPLICATION_NAME
+		))
+		.with_fix(SecurityFix::ReregisterService),
+	}
+}
+
+/// Gets whether users other than the owner can access the path, or None if
+/// that isn't checked on this platform.
+#[cfg(unix)]
+fn accessible_by_others(path: &Path) -> Option<bool> {
+	use std::os::unix::fs::PermissionsExt;
+	std::fs::metadata(path)
+		.ok()
+		.map(|m| m.permissions().mode() & 0o077 != 0)
+}
+
+#[cfg(windows)]
+fn accessible_by_others(_path: &Path) -> Option<bool> {
+	None
+}
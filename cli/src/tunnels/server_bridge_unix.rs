@@ -9,13 +9,21 @@ use tokio::{
 	net::{unix::OwnedWriteHalf, UnixStream},
 };
 
-use crate::util::errors::{wrap, AnyError};
+use crate::{
+	log::{self, SpanCounters},
+	util::errors::{wrap, AnyError},
+};
 
-use super::socket_signal::{ClientMessageDecoder, ServerMessageSink};
+use super::{
+	session_recording::{Direction, SessionRecorder},
+	socket_signal::{ClientMessageDecoder, ServerMessageSink},
+};
 
 pub struct ServerBridge {
 	write: OwnedWriteHalf,
 	decoder: ClientMessageDecoder,
+	counters: SpanCounters,
+	recorder: Option<SessionRecorder>,
 }
 
 pub async fn get_socket_rw_stream(path: &Path) -> Result<UnixStream, AnyError> {
@@ -40,11 +48,16 @@ impl ServerBridge {
 		index: u16,
 		mut target: ServerMessageSink,
 		decoder: ClientMessageDecoder,
+		recorder: Option<SessionRecorder>,
+		log: &log::Logger,
 	) -> Result<Self, AnyError> {
 		let stream = get_socket_rw_stream(path).await?;
 		let (mut read, write) = stream.into_split();
 
-		tokio::spawn(async move {
+		let counters = SpanCounters::default();
+		let read_counters = counters.clone();
+		let read_recorder = recorder.clone();
+		log::spawn_with_heartbeat(log, "server.bridge", Some(counters.clone()), async move {
 			let mut read_buf = vec![0; BUFFER_SIZE];
 			loop {
 				match read.read(&mut read_buf).await {
@@ -54,6 +67,10 @@ impl ServerBridge {
 						return; // EOF
 					}
 					Ok(s) => {
+						read_counters.add_rx(s);
+						if let Some(r) = &read_recorder {
+							r.record(Direction::Output, &read_buf[..s]);
+						}
 						let send = target.server_message(index, &read_buf[..s]).await;
 						if send.is_err() {
 							return;
@@ -63,12 +80,21 @@ impl ServerBridge {
 			}
 		});
 
-		Ok(ServerBridge { write, decoder })
+		Ok(ServerBridge {
+			write,
+			decoder,
+			counters,
+			recorder,
+		})
 	}
 
 	pub async fn write(&mut self, b: Vec<u8>) -> std::io::Result<()> {
 		let dec = self.decoder.decode(&b)?;
 		if !dec.is_empty() {
+			self.counters.add_tx(dec.len());
+			if let Some(r) = &self.recorder {
+				r.record(Direction::Input, dec);
+			}
 			self.write.write_all(dec).await?;
 		}
 		Ok(())
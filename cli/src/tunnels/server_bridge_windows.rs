@@ -12,13 +12,21 @@ use tokio::{
 	time::sleep,
 };
 
-use crate::util::errors::{wrap, AnyError};
+use crate::{
+	log::{self, SpanCounters},
+	util::errors::{wrap, AnyError},
+};
 
-use super::socket_signal::{ClientMessageDecoder, ServerMessageSink};
+use super::{
+	session_recording::{Direction, SessionRecorder},
+	socket_signal::{ClientMessageDecoder, ServerMessageSink},
+};
 
 pub struct ServerBridge {
 	write_tx: mpsc::Sender<Vec<u8>>,
 	decoder: ClientMessageDecoder,
+	counters: SpanCounters,
+	recorder: Option<SessionRecorder>,
 }
 
 const BUFFER_SIZE: usize = 65536;
@@ -52,10 +60,15 @@ impl ServerBridge {
 		index: u16,
 		mut target: ServerMessageSink,
 		decoder: ClientMessageDecoder,
+		recorder: Option<SessionRecorder>,
+		log: &log::Logger,
 	) -> Result<Self, AnyError> {
 		let client = get_socket_rw_stream(path).await?;
 		let (write_tx, mut write_rx) = mpsc::channel(4);
-		tokio::spawn(async move {
+		let counters = SpanCounters::default();
+		let read_counters = counters.clone();
+		let read_recorder = recorder.clone();
+		log::spawn_with_heartbeat(log, "server.bridge", Some(counters.clone()), async move {
 			let mut read_buf = vec![0; BUFFER_SIZE];
 			let mut pending_recv: Option<Vec<u8>> = None;
 
@@ -88,6 +101,10 @@ impl ServerBridge {
 					match client.try_read(&mut read_buf) {
 						Ok(0) => return, // EOF
 						Ok(s) => {
+							read_counters.add_rx(s);
+							if let Some(r) = &read_recorder {
+								r.record(Direction::Output, &read_buf[..s]);
+							}
 							let send = target.server_message(index, &read_buf[..s]).await;
 							if send.is_err() {
 								return;
@@ -115,12 +132,21 @@ impl ServerBridge {
 			}
 		});
 
-		Ok(ServerBridge { write_tx, decoder })
+		Ok(ServerBridge {
+			write_tx,
+			decoder,
+			counters,
+			recorder,
+		})
 	}
 
 	pub async fn write(&mut self, b: Vec<u8>) -> std::io::Result<()> {
 		let dec = self.decoder.decode(&b)?;
 		if !dec.is_empty() {
+			self.counters.add_tx(dec.len());
+			if let Some(r) = &self.recorder {
+				r.record(Direction::Input, dec);
+			}
 			self.write_tx.send(dec.to_vec()).await.ok();
 		}
 		Ok(())
@@ -0,0 +1,436 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Installs a server by updating the install of an earlier commit with a
+//! delta, rather than downloading the whole server, since only a few files
+//! change between most commits.
+//!
+//! A delta is a zstd-compressed tarball. Its first entry is a manifest,
+//! `delta.json`, listing the files that were added, patched, or removed, and
+//! their digests. Added files are in `files/` in full, and patched files are
+//! in `patches/` as bsdiff patches against the earlier commit's copy.
+
+use std::{
+	collections::HashSet,
+	fs,
+	io::Read,
+	path::{Component, Path, PathBuf},
+};
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::util::errors::{wrap, AnyError, InvalidServerArchive};
+
+use super::paths::ServerPaths;
+
+const MANIFEST_ENTRY: &str = "delta.json";
+const FILES_DIR: &str = "files";
+const PATCHES_DIR: &str = "patches";
+
+#[derive(Deserialize)]
+struct DeltaManifest {
+	from: String,
+	to: String,
+	#[serde(default)]
+	added: Vec<DeltaFile>,
+	#[serde(default)]
+	patched: Vec<PatchedFile>,
+	#[serde(default)]
+	removed: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct DeltaFile {
+	path: String,
+	sha256: String,
+}
+
+#[derive(Deserialize)]
+struct PatchedFile {
+	path: String,
+	/// Digest of the earlier commit's copy, which the patch applies to.
+	base_sha256: String,
+	sha256: String,
+}
+
+fn invalid(message: impl Into<String>) -> AnyError {
+	InvalidServerArchive(format!("delta {}", message.into())).into()
+}
+
+/// Installs the `to` commit into `target` by copying the install of the
+/// `from` commit in `base` and applying the delta to it. Every file the delta
+/// writes is checked against the manifest's digests, and patches are only
+/// applied to files whose digests match what they were made against, so a
+/// base that was changed, such as by patching its binaries, fails rather
+/// than giving a broken install. On failure, the target may be left partly
+/// written and should be emptied.
+pub fn apply_delta(
+	delta: &Path,
+	base: &ServerPaths,
+	target: &ServerPaths,
+	from: &str,
+	to: &str,
+) -> Result<(), AnyError> {
+	let file = fs::File::open(delta).map_err(|e| wrap(e, "error opening delta"))?;
+	let decoder =
+		zstd::stream::read::Decoder::new(file).map_err(|e| wrap(e, "error opening delta"))?;
+	let mut archive = tar::Archive::new(decoder);
+	let mut entries = archive
+		.entries()
+		.map_err(|e| wrap(e, "error reading delta"))?;
+
+	let manifest: DeltaManifest = match entries.next() {
+		Some(Ok(mut e))
+			if e.path()
+				.map(|p| p == Path::new(MANIFEST_ENTRY))
+				.unwrap_or(false) =>
+		{
+			let mut s = String::new();
+			e.read_to_string(&mut s)
+				.map_err(|e| wrap(e, "error reading delta manifest"))?;
+			serde_json::from_str(&s).map_err(|e| invalid(format!("manifest is invalid: {}", e)))?
+		}
+		_ => return Err(invalid(format!("doesn't start with {}", MANIFEST_ENTRY))),
+	};
+
+	if manifest.from != from || manifest.to != to {
+		return Err(invalid(format!(
+			"is from {} to {}, expected {} to {}",
+			manifest.from, manifest.to, from, to
+		)));
+	}
+
+	copy_install(base, &base.server_dir, &target.server_dir)?;
+
+	let mut remaining: HashSet<PathBuf> = HashSet::new();
+	for f in &manifest.added {
+		remaining.insert(Path::new(FILES_DIR).join(safe_path(&f.path)?));
+	}
+	for f in &manifest.patched {
+		remaining.insert(Path::new(PATCHES_DIR).join(safe_path(&f.path)?));
+	}
+
+	for entry in entries {
+		let mut entry = entry.map_err(|e| wrap(e, "error reading delta"))?;
+		let entry_path = entry
+			.path()
+			.map_err(|e| wrap(e, "error reading delta"))?
+			.into_owned();
+		if !entry.header().entry_type().is_file() {
+			continue;
+		}
+		if !remaining.remove(&entry_path) {
+			return Err(invalid(format!(
+				"has {}, which isn't in its manifest",
+				entry_path.display()
+			)));
+		}
+
+		let mut contents = vec![];
+		entry
+			.read_to_end(&mut contents)
+			.map_err(|e| wrap(e, "error reading delta"))?;
+
+		if let Ok(rel) = entry_path.strip_prefix(FILES_DIR) {
+			let f = manifest
+				.added
+				.iter()
+				.find(|f| Path::new(&f.path) == rel)
+				.ok_or_else(|| invalid(format!("has no digest for {}", rel.display())))?;
+			check_digest(rel, &contents, &f.sha256)?;
+			write_file(
+				&target.server_dir.join(rel),
+				&contents,
+				entry.header().mode().ok(),
+			)?;
+		} else if let Ok(rel) = entry_path.strip_prefix(PATCHES_DIR) {
+			let f = manifest
+				.patched
+				.iter()
+				.find(|f| Path::new(&f.path) == rel)
+				.ok_or_else(|| invalid(format!("has no digest for {}", rel.display())))?;
+			let old = fs::read(base.server_dir.join(rel))
+				.map_err(|e| wrap(e, format!("error reading {}", rel.display())))?;
+			check_digest(rel, &old, &f.base_sha256)?;
+
+			let mut new = Vec::with_capacity(old.len());
+			bsdiff::patch(&old, &mut contents.as_slice(), &mut new)
+				.map_err(|e| invalid(format!("patch for {} is invalid: {}", rel.display(), e)))?;
+			check_digest(rel, &new, &f.sha256)?;
+			// the copy from the base keeps its permissions
+			write_file(&target.server_dir.join(rel), &new, None)?;
+		}
+	}
+
+	if let Some(missing) = remaining.iter().next() {
+		return Err(invalid(format!("is missing {}", missing.display())));
+	}
+
+	for path in &manifest.removed {
+		let path = target.server_dir.join(safe_path(path)?);
+		if path.is_dir() {
+			fs::remove_dir_all(&path).ok();
+		} else {
+			fs::remove_file(&path).ok();
+		}
+	}
+
+	Ok(())
+}
+
+/// Checks that a path from the delta stays inside the install.
+fn safe_path(path: &str) -> Result<PathBuf, AnyError> {
+	let p = PathBuf::from(path);
+	if p.as_os_str().is_empty() || !p.components().all(|c| matches!(c, Component::Normal(_))) {
+		return Err(invalid(format!("has an invalid path '{}'", path)));
+	}
+
+	Ok(p)
+}
+
+fn check_digest(path: &Path, contents: &[u8], expected: &str) -> Result<(), AnyError> {
+	let actual = format!("{:x}", Sha256::digest(contents));
+	if !actual.eq_ignore_ascii_case(expected) {
+		return Err(invalid(format!(
+			"digest of {} is {}, expected {}",
+			path.display(),
+			actual,
+			expected
+		)));
+	}
+
+	Ok(())
+}
+
+fn write_file(path: &Path, contents: &[u8], mode: Option<u32>) -> Result<(), AnyError> {
+	if let Some(parent) = path.parent() {
+		fs::create_dir_all(parent)
+			.map_err(|e| wrap(e, format!("error creating {}", parent.display())))?;
+	}
+	fs::write(path, contents).map_err(|e| wrap(e, format!("error writing {}", path.display())))?;
+
+	#[cfg(unix)]
+	if let Some(mode) = mode {
+		use std::os::unix::fs::PermissionsExt;
+		fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777)).map_err(|e| {
+			wrap(
+				e,
+				format!("error setting permissions on {}", path.display()),
+			)
+		})?;
+	}
+	#[cfg(not(unix))]
+	let _ = mode;
+
+	Ok(())
+}
+
+/// Copies the server's files from one install to another, leaving out the
+/// files the CLI keeps alongside them.
+fn copy_install(base: &ServerPaths, from: &Path, to: &Path) -> Result<(), AnyError> {
+	fs::create_dir_all(to).map_err(|e| wrap(e, format!("error creating {}", to.display())))?;
+	let entries =
+		fs::read_dir(from).map_err(|e| wrap(e, format!("error reading {}", from.display())))?;
+	for entry in entries {
+		let entry = entry.map_err(|e| wrap(e, format!("error reading {}", from.display())))?;
+		let path = entry.path();
+		if base.is_cli_file(&path) {
+			continue;
+		}
+
+		let dest = to.join(entry.file_name());
+		let file_type = entry
+			.file_type()
+			.map_err(|e| wrap(e, format!("error reading {}", path.display())))?;
+		if file_type.is_dir() {
+			copy_install(base, &path, &dest)?;
+		} else if file_type.is_symlink() {
+			copy_symlink(&path, &dest)?;
+		} else {
+			fs::copy(&path, &dest)
+				.map_err(|e| wrap(e, format!("error copying {}", path.display())))?;
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &Path, to: &Path) -> Result<(), AnyError> {
+	let target =
+		fs::read_link(from).map_err(|e| wrap(e, format!("error reading {}", from.display())))?;
+	std::os::unix::fs::symlink(target, to)
+		.map_err(|e| wrap(e, format!("error copying {}", from.display())).into())
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(from: &Path, to: &Path) -> Result<(), AnyError> {
+	fs::copy(from, to)
+		.map(|_| ())
+		.map_err(|e| wrap(e, format!("error copying {}", from.display())).into())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{options::Quality, state::LauncherPaths, tunnels::paths::InstalledServer};
+	use serde_json::json;
+
+	fn sha(contents: &[u8]) -> String {
+		format!("{:x}", Sha256::digest(contents))
+	}
+
+	fn make_server(lp: &LauncherPaths, commit: &str) -> ServerPaths {
+		InstalledServer {
+			quality: Quality::Stable,
+			commit: commit.to_owned(),
+			headless: true,
+		}
+		.server_paths(lp)
+	}
+
+	fn write_delta(path: &Path, manifest: serde_json::Value, files: &[(&str, &[u8])]) {
+		let file = fs::File::create(path).unwrap();
+		let encoder = zstd::stream::write::Encoder::new(file, 0).unwrap();
+		let mut builder = tar::Builder::new(encoder);
+
+		let manifest = manifest.to_string();
+		let entries = std::iter::once((MANIFEST_ENTRY, manifest.as_bytes()));
+		for (name, contents) in entries.chain(files.iter().copied()) {
+			let mut header = tar::Header::new_gnu();
+			header.set_size(contents.len() as u64);
+			header.set_mode(0o644);
+			header.set_cksum();
+			builder.append_data(&mut header, name, contents).unwrap();
+		}
+
+		builder.into_inner().unwrap().finish().unwrap();
+	}
+
+	fn patch_for(old: &[u8], new: &[u8]) -> Vec<u8> {
+		let mut patch = vec![];
+		bsdiff::diff(old, new, &mut patch).unwrap();
+		patch
+	}
+
+	#[test]
+	fn test_applies_delta() {
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let base = make_server(&lp, "a");
+		let target = make_server(&lp, "b");
+
+		let old_main = b"console.log('hello from a');".to_vec();
+		let new_main = b"console.log('hello from b');".to_vec();
+		write_file(&base.server_dir.join("out/main.js"), &old_main, None).unwrap();
+		write_file(&base.server_dir.join("product.json"), b"{\"v\":1}", None).unwrap();
+		write_file(&base.server_dir.join("unchanged.txt"), b"same", None).unwrap();
+		write_file(&base.server_dir.join("old.txt"), b"gone", None).unwrap();
+		write_file(&base.server_dir.join("old/nested.txt"), b"gone", None).unwrap();
+
+		let patch = patch_for(&old_main, &new_main);
+		let delta = dir.path().join("delta.tar.zst");
+		write_delta(
+			&delta,
+			json!({
+				"from": "a",
+				"to": "b",
+				"added": [
+					{ "path": "new.txt", "sha256": sha(b"added") },
+					{ "path": "product.json", "sha256": sha(b"{\"v\":2}") },
+				],
+				"patched": [
+					{ "path": "out/main.js", "base_sha256": sha(&old_main), "sha256": sha(&new_main) },
+				],
+				"removed": ["old.txt", "old"],
+			}),
+			&[
+				("files/new.txt", b"added"),
+				("files/product.json", b"{\"v\":2}"),
+				("patches/out/main.js", &patch),
+			],
+		);
+
+		apply_delta(&delta, &base, &target, "a", "b").unwrap();
+
+		let read = |p: &str| fs::read(target.server_dir.join(p)).unwrap();
+		assert_eq!(read("new.txt"), b"added");
+		assert_eq!(read("product.json"), b"{\"v\":2}");
+		assert_eq!(read("out/main.js"), new_main);
+		assert_eq!(read("unchanged.txt"), b"same");
+		assert!(!target.server_dir.join("old.txt").exists());
+		assert!(!target.server_dir.join("old").exists());
+
+		// the base is left as it was
+		assert_eq!(
+			fs::read(base.server_dir.join("out/main.js")).unwrap(),
+			old_main
+		);
+		assert!(base.server_dir.join("old.txt").exists());
+	}
+
+	#[test]
+	fn test_rejects_delta_for_changed_base() {
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let base = make_server(&lp, "a");
+		let target = make_server(&lp, "b");
+
+		let old_main = b"console.log('hello from a');".to_vec();
+		let new_main = b"console.log('hello from b');".to_vec();
+		write_file(&base.server_dir.join("main.js"), b"patched locally", None).unwrap();
+
+		let patch = patch_for(&old_main, &new_main);
+		let delta = dir.path().join("delta.tar.zst");
+		write_delta(
+			&delta,
+			json!({
+				"from": "a",
+				"to": "b",
+				"patched": [
+					{ "path": "main.js", "base_sha256": sha(&old_main), "sha256": sha(&new_main) },
+				],
+			}),
+			&[("patches/main.js", &patch)],
+		);
+
+		assert!(apply_delta(&delta, &base, &target, "a", "b").is_err());
+		assert!(apply_delta(&delta, &base, &target, "a", "c").is_err());
+	}
+
+	#[test]
+	fn test_rejects_files_missing_from_manifest() {
+		let dir = tempfile::tempdir().unwrap();
+		let lp = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let base = make_server(&lp, "a");
+		let target = make_server(&lp, "b");
+		fs::create_dir_all(&base.server_dir).unwrap();
+
+		let delta = dir.path().join("delta.tar.zst");
+		write_delta(
+			&delta,
+			json!({ "from": "a", "to": "b" }),
+			&[("files/extra.txt", b"extra")],
+		);
+		assert!(apply_delta(&delta, &base, &target, "a", "b").is_err());
+
+		write_delta(
+			&delta,
+			json!({ "from": "a", "to": "b", "added": [{ "path": "new.txt", "sha256": sha(b"added") }] }),
+			&[],
+		);
+		assert!(apply_delta(&delta, &base, &target, "a", "b").is_err());
+	}
+
+	#[test]
+	fn test_safe_path() {
+		assert!(safe_path("bin/code-server").is_ok());
+		assert!(safe_path("../outside").is_err());
+		assert!(safe_path("/etc/passwd").is_err());
+		assert!(safe_path("bin/../../outside").is_err());
+		assert!(safe_path("").is_err());
+	}
+}
@@ -0,0 +1,151 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Serves some clients an alternate server build, hosted side by side with
+//! the one everyone else gets, so a build like Insiders can be tried against
+//! a real workspace without moving the whole tunnel over to it.
+
+use std::{fmt, str::FromStr};
+
+use crate::options::Quality;
+
+use super::server_selection::ServerSelection;
+
+/// A server build some clients are served instead of the one they ask for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AlternateServer {
+	pub quality: Quality,
+	/// Commit to serve. If not given, the latest build of the quality is used.
+	pub commit: Option<String>,
+}
+
+impl FromStr for AlternateServer {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (quality, commit) = match s.split_once('=') {
+			Some((q, c)) => (q, Some(c.trim())),
+			None => (s, None),
+		};
+
+		if let Some(c) = commit {
+			if c.is_empty() || !c.chars().all(|c| c.is_ascii_hexdigit()) {
+				return Err(format!("invalid commit '{}', expected a hex commit ID", c));
+			}
+		}
+
+		Ok(AlternateServer {
+			quality: Quality::try_from(quality.trim().to_lowercase().as_str())?,
+			commit: commit.map(|c| c.to_lowercase()),
+		})
+	}
+}
+
+impl fmt::Display for AlternateServer {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match &self.commit {
+			Some(c) => write!(f, "{} ({})", self.quality, c),
+			None => write!(f, "{}", self.quality),
+		}
+	}
+}
+
+/// Decides which clients are served the alternate server, if one is set up.
+#[derive(Clone, Debug, Default)]
+pub struct ServerRouting {
+	pub alternate: Option<AlternateServer>,
+	/// Identities, like account names, of clients always served the alternate.
+	pub identities: Vec<String>,
+	/// Server everyone not served the alternate gets.
+	pub selection: ServerSelection,
+}
+
+impl ServerRouting {
+	/// Gets the alternate server to serve a client, given the identity it
+	/// reported and the quality it hinted it wants, like with `?quality=`.
+	/// Hints only select the alternate server; they can't start other builds.
+	///
+	/// Clients that pin a commit, like desktop VS Code, always get the server
+	/// they asked for, since they can't talk to another build. The identity
+	/// is whatever the client reports, so it only picks which of the host's
+	/// builds to serve and isn't a way to restrict access to either.
+	pub fn route(
+		&self,
+		identity: Option<&str>,
+		hint: Option<Quality>,
+		commit: Option<&str>,
+	) -> Option<&AlternateServer> {
+		if commit.is_some() {
+			return None;
+		}
+
+		let alternate = self.alternate.as_ref()?;
+		let by_identity = identity
+			.map(|i| self.identities.iter().any(|x| x.eq_ignore_ascii_case(i)))
+			.unwrap_or(false);
+
+		if by_identity || hint == Some(alternate.quality) {
+			Some(alternate)
+		} else {
+			None
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parses_alternate_server() {
+		let a: AlternateServer = "insiders".parse().unwrap();
+		assert_eq!(a.quality, Quality::Insiders);
+		assert_eq!(a.commit, None);
+
+		let a: AlternateServer = "Insiders=ABC123".parse().unwrap();
+		assert_eq!(a.commit.as_deref(), Some("abc123"));
+
+		assert!("nightly".parse::<AlternateServer>().is_err());
+		assert!("insiders=".parse::<AlternateServer>().is_err());
+		assert!("insiders=../x".parse::<AlternateServer>().is_err());
+	}
+
+	#[test]
+	fn test_routes_clients() {
+		let routing = ServerRouting {
+			alternate: Some("insiders".parse().unwrap()),
+			identities: vec!["me@example.com".to_string()],
+			..Default::default()
+		};
+
+		assert!(routing.route(Some("Me@Example.com"), None, None).is_some());
+		assert!(routing
+			.route(Some("teammate@example.com"), None, None)
+			.is_none());
+		assert!(routing.route(None, Some(Quality::Insiders), None).is_some());
+		assert!(routing
+			.route(None, Some(Quality::Exploration), None)
+			.is_none());
+		assert!(ServerRouting::default()
+			.route(Some("me@example.com"), Some(Quality::Insiders), None)
+			.is_none());
+	}
+
+	#[test]
+	fn test_does_not_route_pinned_commits() {
+		let routing = ServerRouting {
+			alternate: Some("insiders".parse().unwrap()),
+			identities: vec!["me@example.com".to_string()],
+			..Default::default()
+		};
+
+		assert!(routing
+			.route(Some("me@example.com"), None, Some("abc123"))
+			.is_none());
+		assert!(routing
+			.route(None, Some(Quality::Insiders), Some("abc123"))
+			.is_none());
+	}
+}
@@ -0,0 +1,159 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Decides which server the host provisions, from `--quality` and `--commit`
+//! and the pinned version, so every path that starts servers, like clients
+//! connecting through the tunnel, the service, and the local web UI, picks
+//! the same one.
+
+use crate::{
+	desktop::RequestedVersion,
+	options::Quality,
+	state::LauncherPaths,
+	update_service::Platform,
+	util::errors::{AnyError, ConflictingServerSelection},
+};
+
+use super::{
+	code_server::{CodeServerArgs, ServerParamsRaw},
+	paths::get_pinned_version,
+};
+
+/// The server chosen on the command line, if any. A commit given here is
+/// used over the pinned version and over commits clients ask for.
+#[derive(Clone, Debug, Default)]
+pub struct ServerSelection {
+	pub quality: Option<Quality>,
+	pub commit: Option<String>,
+}
+
+impl ServerSelection {
+	/// Checks that the selection can be used, and doesn't conflict with the
+	/// pinned version or the quality another option asks for.
+	pub fn check(
+		&self,
+		pinned: Option<&RequestedVersion>,
+		other_quality: Option<(&str, Quality)>,
+	) -> Result<(), AnyError> {
+		if let Some(c) = &self.commit {
+			if c.is_empty() || !c.chars().all(|c| c.is_ascii_hexdigit()) {
+				return Err(ConflictingServerSelection(format!(
+					"--commit {} isn't a commit ID",
+					c
+				))
+				.into());
+			}
+		}
+
+		let quality = match self.quality {
+			Some(q) => q,
+			None => return Ok(()),
+		};
+
+		if let Some((option, other)) = other_quality {
+			if other != quality {
+				return Err(ConflictingServerSelection(format!(
+					"--quality {} conflicts with {} {}",
+					quality, option, other
+				))
+				.into());
+			}
+		}
+
+		// a commit on the command line is used over the pin, so only a pin
+		// that would be used can conflict
+		if self.commit.is_none() {
+			if let Some(pinned_quality) = pinned.and_then(pinned_quality) {
+				if pinned_quality != quality {
+					return Err(ConflictingServerSelection(format!(
+						"--quality {} conflicts with the pinned version {}. Run `version unpin` to use the latest {} server",
+						quality,
+						pinned.unwrap(),
+						quality
+					))
+					.into());
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Gets the params of the server to start for a client that asked for
+	/// the commit and quality. The pinned version is read each time, so
+	/// pinning or rolling back a server applies without a restart.
+	pub fn params(
+		&self,
+		launcher_paths: &LauncherPaths,
+		requested_commit: Option<String>,
+		requested_quality: Quality,
+		code_server_args: CodeServerArgs,
+		headless: bool,
+		platform: Platform,
+	) -> Result<ServerParamsRaw, AnyError> {
+		let pinned = get_pinned_version(launcher_paths);
+		self.check(pinned.as_ref(), None)?;
+
+		let quality = self.quality.unwrap_or(requested_quality);
+		let (commit_id, pinned_version) = match &self.commit {
+			Some(c) => (Some(c.clone()), None),
+			// a commit the client asked for belongs to the quality it asked for
+			None if quality != requested_quality => (None, pinned),
+			None => (requested_commit, pinned),
+		};
+
+		Ok(ServerParamsRaw {
+			commit_id,
+			quality,
+			code_server_args,
+			headless,
+			platform,
+			pinned_version,
+		})
+	}
+}
+
+fn pinned_quality(pinned: &RequestedVersion) -> Option<Quality> {
+	match pinned {
+		RequestedVersion::Version { quality, .. } | RequestedVersion::Commit { quality, .. } => {
+			Some(*quality)
+		}
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_checks_conflicts() {
+		let pinned = RequestedVersion::Commit {
+			commit: "abc".to_string(),
+			quality: Quality::Stable,
+		};
+		let insiders = ServerSelection {
+			quality: Some(Quality::Insiders),
+			commit: None,
+		};
+		assert!(insiders.check(None, None).is_ok());
+		assert!(insiders.check(Some(&pinned), None).is_err());
+		assert!(insiders
+			.check(None, Some(("--local-web-quality", Quality::Stable)))
+			.is_err());
+
+		let commit = ServerSelection {
+			quality: Some(Quality::Insiders),
+			commit: Some("def".to_string()),
+		};
+		assert!(commit.check(Some(&pinned), None).is_ok());
+
+		let invalid = ServerSelection {
+			quality: None,
+			commit: Some("../x".to_string()),
+		};
+		assert!(invalid.check(None, None).is_err());
+	}
+}
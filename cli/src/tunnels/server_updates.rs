@@ -0,0 +1,328 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	collections::HashSet,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use tokio::{
+	sync::{OwnedRwLockWriteGuard, RwLock},
+	time::Instant,
+};
+
+use crate::{
+	info, log,
+	options::Quality,
+	state::LauncherPaths,
+	update_service::{Platform, UpdateServiceCache},
+	util::{errors::AnyError, http::ReqwestSimpleHttp, sync::Barrier},
+	warning,
+};
+
+use super::{
+	code_server::{CodeServerArgs, ServerBuilder},
+	maintenance::MaintenanceWindows,
+	paths::{
+		get_install_state, stop_running_servers_where, InstallState, InstalledServer,
+		LastUsedServers,
+	},
+	server_selection::ServerSelection,
+};
+
+/// How often the host checks whether it's idle, to swap in a new server.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long servers are left running after their last client disconnects.
+/// It matches the servers' default reconnection grace time, during which
+/// clients can reconnect to their sessions.
+const RECONNECTION_GRACE: Duration = Duration::from_secs(3 * 60 * 60);
+
+/// Clients connected to the host, and the commits they asked for.
+#[derive(Clone)]
+pub struct ActiveClients {
+	count: Arc<Mutex<ClientCount>>,
+	/// Taken for reading to connect, and for writing while servers are
+	/// stopped, so no client connects to a server that's being stopped.
+	gate: Arc<RwLock<()>>,
+	/// Commits clients asked for by name, which are never stopped.
+	requested: Arc<Mutex<HashSet<String>>>,
+}
+
+struct ClientCount {
+	connected: usize,
+	/// When the last client disconnected, or the host started.
+	idle_since: Instant,
+}
+
+impl Default for ActiveClients {
+	fn default() -> Self {
+		ActiveClients {
+			count: Arc::new(Mutex::new(ClientCount {
+				connected: 0,
+				idle_since: Instant::now(),
+			})),
+			gate: Arc::new(RwLock::new(())),
+			requested: Arc::new(Mutex::new(HashSet::new())),
+		}
+	}
+}
+
+impl ActiveClients {
+	/// Counts a client as connected until the guard is dropped. Waits while
+	/// servers are being stopped.
+	pub async fn connect(&self) -> ActiveClientGuard {
+		let _gate = self.gate.read().await;
+		self.count.lock().unwrap().connected += 1;
+		ActiveClientGuard(self.count.clone())
+	}
+
+	/// Records that a client asked for the commit, so it's never stopped.
+	pub fn request_commit(&self, commit: &str) {
+		self.requested.lock().unwrap().insert(commit.to_string());
+	}
+
+	/// If no clients have been connected for the grace period, returns a
+	/// guard that keeps new ones from connecting until it's dropped.
+	async fn hold_if_idle_for(&self, grace: Duration) -> Option<IdleGuard> {
+		let gate = self.gate.clone().write_owned().await;
+		let count = self.count.lock().unwrap();
+		if count.connected == 0 && count.idle_since.elapsed() >= grace {
+			Some(IdleGuard(gate))
+		} else {
+			None
+		}
+	}
+
+	fn requested_commits(&self) -> HashSet<String> {
+		self.requested.lock().unwrap().clone()
+	}
+}
+
+pub struct ActiveClientGuard(Arc<Mutex<ClientCount>>);
+
+impl Drop for ActiveClientGuard {
+	fn drop(&mut self) {
+		let mut count = self.0.lock().unwrap();
+		count.connected -= 1;
+		if count.connected == 0 {
+			count.idle_since = Instant::now();
+		}
+	}
+}
+
+/// Keeps clients from connecting while it's held.
+struct IdleGuard(#[allow(dead_code)] OwnedRwLockWriteGuard<()>);
+
+pub struct ServerUpdateOptions {
+	pub interval: Duration,
+	pub code_server_args: CodeServerArgs,
+	pub platform: Platform,
+	pub selection: ServerSelection,
+	pub update_cache: Option<UpdateServiceCache>,
+	pub maintenance: MaintenanceWindows,
+}
+
+/// Checks for new server builds on the interval, installing them ahead of
+/// time so clients don't wait for the download when they next connect. Once
+/// one is installed and no clients have been connected for the reconnection
+/// grace period, within a maintenance window if any are configured, servers
+/// of older builds are stopped, so the next connection starts the new one.
+/// Runs until the barrier opens.
+pub async fn update_servers_when_idle(
+	log: log::Logger,
+	paths: LauncherPaths,
+	options: ServerUpdateOptions,
+	clients: ActiveClients,
+	mut exit: Barrier<()>,
+) {
+	info!(
+		log,
+		"Checking for new servers every {}s, installing them while the host is idle",
+		options.interval.as_secs()
+	);
+
+	let mut check = tokio::time::interval(options.interval);
+	let mut idle_check = tokio::time::interval(IDLE_CHECK_INTERVAL);
+	let mut latest: Option<InstalledServer> = None;
+	loop {
+		tokio::select! {
+			_ = exit.wait() => return,
+			_ = check.tick() => match install_latest(&log, &paths, &options).await {
+				Ok(server) => latest = server,
+				Err(e) => warning!(log, "Error checking for a new server: {}", e),
+			},
+			_ = idle_check.tick() => {
+				let latest = match &latest {
+					Some(l) => l,
+					None => continue,
+				};
+				if !options.maintenance.is_empty() && !options.maintenance.allows_now() {
+					continue;
+				}
+				let _idle = match clients.hold_if_idle_for(RECONNECTION_GRACE).await {
+					Some(guard) => guard,
+					None => continue,
+				};
+
+				let requested = clients.requested_commits();
+				let stopped = stop_running_servers_where(&paths, |s| {
+					is_outdated(s, latest, &requested)
+				})
+				.await;
+				for s in stopped {
+					info!(
+						log,
+						"Stopped server {} while idle, clients will use {} when they connect",
+						s.commit,
+						latest.commit
+					);
+				}
+			},
+		}
+	}
+}
+
+/// Gets whether the server is one the latest replaces: a server of the same
+/// quality, started by the tunnel, that no client asked for by commit.
+fn is_outdated(
+	server: &InstalledServer,
+	latest: &InstalledServer,
+	requested: &HashSet<String>,
+) -> bool {
+	server.headless
+		&& server.quality == latest.quality
+		&& server.commit != latest.commit
+		&& !requested.contains(&server.commit)
+}
+
+/// Installs the server clients would get if they connected now, returning it
+/// unless the selection fixes the commit, in which case there's nothing to
+/// update.
+async fn install_latest(
+	log: &log::Logger,
+	paths: &LauncherPaths,
+	options: &ServerUpdateOptions,
+) -> Result<Option<InstalledServer>, AnyError> {
+	// clients usually ask for the quality they last used
+	let quality = LastUsedServers::new(paths)
+		.get_all()
+		.into_iter()
+		.find(|s| s.headless)
+		.map(|s| s.quality)
+		.unwrap_or(Quality::Stable);
+	let params = options.selection.params(
+		paths,
+		None,
+		quality,
+		options.code_server_args.clone(),
+		true,
+		options.platform,
+	)?;
+	if params.commit_id.is_some() {
+		return Ok(None);
+	}
+
+	let resolved = params
+		.resolve(log, ReqwestSimpleHttp::new(), options.update_cache.clone())
+		.await?;
+	let server = resolved.as_installed_server();
+	let installed = matches!(
+		get_install_state(&server, &server.server_paths(paths)),
+		InstallState::Intact | InstallState::Legacy
+	);
+	if !installed {
+		info!(
+			log,
+			"Installing server {} ahead of clients connecting", server.commit
+		);
+		ServerBuilder::new(log, &resolved, paths, ReqwestSimpleHttp::new())
+			.install_ahead()
+			.await?;
+	}
+
+	Ok(Some(server))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn server(commit: &str, quality: Quality, headless: bool) -> InstalledServer {
+		InstalledServer {
+			commit: commit.to_string(),
+			quality,
+			headless,
+		}
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_active_clients() {
+		let clients = ActiveClients::default();
+		let grace = Duration::from_secs(60);
+		assert!(clients.hold_if_idle_for(grace).await.is_none());
+
+		tokio::time::advance(grace).await;
+		assert!(clients.hold_if_idle_for(grace).await.is_some());
+
+		let a = clients.connect().await;
+		let b = clients.connect().await;
+		drop(a);
+		tokio::time::advance(grace).await;
+		assert!(clients.hold_if_idle_for(grace).await.is_none());
+
+		// the grace period starts once the last client disconnects
+		drop(b);
+		assert!(clients.hold_if_idle_for(grace).await.is_none());
+		tokio::time::advance(grace).await;
+		let idle = clients.hold_if_idle_for(grace).await.unwrap();
+
+		// clients wait to connect until servers are stopped
+		let connecting = tokio::spawn({
+			let clients = clients.clone();
+			async move { clients.connect().await }
+		});
+		tokio::task::yield_now().await;
+		assert!(!connecting.is_finished());
+		drop(idle);
+		let _c = connecting.await.unwrap();
+		assert!(clients.hold_if_idle_for(Duration::ZERO).await.is_none());
+	}
+
+	#[test]
+	fn test_is_outdated() {
+		let latest = server("new", Quality::Stable, true);
+		let none = HashSet::new();
+
+		assert!(is_outdated(
+			&server("old", Quality::Stable, true),
+			&latest,
+			&none
+		));
+		assert!(!is_outdated(
+			&server("new", Quality::Stable, true),
+			&latest,
+			&none
+		));
+		assert!(!is_outdated(
+			&server("old", Quality::Stable, false),
+			&latest,
+			&none
+		));
+		assert!(!is_outdated(
+			&server("old", Quality::Insiders, true),
+			&latest,
+			&none
+		));
+
+		let requested = HashSet::from(["old".to_string()]);
+		assert!(!is_outdated(
+			&server("old", Quality::Stable, true),
+			&latest,
+			&requested
+		));
+	}
+}
@@ -6,16 +6,66 @@
 use std::path::{Path, PathBuf};
 
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 
 use crate::commands::tunnels::ShutdownSignal;
 use crate::log;
-use crate::state::LauncherPaths;
-use crate::util::errors::{wrap, AnyError};
+use crate::state::{LauncherPaths, PersistedState};
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+use crate::util::errors::UnsupportedPlatformError;
+use crate::util::errors::{wrap, AnyError, WrappedError};
 use crate::util::io::{tailf, TailEvent};
 
 pub const SERVICE_LOG_FILE_NAME: &str = "tunnel-service.log";
 
+/// What the service was last registered to run, so that it can be registered
+/// again if the CLI's moved, such as when an update installs it to a new path.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceRegistration {
+	pub exe: PathBuf,
+	pub args: Vec<String>,
+}
+
+/// How the registered service's executable relates to this one.
+pub enum ServiceExecutable {
+	/// The service runs this executable.
+	Current,
+	/// The service runs an executable that no longer exists, so it will fail
+	/// to start.
+	Missing(PathBuf),
+	/// The service runs another CLI, such as a different installation.
+	Other(PathBuf),
+}
+
+fn registration_state(paths: &LauncherPaths) -> PersistedState<Option<ServiceRegistration>> {
+	PersistedState::new(paths.root().join("service-registration.json"))
+}
+
+/// Gets what the service was last registered to run, if it's registered.
+/// Services registered by older CLIs aren't recorded.
+pub fn load_service_registration(paths: &LauncherPaths) -> Option<ServiceRegistration> {
+	registration_state(paths).load()
+}
+
+/// Records what the service was registered to run.
+pub fn save_service_registration(
+	paths: &LauncherPaths,
+	registration: Option<ServiceRegistration>,
+) -> Result<(), WrappedError> {
+	registration_state(paths).save(registration)
+}
+
+/// Checks whether the registered service runs this executable.
+pub fn check_service_executable(registration: &ServiceRegistration) -> ServiceExecutable {
+	let current = std::env::current_exe().and_then(|p| p.canonicalize()).ok();
+	match registration.exe.canonicalize() {
+		Ok(p) if Some(&p) == current.as_ref() => ServiceExecutable::Current,
+		Ok(_) => ServiceExecutable::Other(registration.exe.clone()),
+		Err(_) => ServiceExecutable::Missing(registration.exe.clone()),
+	}
+}
+
 #[async_trait]
 pub trait ServiceContainer: Send {
 	async fn run_service(
@@ -46,6 +96,15 @@ pub trait ServiceManager {
 
 	/// Unregisters the current executable as a service.
 	async fn unregister(&self) -> Result<(), AnyError>;
+
+	/// Gets the name the service is registered under with the OS.
+	fn service_name(&self) -> String;
+
+	/// Lists hardening settings missing from the registered service's
+	/// definition, or returns None if it isn't checked on this platform.
+	fn missing_hardening(&self) -> Option<Vec<&'static str>> {
+		None
+	}
 }
 
 #[cfg(target_os = "windows")]
@@ -57,6 +116,9 @@ pub type ServiceManagerImpl = super::service_linux::SystemdService;
 #[cfg(target_os = "macos")]
 pub type ServiceManagerImpl = super::service_macos::LaunchdService;
 
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+pub type ServiceManagerImpl = UnsupportedServiceManager;
+
 #[allow(unreachable_code)]
 #[allow(unused_variables)]
 pub fn create_service_manager(log: log::Logger, paths: &LauncherPaths) -> ServiceManagerImpl {
@@ -72,6 +134,43 @@ pub fn create_service_manager(log: log::Logger, paths: &LauncherPaths) -> Servic
 	{
 		super::service_linux::SystemdService::new(log, paths.clone())
 	}
+	#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+	{
+		UnsupportedServiceManager()
+	}
+}
+
+/// Service manager for platforms the CLI can't register services on, like
+/// FreeBSD, whose operations all fail.
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+pub struct UnsupportedServiceManager();
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+#[async_trait]
+impl ServiceManager for UnsupportedServiceManager {
+	async fn register(&self, _exe: PathBuf, _args: &[&str]) -> Result<(), AnyError> {
+		Err(UnsupportedPlatformError().into())
+	}
+
+	async fn run(
+		self,
+		_launcher_paths: LauncherPaths,
+		_handle: impl 'static + ServiceContainer,
+	) -> Result<(), AnyError> {
+		Err(UnsupportedPlatformError().into())
+	}
+
+	async fn show_logs(&self) -> Result<(), AnyError> {
+		Err(UnsupportedPlatformError().into())
+	}
+
+	async fn unregister(&self) -> Result<(), AnyError> {
+		Err(UnsupportedPlatformError().into())
+	}
+
+	fn service_name(&self) -> String {
+		"code-tunnel".to_string()
+	}
 }
 
 #[allow(dead_code)] // unused on Linux
@@ -4,10 +4,8 @@
  *--------------------------------------------------------------------------------------------*/
 
 use std::{
-	fs::File,
 	io::{self, Write},
-	path::PathBuf,
-	process::Command,
+	path::{Path, PathBuf},
 };
 
 use async_trait::async_trait;
@@ -19,25 +17,49 @@ use crate::{
 	constants::{APPLICATION_NAME, PRODUCT_NAME_LONG},
 	log,
 	state::LauncherPaths,
-	util::errors::{wrap, AnyError},
+	util::{
+		command::ExternalCommand,
+		errors::{wrap, AnyError},
+		tempfile::write_file_atomic,
+	},
 };
 
 use super::ServiceManager;
 
+/// Files whose presence shows the root filesystem is immutable or managed
+/// declaratively, like on NixOS or ostree-based distros.
+const IMMUTABLE_ROOT_MARKERS: [&str; 2] = ["/etc/NIXOS", "/run/ostree-booted"];
+
 pub struct SystemdService {
 	log: log::Logger,
 	service_file: PathBuf,
+	/// Whether the service file is in a directory systemd loads units from,
+	/// rather than being linked there.
+	in_unit_dir: bool,
 }
 
 impl SystemdService {
 	pub fn new(log: log::Logger, paths: LauncherPaths) -> Self {
+		// Linked units are resolved through paths that may not be stable on
+		// immutable systems, so the unit is written where systemd finds it.
+		let unit_dir = dirs::config_dir()
+			.filter(|_| has_immutable_root())
+			.map(|d| d.join("systemd").join("user"));
+
 		Self {
 			log,
-			service_file: paths.root().join(SystemdService::service_name_string()),
+			in_unit_dir: unit_dir.is_some(),
+			service_file: unit_dir
+				.unwrap_or_else(|| paths.root().to_path_buf())
+				.join(SystemdService::service_name_string()),
 		}
 	}
 }
 
+fn has_immutable_root() -> bool {
+	IMMUTABLE_ROOT_MARKERS.iter().any(|p| Path::new(p).exists())
+}
+
 impl SystemdService {
 	async fn connect() -> Result<Connection, AnyError> {
 		let connection = Connection::session()
@@ -81,14 +103,21 @@ impl ServiceManager for SystemdService {
 		write_systemd_service_file(&self.service_file, exe, args)
 			.map_err(|e| wrap(e, "error creating service file"))?;
 
-		proxy
-			.link_unit_files(
-				vec![self.service_path_string()],
-				/* 'runtime only'= */ false,
-				/* replace existing = */ true,
-			)
-			.await
-			.map_err(|e| wrap(e, "error registering service"))?;
+		if self.in_unit_dir {
+			proxy
+				.reload()
+				.await
+				.map_err(|e| wrap(e, "error registering service"))?;
+		} else {
+			proxy
+				.link_unit_files(
+					vec![self.service_path_string()],
+					/* 'runtime only'= */ false,
+					/* replace existing = */ true,
+				)
+				.await
+				.map_err(|e| wrap(e, "error registering service"))?;
+		}
 
 		info!(self.log, "Successfully registered service...");
 
@@ -118,7 +147,7 @@ impl ServiceManager for SystemdService {
 
 	async fn show_logs(&self) -> Result<(), AnyError> {
 		// show the systemctl status header...
-		Command::new("systemctl")
+		ExternalCommand::new("systemctl")
 			.args([
 				"--user",
 				"status",
@@ -126,16 +155,17 @@ impl ServiceManager for SystemdService {
 				"0",
 				&SystemdService::service_name_string(),
 			])
-			.status()
-			.map(|s| s.code().unwrap_or(1))
-			.map_err(|e| wrap(e, "error running systemctl"))?;
+			.log(&self.log)
+			.run_interactive()
+			.await?;
 
-		// then follow log files
-		Command::new("journalctl")
+		// then follow log files until interrupted
+		ExternalCommand::new("journalctl")
 			.args(["--user", "-f", "-u", &SystemdService::service_name_string()])
-			.status()
-			.map(|s| s.code().unwrap_or(1))
-			.map_err(|e| wrap(e, "error running journalctl"))?;
+			.timeout(None)
+			.log(&self.log)
+			.run_interactive()
+			.await?;
 		Ok(())
 	}
 
@@ -158,18 +188,41 @@ impl ServiceManager for SystemdService {
 			.await
 			.map_err(|e| wrap(e, "error unregistering service"))?;
 
+		if self.in_unit_dir {
+			std::fs::remove_file(&self.service_file).ok();
+		}
+
 		info!(self.log, "Tunnel service uninstalled");
 
 		Ok(())
 	}
+
+	fn service_name(&self) -> String {
+		SystemdService::service_name_string()
+	}
+
+	fn missing_hardening(&self) -> Option<Vec<&'static str>> {
+		let contents = std::fs::read_to_string(&self.service_file).unwrap_or_default();
+		Some(
+			SERVICE_HARDENING
+				.iter()
+				.filter(|h| !contents.lines().any(|l| l.trim() == **h))
+				.copied()
+				.collect(),
+		)
+	}
 }
 
+/// Settings that limit what the service can do, checked by `tunnel doctor
+/// --security`. These work in user units, unlike most sandboxing settings.
+const SERVICE_HARDENING: [&str; 2] = ["NoNewPrivileges=true", "UMask=0077"];
+
 fn write_systemd_service_file(
 	path: &PathBuf,
 	exe: std::path::PathBuf,
 	args: &[&str],
 ) -> io::Result<()> {
-	let mut f = File::create(path)?;
+	let mut f = Vec::new();
 	write!(
 		&mut f,
 		"[Unit]\n\
@@ -181,16 +234,18 @@ fn write_systemd_service_file(
       Type=simple\n\
       Restart=always\n\
       RestartSec=10\n\
+      {}\n\
       ExecStart={} \"{}\"\n\
       \n\
       [Install]\n\
-      WantedBy=multi-user.target\n\
+      WantedBy=default.target\n\
     ",
 		PRODUCT_NAME_LONG,
+		SERVICE_HARDENING.join("\n"),
 		exe.into_os_string().to_string_lossy(),
 		args.join("\" \"")
 	)?;
-	Ok(())
+	write_file_atomic(path, &f)
 }
 
 /// Minimal implementation of systemd types for the services we need. The full
@@ -227,6 +282,9 @@ trait SystemdManagerDbus {
 		runtime: bool,
 	) -> zbus::Result<Vec<(String, String, String)>>;
 
+	#[dbus_proxy(name = "Reload")]
+	fn reload(&self) -> zbus::Result<()>;
+
 	#[dbus_proxy(name = "StartUnit")]
 	fn start_unit(&self, name: String, mode: String) -> zbus::Result<zvariant::OwnedObjectPath>;
 
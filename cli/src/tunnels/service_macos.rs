@@ -4,7 +4,7 @@
  *--------------------------------------------------------------------------------------------*/
 
 use std::{
-	fs::{remove_file, File},
+	fs::remove_file,
 	io::{self, Write},
 	path::{Path, PathBuf},
 };
@@ -18,8 +18,9 @@ use crate::{
 	log,
 	state::LauncherPaths,
 	util::{
-		command::capture_command_and_check_status,
+		command::ExternalCommand,
 		errors::{wrap, AnyError, MissingHomeDirectory},
+		tempfile::write_file_atomic,
 	},
 };
 
@@ -52,13 +53,17 @@ impl ServiceManager for LaunchdService {
 
 		info!(self.log, "Successfully registered service...");
 
-		capture_command_and_check_status(
-			"launchctl",
-			&["load", service_file.as_os_str().to_string_lossy().as_ref()],
-		)
-		.await?;
+		ExternalCommand::new("launchctl")
+			.args(["load", service_file.as_os_str().to_string_lossy().as_ref()])
+			.log(&self.log)
+			.run()
+			.await?;
 
-		capture_command_and_check_status("launchctl", &["start", &get_service_label()]).await?;
+		ExternalCommand::new("launchctl")
+			.args(["start", &get_service_label()])
+			.log(&self.log)
+			.run()
+			.await?;
 
 		info!(self.log, "Tunnel service successfully started");
 
@@ -86,7 +91,12 @@ impl ServiceManager for LaunchdService {
 	async fn unregister(&self) -> Result<(), crate::util::errors::AnyError> {
 		let service_file = get_service_file_path()?;
 
-		match capture_command_and_check_status("launchctl", &["stop", &get_service_label()]).await {
+		match ExternalCommand::new("launchctl")
+			.args(["stop", &get_service_label()])
+			.log(&self.log)
+			.run()
+			.await
+		{
 			Ok(_) => {}
 			// status 3 == "no such process"
 			Err(AnyError::CommandFailed(e)) if e.output.status.code() == Some(3) => {}
@@ -95,14 +105,14 @@ impl ServiceManager for LaunchdService {
 
 		info!(self.log, "Successfully stopped service...");
 
-		capture_command_and_check_status(
-			"launchctl",
-			&[
+		ExternalCommand::new("launchctl")
+			.args([
 				"unload",
 				service_file.as_os_str().to_string_lossy().as_ref(),
-			],
-		)
-		.await?;
+			])
+			.log(&self.log)
+			.run()
+			.await?;
 
 		info!(self.log, "Tunnel service uninstalled");
 
@@ -112,6 +122,22 @@ impl ServiceManager for LaunchdService {
 
 		Ok(())
 	}
+
+	fn service_name(&self) -> String {
+		get_service_label()
+	}
+
+	fn missing_hardening(&self) -> Option<Vec<&'static str>> {
+		let contents = get_service_file_path()
+			.ok()
+			.and_then(|p| std::fs::read_to_string(p).ok())
+			.unwrap_or_default();
+		Some(if contents.contains("<key>Umask</key>") {
+			vec![]
+		} else {
+			vec!["Umask"]
+		})
+	}
 }
 
 fn get_service_label() -> String {
@@ -134,7 +160,7 @@ fn write_service_file(
 	exe: std::path::PathBuf,
 	args: &[&str],
 ) -> io::Result<()> {
-	let mut f = File::create(path)?;
+	let mut f = Vec::new();
 	let log_file = log_file.as_os_str().to_string_lossy();
 	// todo: we may be able to skip file logging and use the ASL instead
 	// if/when we no longer need to support older macOS versions.
@@ -155,6 +181,8 @@ fn write_service_file(
 			</array>\n\
 			<key>KeepAlive</key>\n\
 			<true/>\n\
+			<key>Umask</key>\n\
+			<integer>63</integer>\n\
 			<key>StandardErrorPath</key>\n\
 			<string>{}</string>\n\
 			<key>StandardOutPath</key>\n\
@@ -167,5 +195,5 @@ fn write_service_file(
 		log_file,
 		log_file
 	)?;
-	Ok(())
+	write_file_atomic(path, &f)
 }
@@ -4,9 +4,15 @@
  *--------------------------------------------------------------------------------------------*/
 
 use async_trait::async_trait;
-use dialoguer::{theme::ColorfulTheme, Input, Password};
+use dialoguer::{Input, Password};
 use lazy_static::lazy_static;
-use std::{ffi::OsString, path::PathBuf, sync::Mutex, thread, time::Duration};
+use std::{
+	ffi::OsString,
+	path::PathBuf,
+	sync::{Arc, Mutex},
+	thread,
+	time::Duration,
+};
 use tokio::sync::mpsc;
 use windows_service::{
 	define_windows_service,
@@ -22,7 +28,10 @@ use windows_service::{
 use crate::{
 	commands::tunnels::ShutdownSignal,
 	constants::QUALITYLESS_PRODUCT_NAME,
-	util::errors::{wrap, wrapdbg, AnyError, WindowsNeedsElevation},
+	util::{
+		errors::{wrap, wrapdbg, AnyError, WindowsNeedsElevation},
+		input::prompt_theme,
+	},
 };
 use crate::{
 	log::{self, FileLogSink},
@@ -41,6 +50,10 @@ pub struct WindowsService {
 const SERVICE_NAME: &str = "code_tunnel";
 const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
 
+/// Time the service manager is told to allow for the tunnel to stop, during
+/// which clients are disconnected and servers shut down.
+const STOP_WAIT_HINT: Duration = Duration::from_secs(20);
+
 impl WindowsService {
 	pub fn new(log: log::Logger, paths: &LauncherPaths) -> Self {
 		Self {
@@ -204,6 +217,10 @@ impl CliServiceManager for WindowsService {
 
 		Ok(())
 	}
+
+	fn service_name(&self) -> String {
+		SERVICE_NAME.to_string()
+	}
 }
 
 struct ServiceImpl {
@@ -223,30 +240,41 @@ fn service_main(_arguments: Vec<OsString>) -> Result<(), AnyError> {
 	// Create a channel to be able to poll a stop event from the service worker loop.
 	let (shutdown_tx, shutdown_rx) = mpsc::unbounded_channel::<ShutdownSignal>();
 	let mut shutdown_tx = Some(shutdown_tx);
+	// set once registered, so the handler can report that the service is stopping
+	let handler_status = Arc::new(Mutex::new(None));
+	let status_for_handler = handler_status.clone();
 
 	// Define system service event handler that will be receiving service events.
 	let event_handler = move |control_event| -> ServiceControlHandlerResult {
-		match control_event {
-			ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
-			ServiceControl::Stop => {
-				shutdown_tx
-					.take()
-					.and_then(|tx| tx.send(ShutdownSignal::ServiceStopped).ok());
-				ServiceControlHandlerResult::NoError
+		let signal = match control_event {
+			ServiceControl::Interrogate => return ServiceControlHandlerResult::NoError,
+			ServiceControl::Stop => ShutdownSignal::ServiceStopped,
+			// sent before services are stopped at shutdown, allowing time to drain
+			ServiceControl::Preshutdown | ServiceControl::Shutdown => {
+				ShutdownSignal::SystemShutdown
+			}
+			_ => return ServiceControlHandlerResult::NotImplemented,
+		};
+
+		if let Some(tx) = shutdown_tx.take() {
+			tx.send(signal).ok();
+			if let Some(handle) = *status_for_handler.lock().unwrap() {
+				set_stop_pending(handle).ok();
 			}
-			_ => ServiceControlHandlerResult::NotImplemented,
 		}
+		ServiceControlHandlerResult::NoError
 	};
 
 	let status_handle = service_control_handler::register(SERVICE_NAME, event_handler)
 		.map_err(|e| wrap(e, "error registering service event handler"))?;
+	handler_status.lock().unwrap().replace(status_handle);
 
 	// Tell the system that service is running
 	status_handle
 		.set_service_status(ServiceStatus {
 			service_type: SERVICE_TYPE,
 			current_state: ServiceState::Running,
-			controls_accepted: ServiceControlAccept::STOP,
+			controls_accepted: ServiceControlAccept::STOP | ServiceControlAccept::PRESHUTDOWN,
 			exit_code: ServiceExitCode::Win32(0),
 			checkpoint: 0,
 			wait_hint: Duration::default(),
@@ -286,16 +314,32 @@ fn service_main(_arguments: Vec<OsString>) -> Result<(), AnyError> {
 	result
 }
 
+/// Tells the service manager the service is stopping, so it waits for the
+/// tunnel to shut down rather than ending the process.
+fn set_stop_pending(
+	handle: service_control_handler::ServiceStatusHandle,
+) -> windows_service::Result<()> {
+	handle.set_service_status(ServiceStatus {
+		service_type: SERVICE_TYPE,
+		current_state: ServiceState::StopPending,
+		controls_accepted: ServiceControlAccept::empty(),
+		exit_code: ServiceExitCode::Win32(0),
+		checkpoint: 0,
+		wait_hint: STOP_WAIT_HINT,
+		process_id: None,
+	})
+}
+
 fn prompt_credentials() -> Result<(String, String), AnyError> {
 	println!("Running a Windows service under your user requires your username and password.");
 	println!("These are sent to the Windows Service Manager and are not stored by VS Code.");
 
-	let username: String = Input::with_theme(&ColorfulTheme::default())
+	let username: String = Input::with_theme(prompt_theme().as_ref())
 		.with_prompt("Windows username:")
 		.interact_text()
 		.map_err(|e| wrap(e, "Failed to read username"))?;
 
-	let password = Password::with_theme(&ColorfulTheme::default())
+	let password = Password::with_theme(prompt_theme().as_ref())
 		.with_prompt("Windows password:")
 		.interact()
 		.map_err(|e| wrap(e, "Failed to read password"))?;
@@ -0,0 +1,502 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Opt-in recording of the sessions the CLI relays, for hosts where
+//! compliance requires it: connections to the VS Code server, which carry
+//! its terminals and the processes it runs, SSH bridge connections on the
+//! host and in `tunnel stdio-bridge` and `tunnel sftp`, and `command-shell`
+//! sessions.
+//!
+//! Each session is written to its own file in the recordings directory,
+//! readable only by the current user, in a format like asciicast: a JSON
+//! header line, then one `[seconds, code, data]` line per event, where the
+//! code is "i" for data sent by the side that opened the session, "o" for
+//! data sent to it, and "x" when the session ends. SSH sessions are
+//! encrypted end to end, so only the size of each chunk is recorded for
+//! them; command-shell messages are recorded in full, and server
+//! connections in full as base64, since their protocol is binary.
+//!
+//! Given a recipient, recordings are encrypted to it with age, so they can
+//! only be read with its identity, for instance by `age -d -i key.txt`,
+//! and not by anyone else on the host.
+//!
+//! Recording fails closed: if a recording can't be created, the session is
+//! refused rather than relayed unrecorded.
+
+use std::{
+	io::{BufWriter, Write},
+	path::{Path, PathBuf},
+	pin::Pin,
+	sync::{Arc, RwLock},
+	task::{Context, Poll},
+	time::Instant,
+};
+
+use chrono::{Duration, Utc};
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use tokio::{
+	io::{AsyncRead, AsyncWrite, ReadBuf},
+	sync::mpsc,
+};
+
+use crate::{
+	constants::VSCODE_CLI_VERSION,
+	util::{
+		errors::{wrap, AnyError, InvalidRecordingRecipient},
+		io::restrict_to_owner,
+	},
+};
+
+/// How long recordings are kept for when no retention is given.
+const DEFAULT_RETENTION_DAYS: i64 = 30;
+
+lazy_static! {
+	static ref RECORDING: RwLock<Option<RecordingOptions>> = RwLock::new(None);
+}
+
+#[derive(Clone, Debug)]
+pub struct RecordingOptions {
+	/// Directory recordings are written to.
+	pub dir: PathBuf,
+	/// Recordings older than this are deleted as new sessions start.
+	pub retention: Duration,
+	/// Recipient recordings are encrypted to, if any.
+	pub recipient: Option<age::x25519::Recipient>,
+}
+
+impl RecordingOptions {
+	pub fn new(dir: PathBuf, retention: Option<Duration>) -> Self {
+		RecordingOptions {
+			dir,
+			retention: retention.unwrap_or_else(|| Duration::days(DEFAULT_RETENTION_DAYS)),
+			recipient: None,
+		}
+	}
+
+	/// Encrypts recordings to the age public key, like "age1...".
+	pub fn with_recipient(mut self, recipient: &str) -> Result<Self, AnyError> {
+		let parsed = recipient
+			.parse::<age::x25519::Recipient>()
+			.map_err(|e| InvalidRecordingRecipient(recipient.to_string(), e.to_string()))?;
+		self.recipient = Some(parsed);
+		Ok(self)
+	}
+}
+
+/// Turns recording of relayed sessions on or off for this process.
+pub fn set_session_recording(options: Option<RecordingOptions>) {
+	*RECORDING.write().unwrap() = options;
+}
+
+/// Which side of a session data was sent by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+	/// Sent by the side that opened the session.
+	Input,
+	/// Sent to the side that opened the session.
+	Output,
+}
+
+/// How much of a session's data is recorded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordedData {
+	Contents,
+	/// Chunks in full, as base64, for binary protocols.
+	Binary,
+	/// Only the size of each chunk, for data the CLI can't read anyway.
+	SizesOnly,
+}
+
+struct RecorderInner {
+	tx: mpsc::UnboundedSender<String>,
+	started: Instant,
+	data: RecordedData,
+}
+
+impl RecorderInner {
+	fn event(&self, code: &str, data: Value) {
+		let elapsed = self.started.elapsed().as_secs_f64();
+		self.tx.send(json!([elapsed, code, data]).to_string()).ok();
+	}
+}
+
+impl Drop for RecorderInner {
+	fn drop(&mut self) {
+		self.event("x", Value::Null);
+	}
+}
+
+/// Records one session. Clones record to the same file, which is closed once
+/// all of them are dropped.
+#[derive(Clone)]
+pub struct SessionRecorder {
+	inner: Arc<RecorderInner>,
+}
+
+impl SessionRecorder {
+	/// Starts recording a session of the given kind, like "ssh-bridge", if
+	/// recording is turned on. The metadata is saved in the header.
+	pub fn start(
+		kind: &str,
+		data: RecordedData,
+		metadata: Value,
+	) -> Result<Option<SessionRecorder>, AnyError> {
+		let options = RECORDING.read().unwrap().clone();
+		match options {
+			Some(o) => Self::start_in(&o, kind, data, metadata).map(Some),
+			None => Ok(None),
+		}
+	}
+
+	fn start_in(
+		options: &RecordingOptions,
+		kind: &str,
+		data: RecordedData,
+		metadata: Value,
+	) -> Result<SessionRecorder, AnyError> {
+		create_private_dir(&options.dir)?;
+		// the directory may have been made before, by something else
+		restrict_to_owner(&options.dir, 0o700)
+			.map_err(|e| wrap(e, format!("error securing {}", options.dir.display())))?;
+		prune_recordings(&options.dir, options.retention);
+
+		let now = Utc::now();
+		let path = options.dir.join(format!(
+			"{}-{}-{}.cast",
+			now.format("%Y%m%dT%H%M%S"),
+			kind,
+			&uuid::Uuid::new_v4().to_simple().to_string()[..8]
+		));
+		let header = json!({
+			"version": 2,
+			"timestamp": now.timestamp(),
+			"kind": kind,
+			"cliVersion": VSCODE_CLI_VERSION,
+			"contents": match data {
+				RecordedData::Contents => json!(true),
+				RecordedData::Binary => json!("base64"),
+				RecordedData::SizesOnly => json!(false),
+			},
+			"metadata": metadata,
+		});
+
+		let mut writer = RecordingWriter::new(create_private_file(&path)?, &options.recipient)?;
+		let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+		std::thread::spawn(move || {
+			while let Some(mut line) = rx.blocking_recv() {
+				line.push('\n');
+				if writer.write_all(line.as_bytes()).is_err() {
+					return;
+				}
+			}
+			writer.finish().ok();
+		});
+
+		let recorder = SessionRecorder {
+			inner: Arc::new(RecorderInner {
+				tx,
+				started: Instant::now(),
+				data,
+			}),
+		};
+		recorder.inner.tx.send(header.to_string()).ok();
+		Ok(recorder)
+	}
+
+	/// Records data sent in the session.
+	pub fn record(&self, direction: Direction, bytes: &[u8]) {
+		if bytes.is_empty() {
+			return;
+		}
+
+		let code = match direction {
+			Direction::Input => "i",
+			Direction::Output => "o",
+		};
+		let data = match self.inner.data {
+			RecordedData::Contents => json!(String::from_utf8_lossy(bytes)),
+			RecordedData::Binary => json!(base64::encode(bytes)),
+			RecordedData::SizesOnly => json!(bytes.len()),
+		};
+		self.inner.event(code, data);
+	}
+}
+
+/// Writes a recording, encrypting it if there's a recipient.
+enum RecordingWriter {
+	Plain(BufWriter<std::fs::File>),
+	Encrypted(age::stream::StreamWriter<BufWriter<std::fs::File>>),
+}
+
+impl RecordingWriter {
+	fn new(
+		file: std::fs::File,
+		recipient: &Option<age::x25519::Recipient>,
+	) -> Result<Self, AnyError> {
+		let file = BufWriter::new(file);
+		let recipient = match recipient {
+			Some(r) => r.clone(),
+			None => return Ok(RecordingWriter::Plain(file)),
+		};
+
+		let encryptor = age::Encryptor::with_recipients(vec![Box::new(recipient)])
+			.expect("expected a recipient");
+		encryptor
+			.wrap_output(file)
+			.map(RecordingWriter::Encrypted)
+			.map_err(|e| wrap(e, "error encrypting recording").into())
+	}
+
+	fn write_all(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+		match self {
+			RecordingWriter::Plain(w) => w.write_all(bytes),
+			RecordingWriter::Encrypted(w) => w.write_all(bytes),
+		}
+	}
+
+	/// Flushes the recording, finishing its encryption.
+	fn finish(self) -> std::io::Result<()> {
+		match self {
+			RecordingWriter::Plain(mut w) => w.flush(),
+			RecordingWriter::Encrypted(w) => w.finish()?.flush(),
+		}
+	}
+}
+
+/// Stream that records the data read from and written to it. Reads are
+/// recorded in the given direction, and writes in the other.
+pub struct RecordedStream<S> {
+	inner: S,
+	recorder: Option<SessionRecorder>,
+	reads: Direction,
+}
+
+impl<S> RecordedStream<S> {
+	pub fn new(inner: S, recorder: Option<SessionRecorder>, reads: Direction) -> Self {
+		RecordedStream {
+			inner,
+			recorder,
+			reads,
+		}
+	}
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RecordedStream<S> {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<std::io::Result<()>> {
+		let before = buf.filled().len();
+		let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+		if let (Poll::Ready(Ok(())), Some(recorder)) = (&r, &self.recorder) {
+			recorder.record(self.reads, &buf.filled()[before..]);
+		}
+		r
+	}
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RecordedStream<S> {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<std::io::Result<usize>> {
+		let r = Pin::new(&mut self.inner).poll_write(cx, buf);
+		if let (Poll::Ready(Ok(n)), Some(recorder)) = (&r, &self.recorder) {
+			let direction = match self.reads {
+				Direction::Input => Direction::Output,
+				Direction::Output => Direction::Input,
+			};
+			recorder.record(direction, &buf[..*n]);
+		}
+		r
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
+
+/// Deletes recordings last written longer ago than the retention.
+fn prune_recordings(dir: &Path, retention: Duration) {
+	let retention = match retention.to_std() {
+		Ok(r) => r,
+		Err(_) => return,
+	};
+
+	let entries = match std::fs::read_dir(dir) {
+		Ok(e) => e,
+		Err(_) => return,
+	};
+
+	for entry in entries.flatten() {
+		let expired = entry
+			.metadata()
+			.and_then(|m| m.modified())
+			.ok()
+			.and_then(|m| m.elapsed().ok())
+			.map(|age| age > retention)
+			.unwrap_or(false);
+		if expired {
+			std::fs::remove_file(entry.path()).ok();
+		}
+	}
+}
+
+fn create_private_dir(dir: &Path) -> Result<(), AnyError> {
+	let mut builder = std::fs::DirBuilder::new();
+	builder.recursive(true);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::DirBuilderExt;
+		builder.mode(0o700);
+	}
+
+	builder
+		.create(dir)
+		.map_err(|e| wrap(e, format!("error creating {}", dir.display())).into())
+}
+
+fn create_private_file(path: &Path) -> Result<std::fs::File, AnyError> {
+	let mut options = std::fs::OpenOptions::new();
+	options.write(true).create_new(true);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::OpenOptionsExt;
+		options.mode(0o600);
+	}
+
+	options
+		.open(path)
+		.map_err(|e| wrap(e, format!("error creating recording {}", path.display())).into())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Read;
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+	async fn read_recording(dir: &Path) -> Vec<Value> {
+		// let the writer task finish
+		tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+		let entry = std::fs::read_dir(dir).unwrap().next().unwrap().unwrap();
+		std::fs::read_to_string(entry.path())
+			.unwrap()
+			.lines()
+			.map(|l| serde_json::from_str(l).unwrap())
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn test_records_sizes_only() {
+		let dir = tempfile::tempdir().unwrap();
+		let options = RecordingOptions::new(dir.path().to_owned(), None);
+		let recorder = SessionRecorder::start_in(
+			&options,
+			"ssh-bridge",
+			RecordedData::SizesOnly,
+			json!({ "sshPort": 22 }),
+		)
+		.unwrap();
+
+		let (client, mut server) = tokio::io::duplex(64);
+		let mut stream = RecordedStream::new(client, Some(recorder), Direction::Input);
+		server.write_all(b"hello").await.unwrap();
+		let mut buf = [0u8; 5];
+		stream.read_exact(&mut buf).await.unwrap();
+		stream.write_all(b"hi").await.unwrap();
+		drop(stream);
+
+		let lines = read_recording(dir.path()).await;
+		assert_eq!(lines[0]["kind"], "ssh-bridge");
+		assert_eq!(lines[0]["metadata"]["sshPort"], 22);
+		assert_eq!((&lines[1][1], &lines[1][2]), (&json!("i"), &json!(5)));
+		assert_eq!((&lines[2][1], &lines[2][2]), (&json!("o"), &json!(2)));
+		assert_eq!(lines[3][1], "x");
+	}
+
+	#[tokio::test]
+	async fn test_records_contents() {
+		let dir = tempfile::tempdir().unwrap();
+		let options = RecordingOptions::new(dir.path().to_owned(), None);
+		let recorder = SessionRecorder::start_in(
+			&options,
+			"command-shell",
+			RecordedData::Contents,
+			Value::Null,
+		)
+		.unwrap();
+		recorder.record(Direction::Input, b"{\"method\":\"initialize\"}");
+		drop(recorder);
+
+		let lines = read_recording(dir.path()).await;
+		assert_eq!(lines[1][2], "{\"method\":\"initialize\"}");
+	}
+
+	#[tokio::test]
+	async fn test_records_encrypted() {
+		let dir = tempfile::tempdir().unwrap();
+		let identity = age::x25519::Identity::generate();
+		let options = RecordingOptions::new(dir.path().to_owned(), None)
+			.with_recipient(&identity.to_public().to_string())
+			.unwrap();
+		let recorder =
+			SessionRecorder::start_in(&options, "server", RecordedData::Binary, Value::Null)
+				.unwrap();
+		recorder.record(Direction::Output, &[0, 1, 2]);
+		drop(recorder);
+
+		tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+		let entry = std::fs::read_dir(dir.path())
+			.unwrap()
+			.next()
+			.unwrap()
+			.unwrap();
+		let encrypted = std::fs::read(entry.path()).unwrap();
+		assert!(!String::from_utf8_lossy(&encrypted).contains("server"));
+
+		let decryptor = match age::Decryptor::new(&encrypted[..]).unwrap() {
+			age::Decryptor::Recipients(d) => d,
+			_ => panic!("expected a recipients decryptor"),
+		};
+		let mut contents = String::new();
+		decryptor
+			.decrypt(std::iter::once(&identity as &dyn age::Identity))
+			.unwrap()
+			.read_to_string(&mut contents)
+			.unwrap();
+		let lines: Vec<Value> = contents
+			.lines()
+			.map(|l| serde_json::from_str(l).unwrap())
+			.collect();
+		assert_eq!(lines[0]["contents"], "base64");
+		assert_eq!(lines[1][2], "AAEC");
+	}
+
+	#[test]
+	fn test_rejects_invalid_recipient() {
+		let options = RecordingOptions::new(PathBuf::from("recordings"), None);
+		assert!(options.with_recipient("not-a-key").is_err());
+	}
+
+	#[tokio::test]
+	async fn test_prunes_expired() {
+		let dir = tempfile::tempdir().unwrap();
+		std::fs::write(dir.path().join("old.cast"), "").unwrap();
+		prune_recordings(dir.path(), Duration::days(1));
+		assert!(dir.path().join("old.cast").exists());
+
+		tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+		prune_recordings(dir.path(), Duration::milliseconds(1));
+		assert!(!dir.path().join("old.cast").exists());
+	}
+}
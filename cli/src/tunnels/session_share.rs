@@ -0,0 +1,269 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Shares the tunnel daemon's relay session with other clients on this
+//! machine, like desktop VS Code, so they needn't log in or start a CLI of
+//! their own. When the daemon is started with `--share-session`, or
+//! `shareSession` is set in config.json, it also serves its control protocol
+//! on a socket in the CLI data directory (a named pipe on Windows) that only
+//! the current user can open. Clients on it use the daemon's tunnel as they
+//! would through the relay, and can call `sharesession` for the daemon's
+//! access to the account's other tunnels.
+//!
+//! The host decides which tunnels that's allowed for: those passed to
+//! `--share-session` or listed in `shareSession`. For others, the host is
+//! asked if the daemon runs in a terminal, and the answer is kept until the
+//! daemon exits. Clients can't allow it themselves.
+
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use sha2::{Digest, Sha256};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::{
+	auth::Auth,
+	debug, info, log,
+	state::LauncherPaths,
+	util::{
+		errors::{wrap, AnyError, SessionShareNotAllowed},
+		input::prompt_consent,
+		io::AsyncStream,
+		sync::Barrier,
+	},
+	warning,
+};
+
+use super::{dev_tunnels::DevTunnels, protocol::ShareSessionResult};
+
+/// Connections from local clients waiting to be served, before new ones wait
+/// to be accepted.
+const LOCAL_BACKLOG: usize = 4;
+
+/// Answers requests for the daemon's access to the account's tunnels.
+#[derive(Clone)]
+pub struct SessionShare {
+	log: log::Logger,
+	paths: LauncherPaths,
+	auth: Auth,
+	/// Tunnels the host's owner allowed up front.
+	allowed: Vec<String>,
+	/// Whether the host's owner can be asked about other tunnels.
+	can_ask: bool,
+	/// Answers the host's owner gave, by tunnel. Held while asking, so only
+	/// one question is asked at a time.
+	answers: Arc<Mutex<HashMap<String, bool>>>,
+}
+
+impl SessionShare {
+	pub fn new(log: log::Logger, paths: LauncherPaths, auth: Auth, allowed: Vec<String>) -> Self {
+		SessionShare {
+			log,
+			paths,
+			auth,
+			allowed,
+			can_ask: atty::is(atty::Stream::Stdin),
+			answers: Arc::new(Mutex::new(HashMap::new())),
+		}
+	}
+
+	/// Gets whether the host's owner allows the login to be shared for the
+	/// tunnel, asking them if they haven't said.
+	async fn is_allowed(&self, tunnel: &str) -> bool {
+		if self.allowed.iter().any(|t| t == tunnel) {
+			return true;
+		}
+		if !self.can_ask {
+			return false;
+		}
+
+		let mut answers = self.answers.lock().await;
+		if let Some(allowed) = answers.get(tunnel) {
+			return *allowed;
+		}
+
+		let question = format!(
+			"A client on this machine wants to connect to {} with this tunnel's login. Allow it until the tunnel stops?",
+			tunnel
+		);
+		let allowed = match tokio::task::spawn_blocking(move || prompt_consent(&question)).await {
+			Ok(Ok(allowed)) => allowed,
+			Ok(Err(e)) => {
+				debug!(self.log, "Could not ask to share the login: {}", e);
+				return false;
+			}
+			Err(_) => return false,
+		};
+		answers.insert(tunnel.to_string(), allowed);
+		allowed
+	}
+
+	/// Gets the daemon's access to the tunnel for a local client, if the
+	/// host's owner allows it.
+	pub async fn share(&self, tunnel: &str) -> Result<ShareSessionResult, AnyError> {
+		if !self.is_allowed(tunnel).await {
+			info!(
+				self.log,
+				"Refused to share the login for {} with a local client", tunnel
+			);
+			return Err(SessionShareNotAllowed(tunnel.to_string()).into());
+		}
+
+		info!(
+			self.log,
+			"Sharing the login for {} with a local client", tunnel
+		);
+		let mut dt = DevTunnels::new(&self.log, self.auth.clone(), &self.paths);
+		let access = dt.get_client_access(tunnel).await?;
+		Ok(ShareSessionResult {
+			tunnel_id: access.id,
+			cluster_id: access.cluster,
+			port_uri_format: access.port_uri_format,
+			access_token: access.token,
+		})
+	}
+}
+
+/// Gets the socket the daemon using the data directory serves local clients
+/// on.
+pub fn session_socket_path(paths: &LauncherPaths) -> PathBuf {
+	if cfg!(windows) {
+		let digest = Sha256::digest(paths.root().to_string_lossy().as_bytes());
+		PathBuf::from(format!(
+			r"\\.\pipe\vscode-cli-session-{}",
+			&format!("{:x}", digest)[..16]
+		))
+	} else {
+		paths.root().join("session.sock")
+	}
+}
+
+/// Accepts local clients on the socket until the barrier opens, returning
+/// their connections to be served with the control protocol.
+pub fn listen_local(
+	log: log::Logger,
+	paths: &LauncherPaths,
+	mut closer: Barrier<()>,
+) -> mpsc::Receiver<Box<dyn AsyncStream>> {
+	let socket = session_socket_path(paths);
+	let (tx, rx) = mpsc::channel(LOCAL_BACKLOG);
+	tokio::spawn(async move {
+		info!(
+			log,
+			"Sharing this tunnel with local clients on {}",
+			socket.display()
+		);
+		tokio::select! {
+			Err(e) = accept(&socket, tx) => {
+				warning!(log, "Could not share the tunnel with local clients: {}", e);
+			},
+			_ = closer.wait() => {},
+		}
+	});
+	rx
+}
+
+#[cfg(unix)]
+async fn accept(
+	socket: &std::path::Path,
+	tx: mpsc::Sender<Box<dyn AsyncStream>>,
+) -> Result<(), AnyError> {
+	use crate::util::io::restrict_to_owner;
+
+	// left behind if an earlier daemon didn't exit cleanly
+	std::fs::remove_file(socket).ok();
+	let listener = tokio::net::UnixListener::bind(socket)
+		.map_err(|e| wrap(e, format!("error listening on {}", socket.display())))?;
+	restrict_to_owner(socket, 0o600)
+		.map_err(|e| wrap(e, format!("error restricting {}", socket.display())))?;
+
+	loop {
+		let (stream, _) = listener
+			.accept()
+			.await
+			.map_err(|e| wrap(e, "error accepting local connection"))?;
+		if tx.send(Box::new(stream)).await.is_err() {
+			return Ok(());
+		}
+	}
+}
+
+#[cfg(windows)]
+async fn accept(
+	socket: &std::path::Path,
+	tx: mpsc::Sender<Box<dyn AsyncStream>>,
+) -> Result<(), AnyError> {
+	use tokio::net::windows::named_pipe::ServerOptions;
+
+	let create = |first: bool| {
+		ServerOptions::new()
+			.first_pipe_instance(first)
+			.reject_remote_clients(true)
+			.create(socket)
+			.map_err(|e| wrap(e, format!("error listening on {}", socket.display())))
+	};
+
+	let mut server = create(true)?;
+	loop {
+		server
+			.connect()
+			.await
+			.map_err(|e| wrap(e, "error accepting local connection"))?;
+		let connected = std::mem::replace(&mut server, create(false)?);
+		if tx.send(Box::new(connected)).await.is_err() {
+			return Ok(());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn share(dir: &std::path::Path, allowed: &[&str]) -> SessionShare {
+		let paths = LauncherPaths::new_without_replacements(dir.to_owned());
+		let log = log::Logger::test();
+		SessionShare {
+			auth: Auth::new(&paths, log.clone()),
+			log,
+			paths,
+			allowed: allowed.iter().map(|t| t.to_string()).collect(),
+			can_ask: false,
+			answers: Arc::new(Mutex::new(HashMap::new())),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_only_shares_allowed_tunnels() {
+		let dir = tempfile::tempdir().unwrap();
+		let share = share(dir.path(), &["my-box"]);
+
+		assert!(share.is_allowed("my-box").await);
+		assert!(!share.is_allowed("other-box").await);
+		assert!(matches!(
+			share.share("other-box").await,
+			Err(AnyError::SessionShareNotAllowed(_))
+		));
+	}
+
+	#[tokio::test]
+	async fn test_keeps_answers() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut share = share(dir.path(), &[]);
+		share.can_ask = true;
+		share
+			.answers
+			.lock()
+			.await
+			.insert("my-box".to_string(), true);
+		share
+			.answers
+			.lock()
+			.await
+			.insert("other-box".to_string(), false);
+
+		assert!(share.is_allowed("my-box").await);
+		assert!(!share.is_allowed("other-box").await);
+	}
+}
@@ -0,0 +1,229 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::path::{Path, PathBuf};
+
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use crate::{
+	auth::{Auth, AuthFeature, AuthProvider},
+	constants::{SERVER_DATA_FOLDER_NAME, VSCODE_CLI_SETTINGS_SYNC_URL},
+	info, log, trace,
+	util::{
+		errors::{wrap, AnyError, MissingHomeDirectory, SettingsSyncError, StatusError},
+		http::shared_client,
+	},
+};
+
+/// Written to the user data directory while synced data is being applied, so
+/// an attempt that fails partway is retried rather than taken for data the
+/// server wrote.
+const PENDING_MARKER: &str = ".settings-sync-pending";
+/// The pending marker is renamed to this once synced data has been applied.
+const APPLIED_MARKER: &str = ".settings-sync-applied";
+
+/// Envelope the sync service stores each resource in.
+#[derive(Deserialize)]
+struct SyncData {
+	content: String,
+}
+
+#[derive(Deserialize)]
+struct SettingsContent {
+	settings: String,
+}
+
+#[derive(Deserialize)]
+struct SyncExtension {
+	identifier: SyncExtensionIdentifier,
+	#[serde(default)]
+	disabled: bool,
+	installed: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct SyncExtensionIdentifier {
+	id: String,
+}
+
+/// Gets the user data directory of the server on this machine.
+fn server_user_data_dir() -> Result<PathBuf, AnyError> {
+	let home = dirs::home_dir().ok_or(MissingHomeDirectory())?;
+	Ok(home.join(SERVER_DATA_FOLDER_NAME).join("data").join("User"))
+}
+
+/// Pulls the user's settings, keybindings, and extension list from Settings
+/// Sync and writes them into the server's user data directory, if the server
+/// hasn't been used on this machine before. Returns the IDs of extensions the
+/// server should install when it next starts.
+pub async fn bootstrap_settings_sync(
+	log: &log::Logger,
+	auth: &Auth,
+) -> Result<Vec<String>, AnyError> {
+	let user_dir = server_user_data_dir()?;
+	if !should_apply(&user_dir) {
+		info!(
+			log,
+			"Server user data already exists in {}, not applying Settings Sync data",
+			user_dir.display()
+		);
+		return Ok(vec![]);
+	}
+
+	let sync_url = VSCODE_CLI_SETTINGS_SYNC_URL
+		.ok_or_else(|| SettingsSyncError("no service url is configured".to_string()))?;
+
+	let creds = auth
+		.get_credential_for_features(None, &[AuthFeature::SettingsSync])
+		.await?;
+	if creds.provider() != AuthProvider::Github {
+		// the token we hold for Microsoft accounts is only valid for the tunnel service
+		return Err(SettingsSyncError(format!(
+			"only supported when logged in with {}",
+			AuthProvider::Github
+		))
+		.into());
+	}
+
+	let client = SyncClient {
+		client: shared_client(),
+		base_url: sync_url.trim_end_matches('/').to_string(),
+		token: creds.access_token().to_string(),
+	};
+
+	std::fs::create_dir_all(&user_dir)
+		.map_err(|e| wrap(e, format!("error creating {}", user_dir.display())))?;
+	write_file(&user_dir.join(PENDING_MARKER), "")?;
+
+	if let Some(settings) = client.get_resource::<SettingsContent>("settings").await? {
+		write_file(&user_dir.join("settings.json"), &settings.settings)?;
+		info!(log, "Applied synced settings");
+	}
+
+	if let Some(keybindings) = client
+		.get_resource::<serde_json::Value>("keybindings")
+		.await?
+		.and_then(|k| get_platform_keybindings(&k))
+	{
+		write_file(&user_dir.join("keybindings.json"), &keybindings)?;
+		info!(log, "Applied synced keybindings");
+	}
+
+	let extensions = client
+		.get_resource::<Vec<SyncExtension>>("extensions")
+		.await?
+		.unwrap_or_default()
+		.into_iter()
+		.filter(|e| !e.disabled && e.installed.unwrap_or(true))
+		.map(|e| e.identifier.id)
+		.collect::<Vec<_>>();
+	trace!(log, "Synced extensions to install: {:?}", extensions);
+
+	let pending = user_dir.join(PENDING_MARKER);
+	std::fs::rename(&pending, user_dir.join(APPLIED_MARKER))
+		.map_err(|e| wrap(e, format!("error renaming {}", pending.display())))?;
+
+	Ok(extensions)
+}
+
+/// Gets whether synced data should be written into the user data directory:
+/// if the server hasn't created it yet, or if an earlier attempt to apply
+/// the data didn't finish.
+fn should_apply(user_dir: &Path) -> bool {
+	if user_dir.join(APPLIED_MARKER).exists() {
+		return false;
+	}
+
+	!user_dir.exists() || user_dir.join(PENDING_MARKER).exists()
+}
+
+/// Keybindings are synced per platform. Older clients synced only the
+/// current platform's, under a single key.
+fn get_platform_keybindings(value: &serde_json::Value) -> Option<String> {
+	let key = if cfg!(target_os = "windows") {
+		"windows"
+	} else if cfg!(target_os = "macos") {
+		"mac"
+	} else {
+		"linux"
+	};
+
+	value
+		.get(key)
+		.or_else(|| value.get("keybindings"))
+		.and_then(|v| v.as_str())
+		.map(|s| s.to_string())
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<(), AnyError> {
+	std::fs::write(path, contents)
+		.map_err(|e| wrap(e, format!("error writing {}", path.display())).into())
+}
+
+struct SyncClient {
+	client: reqwest::Client,
+	base_url: String,
+	token: String,
+}
+
+impl SyncClient {
+	/// Gets the latest content of the resource, or None if it was never synced.
+	async fn get_resource<T>(&self, resource: &str) -> Result<Option<T>, AnyError>
+	where
+		T: serde::de::DeserializeOwned,
+	{
+		let url = format!("{}/v1/resource/{}/latest", self.base_url, resource);
+		let response = self
+			.client
+			.get(&url)
+			.bearer_auth(&self.token)
+			.header("X-Account-Type", "github")
+			.send()
+			.await?;
+
+		if response.status() == StatusCode::NO_CONTENT || response.status() == StatusCode::NOT_FOUND
+		{
+			return Ok(None);
+		}
+
+		if !response.status().is_success() {
+			return Err(StatusError::from_res(response).await?.into());
+		}
+
+		let text = response.text().await?;
+		if text.is_empty() {
+			return Ok(None);
+		}
+
+		let data: SyncData = serde_json::from_str(&text)
+			.map_err(|e| wrap(e, format!("error parsing synced {}", resource)))?;
+		let content = serde_json::from_str(&data.content)
+			.map_err(|e| wrap(e, format!("error parsing synced {} content", resource)))?;
+
+		Ok(Some(content))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_should_apply() {
+		let dir = tempfile::tempdir().unwrap();
+		let user_dir = dir.path().join("User");
+		assert!(should_apply(&user_dir));
+
+		std::fs::create_dir(&user_dir).unwrap();
+		assert!(!should_apply(&user_dir));
+
+		std::fs::write(user_dir.join(PENDING_MARKER), "").unwrap();
+		assert!(should_apply(&user_dir));
+
+		std::fs::rename(user_dir.join(PENDING_MARKER), user_dir.join(APPLIED_MARKER)).unwrap();
+		assert!(!should_apply(&user_dir));
+	}
+}
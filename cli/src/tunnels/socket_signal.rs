@@ -3,6 +3,11 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
+use std::{
+	collections::VecDeque,
+	time::{Duration, Instant},
+};
+
 use serde::Serialize;
 use tokio::sync::mpsc;
 
@@ -13,6 +18,9 @@ pub struct CloseReason(pub String);
 pub enum SocketSignal {
 	/// Signals bytes to send to the socket.
 	Send(Vec<u8>),
+	/// Signals bytes to send to the socket that may be dropped if the client
+	/// isn't keeping up, such as logs and diagnostics.
+	SendLowPriority(Vec<u8>),
 	/// Closes the socket (e.g. as a result of an error)
 	CloseWith(CloseReason),
 	/// Disposes ServerBridge corresponding to an ID
@@ -26,6 +34,110 @@ impl SocketSignal {
 	{
 		SocketSignal::Send(rmp_serde::to_vec_named(msg).unwrap())
 	}
+
+	pub fn from_low_priority_message<T>(msg: &T) -> Self
+	where
+		T: Serialize + ?Sized,
+	{
+		SocketSignal::SendLowPriority(rmp_serde::to_vec_named(msg).unwrap())
+	}
+}
+
+/// Maximum number of interactive messages held before applying backpressure.
+const MAX_QUEUED_HIGH_PRIORITY: usize = 8;
+/// Maximum number of low-priority messages held; the oldest are dropped first.
+const MAX_QUEUED_LOW_PRIORITY: usize = 256;
+/// How long a client can stay behind before low-priority messages are shed.
+const SLOW_CONSUMER_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// Change in whether the client is keeping up with sent data.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BacklogEvent {
+	/// The client has been behind for a while, so low-priority messages will
+	/// be dropped until it catches up.
+	Shedding {
+		backlogged_for: Duration,
+		queued: usize,
+		dropped: usize,
+	},
+	/// The client caught up. Includes the total number of messages dropped.
+	Recovered { dropped: usize },
+}
+
+/// Queue of messages waiting to be written to a client. Interactive messages
+/// are always sent ahead of low-priority ones, and low-priority ones are shed
+/// when the client stays behind, rather than buffering everything.
+#[derive(Default)]
+pub struct OutgoingQueue {
+	high: VecDeque<Vec<u8>>,
+	low: VecDeque<Vec<u8>>,
+	backlogged_since: Option<Instant>,
+	shedding: bool,
+	dropped: usize,
+}
+
+impl OutgoingQueue {
+	/// Gets whether more interactive messages can be queued. If not, callers
+	/// should wait for messages to be written first.
+	pub fn can_accept(&self) -> bool {
+		self.high.len() < MAX_QUEUED_HIGH_PRIORITY
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.high.is_empty() && self.low.is_empty()
+	}
+
+	pub fn push(&mut self, bytes: Vec<u8>) {
+		self.high.push_back(bytes);
+	}
+
+	pub fn push_low_priority(&mut self, bytes: Vec<u8>) {
+		if self.shedding {
+			self.dropped += 1;
+			return;
+		}
+
+		if self.low.len() == MAX_QUEUED_LOW_PRIORITY {
+			self.low.pop_front();
+			self.dropped += 1;
+		}
+		self.low.push_back(bytes);
+	}
+
+	/// Takes the next message to write.
+	pub fn pop(&mut self) -> Option<Vec<u8>> {
+		self.high.pop_front().or_else(|| self.low.pop_front())
+	}
+
+	/// Should be called after each write to track whether the client is
+	/// keeping up. Returns an event when shedding starts or stops.
+	pub fn check_backlog(&mut self, now: Instant) -> Option<BacklogEvent> {
+		if self.is_empty() {
+			self.backlogged_since = None;
+			if self.shedding {
+				self.shedding = false;
+				return Some(BacklogEvent::Recovered {
+					dropped: std::mem::take(&mut self.dropped),
+				});
+			}
+			return None;
+		}
+
+		let since = *self.backlogged_since.get_or_insert(now);
+		let backlogged_for = now.duration_since(since);
+		if self.shedding || backlogged_for < SLOW_CONSUMER_THRESHOLD {
+			return None;
+		}
+
+		self.shedding = true;
+		self.dropped += self.low.len();
+		self.low.clear();
+		Some(BacklogEvent::Shedding {
+			backlogged_for,
+			queued: self.high.len(),
+			dropped: self.dropped,
+		})
+	}
 }
 
 /// Struct that handling sending or closing a connected server socket.
@@ -241,4 +353,43 @@ mod tests {
 			assert_eq!(decompressed, vals);
 		}
 	}
+
+	#[test]
+	fn test_outgoing_queue_prioritizes_interactive() {
+		let mut queue = OutgoingQueue::default();
+		queue.push_low_priority(vec![1]);
+		queue.push(vec![2]);
+		queue.push_low_priority(vec![3]);
+		queue.push(vec![4]);
+
+		let order = std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>();
+		assert_eq!(order, vec![vec![2], vec![4], vec![1], vec![3]]);
+	}
+
+	#[test]
+	fn test_outgoing_queue_sheds_for_slow_consumer() {
+		let mut queue = OutgoingQueue::default();
+		let start = Instant::now();
+		queue.push(vec![1]);
+		queue.push_low_priority(vec![2]);
+		assert_eq!(queue.check_backlog(start), None);
+
+		let later = start + SLOW_CONSUMER_THRESHOLD;
+		assert_eq!(
+			queue.check_backlog(later),
+			Some(BacklogEvent::Shedding {
+				backlogged_for: SLOW_CONSUMER_THRESHOLD,
+				queued: 1,
+				dropped: 1,
+			})
+		);
+
+		queue.push_low_priority(vec![3]);
+		assert_eq!(queue.pop(), Some(vec![1]));
+		assert_eq!(queue.pop(), None);
+		assert_eq!(
+			queue.check_backlog(later),
+			Some(BacklogEvent::Recovered { dropped: 2 })
+		);
+	}
 }
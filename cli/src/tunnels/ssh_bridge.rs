@@ -0,0 +1,296 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Bridges SSH connections through a tunnel. The host serves an HTTP endpoint
+//! on `SSH_BRIDGE_PORT` that, once a connection is upgraded, relays bytes to
+//! its local SSH server. Clients upgrade a request to that port's public URI
+//! and relay stdin and stdout over it, so `code tunnel stdio-bridge` can be
+//! used as an SSH `ProxyCommand`, or over connections to a local port, so
+//! SFTP clients can use `code tunnel sftp`. SFTP itself is served by the
+//! host's SSH server, so the usual user permissions apply. Connections are
+//! recorded on either side when `--record-sessions` is set.
+
+use std::convert::Infallible;
+
+use hyper::{
+	header::{CONNECTION, UPGRADE},
+	server::conn::Http,
+	service::service_fn,
+	Body, Request, Response, StatusCode,
+};
+use reqwest::Upgraded;
+use serde_json::json;
+use tokio::{
+	io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+	net::{TcpListener, TcpStream},
+	sync::mpsc,
+};
+
+use crate::{
+	debug, log, spanh,
+	util::{
+		errors::{wrap, AnyError, StatusError},
+		http::new_client_builder,
+	},
+	warning,
+};
+
+use super::{
+	dev_tunnels::PortConnection,
+	ip_filter::PortGate,
+	session_recording::{Direction, RecordedData, RecordedStream, SessionRecorder},
+};
+
+/// Protocol used in the Upgrade header. Websocket upgrades are relayed by the
+/// tunnel service's web forwarding, though no websocket framing is used.
+const UPGRADE_PROTOCOL: &str = "websocket";
+/// Header in which the tunnel access token is sent.
+const TUNNEL_AUTHORIZATION_HEADER: &str = "X-Tunnel-Authorization";
+const BRIDGE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Serves bridge connections from the tunnel, relaying them to the SSH server
+/// listening on `ssh_port`, until the tunnel is closed.
+pub async fn serve_ssh_bridge(
+	log: log::Logger,
+	connections: mpsc::UnboundedReceiver<PortConnection>,
+	gate: PortGate,
+	ssh_port: u16,
+) {
+	serve_upgrades(log, connections, gate, move |log, req| {
+		accept_bridge(log, req, ssh_port)
+	})
+	.await
+}
+
+/// Serves HTTP on each connection made to a tunnel port, responding to each
+/// request the gate lets through with `accept`, which is expected to handle
+/// upgrades.
+pub(super) async fn serve_upgrades<F>(
+	log: log::Logger,
+	mut connections: mpsc::UnboundedReceiver<PortConnection>,
+	gate: PortGate,
+	accept: F,
+) where
+	F: Fn(log::Logger, Request<Body>) -> Response<Body> + Clone + Send + Sync + 'static,
+{
+	while let Some(conn) = connections.recv().await {
+		let log = log.clone();
+		let accept = accept.clone();
+		let gate = gate.clone();
+		tokio::spawn(async move {
+			// hyper needs a single duplex stream, so pipe the halves through one
+			let (writehalf, readhalf) = conn.into_split();
+			let (local, remote) = tokio::io::duplex(BRIDGE_BUFFER_SIZE);
+			let (local_read, local_write) = tokio::io::split(local);
+			tokio::spawn(pipe(readhalf, local_write));
+			tokio::spawn(pipe(local_read, writehalf));
+
+			let service_log = log.clone();
+			let result = Http::new()
+				.http1_only(true)
+				.serve_connection(
+					remote,
+					service_fn(move |req| {
+						let response = match gate.check(&req) {
+							Ok(()) => accept(service_log.clone(), req),
+							Err(rejected) => rejected,
+						};
+						async move { Ok::<_, Infallible>(response) }
+					}),
+				)
+				.with_upgrades()
+				.await;
+
+			if let Err(e) = result {
+				debug!(log, "Bridge connection closed: {}", e);
+			}
+		});
+	}
+}
+
+/// Accepts an upgrade request, relaying the upgraded connection to the SSH
+/// server once the response is sent.
+fn accept_bridge(log: log::Logger, req: Request<Body>, ssh_port: u16) -> Response<Body> {
+	if !req.headers().contains_key(UPGRADE) {
+		return bad_request("expected an upgrade request");
+	}
+
+	let recorder = match SessionRecorder::start(
+		"ssh-bridge",
+		RecordedData::SizesOnly,
+		json!({ "sshPort": ssh_port }),
+	) {
+		Ok(r) => r,
+		Err(e) => {
+			warning!(log, "Refusing SSH bridge connection: {}", e);
+			return Response::builder()
+				.status(StatusCode::INTERNAL_SERVER_ERROR)
+				.body(Body::from("the session could not be recorded"))
+				.unwrap();
+		}
+	};
+
+	tokio::spawn(async move {
+		let result = spanh!(log, "ssh.bridge", None, async {
+			let upgraded = hyper::upgrade::on(req)
+				.await
+				.map_err(|e| wrap(e, "error upgrading bridge connection"))?;
+			let mut upgraded = RecordedStream::new(upgraded, recorder, Direction::Input);
+			let mut ssh = TcpStream::connect(("127.0.0.1", ssh_port))
+				.await
+				.map_err(|e| wrap(e, "error connecting to the SSH server"))?;
+			tokio::io::copy_bidirectional(&mut upgraded, &mut ssh)
+				.await
+				.map_err(|e| wrap(e, "error relaying SSH connection"))
+		});
+
+		match result {
+			Ok((tx, rx)) => debug!(log, "SSH bridge closed after {}B out, {}B in", rx, tx),
+			Err(e) => debug!(log, "SSH bridge failed: {}", e),
+		}
+	});
+
+	switching_protocols()
+}
+
+pub(super) fn bad_request(message: &'static str) -> Response<Body> {
+	Response::builder()
+		.status(StatusCode::BAD_REQUEST)
+		.body(Body::from(message))
+		.unwrap()
+}
+
+pub(super) fn switching_protocols() -> Response<Body> {
+	Response::builder()
+		.status(StatusCode::SWITCHING_PROTOCOLS)
+		.header(UPGRADE, UPGRADE_PROTOCOL)
+		.header(CONNECTION, "Upgrade")
+		.body(Body::empty())
+		.unwrap()
+}
+
+/// Connects to the bridge at the port URI, then relays stdin and stdout over
+/// it until either side closes.
+pub async fn bridge_stdio(port_uri: &str, access_token: &str) -> Result<(), AnyError> {
+	let recorder = SessionRecorder::start(
+		"stdio-bridge",
+		RecordedData::SizesOnly,
+		json!({ "uri": port_uri }),
+	)?;
+	let upgraded = connect_bridge(port_uri, access_token, None).await?;
+	let upgraded = RecordedStream::new(upgraded, recorder, Direction::Output);
+	let (read, write) = tokio::io::split(upgraded);
+	let from_remote = pipe(read, tokio::io::stdout());
+	tokio::pin!(from_remote);
+
+	// once stdin closes, wait for any remaining output; but reads from stdin
+	// can't be cancelled, so stop as soon as the remote closes.
+	tokio::select! {
+		r = &mut from_remote => return r,
+		r = pipe(tokio::io::stdin(), write) => r?,
+	}
+
+	from_remote.await
+}
+
+/// Listens on the local port, relaying each connection made to it through
+/// the bridge, so SFTP and SSH clients that can't use a `ProxyCommand` can
+/// connect to the host's SSH server. Runs until Ctrl+C is pressed.
+pub async fn bridge_local_port(
+	log: &log::Logger,
+	local_port: u16,
+	port_uri: &str,
+	access_token: &str,
+) -> Result<(), AnyError> {
+	let listener = TcpListener::bind(("127.0.0.1", local_port))
+		.await
+		.map_err(|e| wrap(e, format!("error listening on port {}", local_port)))?;
+	log.result(format!(
+		"Listening for SFTP and SSH connections on 127.0.0.1:{}. Press Ctrl+C to stop.",
+		local_port
+	));
+
+	loop {
+		let (mut stream, addr) = tokio::select! {
+			_ = tokio::signal::ctrl_c() => return Ok(()),
+			r = listener.accept() => r.map_err(|e| wrap(e, "error accepting connection"))?,
+		};
+
+		debug!(log, "Bridging connection from {}", addr);
+		let log = log.clone();
+		let (port_uri, access_token) = (port_uri.to_string(), access_token.to_string());
+		tokio::spawn(async move {
+			let result = async {
+				let recorder = SessionRecorder::start(
+					"local-bridge",
+					RecordedData::SizesOnly,
+					json!({ "uri": port_uri, "client": addr.to_string() }),
+				)?;
+				let upgraded = connect_bridge(&port_uri, &access_token, None).await?;
+				let mut upgraded = RecordedStream::new(upgraded, recorder, Direction::Output);
+				tokio::io::copy_bidirectional(&mut stream, &mut upgraded)
+					.await
+					.map_err(|e| wrap(e, "error relaying data"))?;
+				Ok::<(), AnyError>(())
+			}
+			.await;
+
+			if let Err(e) = result {
+				warning!(log, "Connection from {} failed: {}", addr, e);
+			}
+		});
+	}
+}
+
+/// Upgrades a request to the port URI, returning the upgraded connection. The
+/// header is sent with the request, if given.
+pub(super) async fn connect_bridge(
+	port_uri: &str,
+	access_token: &str,
+	header: Option<(&'static str, &str)>,
+) -> Result<Upgraded, AnyError> {
+	let client = new_client_builder()
+		.http1_only()
+		.build()
+		.map_err(|e| wrap(e, "error creating http client"))?;
+	let mut req = client.get(port_uri);
+	if let Some((name, value)) = header {
+		req = req.header(name, value);
+	}
+
+	let res = req
+		.header(CONNECTION, "Upgrade")
+		.header(UPGRADE, UPGRADE_PROTOCOL)
+		.header("Sec-WebSocket-Version", "13")
+		.header(
+			"Sec-WebSocket-Key",
+			uuid::Uuid::new_v4().to_simple().to_string(),
+		)
+		.header(
+			TUNNEL_AUTHORIZATION_HEADER,
+			format!("tunnel {}", access_token),
+		)
+		.send()
+		.await?;
+
+	if res.status() != reqwest::StatusCode::SWITCHING_PROTOCOLS {
+		return Err(StatusError::from_res(res).await?.into());
+	}
+
+	res.upgrade()
+		.await
+		.map_err(|e| wrap(e, "error upgrading bridge connection").into())
+}
+
+async fn pipe(
+	mut from: impl AsyncRead + Unpin,
+	mut to: impl AsyncWrite + Unpin,
+) -> Result<(), AnyError> {
+	tokio::io::copy(&mut from, &mut to)
+		.await
+		.map_err(|e| wrap(e, "error relaying data"))?;
+	to.shutdown().await.ok();
+	Ok(())
+}
@@ -0,0 +1,266 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	path::PathBuf,
+	sync::{Arc, Mutex},
+	time::Instant,
+};
+
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	log,
+	state::{LauncherPaths, PersistedState},
+	util::errors::WrappedError,
+	warning,
+};
+
+/// Number of hourly buckets kept, a little over a month. Older ones are
+/// dropped as new ones are added.
+const MAX_HOURS: usize = 24 * 32;
+
+/// Statistics for the tunnel over one hour.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HourlyStats {
+	/// Start of the hour, in UTC.
+	pub hour: DateTime<Utc>,
+	/// Bytes sent to clients.
+	pub tx: u64,
+	/// Bytes received from clients.
+	pub rx: u64,
+	/// Number of client connections made.
+	pub connections: u32,
+	/// Seconds the tunnel was hosted for.
+	pub uptime_secs: u32,
+	/// Number of times the tunnel reconnected to the relay.
+	pub reconnects: u32,
+}
+
+impl HourlyStats {
+	fn is_empty(&self) -> bool {
+		self.tx == 0
+			&& self.rx == 0
+			&& self.connections == 0
+			&& self.uptime_secs == 0
+			&& self.reconnects == 0
+	}
+
+	/// Adds the other stats into these ones.
+	pub fn merge(&mut self, other: &HourlyStats) {
+		self.tx += other.tx;
+		self.rx += other.rx;
+		self.connections += other.connections;
+		self.uptime_secs += other.uptime_secs;
+		self.reconnects += other.reconnects;
+	}
+}
+
+/// Gets the path of the stats history for the tunnel.
+pub fn stats_history_path(paths: &LauncherPaths, tunnel_name: &str) -> PathBuf {
+	let safe_name: String = tunnel_name
+		.chars()
+		.map(|c| {
+			if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+				c
+			} else {
+				'_'
+			}
+		})
+		.collect();
+
+	paths.root().join(format!("stats-{}.json", safe_name))
+}
+
+/// Loads the stats recorded for the tunnel at or after the given time,
+/// oldest first.
+pub fn load_stats_history(
+	paths: &LauncherPaths,
+	tunnel_name: &str,
+	since: DateTime<Utc>,
+) -> Vec<HourlyStats> {
+	let state: PersistedState<Vec<HourlyStats>> =
+		PersistedState::new(stats_history_path(paths, tunnel_name));
+	let since = truncate_to_hour(since);
+	state
+		.load()
+		.into_iter()
+		.filter(|s| s.hour >= since)
+		.collect()
+}
+
+fn truncate_to_hour(t: DateTime<Utc>) -> DateTime<Utc> {
+	t.duration_trunc(Duration::hours(1)).unwrap_or(t)
+}
+
+/// Records hourly statistics for a tunnel. Data is kept in memory and
+/// written out when `flush` is called, so recording is cheap.
+#[derive(Clone)]
+pub struct StatsRecorder {
+	state: PersistedState<Vec<HourlyStats>>,
+	pending: Arc<Mutex<HourlyStats>>,
+}
+
+impl StatsRecorder {
+	pub fn new(paths: &LauncherPaths, tunnel_name: &str) -> Self {
+		StatsRecorder {
+			state: PersistedState::new(stats_history_path(paths, tunnel_name)),
+			pending: Arc::new(Mutex::new(HourlyStats {
+				hour: truncate_to_hour(Utc::now()),
+				..Default::default()
+			})),
+		}
+	}
+
+	/// Records a client connection, and the data transferred over it.
+	pub fn record_connection(&self, tx: usize, rx: usize) {
+		self.update(|s| {
+			s.connections += 1;
+			s.tx += tx as u64;
+			s.rx += rx as u64;
+		});
+	}
+
+	/// Records time the tunnel was hosted for.
+	pub fn record_uptime(&self, duration: std::time::Duration) {
+		self.update(|s| s.uptime_secs += duration.as_secs() as u32);
+	}
+
+	/// Records reconnections to the relay.
+	pub fn record_reconnects(&self, count: u32) {
+		self.update(|s| s.reconnects += count);
+	}
+
+	fn update(&self, f: impl FnOnce(&mut HourlyStats)) {
+		let now_hour = truncate_to_hour(Utc::now());
+		let mut pending = self.pending.lock().unwrap();
+		if pending.hour != now_hour {
+			self.write_bucket(&pending).ok();
+			*pending = HourlyStats {
+				hour: now_hour,
+				..Default::default()
+			};
+		}
+		f(&mut pending);
+	}
+
+	/// Writes stats recorded so far to disk.
+	pub fn flush(&self, log: &log::Logger) {
+		let mut pending = self.pending.lock().unwrap();
+		if let Err(e) = self.write_bucket(&pending) {
+			warning!(log, "Error saving tunnel stats: {}", e);
+			return;
+		}
+
+		// the bucket's now on disk, keep accumulating into it from zero
+		*pending = HourlyStats {
+			hour: pending.hour,
+			..Default::default()
+		};
+	}
+
+	fn write_bucket(&self, bucket: &HourlyStats) -> Result<(), WrappedError> {
+		if bucket.is_empty() {
+			return Ok(());
+		}
+
+		let mut history = self.state.load();
+		add_to_history(&mut history, bucket);
+		self.state.save(history)
+	}
+}
+
+fn add_to_history(history: &mut Vec<HourlyStats>, bucket: &HourlyStats) {
+	match history.iter_mut().rev().find(|s| s.hour == bucket.hour) {
+		Some(existing) => existing.merge(bucket),
+		None => {
+			history.push(bucket.clone());
+			history.sort_by_key(|s| s.hour);
+		}
+	}
+
+	if history.len() > MAX_HOURS {
+		history.drain(..history.len() - MAX_HOURS);
+	}
+}
+
+/// Records uptime and relay reconnects while a tunnel is hosted. Anything
+/// not yet recorded is written out when it's dropped.
+pub struct UptimeRecorder {
+	recorder: StatsRecorder,
+	log: log::Logger,
+	last_tick: Instant,
+	last_reconnects: u32,
+}
+
+impl UptimeRecorder {
+	pub fn new(recorder: StatsRecorder, log: log::Logger) -> Self {
+		UptimeRecorder {
+			recorder,
+			log,
+			last_tick: Instant::now(),
+			last_reconnects: 0,
+		}
+	}
+
+	/// Records time since the last tick, and any new reconnects given the
+	/// tunnel's current total, then flushes the stats to disk.
+	pub fn tick(&mut self, reconnect_count: u32) {
+		self.record_uptime();
+		self.recorder
+			.record_reconnects(reconnect_count.saturating_sub(self.last_reconnects));
+		self.last_reconnects = reconnect_count;
+		self.recorder.flush(&self.log);
+	}
+
+	fn record_uptime(&mut self) {
+		let now = Instant::now();
+		self.recorder.record_uptime(now - self.last_tick);
+		self.last_tick = now;
+	}
+}
+
+impl Drop for UptimeRecorder {
+	fn drop(&mut self) {
+		self.record_uptime();
+		self.recorder.flush(&self.log);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::TimeZone;
+
+	fn bucket(hour: u32, connections: u32) -> HourlyStats {
+		HourlyStats {
+			hour: Utc.ymd(2022, 10, 1).and_hms(hour, 0, 0),
+			connections,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_merges_same_hour() {
+		let mut history = vec![bucket(1, 1)];
+		add_to_history(&mut history, &bucket(1, 2));
+		add_to_history(&mut history, &bucket(2, 5));
+		assert_eq!(history, vec![bucket(1, 3), bucket(2, 5)]);
+	}
+
+	#[test]
+	fn test_keeps_newest() {
+		let mut history = vec![];
+		for i in 0..(MAX_HOURS + 10) {
+			let mut b = bucket(0, 1);
+			b.hour = b.hour + Duration::hours(i as i64);
+			add_to_history(&mut history, &b);
+		}
+
+		assert_eq!(history.len(), MAX_HOURS);
+		assert_eq!(history[0].hour, bucket(0, 1).hour + Duration::hours(10));
+	}
+}
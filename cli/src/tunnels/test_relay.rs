@@ -0,0 +1,707 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Fake relay for integration tests, built with the `test-relay` feature. A
+//! host started with `--test-relay <addr>` is hosted through it instead of
+//! the dev tunnels service, so that connecting, forwarding, and reconnecting
+//! can be tested without credentials or a network.
+//!
+//! The host keeps a control connection to the relay, on which it sends JSON
+//! lines to add and remove ports. The relay listens on a local address for
+//! each port, and when a client connects there, asks the host to open a data
+//! connection. After a line naming the client, that carries the client's
+//! bytes as they are.
+
+use std::{
+	collections::HashMap,
+	net::SocketAddr,
+	sync::{
+		atomic::{AtomicU32, Ordering},
+		Arc, Mutex,
+	},
+	time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+	net::{
+		tcp::{OwnedReadHalf, OwnedWriteHalf},
+		TcpListener, TcpStream,
+	},
+	sync::{mpsc, oneshot, watch},
+	task::JoinHandle,
+};
+
+use crate::{
+	debug, info, log, trace,
+	util::errors::{wrap, AnyError, DevTunnelError, WrappedError},
+	warning,
+};
+
+use super::dev_tunnels::PortConnection;
+
+/// Interval at which the host reconnects after losing its connection to the
+/// relay. It's fixed rather than backing off, so that tests are predictable.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the relay waits for the host to accept a client.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest line accepted at the start of a connection.
+const MAX_LINE_LENGTH: usize = 4096;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum HostMessage {
+	/// Opens the host's control connection.
+	Hello,
+	AddPort {
+		port: u16,
+	},
+	RemovePort {
+		port: u16,
+	},
+	/// Opens a data connection for the client with the ID.
+	Accept {
+		id: u64,
+	},
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum RelayMessage {
+	/// The port is forwarded, and clients can connect to it at the address.
+	PortAdded { port: u16, address: SocketAddr },
+	/// A client connected to the port, and should be accepted by the host.
+	Connection { port: u16, id: u64 },
+}
+
+fn to_line<T: Serialize>(message: &T) -> Vec<u8> {
+	let mut line = serde_json::to_vec(message).expect("expected to serialize message");
+	line.push(b'\n');
+	line
+}
+
+/// Reads the line at the start of a connection. It's read a byte at a time,
+/// so that nothing after it is consumed.
+async fn read_first_line(stream: &mut TcpStream) -> Option<String> {
+	let mut line = vec![];
+	loop {
+		match stream.read_u8().await.ok()? {
+			b'\n' => return Some(String::from_utf8_lossy(&line).to_string()),
+			_ if line.len() >= MAX_LINE_LENGTH => return None,
+			b => line.push(b),
+		}
+	}
+}
+
+/// Where connections to a forwarded port go.
+#[derive(Clone)]
+enum PortTarget {
+	/// Connections are handled by the CLI.
+	Direct(mpsc::UnboundedSender<PortConnection>),
+	/// Connections are relayed to the port on this machine.
+	Tcp,
+}
+
+struct HostState {
+	log: log::Logger,
+	relay_addr: String,
+	ports: Mutex<HashMap<u16, PortTarget>>,
+	/// Addresses the relay accepts clients on, by port.
+	addresses: Mutex<HashMap<u16, SocketAddr>>,
+	addresses_tx: watch::Sender<()>,
+	addresses_rx: watch::Receiver<()>,
+	/// Control connection to the relay, if it's connected.
+	control: tokio::sync::Mutex<Option<OwnedWriteHalf>>,
+	/// Number of times the control connection has been established.
+	connect_count: AtomicU32,
+}
+
+impl HostState {
+	/// Opens the control connection, registering the ports forwarded so far.
+	async fn connect(&self) -> Result<OwnedReadHalf, WrappedError> {
+		let stream = TcpStream::connect(&self.relay_addr).await.map_err(|e| {
+			wrap(
+				e,
+				format!("error connecting to test relay at {}", self.relay_addr),
+			)
+		})?;
+		self.connect_count.fetch_add(1, Ordering::Relaxed);
+
+		// the lock is held so ports added meanwhile are sent on this connection
+		let (read, mut write) = stream.into_split();
+		let mut control = self.control.lock().await;
+		let ports: Vec<u16> = self.ports.lock().unwrap().keys().copied().collect();
+		let mut lines = to_line(&HostMessage::Hello);
+		for port in ports {
+			lines.extend(to_line(&HostMessage::AddPort { port }));
+		}
+		write
+			.write_all(&lines)
+			.await
+			.map_err(|e| wrap(e, "error registering with test relay"))?;
+
+		*control = Some(write);
+		Ok(read)
+	}
+
+	async fn disconnect(&self) {
+		self.control.lock().await.take();
+		self.addresses.lock().unwrap().clear();
+		self.addresses_tx.send(()).ok();
+	}
+
+	/// Sends the message on the control connection. If it's disconnected,
+	/// ports are registered again once it reconnects, so nothing's lost.
+	async fn send(&self, message: &HostMessage) {
+		if let Some(write) = self.control.lock().await.as_mut() {
+			if let Err(e) = write.write_all(&to_line(message)).await {
+				trace!(self.log, "Error sending to test relay: {}", e);
+			}
+		}
+	}
+
+	fn handle_message(self: &Arc<Self>, line: &str) {
+		match serde_json::from_str::<RelayMessage>(line) {
+			Ok(RelayMessage::PortAdded { port, address }) => {
+				self.addresses.lock().unwrap().insert(port, address);
+				self.addresses_tx.send(()).ok();
+			}
+			Ok(RelayMessage::Connection { port, id }) => {
+				let target = match self.ports.lock().unwrap().get(&port) {
+					Some(t) => t.clone(),
+					None => return,
+				};
+
+				let state = self.clone();
+				tokio::spawn(async move {
+					if let Err(e) = state.accept(port, id, target).await {
+						debug!(state.log, "Error accepting test relay connection: {}", e);
+					}
+				});
+			}
+			Err(e) => warning!(self.log, "Invalid message from test relay: {}", e),
+		}
+	}
+
+	/// Opens a data connection for the client, and hands it to the target.
+	async fn accept(&self, port: u16, id: u64, target: PortTarget) -> Result<(), AnyError> {
+		let mut stream = TcpStream::connect(&self.relay_addr)
+			.await
+			.map_err(|e| wrap(e, "error connecting to test relay"))?;
+		stream
+			.write_all(&to_line(&HostMessage::Accept { id }))
+			.await
+			.map_err(|e| wrap(e, "error accepting connection"))?;
+
+		match target {
+			PortTarget::Direct(tx) => {
+				tx.send(PortConnection::Test(stream)).ok();
+			}
+			PortTarget::Tcp => {
+				let mut local = TcpStream::connect(("127.0.0.1", port))
+					.await
+					.map_err(|e| wrap(e, format!("error connecting to port {}", port)))?;
+				tokio::io::copy_bidirectional(&mut stream, &mut local)
+					.await
+					.ok();
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// Host side of a tunnel through the test relay.
+pub struct TestRelayHost {
+	state: Arc<HostState>,
+	close_tx: Option<oneshot::Sender<()>>,
+	task: Option<JoinHandle<()>>,
+}
+
+impl TestRelayHost {
+	/// Connects to the relay, failing if it can't be reached. The connection's
+	/// re-established in the background if it's lost after that.
+	pub async fn connect(log: log::Logger, relay_addr: String) -> Result<Self, AnyError> {
+		let (addresses_tx, addresses_rx) = watch::channel(());
+		let state = Arc::new(HostState {
+			log,
+			relay_addr,
+			ports: Mutex::new(HashMap::new()),
+			addresses: Mutex::new(HashMap::new()),
+			addresses_tx,
+			addresses_rx,
+			control: tokio::sync::Mutex::new(None),
+			connect_count: AtomicU32::new(0),
+		});
+
+		let read = state.connect().await?;
+		let (close_tx, close_rx) = oneshot::channel();
+		let task = tokio::spawn(run_host(state.clone(), read, close_rx));
+
+		Ok(TestRelayHost {
+			state,
+			close_tx: Some(close_tx),
+			task: Some(task),
+		})
+	}
+
+	pub fn connect_count(&self) -> &AtomicU32 {
+		&self.state.connect_count
+	}
+
+	pub async fn add_port_direct(
+		&self,
+		port: u16,
+	) -> Result<mpsc::UnboundedReceiver<PortConnection>, AnyError> {
+		let (tx, rx) = mpsc::unbounded_channel();
+		self.add_port(port, PortTarget::Direct(tx)).await;
+		Ok(rx)
+	}
+
+	pub async fn add_port_tcp(&self, port: u16) -> Result<(), AnyError> {
+		self.add_port(port, PortTarget::Tcp).await;
+		Ok(())
+	}
+
+	async fn add_port(&self, port: u16, target: PortTarget) {
+		self.state.ports.lock().unwrap().insert(port, target);
+		self.state.send(&HostMessage::AddPort { port }).await;
+	}
+
+	pub async fn remove_port(&self, port: u16) -> Result<(), AnyError> {
+		self.state.ports.lock().unwrap().remove(&port);
+		self.state.addresses.lock().unwrap().remove(&port);
+		self.state.send(&HostMessage::RemovePort { port }).await;
+		Ok(())
+	}
+
+	/// Gets the URI clients can reach the port on, once the relay has added it.
+	pub async fn get_port_uri(&self, port: u16) -> Result<String, AnyError> {
+		let mut rx = self.state.addresses_rx.clone();
+		loop {
+			if !self.state.ports.lock().unwrap().contains_key(&port) {
+				return Err(DevTunnelError(format!("port {} isn't forwarded", port)).into());
+			}
+
+			let address = self.state.addresses.lock().unwrap().get(&port).copied();
+			if let Some(address) = address {
+				return Ok(format!("http://{}", address));
+			}
+
+			if rx.changed().await.is_err() {
+				return Err(DevTunnelError("test relay host closed".to_string()).into());
+			}
+		}
+	}
+
+	/// Stops hosting. Receivers of forwarded connections are closed.
+	pub async fn close(&mut self) {
+		self.state.ports.lock().unwrap().clear();
+		if let Some(tx) = self.close_tx.take() {
+			tx.send(()).ok();
+		}
+		if let Some(task) = self.task.take() {
+			task.await.ok();
+		}
+		self.state.disconnect().await;
+	}
+}
+
+/// Reads messages from the relay until the host is closed, reconnecting if
+/// the connection's lost.
+async fn run_host(
+	state: Arc<HostState>,
+	mut read: OwnedReadHalf,
+	mut close_rx: oneshot::Receiver<()>,
+) {
+	loop {
+		let mut lines = BufReader::new(read).lines();
+		loop {
+			tokio::select! {
+				_ = &mut close_rx => return,
+				line = lines.next_line() => match line {
+					Ok(Some(l)) => state.handle_message(&l),
+					Ok(None) => break,
+					Err(e) => {
+						debug!(state.log, "Error reading from test relay: {}", e);
+						break;
+					}
+				}
+			}
+		}
+
+		state.disconnect().await;
+		info!(state.log, "Lost connection to the test relay, reconnecting");
+
+		read = loop {
+			tokio::select! {
+				_ = &mut close_rx => return,
+				_ = tokio::time::sleep(RECONNECT_INTERVAL) => {},
+			}
+
+			match state.connect().await {
+				Ok(r) => break r,
+				Err(e) => trace!(state.log, "{}", e),
+			}
+		};
+	}
+}
+
+/// Fake relay that hosts started with `--test-relay` connect to. Only one
+/// host is expected to be connected at a time.
+#[derive(Clone)]
+pub struct TestRelay {
+	addr: SocketAddr,
+	state: Arc<RelayState>,
+}
+
+struct RelayState {
+	log: log::Logger,
+	inner: Mutex<RelayInner>,
+	changed_tx: watch::Sender<()>,
+	changed_rx: watch::Receiver<()>,
+}
+
+#[derive(Default)]
+struct RelayInner {
+	/// Sends messages on the host's control connection, if it's connected.
+	host: Option<mpsc::UnboundedSender<RelayMessage>>,
+	/// Drops the host's control connection.
+	disconnect_host: Option<oneshot::Sender<()>>,
+	/// Number of times a host has connected.
+	host_connections: u32,
+	/// Addresses clients connect to for each port, and the tasks accepting
+	/// them.
+	ports: HashMap<u16, (SocketAddr, JoinHandle<()>)>,
+	/// Clients waiting for the host to accept them.
+	pending: HashMap<u64, oneshot::Sender<TcpStream>>,
+	next_id: u64,
+}
+
+impl TestRelay {
+	/// Starts the relay listening on the address, like '127.0.0.1:0'.
+	pub async fn start(log: log::Logger, addr: &str) -> Result<TestRelay, AnyError> {
+		let listener = TcpListener::bind(addr)
+			.await
+			.map_err(|e| wrap(e, format!("error listening on {}", addr)))?;
+		let addr = listener
+			.local_addr()
+			.map_err(|e| wrap(e, "error getting test relay address"))?;
+
+		let (changed_tx, changed_rx) = watch::channel(());
+		let relay = TestRelay {
+			addr,
+			state: Arc::new(RelayState {
+				log,
+				inner: Mutex::new(RelayInner::default()),
+				changed_tx,
+				changed_rx,
+			}),
+		};
+
+		tokio::spawn(relay.clone().accept_loop(listener));
+		Ok(relay)
+	}
+
+	/// Gets the address hosts connect to.
+	pub fn local_addr(&self) -> SocketAddr {
+		self.addr
+	}
+
+	/// Waits until hosts have connected the given number of times in total.
+	pub async fn wait_for_host_connections(&self, count: u32) {
+		self.wait_for(|i| {
+			if i.host_connections >= count {
+				Some(())
+			} else {
+				None
+			}
+		})
+		.await
+	}
+
+	/// Waits until the port is forwarded, returning the address clients
+	/// connect to it on.
+	pub async fn wait_for_port(&self, port: u16) -> SocketAddr {
+		self.wait_for(|i| i.ports.get(&port).map(|(a, _)| *a)).await
+	}
+
+	/// Drops the host's control connection, as if the network failed, so
+	/// that it reconnects.
+	pub fn disconnect_host(&self) {
+		if let Some(tx) = self.state.inner.lock().unwrap().disconnect_host.take() {
+			tx.send(()).ok();
+		}
+	}
+
+	async fn wait_for<T>(&self, check: impl Fn(&RelayInner) -> Option<T>) -> T {
+		let mut rx = self.state.changed_rx.clone();
+		loop {
+			let value = check(&self.state.inner.lock().unwrap());
+			if let Some(value) = value {
+				return value;
+			}
+
+			rx.changed().await.ok();
+		}
+	}
+
+	fn notify(&self) {
+		self.state.changed_tx.send(()).ok();
+	}
+
+	async fn accept_loop(self, listener: TcpListener) {
+		loop {
+			match listener.accept().await {
+				Ok((stream, _)) => {
+					tokio::spawn(self.clone().handle_incoming(stream));
+				}
+				Err(e) => warning!(self.state.log, "Error accepting connection: {}", e),
+			}
+		}
+	}
+
+	async fn handle_incoming(self, mut stream: TcpStream) {
+		let line = match read_first_line(&mut stream).await {
+			Some(l) => l,
+			None => return,
+		};
+
+		match serde_json::from_str::<HostMessage>(&line) {
+			Ok(HostMessage::Hello) => self.serve_host(stream).await,
+			Ok(HostMessage::Accept { id }) => {
+				let pending = self.state.inner.lock().unwrap().pending.remove(&id);
+				if let Some(tx) = pending {
+					tx.send(stream).ok();
+				}
+			}
+			_ => debug!(
+				self.state.log,
+				"Unexpected connection to test relay: {}", line
+			),
+		}
+	}
+
+	async fn serve_host(self, stream: TcpStream) {
+		let (read, mut write) = stream.into_split();
+		let (tx, mut rx) = mpsc::unbounded_channel::<RelayMessage>();
+		let (disconnect_tx, mut disconnect_rx) = oneshot::channel();
+		let connection = {
+			let mut inner = self.state.inner.lock().unwrap();
+			inner.host = Some(tx.clone());
+			inner.disconnect_host = Some(disconnect_tx);
+			inner.host_connections += 1;
+			inner.host_connections
+		};
+		self.notify();
+		info!(self.state.log, "Host connected to the test relay");
+
+		let writer = tokio::spawn(async move {
+			while let Some(m) = rx.recv().await {
+				if write.write_all(&to_line(&m)).await.is_err() {
+					break;
+				}
+			}
+		});
+
+		let mut lines = BufReader::new(read).lines();
+		loop {
+			tokio::select! {
+				_ = &mut disconnect_rx => break,
+				line = lines.next_line() => match line {
+					Ok(Some(l)) => self.handle_host_message(&l, &tx).await,
+					_ => break,
+				}
+			}
+		}
+
+		writer.abort();
+		{
+			let mut inner = self.state.inner.lock().unwrap();
+			if inner.host_connections == connection {
+				inner.host = None;
+				inner.disconnect_host = None;
+			}
+		}
+		self.notify();
+		info!(self.state.log, "Host disconnected from the test relay");
+	}
+
+	async fn handle_host_message(&self, line: &str, tx: &mpsc::UnboundedSender<RelayMessage>) {
+		match serde_json::from_str::<HostMessage>(line) {
+			Ok(HostMessage::AddPort { port }) => match self.add_port(port).await {
+				Ok(address) => {
+					tx.send(RelayMessage::PortAdded { port, address }).ok();
+				}
+				Err(e) => warning!(self.state.log, "Error forwarding port {}: {}", port, e),
+			},
+			Ok(HostMessage::RemovePort { port }) => {
+				let removed = self.state.inner.lock().unwrap().ports.remove(&port);
+				if let Some((_, task)) = removed {
+					task.abort();
+				}
+				self.notify();
+			}
+			_ => debug!(self.state.log, "Unexpected message from host: {}", line),
+		}
+	}
+
+	/// Listens for clients of the port, if it isn't already. Addresses are
+	/// kept when the host reconnects, so clients can connect again.
+	async fn add_port(&self, port: u16) -> Result<SocketAddr, std::io::Error> {
+		if let Some((address, _)) = self.state.inner.lock().unwrap().ports.get(&port) {
+			return Ok(*address);
+		}
+
+		let listener = TcpListener::bind("127.0.0.1:0").await?;
+		let address = listener.local_addr()?;
+		let task = tokio::spawn(self.clone().serve_port(port, listener));
+		self.state
+			.inner
+			.lock()
+			.unwrap()
+			.ports
+			.insert(port, (address, task));
+		self.notify();
+
+		info!(
+			self.state.log,
+			"Forwarding port {} to clients connecting on {}", port, address
+		);
+		Ok(address)
+	}
+
+	async fn serve_port(self, port: u16, listener: TcpListener) {
+		loop {
+			if let Ok((client, _)) = listener.accept().await {
+				tokio::spawn(self.clone().relay_client(port, client));
+			}
+		}
+	}
+
+	/// Asks the host to accept the client, and relays between them.
+	async fn relay_client(self, port: u16, mut client: TcpStream) {
+		let (tx, rx) = oneshot::channel();
+		let id = {
+			let mut inner = self.state.inner.lock().unwrap();
+			let host = match &inner.host {
+				Some(h) => h.clone(),
+				None => {
+					debug!(
+						self.state.log,
+						"No host connected for client of port {}", port
+					);
+					return;
+				}
+			};
+
+			inner.next_id += 1;
+			let id = inner.next_id;
+			inner.pending.insert(id, tx);
+			host.send(RelayMessage::Connection { port, id }).ok();
+			id
+		};
+
+		let mut host_stream = match tokio::time::timeout(ACCEPT_TIMEOUT, rx).await {
+			Ok(Ok(s)) => s,
+			_ => {
+				self.state.inner.lock().unwrap().pending.remove(&id);
+				debug!(self.state.log, "Host didn't accept client of port {}", port);
+				return;
+			}
+		};
+
+		tokio::io::copy_bidirectional(&mut client, &mut host_stream)
+			.await
+			.ok();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tunnels::dev_tunnels::ActiveTunnel;
+
+	async fn round_trip(addr: SocketAddr) -> Vec<u8> {
+		let mut client = TcpStream::connect(addr).await.unwrap();
+		client.write_all(b"hello").await.unwrap();
+		let mut buf = vec![0; 5];
+		client.read_exact(&mut buf).await.unwrap();
+		buf
+	}
+
+	async fn echo(conn: PortConnection) {
+		let (mut write, mut read) = conn.into_split();
+		let mut buf = [0; 5];
+		read.read_exact(&mut buf).await.unwrap();
+		write.write_all(&buf).await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_connect_forward_reconnect() {
+		let log = log::Logger::test();
+		let relay = TestRelay::start(log.clone(), "127.0.0.1:0").await.unwrap();
+		let mut tunnel = ActiveTunnel::start_test_tunnel(
+			&log,
+			"test".to_string(),
+			&relay.local_addr().to_string(),
+		)
+		.await
+		.unwrap();
+
+		let mut connections = tunnel.add_port_direct(31545).await.unwrap();
+		let addr = relay.wait_for_port(31545).await;
+		assert_eq!(
+			tunnel.get_port_uri(31545).await.unwrap(),
+			format!("http://{}", addr)
+		);
+
+		let client = tokio::spawn(round_trip(addr));
+		echo(connections.recv().await.unwrap()).await;
+		assert_eq!(client.await.unwrap(), b"hello");
+
+		relay.disconnect_host();
+		relay.wait_for_host_connections(2).await;
+		assert_eq!(tunnel.reconnect_count(), 1);
+
+		let client = tokio::spawn(round_trip(addr));
+		echo(connections.recv().await.unwrap()).await;
+		assert_eq!(client.await.unwrap(), b"hello");
+
+		tunnel.close().await.unwrap();
+		assert!(connections.recv().await.is_none());
+	}
+
+	#[tokio::test]
+	async fn test_forward_tcp_port() {
+		let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = local.local_addr().unwrap().port();
+		tokio::spawn(async move {
+			let (mut s, _) = local.accept().await.unwrap();
+			let mut buf = [0; 5];
+			s.read_exact(&mut buf).await.unwrap();
+			s.write_all(&buf).await.unwrap();
+		});
+
+		let log = log::Logger::test();
+		let relay = TestRelay::start(log.clone(), "127.0.0.1:0").await.unwrap();
+		let mut tunnel = ActiveTunnel::start_test_tunnel(
+			&log,
+			"test".to_string(),
+			&relay.local_addr().to_string(),
+		)
+		.await
+		.unwrap();
+
+		tunnel.add_port_tcp(port).await.unwrap();
+		let addr = relay.wait_for_port(port).await;
+		assert_eq!(round_trip(addr).await, b"hello");
+
+		tunnel.remove_port(port).await.unwrap();
+		assert!(tunnel.get_port_uri(port).await.is_err());
+		tunnel.close().await.unwrap();
+	}
+}
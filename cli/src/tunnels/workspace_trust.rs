@@ -0,0 +1,144 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::path::{Component, Path, PathBuf};
+
+/// Folders the host owner has declared trusted, sent to clients when they
+/// connect so they don't ask the user to trust their own machine each session,
+/// and optionally a root that clients are kept inside of. The root is
+/// enforced by jailing the server to it; the checks here only cover requests
+/// the CLI makes to the server on clients' behalf.
+#[derive(Clone, Debug, Default)]
+pub struct WorkspacePolicy {
+	pub trusted_folders: Vec<PathBuf>,
+	/// If set, files outside this folder aren't served to clients, and the
+	/// server is jailed to it.
+	pub root: Option<PathBuf>,
+}
+
+impl WorkspacePolicy {
+	/// Creates a policy, resolving the folders to absolute paths. Trusted
+	/// folders outside the root are returned as an error, since clients
+	/// couldn't open them anyway.
+	pub fn new(trusted_folders: Vec<PathBuf>, root: Option<PathBuf>) -> Result<Self, String> {
+		let root = root.map(|r| resolve(&r));
+		let trusted_folders: Vec<PathBuf> = trusted_folders.iter().map(|f| resolve(f)).collect();
+		if let Some(root) = &root {
+			if let Some(f) = trusted_folders.iter().find(|f| !f.starts_with(root)) {
+				return Err(format!(
+					"trusted folder {} is outside the workspace root {}",
+					f.display(),
+					root.display()
+				));
+			}
+		}
+
+		Ok(WorkspacePolicy {
+			trusted_folders,
+			root,
+		})
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.trusted_folders.is_empty() && self.root.is_none()
+	}
+
+	/// Gets whether clients may access the path.
+	pub fn allows(&self, path: &Path) -> bool {
+		match &self.root {
+			Some(root) => resolve(path).starts_with(root),
+			None => true,
+		}
+	}
+
+	/// Gets whether clients may access the file referenced by a request to
+	/// the server, such as `/vscode-remote-resource?path=/etc/passwd`.
+	/// Requests that don't reference a file are allowed.
+	pub fn allows_request(&self, request_path: &str) -> bool {
+		if self.root.is_none() {
+			return true;
+		}
+
+		let query = match request_path.split_once('?') {
+			Some((_, q)) => q,
+			None => return true,
+		};
+
+		url::form_urlencoded::parse(query.as_bytes())
+			.filter(|(k, _)| k == "path")
+			.all(|(_, v)| self.allows(Path::new(v.as_ref())))
+	}
+}
+
+/// Resolves the path to an absolute one without `..` components, following
+/// symlinks in the part of it that exists so they can't be used to escape
+/// the root.
+fn resolve(path: &Path) -> PathBuf {
+	let absolute = if path.is_absolute() {
+		path.to_path_buf()
+	} else {
+		std::env::current_dir().unwrap_or_default().join(path)
+	};
+
+	let mut normalized = PathBuf::new();
+	for component in absolute.components() {
+		match component {
+			Component::ParentDir => {
+				normalized.pop();
+			}
+			Component::CurDir => {}
+			c => normalized.push(c),
+		}
+	}
+
+	let mut existing = normalized.as_path();
+	let mut rest = vec![];
+	loop {
+		if let Ok(p) = std::fs::canonicalize(existing) {
+			return rest.iter().rev().fold(p, |p, c| p.join(c));
+		}
+
+		match (existing.parent(), existing.file_name()) {
+			(Some(parent), Some(name)) => {
+				rest.push(name.to_owned());
+				existing = parent;
+			}
+			_ => return normalized,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_allows() {
+		let dir = tempfile::tempdir().unwrap();
+		let root = dir.path().join("project");
+		std::fs::create_dir(&root).unwrap();
+
+		let policy = WorkspacePolicy::new(vec![], Some(root.clone())).unwrap();
+		assert!(policy.allows(&root.join("src/main.rs")));
+		assert!(!policy.allows(&root.join("../secret")));
+		assert!(!policy.allows(dir.path()));
+
+		let path = root.join("a.txt");
+		assert!(policy.allows_request(&format!("/vscode-remote-resource?path={}", path.display())));
+		assert!(!policy.allows_request("/vscode-remote-resource?path=%2Fetc%2Fpasswd"));
+		assert!(policy.allows_request("/version"));
+
+		assert!(WorkspacePolicy::default().allows(Path::new("/etc/passwd")));
+	}
+
+	#[test]
+	fn test_trusted_folders_inside_root() {
+		let dir = tempfile::tempdir().unwrap();
+		let root = dir.path().join("project");
+
+		assert!(WorkspacePolicy::new(vec![root.join("app")], Some(root.clone())).is_ok());
+		assert!(WorkspacePolicy::new(vec![dir.path().join("other")], Some(root)).is_err());
+	}
+}
@@ -3,24 +3,134 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::path::Path;
+use std::{
+	collections::HashMap,
+	fmt,
+	path::{Path, PathBuf},
+	str::FromStr,
+	sync::{
+		atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+		RwLock,
+	},
+};
 
-use serde::Deserialize;
+use chrono::{DateTime, Duration, Utc};
+use hyper::{
+	header::{
+		ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+		LAST_MODIFIED, RANGE,
+	},
+	http::HeaderValue,
+	HeaderMap, StatusCode,
+};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::SyncIoBridge;
 
 use crate::{
-	constants::VSCODE_CLI_UPDATE_ENDPOINT,
-	debug, log, options, spanf,
+	constants::{VSCODE_CLI_SIGNING_KEY, VSCODE_CLI_UPDATE_ENDPOINT},
+	debug, log,
+	options::{self, UpdateEndpointLayout},
+	spanf,
+	state::{LauncherPaths, PersistedState},
+	trace,
 	util::{
-		errors::{AnyError, UnsupportedPlatformError, UpdatesNotConfigured, WrappedError},
-		http::{SimpleHttp, SimpleResponse},
-		io::ReportCopyProgress,
+		archive::{self, ArchiveFormat},
+		errors::{
+			wrap, wrap_read, AnyError, ChecksumMismatchError, InvalidReleaseName,
+			NoBuildsForPlatformError, SignatureVerificationError, UnsupportedPlatformError,
+			UpdateCheckDisabledError, UpdatesNotConfigured, WrappedError,
+		},
+		http::{
+			make_request_with_retry, RetryPolicy, SimpleHttp, SimpleResponse,
+			DEFAULT_RETRY_ATTEMPTS,
+		},
+		io::{copy_async_progress, Sha256Writer},
+		priority::run_maintenance,
+		progress::ReportProgress,
+		signing::verify_digest_signature,
+		tar,
+		tempfile::write_file_atomic,
 	},
+	warning,
 };
 
+/// Duration for which a cached metadata response is used without asking the
+/// update service whether it's still current.
+const METADATA_CACHE_TTL_MINUTES: i64 = 60;
+
+lazy_static! {
+	static ref UPDATE_ENDPOINT: RwLock<Option<(String, UpdateEndpointLayout)>> = RwLock::new(None);
+}
+
+/// Sets the update endpoint releases are resolved and downloaded from, in
+/// place of the one the CLI was built with, such as an internal mirror.
+pub fn set_update_endpoint(url: &str, layout: UpdateEndpointLayout) {
+	let url = url.trim_end_matches('/').to_string();
+	*UPDATE_ENDPOINT.write().unwrap() = Some((url, layout));
+}
+
+/// Gets the configured update endpoint and its layout.
+fn update_endpoint() -> Result<(String, UpdateEndpointLayout), UpdatesNotConfigured> {
+	if let Some(e) = UPDATE_ENDPOINT.read().unwrap().clone() {
+		return Ok(e);
+	}
+
+	VSCODE_CLI_UPDATE_ENDPOINT
+		.map(|u| (u.to_string(), UpdateEndpointLayout::Service))
+		.ok_or_else(UpdatesNotConfigured::no_url)
+}
+
 /// Implementation of the VS Code Update service for use in the CLI.
 pub struct UpdateService {
 	client: Box<dyn SimpleHttp + Send + Sync + 'static>,
 	log: log::Logger,
+	cache: Option<UpdateServiceCache>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CachedMetadata {
+	etag: Option<String>,
+	#[serde(default)]
+	last_modified: Option<String>,
+	fetched_at: DateTime<Utc>,
+	version: UpdateServerVersion,
+}
+
+/// On-disk cache of version metadata returned from the update service, keyed
+/// by request URL. Entries are reused until their TTL expires, after which
+/// they are revalidated using their ETag or Last-Modified time.
+#[derive(Clone)]
+pub struct UpdateServiceCache {
+	state: PersistedState<HashMap<String, CachedMetadata>>,
+	no_update_check: bool,
+}
+
+impl UpdateServiceCache {
+	pub fn new(paths: &LauncherPaths) -> Self {
+		UpdateServiceCache {
+			state: PersistedState::new(paths.root().join("update-cache.json")),
+			no_update_check: false,
+		}
+	}
+
+	/// Uses cached entries however old they are, never asking the update
+	/// service, for `--no-update-check`. Lookups that were never cached fail.
+	pub fn without_update_check(mut self) -> Self {
+		self.no_update_check = true;
+		self
+	}
+
+	fn get(&self, url: &str) -> Option<CachedMetadata> {
+		self.state.load().remove(url)
+	}
+
+	fn set(&self, url: String, entry: CachedMetadata) -> Result<(), WrappedError> {
+		self.state.update_with((url, entry), |(url, entry), state| {
+			state.insert(url, entry);
+		})
+	}
 }
 
 /// Describes a specific release, can be created manually or returned from the update service.
@@ -30,6 +140,8 @@ pub struct Release {
 	pub target: TargetKind,
 	pub quality: options::Quality,
 	pub commit: String,
+	/// SHA-256 digest of the release's download, if the update service gave one.
+	pub sha256: Option<String>,
 }
 
 impl std::fmt::Display for Release {
@@ -38,10 +150,12 @@ impl std::fmt::Display for Release {
 	}
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct UpdateServerVersion {
 	pub version: String,
 	pub name: String,
+	#[serde(default)]
+	pub sha256hash: Option<String>,
 }
 
 fn quality_download_segment(quality: options::Quality) -> &'static str {
@@ -57,9 +171,16 @@ impl UpdateService {
 		UpdateService {
 			client: Box::new(http),
 			log,
+			cache: None,
 		}
 	}
 
+	/// Caches version lookups in the given cache.
+	pub fn with_cache(mut self, cache: Option<UpdateServiceCache>) -> Self {
+		self.cache = cache;
+		self
+	}
+
 	pub async fn get_release_by_semver_version(
 		&self,
 		platform: Platform,
@@ -67,30 +188,41 @@ impl UpdateService {
 		quality: options::Quality,
 		version: &str,
 	) -> Result<Release, AnyError> {
-		let update_endpoint =
-			VSCODE_CLI_UPDATE_ENDPOINT.ok_or_else(UpdatesNotConfigured::no_url)?;
+		self.with_compat_fallback(platform, target, |p| {
+			self.lookup_semver_version(p, target, quality, version)
+		})
+		.await
+	}
+
+	async fn lookup_semver_version(
+		&self,
+		platform: Platform,
+		target: TargetKind,
+		quality: options::Quality,
+		version: &str,
+	) -> Result<Release, AnyError> {
+		let (update_endpoint, layout) = update_endpoint()?;
 		let download_segment = target
 			.download_segment(platform)
 			.ok_or(UnsupportedPlatformError())?;
-		let download_url = format!(
-			"{}/api/versions/{}/{}/{}",
-			update_endpoint,
-			version,
-			download_segment,
-			quality_download_segment(quality),
-		);
-
-		let mut response = spanf!(
-			self.log,
-			self.log.span("server.version.resolve"),
-			self.client.make_request("GET", download_url)
-		)?;
-
-		if !response.status_code.is_success() {
-			return Err(response.into_err().await.into());
-		}
+		let download_url = match layout {
+			UpdateEndpointLayout::Service => format!(
+				"{}/api/versions/{}/{}/{}",
+				update_endpoint,
+				version,
+				download_segment,
+				quality_download_segment(quality),
+			),
+			UpdateEndpointLayout::Static => format!(
+				"{}/{}/versions/{}/{}.json",
+				update_endpoint,
+				quality_download_segment(quality),
+				version,
+				download_segment,
+			),
+		};
 
-		let res = response.json::<UpdateServerVersion>().await?;
+		let res = self.get_version_metadata(download_url).await?;
 		debug!(self.log, "Resolved version {} to {}", version, res.version);
 
 		Ok(Release {
@@ -99,6 +231,7 @@ impl UpdateService {
 			quality,
 			name: res.name,
 			commit: res.version,
+			sha256: res.sha256hash,
 		})
 	}
 
@@ -109,29 +242,79 @@ impl UpdateService {
 		target: TargetKind,
 		quality: options::Quality,
 	) -> Result<Release, AnyError> {
-		let update_endpoint =
-			VSCODE_CLI_UPDATE_ENDPOINT.ok_or_else(UpdatesNotConfigured::no_url)?;
-		let download_segment = target
-			.download_segment(platform)
-			.ok_or(UnsupportedPlatformError())?;
-		let download_url = format!(
-			"{}/api/latest/{}/{}",
-			update_endpoint,
-			download_segment,
-			quality_download_segment(quality),
-		);
+		self.with_compat_fallback(platform, target, |p| {
+			self.lookup_latest_commit(p, target, quality)
+		})
+		.await
+	}
 
-		let mut response = spanf!(
-			self.log,
-			self.log.span("server.version.resolve"),
-			self.client.make_request("GET", download_url)
-		)?;
+	/// Looks up a release for the platform. If there are no builds for it, and
+	/// it can run another platform's binaries through a compatibility layer,
+	/// like Linux binaries on FreeBSD, looks up the release for that platform.
+	/// The CLI isn't replaced this way, since the native build is running.
+	async fn with_compat_fallback<F, Fut>(
+		&self,
+		platform: Platform,
+		target: TargetKind,
+		lookup: F,
+	) -> Result<Release, AnyError>
+	where
+		F: Fn(Platform) -> Fut,
+		Fut: std::future::Future<Output = Result<Release, AnyError>>,
+	{
+		let compat = match platform.compat_fallback() {
+			Some(c) if target != TargetKind::Cli => c,
+			_ => return lookup(platform).await,
+		};
 
-		if !response.status_code.is_success() {
-			return Err(response.into_err().await.into());
+		match lookup(platform).await {
+			Err(AnyError::StatusError(e)) if e.status_code == 404 => {}
+			Err(AnyError::NoBuildsForPlatformError(_)) => {}
+			Err(AnyError::UnsupportedPlatformError(_)) => {}
+			r => return r,
 		}
 
-		let res = response.json::<UpdateServerVersion>().await?;
+		warning!(
+			self.log,
+			"No builds were found for {}, using {} builds instead. These run under Linux binary compatibility, which must be enabled: `sysrc linux_enable=YES && service linux start`",
+			platform,
+			compat
+		);
+		lookup(compat).await
+	}
+
+	async fn lookup_latest_commit(
+		&self,
+		platform: Platform,
+		target: TargetKind,
+		quality: options::Quality,
+	) -> Result<Release, AnyError> {
+		let (update_endpoint, layout) = update_endpoint()?;
+		let download_segment = target
+			.download_segment(platform)
+			.ok_or(UnsupportedPlatformError())?;
+		let download_url = match layout {
+			UpdateEndpointLayout::Service => format!(
+				"{}/api/latest/{}/{}",
+				update_endpoint,
+				download_segment,
+				quality_download_segment(quality),
+			),
+			UpdateEndpointLayout::Static => format!(
+				"{}/{}/latest/{}.json",
+				update_endpoint,
+				quality_download_segment(quality),
+				download_segment,
+			),
+		};
+
+		// the latest build always exists, unless none are published for the platform
+		let res = match self.get_version_metadata(download_url).await {
+			Err(AnyError::StatusError(e)) if e.status_code == 404 => {
+				return Err(NoBuildsForPlatformError(download_segment).into())
+			}
+			r => r?,
+		};
 		debug!(self.log, "Resolved quality {} to {}", quality, res.version);
 
 		Ok(Release {
@@ -140,33 +323,1343 @@ impl UpdateService {
 			quality,
 			name: res.name,
 			commit: res.version,
+			sha256: res.sha256hash,
 		})
 	}
 
-	/// Gets the download stream for the release.
-	pub async fn get_download_stream(&self, release: &Release) -> Result<SimpleResponse, AnyError> {
-		let update_endpoint =
-			VSCODE_CLI_UPDATE_ENDPOINT.ok_or_else(UpdatesNotConfigured::no_url)?;
+	/// Gets the versions released for the quality, newest first.
+	pub async fn get_versions(&self, quality: options::Quality) -> Result<Vec<String>, AnyError> {
+		let (update_endpoint, layout) = update_endpoint()?;
+		let url = match layout {
+			UpdateEndpointLayout::Service => format!(
+				"{}/api/releases/{}",
+				update_endpoint,
+				quality_download_segment(quality),
+			),
+			UpdateEndpointLayout::Static => format!(
+				"{}/{}/releases.json",
+				update_endpoint,
+				quality_download_segment(quality),
+			),
+		};
+
+		let response = spanf!(
+			self.log,
+			self.log.span("server.version.list"),
+			self.request("GET", url, HeaderMap::new())
+		)?;
+		if !response.status_code.is_success() {
+			return Err(response.into_err().await.into());
+		}
+
+		Ok(response.json::<Vec<String>>().await?)
+	}
+
+	/// Gets the experiments the update service turns on or off for the
+	/// quality. Endpoints that don't publish any, like most mirrors, give none.
+	pub async fn get_experiments(
+		&self,
+		quality: options::Quality,
+	) -> Result<HashMap<String, bool>, AnyError> {
+		let (update_endpoint, layout) = update_endpoint()?;
+		let url = match layout {
+			UpdateEndpointLayout::Service => format!(
+				"{}/api/experiments/{}",
+				update_endpoint,
+				quality_download_segment(quality),
+			),
+			UpdateEndpointLayout::Static => format!(
+				"{}/{}/experiments.json",
+				update_endpoint,
+				quality_download_segment(quality),
+			),
+		};
+
+		if self.cache.as_ref().map(|c| c.no_update_check) == Some(true) {
+			return Err(UpdateCheckDisabledError(url).into());
+		}
+
+		let response = spanf!(
+			self.log,
+			self.log.span("experiments.fetch"),
+			self.request("GET", url, HeaderMap::new())
+		)?;
+		if response.status_code == StatusCode::NOT_FOUND {
+			return Ok(HashMap::new());
+		}
+		if !response.status_code.is_success() {
+			return Err(response.into_err().await.into());
+		}
+
+		Ok(response.json::<HashMap<String, bool>>().await?)
+	}
+
+	/// Makes a request to the update service, retrying transient failures.
+	async fn request(
+		&self,
+		method: &'static str,
+		url: String,
+		headers: HeaderMap,
+	) -> Result<SimpleResponse, AnyError> {
+		let policy = RetryPolicy {
+			attempts: UPDATE_RETRY_ATTEMPTS.load(Ordering::SeqCst),
+			..Default::default()
+		};
+		make_request_with_retry(&*self.client, &self.log, &policy, method, url, headers).await
+	}
+
+	/// Requests version metadata from the URL, going through the cache if
+	/// one is configured.
+	async fn get_version_metadata(&self, url: String) -> Result<UpdateServerVersion, AnyError> {
+		let cached = self.cache.as_ref().and_then(|c| c.get(&url));
+		let no_update_check = self.cache.as_ref().map(|c| c.no_update_check) == Some(true);
+		if let Some(c) = &cached {
+			if no_update_check
+				|| Utc::now() - c.fetched_at < Duration::minutes(METADATA_CACHE_TTL_MINUTES)
+			{
+				trace!(self.log, "Using cached metadata for {}", url);
+				return Ok(c.version.clone());
+			}
+		}
+		if no_update_check {
+			return Err(UpdateCheckDisabledError(url).into());
+		}
+
+		let previous_etag = cached.as_ref().and_then(|c| c.etag.clone());
+		let previous_last_modified = cached.as_ref().and_then(|c| c.last_modified.clone());
+		let mut headers = HeaderMap::new();
+		if let Some(v) = previous_etag
+			.as_deref()
+			.and_then(|e| HeaderValue::from_str(e).ok())
+		{
+			headers.insert(IF_NONE_MATCH, v);
+		}
+		if let Some(v) = previous_last_modified
+			.as_deref()
+			.and_then(|e| HeaderValue::from_str(e).ok())
+		{
+			headers.insert(IF_MODIFIED_SINCE, v);
+		}
+
+		let mut response = spanf!(
+			self.log,
+			self.log.span("server.version.resolve"),
+			self.request("GET", url.clone(), headers)
+		)?;
+
+		let not_modified = response.status_code == StatusCode::NOT_MODIFIED;
+		let version = match cached {
+			Some(c) if not_modified => {
+				trace!(self.log, "Cached metadata for {} is still current", url);
+				c.version
+			}
+			_ if !response.status_code.is_success() => {
+				return Err(response.into_err().await.into());
+			}
+			_ => response.json::<UpdateServerVersion>().await?,
+		};
+
+		if let Some(cache) = &self.cache {
+			let entry = CachedMetadata {
+				etag: response
+					.headers
+					.get(ETAG)
+					.and_then(|h| h.to_str().ok())
+					.map(|s| s.to_owned())
+					.or_else(|| previous_etag.filter(|_| not_modified)),
+				last_modified: response
+					.headers
+					.get(LAST_MODIFIED)
+					.and_then(|h| h.to_str().ok())
+					.map(|s| s.to_owned())
+					.or_else(|| previous_last_modified.filter(|_| not_modified)),
+				fetched_at: Utc::now(),
+				version: version.clone(),
+			};
+
+			if let Err(e) = cache.set(url, entry) {
+				warning!(self.log, "Error saving update metadata to cache: {}", e);
+			}
+		}
+
+		Ok(version)
+	}
+
+	/// Gets the URL the release can be downloaded from.
+	pub fn get_download_url(&self, release: &Release) -> Result<String, AnyError> {
+		let (update_endpoint, layout) = update_endpoint()?;
 		let download_segment = release
 			.target
 			.download_segment(release.platform)
 			.ok_or(UnsupportedPlatformError())?;
 
-		let download_url = format!(
-			"{}/commit:{}/{}/{}",
-			update_endpoint,
-			release.commit,
-			download_segment,
-			quality_download_segment(release.quality),
-		);
+		Ok(match layout {
+			UpdateEndpointLayout::Service => format!(
+				"{}/commit:{}/{}/{}",
+				update_endpoint,
+				release.commit,
+				download_segment,
+				quality_download_segment(release.quality),
+			),
+			UpdateEndpointLayout::Static => format!(
+				"{}/{}/{}/{}",
+				update_endpoint,
+				quality_download_segment(release.quality),
+				release.commit,
+				download_segment,
+			),
+		})
+	}
+
+	/// Gets the URL of the delta that updates an install of the commit to the
+	/// release, if the update service publishes one.
+	pub fn get_delta_url(&self, release: &Release, from_commit: &str) -> Result<String, AnyError> {
+		let (update_endpoint, layout) = update_endpoint()?;
+		let download_segment = release
+			.target
+			.download_segment(release.platform)
+			.ok_or(UnsupportedPlatformError())?;
+
+		Ok(match layout {
+			UpdateEndpointLayout::Service => format!(
+				"{}/delta/{}/commit:{}/{}/{}",
+				update_endpoint,
+				from_commit,
+				release.commit,
+				download_segment,
+				quality_download_segment(release.quality),
+			),
+			UpdateEndpointLayout::Static => format!(
+				"{}/{}/{}/{}.delta-from-{}",
+				update_endpoint,
+				quality_download_segment(release.quality),
+				release.commit,
+				download_segment,
+				from_commit,
+			),
+		})
+	}
+
+	/// Downloads the delta that updates an install of the commit to the
+	/// release into the file, which is much smaller than the release when few
+	/// files changed. Returns false if no delta is published between them.
+	/// Interrupted downloads are resumed, and the checksum and signature are
+	/// checked, like for `download_release`.
+	pub async fn download_delta(
+		&self,
+		release: &Release,
+		from_commit: &str,
+		target: &Path,
+		mut progress: impl ReportProgress,
+	) -> Result<bool, AnyError> {
+		let url = self.get_delta_url(release, from_commit)?;
+		let mut attempt = 1;
+		let actual = loop {
+			match self.download_attempt(&url, target, &mut progress).await {
+				Ok(digest) => break digest,
+				Err(DownloadAttemptError::Failed(AnyError::StatusError(e)))
+					if e.status_code == 404 =>
+				{
+					tokio::fs::remove_file(target).await.ok();
+					return Ok(false);
+				}
+				Err(DownloadAttemptError::Interrupted(e)) if attempt < MAX_DOWNLOAD_ATTEMPTS => {
+					warning!(
+						self.log,
+						"Download was interrupted, resuming ({}/{}): {}",
+						attempt,
+						MAX_DOWNLOAD_ATTEMPTS - 1,
+						e
+					);
+					tokio::time::sleep(DOWNLOAD_RETRY_DELAY * attempt).await;
+					attempt += 1;
+				}
+				Err(DownloadAttemptError::Interrupted(e) | DownloadAttemptError::Failed(e)) => {
+					return Err(e)
+				}
+			}
+		};
+
+		let expected = self.get_sidecar_sha256(&url).await;
+		if expected.is_none() {
+			warning!(
+				self.log,
+				"No checksum is available for the delta to {}, its download won't be verified",
+				release
+			);
+		}
+		check_sha256(&url, target, expected, &actual).await?;
+		self.check_signature(&url, target, &actual).await?;
+		Ok(true)
+	}
 
-		let response = self.client.make_request("GET", download_url).await?;
+	/// Gets the size of the release's download in bytes, without downloading
+	/// it, if the server reports one.
+	pub async fn get_download_size(&self, release: &Release) -> Result<Option<u64>, AnyError> {
+		let download_url = self.get_download_url(release)?;
+		let response = self.request("HEAD", download_url, HeaderMap::new()).await?;
+		if !response.status_code.is_success() {
+			return Err(response.into_err().await.into());
+		}
+
+		Ok(response
+			.headers
+			.get(CONTENT_LENGTH)
+			.and_then(|h| h.to_str().ok())
+			.and_then(|s| s.parse().ok()))
+	}
+
+	/// Gets the download stream for the release.
+	pub async fn get_download_stream(&self, release: &Release) -> Result<SimpleResponse, AnyError> {
+		let download_url = self.get_download_url(release)?;
+		let response = self.request("GET", download_url, HeaderMap::new()).await?;
 		if !response.status_code.is_success() {
 			return Err(response.into_err().await.into());
 		}
 
 		Ok(response)
 	}
+
+	/// Gets the expected SHA-256 digest of the release's download, from the
+	/// release itself or else from a `.sha256` file next to the download.
+	pub async fn get_expected_sha256(&self, release: &Release) -> Option<String> {
+		if let Some(h) = &release.sha256 {
+			return Some(h.clone());
+		}
+
+		self.get_sidecar_sha256(&self.get_download_url(release).ok()?)
+			.await
+	}
+
+	/// Gets the detached signature published next to the release's download,
+	/// if there is one.
+	pub async fn get_download_signature(&self, release: &Release) -> Option<String> {
+		self.get_signature(&self.get_download_url(release).ok()?)
+			.await
+	}
+
+	/// Gets the SHA-256 digest of the download at the URL from a `.sha256`
+	/// file next to it, if there is one.
+	async fn get_sidecar_sha256(&self, url: &str) -> Option<String> {
+		let url = format!("{}.sha256", url);
+		let mut response = match self.request("GET", url.clone(), HeaderMap::new()).await {
+			Ok(r) if r.status_code.is_success() => r,
+			Ok(r) => {
+				trace!(self.log, "No checksum at {}: {}", url, r.status_code);
+				return None;
+			}
+			Err(e) => {
+				trace!(self.log, "Error getting checksum from {}: {}", url, e);
+				return None;
+			}
+		};
+
+		let mut body = String::new();
+		response.read.read_to_string(&mut body).await.ok()?;
+
+		// sidecar files may be in `sha256sum` format, "<digest>  <file name>"
+		body.split_whitespace()
+			.next()
+			.filter(|h| h.len() == 64 && h.chars().all(|c| c.is_ascii_hexdigit()))
+			.map(|h| h.to_lowercase())
+	}
+
+	/// Gets the detached signature of the release's download, from a `.sig`
+	/// file next to it.
+	async fn get_signature(&self, url: &str) -> Option<String> {
+		let url = format!("{}.sig", url);
+		let mut response = match self.request("GET", url.clone(), HeaderMap::new()).await {
+			Ok(r) if r.status_code.is_success() => r,
+			Ok(r) => {
+				trace!(self.log, "No signature at {}: {}", url, r.status_code);
+				return None;
+			}
+			Err(e) => {
+				trace!(self.log, "Error getting signature from {}: {}", url, e);
+				return None;
+			}
+		};
+
+		let mut body = String::new();
+		response.read.read_to_string(&mut body).await.ok()?;
+		Some(body)
+	}
+
+	/// Checks the signature of the downloaded file, if the CLI was built with
+	/// a signing key, removing it if the signature doesn't match. Unsigned
+	/// downloads are only rejected if signatures are required, see
+	/// `set_require_signed`.
+	async fn check_signature(
+		&self,
+		url: &str,
+		target: &Path,
+		sha256: &str,
+	) -> Result<(), AnyError> {
+		let required = REQUIRE_SIGNED.load(Ordering::SeqCst);
+		let fail = |reason: String| -> AnyError {
+			SignatureVerificationError {
+				url: url.to_string(),
+				reason,
+			}
+			.into()
+		};
+
+		let key = match VSCODE_CLI_SIGNING_KEY {
+			Some(k) => k,
+			None if required => {
+				return Err(fail("no signing key is built into this CLI".to_string()))
+			}
+			None => return Ok(()),
+		};
+
+		let signature = match self.get_signature(url).await {
+			Some(s) => s,
+			None if required => {
+				tokio::fs::remove_file(target).await.ok();
+				return Err(fail("no signature was published for it".to_string()));
+			}
+			None => {
+				warning!(
+					self.log,
+					"No signature is available for {}, its download won't be verified",
+					url
+				);
+				return Ok(());
+			}
+		};
+
+		if let Err(reason) = verify_digest_signature(key, &signature, sha256) {
+			tokio::fs::remove_file(target).await.ok();
+			return Err(fail(reason));
+		}
+
+		debug!(self.log, "Verified the signature of {}", url);
+		Ok(())
+	}
+
+	/// Downloads the release into the file. Large downloads are split across
+	/// several connections, see `set_download_connections`. Otherwise, if the
+	/// file holds part of the download from an earlier attempt, the rest is requested with a `Range`
+	/// header and appended; interrupted downloads are resumed the same way, a
+	/// few times. The file is kept if the download still fails, so it can be
+	/// resumed later. Once complete, its size is checked, and if the expected
+	/// SHA-256 digest is known, a ChecksumMismatchError is returned if it
+	/// doesn't match, before anything is extracted from it. Its signature is
+	/// then checked the same way, see `check_signature`.
+	pub async fn download_release(
+		&self,
+		release: &Release,
+		target: &Path,
+		mut progress: impl ReportProgress,
+	) -> Result<(), AnyError> {
+		let expected = self.get_expected_sha256(release).await;
+		if expected.is_none() {
+			warning!(
+				self.log,
+				"No checksum is available for {}, its download won't be verified",
+				release
+			);
+		}
+
+		let url = self.get_download_url(release)?;
+		if let Some(actual) = self
+			.try_download_parallel(&url, target, &mut progress)
+			.await
+		{
+			check_sha256(&url, target, expected, &actual).await?;
+			return self.check_signature(&url, target, &actual).await;
+		}
+
+		trim_to_downloaded(target).await?;
+		let mut attempt = 1;
+		let actual = loop {
+			match self
+				.download_attempt(&url, target, expected.as_deref(), &mut progress)
+				.await
+			{
+				Ok(digest) => break digest,
+				Err(DownloadAttemptError::Interrupted(e)) if attempt < MAX_DOWNLOAD_ATTEMPTS => {
+					warning!(
+						self.log,
+						"Download was interrupted, resuming ({}/{}): {}",
+						attempt,
+						MAX_DOWNLOAD_ATTEMPTS - 1,
+						e
+					);
+					tokio::time::sleep(DOWNLOAD_RETRY_DELAY * attempt).await;
+					attempt += 1;
+				}
+				Err(DownloadAttemptError::Interrupted(e) | DownloadAttemptError::Failed(e)) => {
+					return Err(e)
+				}
+			}
+		};
+
+		check_sha256(&url, target, expected, &actual).await?;
+		self.check_signature(&url, target, &actual).await
+	}
+
+	/// Downloads the release and extracts it into the directory as it's
+	/// downloaded, rather than writing the archive to disk first, so it takes
+	/// half the disk space and the two overlap. Returns false, leaving the
+	/// directory empty, if it can't be installed this way, in which case
+	/// `download_release` should be used instead. That's the case for zip
+	/// archives, whose index is at their end, and for downloads that fail or
+	/// are interrupted, since only downloads to a file can be resumed. It's
+	/// extracted into a staging directory and only moved into place once its
+	/// checksum and signature have been checked, so nothing is installed from
+	/// a download that fails them.
+	pub async fn download_and_extract_release(
+		&self,
+		release: &Release,
+		target_dir: &Path,
+		mut progress: impl ReportProgress,
+	) -> Result<bool, AnyError> {
+		let url = self.get_download_url(release)?;
+		let mut response = match self.request("GET", url.clone(), HeaderMap::new()).await {
+			Ok(r) if r.status_code.is_success() => r,
+			_ => return Ok(false),
+		};
+
+		// the format's needed to pick a decoder, so peek at the first bytes
+		let mut header = [0; archive::MAGIC_LEN];
+		let mut header_len = 0;
+		while header_len < header.len() {
+			match response.read.read(&mut header[header_len..]).await {
+				Ok(0) => break,
+				Ok(n) => header_len += n,
+				Err(_) => return Ok(false),
+			}
+		}
+		let compression = match ArchiveFormat::from_magic(&header[..header_len]) {
+			Some(ArchiveFormat::Tar(c)) => c,
+			_ => return Ok(false),
+		};
+
+		let total = response
+			.headers
+			.get(CONTENT_LENGTH)
+			.and_then(|h| h.to_str().ok())
+			.and_then(|s| s.parse::<u64>().ok());
+		debug!(self.log, "Extracting {} while downloading it", url);
+
+		let staging = target_dir.join(STAGING_DIR_NAME);
+		empty_dir(&staging).await?;
+
+		let (reader, writer) = tokio::io::duplex(STREAMING_BUFFER_SIZE);
+		let reader = SyncIoBridge::new(reader);
+		let dir = staging.clone();
+		let extraction = tokio::task::spawn_blocking(move || {
+			tar::decompress_tar_stream(reader, &dir, compression)
+		});
+
+		let mut writer = Sha256Writer::new(writer);
+		let copied = match writer.write_all(&header[..header_len]).await {
+			Ok(()) => {
+				copy_async_progress(
+					ResumedProgress {
+						inner: &mut progress,
+						offset: header_len as u64,
+					},
+					&mut response.read,
+					&mut writer,
+					total
+						.map(|t| t.saturating_sub(header_len as u64))
+						.unwrap_or(0),
+				)
+				.await
+			}
+			Err(e) => Err(e),
+		};
+		// closing the pipe lets extraction finish
+		let (_, actual) = writer.finish();
+
+		let extracted = extraction
+			.await
+			.map_err(|e| wrap(e, "extraction panicked"))
+			.and_then(|r| r);
+		// extraction errors come first, since they also cut the download short
+		let failure = match (copied, extracted) {
+			(_, Err(e)) => Some(e.to_string()),
+			(Err(e), _) => Some(e.to_string()),
+			(Ok(c), Ok(())) => match (c + header_len as u64, total) {
+				(size, Some(t)) if size != t => {
+					Some(format!("download was {} bytes, but expected {}", size, t))
+				}
+				_ => None,
+			},
+		};
+		if let Some(reason) = failure {
+			warning!(
+				self.log,
+				"Error extracting the download as it arrived, downloading it first instead: {}",
+				reason
+			);
+			empty_dir(target_dir).await?;
+			return Ok(false);
+		}
+
+		let expected = self.get_expected_sha256(release).await;
+		if expected.is_none() {
+			warning!(
+				self.log,
+				"No checksum is available for {}, its download won't be verified",
+				release
+			);
+		}
+
+		let verified = match check_sha256(&url, &staging, expected, &actual).await {
+			Ok(()) => self.check_signature(&url, &staging, &actual).await,
+			Err(e) => Err(e),
+		};
+		if let Err(e) = verified {
+			empty_dir(target_dir).await?;
+			return Err(e);
+		}
+
+		move_dir_contents(&staging, target_dir).await?;
+		Ok(true)
+	}
+
+	/// Downloads the file in several ranges at once, if parallel downloads
+	/// are enabled, the server supports them, and the file is large enough to
+	/// benefit. The ranges downloaded so far are tracked in a file next to it,
+	/// see `DownloadRanges`, so an interrupted download can be resumed.
+	/// Returns the SHA-256 digest of the file, or None if it wasn't
+	/// downloaded this way, in which case there's no partial file left but
+	/// one from an earlier attempt that wasn't split across connections.
+	async fn try_download_parallel(
+		&self,
+		url: &str,
+		target: &Path,
+		progress: &mut impl ReportProgress,
+	) -> Option<String> {
+		let connections = DOWNLOAD_CONNECTIONS.load(Ordering::SeqCst);
+		if connections < 2 {
+			return None;
+		}
+
+		// a partial file from an earlier attempt is resumed from its ranges,
+		// or from its end if it wasn't split across connections
+		let ranges = PersistedState::<DownloadRanges>::new(DownloadRanges::path_for(target));
+		let resume = match (target.exists(), ranges.path().exists()) {
+			(true, true) => Some(ranges.load()),
+			(true, false) => return None,
+			(false, _) => None,
+		};
+
+		let head = self
+			.request("HEAD", url.to_string(), HeaderMap::new())
+			.await
+			.ok()?;
+		let accepts_ranges = head
+			.headers
+			.get(ACCEPT_RANGES)
+			.and_then(|h| h.to_str().ok())
+			.map(|h| h.eq_ignore_ascii_case("bytes"))
+			.unwrap_or(false);
+		let size = head
+			.headers
+			.get(CONTENT_LENGTH)
+			.and_then(|h| h.to_str().ok())
+			.and_then(|s| s.parse::<u64>().ok())?;
+		if !head.status_code.is_success() || !accepts_ranges || size < MIN_PARALLEL_DOWNLOAD_SIZE {
+			return None;
+		}
+
+		// ranges of another download, such as of a different release
+		let resume = resume.filter(|r| r.size == size);
+		debug!(
+			self.log,
+			"Downloading {} bytes over {} connections", size, connections
+		);
+		match self
+			.download_parallel(
+				url,
+				target,
+				&ranges,
+				resume,
+				connections as u64,
+				size,
+				progress,
+			)
+			.await
+		{
+			Ok(digest) => Some(digest),
+			Err(e) => {
+				warning!(
+					self.log,
+					"Error downloading over several connections, retrying over one: {}",
+					e
+				);
+				tokio::fs::remove_file(target).await.ok();
+				tokio::fs::remove_file(ranges.path()).await.ok();
+				None
+			}
+		}
+	}
+
+	#[allow(clippy::too_many_arguments)]
+	async fn download_parallel(
+		&self,
+		url: &str,
+		target: &Path,
+		ranges: &PersistedState<DownloadRanges>,
+		resume: Option<DownloadRanges>,
+		connections: u64,
+		size: u64,
+		progress: &mut impl ReportProgress,
+	) -> Result<String, AnyError> {
+		let state = match resume {
+			Some(r) => {
+				debug!(
+					self.log,
+					"Resuming {} bytes already downloaded",
+					r.downloaded()
+				);
+				r
+			}
+			None => {
+				// saved before the file's allocated, so it's never mistaken
+				// for a complete download
+				let r = DownloadRanges::new(size, connections);
+				ranges.save(r.clone())?;
+				let file = tokio::fs::File::create(target)
+					.await
+					.map_err(|e| wrap(e, "failed to create download file"))?;
+				file.set_len(size)
+					.await
+					.map_err(|e| wrap(e, "failed to allocate download file"))?;
+				r
+			}
+		};
+
+		let offsets: Vec<AtomicU64> = state.chunks.iter().map(|c| AtomicU64::new(c[1])).collect();
+		let chunks = futures::future::try_join_all(
+			state
+				.chunks
+				.iter()
+				.zip(&offsets)
+				.map(|(c, offset)| self.download_chunk(url, target, offset, c[2])),
+		);
+		tokio::pin!(chunks);
+
+		let mut report_interval = tokio::time::interval(std::time::Duration::from_millis(250));
+		loop {
+			tokio::select! {
+				r = &mut chunks => {
+					r?;
+					break;
+				}
+				_ = report_interval.tick() => {
+					let current = state.with_offsets(&offsets);
+					progress.report_progress(current.downloaded(), size);
+					ranges.save(current).ok();
+				}
+			}
+		}
+		progress.report_progress(size, size);
+
+		let file = tokio::fs::File::open(target)
+			.await
+			.map_err(|e| wrap(e, "failed to open download file"))?;
+		let mut writer = Sha256Writer::new(file);
+		hash_existing(&mut writer)
+			.await
+			.map_err(|e| wrap(e, "failed to read download file"))?;
+		tokio::fs::remove_file(ranges.path()).await.ok();
+		Ok(writer.finish().1)
+	}
+
+	/// Downloads the bytes from `offset` up to `end` into the same range of
+	/// the file, resuming the request a few times if it's interrupted. The
+	/// offset is advanced as bytes are written to the file.
+	async fn download_chunk(
+		&self,
+		url: &str,
+		target: &Path,
+		progress: &AtomicU64,
+		end: u64,
+	) -> Result<(), AnyError> {
+		let mut file = tokio::fs::OpenOptions::new()
+			.write(true)
+			.open(target)
+			.await
+			.map_err(|e| wrap(e, "failed to open download file"))?;
+
+		let mut offset = progress.load(Ordering::Relaxed);
+		let mut attempt = 1;
+		let mut buf = vec![0; 64 * 1024];
+		while offset < end {
+			let mut headers = HeaderMap::new();
+			headers.insert(
+				RANGE,
+				HeaderValue::from_str(&format!("bytes={}-{}", offset, end - 1)).unwrap(),
+			);
+
+			let result: Result<(), AnyError> = async {
+				let mut response = self.request("GET", url.to_string(), headers).await?;
+				if response.status_code != StatusCode::PARTIAL_CONTENT {
+					return Err(wrap(
+						"",
+						format!("server didn't return a range ({})", response.status_code),
+					)
+					.into());
+				}
+
+				file.seek(std::io::SeekFrom::Start(offset))
+					.await
+					.map_err(|e| wrap(e, "failed to seek in download file"))?;
+				loop {
+					let n = response
+						.read
+						.read(&mut buf)
+						.await
+						.map_err(|e| wrap(e, "failed to download range"))?;
+					if n == 0 {
+						break;
+					}
+					let n = std::cmp::min(n as u64, end - offset) as usize;
+					// flushed before it's counted, so only written bytes are
+					// recorded as downloaded
+					file.write_all(&buf[..n])
+						.await
+						.map_err(|e| wrap(e, "failed to write download file"))?;
+					file.flush()
+						.await
+						.map_err(|e| wrap(e, "failed to write download file"))?;
+					offset += n as u64;
+					progress.store(offset, Ordering::Relaxed);
+					if offset == end {
+						break;
+					}
+				}
+
+				Ok(())
+			}
+			.await;
+
+			match result {
+				Ok(()) if offset == end => break,
+				Ok(()) if attempt < MAX_DOWNLOAD_ATTEMPTS => {}
+				Err(e) if attempt < MAX_DOWNLOAD_ATTEMPTS => {
+					debug!(self.log, "Resuming download of range at {}: {}", offset, e);
+				}
+				Ok(()) => {
+					return Err(wrap("", format!("download of range ended at {}", offset)).into())
+				}
+				Err(e) => return Err(e),
+			}
+
+			tokio::time::sleep(DOWNLOAD_RETRY_DELAY * attempt).await;
+			attempt += 1;
+		}
+
+		file.flush()
+			.await
+			.map_err(|e| wrap(e, "failed to write download file"))?;
+		Ok(())
+	}
+
+	/// Downloads the rest of the file, returning the SHA-256 digest of the
+	/// whole file once it's complete. A file the server says is complete
+	/// already is only kept if it has the expected digest.
+	async fn download_attempt(
+		&self,
+		url: &str,
+		target: &Path,
+		expected: Option<&str>,
+		progress: &mut impl ReportProgress,
+	) -> Result<String, DownloadAttemptError> {
+		use DownloadAttemptError::{Failed, Interrupted};
+
+		let existing = tokio::fs::metadata(target)
+			.await
+			.map(|m| m.len())
+			.unwrap_or(0);
+		let mut headers = HeaderMap::new();
+		if existing > 0 {
+			headers.insert(
+				RANGE,
+				HeaderValue::from_str(&format!("bytes={}-", existing)).unwrap(),
+			);
+		}
+
+		let mut response = self
+			.request("GET", url.to_string(), headers)
+			.await
+			.map_err(Interrupted)?;
+
+		let content_length = response
+			.headers
+			.get(CONTENT_LENGTH)
+			.and_then(|h| h.to_str().ok())
+			.and_then(|s| s.parse::<u64>().ok());
+		let range_total = response
+			.headers
+			.get(CONTENT_RANGE)
+			.and_then(|h| h.to_str().ok())
+			.and_then(|s| s.rsplit_once('/'))
+			.and_then(|(_, t)| t.parse::<u64>().ok());
+
+		// the body is only appended for successful responses
+		let (offset, total, append) = match response.status_code {
+			StatusCode::PARTIAL_CONTENT if existing > 0 => {
+				debug!(self.log, "Resuming download from byte {}", existing);
+				(
+					existing,
+					range_total.or(content_length.map(|l| l + existing)),
+					true,
+				)
+			}
+			// the file is complete already, or isn't part of this download
+			StatusCode::RANGE_NOT_SATISFIABLE if existing > 0 => {
+				if range_total == Some(existing) {
+					(existing, Some(existing), false)
+				} else {
+					tokio::fs::remove_file(target).await.ok();
+					return Err(Interrupted(
+						wrap("", "partial download didn't match the file, restarting").into(),
+					));
+				}
+			}
+			s if s.is_success() => (0, content_length, true),
+			_ => return Err(Failed(response.into_err().await.into())),
+		};
+
+		let file = tokio::fs::OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.open(target)
+			.await
+			.map_err(|e| Failed(wrap(e, "failed to open download file").into()))?;
+
+		// hash what's already downloaded, which leaves the file at its end
+		let mut writer = Sha256Writer::new(file);
+		if offset == 0 {
+			writer
+				.get_mut()
+				.set_len(0)
+				.await
+				.map_err(|e| Failed(wrap(e, "failed to truncate download file").into()))?;
+		} else {
+			hash_existing(&mut writer)
+				.await
+				.map_err(|e| Failed(wrap(e, "failed to read download file").into()))?;
+		}
+
+		let written = if append {
+			copy_async_progress(
+				ResumedProgress {
+					inner: progress,
+					offset,
+				},
+				&mut response.read,
+				&mut writer,
+				total.map(|t| t.saturating_sub(offset)).unwrap_or(0),
+			)
+			.await
+			.map_err(|e| Interrupted(wrap_read(e, "failed to download file")))?
+		} else {
+			0
+		};
+		writer
+			.flush()
+			.await
+			.map_err(|e| Failed(wrap(e, "failed to write download file").into()))?;
+
+		let size = offset + written;
+		match total {
+			Some(t) if size < t => Err(Interrupted(
+				wrap("", format!("download ended after {} of {} bytes", size, t)).into(),
+			)),
+			Some(t) if size > t => {
+				tokio::fs::remove_file(target).await.ok();
+				Err(Failed(
+					wrap(
+						"",
+						format!("download was {} bytes, but expected {}", size, t),
+					)
+					.into(),
+				))
+			}
+			_ if append => Ok(writer.finish().1),
+			_ => {
+				let actual = writer.finish().1;
+				match expected {
+					Some(e) if actual.eq_ignore_ascii_case(e.trim()) => Ok(actual),
+					_ => {
+						tokio::fs::remove_file(target).await.ok();
+						Err(Interrupted(
+							wrap("", "downloaded file couldn't be verified, restarting").into(),
+						))
+					}
+				}
+			}
+		}
+	}
+}
+
+/// Progress of a download split across connections, kept in a file next to
+/// the download until it's complete. The download is allocated at its full
+/// size up front, so its size says nothing about how much was downloaded.
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct DownloadRanges {
+	size: u64,
+	/// The start, the end of what's downloaded so far, and the end of each
+	/// connection's range.
+	chunks: Vec<[u64; 3]>,
+}
+
+impl DownloadRanges {
+	fn new(size: u64, connections: u64) -> Self {
+		let chunk_size = (size + connections - 1) / connections;
+		let chunks = (0..connections)
+			.map(|i| {
+				let start = std::cmp::min(i * chunk_size, size);
+				[start, start, std::cmp::min(start + chunk_size, size)]
+			})
+			.collect();
+
+		DownloadRanges { size, chunks }
+	}
+
+	fn path_for(target: &Path) -> PathBuf {
+		let mut name = target.file_name().unwrap_or_default().to_owned();
+		name.push(".ranges");
+		target.with_file_name(name)
+	}
+
+	fn with_offsets(&self, offsets: &[AtomicU64]) -> Self {
+		let chunks = self
+			.chunks
+			.iter()
+			.zip(offsets)
+			.map(|(c, o)| [c[0], o.load(Ordering::Relaxed), c[2]])
+			.collect();
+
+		DownloadRanges {
+			size: self.size,
+			chunks,
+		}
+	}
+
+	fn downloaded(&self) -> u64 {
+		self.chunks.iter().map(|c| c[1] - c[0]).sum()
+	}
+
+	/// Gets how much of the start of the file is downloaded, without gaps.
+	fn contiguous(&self) -> u64 {
+		let mut end = 0;
+		for c in &self.chunks {
+			if c[0] != end {
+				break;
+			}
+			end = c[1];
+			if c[1] < c[2] {
+				break;
+			}
+		}
+
+		end
+	}
+}
+
+/// Truncates a download that was split across connections to the part at its
+/// start that's downloaded, so it can be resumed from its end.
+async fn trim_to_downloaded(target: &Path) -> Result<(), AnyError> {
+	let ranges = PersistedState::<DownloadRanges>::new(DownloadRanges::path_for(target));
+	if !ranges.path().exists() {
+		return Ok(());
+	}
+
+	// unreadable ranges, like from a write that was cut off, count as none
+	let downloaded = ranges.load().contiguous();
+	if let Ok(file) = tokio::fs::OpenOptions::new().write(true).open(target).await {
+		file.set_len(downloaded)
+			.await
+			.map_err(|e| wrap(e, "failed to truncate download file"))?;
+	}
+	tokio::fs::remove_file(ranges.path())
+		.await
+		.map_err(|e| wrap(e, "failed to remove download ranges"))?;
+	Ok(())
+}
+
+/// Checks the downloaded file has the expected SHA-256 digest, if there is
+/// one, removing it if it doesn't.
+async fn check_sha256(
+	url: &str,
+	target: &Path,
+	expected: Option<String>,
+	actual: &str,
+) -> Result<(), AnyError> {
+	if let Some(expected) = expected {
+		if !actual.eq_ignore_ascii_case(expected.trim()) {
+			tokio::fs::remove_file(target).await.ok();
+			return Err(ChecksumMismatchError {
+				url: url.to_string(),
+				expected: expected.trim().to_lowercase(),
+				actual: actual.to_string(),
+			}
+			.into());
+		}
+	}
+
+	Ok(())
+}
+
+/// Removes everything in the directory, leaving it in place. It's created if
+/// it doesn't exist.
+async fn empty_dir(dir: &Path) -> Result<(), AnyError> {
+	match tokio::fs::remove_dir_all(dir).await {
+		Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+			return Err(wrap(e, format!("error cleaning up {}", dir.display())).into())
+		}
+		_ => {}
+	}
+	tokio::fs::create_dir_all(dir)
+		.await
+		.map_err(|e| wrap(e, format!("error creating {}", dir.display())))?;
+	Ok(())
+}
+
+/// Moves everything in the directory into another, then removes it.
+async fn move_dir_contents(from: &Path, to: &Path) -> Result<(), AnyError> {
+	let mut entries = tokio::fs::read_dir(from)
+		.await
+		.map_err(|e| wrap(e, format!("error reading {}", from.display())))?;
+	while let Some(entry) = entries
+		.next_entry()
+		.await
+		.map_err(|e| wrap(e, format!("error reading {}", from.display())))?
+	{
+		let target = to.join(entry.file_name());
+		tokio::fs::rename(entry.path(), &target)
+			.await
+			.map_err(|e| wrap(e, format!("error moving into {}", target.display())))?;
+	}
+
+	tokio::fs::remove_dir(from)
+		.await
+		.map_err(|e| wrap(e, format!("error cleaning up {}", from.display())))?;
+	Ok(())
+}
+
+/// Hashes the rest of the file the writer wraps, leaving it at its end.
+async fn hash_existing(writer: &mut Sha256Writer<tokio::fs::File>) -> std::io::Result<()> {
+	let mut buf = vec![0; 64 * 1024];
+	loop {
+		let n = writer.get_mut().read(&mut buf).await?;
+		if n == 0 {
+			return Ok(());
+		}
+		writer.update(&buf[..n]);
+	}
+}
+
+/// Bytes buffered between the download and extraction when they're done
+/// together.
+const STREAMING_BUFFER_SIZE: usize = 256 * 1024;
+/// Directory within the install that downloads are extracted into as they
+/// arrive, until they've been verified.
+const STAGING_DIR_NAME: &str = ".staging";
+
+static DOWNLOAD_CONNECTIONS: AtomicUsize = AtomicUsize::new(DEFAULT_DOWNLOAD_CONNECTIONS);
+
+/// Number of connections large downloads are split across by default.
+pub const DEFAULT_DOWNLOAD_CONNECTIONS: usize = 4;
+/// Downloads smaller than this aren't split across connections.
+const MIN_PARALLEL_DOWNLOAD_SIZE: u64 = 8 * 1024 * 1024;
+
+static REQUIRE_SIGNED: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether downloads must have a valid signature. Otherwise, they're
+/// only rejected if their signature is invalid.
+pub fn set_require_signed(require: bool) {
+	REQUIRE_SIGNED.store(require, Ordering::SeqCst);
+}
+
+static UPDATE_RETRY_ATTEMPTS: AtomicU32 = AtomicU32::new(DEFAULT_RETRY_ATTEMPTS);
+
+/// Sets how many times requests to the update service are attempted when
+/// they fail transiently. 1 doesn't retry.
+pub fn set_update_retry_attempts(attempts: u32) {
+	UPDATE_RETRY_ATTEMPTS.store(attempts.max(1), Ordering::SeqCst);
+}
+
+/// Sets how many connections large downloads are split across, when the
+/// server supports range requests. 1 downloads over a single connection.
+pub fn set_download_connections(connections: usize) {
+	DOWNLOAD_CONNECTIONS.store(connections.max(1), Ordering::SeqCst);
+}
+
+/// Number of times a download is attempted before giving up.
+const MAX_DOWNLOAD_ATTEMPTS: u32 = 5;
+/// Delay before resuming an interrupted download, multiplied by the attempt.
+const DOWNLOAD_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+
+enum DownloadAttemptError {
+	/// The download was cut short, and can be resumed.
+	Interrupted(AnyError),
+	Failed(AnyError),
+}
+
+/// Reports progress through a resumed download, counting what was
+/// downloaded before it was resumed.
+struct ResumedProgress<'a, T: ReportProgress> {
+	inner: &'a mut T,
+	offset: u64,
+}
+
+impl<'a, T: ReportProgress> ReportProgress for ResumedProgress<'a, T> {
+	fn report_progress(&mut self, done: u64, total: u64) {
+		let total = if total == 0 { 0 } else { total + self.offset };
+		self.inner.report_progress(done + self.offset, total);
+	}
+
+	fn report_indeterminate(&mut self) {
+		self.inner.report_indeterminate();
+	}
+}
+
+/// A directory of releases in the static update endpoint layout, see
+/// `UpdateEndpointLayout::Static`, which any web server can host as a mirror.
+pub struct StaticMirror {
+	root: PathBuf,
+}
+
+impl StaticMirror {
+	pub fn new(root: PathBuf) -> Self {
+		StaticMirror { root }
+	}
+
+	fn quality_dir(&self, quality: options::Quality) -> PathBuf {
+		self.root.join(quality_download_segment(quality))
+	}
+
+	fn segment(release: &Release) -> Result<String, AnyError> {
+		Ok(release
+			.target
+			.download_segment(release.platform)
+			.ok_or(UnsupportedPlatformError())?)
+	}
+
+	/// Gets the path the release's download is kept at.
+	pub fn download_path(&self, release: &Release) -> Result<PathBuf, AnyError> {
+		Ok(self
+			.quality_dir(release.quality)
+			.join(check_dir_name(&release.commit)?)
+			.join(Self::segment(release)?))
+	}
+
+	/// Gets whether the release is already the latest for its platform in the
+	/// mirror, and downloaded.
+	pub fn has_latest(&self, release: &Release) -> Result<bool, AnyError> {
+		let latest = self
+			.quality_dir(release.quality)
+			.join("latest")
+			.join(format!("{}.json", Self::segment(release)?));
+		let latest = match read_json::<UpdateServerVersion>(&latest)? {
+			Some(v) => v,
+			None => return Ok(false),
+		};
+
+		Ok(latest.version == release.commit && self.download_path(release)?.exists())
+	}
+
+	/// Publishes the release, whose download must already be in place, as the
+	/// latest for its platform and the build of its version, along with its
+	/// checksum and signature. The latest version's metadata is written last,
+	/// so clients of the mirror don't see the release before it's complete.
+	pub fn publish(
+		&self,
+		release: &Release,
+		sha256: Option<&str>,
+		signature: Option<&str>,
+	) -> Result<(), AnyError> {
+		let download = self.download_path(release)?;
+		if let Some(sha256) = sha256 {
+			write_atomic(&sidecar(&download, "sha256"), sha256.as_bytes())?;
+		}
+		if let Some(signature) = signature {
+			write_atomic(&sidecar(&download, "sig"), signature.as_bytes())?;
+		}
+
+		let segment = Self::segment(release)?;
+		let quality_dir = self.quality_dir(release.quality);
+		let metadata = UpdateServerVersion {
+			version: release.commit.clone(),
+			name: release.name.clone(),
+			sha256hash: sha256.map(|s| s.to_string()),
+		};
+		write_json(
+			&quality_dir
+				.join("versions")
+				.join(check_dir_name(&release.name)?)
+				.join(format!("{}.json", segment)),
+			&metadata,
+		)?;
+
+		// releases are listed newest first
+		let releases_file = quality_dir.join("releases.json");
+		let mut releases = read_json::<Vec<String>>(&releases_file)?.unwrap_or_default();
+		if !releases.contains(&release.name) {
+			releases.insert(0, release.name.clone());
+			write_json(&releases_file, &releases)?;
+		}
+
+		write_json(
+			&quality_dir.join("latest").join(format!("{}.json", segment)),
+			&metadata,
+		)
+	}
+}
+
+/// Checks that a name from the update service can be used as a directory in
+/// the mirror, without reaching outside of it.
+fn check_dir_name(name: &str) -> Result<&str, AnyError> {
+	if name.is_empty() || name.contains("..") || name.contains(|c| matches!(c, '/' | '\\' | '\0')) {
+		return Err(InvalidReleaseName(name.to_string()).into());
+	}
+
+	Ok(name)
+}
+
+/// Gets the path of the file published next to the download.
+fn sidecar(download: &Path, extension: &str) -> PathBuf {
+	let mut name = download.as_os_str().to_owned();
+	name.push(".");
+	name.push(extension);
+	PathBuf::from(name)
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(file: &Path) -> Result<Option<T>, AnyError> {
+	match std::fs::read(file) {
+		Ok(contents) => serde_json::from_slice(&contents)
+			.map(Some)
+			.map_err(|e| wrap(e, format!("error parsing {}", file.display())).into()),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+		Err(e) => Err(wrap(e, format!("error reading {}", file.display())).into()),
+	}
+}
+
+fn write_json<T: Serialize>(file: &Path, value: &T) -> Result<(), AnyError> {
+	let contents = serde_json::to_vec_pretty(value)
+		.map_err(|e| wrap(e, format!("error serializing {}", file.display())))?;
+	write_atomic(file, &contents)
+}
+
+/// Writes the file through a temporary one, so it's never seen half written.
+fn write_atomic(file: &Path, contents: &[u8]) -> Result<(), AnyError> {
+	if let Some(dir) = file.parent() {
+		std::fs::create_dir_all(dir)
+			.map_err(|e| wrap(e, format!("error creating {}", dir.display())))?;
+	}
+
+	write_file_atomic(file, contents)
+		.map_err(|e| wrap(e, format!("error writing {}", file.display())).into())
 }
 
 pub fn unzip_downloaded_release<T>(
@@ -175,18 +1668,9 @@ pub fn unzip_downloaded_release<T>(
 	reporter: T,
 ) -> Result<(), WrappedError>
 where
-	T: ReportCopyProgress,
+	T: ReportProgress + Send,
 {
-	#[cfg(any(target_os = "windows", target_os = "macos"))]
-	{
-		use crate::util::zipper;
-		zipper::unzip_file(compressed_file, target_dir, reporter)
-	}
-	#[cfg(target_os = "linux")]
-	{
-		use crate::util::tar;
-		tar::decompress_tarball(compressed_file, target_dir, reporter)
-	}
+	run_maintenance(|| archive::extract(compressed_file, target_dir, reporter))
 }
 
 #[derive(Eq, PartialEq, Copy, Clone)]
@@ -215,14 +1699,52 @@ pub enum Platform {
 	LinuxX64,
 	LinuxARM64,
 	LinuxARM32,
+	LinuxRiscv64,
 	DarwinX64,
 	DarwinARM64,
 	WindowsX64,
 	WindowsX86,
 	WindowsARM64,
+	FreeBsdX64,
+	FreeBsdARM64,
 }
 
 impl Platform {
+	pub const ALL: [Platform; 13] = [
+		Platform::LinuxAlpineX64,
+		Platform::LinuxAlpineARM64,
+		Platform::LinuxX64,
+		Platform::LinuxARM64,
+		Platform::LinuxARM32,
+		Platform::LinuxRiscv64,
+		Platform::DarwinX64,
+		Platform::DarwinARM64,
+		Platform::WindowsX64,
+		Platform::WindowsX86,
+		Platform::WindowsARM64,
+		Platform::FreeBsdX64,
+		Platform::FreeBsdARM64,
+	];
+
+	/// Gets the name the platform is given by on the command line.
+	pub fn name(&self) -> &'static str {
+		match self {
+			Platform::LinuxAlpineX64 => "alpine-x64",
+			Platform::LinuxAlpineARM64 => "alpine-arm64",
+			Platform::LinuxX64 => "linux-x64",
+			Platform::LinuxARM64 => "linux-arm64",
+			Platform::LinuxARM32 => "linux-armhf",
+			Platform::LinuxRiscv64 => "linux-riscv64",
+			Platform::DarwinX64 => "darwin-x64",
+			Platform::DarwinARM64 => "darwin-arm64",
+			Platform::WindowsX64 => "win32-x64",
+			Platform::WindowsX86 => "win32-x86",
+			Platform::WindowsARM64 => "win32-arm64",
+			Platform::FreeBsdX64 => "freebsd-x64",
+			Platform::FreeBsdARM64 => "freebsd-arm64",
+		}
+	}
+
 	pub fn archive(&self) -> Option<String> {
 		match self {
 			Platform::LinuxX64 => Some("linux-x64".to_owned()),
@@ -243,11 +1765,14 @@ impl Platform {
 			Platform::LinuxX64 => "server-linux-x64",
 			Platform::LinuxARM64 => "server-linux-arm64",
 			Platform::LinuxARM32 => "server-linux-armhf",
+			Platform::LinuxRiscv64 => "server-linux-riscv64",
 			Platform::DarwinX64 => "server-darwin",
 			Platform::DarwinARM64 => "server-darwin-arm64",
 			Platform::WindowsX64 => "server-win32-x64",
 			Platform::WindowsX86 => "server-win32",
 			Platform::WindowsARM64 => "server-win32-arm64",
+			Platform::FreeBsdX64 => "server-freebsd-x64",
+			Platform::FreeBsdARM64 => "server-freebsd-arm64",
 		}
 		.to_owned()
 	}
@@ -259,15 +1784,29 @@ impl Platform {
 			Platform::LinuxX64 => "cli-linux-x64",
 			Platform::LinuxARM64 => "cli-linux-arm64",
 			Platform::LinuxARM32 => "cli-linux-armhf",
+			Platform::LinuxRiscv64 => "cli-linux-riscv64",
 			Platform::DarwinX64 => "cli-darwin-x64",
 			Platform::DarwinARM64 => "cli-darwin-arm64",
 			Platform::WindowsARM64 => "cli-win32-arm64",
 			Platform::WindowsX64 => "cli-win32-x64",
 			Platform::WindowsX86 => "cli-win32",
+			Platform::FreeBsdX64 => "cli-freebsd-x64",
+			Platform::FreeBsdARM64 => "cli-freebsd-arm64",
 		}
 		.to_owned()
 	}
 
+	/// Gets the platform whose builds can be used, through a compatibility
+	/// layer, if there are none for this one. FreeBSD runs Linux binaries with
+	/// its Linux binary compatibility (linuxulator).
+	pub fn compat_fallback(&self) -> Option<Platform> {
+		match self {
+			Platform::FreeBsdX64 => Some(Platform::LinuxX64),
+			Platform::FreeBsdARM64 => Some(Platform::LinuxARM64),
+			_ => None,
+		}
+	}
+
 	pub fn web(&self) -> String {
 		format!("{}-web", self.headless())
 	}
@@ -291,18 +1830,156 @@ impl Platform {
 			Some(Platform::LinuxARM32)
 		} else if cfg!(all(target_os = "linux", target_arch = "aarch64")) {
 			Some(Platform::LinuxARM64)
+		} else if cfg!(all(target_os = "linux", target_arch = "riscv64")) {
+			Some(Platform::LinuxRiscv64)
 		} else if cfg!(all(target_os = "macos", target_arch = "x86_64")) {
 			Some(Platform::DarwinX64)
 		} else if cfg!(all(target_os = "macos", target_arch = "aarch64")) {
 			Some(Platform::DarwinARM64)
-		} else if cfg!(all(target_os = "windows", target_arch = "x86_64")) {
-			Some(Platform::WindowsX64)
-		} else if cfg!(all(target_os = "windows", target_arch = "x86")) {
-			Some(Platform::WindowsX86)
-		} else if cfg!(all(target_os = "windows", target_arch = "aarch64")) {
-			Some(Platform::WindowsARM64)
+		} else if cfg!(all(target_os = "freebsd", target_arch = "x86_64")) {
+			Some(Platform::FreeBsdX64)
+		} else if cfg!(all(target_os = "freebsd", target_arch = "aarch64")) {
+			Some(Platform::FreeBsdARM64)
+		} else if cfg!(target_os = "windows") {
+			windows_native_platform()
+		} else {
+			None
+		}
+	}
+}
+
+impl fmt::Display for Platform {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.name())
+	}
+}
+
+impl FromStr for Platform {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.trim().to_lowercase();
+		Platform::ALL
+			.iter()
+			.find(|p| p.name() == s)
+			.copied()
+			.ok_or_else(|| {
+				let names: Vec<&str> = Platform::ALL.iter().map(|p| p.name()).collect();
+				format!(
+					"unknown platform '{}', expected one of: {}",
+					s,
+					names.join(", ")
+				)
+			})
+	}
+}
+
+/// Gets the platform for the machine's native architecture on Windows. The
+/// compile-time architecture isn't enough here: ARM64 machines run x64 builds
+/// of the CLI under emulation, and x64 machines run x86 builds under WOW64,
+/// and in both cases we want the native server rather than the emulated one.
+#[cfg(target_os = "windows")]
+fn windows_native_platform() -> Option<Platform> {
+	use winapi::{
+		shared::minwindef::BOOL,
+		um::{
+			libloaderapi::{GetModuleHandleA, GetProcAddress},
+			processthreadsapi::GetCurrentProcess,
+			winnt::{HANDLE, IMAGE_FILE_MACHINE_AMD64, IMAGE_FILE_MACHINE_I386},
+		},
+	};
+
+	const IMAGE_FILE_MACHINE_ARM64: u16 = 0xAA64;
+	type IsWow64Process2Fn = unsafe extern "system" fn(HANDLE, *mut u16, *mut u16) -> BOOL;
+
+	// IsWow64Process2 is only available on Windows 10 1709 and later, so it's
+	// looked up dynamically rather than linked, so we still start on older
+	// versions where there's no ARM64 to worry about.
+	let native_machine = unsafe {
+		let kernel32 = GetModuleHandleA(b"kernel32.dll\0".as_ptr() as *const i8);
+		let proc = if kernel32.is_null() {
+			std::ptr::null_mut()
 		} else {
+			GetProcAddress(kernel32, b"IsWow64Process2\0".as_ptr() as *const i8)
+		};
+
+		if proc.is_null() {
 			None
+		} else {
+			let is_wow64_process2: IsWow64Process2Fn = std::mem::transmute(proc);
+			let mut process_machine = 0u16;
+			let mut native_machine = 0u16;
+			if is_wow64_process2(
+				GetCurrentProcess(),
+				&mut process_machine,
+				&mut native_machine,
+			) != 0
+			{
+				Some(native_machine)
+			} else {
+				None
+			}
 		}
+	};
+
+	match native_machine {
+		Some(IMAGE_FILE_MACHINE_ARM64) => Some(Platform::WindowsARM64),
+		Some(IMAGE_FILE_MACHINE_AMD64) => Some(Platform::WindowsX64),
+		Some(IMAGE_FILE_MACHINE_I386) => Some(Platform::WindowsX86),
+		_ if cfg!(target_arch = "aarch64") => Some(Platform::WindowsARM64),
+		_ if cfg!(target_arch = "x86_64") => Some(Platform::WindowsX64),
+		_ if cfg!(target_arch = "x86") => Some(Platform::WindowsX86),
+		_ => None,
+	}
+}
+
+#[cfg(not(target_os = "windows"))]
+fn windows_native_platform() -> Option<Platform> {
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_download_ranges() {
+		let ranges = DownloadRanges::new(10, 3);
+		assert_eq!(ranges.chunks, vec![[0, 0, 4], [4, 4, 8], [8, 8, 10]]);
+
+		let offsets = [AtomicU64::new(4), AtomicU64::new(6), AtomicU64::new(10)];
+		let ranges = ranges.with_offsets(&offsets);
+		assert_eq!(ranges.downloaded(), 8);
+		assert_eq!(ranges.contiguous(), 6);
+
+		let offsets = [AtomicU64::new(2), AtomicU64::new(8), AtomicU64::new(10)];
+		assert_eq!(ranges.with_offsets(&offsets).contiguous(), 2);
+	}
+
+	#[tokio::test]
+	async fn test_trim_to_downloaded() {
+		let dir = tempfile::tempdir().unwrap();
+		let target = dir.path().join("download.partial");
+		std::fs::write(&target, [1u8; 10]).unwrap();
+		let offsets = [AtomicU64::new(4), AtomicU64::new(5), AtomicU64::new(8)];
+		let ranges = DownloadRanges::new(10, 3).with_offsets(&offsets);
+		PersistedState::new(DownloadRanges::path_for(&target))
+			.save(ranges)
+			.unwrap();
+
+		trim_to_downloaded(&target).await.unwrap();
+		assert_eq!(std::fs::metadata(&target).unwrap().len(), 5);
+		assert!(!DownloadRanges::path_for(&target).exists());
+	}
+
+	#[test]
+	fn test_check_dir_name() {
+		assert!(check_dir_name("1.90.0").is_ok());
+		assert!(check_dir_name("abc123").is_ok());
+		assert!(check_dir_name("").is_err());
+		assert!(check_dir_name("..").is_err());
+		assert!(check_dir_name("../1.90.0").is_err());
+		assert!(check_dir_name("1.90/0").is_err());
+		assert!(check_dir_name("1.90\\0").is_err());
 	}
 }
@@ -5,18 +5,30 @@
 
 mod is_integrated;
 
+pub mod archive;
+pub mod clipboard;
+pub mod clock;
 pub mod command;
 pub mod errors;
 pub mod http;
 pub mod input;
 pub mod io;
 pub mod machine;
+pub mod patchelf;
+pub mod plain;
+pub mod power;
 pub mod prereqs;
+pub mod priority;
+pub mod progress;
+pub mod protocol_version;
+pub mod proxy;
+pub mod signing;
 pub mod sync;
-pub use is_integrated::*;
-
-#[cfg(target_os = "linux")]
 pub mod tar;
-
-#[cfg(any(target_os = "windows", target_os = "macos"))]
+pub mod tempfile;
+pub mod tls;
 pub mod zipper;
+pub use is_integrated::*;
+
+#[cfg(target_os = "windows")]
+pub mod elevation;
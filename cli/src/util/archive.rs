@@ -0,0 +1,92 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{fs::File, io::Read, path::Path};
+
+use super::{
+	errors::{wrap, WrappedError},
+	progress::ReportProgress,
+	tar::{self, TarCompression},
+	zipper,
+};
+
+/// Format of a downloaded archive. It's detected from the file's contents,
+/// since downloads don't have a meaningful extension and the update service
+/// may start serving a different format for the same platform.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+	Zip,
+	Tar(TarCompression),
+}
+
+/// Number of bytes at the start of an archive needed to detect its format.
+pub const MAGIC_LEN: usize = 6;
+
+impl ArchiveFormat {
+	/// Detects the format from the first `MAGIC_LEN` bytes of an archive.
+	pub fn from_magic(header: &[u8]) -> Option<Self> {
+		match header {
+			[b'P', b'K', 3, 4, ..] | [b'P', b'K', 5, 6, ..] => Some(ArchiveFormat::Zip),
+			[0x1f, 0x8b, ..] => Some(ArchiveFormat::Tar(TarCompression::Gzip)),
+			[0x28, 0xb5, 0x2f, 0xfd, ..] => Some(ArchiveFormat::Tar(TarCompression::Zstd)),
+			[0xfd, b'7', b'z', b'X', b'Z', 0, ..] => Some(ArchiveFormat::Tar(TarCompression::Xz)),
+			_ => None,
+		}
+	}
+
+	/// Detects the format of the archive from its first bytes.
+	pub fn detect(path: &Path) -> Result<Self, WrappedError> {
+		let mut header = Vec::with_capacity(MAGIC_LEN);
+		File::open(path)
+			.and_then(|f| f.take(MAGIC_LEN as u64).read_to_end(&mut header))
+			.map_err(|e| wrap(e, format!("error reading archive {}", path.display())))?;
+
+		ArchiveFormat::from_magic(&header).ok_or_else(|| {
+			wrap(
+				"expected a zip, or a tarball compressed with gzip, zstd, or xz",
+				format!("unrecognized archive format in {}", path.display()),
+			)
+		})
+	}
+}
+
+/// Extracts the archive into the directory, removing the folder all its
+/// files are in, if any.
+pub fn extract<T>(path: &Path, target_dir: &Path, reporter: T) -> Result<(), WrappedError>
+where
+	T: ReportProgress,
+{
+	match ArchiveFormat::detect(path)? {
+		ArchiveFormat::Zip => zipper::unzip_file(path, target_dir, reporter),
+		ArchiveFormat::Tar(c) => tar::decompress_tarball(path, target_dir, c, reporter),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_detects_format() {
+		assert_eq!(
+			ArchiveFormat::from_magic(b"PK\x03\x04\x14\x00"),
+			Some(ArchiveFormat::Zip)
+		);
+		assert_eq!(
+			ArchiveFormat::from_magic(&[0x1f, 0x8b, 0x08, 0, 0, 0]),
+			Some(ArchiveFormat::Tar(TarCompression::Gzip))
+		);
+		assert_eq!(
+			ArchiveFormat::from_magic(&[0x28, 0xb5, 0x2f, 0xfd, 0x04, 0x58]),
+			Some(ArchiveFormat::Tar(TarCompression::Zstd))
+		);
+		assert_eq!(
+			ArchiveFormat::from_magic(&[0xfd, b'7', b'z', b'X', b'Z', 0]),
+			Some(ArchiveFormat::Tar(TarCompression::Xz))
+		);
+		assert_eq!(ArchiveFormat::from_magic(b"{\"url\""), None);
+		assert_eq!(ArchiveFormat::from_magic(&[]), None);
+	}
+}
@@ -0,0 +1,62 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+use std::process::Stdio;
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use super::errors::{wrap, AnyError};
+
+/// Commands that can take text on stdin and place it on the clipboard, in
+/// order of preference for the current platform.
+fn clipboard_commands() -> Vec<&'static [&'static str]> {
+	if cfg!(target_os = "macos") {
+		vec![&["pbcopy"]]
+	} else if cfg!(target_os = "windows") {
+		vec![&["clip"]]
+	} else {
+		let mut cmds: Vec<&'static [&'static str]> = vec![];
+		if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+			cmds.push(&["wl-copy"]);
+		}
+		if std::env::var_os("DISPLAY").is_some() {
+			cmds.push(&["xclip", "-selection", "clipboard"]);
+			cmds.push(&["xsel", "--clipboard", "--input"]);
+		}
+		cmds
+	}
+}
+
+/// Copies the text to the system clipboard using the platform's clipboard
+/// utility. Fails if no utility is available, e.g. on a headless machine.
+pub async fn copy_to_clipboard(text: &str) -> Result<(), AnyError> {
+	for cmd in clipboard_commands() {
+		let mut child = match Command::new(cmd[0])
+			.args(&cmd[1..])
+			.stdin(Stdio::piped())
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.spawn()
+		{
+			Ok(c) => c,
+			Err(_) => continue, // not installed, try the next one
+		};
+
+		if let Some(mut stdin) = child.stdin.take() {
+			stdin
+				.write_all(text.as_bytes())
+				.await
+				.map_err(|e| wrap(e, "error writing to clipboard"))?;
+		}
+
+		let status = child
+			.wait()
+			.await
+			.map_err(|e| wrap(e, "error waiting for clipboard command"))?;
+		if status.success() {
+			return Ok(());
+		}
+	}
+
+	Err(wrap("", "no clipboard utility is available").into())
+}
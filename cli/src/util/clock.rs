@@ -0,0 +1,244 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Wall-clock time and timers that can be controlled in tests.
+//!
+//! Timers for durations use tokio's monotonic clock, which tests can pause
+//! and advance. Deadlines in wall-clock time, like token expiry, go through a
+//! `Clock`. The monotonic clock doesn't advance while the system is suspended
+//! on every platform, so waits for wall-clock deadlines sleep in short steps
+//! and re-check the time, which notices a resume promptly.
+
+use std::{
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use tokio::time::{sleep, Instant};
+
+/// Longest a wall-clock wait sleeps before re-checking the time.
+const MAX_WALL_SLEEP: Duration = Duration::from_secs(30);
+/// How far the wall clock must run ahead of the monotonic one before it's
+/// considered a suspend, rather than drift or a small clock adjustment.
+const SUSPEND_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// Source of wall-clock time.
+pub trait Clock: Send + Sync {
+	fn now(&self) -> DateTime<Utc>;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The system's clock.
+#[derive(Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> DateTime<Utc> {
+		Utc::now()
+	}
+}
+
+pub fn system_clock() -> SharedClock {
+	Arc::new(SystemClock)
+}
+
+/// Clock for tests, whose time only changes when it's advanced.
+#[derive(Clone)]
+pub struct ManualClock {
+	now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl ManualClock {
+	pub fn new(now: DateTime<Utc>) -> Self {
+		ManualClock {
+			now: Arc::new(Mutex::new(now)),
+		}
+	}
+
+	pub fn advance(&self, by: chrono::Duration) {
+		*self.now.lock().unwrap() += by;
+	}
+}
+
+impl Clock for ManualClock {
+	fn now(&self) -> DateTime<Utc> {
+		*self.now.lock().unwrap()
+	}
+}
+
+/// Waits until the clock reaches the deadline, including time the system
+/// spent suspended.
+pub async fn sleep_until_wall(clock: &dyn Clock, deadline: DateTime<Utc>) {
+	loop {
+		let remaining = match (deadline - clock.now()).to_std() {
+			Ok(r) if !r.is_zero() => r,
+			_ => return,
+		};
+
+		sleep(remaining.min(MAX_WALL_SLEEP)).await;
+	}
+}
+
+/// Like tokio's `Interval`, but the period is measured on a `Clock`, so a
+/// tick that came due while the system was suspended fires soon after it
+/// resumes. The first tick completes immediately.
+pub struct WallInterval {
+	clock: SharedClock,
+	period: chrono::Duration,
+	next: Option<DateTime<Utc>>,
+}
+
+impl WallInterval {
+	pub fn new(clock: SharedClock, period: Duration) -> Self {
+		WallInterval {
+			clock,
+			period: chrono::Duration::from_std(period).unwrap(),
+			next: None,
+		}
+	}
+
+	pub async fn tick(&mut self) {
+		if let Some(next) = self.next {
+			sleep_until_wall(self.clock.as_ref(), next).await;
+		}
+
+		// schedule from now, rather than the deadline, so ticks missed during
+		// a suspend aren't fired in a burst
+		self.next = Some(self.clock.now() + self.period);
+	}
+}
+
+/// Detects when the system resumes from being suspended, by noticing the
+/// wall clock has moved further than the monotonic clock between checks.
+pub struct SuspendDetector {
+	clock: SharedClock,
+	last_wall: DateTime<Utc>,
+	last_monotonic: Instant,
+}
+
+impl SuspendDetector {
+	pub fn new(clock: SharedClock) -> Self {
+		SuspendDetector {
+			last_wall: clock.now(),
+			last_monotonic: Instant::now(),
+			clock,
+		}
+	}
+
+	/// Returns roughly how long the system was suspended for, if it was
+	/// suspended since the last check.
+	pub fn check(&mut self) -> Option<Duration> {
+		let wall = self.clock.now();
+		let monotonic = Instant::now();
+		let wall_elapsed = (wall - self.last_wall).to_std().unwrap_or_default();
+		let monotonic_elapsed = monotonic - self.last_monotonic;
+		self.last_wall = wall;
+		self.last_monotonic = monotonic;
+
+		wall_elapsed
+			.checked_sub(monotonic_elapsed)
+			.filter(|d| *d > SUSPEND_THRESHOLD)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::TimeZone;
+
+	fn clock() -> ManualClock {
+		ManualClock::new(Utc.ymd(2022, 10, 1).and_hms(0, 0, 0))
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_sleep_until_wall_notices_suspend() {
+		let clock = clock();
+		let deadline = clock.now() + chrono::Duration::hours(1);
+		let task = {
+			let clock = clock.clone();
+			tokio::spawn(async move { sleep_until_wall(&clock, deadline).await })
+		};
+
+		// simulate a suspend: wall time passes, but monotonic time doesn't
+		tokio::task::yield_now().await;
+		clock.advance(chrono::Duration::hours(2));
+		tokio::time::advance(MAX_WALL_SLEEP).await;
+		tokio::time::timeout(Duration::from_millis(1), task)
+			.await
+			.expect("expected to wake")
+			.unwrap();
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_sleep_until_wall_fires_at_deadline() {
+		let clock = clock();
+		let deadline = clock.now() + chrono::Duration::seconds(60);
+		let mut task = {
+			let clock = clock.clone();
+			tokio::spawn(async move { sleep_until_wall(&clock, deadline).await })
+		};
+
+		tokio::task::yield_now().await;
+		clock.advance(chrono::Duration::seconds(59));
+		tokio::time::advance(Duration::from_secs(59)).await;
+		assert!(tokio::time::timeout(Duration::from_millis(1), &mut task)
+			.await
+			.is_err());
+
+		clock.advance(chrono::Duration::seconds(1));
+		tokio::time::advance(Duration::from_secs(1)).await;
+		tokio::time::timeout(Duration::from_millis(1), task)
+			.await
+			.expect("expected to wake at the deadline")
+			.unwrap();
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_wall_interval() {
+		let clock = clock();
+		let mut interval = WallInterval::new(Arc::new(clock.clone()), Duration::from_secs(60));
+		interval.tick().await;
+
+		let start = Instant::now();
+		clock.advance(chrono::Duration::seconds(60));
+		interval.tick().await;
+		assert!(Instant::now() - start < MAX_WALL_SLEEP);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_wall_interval_after_suspend() {
+		let clock = clock();
+		let mut interval = WallInterval::new(Arc::new(clock.clone()), Duration::from_secs(60));
+		interval.tick().await;
+
+		// ticks missed while suspended fire once on resume, not in a burst
+		clock.advance(chrono::Duration::minutes(10));
+		tokio::time::timeout(Duration::from_millis(1), interval.tick())
+			.await
+			.expect("expected to tick on resume");
+		assert!(
+			tokio::time::timeout(Duration::from_secs(59), interval.tick())
+				.await
+				.is_err()
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_suspend_detector() {
+		let clock = clock();
+		let mut detector = SuspendDetector::new(Arc::new(clock.clone()));
+
+		clock.advance(chrono::Duration::seconds(5));
+		tokio::time::advance(Duration::from_secs(5)).await;
+		assert_eq!(detector.check(), None);
+
+		clock.advance(chrono::Duration::minutes(10));
+		tokio::time::advance(Duration::from_secs(1)).await;
+		let suspended = detector.check().expect("expected to detect suspend");
+		assert_eq!(suspended, Duration::from_secs(599));
+	}
+}
@@ -2,32 +2,178 @@
  *  Copyright (c) Microsoft Corporation. All rights reserved.
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
-use super::errors::{wrap, AnyError, CommandFailed, WrappedError};
-use std::{borrow::Cow, ffi::OsStr, process::Stdio};
+use super::errors::{wrap, AnyError, CommandFailed, CommandTimedOut, WrappedError};
+use crate::{debug, log};
+use std::{
+	ffi::{OsStr, OsString},
+	future::Future,
+	process::{Output, Stdio},
+	time::Duration,
+};
 use tokio::process::Command;
 
+/// How long external commands may run before they're stopped, by default.
+pub const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An external command, like `systemctl`, run with a timeout and with its
+/// output captured and logged. The process is killed if it times out or the
+/// future running it is dropped, so a hung command can't outlive its caller.
+pub struct ExternalCommand {
+	program: OsString,
+	args: Vec<OsString>,
+	envs: Vec<(OsString, OsString)>,
+	timeout: Option<Duration>,
+	log: Option<log::Logger>,
+}
+
+impl ExternalCommand {
+	pub fn new(program: impl AsRef<OsStr>) -> Self {
+		ExternalCommand {
+			program: program.as_ref().to_owned(),
+			args: vec![],
+			envs: vec![],
+			timeout: Some(DEFAULT_COMMAND_TIMEOUT),
+			log: None,
+		}
+	}
+
+	pub fn args<I, S>(mut self, args: I) -> Self
+	where
+		I: IntoIterator<Item = S>,
+		S: AsRef<OsStr>,
+	{
+		self.args
+			.extend(args.into_iter().map(|a| a.as_ref().to_owned()));
+		self
+	}
+
+	/// Sets a variable in the command's environment.
+	pub fn env(mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> Self {
+		self.envs
+			.push((key.as_ref().to_owned(), value.as_ref().to_owned()));
+		self
+	}
+
+	/// Sets how long the command may run, or `None` to wait until it exits,
+	/// for commands like following logs.
+	pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+		self.timeout = timeout;
+		self
+	}
+
+	/// Logs the command when it's run, and the output it captures.
+	pub fn log(mut self, log: &log::Logger) -> Self {
+		self.log = Some(log.clone());
+		self
+	}
+
+	/// Runs the command, capturing its output.
+	pub async fn output(&self) -> Result<Output, AnyError> {
+		let mut command = self.command();
+		command
+			.stdin(Stdio::null())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::piped());
+
+		if let Some(log) = &self.log {
+			debug!(log, "Running {}", self.describe());
+		}
+		let output = self.wait(command.output()).await?;
+		if let Some(log) = &self.log {
+			for (stream, contents) in [("stdout", &output.stdout), ("stderr", &output.stderr)] {
+				let contents = String::from_utf8_lossy(contents);
+				if !contents.trim().is_empty() {
+					debug!(
+						log,
+						"{} {}: {}",
+						self.program.to_string_lossy(),
+						stream,
+						contents.trim_end()
+					);
+				}
+			}
+		}
+
+		Ok(output)
+	}
+
+	/// Runs the command, failing if it doesn't exit successfully.
+	pub async fn run(&self) -> Result<Output, AnyError> {
+		let output = self.output().await?;
+		if !output.status.success() {
+			return Err(CommandFailed {
+				command: self.describe(),
+				output,
+			}
+			.into());
+		}
+
+		Ok(output)
+	}
+
+	/// Runs the command with its output going to the terminal, for output
+	/// meant for the user, like a service's status. Returns its exit code.
+	pub async fn run_interactive(&self) -> Result<i32, AnyError> {
+		if let Some(log) = &self.log {
+			debug!(log, "Running {}", self.describe());
+		}
+		let status = self.wait(self.command().status()).await?;
+		Ok(status.code().unwrap_or(1))
+	}
+
+	fn command(&self) -> Command {
+		let mut command = Command::new(&self.program);
+		command
+			.args(&self.args)
+			.envs(self.envs.iter().map(|(k, v)| (k, v)))
+			.kill_on_drop(true);
+		command
+	}
+
+	async fn wait<T>(&self, f: impl Future<Output = std::io::Result<T>>) -> Result<T, AnyError> {
+		let result = match self.timeout {
+			Some(timeout) => {
+				tokio::time::timeout(timeout, f)
+					.await
+					.map_err(|_| CommandTimedOut {
+						command: self.describe(),
+						timeout,
+					})?
+			}
+			None => f.await,
+		};
+
+		result.map_err(|e| {
+			wrap(
+				e,
+				format!(
+					"failed to execute command '{}'",
+					self.program.to_string_lossy()
+				),
+			)
+			.into()
+		})
+	}
+
+	fn describe(&self) -> String {
+		let mut s = self.program.to_string_lossy().to_string();
+		for a in &self.args {
+			s.push(' ');
+			s.push_str(&a.to_string_lossy());
+		}
+		s
+	}
+}
+
 pub async fn capture_command_and_check_status(
 	command_str: impl AsRef<OsStr>,
 	args: &[impl AsRef<OsStr>],
 ) -> Result<std::process::Output, AnyError> {
-	let output = capture_command(&command_str, args).await?;
-
-	if !output.status.success() {
-		return Err(CommandFailed {
-			command: format!(
-				"{} {}",
-				command_str.as_ref().to_string_lossy(),
-				args.iter()
-					.map(|a| a.as_ref().to_string_lossy())
-					.collect::<Vec<Cow<'_, str>>>()
-					.join(" ")
-			),
-			output,
-		}
-		.into());
-	}
-
-	Ok(output)
+	ExternalCommand::new(command_str)
+		.args(args)
+		.timeout(None)
+		.run()
+		.await
 }
 
 pub async fn capture_command<A, I, S>(
@@ -0,0 +1,156 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Runs the current command again as an administrator, for `--elevate`. The
+//! CLI's own executable is relaunched, never one named by its arguments, so
+//! the UAC prompt shows the publisher it's signed by. The elevated command
+//! writes its output to a file, which is printed once it exits.
+
+use std::{
+	ffi::{OsStr, OsString},
+	io,
+	os::windows::ffi::OsStrExt,
+};
+
+use winapi::{
+	shared::winerror::ERROR_CANCELLED,
+	um::{
+		handleapi::CloseHandle,
+		processthreadsapi::GetExitCodeProcess,
+		shellapi::{ShellExecuteExW, SEE_MASK_NOASYNC, SEE_MASK_NOCLOSEPROCESS, SHELLEXECUTEINFOW},
+		synchapi::WaitForSingleObject,
+		winbase::INFINITE,
+		winuser::SW_SHOWNORMAL,
+	},
+};
+
+use super::{
+	errors::{wrap, AnyError, WrappedError},
+	tempfile::new_temp_file,
+};
+
+/// Runs the CLI again with the same arguments as an administrator, waiting
+/// for it to exit and printing its output. Returns its exit code.
+pub fn relaunch_elevated() -> Result<i32, AnyError> {
+	let exe = std::env::current_exe().map_err(|e| wrap(e, "could not get current exe"))?;
+	let output = new_temp_file()?;
+
+	let mut args: Vec<OsString> = std::env::args_os().skip(1).collect();
+	args.push(OsString::from("--elevated-output"));
+	args.push(output.as_os_str().to_os_string());
+
+	let code = shell_execute_runas(exe.as_os_str(), &args)?;
+
+	if let Ok(contents) = std::fs::read_to_string(&output) {
+		print!("{}", contents);
+	}
+
+	Ok(code)
+}
+
+fn shell_execute_runas(exe: &OsStr, args: &[OsString]) -> Result<i32, WrappedError> {
+	let verb = to_wide(OsStr::new("runas"));
+	let file = to_wide(exe);
+	let mut parameters = Vec::new();
+	for (i, arg) in args.iter().enumerate() {
+		if i > 0 {
+			parameters.push(b' ' as u16);
+		}
+		append_quoted(&mut parameters, arg);
+	}
+	parameters.push(0);
+
+	let mut info: SHELLEXECUTEINFOW = unsafe { std::mem::zeroed() };
+	info.cbSize = std::mem::size_of::<SHELLEXECUTEINFOW>() as u32;
+	info.fMask = SEE_MASK_NOCLOSEPROCESS | SEE_MASK_NOASYNC;
+	info.lpVerb = verb.as_ptr();
+	info.lpFile = file.as_ptr();
+	info.lpParameters = parameters.as_ptr();
+	info.nShow = SW_SHOWNORMAL;
+
+	if unsafe { ShellExecuteExW(&mut info) } == 0 {
+		let e = io::Error::last_os_error();
+		if e.raw_os_error() == Some(ERROR_CANCELLED as i32) {
+			return Err(wrap(
+				"",
+				"the request for administrator rights was declined",
+			));
+		}
+		return Err(wrap(e, "error running the command as an administrator"));
+	}
+
+	if info.hProcess.is_null() {
+		return Err(wrap("", "the elevated command could not be waited on"));
+	}
+
+	let mut code = 1;
+	unsafe {
+		WaitForSingleObject(info.hProcess, INFINITE);
+		GetExitCodeProcess(info.hProcess, &mut code);
+		CloseHandle(info.hProcess);
+	}
+
+	Ok(code as i32)
+}
+
+fn to_wide(s: &OsStr) -> Vec<u16> {
+	s.encode_wide().chain(std::iter::once(0)).collect()
+}
+
+/// Appends the argument so that CommandLineToArgvW, and so the C runtime,
+/// parses it back to the same string.
+fn append_quoted(cmd: &mut Vec<u16>, arg: &OsStr) {
+	const QUOTE: u16 = b'"' as u16;
+	const BACKSLASH: u16 = b'\\' as u16;
+
+	let arg: Vec<u16> = arg.encode_wide().collect();
+	let needs_quotes = arg.is_empty()
+		|| arg
+			.iter()
+			.any(|&c| c == b' ' as u16 || c == b'\t' as u16 || c == QUOTE);
+	if !needs_quotes {
+		cmd.extend(arg);
+		return;
+	}
+
+	cmd.push(QUOTE);
+	let mut backslashes = 0;
+	for c in arg {
+		if c == BACKSLASH {
+			backslashes += 1;
+		} else {
+			// backslashes are only special before a quote, where they're doubled
+			if c == QUOTE {
+				cmd.extend(std::iter::repeat(BACKSLASH).take(backslashes + 1));
+			}
+			backslashes = 0;
+		}
+		cmd.push(c);
+	}
+	// likewise before the closing quote
+	cmd.extend(std::iter::repeat(BACKSLASH).take(backslashes));
+	cmd.push(QUOTE);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn quoted(arg: &str) -> String {
+		let mut cmd = Vec::new();
+		append_quoted(&mut cmd, OsStr::new(arg));
+		String::from_utf16(&cmd).unwrap()
+	}
+
+	#[test]
+	fn test_append_quoted() {
+		assert_eq!(quoted("tunnel"), "tunnel");
+		assert_eq!(quoted(""), "\"\"");
+		assert_eq!(quoted("C:\\Program Files\\"), "\"C:\\Program Files\\\\\"");
+		assert_eq!(quoted("a\"b"), "\"a\\\"b\"");
+		assert_eq!(quoted("a\\\"b c"), "\"a\\\\\\\"b c\"");
+		assert_eq!(quoted("C:\\no\\spaces"), "C:\\no\\spaces");
+	}
+}
@@ -6,6 +6,7 @@ use std::fmt::Display;
 
 use crate::constants::{
 	APPLICATION_NAME, CONTROL_PORT, DOCUMENTATION_URL, QUALITYLESS_PRODUCT_NAME,
+	QUALITYLESS_SERVER_NAME,
 };
 
 // Wraps another error with additional info.
@@ -13,6 +14,9 @@ use crate::constants::{
 pub struct WrappedError {
 	message: String,
 	original: String,
+	/// Whether the error is transient, like a timeout, so that trying again
+	/// may succeed.
+	retryable: bool,
 }
 
 impl std::fmt::Display for WrappedError {
@@ -31,6 +35,11 @@ impl WrappedError {
 	// fn new(original: Box<dyn std::error::Error>, message: String) -> WrappedError {
 	//     WrappedError { message, original }
 	// }
+
+	/// Whether trying the operation again may succeed.
+	pub fn is_retryable(&self) -> bool {
+		self.retryable
+	}
 }
 
 impl From<reqwest::Error> for WrappedError {
@@ -41,6 +50,8 @@ impl From<reqwest::Error> for WrappedError {
 				e.url().map_or("<unknown>", |u| u.as_str())
 			),
 			original: format!("{}", e),
+			// includes failed DNS lookups and refused connections
+			retryable: e.is_connect() || e.is_timeout(),
 		}
 	}
 }
@@ -53,6 +64,25 @@ where
 	WrappedError {
 		message: message.into(),
 		original: format!("{:?}", original),
+		retryable: false,
+	}
+}
+
+/// Wraps an error from reading a response body, keeping a body that stopped
+/// arriving as the retryable `RequestTimeoutError` it was raised as.
+pub fn wrap_read<S>(original: std::io::Error, message: S) -> AnyError
+where
+	S: Into<String>,
+{
+	let timeout = original
+		.get_ref()
+		.and_then(|e| e.downcast_ref::<RequestTimeoutError>());
+	match timeout {
+		Some(e) => AnyError::RequestTimeoutError(RequestTimeoutError {
+			url: e.url.clone(),
+			phase: e.phase,
+		}),
+		None => wrap(original, message).into(),
 	}
 }
 
@@ -64,6 +94,7 @@ where
 	WrappedError {
 		message: message.into(),
 		original: format!("{}", original),
+		retryable: false,
 	}
 }
 
@@ -85,7 +116,17 @@ impl std::fmt::Display for StatusError {
 	}
 }
 
+/// Whether a request that got a response with the status code may succeed if
+/// it's made again: timeouts, rate limiting, and server errors.
+pub fn is_retryable_status(status_code: u16) -> bool {
+	status_code == 408 || status_code == 429 || status_code >= 500
+}
+
 impl StatusError {
+	pub fn is_retryable(&self) -> bool {
+		is_retryable_status(self.status_code)
+	}
+
 	pub async fn from_res(res: reqwest::Response) -> Result<StatusError, AnyError> {
 		let status_code = res.status().as_u16();
 		let url = res.url().to_string();
@@ -128,13 +169,18 @@ impl std::fmt::Display for MismatchConnectionToken {
 	}
 }
 
-// When the VS Code server has an unrecognized extension (rather than zip or gz)
+// When a server that doesn't require a connection token would listen on an
+// address other machines can reach.
 #[derive(Debug)]
-pub struct InvalidServerExtensionError(pub String);
+pub struct ConnectionTokenRequired(pub String);
 
-impl std::fmt::Display for InvalidServerExtensionError {
+impl std::fmt::Display for ConnectionTokenRequired {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-		write!(f, "invalid server extension '{}'", self.0)
+		write!(
+			f,
+			"A server listening on '{}' can be reached from other machines, so it must require a connection token. Listen on a loopback address like 127.0.0.1 to use the connection token mode 'none'.",
+			self.0
+		)
 	}
 }
 
@@ -239,6 +285,56 @@ impl std::fmt::Display for MismatchedLaunchModeError {
 	}
 }
 
+// When a server archive given for an offline install can't be used.
+#[derive(Debug)]
+pub struct InvalidServerArchive(pub String);
+
+impl std::fmt::Display for InvalidServerArchive {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "The server archive can't be installed: {}", self.0)
+	}
+}
+
+// When a server is running in a different jail than the one requested.
+#[derive(Debug)]
+pub struct MismatchedJail(pub String);
+
+impl std::fmt::Display for MismatchedJail {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+// When the server must be sandboxed, but the system doesn't support it.
+#[derive(Debug)]
+pub struct SandboxUnavailable(pub String);
+
+impl std::fmt::Display for SandboxUnavailable {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "The server can't be sandboxed: {}", self.0)
+	}
+}
+
+// When the configured trusted folders or workspace root can't be used.
+#[derive(Debug)]
+pub struct InvalidWorkspacePolicy(pub String);
+
+impl std::fmt::Display for InvalidWorkspacePolicy {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "Invalid workspace trust settings: {}", self.0)
+	}
+}
+
+// When an SMTP server rejects a notification email.
+#[derive(Debug)]
+pub struct SmtpError(pub String);
+
+impl std::fmt::Display for SmtpError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "The mail server rejected the notification: {}", self.0)
+	}
+}
+
 #[derive(Debug)]
 pub struct NoAttachedServerError();
 
@@ -248,6 +344,19 @@ impl std::fmt::Display for NoAttachedServerError {
 	}
 }
 
+#[derive(Debug)]
+pub struct NoInstalledServerError();
+
+impl std::fmt::Display for NoInstalledServerError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"No {} is installed yet. Run `{} tunnel` to install one.",
+			QUALITYLESS_SERVER_NAME, APPLICATION_NAME
+		)
+	}
+}
+
 #[derive(Debug)]
 pub struct ServerWriteError();
 
@@ -266,6 +375,19 @@ impl std::fmt::Display for RefreshTokenNotAvailableError {
 	}
 }
 
+#[derive(Debug)]
+pub struct AuthScopesNotGranted(pub Vec<String>);
+
+impl std::fmt::Display for AuthScopesNotGranted {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"This feature requires additional permissions which were not granted: {}",
+			self.0.join(", ")
+		)
+	}
+}
+
 #[derive(Debug)]
 pub struct UnsupportedPlatformError();
 
@@ -278,6 +400,81 @@ impl std::fmt::Display for UnsupportedPlatformError {
 	}
 }
 
+// When the update service has no builds for a platform, which happens for
+// newer platforms builds aren't published for on every release yet.
+#[derive(Debug)]
+pub struct NoBuildsForPlatformError(pub String);
+
+impl std::fmt::Display for NoBuildsForPlatformError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"There are no {} builds published for this platform ({}) yet. Try again after a later release, or install a server built for it with `tunnel --install-server-from <archive>`.",
+			QUALITYLESS_PRODUCT_NAME, self.0
+		)
+	}
+}
+
+// When the server selected on the command line can't be used together with
+// other options or the pinned version.
+#[derive(Debug)]
+pub struct ConflictingServerSelection(pub String);
+
+impl std::fmt::Display for ConflictingServerSelection {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "Invalid server selection: {}", self.0)
+	}
+}
+
+// When there's no earlier server for `server rollback` to switch to.
+#[derive(Debug)]
+pub struct NoServerToRollBackTo(pub String);
+
+impl std::fmt::Display for NoServerToRollBackTo {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "There's no server to roll back to: {}", self.0)
+	}
+}
+
+// When a version lookup isn't cached and `--no-update-check` was given.
+#[derive(Debug)]
+pub struct UpdateCheckDisabledError(pub String);
+
+impl std::fmt::Display for UpdateCheckDisabledError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"No cached version information for {}, and --no-update-check was given. Run once without it to cache the latest versions.",
+			self.0
+		)
+	}
+}
+
+// When the file given to `tunnel apply` can't be read or describes an
+// invalid setup.
+#[derive(Debug)]
+pub struct InvalidApplyFile(pub String);
+
+impl std::fmt::Display for InvalidApplyFile {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "Invalid tunnel setup file: {}", self.0)
+	}
+}
+
+// When the CLI's config file has errors and is checked strictly.
+#[derive(Debug)]
+pub struct InvalidConfigError(pub String);
+
+impl std::fmt::Display for InvalidConfigError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"Error in the CLI config file, which is checked strictly because of --strict-config or 'strictConfig': {}",
+			self.0
+		)
+	}
+}
+
 #[derive(Debug)]
 pub struct NoInstallInUserProvidedPath(pub String);
 
@@ -324,6 +521,83 @@ impl std::fmt::Display for CannotForwardControlPort {
 	}
 }
 
+/// A port was to be forwarded to a host that isn't allowed.
+#[derive(Debug)]
+pub struct ForwardTargetNotAllowed(pub String);
+
+impl std::fmt::Display for ForwardTargetNotAllowed {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"Forwarding to {} is not allowed. Allow it with --forward-allow or forwardAllow in config.json.",
+			self.0
+		)
+	}
+}
+
+/// A port was to be forwarded to another host while client addresses are
+/// restricted, which can't be checked for its connections.
+#[derive(Debug)]
+pub struct ForwardTargetNotFilterable(pub String);
+
+impl std::fmt::Display for ForwardTargetNotFilterable {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"Cannot forward to {} while client addresses are restricted with --allow-ip or --deny-ip, since its connections don't carry the client's address.",
+			self.0
+		)
+	}
+}
+
+// When a prompt needs the user's consent, but there's no terminal to ask on.
+#[derive(Debug)]
+pub struct ConsentRequired(pub String);
+
+impl std::fmt::Display for ConsentRequired {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"Can't ask \"{}\" without a terminal, run this command in one to answer",
+			self.0
+		)
+	}
+}
+
+// When a local client asks for the login for a tunnel the host's owner
+// hasn't allowed it to be shared for.
+#[derive(Debug)]
+pub struct SessionShareNotAllowed(pub String);
+
+impl std::fmt::Display for SessionShareNotAllowed {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"Sharing the login for {} wasn't allowed on the host, pass it to --share-session or add it to 'shareSession' in config.json",
+			self.0
+		)
+	}
+}
+
+// When a port asked to be forwarded from the host can't be listened on.
+#[derive(Debug)]
+pub struct InvalidReversePort(pub String);
+
+impl std::fmt::Display for InvalidReversePort {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "Can't forward from the host: {}", self.0)
+	}
+}
+
+#[derive(Debug)]
+pub struct NoReverseForwards();
+
+impl std::fmt::Display for NoReverseForwards {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "No ports were forwarded from the host.")
+	}
+}
+
 #[derive(Debug)]
 pub struct ServerHasClosed();
 
@@ -347,6 +621,124 @@ impl std::fmt::Display for UpdatesNotConfigured {
 		write!(f, "Update service is not configured: {}", self.0)
 	}
 }
+#[derive(Debug)]
+pub struct SettingsSyncError(pub String);
+
+impl std::fmt::Display for SettingsSyncError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "Could not bootstrap from Settings Sync: {}", self.0)
+	}
+}
+
+#[derive(Debug)]
+pub struct InvalidTunnelExpiry(pub String);
+
+impl std::fmt::Display for InvalidTunnelExpiry {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "Invalid expiry for the anonymous tunnel: {}", self.0)
+	}
+}
+
+#[derive(Debug)]
+pub struct ProxyAuthFailed(pub String);
+
+impl std::fmt::Display for ProxyAuthFailed {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "Could not authenticate to the proxy: {}", self.0)
+	}
+}
+
+#[derive(Debug)]
+pub struct ProxyConnectFailed(pub String);
+
+impl std::fmt::Display for ProxyConnectFailed {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "Could not connect through the proxy: {}", self.0)
+	}
+}
+
+// When a backup of the data directory from before a migration can't be found.
+#[derive(Debug)]
+pub struct NoStateBackup(pub String);
+
+impl std::fmt::Display for NoStateBackup {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "Could not restore the data directory: {}", self.0)
+	}
+}
+
+#[derive(Debug)]
+pub struct InvalidTlsConfig(pub String);
+
+impl std::fmt::Display for InvalidTlsConfig {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "Error in the TLS configuration: {}", self.0)
+	}
+}
+
+// When a release's name or commit can't be used as a directory in a mirror.
+#[derive(Debug)]
+pub struct InvalidReleaseName(pub String);
+
+impl std::fmt::Display for InvalidReleaseName {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"The release '{}' can't be mirrored, since its name isn't a valid directory name",
+			self.0
+		)
+	}
+}
+
+#[derive(Debug)]
+pub struct InvalidRecordingRecipient(pub String, pub String);
+
+impl std::fmt::Display for InvalidRecordingRecipient {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"{} can't be used to encrypt session recordings, it must be an age public key: {}",
+			self.0, self.1
+		)
+	}
+}
+
+/// An HTTP request got no connection or response in time, such as behind a
+/// captive portal that holds connections open.
+#[derive(Debug)]
+pub struct RequestTimeoutError {
+	pub url: String,
+	/// What timed out, like "connecting".
+	pub phase: &'static str,
+}
+
+impl std::fmt::Display for RequestTimeoutError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"Request to {} timed out while {}. If this network has a captive portal, sign in to it first, or raise the limit with --http-connect-timeout or --http-read-timeout.",
+			self.url, self.phase
+		)
+	}
+}
+
+impl std::error::Error for RequestTimeoutError {}
+
+/// The CLI's data was copied from another machine, such as in a cloned VM
+/// image, so this machine would host the same tunnel as it.
+#[derive(Debug)]
+pub struct MachineIdentityMismatch(pub String);
+
+impl std::fmt::Display for MachineIdentityMismatch {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"{}. This usually means this machine was cloned from one that hosts the tunnel, and hosting it from both would make them fight over it. To host a separate tunnel from this machine, run `{} tunnel id rotate`. If this is the only machine using the tunnel, run `{} tunnel id accept` instead.",
+			self.0, APPLICATION_NAME, APPLICATION_NAME
+		)
+	}
+}
+
 #[derive(Debug)]
 pub struct ServiceAlreadyRegistered();
 
@@ -372,10 +764,15 @@ impl std::fmt::Display for WindowsNeedsElevation {
 				" 3. Run &'{}' '{}'",
 				exe.display(),
 				std::env::args().skip(1).collect::<Vec<_>>().join("' '")
-			)
+			)?;
 		} else {
-			writeln!(f, " 3. Run the same command again",)
+			writeln!(f, " 3. Run the same command again",)?;
 		}
+		writeln!(f)?;
+		writeln!(
+			f,
+			"Or, run the same command with --elevate to be asked for administrator rights."
+		)
 	}
 }
 
@@ -392,6 +789,39 @@ impl std::fmt::Display for CorruptDownload {
 	}
 }
 
+#[derive(Debug)]
+pub struct ChecksumMismatchError {
+	pub url: String,
+	pub expected: String,
+	pub actual: String,
+}
+
+impl std::fmt::Display for ChecksumMismatchError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"The download from {} was corrupted: expected SHA-256 {}, but got {}. This can happen when a proxy alters or truncates downloads; please try again.",
+			self.url, self.expected, self.actual
+		)
+	}
+}
+
+#[derive(Debug)]
+pub struct SignatureVerificationError {
+	pub url: String,
+	pub reason: String,
+}
+
+impl std::fmt::Display for SignatureVerificationError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"The signature of the download from {} couldn't be verified: {}. It may have been tampered with, so it won't be used.",
+			self.url, self.reason
+		)
+	}
+}
+
 #[derive(Debug)]
 pub struct MissingHomeDirectory();
 
@@ -423,6 +853,24 @@ impl std::fmt::Display for CommandFailed {
 	}
 }
 
+// When an external command doesn't finish in time and is stopped.
+#[derive(Debug)]
+pub struct CommandTimedOut {
+	pub command: String,
+	pub timeout: std::time::Duration,
+}
+
+impl std::fmt::Display for CommandTimedOut {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"Command \"{}\" didn't finish within {} seconds and was stopped",
+			self.command,
+			self.timeout.as_secs()
+		)
+	}
+}
+
 // Makes an "AnyError" enum that contains any of the given errors, in the form
 // `enum AnyError { FooError(FooError) }` (when given `makeAnyError!(FooError)`).
 // Useful to easily deal with application error types without making tons of "From"
@@ -461,10 +909,10 @@ macro_rules! makeAnyError {
 makeAnyError!(
 	MissingLegalConsent,
 	MismatchConnectionToken,
+	ConnectionTokenRequired,
 	DevTunnelError,
 	StatusError,
 	WrappedError,
-	InvalidServerExtensionError,
 	MissingEntrypointError,
 	SetupError,
 	NoHomeForLauncherError,
@@ -473,25 +921,81 @@ makeAnyError!(
 	InvalidTunnelName,
 	ExtensionInstallFailed,
 	MismatchedLaunchModeError,
+	InvalidServerArchive,
+	SmtpError,
+	InvalidWorkspacePolicy,
+	SandboxUnavailable,
+	MismatchedJail,
 	NoAttachedServerError,
+	NoInstalledServerError,
 	ServerWriteError,
 	UnsupportedPlatformError,
+	NoBuildsForPlatformError,
+	InvalidConfigError,
+	InvalidApplyFile,
+	UpdateCheckDisabledError,
+	NoServerToRollBackTo,
+	ConflictingServerSelection,
 	RefreshTokenNotAvailableError,
+	AuthScopesNotGranted,
 	NoInstallInUserProvidedPath,
 	UserCancelledInstallation,
 	InvalidRequestedVersion,
 	CannotForwardControlPort,
+	ForwardTargetNotAllowed,
+	ForwardTargetNotFilterable,
+	ConsentRequired,
+	SessionShareNotAllowed,
+	InvalidReversePort,
+	NoReverseForwards,
 	ServerHasClosed,
 	ServiceAlreadyRegistered,
 	WindowsNeedsElevation,
 	UpdatesNotConfigured,
+	SettingsSyncError,
+	InvalidTunnelExpiry,
+	ProxyAuthFailed,
+	ProxyConnectFailed,
+	InvalidTlsConfig,
+	InvalidReleaseName,
+	InvalidRecordingRecipient,
+	RequestTimeoutError,
+	NoStateBackup,
 	CorruptDownload,
+	ChecksumMismatchError,
+	SignatureVerificationError,
 	MissingHomeDirectory,
-	CommandFailed
+	CommandFailed,
+	CommandTimedOut,
+	MachineIdentityMismatch
 );
 
+impl AnyError {
+	/// Whether the operation that failed may succeed if it's tried again,
+	/// such as after a timeout or a 503 response.
+	pub fn is_retryable(&self) -> bool {
+		match self {
+			AnyError::StatusError(e) => e.is_retryable(),
+			AnyError::WrappedError(e) => e.is_retryable(),
+			AnyError::RequestTimeoutError(_) => true,
+			_ => false,
+		}
+	}
+}
+
 impl From<reqwest::Error> for AnyError {
 	fn from(e: reqwest::Error) -> AnyError {
+		if e.is_timeout() {
+			return AnyError::RequestTimeoutError(RequestTimeoutError {
+				url: e.url().map_or("<unknown>", |u| u.as_str()).to_string(),
+				phase: if e.is_connect() {
+					"connecting"
+				} else {
+					"waiting for a response"
+				},
+			});
+		}
+
 		AnyError::WrappedError(WrappedError::from(e))
 	}
 }
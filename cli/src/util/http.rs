@@ -4,19 +4,31 @@
  *--------------------------------------------------------------------------------------------*/
 use crate::{
 	constants::get_default_user_agent,
-	log,
+	debug, log,
 	util::errors::{self, WrappedError},
 };
 use async_trait::async_trait;
 use core::panic;
 use futures::stream::TryStreamExt;
 use hyper::{
-	header::{HeaderName, CONTENT_LENGTH},
+	header::{HeaderName, CONTENT_LENGTH, RETRY_AFTER},
 	http::HeaderValue,
 	HeaderMap, StatusCode,
 };
+use lazy_static::lazy_static;
 use serde::de::DeserializeOwned;
-use std::{io, pin::Pin, str::FromStr, task::Poll};
+use std::{
+	future::Future,
+	io,
+	pin::Pin,
+	str::FromStr,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		RwLock,
+	},
+	task::Poll,
+	time::{Duration, Instant},
+};
 use tokio::{
 	fs,
 	io::{AsyncRead, AsyncReadExt},
@@ -25,8 +37,11 @@ use tokio::{
 use tokio_util::compat::FuturesAsyncReadCompatExt;
 
 use super::{
-	errors::{wrap, AnyError, StatusError},
-	io::{copy_async_progress, ReadBuffer, ReportCopyProgress},
+	errors::{wrap, AnyError, RequestTimeoutError, StatusError},
+	io::{copy_async_progress, ReadBuffer},
+	progress::ReportProgress,
+	proxy::{apply_proxy, has_proxy_auth_helper, PROXY_AUTH_LIFETIME},
+	tls::apply_tls,
 };
 
 pub async fn download_into_file<T>(
@@ -35,7 +50,7 @@ pub async fn download_into_file<T>(
 	mut res: SimpleResponse,
 ) -> Result<fs::File, WrappedError>
 where
-	T: ReportCopyProgress,
+	T: ReportProgress,
 {
 	let mut file = fs::File::create(filename)
 		.await
@@ -50,7 +65,7 @@ where
 
 	copy_async_progress(progress, &mut res.read, &mut file, content_length)
 		.await
-		.map_err(|e| errors::wrap(e, "failed to download file"))?;
+		.map_err(|e| errors::wrap_read(e, "failed to download file"))?;
 
 	Ok(file)
 }
@@ -94,7 +109,7 @@ impl SimpleResponse {
 		self.read
 			.read_to_end(&mut buf)
 			.await
-			.map_err(|e| wrap(e, "error reading response"))?;
+			.map_err(|e| errors::wrap_read(e, "error reading response"))?;
 
 		let t = serde_json::from_slice(&buf)
 			.map_err(|e| wrap(e, format!("error decoding json from {}", self.url)))?;
@@ -114,6 +129,92 @@ pub trait SimpleHttp {
 		method: &'static str,
 		url: String,
 	) -> Result<SimpleResponse, AnyError>;
+
+	/// Makes a request with additional headers, such as for conditional
+	/// requests. Implementations that cannot forward headers may ignore them.
+	async fn make_request_with_headers(
+		&self,
+		method: &'static str,
+		url: String,
+		_headers: HeaderMap,
+	) -> Result<SimpleResponse, AnyError> {
+		self.make_request(method, url).await
+	}
+}
+
+/// How long idle connections are kept open for reuse.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// Seconds to wait for a connection to be established, by default.
+pub const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 15;
+/// Seconds to wait for a response, or for more of its body, by default.
+pub const DEFAULT_READ_TIMEOUT_SECS: u64 = 60;
+
+static CONNECT_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(DEFAULT_CONNECT_TIMEOUT_SECS);
+static READ_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(DEFAULT_READ_TIMEOUT_SECS);
+
+/// Sets how long HTTP requests wait to connect, and for a response or more
+/// of its body once connected, in seconds. 0 waits indefinitely. Like the
+/// proxy, they must be set before the shared client is built.
+pub fn set_http_timeouts(connect_secs: u64, read_secs: u64) {
+	CONNECT_TIMEOUT_SECS.store(connect_secs, Ordering::SeqCst);
+	READ_TIMEOUT_SECS.store(read_secs, Ordering::SeqCst);
+}
+
+fn timeout_from_secs(secs: &AtomicU64) -> Option<Duration> {
+	match secs.load(Ordering::SeqCst) {
+		0 => None,
+		s => Some(Duration::from_secs(s)),
+	}
+}
+
+lazy_static! {
+	static ref SHARED_CLIENT: RwLock<(Instant, reqwest::Client)> =
+		RwLock::new((Instant::now(), build_shared_client()));
+}
+
+fn build_shared_client() -> reqwest::Client {
+	new_client_builder()
+		.build()
+		.expect("expected to build http client")
+}
+
+/// Creates a reqwest client builder with the CLI's user agent, proxy, TLS,
+/// and connect timeout settings.
+/// Connections are pooled, and HTTP/2 is used where the server supports it,
+/// so requests to the same host share a connection rather than each paying
+/// for a TCP and TLS handshake.
+pub fn new_client_builder() -> reqwest::ClientBuilder {
+	let mut builder = reqwest::ClientBuilder::new()
+		.user_agent(get_default_user_agent())
+		.pool_idle_timeout(POOL_IDLE_TIMEOUT)
+		.tcp_keepalive(TCP_KEEPALIVE)
+		.http2_adaptive_window(true);
+	if let Some(timeout) = timeout_from_secs(&CONNECT_TIMEOUT_SECS) {
+		builder = builder.connect_timeout(timeout);
+	}
+
+	apply_tls(apply_proxy(builder))
+}
+
+/// Gets the client shared across the process, so its connection pool is
+/// shared too. It's built on first use, so the proxy and TLS settings must
+/// be configured before then, and rebuilt once the token from a proxy auth
+/// helper is due to expire.
+pub fn shared_client() -> reqwest::Client {
+	{
+		let shared = SHARED_CLIENT.read().unwrap();
+		if !has_proxy_auth_helper() || shared.0.elapsed() < PROXY_AUTH_LIFETIME {
+			return shared.1.clone();
+		}
+	}
+
+	let mut shared = SHARED_CLIENT.write().unwrap();
+	if shared.0.elapsed() >= PROXY_AUTH_LIFETIME {
+		*shared = (Instant::now(), build_shared_client());
+	}
+	shared.1.clone()
 }
 
 // Implementation of SimpleHttp that uses a reqwest client.
@@ -125,10 +226,7 @@ pub struct ReqwestSimpleHttp {
 impl ReqwestSimpleHttp {
 	pub fn new() -> Self {
 		Self {
-			client: reqwest::ClientBuilder::new()
-				.user_agent(get_default_user_agent())
-				.build()
-				.unwrap(),
+			client: shared_client(),
 		}
 	}
 
@@ -150,26 +248,242 @@ impl SimpleHttp for ReqwestSimpleHttp {
 		method: &'static str,
 		url: String,
 	) -> Result<SimpleResponse, AnyError> {
-		let res = self
+		self.make_request_with_headers(method, url, HeaderMap::new())
+			.await
+	}
+
+	async fn make_request_with_headers(
+		&self,
+		method: &'static str,
+		url: String,
+		headers: HeaderMap,
+	) -> Result<SimpleResponse, AnyError> {
+		let send = self
 			.client
 			.request(reqwest::Method::try_from(method).unwrap(), &url)
-			.send()
-			.await?;
+			.headers(headers)
+			.send();
+
+		// reqwest only limits the whole request, which would cut off long
+		// downloads, so waits for the response and its body are limited here
+		let read_timeout = timeout_from_secs(&READ_TIMEOUT_SECS);
+		let res = match read_timeout {
+			Some(t) => tokio::time::timeout(t, send)
+				.await
+				.map_err(|_| RequestTimeoutError {
+					url: url.clone(),
+					phase: "waiting for a response",
+				})??,
+			None => send.await?,
+		};
+
+		let status_code = res.status();
+		let headers = res.headers().clone();
+		let body: Pin<Box<dyn Send + AsyncRead + 'static>> = Box::pin(
+			res.bytes_stream()
+				.map_err(|e| futures::io::Error::new(futures::io::ErrorKind::Other, e))
+				.into_async_read()
+				.compat(),
+		);
 
 		Ok(SimpleResponse {
-			status_code: res.status(),
-			headers: res.headers().clone(),
+			status_code,
+			headers,
 			url,
-			read: Box::pin(
-				res.bytes_stream()
-					.map_err(|e| futures::io::Error::new(futures::io::ErrorKind::Other, e))
-					.into_async_read()
-					.compat(),
-			),
+			read: match read_timeout {
+				Some(t) => Box::pin(ReadTimeout::new(body, url.clone(), t)),
+				None => body,
+			},
 		})
 	}
 }
 
+/// Fails reads of a response body when none of it arrives within the
+/// timeout, so a stalled connection doesn't hang the CLI.
+struct ReadTimeout {
+	inner: Pin<Box<dyn Send + AsyncRead + 'static>>,
+	url: String,
+	timeout: Duration,
+	deadline: Pin<Box<tokio::time::Sleep>>,
+	/// Whether data arrived since the deadline was last set. The deadline's
+	/// only moved when a read has to wait, rather than on every read.
+	progressed: bool,
+}
+
+impl ReadTimeout {
+	fn new(
+		inner: Pin<Box<dyn Send + AsyncRead + 'static>>,
+		url: String,
+		timeout: Duration,
+	) -> Self {
+		ReadTimeout {
+			inner,
+			url,
+			timeout,
+			deadline: Box::pin(tokio::time::sleep(timeout)),
+			progressed: false,
+		}
+	}
+}
+
+impl AsyncRead for ReadTimeout {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut std::task::Context<'_>,
+		buf: &mut tokio::io::ReadBuf<'_>,
+	) -> std::task::Poll<std::io::Result<()>> {
+		let this = &mut *self;
+		match this.inner.as_mut().poll_read(cx, buf) {
+			Poll::Ready(r) => {
+				this.progressed = true;
+				Poll::Ready(r)
+			}
+			Poll::Pending => {
+				if this.progressed {
+					this.progressed = false;
+					let next = tokio::time::Instant::now() + this.timeout;
+					this.deadline.as_mut().reset(next);
+				}
+
+				match this.deadline.as_mut().poll(cx) {
+					Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+						io::ErrorKind::TimedOut,
+						RequestTimeoutError {
+							url: this.url.clone(),
+							phase: "waiting for more of the response",
+						},
+					))),
+					Poll::Pending => Poll::Pending,
+				}
+			}
+		}
+	}
+}
+
+/// How requests that fail transiently, such as with a 503 response or a
+/// failed DNS lookup, are retried.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+	/// Number of times the request is made before giving up.
+	pub attempts: u32,
+	/// Delay before the first retry, doubled for each one after.
+	pub base_delay: Duration,
+	/// Longest delay between attempts.
+	pub max_delay: Duration,
+	/// Whether delays are randomized, so that clients which failed together
+	/// don't retry together.
+	pub jitter: bool,
+}
+
+/// Number of attempts made by the default retry policy.
+pub const DEFAULT_RETRY_ATTEMPTS: u32 = 4;
+
+/// Longest `Retry-After` that's waited for. If a server asks for a longer
+/// wait, its response is returned instead.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(120);
+
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		RetryPolicy {
+			attempts: DEFAULT_RETRY_ATTEMPTS,
+			base_delay: Duration::from_millis(500),
+			max_delay: Duration::from_secs(30),
+			jitter: true,
+		}
+	}
+}
+
+impl RetryPolicy {
+	/// Gets the delay before the given retry, starting from 1. With jitter, a
+	/// random delay up to the backoff is used.
+	pub fn backoff(&self, retry: u32) -> Duration {
+		let backoff = self
+			.base_delay
+			.saturating_mul(1 << retry.saturating_sub(1).min(16))
+			.min(self.max_delay);
+		if self.jitter {
+			backoff.mul_f64(rand::random::<f64>())
+		} else {
+			backoff
+		}
+	}
+}
+
+/// Makes a request, retrying as the policy allows when it fails in a way that
+/// may be transient. Responses with retryable status codes, like 429 or 503,
+/// are returned once attempts run out, so callers handle them like any other
+/// unsuccessful response. A `Retry-After` header is honored in place of the
+/// policy's delay.
+pub async fn make_request_with_retry(
+	http: &(dyn SimpleHttp + Send + Sync),
+	log: &log::Logger,
+	policy: &RetryPolicy,
+	method: &'static str,
+	url: String,
+	headers: HeaderMap,
+) -> Result<SimpleResponse, AnyError> {
+	let mut attempt = 1;
+	loop {
+		let result = http
+			.make_request_with_headers(method, url.clone(), headers.clone())
+			.await;
+		let last_attempt = attempt >= policy.attempts;
+		let delay = match &result {
+			Ok(res) if !last_attempt && errors::is_retryable_status(res.status_code.as_u16()) => {
+				match retry_after(&res.headers, chrono::Utc::now()) {
+					Some(d) if d > MAX_RETRY_AFTER => return result,
+					Some(d) => d,
+					None => policy.backoff(attempt),
+				}
+			}
+			Err(e) if !last_attempt && e.is_retryable() => policy.backoff(attempt),
+			_ => return result,
+		};
+
+		match &result {
+			Ok(res) => debug!(
+				log,
+				"{} {} returned {}, retrying in {}ms ({}/{})",
+				method,
+				url,
+				res.status_code,
+				delay.as_millis(),
+				attempt,
+				policy.attempts - 1
+			),
+			Err(e) => debug!(
+				log,
+				"{} {} failed, retrying in {}ms ({}/{}): {}",
+				method,
+				url,
+				delay.as_millis(),
+				attempt,
+				policy.attempts - 1,
+				e
+			),
+		}
+
+		tokio::time::sleep(delay).await;
+		attempt += 1;
+	}
+}
+
+/// Parses a `Retry-After` header, which is either a number of seconds or an
+/// HTTP date.
+fn retry_after(headers: &HeaderMap, now: chrono::DateTime<chrono::Utc>) -> Option<Duration> {
+	let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+	if let Ok(secs) = value.parse::<u64>() {
+		return Some(Duration::from_secs(secs));
+	}
+
+	let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+	Some(
+		(at.with_timezone(&chrono::Utc) - now)
+			.to_std()
+			.unwrap_or(Duration::ZERO),
+	)
+}
+
 enum DelegatedHttpEvent {
 	InitResponse {
 		status_code: u16,
@@ -351,7 +665,20 @@ impl SimpleHttp for FallbackSimpleHttp {
 		method: &'static str,
 		url: String,
 	) -> Result<SimpleResponse, AnyError> {
-		let r1 = self.native.make_request(method, url.clone()).await;
+		self.make_request_with_headers(method, url, HeaderMap::new())
+			.await
+	}
+
+	async fn make_request_with_headers(
+		&self,
+		method: &'static str,
+		url: String,
+		headers: HeaderMap,
+	) -> Result<SimpleResponse, AnyError> {
+		let r1 = self
+			.native
+			.make_request_with_headers(method, url.clone(), headers)
+			.await;
 		if let Ok(res) = r1 {
 			if !res.status_code.is_server_error() {
 				return Ok(res);
@@ -361,3 +688,75 @@ impl SimpleHttp for FallbackSimpleHttp {
 		self.delegated.make_request(method, url).await
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_backoff() {
+		let policy = RetryPolicy {
+			attempts: 5,
+			base_delay: Duration::from_secs(1),
+			max_delay: Duration::from_secs(3),
+			jitter: false,
+		};
+		assert_eq!(policy.backoff(1), Duration::from_secs(1));
+		assert_eq!(policy.backoff(2), Duration::from_secs(2));
+		assert_eq!(policy.backoff(3), Duration::from_secs(3));
+		assert_eq!(policy.backoff(40), Duration::from_secs(3));
+
+		let jittered = RetryPolicy {
+			jitter: true,
+			..policy
+		};
+		assert!(jittered.backoff(2) <= Duration::from_secs(2));
+	}
+
+	#[test]
+	fn test_retry_after() {
+		let now = chrono::DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+			.unwrap()
+			.with_timezone(&chrono::Utc);
+		let mut headers = HeaderMap::new();
+		assert_eq!(retry_after(&headers, now), None);
+
+		headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+		assert_eq!(retry_after(&headers, now), Some(Duration::from_secs(120)));
+
+		headers.insert(
+			RETRY_AFTER,
+			HeaderValue::from_static("Wed, 21 Oct 2015 07:28:30 GMT"),
+		);
+		assert_eq!(retry_after(&headers, now), Some(Duration::from_secs(30)));
+
+		headers.insert(
+			RETRY_AFTER,
+			HeaderValue::from_static("Wed, 21 Oct 2015 07:27:00 GMT"),
+		);
+		assert_eq!(retry_after(&headers, now), Some(Duration::ZERO));
+	}
+
+	#[tokio::test]
+	async fn test_read_timeout() {
+		let (mut tx, rx) = tokio::io::duplex(64);
+		let mut reader = ReadTimeout::new(
+			Box::pin(rx),
+			"https://example.com".to_string(),
+			Duration::from_millis(50),
+		);
+
+		tokio::io::AsyncWriteExt::write_all(&mut tx, b"hello")
+			.await
+			.unwrap();
+		let mut buf = [0; 5];
+		reader.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"hello");
+
+		// the writer is still open, but nothing more arrives
+		let err = reader.read(&mut buf).await.unwrap_err();
+		let err = errors::wrap_read(err, "error reading response");
+		assert!(matches!(err, AnyError::RequestTimeoutError(_)));
+		assert!(err.is_retryable());
+	}
+}
@@ -3,54 +3,223 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 use crate::util::errors::wrap;
-use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
-use indicatif::ProgressBar;
-use std::fmt::Display;
+use dialoguer::{
+	theme::{ColorfulTheme, SimpleTheme, Theme},
+	Confirm, Input, Select,
+};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::{
+	fmt::Display,
+	io::{BufRead, Write},
+	sync::atomic::{AtomicBool, Ordering},
+};
 
-use super::{errors::WrappedError, io::ReportCopyProgress};
+use super::{
+	errors::{AnyError, ConsentRequired, WrappedError},
+	plain::is_plain_output,
+	progress::{ProgressStage, ReportProgress},
+};
 
-/// Wrapper around indicatif::ProgressBar that implements ReportCopyProgress.
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+/// Makes prompts return their defaults without asking, and confirmations
+/// answer yes, for commands run unattended with `--yes`.
+pub fn set_assume_yes(yes: bool) {
+	ASSUME_YES.store(yes, Ordering::SeqCst);
+}
+
+/// Gets whether prompts are answered with their defaults.
+pub fn is_assume_yes() -> bool {
+	ASSUME_YES.load(Ordering::SeqCst)
+}
+
+/// Wrapper around indicatif::ProgressBar that implements ReportProgress.
+/// In plain output mode, the bar is hidden and progress is instead printed as
+/// sequential lines every 10%. Each stage restarts the bar, labelled with the
+/// stage, and indeterminate stages show a spinner.
 pub struct ProgressBarReporter {
 	bar: ProgressBar,
 	has_set_total: bool,
+	last_plain_percent: Option<u64>,
+	depth: usize,
 }
 
 impl From<ProgressBar> for ProgressBarReporter {
 	fn from(bar: ProgressBar) -> Self {
+		if is_plain_output() {
+			bar.set_draw_target(ProgressDrawTarget::hidden());
+		}
+
 		ProgressBarReporter {
 			bar,
 			has_set_total: false,
+			last_plain_percent: None,
+			depth: 0,
 		}
 	}
 }
 
-impl ReportCopyProgress for ProgressBarReporter {
+impl ProgressBarReporter {
+	fn report_plain(&mut self, bytes_so_far: u64, total_bytes: u64) {
+		if total_bytes == 0 {
+			return;
+		}
+
+		let percent = (bytes_so_far * 100 / total_bytes) / 10 * 10;
+		if self.last_plain_percent != Some(percent) {
+			self.last_plain_percent = Some(percent);
+			println!("{}% complete", percent);
+		}
+	}
+}
+
+impl ReportProgress for ProgressBarReporter {
 	fn report_progress(&mut self, bytes_so_far: u64, total_bytes: u64) {
+		if is_plain_output() {
+			self.report_plain(bytes_so_far, total_bytes);
+		}
+
 		if !self.has_set_total {
+			self.bar.set_style(ProgressStyle::default_bar());
 			self.bar.set_length(total_bytes);
+			self.has_set_total = true;
 		}
 
-		if bytes_so_far == total_bytes {
+		// within stages, the bar is cleared once the outermost one ends
+		if bytes_so_far == total_bytes && self.depth == 0 {
 			self.bar.finish_and_clear();
 		} else {
 			self.bar.set_position(bytes_so_far);
 		}
 	}
+
+	fn report_indeterminate(&mut self) {
+		self.bar.set_style(ProgressStyle::default_spinner());
+		self.bar.enable_steady_tick(100);
+		self.has_set_total = false;
+	}
+
+	fn begin_stage(&mut self, stage: ProgressStage) {
+		self.depth += 1;
+		self.has_set_total = false;
+		self.last_plain_percent = None;
+		self.bar.reset();
+		self.bar.set_message(format!("{}...", stage));
+		if is_plain_output() {
+			println!("{}...", stage);
+		}
+	}
+
+	fn end_stage(&mut self) {
+		self.depth = self.depth.saturating_sub(1);
+		self.bar.disable_steady_tick();
+		if self.depth == 0 {
+			self.bar.finish_and_clear();
+		}
+	}
+}
+
+/// Gets the theme to use for interactive prompts.
+pub fn prompt_theme() -> Box<dyn Theme> {
+	if is_plain_output() {
+		Box::new(SimpleTheme)
+	} else {
+		Box::new(ColorfulTheme::default())
+	}
+}
+
+/// Reads a line of input after printing the prompt, used in plain output mode
+/// in place of prompts that redraw the terminal.
+fn read_plain_line(prompt: &str) -> Result<String, WrappedError> {
+	print!("{}: ", prompt);
+	std::io::stdout()
+		.flush()
+		.map_err(|e| wrap(e, "Failed to write prompt"))?;
+
+	let mut line = String::new();
+	std::io::stdin()
+		.lock()
+		.read_line(&mut line)
+		.map_err(|e| wrap(e, "Failed to read input"))?;
+
+	Ok(line.trim().to_string())
 }
 
 pub fn prompt_yn(text: &str) -> Result<bool, WrappedError> {
-	Confirm::with_theme(&ColorfulTheme::default())
+	if is_assume_yes() {
+		return Ok(true);
+	}
+
+	if is_plain_output() {
+		loop {
+			let answer = read_plain_line(&format!("{} (yes or no, default yes)", text))?;
+			match answer.to_lowercase().as_str() {
+				"" | "y" | "yes" => return Ok(true),
+				"n" | "no" => return Ok(false),
+				_ => println!("Please answer yes or no."),
+			}
+		}
+	}
+
+	Confirm::with_theme(prompt_theme().as_ref())
 		.with_prompt(text)
 		.default(true)
 		.interact()
 		.map_err(|e| wrap(e, "Failed to read confirm input"))
 }
 
+/// Asks the user to allow something that opens up their machine or account.
+/// Unlike other prompts, it isn't answered by `--yes`, defaults to no, and
+/// fails if stdin isn't a terminal, so consent can't be given by accident.
+pub fn prompt_consent(text: &str) -> Result<bool, AnyError> {
+	if !atty::is(atty::Stream::Stdin) {
+		return Err(ConsentRequired(text.to_string()).into());
+	}
+
+	if is_plain_output() {
+		loop {
+			let answer = read_plain_line(&format!("{} (yes or no, default no)", text))?;
+			match answer.to_lowercase().as_str() {
+				"y" | "yes" => return Ok(true),
+				"" | "n" | "no" => return Ok(false),
+				_ => println!("Please answer yes or no."),
+			}
+		}
+	}
+
+	Confirm::with_theme(prompt_theme().as_ref())
+		.with_prompt(text)
+		.default(false)
+		.interact()
+		.map_err(|e| wrap(e, "Failed to read confirm input").into())
+}
+
 pub fn prompt_options<T>(text: impl Into<String>, options: &[T]) -> Result<T, WrappedError>
 where
 	T: Display + Copy,
 {
-	let chosen = Select::with_theme(&ColorfulTheme::default())
+	if is_assume_yes() {
+		return Ok(options[0]);
+	}
+
+	if is_plain_output() {
+		println!("{}", text.into());
+		for (i, option) in options.iter().enumerate() {
+			println!("{}. {}", i + 1, option);
+		}
+		loop {
+			let answer = read_plain_line("Enter a number, default 1")?;
+			if answer.is_empty() {
+				return Ok(options[0]);
+			}
+			match answer.parse::<usize>() {
+				Ok(n) if (1..=options.len()).contains(&n) => return Ok(options[n - 1]),
+				_ => println!("Please enter a number from 1 to {}.", options.len()),
+			}
+		}
+	}
+
+	let chosen = Select::with_theme(prompt_theme().as_ref())
 		.with_prompt(text)
 		.items(options)
 		.default(0)
@@ -61,7 +230,20 @@ where
 }
 
 pub fn prompt_placeholder(question: &str, placeholder: &str) -> Result<String, WrappedError> {
-	Input::with_theme(&ColorfulTheme::default())
+	if is_assume_yes() {
+		return Ok(placeholder.to_string());
+	}
+
+	if is_plain_output() {
+		let answer = read_plain_line(&format!("{} (default {})", question, placeholder))?;
+		return Ok(if answer.is_empty() {
+			placeholder.to_string()
+		} else {
+			answer
+		});
+	}
+
+	Input::with_theme(prompt_theme().as_ref())
 		.with_prompt(question)
 		.default(placeholder.to_string())
 		.interact_text()
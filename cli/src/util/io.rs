@@ -5,25 +5,38 @@
 use std::{
 	fs::File,
 	io::{self, BufRead, Seek},
+	pin::Pin,
 	task::Poll,
 	time::Duration,
 };
 
+use sha2::{Digest, Sha256};
+
 use tokio::{
 	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
 	sync::mpsc,
 	time::sleep,
 };
 
-pub trait ReportCopyProgress {
-	fn report_progress(&mut self, bytes_so_far: u64, total_bytes: u64);
-}
+use super::progress::ReportProgress;
 
-/// Type that doesn't emit anything for download progress.
-pub struct SilentCopyProgress();
+/// A connection that can be read and written, like a TCP stream or a TLS
+/// session over one, boxed when what it is depends on the configuration.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
 
-impl ReportCopyProgress for SilentCopyProgress {
-	fn report_progress(&mut self, _bytes_so_far: u64, _total_bytes: u64) {}
+impl<T> AsyncStream for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+/// Makes the file or directory accessible only by its owner, given the
+/// owner's permissions as a Unix mode, like 0o600. Does nothing elsewhere,
+/// where new files already inherit their parent's ACL.
+pub fn restrict_to_owner(path: &std::path::Path, _mode: u32) -> io::Result<()> {
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::PermissionsExt;
+		std::fs::set_permissions(path, std::fs::Permissions::from_mode(_mode))?;
+	}
+
+	Ok(())
 }
 
 /// Copies from the reader to the writer, reporting progress to the provided
@@ -37,7 +50,7 @@ pub async fn copy_async_progress<T, R, W>(
 where
 	R: AsyncRead + Unpin,
 	W: AsyncWrite + Unpin,
-	T: ReportCopyProgress,
+	T: ReportProgress,
 {
 	let mut buf = vec![0; 8 * 1024];
 	let mut bytes_so_far = 0;
@@ -67,6 +80,62 @@ where
 	Ok(bytes_so_far)
 }
 
+/// Writer that computes the SHA-256 digest of everything written through it.
+pub struct Sha256Writer<W> {
+	inner: W,
+	hasher: Sha256,
+}
+
+impl<W> Sha256Writer<W> {
+	pub fn new(inner: W) -> Self {
+		Sha256Writer {
+			inner,
+			hasher: Sha256::new(),
+		}
+	}
+
+	/// Hashes the data as though it had been written, such as for content
+	/// the inner writer already holds.
+	pub fn update(&mut self, data: &[u8]) {
+		self.hasher.update(data);
+	}
+
+	pub fn get_mut(&mut self) -> &mut W {
+		&mut self.inner
+	}
+
+	/// Returns the inner writer and the lowercase hex digest of what was written.
+	pub fn finish(self) -> (W, String) {
+		(self.inner, format!("{:x}", self.hasher.finalize()))
+	}
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Sha256Writer<W> {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut std::task::Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		let r = Pin::new(&mut this.inner).poll_write(cx, buf);
+		if let Poll::Ready(Ok(n)) = r {
+			this.hasher.update(&buf[..n]);
+		}
+		r
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(
+		self: Pin<&mut Self>,
+		cx: &mut std::task::Context<'_>,
+	) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+	}
+}
+
 /// Helper used when converting Future interfaces to poll-based interfaces.
 /// Stores excess data that can be reused on future polls.
 #[derive(Default)]
@@ -366,4 +435,17 @@ mod tests {
 			unreachable!("expect a line event, got {:?}", recv)
 		}
 	}
+
+	#[tokio::test]
+	async fn test_sha256_writer() {
+		let mut w = Sha256Writer::new(Vec::new());
+		w.write_all(b"hello ").await.unwrap();
+		w.write_all(b"world").await.unwrap();
+		let (inner, digest) = w.finish();
+		assert_eq!(inner, b"hello world");
+		assert_eq!(
+			digest,
+			"b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+		);
+	}
 }
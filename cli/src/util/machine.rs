@@ -3,8 +3,9 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
+use serde::Serialize;
 use std::path::Path;
-use sysinfo::{Pid, PidExt, ProcessExt, System, SystemExt};
+use sysinfo::{DiskExt, Pid, PidExt, ProcessExt, RefreshKind, System, SystemExt};
 
 pub fn process_at_path_exists(pid: u32, name: &Path) -> bool {
 	// TODO https://docs.rs/sysinfo/latest/sysinfo/index.html#usage
@@ -49,3 +50,45 @@ pub fn find_running_process(name: &Path) -> Option<u32> {
 	}
 	None
 }
+
+/// Snapshot of the host's resource usage. Sizes are in bytes.
+#[derive(Serialize, Debug, Clone)]
+pub struct HostResources {
+	pub cpus: usize,
+	/// Load averages over 1, 5, and 15 minutes. Always zero on Windows.
+	pub load_average: [f64; 3],
+	pub total_memory: u64,
+	pub available_memory: u64,
+	/// Size of the volume containing the workspace, if it could be found.
+	pub disk_total: Option<u64>,
+	pub disk_available: Option<u64>,
+}
+
+/// Gets a snapshot of resource usage, including the volume the workspace is on.
+pub fn get_host_resources(workspace: &Path) -> HostResources {
+	let mut sys = System::new_with_specifics(
+		RefreshKind::new()
+			.with_cpu()
+			.with_memory()
+			.with_disks_list(),
+	);
+	sys.refresh_disks();
+
+	// the workspace is on the disk with the longest mount point containing it
+	let disk = sys
+		.disks()
+		.iter()
+		.filter(|d| workspace.starts_with(d.mount_point()))
+		.max_by_key(|d| d.mount_point().as_os_str().len());
+	let load = sys.load_average();
+
+	// sysinfo reports memory in KB
+	HostResources {
+		cpus: sys.processors().len(),
+		load_average: [load.one, load.five, load.fifteen],
+		total_memory: sys.total_memory() * 1024,
+		available_memory: sys.available_memory() * 1024,
+		disk_total: disk.map(|d| d.total_space()),
+		disk_available: disk.map(|d| d.available_space()),
+	}
+}
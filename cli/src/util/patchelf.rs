@@ -0,0 +1,116 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Fixes up downloaded server binaries on distributions without the usual
+//! filesystem layout, like NixOS, where the ELF interpreter and libraries the
+//! binaries are linked against aren't at the paths they expect.
+
+use std::path::Path;
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+
+use super::command::{capture_command, capture_command_and_check_status};
+use super::errors::{AnyError, SetupError};
+use crate::{log, options::ServerBinaryFixup, trace, warning};
+
+lazy_static! {
+	static ref BINARY_FIXUP: RwLock<ServerBinaryFixup> = RwLock::new(ServerBinaryFixup::Auto);
+}
+
+/// Sets whether downloaded server binaries are patched to run on this system.
+pub fn set_binary_fixup(fixup: ServerBinaryFixup) {
+	*BINARY_FIXUP.write().unwrap() = fixup;
+}
+
+/// Interpreter server binaries are built to load with.
+const STANDARD_INTERPRETER: &str = if cfg!(target_arch = "aarch64") {
+	"/lib/ld-linux-aarch64.so.1"
+} else if cfg!(target_arch = "arm") {
+	"/lib/ld-linux-armhf.so.3"
+} else {
+	"/lib64/ld-linux-x86-64.so.2"
+};
+
+/// Binaries in the server, relative to its directory, that are patched.
+const SERVER_BINARIES: [&str; 1] = ["node"];
+
+/// System binary whose interpreter is known to work on this machine.
+const REFERENCE_BINARY: &str = "/bin/sh";
+
+/// Gets whether binaries built for common distributions can't run here as-is
+/// because their interpreter is missing.
+pub fn needs_fixup() -> bool {
+	cfg!(target_os = "linux") && !Path::new(STANDARD_INTERPRETER).exists()
+}
+
+/// Patches the interpreter and library path of the server's binaries using
+/// `patchelf`, if it's needed and enabled.
+pub async fn fixup_server_binaries(log: &log::Logger, server_dir: &Path) -> Result<(), AnyError> {
+	let fixup = *BINARY_FIXUP.read().unwrap();
+	match fixup {
+		ServerBinaryFixup::None => return Ok(()),
+		ServerBinaryFixup::Auto if !needs_fixup() => return Ok(()),
+		_ => {}
+	}
+
+	match patch_binaries(log, server_dir).await {
+		Ok(()) => Ok(()),
+		Err(e) if fixup == ServerBinaryFixup::Auto => {
+			warning!(
+				log,
+				"{} is missing, so the server may not run. Install patchelf, or nix-ld on NixOS, to fix this: {}",
+				STANDARD_INTERPRETER,
+				e
+			);
+			Ok(())
+		}
+		Err(e) => Err(e),
+	}
+}
+
+async fn patch_binaries(log: &log::Logger, server_dir: &Path) -> Result<(), AnyError> {
+	let interpreter = find_interpreter().await?;
+	// nix-ld sets the library path binaries from other distros should use
+	let library_path = std::env::var("NIX_LD_LIBRARY_PATH").ok();
+
+	for binary in SERVER_BINARIES {
+		let path = server_dir.join(binary);
+		if !path.exists() {
+			continue;
+		}
+
+		let path = path.to_string_lossy().to_string();
+		let mut args = vec!["--set-interpreter", interpreter.as_str()];
+		if let Some(p) = &library_path {
+			args.extend(["--force-rpath", "--set-rpath", p.as_str()]);
+		}
+		args.push(path.as_str());
+
+		trace!(log, "Patching {} to use {}", path, interpreter);
+		capture_command_and_check_status("patchelf", &args).await?;
+	}
+
+	Ok(())
+}
+
+/// Finds the ELF interpreter binaries on this system use.
+async fn find_interpreter() -> Result<String, AnyError> {
+	if let Ok(i) = std::env::var("NIX_LD") {
+		return Ok(i);
+	}
+
+	let output = capture_command("patchelf", ["--print-interpreter", REFERENCE_BINARY]).await?;
+	let interpreter = String::from_utf8_lossy(&output.stdout).trim().to_string();
+	if !output.status.success() || interpreter.is_empty() {
+		return Err(SetupError(format!(
+			"could not find the interpreter used by {}",
+			REFERENCE_BINARY
+		))
+		.into());
+	}
+
+	Ok(interpreter)
+}
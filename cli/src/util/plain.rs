@@ -0,0 +1,26 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static PLAIN_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Enables plain output, in which the CLI avoids colors, progress bars,
+/// box-drawing characters, and rewriting lines it's already printed, so that
+/// output can be followed sequentially by screen readers.
+pub fn set_plain_output(plain: bool) {
+	PLAIN_OUTPUT.store(plain, Ordering::SeqCst);
+}
+
+/// Gets whether plain output is enabled, either explicitly or because the
+/// environment indicates the terminal can't handle anything fancier.
+pub fn is_plain_output() -> bool {
+	PLAIN_OUTPUT.load(Ordering::SeqCst) || plain_output_requested_by_env()
+}
+
+fn plain_output_requested_by_env() -> bool {
+	std::env::var_os("NO_COLOR").is_some()
+		|| std::env::var("TERM").map(|t| t == "dumb").unwrap_or(false)
+}
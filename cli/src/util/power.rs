@@ -0,0 +1,141 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Notifies when the system resumes from sleep. On Linux, logind's
+//! `PrepareForSleep` signal is used when it's available. Everywhere, the wall
+//! clock is also polled for jumps past the monotonic clock, which doesn't
+//! advance while suspended; this covers macOS and Windows, and Linux systems
+//! without logind, within a few seconds of waking.
+
+use std::time::Duration;
+
+use tokio::{
+	sync::mpsc,
+	time::{sleep, Instant},
+};
+
+use super::clock::{system_clock, SharedClock, SuspendDetector};
+use crate::{log, trace};
+
+/// How often the clock is checked for a jump.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Resumes reported by different sources within this time are treated as one.
+const DEBOUNCE: Duration = Duration::from_secs(10);
+
+/// Returns a receiver that gets a message each time the system resumes from
+/// sleep. Watching stops once the receiver is dropped.
+pub fn watch_for_resume(log: log::Logger) -> mpsc::UnboundedReceiver<()> {
+	let (source_tx, mut source_rx) = mpsc::unbounded_channel();
+	let (tx, rx) = mpsc::unbounded_channel();
+
+	#[cfg(target_os = "linux")]
+	tokio::spawn(logind::watch(log.clone(), source_tx.clone()));
+	tokio::spawn(poll_for_resume(system_clock(), source_tx));
+
+	tokio::spawn(async move {
+		let mut last: Option<Instant> = None;
+		while let Some(source) = source_rx.recv().await {
+			let now = Instant::now();
+			if matches!(last, Some(l) if now - l < DEBOUNCE) {
+				continue;
+			}
+
+			trace!(log, "System resumed from sleep (detected by {})", source);
+			last = Some(now);
+			if tx.send(()).is_err() {
+				return;
+			}
+		}
+	});
+
+	rx
+}
+
+async fn poll_for_resume(clock: SharedClock, tx: mpsc::UnboundedSender<&'static str>) {
+	let mut detector = SuspendDetector::new(clock);
+	while !tx.is_closed() {
+		sleep(POLL_INTERVAL).await;
+		if detector.check().is_some() && tx.send("clock").is_err() {
+			return;
+		}
+	}
+}
+
+#[cfg(target_os = "linux")]
+mod logind {
+	use futures::StreamExt;
+	use tokio::sync::mpsc;
+	use zbus::{dbus_proxy, Connection};
+
+	use crate::{debug, log};
+
+	pub async fn watch(log: log::Logger, tx: mpsc::UnboundedSender<&'static str>) {
+		if let Err(e) = watch_inner(&tx).await {
+			// containers and minimal hosts often don't have logind; polling
+			// the clock still picks up resumes there
+			debug!(log, "Not watching logind for sleep: {}", e);
+		}
+	}
+
+	async fn watch_inner(tx: &mpsc::UnboundedSender<&'static str>) -> zbus::Result<()> {
+		let connection = Connection::system().await?;
+		let proxy = LogindManagerDbusProxy::new(&connection).await?;
+		let mut signals = proxy.receive_prepare_for_sleep().await?;
+
+		while let Some(signal) = signals.next().await {
+			// the signal is sent with `false` once the system has woken up
+			if !signal.args()?.start && tx.send("logind").is_err() {
+				break;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// A slice of the logind manager interface. See
+	/// https://www.freedesktop.org/software/systemd/man/org.freedesktop.login1.html
+	#[dbus_proxy(
+		interface = "org.freedesktop.login1.Manager",
+		gen_blocking = false,
+		default_service = "org.freedesktop.login1",
+		default_path = "/org/freedesktop/login1"
+	)]
+	trait LogindManagerDbus {
+		#[dbus_proxy(signal)]
+		fn prepare_for_sleep(&self, start: bool) -> zbus::Result<()>;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use chrono::{TimeZone, Utc};
+	use tokio::time::timeout;
+
+	use super::*;
+	use crate::util::clock::ManualClock;
+
+	#[tokio::test(start_paused = true)]
+	async fn test_poll_for_resume() {
+		let clock = ManualClock::new(Utc.ymd(2022, 10, 1).and_hms(0, 0, 0));
+		let (tx, mut rx) = mpsc::unbounded_channel();
+		tokio::spawn(poll_for_resume(Arc::new(clock.clone()), tx));
+
+		// time passing normally isn't a resume
+		for _ in 0..5 {
+			clock.advance(chrono::Duration::from_std(POLL_INTERVAL).unwrap());
+			tokio::time::advance(POLL_INTERVAL).await;
+		}
+		assert!(timeout(Duration::from_millis(1), rx.recv()).await.is_err());
+
+		// wall time jumping ahead of monotonic time is
+		clock.advance(chrono::Duration::minutes(10));
+		let source = timeout(POLL_INTERVAL * 2, rx.recv())
+			.await
+			.expect("expected a resume");
+		assert_eq!(source, Some("clock"));
+	}
+}
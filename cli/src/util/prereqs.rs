@@ -3,6 +3,7 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 use std::cmp::Ordering;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 
 use super::command::capture_command;
 use crate::constants::QUALITYLESS_SERVER_NAME;
@@ -27,6 +28,14 @@ lazy_static! {
 
 const NIXOS_TEST_PATH: &str = "/etc/NIXOS";
 
+static FORCE_X64: AtomicBool = AtomicBool::new(false);
+
+/// Makes the prereq checker pick x64 builds on Windows ARM64 machines, which
+/// run under emulation. Some extensions don't support ARM64 yet.
+pub fn set_force_x64(force: bool) {
+	FORCE_X64.store(force, AtomicOrdering::SeqCst);
+}
+
 pub struct PreReqChecker {}
 
 impl Default for PreReqChecker {
@@ -43,12 +52,18 @@ impl PreReqChecker {
 	#[cfg(not(target_os = "linux"))]
 	pub async fn verify(&self) -> Result<Platform, AnyError> {
 		use crate::constants::QUALITYLESS_PRODUCT_NAME;
-		Platform::env_default().ok_or_else(|| {
+		let platform = Platform::env_default().ok_or_else(|| {
 			SetupError(format!(
 				"{} is not supported on this platform",
 				QUALITYLESS_PRODUCT_NAME
 			))
-			.into()
+		})?;
+
+		Ok(match platform {
+			Platform::WindowsARM64 if FORCE_X64.load(AtomicOrdering::SeqCst) => {
+				Platform::WindowsX64
+			}
+			p => p,
 		})
 	}
 
@@ -66,6 +81,8 @@ impl PreReqChecker {
 				Platform::LinuxX64
 			} else if cfg!(target_arch = "armhf") {
 				Platform::LinuxARM32
+			} else if cfg!(target_arch = "riscv64") {
+				Platform::LinuxRiscv64
 			} else {
 				Platform::LinuxARM64
 			});
@@ -0,0 +1,83 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::options::MaintenancePriority;
+
+static REDUCED_PRIORITY: AtomicBool = AtomicBool::new(false);
+
+/// Sets the priority used for subsequent calls to `run_maintenance`.
+pub fn set_maintenance_priority(priority: MaintenancePriority) {
+	REDUCED_PRIORITY.store(priority == MaintenancePriority::Low, Ordering::SeqCst);
+}
+
+/// Runs a blocking maintenance task, such as extraction or pruning. If a low
+/// maintenance priority is configured, this happens on a dedicated thread with
+/// reduced CPU and IO priority, so that the thread which handles interactive
+/// traffic is not affected. (Unprivileged processes can't restore a thread's
+/// priority once lowered on Linux, so we can't just lower the current one.)
+pub fn run_maintenance<F, R>(f: F) -> R
+where
+	F: FnOnce() -> R + Send,
+	R: Send,
+{
+	if !REDUCED_PRIORITY.load(Ordering::SeqCst) {
+		return f();
+	}
+
+	std::thread::scope(|s| {
+		s.spawn(|| {
+			lower_current_thread_priority();
+			f()
+		})
+		.join()
+		.expect("expected maintenance task not to panic")
+	})
+}
+
+#[cfg(target_os = "linux")]
+fn lower_current_thread_priority() {
+	const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+	const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+	const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+	// On Linux both of these apply only to the calling thread when given its
+	// ID, or 0 for ioprio_set, rather than the whole process.
+	unsafe {
+		let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+		libc::setpriority(libc::PRIO_PROCESS, tid, 10);
+		libc::syscall(
+			libc::SYS_ioprio_set,
+			IOPRIO_WHO_PROCESS,
+			0,
+			IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+		);
+	}
+}
+
+#[cfg(target_os = "macos")]
+fn lower_current_thread_priority() {
+	// background mode throttles both CPU and IO for the thread
+	unsafe {
+		libc::setpriority(libc::PRIO_DARWIN_THREAD, 0, libc::PRIO_DARWIN_BG);
+	}
+}
+
+#[cfg(windows)]
+fn lower_current_thread_priority() {
+	use winapi::um::{processthreadsapi, winbase};
+
+	// background mode lowers CPU, IO, and memory priority for the thread
+	unsafe {
+		processthreadsapi::SetThreadPriority(
+			processthreadsapi::GetCurrentThread(),
+			winbase::THREAD_MODE_BACKGROUND_BEGIN as i32,
+		);
+	}
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn lower_current_thread_priority() {}
@@ -0,0 +1,221 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Progress reporting for long-running operations, like installing a server.
+//! An operation goes through named stages, each of which reports how far
+//! along it is, or that it can't tell. Stages begun while another is active
+//! are nested within it, so a reporter can be passed down to sub-operations
+//! that report their own stages.
+
+use std::fmt;
+use std::io::Write;
+
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressStage {
+	/// Finding which release to use.
+	Resolve,
+	Download,
+	/// Checking downloaded files are intact.
+	Verify,
+	Extract,
+	/// Moving installed files into place.
+	Install,
+	/// Starting a process and waiting for it to be ready.
+	Spawn,
+	/// Any other stage, named by the operation.
+	Other(&'static str),
+}
+
+impl fmt::Display for ProgressStage {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ProgressStage::Resolve => write!(f, "resolve"),
+			ProgressStage::Download => write!(f, "download"),
+			ProgressStage::Verify => write!(f, "verify"),
+			ProgressStage::Extract => write!(f, "extract"),
+			ProgressStage::Install => write!(f, "install"),
+			ProgressStage::Spawn => write!(f, "spawn"),
+			ProgressStage::Other(name) => write!(f, "{}", name),
+		}
+	}
+}
+
+impl Serialize for ProgressStage {
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_str(self)
+	}
+}
+
+pub trait ReportProgress {
+	/// Reports progress through the current stage, in bytes or items.
+	fn report_progress(&mut self, done: u64, total: u64);
+
+	/// Reports that the current stage is underway, but not how far along.
+	fn report_indeterminate(&mut self) {}
+
+	/// Begins a stage, nested within the current one if there is one.
+	fn begin_stage(&mut self, _stage: ProgressStage) {}
+
+	/// Ends the most recently begun stage.
+	fn end_stage(&mut self) {}
+}
+
+impl<T: ReportProgress + ?Sized> ReportProgress for &mut T {
+	fn report_progress(&mut self, done: u64, total: u64) {
+		(**self).report_progress(done, total)
+	}
+
+	fn report_indeterminate(&mut self) {
+		(**self).report_indeterminate()
+	}
+
+	fn begin_stage(&mut self, stage: ProgressStage) {
+		(**self).begin_stage(stage)
+	}
+
+	fn end_stage(&mut self) {
+		(**self).end_stage()
+	}
+}
+
+impl<T: ReportProgress + ?Sized> ReportProgress for Box<T> {
+	fn report_progress(&mut self, done: u64, total: u64) {
+		(**self).report_progress(done, total)
+	}
+
+	fn report_indeterminate(&mut self) {
+		(**self).report_indeterminate()
+	}
+
+	fn begin_stage(&mut self, stage: ProgressStage) {
+		(**self).begin_stage(stage)
+	}
+
+	fn end_stage(&mut self) {
+		(**self).end_stage()
+	}
+}
+
+/// Type that doesn't emit anything for progress.
+pub struct SilentProgress();
+
+impl ReportProgress for SilentProgress {
+	fn report_progress(&mut self, _done: u64, _total: u64) {}
+}
+
+/// Reports progress to both reporters.
+pub struct TeeProgress<A, B>(pub A, pub B);
+
+impl<A: ReportProgress, B: ReportProgress> ReportProgress for TeeProgress<A, B> {
+	fn report_progress(&mut self, done: u64, total: u64) {
+		self.0.report_progress(done, total);
+		self.1.report_progress(done, total);
+	}
+
+	fn report_indeterminate(&mut self) {
+		self.0.report_indeterminate();
+		self.1.report_indeterminate();
+	}
+
+	fn begin_stage(&mut self, stage: ProgressStage) {
+		self.0.begin_stage(stage);
+		self.1.begin_stage(stage);
+	}
+
+	fn end_stage(&mut self) {
+		self.0.end_stage();
+		self.1.end_stage();
+	}
+}
+
+/// Tracks the stages a reporter is in, for reporters that describe the full
+/// path to the current stage.
+#[derive(Default)]
+pub struct StageStack(Vec<ProgressStage>);
+
+impl StageStack {
+	pub fn push(&mut self, stage: ProgressStage) {
+		self.0.push(stage);
+	}
+
+	pub fn pop(&mut self) -> Option<ProgressStage> {
+		self.0.pop()
+	}
+
+	pub fn path(&self) -> &[ProgressStage] {
+		&self.0
+	}
+}
+
+/// A progress event, as sent by reporters that serialize them.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum ProgressEvent<'a> {
+	Begin {
+		path: &'a [ProgressStage],
+	},
+	Progress {
+		path: &'a [ProgressStage],
+		done: u64,
+		total: u64,
+	},
+	Indeterminate {
+		path: &'a [ProgressStage],
+	},
+	End {
+		path: &'a [ProgressStage],
+	},
+}
+
+/// Writes progress events as JSON, one per line.
+pub struct JsonProgressReporter<W: Write> {
+	out: W,
+	stages: StageStack,
+}
+
+impl<W: Write> JsonProgressReporter<W> {
+	pub fn new(out: W) -> Self {
+		JsonProgressReporter {
+			out,
+			stages: StageStack::default(),
+		}
+	}
+}
+
+fn write_event(out: &mut impl Write, event: &ProgressEvent) {
+	if let Ok(s) = serde_json::to_string(event) {
+		writeln!(out, "{}", s).ok();
+		out.flush().ok();
+	}
+}
+
+impl<W: Write> ReportProgress for JsonProgressReporter<W> {
+	fn report_progress(&mut self, done: u64, total: u64) {
+		let path = self.stages.path();
+		write_event(
+			&mut self.out,
+			&ProgressEvent::Progress { path, done, total },
+		);
+	}
+
+	fn report_indeterminate(&mut self) {
+		let path = self.stages.path();
+		write_event(&mut self.out, &ProgressEvent::Indeterminate { path });
+	}
+
+	fn begin_stage(&mut self, stage: ProgressStage) {
+		self.stages.push(stage);
+		let path = self.stages.path();
+		write_event(&mut self.out, &ProgressEvent::Begin { path });
+	}
+
+	fn end_stage(&mut self) {
+		let path = self.stages.path();
+		write_event(&mut self.out, &ProgressEvent::End { path });
+		self.stages.pop();
+	}
+}
@@ -0,0 +1,116 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Version handshake for the local protocols spoken between processes that
+//! can come from different CLI releases, such as `command-shell` and the
+//! program embedding it after either is updated.
+//!
+//! Each side sends the newest protocol version it speaks and the oldest one
+//! it still supports, and they use the newest version both speak. A newer
+//! side keeps supporting older versions for core operations, so it only
+//! fails when the other side is too old for any of them.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProtocolVersions {
+	/// Newest version spoken.
+	pub version: u32,
+	/// Oldest version still spoken.
+	pub min_version: u32,
+}
+
+/// Error when two sides have no protocol version in common.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionMismatch {
+	/// The other side only speaks versions older than we support.
+	PeerIsOlder { peer: u32, min_supported: u32 },
+	/// The other side only speaks versions newer than we do.
+	PeerIsNewer { peer_min: u32, own: u32 },
+}
+
+impl fmt::Display for VersionMismatch {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			VersionMismatch::PeerIsOlder {
+				peer,
+				min_supported,
+			} => write!(
+				f,
+				"the other side speaks protocol version {}, but at least {} is needed",
+				peer, min_supported
+			),
+			VersionMismatch::PeerIsNewer { peer_min, own } => write!(
+				f,
+				"the other side needs protocol version {} or newer, but this side speaks up to {}",
+				peer_min, own
+			),
+		}
+	}
+}
+
+impl ProtocolVersions {
+	pub const fn new(version: u32, min_version: u32) -> Self {
+		ProtocolVersions {
+			version,
+			min_version,
+		}
+	}
+
+	/// Versions assumed for a peer that predates the handshake and only
+	/// speaks the first version.
+	pub const fn unversioned() -> Self {
+		ProtocolVersions::new(1, 1)
+	}
+
+	/// Picks the newest version both sides speak.
+	pub fn negotiate(&self, peer: &ProtocolVersions) -> Result<u32, VersionMismatch> {
+		let version = self.version.min(peer.version);
+		if version < self.min_version {
+			return Err(VersionMismatch::PeerIsOlder {
+				peer: peer.version,
+				min_supported: self.min_version,
+			});
+		}
+		if version < peer.min_version {
+			return Err(VersionMismatch::PeerIsNewer {
+				peer_min: peer.min_version,
+				own: self.version,
+			});
+		}
+
+		Ok(version)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_negotiate() {
+		let ours = ProtocolVersions::new(3, 2);
+		assert_eq!(ours.negotiate(&ProtocolVersions::new(3, 1)), Ok(3));
+		assert_eq!(ours.negotiate(&ProtocolVersions::new(5, 2)), Ok(3));
+		assert_eq!(ours.negotiate(&ProtocolVersions::new(2, 1)), Ok(2));
+		assert_eq!(
+			ours.negotiate(&ProtocolVersions::unversioned()),
+			Err(VersionMismatch::PeerIsOlder {
+				peer: 1,
+				min_supported: 2
+			})
+		);
+		assert_eq!(
+			ours.negotiate(&ProtocolVersions::new(6, 4)),
+			Err(VersionMismatch::PeerIsNewer {
+				peer_min: 4,
+				own: 3
+			})
+		);
+	}
+}
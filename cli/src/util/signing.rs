@@ -0,0 +1,74 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use openssl::{
+	pkey::{Id, PKey},
+	sign::Verifier,
+};
+
+/// Checks an Ed25519 signature of a download, as published in a `.sig` file
+/// next to it. What's signed is the download's 32-byte SHA-256 digest, so it
+/// can be checked without reading the download again. The key and signature
+/// are base64 encoded, and the digest is hex encoded as the CLI computes it.
+pub fn verify_digest_signature(
+	public_key: &str,
+	signature: &str,
+	sha256: &str,
+) -> Result<(), String> {
+	let key = base64::decode(public_key.trim())
+		.map_err(|e| format!("the signing key is invalid: {}", e))?;
+	let key = PKey::public_key_from_raw_bytes(&key, Id::ED25519)
+		.map_err(|e| format!("the signing key is invalid: {}", e))?;
+	let signature =
+		base64::decode(signature.trim()).map_err(|e| format!("the signature is invalid: {}", e))?;
+	let digest = decode_hex(sha256).ok_or_else(|| "the checksum is invalid".to_string())?;
+
+	let verified = Verifier::new_without_digest(&key)
+		.and_then(|mut v| v.verify_oneshot(&signature, &digest))
+		.map_err(|e| format!("error checking the signature: {}", e))?;
+	if !verified {
+		return Err("the signature doesn't match".to_string());
+	}
+
+	Ok(())
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+	if s.len() % 2 != 0 {
+		return None;
+	}
+
+	(0..s.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use openssl::sign::Signer;
+
+	#[test]
+	fn test_verify_digest_signature() {
+		let digest = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+		let key = PKey::generate_ed25519().unwrap();
+		let signature = Signer::new_without_digest(&key)
+			.unwrap()
+			.sign_oneshot_to_vec(&decode_hex(digest).unwrap())
+			.unwrap();
+		let public_key = base64::encode(key.raw_public_key().unwrap());
+		let signature = base64::encode(signature);
+
+		assert!(verify_digest_signature(&public_key, &signature, digest).is_ok());
+
+		let other = "0000000000000000000000000000000000000000000000000000000000000000";
+		assert_eq!(
+			verify_digest_signature(&public_key, &signature, other),
+			Err("the signature doesn't match".to_string())
+		);
+		assert!(verify_digest_signature("not a key", &signature, digest).is_err());
+	}
+}
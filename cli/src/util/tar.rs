@@ -5,20 +5,46 @@
 use crate::util::errors::{wrap, WrappedError};
 
 use flate2::read::GzDecoder;
+use std::collections::HashSet;
 use std::fs;
-use std::io::{Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 use tar::Archive;
+use xz2::read::XzDecoder;
 
-use super::io::ReportCopyProgress;
+use super::progress::ReportProgress;
 
-fn should_skip_first_segment(file: &fs::File) -> Result<bool, WrappedError> {
+/// How a tarball is compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TarCompression {
+	Gzip,
+	Zstd,
+	Xz,
+}
+
+impl TarCompression {
+	fn decoder<'a, R: Read + 'a>(&self, reader: R) -> Result<Box<dyn Read + 'a>, WrappedError> {
+		Ok(match self {
+			TarCompression::Gzip => Box::new(GzDecoder::new(reader)),
+			TarCompression::Zstd => Box::new(
+				zstd::stream::read::Decoder::new(reader)
+					.map_err(|e| wrap(e, "error opening zstd stream"))?,
+			),
+			TarCompression::Xz => Box::new(XzDecoder::new(reader)),
+		})
+	}
+}
+
+fn should_skip_first_segment(
+	file: &fs::File,
+	compression: TarCompression,
+) -> Result<bool, WrappedError> {
 	// unfortunately, we need to re-read the archive here since you cannot reuse
 	// `.entries()`. But this will generally only look at one or two files, so this
 	// should be acceptably speedy... If not, we could hardcode behavior for
 	// different types of archives.
 
-	let tar = GzDecoder::new(file);
+	let tar = compression.decoder(file)?;
 	let mut archive = Archive::new(tar);
 	let mut entries = archive
 		.entries()
@@ -54,21 +80,25 @@ fn should_skip_first_segment(file: &fs::File) -> Result<bool, WrappedError> {
 pub fn decompress_tarball<T>(
 	path: &Path,
 	parent_path: &Path,
+	compression: TarCompression,
 	mut reporter: T,
 ) -> Result<(), WrappedError>
 where
-	T: ReportCopyProgress,
+	T: ReportProgress,
 {
-	let mut tar_gz = fs::File::open(path)
+	let mut tar_file = fs::File::open(path)
 		.map_err(|e| wrap(e, format!("error opening file {}", path.display())))?;
-	let skip_first = should_skip_first_segment(&tar_gz)?;
+	let skip_first = should_skip_first_segment(&tar_file, compression)?;
 
 	// reset since skip logic read the tar already:
-	tar_gz
+	tar_file
 		.seek(SeekFrom::Start(0))
 		.map_err(|e| wrap(e, "error resetting seek position"))?;
 
-	let tar = GzDecoder::new(tar_gz);
+	// Tarballs don't have a way to get the number of entries ahead of time
+	reporter.report_indeterminate();
+
+	let tar = compression.decoder(&tar_file)?;
 	let mut archive = Archive::new(tar);
 
 	let results = archive
@@ -98,8 +128,112 @@ where
 		})
 		.collect::<Result<Vec<PathBuf>, WrappedError>>()?;
 
-	// Tarballs don't have a way to get the number of entries ahead of time
 	reporter.report_progress(results.len() as u64, results.len() as u64);
 
 	Ok(())
 }
+
+/// Extracts a tarball as it's read, such as while it's being downloaded.
+/// Unlike `decompress_tarball`, the archive can't be read twice to tell
+/// whether everything is in a single folder, so if it is, the folder's
+/// contents are moved up once extraction is done. Fails if any entry can't
+/// be read, since that usually means the stream was cut short.
+pub fn decompress_tar_stream(
+	reader: impl Read,
+	parent_path: &Path,
+	compression: TarCompression,
+) -> Result<(), WrappedError> {
+	let mut archive = Archive::new(compression.decoder(reader)?);
+	let mut first_segments = HashSet::new();
+	let mut count = 0;
+
+	for entry in archive
+		.entries()
+		.map_err(|e| wrap(e, "error opening archive"))?
+	{
+		let mut entry = entry.map_err(|e| wrap(e, "error reading archive entry"))?;
+		let entry_path = entry
+			.path()
+			.map_err(|e| wrap(e, "error reading entry path"))?
+			.into_owned();
+		if let Some(first) = entry_path.iter().next() {
+			first_segments.insert(first.to_owned());
+		}
+
+		entry
+			.unpack_in(parent_path)
+			.map_err(|e| wrap(e, format!("error unpacking {}", entry_path.display())))?;
+		count += 1;
+	}
+
+	// prefix removal is invalid if there's only a single file
+	if count > 1 && first_segments.len() == 1 {
+		let folder = parent_path.join(first_segments.into_iter().next().unwrap());
+		if folder.is_dir() {
+			hoist_folder(&folder, parent_path)?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Moves the folder's contents into the parent, removing the folder.
+fn hoist_folder(folder: &Path, parent_path: &Path) -> Result<(), WrappedError> {
+	// moved aside first, in case it contains an entry with its own name
+	let staging = parent_path.join(".cli-extract-root");
+	fs::rename(folder, &staging).map_err(|e| wrap(e, "error moving extracted files"))?;
+
+	for child in fs::read_dir(&staging).map_err(|e| wrap(e, "error reading extracted files"))? {
+		let child = child.map_err(|e| wrap(e, "error reading extracted files"))?;
+		fs::rename(child.path(), parent_path.join(child.file_name()))
+			.map_err(|e| wrap(e, "error moving extracted files"))?;
+	}
+
+	fs::remove_dir(&staging).map_err(|e| wrap(e, "error removing extracted folder"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use flate2::{write::GzEncoder, Compression};
+
+	fn make_tarball(files: &[(&str, &str)]) -> Vec<u8> {
+		let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+		for (path, contents) in files {
+			let mut header = tar::Header::new_gnu();
+			header.set_size(contents.len() as u64);
+			header.set_mode(0o644);
+			header.set_cksum();
+			builder
+				.append_data(&mut header, path, contents.as_bytes())
+				.unwrap();
+		}
+		builder.into_inner().unwrap().finish().unwrap()
+	}
+
+	#[test]
+	fn test_decompress_tar_stream() {
+		let dir = tempfile::tempdir().unwrap();
+		let tarball = make_tarball(&[
+			("server/bin/server", "entry"),
+			("server/server/nested", "nested"),
+			("server/product.json", "{}"),
+		]);
+		decompress_tar_stream(&tarball[..], dir.path(), TarCompression::Gzip).unwrap();
+
+		assert_eq!(
+			fs::read_to_string(dir.path().join("bin/server")).unwrap(),
+			"entry"
+		);
+		assert_eq!(
+			fs::read_to_string(dir.path().join("server/nested")).unwrap(),
+			"nested"
+		);
+		assert!(dir.path().join("product.json").exists());
+		assert!(!dir.path().join(".cli-extract-root").exists());
+
+		let truncated = &tarball[..tarball.len() / 2];
+		let dir = tempfile::tempdir().unwrap();
+		assert!(decompress_tar_stream(truncated, dir.path(), TarCompression::Gzip).is_err());
+	}
+}
@@ -0,0 +1,146 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Temporary files and directories used by the CLI. Everything is created
+//! with a unique name, readable only by the current user, and removed when
+//! its guard is dropped, including while unwinding from a panic.
+
+use std::{
+	io::{self, Write},
+	path::{Path, PathBuf},
+	sync::RwLock,
+};
+
+use lazy_static::lazy_static;
+
+pub use ::tempfile::{TempDir, TempPath};
+
+use super::errors::{wrap, WrappedError};
+
+const TEMP_PREFIX: &str = "vscode-cli-";
+
+lazy_static! {
+	static ref TEMP_ROOT: RwLock<Option<PathBuf>> = RwLock::new(None);
+}
+
+/// Sets the directory temporary files are created in, for hosts where the
+/// system temp directory is small or not writable. `None` uses the system
+/// default.
+pub fn set_temp_root(root: Option<PathBuf>) {
+	*TEMP_ROOT.write().unwrap() = root;
+}
+
+/// Gets the directory temporary files are created in.
+pub fn temp_root() -> PathBuf {
+	TEMP_ROOT
+		.read()
+		.unwrap()
+		.clone()
+		.unwrap_or_else(std::env::temp_dir)
+}
+
+/// Creates a new directory in the temp root.
+pub fn new_temp_dir() -> Result<TempDir, WrappedError> {
+	new_temp_dir_in(&temp_root())
+}
+
+/// Creates a new directory within `parent`. Use this to stage files that
+/// will be renamed into `parent`, since renames can't cross filesystems.
+pub fn new_temp_dir_in(parent: &Path) -> Result<TempDir, WrappedError> {
+	ensure_dir(parent)?;
+	::tempfile::Builder::new()
+		.prefix(TEMP_PREFIX)
+		.tempdir_in(parent)
+		.map_err(|e| {
+			wrap(
+				e,
+				format!("error creating temp dir in {}", parent.display()),
+			)
+		})
+}
+
+/// Creates a new, empty file in the temp root.
+pub fn new_temp_file() -> Result<TempPath, WrappedError> {
+	new_temp_file_in(&temp_root())
+}
+
+/// Creates a new, empty file within `parent`. The file is closed, so it can
+/// be reopened by path (such as to download into it) on any platform.
+pub fn new_temp_file_in(parent: &Path) -> Result<TempPath, WrappedError> {
+	ensure_dir(parent)?;
+	::tempfile::Builder::new()
+		.prefix(TEMP_PREFIX)
+		.tempfile_in(parent)
+		.map(|f| f.into_temp_path())
+		.map_err(|e| {
+			wrap(
+				e,
+				format!("error creating temp file in {}", parent.display()),
+			)
+		})
+}
+
+/// Writes the contents to a temporary file next to `path`, then moves it into
+/// place, so readers never see a partially-written file.
+pub fn write_file_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+	let parent = match path.parent() {
+		Some(p) if !p.as_os_str().is_empty() => p,
+		_ => Path::new("."),
+	};
+
+	let mut file = ::tempfile::Builder::new()
+		.prefix(TEMP_PREFIX)
+		.tempfile_in(parent)?;
+	file.write_all(contents)?;
+	file.as_file().sync_all()?;
+	file.persist(path).map_err(|e| e.error)?;
+	Ok(())
+}
+
+fn ensure_dir(dir: &Path) -> Result<(), WrappedError> {
+	std::fs::create_dir_all(dir)
+		.map_err(|e| wrap(e, format!("error creating directory {}", dir.display())))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_cleans_up_on_drop() {
+		let parent = ::tempfile::tempdir().unwrap();
+		let dir = new_temp_dir_in(parent.path()).unwrap();
+		let file = new_temp_file_in(dir.path()).unwrap();
+		let (dir_path, file_path) = (dir.path().to_owned(), file.to_path_buf());
+		assert!(file_path.exists());
+
+		drop(file);
+		assert!(!file_path.exists());
+		drop(dir);
+		assert!(!dir_path.exists());
+	}
+
+	#[test]
+	fn test_write_file_atomic() {
+		let parent = ::tempfile::tempdir().unwrap();
+		let path = parent.path().join("a.txt");
+		write_file_atomic(&path, b"hello").unwrap();
+		write_file_atomic(&path, b"world").unwrap();
+		assert_eq!(std::fs::read(&path).unwrap(), b"world");
+		assert_eq!(std::fs::read_dir(parent.path()).unwrap().count(), 1);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_only_readable_by_user() {
+		use std::os::unix::fs::PermissionsExt;
+		let parent = ::tempfile::tempdir().unwrap();
+		let dir = new_temp_dir_in(parent.path()).unwrap();
+		let file = new_temp_file_in(parent.path()).unwrap();
+		let mode = |p: &Path| std::fs::metadata(p).unwrap().permissions().mode() & 0o777;
+		assert_eq!(mode(dir.path()), 0o700);
+		assert_eq!(mode(&file), 0o600);
+	}
+}
@@ -0,0 +1,145 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{path::Path, sync::RwLock};
+
+use lazy_static::lazy_static;
+use native_tls::{Certificate, Identity, TlsConnector};
+
+use super::errors::{wrap, AnyError, InvalidTlsConfig};
+
+pub(crate) const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+lazy_static! {
+	/// Connector built from the configured TLS settings, if there are any.
+	static ref TLS: RwLock<Option<TlsConnector>> = RwLock::new(None);
+}
+
+/// Configures the TLS settings of HTTPS requests and the tunnel's relay
+/// connection, for networks where TLS is intercepted or servers ask for a
+/// client certificate:
+///
+/// - `ca_file` is a PEM file of CA certificates to trust, in addition to the
+///   built-in ones.
+/// - `client_cert` is a PEM file with a client certificate to present, and
+///   its PKCS#8 private key unless that's given separately in `client_key`.
+///
+/// Files are read here, so problems with them are reported before any
+/// connection is made.
+pub fn configure_tls(
+	ca_file: Option<&Path>,
+	client_cert: Option<&Path>,
+	client_key: Option<&Path>,
+) -> Result<(), AnyError> {
+	if ca_file.is_none() && client_cert.is_none() && client_key.is_none() {
+		return Ok(());
+	}
+
+	let mut builder = TlsConnector::builder();
+	if let Some(file) = ca_file {
+		for cert in read_certificates(file).map_err(InvalidTlsConfig)? {
+			builder.add_root_certificate(cert);
+		}
+	}
+
+	match (client_cert, client_key) {
+		(Some(cert), key) => {
+			builder.identity(read_identity(cert, key.unwrap_or(cert))?);
+		}
+		(None, Some(_)) => {
+			return Err(InvalidTlsConfig(
+				"a client key was given without a client certificate".to_string(),
+			)
+			.into())
+		}
+		(None, None) => {}
+	}
+
+	let connector = builder
+		.build()
+		.map_err(|e| InvalidTlsConfig(format!("error setting up TLS: {}", e)))?;
+	*TLS.write().unwrap() = Some(connector);
+	Ok(())
+}
+
+/// Makes the client builder use the configured TLS settings, if any.
+pub fn apply_tls(builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+	match TLS.read().unwrap().clone() {
+		Some(connector) => builder.use_preconfigured_tls(connector),
+		None => builder,
+	}
+}
+
+/// Gets a connector for connections not made by reqwest, with the configured
+/// TLS settings or the defaults.
+pub fn tls_connector() -> Result<TlsConnector, AnyError> {
+	if let Some(connector) = TLS.read().unwrap().clone() {
+		return Ok(connector);
+	}
+
+	TlsConnector::new().map_err(|e| wrap(e, "error setting up TLS").into())
+}
+
+fn read_identity(cert: &Path, key: &Path) -> Result<Identity, AnyError> {
+	let read = |file: &Path| {
+		std::fs::read(file)
+			.map_err(|e| InvalidTlsConfig(format!("error reading {}: {}", file.display(), e)))
+	};
+
+	Identity::from_pkcs8(&read(cert)?, &read(key)?).map_err(|e| {
+		InvalidTlsConfig(format!(
+			"the client certificate in {} can't be used, it must be PEM with a PKCS#8 key: {}",
+			cert.display(),
+			e
+		))
+		.into()
+	})
+}
+
+/// Reads the PEM certificates in the file, which must have at least one.
+pub fn read_certificates(file: &Path) -> Result<Vec<Certificate>, String> {
+	let contents = std::fs::read(file).map_err(|e| {
+		format!(
+			"error reading CA certificates from {}: {}",
+			file.display(),
+			e
+		)
+	})?;
+	let contents = String::from_utf8_lossy(&contents);
+
+	let mut certs = vec![];
+	let mut rest = contents.as_ref();
+	while let Some(start) = rest.find(PEM_BEGIN) {
+		let end = match rest[start..].find(PEM_END) {
+			Some(i) => start + i + PEM_END.len(),
+			None => {
+				return Err(format!(
+					"certificate {} in {} isn't terminated",
+					certs.len() + 1,
+					file.display()
+				))
+			}
+		};
+
+		let cert = Certificate::from_pem(rest[start..end].as_bytes()).map_err(|e| {
+			format!(
+				"certificate {} in {} is invalid: {}",
+				certs.len() + 1,
+				file.display(),
+				e
+			)
+		})?;
+
+		certs.push(cert);
+		rest = &rest[end..];
+	}
+
+	if certs.is_empty() {
+		return Err(format!("no PEM certificates found in {}", file.display()));
+	}
+
+	Ok(certs)
+}
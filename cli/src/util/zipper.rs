@@ -3,7 +3,7 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 use super::errors::{wrap, WrappedError};
-use super::io::ReportCopyProgress;
+use super::progress::ReportProgress;
 use std::fs::{self, File};
 use std::io;
 use std::path::Path;
@@ -46,7 +46,7 @@ fn should_skip_first_segment(archive: &mut ZipArchive<File>) -> bool {
 
 pub fn unzip_file<T>(path: &Path, parent_path: &Path, mut reporter: T) -> Result<(), WrappedError>
 where
-	T: ReportCopyProgress,
+	T: ReportProgress,
 {
 	let file = fs::File::open(path)
 		.map_err(|e| wrap(e, format!("unable to open file {}", path.display())))?;